@@ -0,0 +1,78 @@
+//! Embeds the git commit, rustc version and build date for `BuildInfo`
+
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Output of a command, `None` if it couldn't be run or failed
+fn command_output(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+/// `YYYY-MM-DD` for seconds since the epoch
+fn civil_date(secs: u64) -> String {
+    // Howard Hinnant's days-to-civil
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn main() {
+    // Packagers building from a tarball can set the commit themselves
+    println!("cargo:rerun-if-env-changed=SUPERGFXCTL_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(head) = command_output("git", &["symbolic-ref", "-q", "HEAD"]) {
+            let head = format!(".git/{head}");
+            if Path::new(&head).exists() {
+                println!("cargo:rerun-if-changed={head}");
+            }
+        }
+    }
+
+    let commit = env::var("SUPERGFXCTL_GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SUPERGFXCTL_GIT_COMMIT={commit}");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SUPERGFXCTL_RUSTC_VERSION={rustc}");
+
+    // Reproducible builds set SOURCE_DATE_EPOCH
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    println!(
+        "cargo:rustc-env=SUPERGFXCTL_BUILD_DATE={}",
+        civil_date(secs)
+    );
+}
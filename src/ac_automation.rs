@@ -0,0 +1,524 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::{future::BoxFuture, lock::Mutex};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::{sleep, timeout, Instant};
+use zbus::{
+    object_server::{InterfaceRef, SignalEmitter},
+    zvariant::Type,
+};
+
+use crate::{
+    actions::{graphical_sessions_active, UserActionRequired},
+    audit::Actor,
+    automation_inhibit::skip_if_inhibited,
+    config::GfxConfig,
+    controller::{CtrlGraphics, SetModeOptions, SwitchInitiator, SwitchState},
+    dock_automation::DockState,
+    error::GfxError,
+    gpu_users::dgpu_users,
+    pci_device::{DiscreetGpu, GfxMode},
+    power_blockers::PowerBlocker,
+    power_watch::spawn_power_supply_monitor,
+    signal_counters::{emit_counted, Signal},
+    supervisor::RestartPolicy,
+    DBUS_IFACE_PATH,
+};
+
+pub(crate) const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+/// How often the power supply is read without udev events, and the keep-alive otherwise
+const AC_POLL: Duration = Duration::from_secs(5);
+
+/// Where the machine is drawing power from
+#[derive(Debug, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+/// Suggest, and optionally switch to, a mode when AC is plugged in or unplugged
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AcAutomation {
+    /// The mode to use on battery, e.g `Integrated`. `None` suggests nothing.
+    pub on_battery: Option<GfxMode>,
+    /// The mode to use on AC, e.g `Hybrid`. `None` suggests nothing.
+    pub on_ac: Option<GfxMode>,
+    /// Switch without asking if no graphical sessions are active and nothing is using the
+    /// dGPU, otherwise the mode is only suggested
+    pub auto_apply_when_no_sessions: bool,
+    /// Switch even while graphical sessions are active. A switch which needs a logout is left
+    /// pending until they end, which `NotifyAction` tells the user, nothing is forced.
+    pub apply_with_sessions: bool,
+    /// Seconds the power source must stay the same before anything is done, so a loose
+    /// plug doesn't cause a string of switches
+    pub hold_s: u64,
+}
+
+impl Default for AcAutomation {
+    fn default() -> Self {
+        Self {
+            on_battery: None,
+            on_ac: None,
+            auto_apply_when_no_sessions: false,
+            apply_with_sessions: false,
+            hold_s: 10,
+        }
+    }
+}
+
+impl AcAutomation {
+    /// A mode is set for either power source
+    pub fn enabled(&self) -> bool {
+        self.on_battery.is_some() || self.on_ac.is_some()
+    }
+
+    fn mode_for(&self, source: PowerSource) -> Option<GfxMode> {
+        match source {
+            PowerSource::Ac => self.on_ac,
+            PowerSource::Battery => self.on_battery,
+        }
+    }
+}
+
+/// Emitted with `NotifySuggestion` when the power source changes
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ModeSuggestion {
+    pub mode: GfxMode,
+    pub power: PowerSource,
+    /// supergfxd is switching to `mode` itself
+    pub applying: bool,
+    /// Why the switch isn't being made automatically, empty if `applying`
+    pub reason: String,
+    /// The processes found keeping the dGPU awake on battery, see `PowerBlockers`
+    pub blockers: Vec<PowerBlocker>,
+}
+
+/// Read the power source from the `Mains` supplies under `dir`. `None` if there are none,
+/// as on a desktop.
+pub(crate) fn power_source_in(dir: &Path) -> Option<PowerSource> {
+    let mut mains = false;
+    let mut online = false;
+    for entry in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        if kind.trim() != "Mains" {
+            continue;
+        }
+        mains = true;
+        online |= fs::read_to_string(path.join("online")).map_or(false, |s| s.trim() == "1");
+    }
+    mains.then_some(if online {
+        PowerSource::Ac
+    } else {
+        PowerSource::Battery
+    })
+}
+
+/// A change waiting out the hold time
+#[derive(Debug, Clone, Copy)]
+struct PendingTransition<T> {
+    source: T,
+    since: Instant,
+    /// The user switch count when the change was seen
+    user_switches: u64,
+}
+
+/// Holds back changes of state, such as the power source, until they have lasted `hold`.
+/// A change which is undone within the hold time is dropped, and one during which the user
+/// switched modes is cancelled.
+#[derive(Debug)]
+pub(crate) struct Debounce<T> {
+    hold: Duration,
+    /// The state last acted on, `None` before the first reading
+    settled: Option<T>,
+    pending: Option<PendingTransition<T>>,
+}
+
+/// Holds back power source changes
+pub(crate) type AcDebounce = Debounce<PowerSource>;
+
+impl<T: Copy + PartialEq + std::fmt::Debug> Debounce<T> {
+    pub fn new(hold: Duration) -> Self {
+        Self {
+            hold,
+            settled: None,
+            pending: None,
+        }
+    }
+
+    pub fn set_hold(&mut self, hold: Duration) {
+        self.hold = hold;
+    }
+
+    /// Record a reading of the state. The first reading is taken as the starting state and
+    /// is not a change.
+    pub fn observe(&mut self, source: T, now: Instant, user_switches: u64) {
+        let settled = match self.settled {
+            Some(settled) => settled,
+            None => {
+                self.settled = Some(source);
+                return;
+            }
+        };
+        match self.pending {
+            Some(pending) if pending.source == source => {}
+            Some(_) if source == settled => {
+                debug!("Debounce: back to {source:?} within the hold time, ignoring");
+                self.pending = None;
+            }
+            _ if source == settled => {}
+            _ => {
+                self.pending = Some(PendingTransition {
+                    source,
+                    since: now,
+                    user_switches,
+                })
+            }
+        }
+    }
+
+    /// When the pending change will have lasted the hold time
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|pending| pending.since + self.hold)
+    }
+
+    /// The change to act on, once it has lasted the hold time. `None` if there is none yet,
+    /// or the user switched modes since the change.
+    pub fn take_ready(&mut self, now: Instant, user_switches: u64) -> Option<T> {
+        let pending = self.pending?;
+        if now < pending.since + self.hold {
+            return None;
+        }
+        self.pending = None;
+        self.settled = Some(pending.source);
+        if pending.user_switches != user_switches {
+            info!(
+                "Debounce: the mode was changed since {:?} was seen, doing nothing",
+                pending.source
+            );
+            return None;
+        }
+        Some(pending.source)
+    }
+}
+
+/// The daemon state an automation decision depends on
+#[derive(Debug, Clone)]
+pub(crate) struct AcContext {
+    pub mode: GfxMode,
+    pub supported: Vec<GfxMode>,
+    pub switching: bool,
+    pub mode_locked: bool,
+    pub always_reboot: bool,
+    /// The daemon may change the system, it isn't a debug run
+    pub mutation_allowed: bool,
+}
+
+/// What to do after a power source change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AcDecision {
+    /// No mode is set for the source, or it is already in use
+    Nothing,
+    /// Suggest `mode` without switching, for `reason`
+    Suggest { mode: GfxMode, reason: String },
+    /// Suggest `mode` and switch to it
+    Apply(GfxMode),
+}
+
+/// The checks against the running system, so decisions can be tested
+pub(crate) trait AcProbe: Sync {
+    /// Any graphical session is active or online
+    fn sessions_active(&self) -> BoxFuture<'_, Result<bool, GfxError>>;
+    /// The processes using the dGPU, e.g `blender (1234)`
+    fn dgpu_users(&self) -> BoxFuture<'_, Vec<String>>;
+}
+
+/// Decide what to do now that the machine is on `source`. A switch is only made
+/// automatically if it is allowed, needs no more than a logout, and nobody would notice:
+/// no graphical sessions are active and nothing has the dGPU open. With
+/// `apply_with_sessions` sessions may be active, and a switch needing a logout is made
+/// pending until they end.
+pub(crate) async fn decide(
+    automation: &AcAutomation,
+    source: PowerSource,
+    ctx: &AcContext,
+    probe: &dyn AcProbe,
+) -> AcDecision {
+    decide_mode(
+        automation.mode_for(source),
+        automation.auto_apply_when_no_sessions || automation.apply_with_sessions,
+        automation.apply_with_sessions,
+        ctx,
+        probe,
+    )
+    .await
+}
+
+/// As `decide` for a `mode` wanted by any automation, switching to it only if
+/// `auto_apply` is set. With `with_sessions` active graphical sessions don't stop the switch,
+/// one which needs a logout waits for them to end.
+pub(crate) async fn decide_mode(
+    mode: Option<GfxMode>,
+    auto_apply: bool,
+    with_sessions: bool,
+    ctx: &AcContext,
+    probe: &dyn AcProbe,
+) -> AcDecision {
+    let mode = match mode {
+        Some(mode) if mode != ctx.mode => mode,
+        _ => return AcDecision::Nothing,
+    };
+    let suggest = |reason: String| AcDecision::Suggest { mode, reason };
+
+    if !auto_apply {
+        return suggest("automatic switching is off".to_string());
+    }
+    if !ctx.mutation_allowed {
+        return suggest("this is a debug run".to_string());
+    }
+    if ctx.mode_locked {
+        return suggest("the mode is locked".to_string());
+    }
+    if ctx.switching {
+        return suggest("a switch is already in progress".to_string());
+    }
+    if !ctx.supported.contains(&mode) {
+        return suggest(format!("{mode} is not supported right now"));
+    }
+    let action = if ctx.always_reboot {
+        UserActionRequired::Reboot
+    } else {
+        UserActionRequired::mode_change_action(mode, ctx.mode)
+    };
+    if !matches!(
+        action,
+        UserActionRequired::Logout | UserActionRequired::Nothing
+    ) {
+        return suggest(<&str>::from(action).to_string());
+    }
+    if with_sessions {
+        // Staged behind the logout, whatever has the dGPU open now ends with the sessions
+        if action == UserActionRequired::Logout {
+            return AcDecision::Apply(mode);
+        }
+    } else {
+        match probe.sessions_active().await {
+            Ok(false) => {}
+            Ok(true) => return suggest("graphical sessions are active".to_string()),
+            Err(err) => return suggest(format!("could not check for graphical sessions: {err}")),
+        }
+    }
+    let users = probe.dgpu_users().await;
+    if !users.is_empty() {
+        return suggest(format!("the dGPU is in use by {}", users.join(", ")));
+    }
+    AcDecision::Apply(mode)
+}
+
+pub(crate) struct SystemProbe {
+    pub dgpu: Arc<Mutex<DiscreetGpu>>,
+}
+
+impl AcProbe for SystemProbe {
+    fn sessions_active(&self) -> BoxFuture<'_, Result<bool, GfxError>> {
+        Box::pin(graphical_sessions_active())
+    }
+
+    fn dgpu_users(&self) -> BoxFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let dgpu = self.dgpu.lock().await.clone();
+            dgpu_users(&dgpu)
+                .iter()
+                .map(|user| user.to_string())
+                .collect()
+        })
+    }
+}
+
+/// Act on a power source change which has lasted the hold time
+async fn on_transition(
+    ctxt: &SignalEmitter<'static>,
+    automation: &AcAutomation,
+    source: PowerSource,
+) -> Result<(), GfxError> {
+    let iface = ctxt
+        .connection()
+        .object_server()
+        .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
+        .await?;
+    let (ctx, probe, blockers, inhibits, audit) = {
+        let ctrl = iface.get().await;
+        let blockers = ctrl.power_blockers.lock().await.clone();
+        (
+            ctrl.get_ac_context().await,
+            SystemProbe {
+                dgpu: ctrl.dgpu_arc_clone(),
+            },
+            blockers,
+            ctrl.automation_inhibits.clone(),
+            ctrl.audit.clone(),
+        )
+    };
+    let decision = decide(automation, source, &ctx, &probe).await;
+    let what = match &decision {
+        AcDecision::Nothing => return Ok(()),
+        AcDecision::Suggest { mode, .. } => format!("ac_automation on {source:?}: suggest {mode}"),
+        AcDecision::Apply(mode) => format!("ac_automation on {source:?}: switch to {mode}"),
+    };
+    if skip_if_inhibited(&inhibits, &audit, &what).await {
+        return Ok(());
+    }
+    let suggestion = match &decision {
+        AcDecision::Nothing => return Ok(()),
+        AcDecision::Suggest { mode, reason } => {
+            info!("AC automation: on {source:?}, suggesting {mode}: {reason}");
+            ModeSuggestion {
+                mode: *mode,
+                power: source,
+                applying: false,
+                reason: reason.clone(),
+                blockers,
+            }
+        }
+        AcDecision::Apply(mode) => {
+            info!("AC automation: on {source:?}, switching to {mode}");
+            ModeSuggestion {
+                mode: *mode,
+                power: source,
+                applying: true,
+                reason: String::new(),
+                blockers,
+            }
+        }
+    };
+    emit_counted!(ctxt, Signal::Suggestion, notify_suggestion(&suggestion)).await?;
+    if let AcDecision::Apply(mode) = decision {
+        switch_by_automation(ctxt, &iface, mode, SwitchInitiator::Automation).await?;
+    }
+    Ok(())
+}
+
+/// Switch to `mode` as an automation decided to, `Automation` or `Schedule` as `initiator`
+pub(crate) async fn switch_by_automation(
+    ctxt: &SignalEmitter<'static>,
+    iface: &InterfaceRef<CtrlGraphics>,
+    mode: GfxMode,
+    initiator: SwitchInitiator,
+) -> Result<(), GfxError> {
+    let actor = match initiator {
+        SwitchInitiator::Schedule => Actor::Schedule,
+        _ => Actor::Daemon,
+    };
+    let action = iface
+        .get_mut()
+        .await
+        .set_gfx_mode_with_options(mode, SetModeOptions::default(), &actor)
+        .await?;
+    emit_counted!(ctxt, Signal::Action, notify_action(&action)).await?;
+    emit_counted!(
+        ctxt,
+        Signal::ModeChange,
+        notify_mode_change(&mode, &initiator)
+    )
+    .await?;
+    emit_counted!(ctxt, Signal::Gfx, notify_gfx(&mode)).await?;
+    Ok(())
+}
+
+/// Watch the power source and act on changes as set in `ac_automation`, unless the
+/// `dock_profiles` profile of the `dock_state` turns it off
+async fn run_ac_automation(
+    config: Arc<Mutex<GfxConfig>>,
+    user_switches: Arc<AtomicU64>,
+    dock_state: Arc<Mutex<Option<DockState>>>,
+    ctxt: SignalEmitter<'static>,
+) {
+    let mut events = spawn_power_supply_monitor();
+    let mut debounce = AcDebounce::new(Duration::ZERO);
+    loop {
+        let (automation, dock_allows) = {
+            let config = config.lock().await;
+            (
+                config.ac_automation.clone(),
+                config
+                    .dock_profiles
+                    .ac_automation_allowed(*dock_state.lock().await),
+            )
+        };
+        debounce.set_hold(Duration::from_secs(automation.hold_s));
+        let switches = user_switches.load(Ordering::Acquire);
+        if let Some(source) = power_source_in(Path::new(POWER_SUPPLY_PATH)) {
+            debounce.observe(source, Instant::now(), switches);
+        }
+        if let Some(source) = debounce.take_ready(Instant::now(), switches) {
+            if automation.enabled() && dock_allows {
+                on_transition(&ctxt, &automation, source)
+                    .await
+                    .unwrap_or_else(|err| warn!("AC automation: {err}"));
+            }
+        }
+
+        let wait = debounce
+            .deadline()
+            .map_or(AC_POLL, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            })
+            .min(AC_POLL);
+        match events.as_mut() {
+            Some(rx) => {
+                if let Ok(None) = timeout(wait, rx.recv()).await {
+                    warn!("AC automation: udev monitor stopped, polling instead");
+                    events = None;
+                }
+            }
+            None => sleep(wait).await,
+        }
+    }
+}
+
+impl CtrlGraphics {
+    /// The state `decide` needs
+    pub(crate) async fn get_ac_context(&self) -> AcContext {
+        let supported = self.get_supported_modes().await;
+        let config = self.config.lock().await;
+        AcContext {
+            mode: config.effective_mode(),
+            supported,
+            switching: config.switch_state == SwitchState::Switching,
+            mode_locked: config.mode_locked,
+            always_reboot: config.always_reboot,
+            mutation_allowed: self.check_mutation_allowed().is_ok(),
+        }
+    }
+
+    /// Watch for AC being plugged in or unplugged and suggest or switch modes as set in
+    /// `ac_automation`. Not started if there is no signal context to notify with.
+    pub fn start_ac_automation(&self) {
+        let ctxt = match self.signal_ctxt.clone() {
+            Some(ctxt) => ctxt,
+            None => return,
+        };
+        let config = self.config.clone();
+        let user_switches = self.user_switches.clone();
+        let dock_state = self.dock_state.clone();
+        self.tasks
+            .spawn("AC automation", RestartPolicy::WithBackoff, move || {
+                run_ac_automation(
+                    config.clone(),
+                    user_switches.clone(),
+                    dock_state.clone(),
+                    ctxt.clone(),
+                )
+            });
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    actions::{Action, StagedAction},
+    error::GfxError,
+    pci_device::GfxMode,
+    sandbox::note_write,
+    sysfs::Sysfs,
+};
+
+/// Where the acpi_call module takes a method to call, and gives back the result
+pub const ACPI_CALL_PATH: &str = "/proc/acpi/call";
+
+/// The ACPI methods which power the dGPU off and on, for older ASUS laptops such as the
+/// GA401 and GA502 which have no `dgpu_disable`. Experimental: a wrong method can hang the
+/// machine, they are model specific.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AcpiDgpuOff {
+    /// Called once the dGPU was removed when entering Integrated, e.g `\_SB.PCI0.GPP0.PG00._OFF`
+    pub method_off: String,
+    /// Called before the PCI bus is rescanned when leaving Integrated, e.g `\_SB.PCI0.GPP0.PG00._ON`
+    pub method_on: String,
+}
+
+/// Check `method` is a plain ACPI path: a `\` then dot separated names of one to four of
+/// `A-Z`, `0-9` and `_`, not starting with a digit. Arguments aren't allowed.
+pub(crate) fn validate_method(method: &str) -> Result<(), String> {
+    let path = method
+        .strip_prefix('\\')
+        .ok_or_else(|| format!("{method:?} must start with \\"))?;
+    let names: Vec<&str> = path.split('.').collect();
+    if names.len() < 2 {
+        return Err(format!(
+            "{method:?} must be a full path such as \\_SB.PCI0._OFF"
+        ));
+    }
+    for name in names {
+        let valid = (1..=4).contains(&name.len())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            && !name.starts_with(|c: char| c.is_ascii_digit());
+        if !valid {
+            return Err(format!(
+                "{method:?} has the invalid name {name:?}, names are 1 to 4 of A-Z, 0-9 and _"
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl AcpiDgpuOff {
+    pub(crate) fn validate(&self) -> Result<(), GfxError> {
+        for method in [&self.method_off, &self.method_on] {
+            validate_method(method)
+                .map_err(|err| GfxError::AcpiCall(format!("acpi_dgpu_off: {err}")))?;
+        }
+        Ok(())
+    }
+
+    fn method(&self, on: bool) -> &str {
+        if on {
+            &self.method_on
+        } else {
+            &self.method_off
+        }
+    }
+}
+
+/// `/proc/acpi/call`, so the calls can be tested without acpi_call
+pub(crate) trait AcpiCallFile {
+    fn path(&self) -> &Path;
+    /// The module is loaded
+    fn exists(&self) -> bool;
+    fn write(&self, method: &str) -> std::io::Result<()>;
+    /// The result of the last call
+    fn read(&self) -> std::io::Result<String>;
+}
+
+/// The file of the running system
+pub(crate) struct SystemAcpiCall(pub PathBuf);
+
+impl Default for SystemAcpiCall {
+    fn default() -> Self {
+        Self(PathBuf::from(ACPI_CALL_PATH))
+    }
+}
+
+impl AcpiCallFile for SystemAcpiCall {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn exists(&self) -> bool {
+        self.0.exists()
+    }
+
+    fn write(&self, method: &str) -> std::io::Result<()> {
+        note_write(&self.0);
+        fs::write(&self.0, method)
+    }
+
+    fn read(&self) -> std::io::Result<String> {
+        fs::read_to_string(&self.0)
+    }
+}
+
+/// The result acpi_call gave for `method`. acpi_call answers `Error: <reason>` for a
+/// failed call, `not called` if nothing was called, otherwise the value returned such as
+/// `0x0` or `0x0` followed by a NUL.
+pub(crate) fn parse_result(method: &str, result: &str) -> Result<String, GfxError> {
+    let result = result.trim_end_matches('\0').trim();
+    if let Some(err) = result.strip_prefix("Error:") {
+        return Err(GfxError::AcpiCall(format!(
+            "{method} failed: {}",
+            err.trim()
+        )));
+    }
+    if result.is_empty() || result == "not called" {
+        return Err(GfxError::AcpiCall(format!(
+            "{method} was not called, acpi_call gave back {result:?}"
+        )));
+    }
+    Ok(result.to_string())
+}
+
+/// Why the methods can't be called now, `None` if they can
+pub(crate) fn unusable_reason(sysfs: &Sysfs, call: &dyn AcpiCallFile) -> Option<String> {
+    if sysfs.dgpu_disable.exists() {
+        return Some(format!(
+            "{} exists, use hotplug_type Asus instead of acpi_dgpu_off",
+            sysfs.dgpu_disable.0.name()
+        ));
+    }
+    if !call.exists() {
+        return Some(format!(
+            "the acpi_call module isn't loaded, {} doesn't exist. Install acpi_call and load it with `modprobe acpi_call`",
+            call.path().display()
+        ));
+    }
+    None
+}
+
+/// Call the method of `acpi` which powers the dGPU `on` or off, checking the result
+pub(crate) fn acpi_dgpu_set(
+    acpi: &AcpiDgpuOff,
+    on: bool,
+    sysfs: &Sysfs,
+    call: &dyn AcpiCallFile,
+) -> Result<String, GfxError> {
+    acpi.validate()?;
+    if let Some(reason) = unusable_reason(sysfs, call) {
+        return Err(GfxError::AcpiCall(reason));
+    }
+    let method = acpi.method(on);
+    let path = call.path();
+    call.write(method).map_err(|err| match err.kind() {
+        // Gone since it was checked
+        ErrorKind::NotFound => GfxError::AcpiCall(format!(
+            "the acpi_call module isn't loaded, {} doesn't exist",
+            path.display()
+        )),
+        _ => GfxError::Write(path.display().to_string(), err),
+    })?;
+    let result = call
+        .read()
+        .map_err(|err| GfxError::Read(path.display().to_string(), err))?;
+    let result = parse_result(method, &result)?;
+    info!("acpi_dgpu_set: called {method}, it returned {result}");
+    Ok(result)
+}
+
+/// Add the calls of `acpi_dgpu_off` to a switch plan, if set and `usable`. Entering
+/// Integrated the dGPU is powered off once it was removed, as the last action before the
+/// display manager is started again. Leaving it the dGPU is powered on before the PCI bus is
+/// rescanned.
+pub(crate) fn apply_acpi_dgpu(
+    acpi: Option<&AcpiDgpuOff>,
+    usable: bool,
+    from: GfxMode,
+    to: GfxMode,
+    mut actions: Action,
+) -> Action {
+    if acpi.is_none() || from == to {
+        return actions;
+    }
+    if !usable {
+        warn!("apply_acpi_dgpu: acpi_dgpu_off is set but can't be used, the dGPU stays powered");
+        return actions;
+    }
+    if let Action::StagedActions(list) = &mut actions {
+        if to == GfxMode::Integrated {
+            insert_acpi_off(list);
+        } else if from == GfxMode::Integrated {
+            if let Some(idx) = list.iter().position(|a| *a == StagedAction::RescanPci) {
+                list.insert(idx, StagedAction::AcpiDgpuOn);
+            }
+        }
+    }
+    actions
+}
+
+/// As `apply_acpi_dgpu` for the boot actions of `mode`
+pub(crate) fn apply_acpi_dgpu_boot(
+    acpi: Option<&AcpiDgpuOff>,
+    usable: bool,
+    mode: GfxMode,
+    actions: &mut Vec<StagedAction>,
+) {
+    if acpi.is_some() && usable && mode == GfxMode::Integrated {
+        insert_acpi_off(actions);
+    }
+}
+
+fn insert_acpi_off(list: &mut Vec<StagedAction>) {
+    match list.last() {
+        Some(StagedAction::StartDisplayManager | StagedAction::NoLogind) => {
+            list.insert(list.len() - 1, StagedAction::AcpiDgpuOff)
+        }
+        _ => list.push(StagedAction::AcpiDgpuOff),
+    }
+}
+
+/// Whether `acpi_dgpu_off` can be used on the running system
+pub(crate) fn acpi_dgpu_usable() -> bool {
+    unusable_reason(&Sysfs::system(), &SystemAcpiCall::default()).is_none()
+}
+
+/// The state of `acpi_dgpu_off` for the support bundle diagnostics
+pub(crate) fn acpi_dgpu_diagnostics(acpi: Option<&AcpiDgpuOff>) -> Value {
+    match acpi {
+        None => json!({ "configured": false }),
+        Some(acpi) => json!({
+            "configured": true,
+            "experimental": true,
+            "method_off": acpi.method_off,
+            "method_on": acpi.method_on,
+            "unusable_reason": unusable_reason(&Sysfs::system(), &SystemAcpiCall::default()),
+        }),
+    }
+}
@@ -1,41 +1,290 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use log::{debug, info, warn};
+use futures_util::{future::BoxFuture, lock::Mutex};
+use log::{debug, error, info, warn};
 use logind_zbus::{
     manager::{ManagerProxy, SessionInfo},
-    session::{SessionClass, SessionProxy, SessionState, SessionType},
+    session::SessionProxy,
 };
 use serde::{Deserialize, Serialize};
 use tokio::time::sleep;
 use zbus::zvariant::Type;
-use zbus::Connection;
+use zbus::{object_server::SignalEmitter, Connection};
 
 use crate::{
-    config::{check_vulkan_icd, create_modprobe_conf, GfxConfig},
+    acpi_dgpu::{
+        acpi_dgpu_set, acpi_dgpu_usable, apply_acpi_dgpu_boot, AcpiDgpuOff, SystemAcpiCall,
+    },
+    bundle::crc32,
+    config::{
+        check_vulkan_icd, create_modprobe_conf, modprobe_conf, modprobe_extra_for, GfxConfig,
+    },
     do_driver_action,
+    driver_override::DriverOverrides,
     error::GfxError,
-    kill_nvidia_lsof,
-    pci_device::{rescan_pci_bus, DiscreetGpu, GfxMode, GfxVendor, HotplugState, HotplugType},
-    special_asus::{asus_dgpu_set_disabled, asus_egpu_set_enabled, asus_gpu_mux_set_igpu},
+    gpu_users::{blocking_processes, with_blocking_processes},
+    inhibitors::wait_inhibitors,
+    kill_policy::{kill_gpu_users, KillPolicy},
+    logout_switch::{
+        session_blocks_logout, wait_logout, LogindSession, LogoutPolicy, SystemHolderProbe,
+        SystemSessionProbe,
+    },
+    nvidia_nodes::settle_nvidia_nodes,
+    pci_device::{
+        rescan_pci_bus, DiscreetGpu, GfxMode, GfxVendor, HotplugState, HotplugType, NvidiaDriver,
+    },
+    pci_lock::{wait_for_pci_settle, PciLock},
+    sandbox::in_action,
+    signal_counters::{emit_counted, Signal},
+    special_asus::{
+        asus_dgpu_set_disabled, asus_egpu_enable_path, asus_egpu_set_enabled,
+        asus_gpu_mux_set_igpu, AsusToggleState,
+    },
+    special_vendor::{special_toggle_set, SpecialToggle},
+    sysfs::{Sysfs, ASUS_DGPU_DISABLE_PATH, ASUS_GPU_MUX_PATH},
     systemd::{
-        do_systemd_unit_action, wait_systemd_unit_state, SystemdUnitAction, SystemdUnitState,
+        do_systemd_unit_action, is_systemd_unit_installed, is_systemd_unit_state,
+        wait_systemd_unit_state, SystemdUnitAction, SystemdUnitState,
     },
-    toggle_nvidia_persistenced, toggle_nvidia_powerd, DriverAction, DISPLAY_MANAGER, VFIO_DRIVERS,
+    toggle_nvidia_persistenced, toggle_nvidia_powerd,
+    vfio::{bind_vfio, driver_name, release_vfio, unload_vfio_modules, vfio_pci_loaded},
+    DriverAction, MODPROBE_PATH,
 };
 
+/// The parts of the config the actions use
+#[derive(Debug, Default, Clone)]
+pub struct ActionSettings {
+    pub kill_policy: KillPolicy,
+    pub acpi: Option<AcpiDgpuOff>,
+    /// The units `StopDisplayManager` and `StartDisplayManager` act on
+    pub display_managers: Vec<String>,
+    /// How many times a driver is loaded or unloaded before giving up
+    pub driver_attempts: u32,
+    /// Make the nvidia device nodes if they are missing after the driver is loaded
+    pub fix_nvidia_nodes: bool,
+    /// Lines added to the modprobe conf by mode name
+    pub modprobe_extra_options: HashMap<String, Vec<String>>,
+}
+
+impl ActionSettings {
+    pub fn from_config(config: &GfxConfig) -> Self {
+        Self {
+            kill_policy: KillPolicy::from_config(config),
+            acpi: config.acpi_dgpu_off.clone(),
+            display_managers: config.display_manager_units.clone(),
+            driver_attempts: config.driver_attempts(),
+            fix_nvidia_nodes: config.fix_nvidia_nodes,
+            modprobe_extra_options: config.modprobe_extra_options.clone(),
+        }
+    }
+
+    /// The lines added to the modprobe conf for `mode`
+    pub fn modprobe_extra(&self, mode: GfxMode) -> &[String] {
+        modprobe_extra_for(&self.modprobe_extra_options, mode)
+    }
+}
+
+/// Stops and starts the display manager units, so it can be tested without systemctl
+pub(crate) trait DisplayManagerControl: Sync {
+    fn unit_action(&self, action: SystemdUnitAction, unit: &str) -> Result<(), GfxError>;
+    /// Wait for `unit` to reach `state`, as `wait_systemd_unit_state`
+    fn wait_unit<'a>(
+        &'a self,
+        state: SystemdUnitState,
+        unit: &'a str,
+    ) -> BoxFuture<'a, Result<(), GfxError>>;
+}
+
+/// `DisplayManagerControl` with `systemctl`
+pub(crate) struct SystemctlDisplayManagers;
+
+impl DisplayManagerControl for SystemctlDisplayManagers {
+    fn unit_action(&self, action: SystemdUnitAction, unit: &str) -> Result<(), GfxError> {
+        do_systemd_unit_action(action, unit)
+    }
+
+    fn wait_unit<'a>(
+        &'a self,
+        state: SystemdUnitState,
+        unit: &'a str,
+    ) -> BoxFuture<'a, Result<(), GfxError>> {
+        Box::pin(wait_systemd_unit_state(state, unit))
+    }
+}
+
+/// Stop each of `units`, then wait for them all to be inactive. Fails at the first which
+/// doesn't stop, naming it.
+pub(crate) async fn stop_display_managers(
+    ctl: &dyn DisplayManagerControl,
+    units: &[String],
+) -> Result<(), GfxError> {
+    for unit in units {
+        ctl.unit_action(SystemdUnitAction::Stop, unit)?;
+    }
+    for unit in units {
+        ctl.wait_unit(SystemdUnitState::Inactive, unit)
+            .await
+            .map_err(|err| GfxError::SystemdUnitAction(format!("stop {unit} ({err})")))?;
+    }
+    Ok(())
+}
+
+/// Start each of `units`. All are tried so that as many as can be come back, the error
+/// names every one which failed.
+pub(crate) fn start_display_managers(
+    ctl: &dyn DisplayManagerControl,
+    units: &[String],
+) -> Result<(), GfxError> {
+    let failed: Vec<&str> = units
+        .iter()
+        .filter(
+            |unit| match ctl.unit_action(SystemdUnitAction::Start, unit) {
+                Ok(()) => false,
+                Err(err) => {
+                    warn!("{err}");
+                    true
+                }
+            },
+        )
+        .map(|unit| unit.as_str())
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(GfxError::SystemdUnitAction(format!(
+            "start {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+/// Wait for each of `units` to be active, then `delay` more. A unit which is slow to start
+/// is only warned about, the switch is done by then.
+pub(crate) async fn wait_display_managers(
+    ctl: &dyn DisplayManagerControl,
+    units: &[String],
+    delay: Duration,
+) {
+    for unit in units {
+        if let Err(err) = ctl.wait_unit(SystemdUnitState::Active, unit).await {
+            warn!("{unit} isn't active yet ({err}), going on");
+        }
+    }
+    sleep(delay).await;
+}
+
+/// When nvidia-powerd is started in a switch which stops and starts the display manager.
+/// Boot, and switches which leave the display manager running, always start it where
+/// `BeforeDm` does.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum NvidiaPowerdStart {
+    /// Just before the display manager is started
+    #[default]
+    BeforeDm,
+    /// Once the display manager units are active and `delay_ms` has passed, for machines
+    /// where powerd crashes if it races the driver initialising for the display manager
+    AfterDm {
+        #[serde(default)]
+        delay_ms: u64,
+    },
+}
+
+/// How long nvidia-powerd is watched after it was started for it to fail
+pub(crate) const POWERD_CRASH_WATCH: Duration = Duration::from_secs(3);
+/// How often nvidia-powerd is checked while it is watched
+const POWERD_CRASH_POLL: Duration = Duration::from_millis(250);
+
+/// Starts nvidia-powerd and reads whether it failed, so the crash retry can be tested
+/// without systemd
+pub(crate) trait PowerdControl: Sync {
+    fn start(&self) -> Result<(), GfxError>;
+    fn failed(&self) -> bool;
+}
+
+/// `PowerdControl` with `systemctl`
+pub(crate) struct SystemctlPowerd;
+
+impl PowerdControl for SystemctlPowerd {
+    fn start(&self) -> Result<(), GfxError> {
+        toggle_nvidia_powerd(true, GfxVendor::Nvidia)
+    }
+
+    fn failed(&self) -> bool {
+        is_systemd_unit_state(SystemdUnitState::Failed, NVIDIA_POWERD_UNIT).unwrap_or(false)
+    }
+}
+
+/// Whether nvidia-powerd fails within `watch`, checked every `POWERD_CRASH_POLL`
+async fn powerd_crashed(ctl: &dyn PowerdControl, watch: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + watch;
+    loop {
+        if ctl.failed() {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        sleep(POWERD_CRASH_POLL).await;
+    }
+}
+
+/// Start nvidia-powerd and watch it for `watch`. If it fails in that time, as it can when it
+/// races the driver initialising, it is started once more. One which fails again is left
+/// failed with an error logged, as the switch itself has worked.
+pub(crate) async fn start_powerd_watched(
+    ctl: &dyn PowerdControl,
+    watch: Duration,
+) -> Result<(), GfxError> {
+    ctl.start()?;
+    if !powerd_crashed(ctl, watch).await {
+        return Ok(());
+    }
+    warn!("{NVIDIA_POWERD_UNIT} failed within {watch:?} of starting, starting it again");
+    ctl.start()?;
+    if powerd_crashed(ctl, watch).await {
+        error!("{NVIDIA_POWERD_UNIT} failed again after it was restarted");
+    }
+    Ok(())
+}
+
+/// Move `EnableNvidiaPowerd` in a plan which starts the display manager to after it, with
+/// a `WaitDisplayManager` between, if `start` is `AfterDm`
+fn apply_powerd_start(actions: &mut Vec<StagedAction>, start: NvidiaPowerdStart) {
+    let delay_ms = match start {
+        NvidiaPowerdStart::BeforeDm => return,
+        NvidiaPowerdStart::AfterDm { delay_ms } => delay_ms,
+    };
+    let powerd = actions
+        .iter()
+        .position(|action| *action == StagedAction::EnableNvidiaPowerd);
+    let start_dm = actions
+        .iter()
+        .position(|action| *action == StagedAction::StartDisplayManager);
+    if let (Some(powerd), Some(start_dm)) = (powerd, start_dm) {
+        if powerd < start_dm {
+            actions.remove(powerd);
+            // `StartDisplayManager` moved back by one
+            actions.insert(start_dm, StagedAction::WaitDisplayManager(delay_ms));
+            actions.insert(start_dm + 1, StagedAction::EnableNvidiaPowerd);
+        }
+    }
+}
+
 pub enum Action {
     UserAction(UserActionRequired),
     StagedActions(Vec<StagedAction>),
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
 /// The action required by the user after they request a supergfx action
 pub enum UserActionRequired {
     Logout,
@@ -137,19 +386,28 @@ impl From<&UserActionRequired> for &str {
 pub enum StagedAction {
     /// Wait for the user to logout
     WaitLogout,
+    /// Wait this many seconds before the display manager is stopped, emitting a countdown
+    /// each second. Nothing has been changed yet so the switch can still be cancelled.
+    PreStopDelay(u64),
+    /// Wait for other programs, such as a firmware updater, to release blocking shutdown or
+    /// sleep inhibitor locks before the display manager is stopped
+    WaitInhibitors,
     /// Stop the display manager
     StopDisplayManager,
     /// Restart the display manager
     StartDisplayManager,
+    /// Wait for the display manager to be active after `StartDisplayManager`, then this many
+    /// milliseconds, before nvidia-powerd is started with `NvidiaPowerdStart::AfterDm`
+    WaitDisplayManager(u64),
     /// A marker for no logind options
     NoLogind,
     /// Load the dgpu drivers
     LoadGpuDrivers,
     /// Unload the dgpu drivers
     UnloadGpuDrivers,
-    /// Kill all things using the nvidia device
+    /// Kill all things using the nvidia device, except those in `never_kill`
     KillNvidia,
-    /// Kill all things using the AMD device
+    /// Kill the things in `kill_without_prompt` using the AMD device
     KillAmd,
     /// Enable nvidia-persistenced service
     EnableNvidiaPersistenced,
@@ -163,6 +421,8 @@ pub enum StagedAction {
     LoadVfioDrivers,
     /// Unload the vfio modules
     UnloadVfioDrivers,
+    /// Unbind the dGPU from vfio-pci and clear `driver_override`, leaving the modules loaded
+    ReleaseVfioDevices,
     /// A none-action marker to specify an intent, in this case not using ASUS or hotplug device removal and only dev-tree unbind/remove
     DevTreeManaged,
     RescanPci,
@@ -186,6 +446,14 @@ pub enum StagedAction {
     AsusMuxIgpu,
     /// Switch the ASUS MUX to dgpu mode
     AsusMuxDgpu,
+    /// Turn on the vendor toggle with this id, see `special_vendor`
+    SpecialToggleOn(&'static str),
+    /// Turn off the vendor toggle with this id
+    SpecialToggleOff(&'static str),
+    /// Power the removed dGPU off with the `method_off` of `acpi_dgpu_off` through acpi_call
+    AcpiDgpuOff,
+    /// Power the dGPU on with the `method_on` of `acpi_dgpu_off`, before the PCI bus is rescanned
+    AcpiDgpuOn,
     /// Write a modprobe conf according to mode (e.g, hybrid, vfio)
     WriteModprobeConf,
     /// Checks for correct Vulkan ICD (remove nvidia_icd.json if not on "nvidia" or "vfio")
@@ -232,19 +500,19 @@ impl StagedAction {
             Self::NotNvidia
         };
 
-        let hotplug_rm_type = match config.hotplug_type {
+        let hotplug_rm_type = match config.effective_hotplug_type() {
             HotplugType::Std => Self::HotplugUnplug,
             HotplugType::Asus => Self::AsusDgpuDisable,
             HotplugType::None => Self::DevTreeManaged,
         };
 
-        let hotplug_add_type = match config.hotplug_type {
+        let hotplug_add_type = match config.effective_hotplug_type() {
             HotplugType::Std => Self::HotplugPlug,
             HotplugType::Asus => Self::AsusDgpuEnable,
             HotplugType::None => Self::DevTreeManaged,
         };
 
-        match mode {
+        let mut actions = match mode {
             GfxMode::Hybrid => vec![
                 Self::WriteModprobeConf,
                 Self::CheckVulkanIcd,
@@ -289,17 +557,39 @@ impl StagedAction {
                 enable_nvidia_powerd,
             ],
             GfxMode::None => vec![],
+        };
+        if let Some(acpi) = &config.acpi_dgpu_off {
+            apply_acpi_dgpu_boot(Some(acpi), acpi_dgpu_usable(), mode, &mut actions);
         }
+        remove_disabled(&mut actions, &config.disabled_actions);
+        actions
     }
 
     /// Generate a well defined list of specific actions required for the mode switch.
+    /// Switches away from `AsusEgpu` depend on the ASUS toggles, which are read here.
+    pub fn action_list_for_switch(
+        config: &GfxConfig,
+        vendor: GfxVendor,
+        from: GfxMode,
+        to: GfxMode,
+    ) -> Action {
+        let asus = if from == GfxMode::AsusEgpu {
+            AsusToggleState::read()
+        } else {
+            AsusToggleState::default()
+        };
+        Self::action_list_for_switch_with(config, vendor, from, to, asus)
+    }
+
+    /// As `action_list_for_switch` with the ASUS toggles as given
     //
     // There might be some redundancy in this list but it is preferred so as to force checking of all conditions for from/to combos
-    pub fn action_list_for_switch(
+    pub fn action_list_for_switch_with(
         config: &GfxConfig,
         vendor: GfxVendor,
         from: GfxMode,
         to: GfxMode,
+        asus: AsusToggleState,
     ) -> Action {
         let mut wait_logout = Self::NoLogind;
         let mut stop_display = Self::NoLogind;
@@ -325,21 +615,35 @@ impl StagedAction {
             kill_gpu_use = Self::KillAmd;
         }
 
-        let hotplug_rm_type = match config.hotplug_type {
+        let hotplug_rm_type = match config.effective_hotplug_type() {
             HotplugType::Std => Self::HotplugUnplug,
             HotplugType::Asus => Self::AsusDgpuDisable,
             HotplugType::None => Self::DevTreeManaged,
         };
 
-        let hotplug_add_type = match config.hotplug_type {
+        let hotplug_add_type = match config.effective_hotplug_type() {
             HotplugType::Std => Self::HotplugPlug,
             HotplugType::Asus => Self::AsusDgpuEnable,
             HotplugType::None => Self::DevTreeManaged,
         };
 
+        // With the eGPU as the only GPU path the internal dGPU may also have been disabled by
+        // the user. Leave it disabled when leaving for Integrated whatever the hotplug type.
+        let egpu_exit_dgpu_off = if asus.dgpu_disabled {
+            Self::AsusDgpuDisable
+        } else {
+            hotplug_rm_type
+        };
+
+        let leave_vfio = if config.vfio_keep_loaded {
+            Self::ReleaseVfioDevices
+        } else {
+            Self::UnloadVfioDrivers
+        };
+
         // Be verbose in this list of actions. It's okay to have repeated blocks as this makes it much clearer
         // which action chain results from which switching combo
-        match from {
+        let mut actions = match from {
             GfxMode::Hybrid => match to {
                 GfxMode::Integrated => Action::StagedActions(vec![
                     wait_logout,
@@ -475,21 +779,19 @@ impl StagedAction {
             GfxMode::Vfio => match to {
                 GfxMode::Hybrid | GfxMode::NvidiaNoModeset => Action::StagedActions(vec![
                     kill_gpu_use,
-                    Self::UnloadVfioDrivers,
+                    leave_vfio,
                     Self::WriteModprobeConf,
                     Self::CheckVulkanIcd,
                     Self::RescanPci,
                     Self::LoadGpuDrivers,
                 ]),
-                GfxMode::Integrated => Action::StagedActions(vec![
-                    kill_gpu_use,
-                    Self::UnloadVfioDrivers,
-                    Self::UnbindRemoveGpu,
-                ]),
+                GfxMode::Integrated => {
+                    Action::StagedActions(vec![kill_gpu_use, leave_vfio, Self::UnbindRemoveGpu])
+                }
                 GfxMode::AsusEgpu => Action::StagedActions(vec![
                     wait_logout,
                     stop_display,
-                    Self::UnloadVfioDrivers,
+                    leave_vfio,
                     Self::UnbindRemoveGpu,
                     Self::WriteModprobeConf,
                     Self::CheckVulkanIcd,
@@ -520,7 +822,9 @@ impl StagedAction {
                     Self::WriteModprobeConf,
                     Self::CheckVulkanIcd,
                     Self::AsusEgpuDisable,
-                    Self::AsusDgpuEnable, // ensure the dgpu is enabled
+                    // ensure the dgpu is enabled, it may have been disabled along with the
+                    // egpu or by the user. Does nothing if it is already enabled.
+                    Self::AsusDgpuEnable,
                     Self::RescanPci,
                     Self::LoadGpuDrivers,
                     enable_nvidia_persistenced,
@@ -541,7 +845,7 @@ impl StagedAction {
                     Self::UnbindRemoveGpu, // egpu disable also enable dgpu, which can reload the drivers
                     Self::WriteModprobeConf, // TODO: called twice? (why?)
                     Self::CheckVulkanIcd,
-                    hotplug_rm_type, // also need to ensure dgpu is off
+                    egpu_exit_dgpu_off, // also need to ensure dgpu is off
                     start_display,
                 ]),
                 GfxMode::Vfio => Action::UserAction(UserActionRequired::SwitchToIntegrated),
@@ -556,55 +860,179 @@ impl StagedAction {
                 _ => Action::StagedActions(vec![Self::AsusMuxIgpu]),
             },
             GfxMode::None => Action::UserAction(UserActionRequired::Nothing),
+        };
+
+        // Give apps a chance to save state before the display manager is stopped
+        if config.pre_stop_delay_s > 0 {
+            if let Action::StagedActions(list) = &mut actions {
+                if list.first() == Some(&wait_logout) {
+                    list.insert(1, Self::PreStopDelay(config.pre_stop_delay_s));
+                }
+            }
+        }
+        if let Action::StagedActions(list) = &mut actions {
+            remove_disabled(list, &config.disabled_actions);
+            apply_powerd_start(list, config.nvidia_powerd_start);
         }
+
+        actions
+    }
+
+    /// Actions which change nothing on the system. A switch can be cancelled cleanly
+    /// if only these have been performed.
+    pub fn is_cancellable(&self) -> bool {
+        matches!(
+            self,
+            Self::WaitLogout
+                | Self::PreStopDelay(_)
+                | Self::WaitInhibitors
+                | Self::NoLogind
+                | Self::NotNvidia
+                | Self::DevTreeManaged
+                | Self::None
+        )
     }
 
-    /// Do the work required by the action
+    /// Do the work required by the action, as the current action of the sandbox drift check
     pub async fn perform(
         &self,
         changing_to: GfxMode,
         device: &mut DiscreetGpu,
+        settings: &ActionSettings,
         loop_exit: Arc<AtomicBool>,
+        signal_ctxt: Option<&SignalEmitter<'static>>,
+    ) -> Result<(), GfxError> {
+        in_action(
+            *self,
+            self.perform_as_action(changing_to, device, settings, loop_exit, signal_ctxt),
+        )
+        .await
+    }
+
+    async fn perform_as_action(
+        &self,
+        changing_to: GfxMode,
+        device: &mut DiscreetGpu,
+        settings: &ActionSettings,
+        loop_exit: Arc<AtomicBool>,
+        signal_ctxt: Option<&SignalEmitter<'static>>,
     ) -> Result<(), GfxError> {
         match self {
-            StagedAction::WaitLogout => wait_logout(loop_exit).await,
+            StagedAction::WaitLogout => {
+                wait_logout(
+                    &SystemSessionProbe,
+                    &SystemHolderProbe {
+                        dgpu: device.clone(),
+                    },
+                    LogoutPolicy::default(),
+                    &loop_exit,
+                    &Mutex::new(String::new()),
+                    signal_ctxt,
+                )
+                .await
+            }
+            StagedAction::PreStopDelay(seconds) => {
+                pre_stop_countdown(*seconds, loop_exit, signal_ctxt).await
+            }
+            StagedAction::WaitInhibitors => {
+                wait_inhibitors(loop_exit, &Mutex::new(Vec::new()), signal_ctxt).await
+            }
             StagedAction::StopDisplayManager => {
-                do_systemd_unit_action(SystemdUnitAction::Stop, DISPLAY_MANAGER)?;
-                wait_systemd_unit_state(SystemdUnitState::Inactive, DISPLAY_MANAGER)
+                stop_display_managers(&SystemctlDisplayManagers, &settings.display_managers).await
             }
             StagedAction::StartDisplayManager => {
-                do_systemd_unit_action(SystemdUnitAction::Start, DISPLAY_MANAGER)
+                start_display_managers(&SystemctlDisplayManagers, &settings.display_managers)
             }
-            StagedAction::LoadGpuDrivers => device.do_driver_action(DriverAction::Load),
-            StagedAction::UnloadGpuDrivers => device.do_driver_action(DriverAction::Remove),
-            StagedAction::LoadVfioDrivers => do_driver_action("vfio-pci", DriverAction::Load),
-            StagedAction::UnloadVfioDrivers => {
-                for driver in VFIO_DRIVERS.iter() {
-                    do_driver_action(driver, DriverAction::Remove)?;
+            StagedAction::WaitDisplayManager(delay_ms) => {
+                wait_display_managers(
+                    &SystemctlDisplayManagers,
+                    &settings.display_managers,
+                    Duration::from_millis(*delay_ms),
+                )
+                .await;
+                Ok(())
+            }
+            StagedAction::LoadGpuDrivers => {
+                device
+                    .do_driver_action(DriverAction::Load, settings.driver_attempts)
+                    .await?;
+                // nouveau has no /dev/nvidia* nodes
+                if device.is_nvidia() && device.nvidia_driver() != NvidiaDriver::Nouveau {
+                    report_nvidia_nodes(settings.fix_nvidia_nodes, signal_ctxt).await;
                 }
                 Ok(())
             }
-            StagedAction::KillNvidia => kill_nvidia_lsof(),
-            StagedAction::KillAmd => {
-                // TODO: do this
+            StagedAction::UnloadGpuDrivers => device
+                .do_driver_action(DriverAction::Remove, settings.driver_attempts)
+                .await
+                .map_err(|err| with_blocking_processes(err, &blocking_processes(device))),
+            StagedAction::LoadVfioDrivers => {
+                if vfio_pci_loaded() {
+                    bind_vfio(device, &DriverOverrides::system()).await
+                } else {
+                    do_driver_action("vfio-pci", DriverAction::Load, settings.driver_attempts).await
+                }
+            }
+            StagedAction::UnloadVfioDrivers => {
+                release_vfio(device, &DriverOverrides::system()).await?;
+                unload_vfio_modules(settings.driver_attempts).await
+            }
+            StagedAction::ReleaseVfioDevices => {
+                release_vfio(device, &DriverOverrides::system()).await
+            }
+            StagedAction::KillNvidia => kill_gpu_users(&settings.kill_policy, device, true),
+            // Only the processes in `kill_without_prompt`
+            StagedAction::KillAmd => kill_gpu_users(&settings.kill_policy, device, false),
+            StagedAction::EnableNvidiaPersistenced => {
+                toggle_nvidia_persistenced(true, device.vendor())
+            }
+            StagedAction::DisableNvidiaPersistenced => {
+                toggle_nvidia_persistenced(false, device.vendor())
+            }
+            // nvidia-powerd only works with the proprietary driver
+            StagedAction::EnableNvidiaPowerd if device.nvidia_driver() == NvidiaDriver::Nouveau => {
+                debug!("EnableNvidiaPowerd: skipped under nouveau");
                 Ok(())
             }
-            StagedAction::EnableNvidiaPersistenced => toggle_nvidia_persistenced(true, device.vendor()),
-            StagedAction::DisableNvidiaPersistenced => toggle_nvidia_persistenced(false, device.vendor()),
-            StagedAction::EnableNvidiaPowerd => toggle_nvidia_powerd(true, device.vendor()),
+            StagedAction::EnableNvidiaPowerd => {
+                if device.vendor() == GfxVendor::Nvidia
+                    && is_systemd_unit_installed(NVIDIA_POWERD_UNIT)
+                {
+                    start_powerd_watched(&SystemctlPowerd, POWERD_CRASH_WATCH).await
+                } else {
+                    toggle_nvidia_powerd(true, device.vendor())
+                }
+            }
             StagedAction::DisableNvidiaPowerd => toggle_nvidia_powerd(false, device.vendor()),
-            StagedAction::RescanPci => rescan_pci(device),
-            StagedAction::UnbindRemoveGpu => device.unbind_remove(),
-            StagedAction::UnbindGpu => device.unbind(),
+            StagedAction::RescanPci => {
+                let _lock = PciLock::acquire().await;
+                rescan_pci(device).await
+            }
+            StagedAction::UnbindRemoveGpu => {
+                let _lock = PciLock::acquire().await;
+                device.unbind_remove().await
+            }
+            StagedAction::UnbindGpu => device.unbind().await,
             StagedAction::HotplugUnplug => device.set_hotplug(HotplugState::Off),
             StagedAction::HotplugPlug => device.set_hotplug(HotplugState::On),
-            StagedAction::AsusDgpuDisable => asus_dgpu_set_disabled(true),
-            StagedAction::AsusDgpuEnable => asus_dgpu_set_disabled(false),
-            StagedAction::AsusEgpuDisable => asus_egpu_set_enabled(false),
-            StagedAction::AsusEgpuEnable => asus_egpu_set_enabled(true),
+            StagedAction::AsusDgpuDisable => asus_dgpu_set_disabled(true).await,
+            StagedAction::AsusDgpuEnable => asus_dgpu_set_disabled(false).await,
+            StagedAction::AsusEgpuDisable => asus_egpu_set_enabled(false).await,
+            StagedAction::AsusEgpuEnable => asus_egpu_set_enabled(true).await,
             StagedAction::AsusMuxIgpu => asus_gpu_mux_set_igpu(true),
             StagedAction::AsusMuxDgpu => asus_gpu_mux_set_igpu(false),
-            StagedAction::WriteModprobeConf => create_modprobe_conf(changing_to, device),
+            StagedAction::SpecialToggleOn(id) => special_toggle_set(id, true, device).await,
+            StagedAction::SpecialToggleOff(id) => special_toggle_set(id, false, device).await,
+            StagedAction::AcpiDgpuOff | StagedAction::AcpiDgpuOn => {
+                let acpi = settings.acpi.as_ref().ok_or_else(|| {
+                    GfxError::AcpiCall("acpi_dgpu_off isn't set in the config".to_string())
+                })?;
+                let on = *self == StagedAction::AcpiDgpuOn;
+                acpi_dgpu_set(acpi, on, &Sysfs::system(), &SystemAcpiCall::default()).map(|_| ())
+            }
+            StagedAction::WriteModprobeConf => {
+                create_modprobe_conf(changing_to, device, settings.modprobe_extra(changing_to))
+            }
             StagedAction::CheckVulkanIcd => {
                 check_vulkan_icd(changing_to)
                     .map_err(|e| warn!("Vulkan ICD failed: {e:?}"))
@@ -619,82 +1047,1088 @@ impl StagedAction {
     }
 }
 
-/// Check if the user has any graphical uiser sessions that are active or online
-async fn graphical_user_sessions_exist(
-    connection: &Connection,
-    sessions: &[SessionInfo],
-) -> Result<bool, GfxError> {
-    for session in sessions {
-        // should ignore error such as:
-        // Zbus error: org.freedesktop.DBus.Error.UnknownObject: Unknown object '/org/freedesktop/login1/session/c2'
-        if let Ok(session_proxy) = SessionProxy::builder(connection)
-            .path(session.path())?
-            .build()
+/// Check the nvidia driver just loaded has made device nodes apps can open, putting them
+/// right with `fix`. What is still wrong is logged and sent in `NotifyDrift`, the switch
+/// goes on as the driver itself is loaded.
+async fn report_nvidia_nodes(fix: bool, signal_ctxt: Option<&SignalEmitter<'static>>) {
+    let findings = settle_nvidia_nodes(fix).await;
+    if findings.is_empty() {
+        return;
+    }
+    let reported: Vec<String> = findings.iter().map(|finding| finding.to_string()).collect();
+    for finding in &reported {
+        warn!("LoadGpuDrivers: {finding}");
+    }
+    if let Some(ctxt) = signal_ctxt {
+        emit_counted!(ctxt, Signal::Drift, notify_drift(&reported))
             .await
-            .map_err(|e| warn!("graphical_user_sessions_exist: builder: {e:?}"))
-        {
-            if let Ok(type_) = session_proxy.type_().await.map_err(|e| {
-                warn!("graphical_user_sessions_exist: type_: {e:?}");
-                e
-            }) {
-                match type_ {
-                    SessionType::X11 | SessionType::Wayland | SessionType::MIR => {
-                        if let Ok(state) = session_proxy.state().await.map_err(|e| {
-                            warn!("graphical_user_sessions_exist: state: {e:?}");
-                            e
-                        }) {
-                            match state {
-                                SessionState::Online | SessionState::Active => return Ok(true),
-                                SessionState::Closing => {}
-                            }
-                        }
+            .unwrap_or_else(|err| warn!("LoadGpuDrivers: {err}"));
+    }
+}
+
+/// Reads what the actions changed, for `StagedAction::verify_post`, so a system which
+/// doesn't do as it is told can be tested
+pub(crate) trait Readback: Sync {
+    /// The contents of a file, `None` if it can't be read
+    fn read(&self, path: &Path) -> Option<Vec<u8>>;
+    fn exists(&self, path: &Path) -> bool;
+    /// The name of the driver bound to the PCI function at `dev_path`
+    fn driver(&self, dev_path: &Path) -> Option<String>;
+    fn unit_installed(&self, unit: &str) -> bool;
+    /// Wait for `unit` to reach `state`, as `wait_systemd_unit_state`
+    fn wait_unit<'a>(
+        &'a self,
+        state: SystemdUnitState,
+        unit: &'a str,
+    ) -> BoxFuture<'a, Result<(), GfxError>>;
+}
+
+/// The system as it is
+pub(crate) struct SystemReadback;
+
+impl Readback for SystemReadback {
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        std::fs::read(path).ok()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn driver(&self, dev_path: &Path) -> Option<String> {
+        driver_name(dev_path)
+    }
+
+    fn unit_installed(&self, unit: &str) -> bool {
+        is_systemd_unit_installed(unit)
+    }
+
+    fn wait_unit<'a>(
+        &'a self,
+        state: SystemdUnitState,
+        unit: &'a str,
+    ) -> BoxFuture<'a, Result<(), GfxError>> {
+        Box::pin(wait_systemd_unit_state(state, unit))
+    }
+}
+
+const NVIDIA_PERSISTENCED_UNIT: &str = "nvidia-persistenced.service";
+const NVIDIA_POWERD_UNIT: &str = "nvidia-powerd.service";
+
+impl StagedAction {
+    /// Check that the action took effect, for `strict_verify`: a written file reads back
+    /// with the same checksum, modules are in or out of `/sys/module`, removed or unbound
+    /// devices are gone from sysfs, units reach their state and toggles read back the value
+    /// written. Fails with `GfxError::PostCondition` naming what was expected and found.
+    pub(crate) async fn verify_post(
+        &self,
+        changing_to: GfxMode,
+        device: &DiscreetGpu,
+        settings: &ActionSettings,
+        sys: &dyn Readback,
+    ) -> Result<(), GfxError> {
+        let res = match self {
+            StagedAction::WriteModprobeConf => {
+                match modprobe_conf(changing_to, device, settings.modprobe_extra(changing_to))? {
+                    Some(content) => expect_content(sys, Path::new(MODPROBE_PATH), &content),
+                    None => Ok(()),
+                }
+            }
+            StagedAction::LoadGpuDrivers if device.is_nvidia() => {
+                expect_modules(sys, device.nvidia_driver().modules(), true)
+            }
+            StagedAction::UnloadGpuDrivers if device.is_nvidia() => {
+                expect_modules(sys, device.nvidia_driver().modules(), false)
+            }
+            StagedAction::EnableNvidiaPowerd if device.nvidia_driver() == NvidiaDriver::Nouveau => {
+                Ok(())
+            }
+            StagedAction::LoadVfioDrivers => {
+                expect_modules(sys, &["vfio_pci"], true).and_then(|_| {
+                    expect_drivers(sys, device, "bound to vfio-pci", |driver| {
+                        driver == Some("vfio-pci")
+                    })
+                })
+            }
+            StagedAction::UnloadVfioDrivers | StagedAction::ReleaseVfioDevices => {
+                expect_drivers(sys, device, "not bound to vfio-pci", |driver| {
+                    driver != Some("vfio-pci")
+                })
+            }
+            StagedAction::UnbindGpu => {
+                expect_drivers(sys, device, "unbound", |driver| driver.is_none())
+            }
+            StagedAction::UnbindRemoveGpu => expect_devices(sys, device, false),
+            StagedAction::RescanPci => expect_devices(sys, device, true),
+            StagedAction::HotplugPlug | StagedAction::HotplugUnplug => {
+                let state = if *self == StagedAction::HotplugPlug {
+                    HotplugState::On
+                } else {
+                    HotplugState::Off
+                };
+                device
+                    .devices()
+                    .iter()
+                    .filter(|dev| dev.is_dgpu())
+                    .filter_map(|dev| dev.hotplug_path())
+                    .try_for_each(|path| expect_value(sys, path, state.into()))
+            }
+            StagedAction::AsusDgpuDisable => {
+                expect_value(sys, Path::new(ASUS_DGPU_DISABLE_PATH), "1")
+            }
+            StagedAction::AsusDgpuEnable => {
+                expect_value(sys, Path::new(ASUS_DGPU_DISABLE_PATH), "0")
+            }
+            StagedAction::AsusEgpuEnable => {
+                expect_value(sys, Path::new(asus_egpu_enable_path()), "1")
+            }
+            StagedAction::AsusEgpuDisable => {
+                expect_value(sys, Path::new(asus_egpu_enable_path()), "0")
+            }
+            StagedAction::AsusMuxIgpu => expect_value(sys, Path::new(ASUS_GPU_MUX_PATH), "1"),
+            StagedAction::AsusMuxDgpu => expect_value(sys, Path::new(ASUS_GPU_MUX_PATH), "0"),
+            StagedAction::SpecialToggleOn(id) | StagedAction::SpecialToggleOff(id) => {
+                match SpecialToggle::find(id) {
+                    Some(toggle) => {
+                        let value = if matches!(self, StagedAction::SpecialToggleOn(_)) {
+                            toggle.def.on_value
+                        } else {
+                            toggle.def.off_value
+                        };
+                        expect_value(sys, &toggle.path, value)
+                    }
+                    None => Err((format!("the toggle {id}"), "no toggle".to_string())),
+                }
+            }
+            StagedAction::StopDisplayManager => {
+                expect_units(sys, SystemdUnitState::Inactive, &settings.display_managers).await
+            }
+            StagedAction::StartDisplayManager => {
+                expect_units(sys, SystemdUnitState::Active, &settings.display_managers).await
+            }
+            StagedAction::EnableNvidiaPersistenced
+            | StagedAction::DisableNvidiaPersistenced
+            | StagedAction::EnableNvidiaPowerd
+            | StagedAction::DisableNvidiaPowerd
+                if device.vendor() == GfxVendor::Nvidia =>
+            {
+                let (state, unit) = match self {
+                    StagedAction::EnableNvidiaPersistenced => {
+                        (SystemdUnitState::Active, NVIDIA_PERSISTENCED_UNIT)
+                    }
+                    StagedAction::DisableNvidiaPersistenced => {
+                        (SystemdUnitState::Inactive, NVIDIA_PERSISTENCED_UNIT)
+                    }
+                    StagedAction::EnableNvidiaPowerd => {
+                        (SystemdUnitState::Active, NVIDIA_POWERD_UNIT)
                     }
-                    _ => {}
+                    _ => (SystemdUnitState::Inactive, NVIDIA_POWERD_UNIT),
+                };
+                // Not every system has them, starting a missing unit only warns
+                if sys.unit_installed(unit) {
+                    expect_unit(sys, state, unit).await
+                } else {
+                    Ok(())
                 }
             }
+            _ => Ok(()),
+        };
+        res.map_err(|(expected, observed)| {
+            error!("verify_post: {self:?} expected {expected}, found {observed}");
+            GfxError::PostCondition(*self, expected, observed)
+        })
+    }
+}
+
+/// What a post-condition expected and what was found instead
+type PostFailure = (String, String);
+
+/// `path` reads back as `content`, compared by checksum
+fn expect_content(sys: &dyn Readback, path: &Path, content: &[u8]) -> Result<(), PostFailure> {
+    let expected = crc32(content);
+    match sys.read(path) {
+        Some(data) if crc32(&data) == expected => Ok(()),
+        Some(data) => Err((
+            format!("{} with crc32 {expected:08x}", path.display()),
+            format!("crc32 {:08x}", crc32(&data)),
+        )),
+        None => Err((
+            format!("{} with crc32 {expected:08x}", path.display()),
+            "it can't be read".to_string(),
+        )),
+    }
+}
+
+/// The modules are all in `/sys/module` if `loaded`, otherwise none are
+fn expect_modules(sys: &dyn Readback, modules: &[&str], loaded: bool) -> Result<(), PostFailure> {
+    let wrong: Vec<&str> = modules
+        .iter()
+        .copied()
+        .filter(|module| sys.exists(&Path::new("/sys/module").join(module)) != loaded)
+        .collect();
+    if wrong.is_empty() {
+        return Ok(());
+    }
+    let state = if loaded { "loaded" } else { "unloaded" };
+    let found = if loaded { "not loaded" } else { "loaded" };
+    Err((
+        format!("{} {state}", modules.join(", ")),
+        format!("{} {found}", wrong.join(", ")),
+    ))
+}
+
+/// The driver of each dGPU function is `wanted`, described by `expected`
+fn expect_drivers(
+    sys: &dyn Readback,
+    device: &DiscreetGpu,
+    expected: &str,
+    wanted: impl Fn(Option<&str>) -> bool,
+) -> Result<(), PostFailure> {
+    for dev in device.managed_devices() {
+        let driver = sys.driver(dev.dev_path());
+        if !wanted(driver.as_deref()) {
+            return Err((
+                format!("{} {expected}", dev.name()),
+                format!(
+                    "{} bound to {}",
+                    dev.name(),
+                    driver.as_deref().unwrap_or("nothing")
+                ),
+            ));
         }
     }
-    Ok(false)
+    Ok(())
 }
 
-/// It's async because of inner calls, but is a blocking loop
-// TODO: make it a Future
-async fn wait_logout(loop_exit: Arc<AtomicBool>) -> Result<(), GfxError> {
-    loop_exit.store(false, Ordering::Release);
+/// The dGPU functions are all in sysfs if `present`, otherwise none are
+fn expect_devices(
+    sys: &dyn Readback,
+    device: &DiscreetGpu,
+    present: bool,
+) -> Result<(), PostFailure> {
+    match device
+        .managed_devices()
+        .find(|dev| sys.exists(dev.dev_path()) != present)
+    {
+        Some(dev) => {
+            let (expected, found) = if present {
+                ("on the bus", "missing")
+            } else {
+                ("removed", "still on the bus")
+            };
+            Err((
+                format!("{} {expected}", dev.name()),
+                format!("{} {found}", dev.name()),
+            ))
+        }
+        None => Ok(()),
+    }
+}
 
-    const SLEEP_PERIOD: Duration = Duration::from_millis(100);
-    let logout_timeout_s = 30;
-    let start_time = Instant::now();
+/// The sysfs attribute at `path` reads back as `value`
+fn expect_value(sys: &dyn Readback, path: &Path, value: &str) -> Result<(), PostFailure> {
+    let read = sys
+        .read(path)
+        .map(|data| String::from_utf8_lossy(&data).trim().to_string());
+    if read.as_deref() == Some(value) {
+        return Ok(());
+    }
+    Err((
+        format!("{} = {value}", path.display()),
+        read.unwrap_or_else(|| "it can't be read".to_string()),
+    ))
+}
 
-    let connection = Connection::system().await?;
-    let manager = ManagerProxy::new(&connection).await?;
+/// `unit` reaches `state` within the usual wait
+async fn expect_unit(
+    sys: &dyn Readback,
+    state: SystemdUnitState,
+    unit: &str,
+) -> Result<(), PostFailure> {
+    sys.wait_unit(state, unit)
+        .await
+        .map_err(|err| (format!("{unit} {}", <&str>::from(state)), err.to_string()))
+}
 
-    while !loop_exit.load(Ordering::Acquire) {
-        let sessions = manager.list_sessions().await?;
+/// Each of `units` reaches `state` within the usual wait
+async fn expect_units(
+    sys: &dyn Readback,
+    state: SystemdUnitState,
+    units: &[String],
+) -> Result<(), PostFailure> {
+    for unit in units {
+        expect_unit(sys, state, unit).await?;
+    }
+    Ok(())
+}
 
-        if !graphical_user_sessions_exist(&connection, &sessions).await? {
-            break;
+impl StagedAction {
+    /// Verification that the action lists are in the correct order. If incorrect then lockups and other errors can occur
+    pub fn verify_previous_action_for_current(
+        &self,
+        previous_action: StagedAction,
+    ) -> Result<(), GfxError> {
+        if match self {
+            StagedAction::StopDisplayManager => matches!(
+                previous_action,
+                StagedAction::WaitLogout
+                    | StagedAction::PreStopDelay(_)
+                    | StagedAction::WaitInhibitors
+            ),
+            StagedAction::PreStopDelay(_) => {
+                [StagedAction::WaitLogout, StagedAction::NoLogind].contains(&previous_action)
+            }
+            StagedAction::WaitInhibitors => matches!(
+                previous_action,
+                StagedAction::WaitLogout | StagedAction::PreStopDelay(_)
+            ),
+            StagedAction::StartDisplayManager => true,
+            StagedAction::WaitDisplayManager(_) => {
+                previous_action == StagedAction::StartDisplayManager
+            }
+            StagedAction::NoLogind => {
+                matches!(previous_action, StagedAction::PreStopDelay(_))
+                    || [
+                        StagedAction::None,
+                        StagedAction::NoLogind,
+                        StagedAction::HotplugUnplug,
+                        StagedAction::AsusDgpuDisable,
+                        StagedAction::AcpiDgpuOff,
+                        StagedAction::AsusEgpuDisable,
+                        StagedAction::DevTreeManaged,
+                        StagedAction::EnableNvidiaPersistenced,
+                        StagedAction::EnableNvidiaPowerd,
+                        StagedAction::NotNvidia,
+                    ]
+                    .contains(&previous_action)
+            }
+
+            StagedAction::LoadGpuDrivers => previous_action == StagedAction::RescanPci,
+            StagedAction::UnloadGpuDrivers => [
+                StagedAction::StopDisplayManager,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::KillNvidia,
+                StagedAction::KillAmd,
+                StagedAction::NotNvidia,
+                StagedAction::AsusEgpuDisable,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::KillNvidia => [
+                StagedAction::StopDisplayManager,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::KillAmd => [
+                StagedAction::NotNvidia,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::StopDisplayManager,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            // Before the display manager is started, or after it with `AfterDm`
+            StagedAction::EnableNvidiaPowerd => {
+                matches!(previous_action, StagedAction::WaitDisplayManager(_))
+                    || [
+                        StagedAction::DevTreeManaged,
+                        StagedAction::EnableNvidiaPersistenced,
+                        StagedAction::LoadGpuDrivers,
+                        StagedAction::None,
+                    ]
+                    .contains(&previous_action)
+            }
+
+            StagedAction::DisableNvidiaPowerd => [
+                StagedAction::StopDisplayManager,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::NoLogind,
+                StagedAction::RescanPci,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::EnableNvidiaPersistenced => [
+                StagedAction::DevTreeManaged,
+                StagedAction::LoadGpuDrivers,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::DisableNvidiaPersistenced => [
+                StagedAction::StopDisplayManager,
+                StagedAction::NoLogind,
+                StagedAction::RescanPci,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::LoadVfioDrivers => true,
+            StagedAction::UnloadVfioDrivers | StagedAction::ReleaseVfioDevices => true,
+            StagedAction::RescanPci => [
+                StagedAction::None, // Allow None due to VFIO
+                StagedAction::AsusDgpuEnable,
+                StagedAction::AsusDgpuDisable,
+                StagedAction::AsusEgpuEnable,
+                StagedAction::AsusEgpuDisable,
+                StagedAction::HotplugPlug,
+                StagedAction::HotplugUnplug,
+                StagedAction::DevTreeManaged,
+                StagedAction::AcpiDgpuOn,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&previous_action),
+
+            // After the dGPU was removed, however that was done
+            StagedAction::AcpiDgpuOff => [
+                StagedAction::HotplugUnplug,
+                StagedAction::DevTreeManaged,
+                StagedAction::UnbindRemoveGpu,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::AcpiDgpuOn => [
+                StagedAction::HotplugPlug,
+                StagedAction::DevTreeManaged,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::UnbindRemoveGpu => [
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::UnbindGpu => [
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+            ]
+            .contains(&previous_action),
+
+            // Leaving AsusEgpu for Hybrid
+            StagedAction::AsusDgpuEnable => [
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+                StagedAction::AsusEgpuDisable,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::HotplugUnplug
+            | StagedAction::HotplugPlug
+            | StagedAction::AsusDgpuDisable
+            | StagedAction::AsusEgpuDisable
+            | StagedAction::AsusEgpuEnable
+            | StagedAction::DevTreeManaged => [
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::AsusMuxIgpu | StagedAction::SpecialToggleOff(_) => [
+                StagedAction::None,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::NotNvidia,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::AsusMuxDgpu | StagedAction::SpecialToggleOn(_) => [
+                StagedAction::EnableNvidiaPersistenced,
+                StagedAction::EnableNvidiaPowerd,
+                StagedAction::NotNvidia,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::WriteModprobeConf => [
+                StagedAction::StopDisplayManager,
+                StagedAction::NoLogind,
+                StagedAction::UnbindRemoveGpu,
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+                StagedAction::None,
+            ]
+            .contains(&previous_action),
+
+            StagedAction::CheckVulkanIcd
+            | StagedAction::WaitLogout
+            | StagedAction::NotNvidia
+            | StagedAction::None => true,
+        } {
+            Ok(())
+        } else {
+            Err(GfxError::IncorrectActionOrder(*self, previous_action))
+        }
+    }
+
+    pub fn verify_next_allowed_action(
+        &self,
+        next_allowed_action: StagedAction,
+    ) -> Result<(), GfxError> {
+        if match self {
+            StagedAction::WaitLogout => matches!(
+                next_allowed_action,
+                StagedAction::StopDisplayManager
+                    | StagedAction::PreStopDelay(_)
+                    | StagedAction::WaitInhibitors
+            ),
+            StagedAction::PreStopDelay(_) => [
+                StagedAction::StopDisplayManager,
+                StagedAction::NoLogind,
+                StagedAction::WaitInhibitors,
+            ]
+            .contains(&next_allowed_action),
+            StagedAction::WaitInhibitors => next_allowed_action == StagedAction::StopDisplayManager,
+            StagedAction::StopDisplayManager => [
+                StagedAction::EnableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+                StagedAction::KillAmd,
+                StagedAction::KillNvidia,
+                StagedAction::NotNvidia,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::StartDisplayManager => matches!(
+                next_allowed_action,
+                StagedAction::WaitDisplayManager(_) | StagedAction::None
+            ),
+            StagedAction::WaitDisplayManager(_) => {
+                next_allowed_action == StagedAction::EnableNvidiaPowerd
+            }
+            StagedAction::NoLogind => {
+                matches!(next_allowed_action, StagedAction::PreStopDelay(_))
+                    || [
+                        StagedAction::NoLogind,
+                        StagedAction::NotNvidia,
+                        StagedAction::EnableNvidiaPersistenced,
+                        StagedAction::DisableNvidiaPersistenced,
+                        StagedAction::DisableNvidiaPowerd,
+                        StagedAction::WriteModprobeConf,
+                        StagedAction::CheckVulkanIcd,
+                        StagedAction::UnloadVfioDrivers,
+                        StagedAction::ReleaseVfioDevices,
+                    ]
+                    .contains(&next_allowed_action)
+            }
+
+            StagedAction::LoadGpuDrivers => [
+                StagedAction::EnableNvidiaPersistenced,
+                StagedAction::EnableNvidiaPowerd,
+                StagedAction::StartDisplayManager,
+                StagedAction::NotNvidia,
+                StagedAction::None,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::UnloadGpuDrivers => [
+                StagedAction::UnbindGpu,
+                StagedAction::UnbindRemoveGpu,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::KillNvidia => [
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::KillAmd => [
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::EnableNvidiaPowerd => [
+                StagedAction::StartDisplayManager,
+                StagedAction::AsusMuxDgpu,
+                StagedAction::NoLogind,
+                StagedAction::None,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::DisableNvidiaPowerd => {
+                [StagedAction::KillNvidia, StagedAction::KillAmd].contains(&next_allowed_action)
+            }
+
+            StagedAction::EnableNvidiaPersistenced => [
+                StagedAction::EnableNvidiaPowerd,
+                StagedAction::StartDisplayManager,
+                StagedAction::AsusMuxDgpu,
+                StagedAction::NoLogind,
+                StagedAction::None,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::DisableNvidiaPersistenced => [
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::KillNvidia,
+                StagedAction::KillAmd,
+            ]
+            .contains(&next_allowed_action),
+            StagedAction::LoadVfioDrivers => [StagedAction::None].contains(&next_allowed_action),
+            StagedAction::UnloadVfioDrivers | StagedAction::ReleaseVfioDevices => [
+                StagedAction::UnbindRemoveGpu,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::DevTreeManaged => [
+                StagedAction::StartDisplayManager,
+                StagedAction::NoLogind,
+                StagedAction::RescanPci,
+                StagedAction::AcpiDgpuOff,
+                StagedAction::AcpiDgpuOn,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::RescanPci => [
+                StagedAction::LoadGpuDrivers,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::NotNvidia,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::UnbindRemoveGpu => [
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+                StagedAction::AcpiDgpuOff,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::UnbindGpu => {
+                [StagedAction::LoadVfioDrivers].contains(&next_allowed_action)
+            }
+
+            StagedAction::HotplugUnplug => [
+                StagedAction::StartDisplayManager,
+                StagedAction::NoLogind,
+                StagedAction::AcpiDgpuOff,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::HotplugPlug => {
+                [StagedAction::RescanPci, StagedAction::AcpiDgpuOn].contains(&next_allowed_action)
+            }
+            StagedAction::AcpiDgpuOff => {
+                [StagedAction::StartDisplayManager, StagedAction::NoLogind]
+                    .contains(&next_allowed_action)
+            }
+            StagedAction::AcpiDgpuOn => [StagedAction::RescanPci].contains(&next_allowed_action),
+            StagedAction::AsusDgpuDisable => {
+                [StagedAction::StartDisplayManager, StagedAction::NoLogind]
+                    .contains(&next_allowed_action)
+            }
+
+            StagedAction::AsusDgpuEnable => {
+                [StagedAction::RescanPci].contains(&next_allowed_action)
+            }
+
+            StagedAction::AsusEgpuDisable => [
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::AsusDgpuEnable,
+                StagedAction::RescanPci,
+            ]
+            .contains(&next_allowed_action),
+            StagedAction::AsusEgpuEnable => {
+                [StagedAction::RescanPci].contains(&next_allowed_action)
+            }
+
+            StagedAction::AsusMuxIgpu | StagedAction::SpecialToggleOff(_) => {
+                [].contains(&next_allowed_action)
+            }
+            StagedAction::AsusMuxDgpu | StagedAction::SpecialToggleOn(_) => {
+                [].contains(&next_allowed_action)
+            }
+            StagedAction::WriteModprobeConf => [
+                StagedAction::AsusEgpuDisable,
+                StagedAction::AsusEgpuEnable,
+                StagedAction::HotplugUnplug,
+                StagedAction::AsusDgpuDisable,
+                StagedAction::DevTreeManaged,
+                StagedAction::HotplugPlug,
+                StagedAction::AsusDgpuEnable,
+                StagedAction::LoadVfioDrivers,
+                StagedAction::RescanPci,
+                StagedAction::CheckVulkanIcd,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::NotNvidia => [
+                StagedAction::KillAmd,
+                StagedAction::StartDisplayManager,
+                StagedAction::NoLogind,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::None => [
+                StagedAction::RescanPci,
+                StagedAction::NoLogind,
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+                StagedAction::WaitLogout,
+                StagedAction::NotNvidia,
+                StagedAction::KillNvidia,
+                StagedAction::KillAmd,
+                StagedAction::EnableNvidiaPersistenced,
+                StagedAction::DisableNvidiaPersistenced,
+                StagedAction::EnableNvidiaPowerd,
+                StagedAction::DisableNvidiaPowerd,
+                StagedAction::UnloadVfioDrivers,
+                StagedAction::ReleaseVfioDevices,
+            ]
+            .contains(&next_allowed_action),
+
+            StagedAction::CheckVulkanIcd => true,
+        } {
+            Ok(())
+        } else {
+            Err(GfxError::IncorrectActionOrder(next_allowed_action, *self))
+        }
+    }
+}
+
+/// Actions which can't be listed in `disabled_actions`, with the reason. Supergfxd owns the
+/// modprobe conf, and the ASUS toggles keep the dGPU and MUX in a state the firmware expects.
+const NON_DISABLEABLE: &[(&str, &str)] = &[
+    ("WriteModprobeConf", "supergfxd owns the modprobe conf"),
+    (
+        "WaitInhibitors",
+        "use the ignore_inhibitors option of a switch instead",
+    ),
+    (
+        "WaitDisplayManager",
+        "set nvidia_powerd_start to BeforeDm instead",
+    ),
+    ("AsusDgpuDisable", "it is an ASUS safety toggle"),
+    ("AsusDgpuEnable", "it is an ASUS safety toggle"),
+    ("AsusEgpuDisable", "it is an ASUS safety toggle"),
+    ("AsusEgpuEnable", "it is an ASUS safety toggle"),
+    ("AsusMuxIgpu", "it is an ASUS safety toggle"),
+    ("AsusMuxDgpu", "it is an ASUS safety toggle"),
+];
+
+/// One of each action, for listing the ids
+const ALL_ACTIONS: &[StagedAction] = &[
+    StagedAction::WaitLogout,
+    StagedAction::PreStopDelay(0),
+    StagedAction::WaitInhibitors,
+    StagedAction::StopDisplayManager,
+    StagedAction::StartDisplayManager,
+    StagedAction::WaitDisplayManager(0),
+    StagedAction::NoLogind,
+    StagedAction::LoadGpuDrivers,
+    StagedAction::UnloadGpuDrivers,
+    StagedAction::KillNvidia,
+    StagedAction::KillAmd,
+    StagedAction::EnableNvidiaPersistenced,
+    StagedAction::DisableNvidiaPersistenced,
+    StagedAction::EnableNvidiaPowerd,
+    StagedAction::DisableNvidiaPowerd,
+    StagedAction::LoadVfioDrivers,
+    StagedAction::UnloadVfioDrivers,
+    StagedAction::ReleaseVfioDevices,
+    StagedAction::DevTreeManaged,
+    StagedAction::RescanPci,
+    StagedAction::UnbindRemoveGpu,
+    StagedAction::UnbindGpu,
+    StagedAction::HotplugUnplug,
+    StagedAction::HotplugPlug,
+    StagedAction::AsusDgpuDisable,
+    StagedAction::AsusDgpuEnable,
+    StagedAction::AsusEgpuDisable,
+    StagedAction::AsusEgpuEnable,
+    StagedAction::AsusMuxIgpu,
+    StagedAction::AsusMuxDgpu,
+    StagedAction::SpecialToggleOn(""),
+    StagedAction::SpecialToggleOff(""),
+    StagedAction::AcpiDgpuOff,
+    StagedAction::AcpiDgpuOn,
+    StagedAction::WriteModprobeConf,
+    StagedAction::CheckVulkanIcd,
+    StagedAction::NotNvidia,
+    StagedAction::None,
+];
+
+impl StagedAction {
+    /// The name used for the action in `disabled_actions`, the variant name
+    pub fn id(&self) -> &'static str {
+        match self {
+            StagedAction::WaitLogout => "WaitLogout",
+            StagedAction::PreStopDelay(_) => "PreStopDelay",
+            StagedAction::WaitInhibitors => "WaitInhibitors",
+            StagedAction::StopDisplayManager => "StopDisplayManager",
+            StagedAction::StartDisplayManager => "StartDisplayManager",
+            StagedAction::WaitDisplayManager(_) => "WaitDisplayManager",
+            StagedAction::NoLogind => "NoLogind",
+            StagedAction::LoadGpuDrivers => "LoadGpuDrivers",
+            StagedAction::UnloadGpuDrivers => "UnloadGpuDrivers",
+            StagedAction::KillNvidia => "KillNvidia",
+            StagedAction::KillAmd => "KillAmd",
+            StagedAction::EnableNvidiaPersistenced => "EnableNvidiaPersistenced",
+            StagedAction::DisableNvidiaPersistenced => "DisableNvidiaPersistenced",
+            StagedAction::EnableNvidiaPowerd => "EnableNvidiaPowerd",
+            StagedAction::DisableNvidiaPowerd => "DisableNvidiaPowerd",
+            StagedAction::LoadVfioDrivers => "LoadVfioDrivers",
+            StagedAction::UnloadVfioDrivers => "UnloadVfioDrivers",
+            StagedAction::ReleaseVfioDevices => "ReleaseVfioDevices",
+            StagedAction::DevTreeManaged => "DevTreeManaged",
+            StagedAction::RescanPci => "RescanPci",
+            StagedAction::UnbindRemoveGpu => "UnbindRemoveGpu",
+            StagedAction::UnbindGpu => "UnbindGpu",
+            StagedAction::HotplugUnplug => "HotplugUnplug",
+            StagedAction::HotplugPlug => "HotplugPlug",
+            StagedAction::AsusDgpuDisable => "AsusDgpuDisable",
+            StagedAction::AsusDgpuEnable => "AsusDgpuEnable",
+            StagedAction::AsusEgpuDisable => "AsusEgpuDisable",
+            StagedAction::AsusEgpuEnable => "AsusEgpuEnable",
+            StagedAction::AsusMuxIgpu => "AsusMuxIgpu",
+            StagedAction::AsusMuxDgpu => "AsusMuxDgpu",
+            StagedAction::SpecialToggleOn(_) => "SpecialToggleOn",
+            StagedAction::SpecialToggleOff(_) => "SpecialToggleOff",
+            StagedAction::AcpiDgpuOff => "AcpiDgpuOff",
+            StagedAction::AcpiDgpuOn => "AcpiDgpuOn",
+            StagedAction::WriteModprobeConf => "WriteModprobeConf",
+            StagedAction::CheckVulkanIcd => "CheckVulkanIcd",
+            StagedAction::NotNvidia => "NotNvidia",
+            StagedAction::None => "None",
+        }
+    }
+}
+
+/// The stable name of the action: `id`, with the argument of those which have one such as
+/// `PreStopDelay(5)` or `SpecialToggleOn(legion_gsync)`
+impl Display for StagedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StagedAction::PreStopDelay(seconds) => write!(f, "{}({seconds})", self.id()),
+            StagedAction::WaitDisplayManager(delay_ms) => write!(f, "{}({delay_ms})", self.id()),
+            StagedAction::SpecialToggleOn(id) | StagedAction::SpecialToggleOff(id) => {
+                write!(f, "{}({id})", self.id())
+            }
+            _ => write!(f, "{}", self.id()),
+        }
+    }
+}
+
+/// Remove the actions named in `disabled` from a plan
+pub(crate) fn remove_disabled(actions: &mut Vec<StagedAction>, disabled: &[String]) {
+    if !disabled.is_empty() {
+        actions.retain(|action| !disabled.iter().any(|id| id == action.id()));
+    }
+}
+
+/// Check that removing the `disabled` actions from `actions` leaves a valid order. Only the
+/// actions which become neighbours are checked, as in the order tests a plan starts after
+/// `None`.
+fn verify_removals(actions: &[StagedAction], disabled: &[String]) -> Result<(), String> {
+    let mut previous = StagedAction::None;
+    let mut removed: Vec<&str> = Vec::new();
+    for action in actions {
+        if disabled.iter().any(|id| id == action.id()) {
+            removed.push(action.id());
+            continue;
+        }
+        if !removed.is_empty() {
+            action
+                .verify_previous_action_for_current(previous)
+                .and_then(|_| previous.verify_next_allowed_action(*action))
+                .map_err(|err| {
+                    format!(
+                        "removing {} puts {previous:?} before {action:?}: {err}",
+                        removed.join(", ")
+                    )
+                })?;
+            removed.clear();
         }
+        previous = *action;
+    }
+    Ok(())
+}
 
-        // exit if 3 minutes pass
-        if logout_timeout_s != 0
-            && Instant::now().duration_since(start_time).as_secs() > logout_timeout_s
-        {
-            let detail = format!("Time ({} seconds) for logout exceeded", logout_timeout_s);
-            warn!("mode_change_loop: {}", detail);
-            return Err(GfxError::SystemdUnitWaitTimeout(detail));
+/// Check `config.disabled_actions` names only known actions which may be disabled, and that
+/// no boot or switch plan is left in an invalid order by removing them. The plans are made
+/// with the rest of `config` as it is.
+pub(crate) fn validate_disabled_actions(config: &GfxConfig) -> Result<(), GfxError> {
+    let disabled = &config.disabled_actions;
+    if disabled.is_empty() {
+        return Ok(());
+    }
+    for id in disabled {
+        if let Some((_, reason)) = NON_DISABLEABLE.iter().find(|(name, _)| name == id) {
+            return Err(GfxError::DisabledActions(format!(
+                "{id} can't be disabled, {reason}"
+            )));
+        }
+        if !ALL_ACTIONS.iter().any(|action| action.id() == id) {
+            return Err(GfxError::DisabledActions(format!("unknown action {id}")));
         }
+    }
 
-        // Don't spin at max speed
-        sleep(SLEEP_PERIOD).await;
+    let full = GfxConfig {
+        disabled_actions: Vec::new(),
+        ..config.clone()
+    };
+    let modes = [
+        GfxMode::Hybrid,
+        GfxMode::Integrated,
+        GfxMode::NvidiaNoModeset,
+        GfxMode::Vfio,
+        GfxMode::AsusEgpu,
+        GfxMode::AsusMuxDgpu,
+    ];
+    for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
+        for to in modes {
+            let boot = StagedAction::action_list_for_boot(&full, vendor, to);
+            verify_removals(&boot, disabled).map_err(|err| {
+                GfxError::DisabledActions(format!("booting in {to} ({vendor:?}), {err}"))
+            })?;
+            for from in modes {
+                for dgpu_disabled in [false, true] {
+                    let plan = StagedAction::action_list_for_switch_with(
+                        &full,
+                        vendor,
+                        from,
+                        to,
+                        AsusToggleState { dgpu_disabled },
+                    );
+                    if let Action::StagedActions(plan) = plan {
+                        verify_removals(&plan, disabled).map_err(|err| {
+                            GfxError::DisabledActions(format!(
+                                "switching {from} to {to} ({vendor:?}), {err}"
+                            ))
+                        })?;
+                    }
+                }
+            }
+        }
     }
+    Ok(())
+}
 
+/// Read what the wait for the logout needs of `session`. A property which can't be read is
+/// left `None`, such as a class newer than logind-zbus knows, and `None` is returned if
+/// the session is gone.
+async fn read_session(connection: &Connection, session: &SessionInfo) -> Option<LogindSession> {
+    // should ignore error such as:
+    // Zbus error: org.freedesktop.DBus.Error.UnknownObject: Unknown object '/org/freedesktop/login1/session/c2'
+    let proxy = SessionProxy::builder(connection)
+        .path(session.path())
+        .ok()?
+        .build()
+        .await
+        .map_err(|e| warn!("read_session: builder: {e:?}"))
+        .ok()?;
+    let id = session.sid();
+    Some(LogindSession {
+        id: id.to_string(),
+        kind: proxy
+            .type_()
+            .await
+            .map_err(|e| debug!("read_session: {id}: type: {e:?}"))
+            .ok(),
+        class: proxy
+            .class()
+            .await
+            .map_err(|e| debug!("read_session: {id}: class: {e:?}"))
+            .ok(),
+        state: proxy
+            .state()
+            .await
+            .map_err(|e| debug!("read_session: {id}: state: {e:?}"))
+            .ok(),
+        desktop: proxy.desktop().await.unwrap_or_default(),
+    })
+}
+
+/// The ids of the `sessions` which keep a switch waiting for the logout, as decided by
+/// `session_blocks_logout`. Each session considered is logged.
+async fn blocking_session_ids(connection: &Connection, sessions: &[SessionInfo]) -> Vec<String> {
+    let mut ids = Vec::new();
+    for info in sessions {
+        let session = match read_session(connection, info).await {
+            Some(session) => session,
+            None => continue,
+        };
+        let blocks = session_blocks_logout(&session);
+        debug!(
+            "logind session {}: type {:?}, class {:?}, state {:?}, desktop {:?}, blocks logout: {blocks}",
+            session.id, session.kind, session.class, session.state, session.desktop
+        );
+        if blocks {
+            ids.push(session.id);
+        }
+    }
+    ids
+}
+
+/// The ids of the graphical sessions which are active or online right now
+pub(crate) async fn graphical_session_ids() -> Result<Vec<String>, GfxError> {
+    let connection = Connection::system().await?;
+    let manager = ManagerProxy::new(&connection).await?;
+    let sessions = manager.list_sessions().await?;
+    Ok(blocking_session_ids(&connection, &sessions).await)
+}
+
+/// Check if any graphical user session is active or online right now
+pub(crate) async fn graphical_sessions_active() -> Result<bool, GfxError> {
+    Ok(!graphical_session_ids().await?.is_empty())
+}
+
+/// Count down `seconds` before the display manager is stopped, emitting the time remaining
+/// each second. Returns early if `loop_exit` is set, e.g. by a cancel request.
+async fn pre_stop_countdown(
+    seconds: u64,
+    loop_exit: Arc<AtomicBool>,
+    signal_ctxt: Option<&SignalEmitter<'static>>,
+) -> Result<(), GfxError> {
     loop_exit.store(false, Ordering::Release);
-    debug!("wait_logout: loop exited");
+
+    const TICKS_PER_SECOND: u32 = 10;
+    for remaining in (1..=seconds).rev() {
+        debug!("pre_stop_countdown: {remaining}s until the display manager is stopped");
+        if let Some(ctxt) = signal_ctxt {
+            emit_counted!(
+                ctxt,
+                Signal::SwitchCountdown,
+                notify_switch_countdown(remaining)
+            )
+            .await
+            .unwrap_or_else(|err| warn!("pre_stop_countdown: {err}"));
+        }
+        for _ in 0..TICKS_PER_SECOND {
+            if loop_exit.load(Ordering::Acquire) {
+                debug!("pre_stop_countdown: loop exited");
+                return Ok(());
+            }
+            sleep(Duration::from_secs(1) / TICKS_PER_SECOND).await;
+        }
+    }
     Ok(())
 }
 
-fn rescan_pci(device: &mut DiscreetGpu) -> Result<(), GfxError> {
+async fn rescan_pci(device: &mut DiscreetGpu) -> Result<(), GfxError> {
     // Don't do a rescan unless the dev list is empty. This might be the case if
     // asus dgpu_disable is set before the daemon starts. But in general the daemon
     // should have the correct device on boot and retain that.
@@ -709,13 +2143,17 @@ fn rescan_pci(device: &mut DiscreetGpu) -> Result<(), GfxError> {
 
     if do_find_device {
         info!("do_rescan: Device rescan required");
-        match DiscreetGpu::new() {
-            Ok(dev) => *device = dev,
-            Err(e) => warn!("do_rescan: tried to reset Unknown dgpu status/devices: {e:?}"),
+        if let Err(e) = device.refresh() {
+            warn!("do_rescan: tried to reset Unknown dgpu status/devices: {e:?}");
         }
     } else {
         info!("do_rescan: Rescanning PCI bus");
         rescan_pci_bus()?; // should force re-attach of driver
+        let expected: Vec<String> = device
+            .managed_devices()
+            .map(|dev| dev.name().to_string())
+            .collect();
+        wait_for_pci_settle(&expected).await?;
     }
 
     Ok(())
@@ -0,0 +1,200 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{pci_device::GfxMode, sandbox::note_write, STATE_DIR};
+
+/// The items are kept here so they outlast a reboot until dismissed or expired
+const ATTENTION_NAME: &str = "attention.json";
+/// Changes on every boot, so a restart of the daemon isn't counted as one
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Some boot tasks failed, graphics may not work
+pub const ATTENTION_DEGRADED_BOOT: &str = "degraded_boot";
+/// The boot tasks couldn't be run at all
+pub const ATTENTION_BOOT_FAILED: &str = "boot_failed";
+/// `gpu_mux_mode` couldn't be read at boot and the MUX was assumed discreet
+pub const ATTENTION_MUX_ASSUMED: &str = "mux_assumed";
+/// The configured mode needs the iGPU, which wasn't found, so Hybrid was booted
+pub const ATTENTION_IGPU_FALLBACK: &str = "igpu_fallback";
+/// The configured `hotplug_type` can't be used on this machine and None is used
+pub const ATTENTION_HOTPLUG_DOWNGRADE: &str = "hotplug_downgrade";
+/// A graphical session was running so the reduced boot path was used
+pub const ATTENTION_REDUCED_BOOT: &str = "reduced_boot";
+
+/// Something supergfxd did differently at boot which the user should know about. Kept until
+/// dismissed, so it isn't lost when no frontend was connected to see the signal.
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct AttentionItem {
+    /// Stable across releases, such as `mux_assumed`, see the `ATTENTION_` constants
+    pub code: String,
+    pub message: String,
+    /// Seconds since the epoch when it was last registered
+    pub timestamp: u64,
+    /// The mode booted into
+    pub mode: GfxMode,
+    /// How many boots since it was last registered, it expires after
+    /// `attention_expiry_boots`
+    pub boots: u32,
+}
+
+/// What is kept in `STATE_DIR`
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct AttentionFile {
+    /// The boot the items were last aged on
+    boot_id: String,
+    items: Vec<AttentionItem>,
+}
+
+/// What the boot did differently from a normal boot, each registered as an attention item
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct BootDeviations {
+    /// The boot tasks which failed, with the error
+    pub failures: Vec<String>,
+    pub mux_assumed: bool,
+    pub igpu_fallback: Option<String>,
+    pub hotplug_downgrade: Option<String>,
+    pub reduced: Option<String>,
+}
+
+impl BootDeviations {
+    /// The code and message of each deviation
+    pub(crate) fn attention(&self) -> Vec<(&'static str, String)> {
+        let mut items = Vec::new();
+        if !self.failures.is_empty() {
+            items.push((
+                ATTENTION_DEGRADED_BOOT,
+                format!("Boot tasks failed: {}", self.failures.join("; ")),
+            ));
+        }
+        if self.mux_assumed {
+            items.push((
+                ATTENTION_MUX_ASSUMED,
+                "gpu_mux_mode couldn't be read at boot, the MUX was assumed discreet".to_string(),
+            ));
+        }
+        let reasons = [
+            (ATTENTION_IGPU_FALLBACK, &self.igpu_fallback),
+            (ATTENTION_HOTPLUG_DOWNGRADE, &self.hotplug_downgrade),
+            (ATTENTION_REDUCED_BOOT, &self.reduced),
+        ];
+        for (code, reason) in reasons {
+            if let Some(reason) = reason {
+                items.push((code, reason.clone()));
+            }
+        }
+        items
+    }
+}
+
+/// The attention items registered at boot and not dismissed yet
+#[derive(Debug, Default)]
+pub(crate) struct AttentionBoard {
+    /// Where the items are kept, `None` to keep them in memory only
+    path: Option<PathBuf>,
+    boot_id: String,
+    items: Vec<AttentionItem>,
+    /// The items were sent to the first client which asked for the status
+    announced: bool,
+}
+
+impl AttentionBoard {
+    /// Keep the items in memory only, as for a debug run
+    pub(crate) fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// The items of the running system in `STATE_DIR`, see `new`
+    pub(crate) fn system(expiry_boots: u32) -> Self {
+        let boot_id = fs::read_to_string(BOOT_ID_PATH)
+            .map(|id| id.trim().to_string())
+            .unwrap_or_default();
+        Self::new(
+            Path::new(STATE_DIR).join(ATTENTION_NAME),
+            boot_id,
+            expiry_boots,
+        )
+    }
+
+    /// Load the items kept at `path`. If they were kept on another boot than `boot_id` they
+    /// are a boot older, and those registered `expiry_boots` boots ago are dropped. `0`
+    /// keeps them until dismissed.
+    pub(crate) fn new(path: PathBuf, boot_id: String, expiry_boots: u32) -> Self {
+        let file: AttentionFile = fs::read_to_string(&path)
+            .ok()
+            .and_then(|buf| serde_json::from_str(&buf).ok())
+            .unwrap_or_default();
+        let mut board = Self {
+            path: Some(path),
+            boot_id,
+            items: file.items,
+            announced: false,
+        };
+        if file.boot_id != board.boot_id {
+            for item in &mut board.items {
+                item.boots += 1;
+            }
+            board
+                .items
+                .retain(|item| expiry_boots == 0 || item.boots < expiry_boots);
+            board.save();
+        }
+        board
+    }
+
+    pub(crate) fn items(&self) -> &[AttentionItem] {
+        &self.items
+    }
+
+    /// Register `code` at `now`, seconds since the epoch, replacing an item with the same
+    /// code as it happened again
+    pub(crate) fn register(&mut self, code: &str, message: String, mode: GfxMode, now: u64) {
+        self.items.retain(|item| item.code != code);
+        self.items.push(AttentionItem {
+            code: code.to_string(),
+            message,
+            timestamp: now,
+            mode,
+            boots: 0,
+        });
+        self.save();
+    }
+
+    /// Drop the item with `code`, returns it if there was one
+    pub(crate) fn dismiss(&mut self, code: &str) -> Option<AttentionItem> {
+        let index = self.items.iter().position(|item| item.code == code)?;
+        let item = self.items.remove(index);
+        self.save();
+        Some(item)
+    }
+
+    /// The items to send to the first client to ask for the status since the daemon
+    /// started, `None` once that was done or if there are none
+    pub(crate) fn take_announcement(&mut self) -> Option<Vec<AttentionItem>> {
+        if std::mem::replace(&mut self.announced, true) || self.items.is_empty() {
+            return None;
+        }
+        Some(self.items.clone())
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        note_write(path);
+        let file = AttentionFile {
+            boot_id: self.boot_id.clone(),
+            items: self.items.clone(),
+        };
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, serde_json::to_string(&file).unwrap_or_default()))
+            .unwrap_or_else(|err| warn!("attention: {}: {err}", path.display()));
+    }
+}
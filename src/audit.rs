@@ -0,0 +1,266 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use zbus::{message::Header, names::BusName, zvariant::Type, Connection};
+
+use crate::{buffers::BoundedQueue, error::GfxError, sandbox::note_write, STATE_DIR};
+
+/// The audit log under `STATE_DIR`
+const AUDIT_LOG_NAME: &str = "audit.log";
+/// Size at which the log is rotated
+const AUDIT_LOG_MAX_BYTES: u64 = 256 * 1024;
+/// Rotated logs kept, as `audit.log.1` (newest) to `audit.log.<n>`
+const AUDIT_LOG_KEEP: usize = 3;
+
+/// Who made a change recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Actor {
+    /// A dbus client, by its unique bus name such as `:1.42`, with its uid if it was
+    /// looked up
+    Bus { sender: String, uid: Option<u32> },
+    /// The boot safety checks
+    Boot,
+    /// `supergfxd.mode=` on the kernel cmdline
+    Cmdline,
+    /// The daemon itself, such as the AC automation or a self-test
+    Daemon,
+    /// An entry of `schedule` in the config
+    Schedule,
+}
+
+impl Actor {
+    /// The sender of a dbus method call
+    pub fn from_header(header: &Header<'_>) -> Self {
+        let sender = match header.sender() {
+            Some(sender) => sender.to_string(),
+            None => "unknown".to_string(),
+        };
+        Self::Bus { sender, uid: None }
+    }
+
+    /// The sender of a dbus method call with its uid, asked of the bus. The uid is `None`
+    /// if the bus couldn't tell.
+    pub async fn from_call(connection: &Connection, header: &Header<'_>) -> Self {
+        match Self::from_header(header) {
+            Self::Bus { sender, .. } => Self::Bus {
+                uid: sender_uid(connection, header).await.ok(),
+                sender,
+            },
+            actor => actor,
+        }
+    }
+
+    /// The uid of a dbus client
+    pub fn uid(&self) -> Option<u32> {
+        match self {
+            Self::Bus { uid, .. } => *uid,
+            _ => None,
+        }
+    }
+}
+
+/// The uid of the sender of a dbus method call, asked of the bus
+pub(crate) async fn sender_uid(connection: &Connection, header: &Header<'_>) -> zbus::Result<u32> {
+    let sender = header.sender().ok_or(zbus::Error::MissingField)?;
+    Ok(zbus::fdo::DBusProxy::new(connection)
+        .await?
+        .get_connection_unix_user(BusName::Unique(sender.clone()))
+        .await?)
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bus { sender, .. } => write!(f, "{sender}"),
+            Self::Boot => write!(f, "boot"),
+            Self::Cmdline => write!(f, "cmdline"),
+            Self::Daemon => write!(f, "supergfxd"),
+            Self::Schedule => write!(f, "schedule"),
+        }
+    }
+}
+
+/// One decision in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct AuditRecord {
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    /// A bus name such as `:1.42`, or `boot`, `cmdline`, `schedule` or `supergfxd`
+    pub actor: String,
+    /// e.g `mode Hybrid -> Integrated`
+    pub change: String,
+}
+
+impl AuditRecord {
+    /// The line written to the log, tab separated. Tabs and newlines in the fields are
+    /// replaced so a record is always one line.
+    pub(crate) fn to_line(&self) -> String {
+        let clean = |s: &str| s.replace(['\t', '\n', '\r'], " ");
+        format!(
+            "{}\t{}\t{}\n",
+            self.timestamp,
+            clean(&self.actor),
+            clean(&self.change)
+        )
+    }
+
+    /// Parse a line written by `to_line`, `None` if it is malformed
+    pub(crate) fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.trim_end_matches('\n').splitn(3, '\t');
+        Some(Self {
+            timestamp: fields.next()?.parse().ok()?,
+            actor: fields.next()?.to_string(),
+            change: fields.next()?.to_string(),
+        })
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC for seconds since the epoch
+pub fn format_timestamp(secs: u64) -> String {
+    // Howard Hinnant's days-to-civil
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let secs = secs % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// An append-only log of mode and config decisions, rotated by size
+#[derive(Debug)]
+pub struct AuditLog {
+    /// `None` to not record anything, as in tests and debug runs
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    /// Serialises appends with rotation
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            path: Some(path),
+            max_bytes,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The log in `STATE_DIR`
+    pub fn system() -> Self {
+        Self::new(
+            Path::new(STATE_DIR).join(AUDIT_LOG_NAME),
+            AUDIT_LOG_MAX_BYTES,
+        )
+    }
+
+    /// A log which records nothing
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            max_bytes: 0,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn rotated(path: &Path, n: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    /// Append a record of `change` by `actor`. A failure to write is logged, it never fails
+    /// the change itself.
+    pub fn record(&self, actor: &Actor, change: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let record = AuditRecord {
+            timestamp,
+            actor: actor.to_string(),
+            change: change.to_string(),
+        };
+        info!("audit: {actor}: {change}");
+        self.append(&record)
+            .unwrap_or_else(|err| error!("Could not write the audit log: {err}"));
+    }
+
+    pub(crate) fn append(&self, record: &AuditRecord) -> Result<(), GfxError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let line = record.to_line();
+        note_write(path);
+        let size = fs::metadata(path).map_or(0, |m| m.len());
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate(path)?;
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| GfxError::from_io(err, dir.into()))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|err| GfxError::Write(path.display().to_string(), err))
+    }
+
+    /// Shift `audit.log.<n>` up by one, dropping the oldest, and move the log to `.1`
+    fn rotate(&self, path: &Path) -> Result<(), GfxError> {
+        for n in (1..AUDIT_LOG_KEEP).rev() {
+            let from = Self::rotated(path, n);
+            if from.exists() {
+                let to = Self::rotated(path, n + 1);
+                fs::rename(&from, &to).map_err(|err| GfxError::from_io(err, from))?;
+            }
+        }
+        let to = Self::rotated(path, 1);
+        fs::rename(path, &to).map_err(|err| GfxError::from_io(err, path.into()))
+    }
+
+    /// The last `n` records, oldest first, including those in the rotated logs
+    pub fn tail(&self, n: usize) -> Vec<AuditRecord> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut files: Vec<PathBuf> = (1..=AUDIT_LOG_KEEP)
+            .rev()
+            .map(|i| Self::rotated(path, i))
+            .collect();
+        files.push(path.clone());
+        // Only the last `n` are held while reading, not every rotated log
+        let mut records = BoundedQueue::new("audit_tail", n);
+        for buf in files
+            .iter()
+            .filter_map(|file| fs::read_to_string(file).ok())
+        {
+            for record in buf.lines().filter_map(AuditRecord::from_line) {
+                records.push(record);
+            }
+        }
+        records.into_vec()
+    }
+}
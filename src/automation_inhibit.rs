@@ -0,0 +1,220 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::{lock::Mutex, StreamExt};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use zbus::{fdo::DBusProxy, zvariant::Type};
+
+use crate::{
+    audit::{Actor, AuditLog},
+    controller::CtrlGraphics,
+    error::GfxError,
+    signal_filter::signal_filter_owner_gone,
+    supervisor::RestartPolicy,
+};
+
+/// The longest an inhibition can be asked for, it must end by itself
+pub(crate) const MAX_INHIBIT: Duration = Duration::from_secs(12 * 3600);
+
+/// A client asked supergfxd not to do anything by itself for a while, such as during a
+/// presentation or a benchmark. In `GfxStatus`.
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct AutomationInhibition {
+    /// Given to `Uninhibit` to end it early
+    pub cookie: u32,
+    /// The unique bus name of the client which asked, such as `:1.42`
+    pub owner: String,
+    pub reason: String,
+    /// Seconds since the epoch when it ends
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Inhibition {
+    public: AutomationInhibition,
+    until: Instant,
+}
+
+/// The inhibitions of the automatic behaviours: the suggestions and switches of
+/// `ac_automation`, the thermal advisory, the periodic verification and the change to the
+/// fast power poll. Each ends when it expires, when its owner removes it, or when its
+/// owner leaves the bus. A mode switch asked for by a client is never inhibited.
+#[derive(Debug, Default)]
+pub(crate) struct InhibitRegistry {
+    next_cookie: u32,
+    active: Vec<Inhibition>,
+}
+
+impl InhibitRegistry {
+    /// Add an inhibition for `owner` lasting `duration` from `now`, returning its cookie.
+    /// `duration` must be at least a second and at most `MAX_INHIBIT`.
+    pub(crate) fn add(
+        &mut self,
+        owner: &str,
+        reason: &str,
+        duration: Duration,
+        now: Instant,
+    ) -> Result<u32, GfxError> {
+        if duration.as_secs() == 0 || duration > MAX_INHIBIT {
+            return Err(GfxError::InvalidInhibition(format!(
+                "it must last from 1 to {} seconds, not {}",
+                MAX_INHIBIT.as_secs(),
+                duration.as_secs()
+            )));
+        }
+        if reason.trim().is_empty() {
+            return Err(GfxError::InvalidInhibition(
+                "a reason must be given".to_string(),
+            ));
+        }
+        self.expire(now);
+        self.next_cookie = self.next_cookie.wrapping_add(1).max(1);
+        let expires = SystemTime::now()
+            .checked_add(duration)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        self.active.push(Inhibition {
+            public: AutomationInhibition {
+                cookie: self.next_cookie,
+                owner: owner.to_string(),
+                reason: reason.trim().to_string(),
+                expires,
+            },
+            until: now + duration,
+        });
+        Ok(self.next_cookie)
+    }
+
+    /// Remove the inhibition `cookie`, which only `owner` can do
+    pub(crate) fn remove(
+        &mut self,
+        cookie: u32,
+        owner: &str,
+    ) -> Result<AutomationInhibition, GfxError> {
+        let i = self
+            .active
+            .iter()
+            .position(|i| i.public.cookie == cookie && i.public.owner == owner)
+            .ok_or(GfxError::InvalidInhibition(format!(
+                "{owner} holds no inhibition {cookie}"
+            )))?;
+        Ok(self.active.remove(i).public)
+    }
+
+    /// Remove the inhibitions of `owner`, which has left the bus
+    pub(crate) fn owner_gone(&mut self, owner: &str) -> Vec<AutomationInhibition> {
+        let (gone, kept) = self.active.drain(..).partition(|i| i.public.owner == owner);
+        self.active = kept;
+        gone.into_iter().map(|i| i.public).collect()
+    }
+
+    /// Drop the inhibitions which have expired by `now`
+    fn expire(&mut self, now: Instant) {
+        self.active.retain(|i| {
+            let live = i.until > now;
+            if !live {
+                info!(
+                    "Automation inhibition {} ({}) expired",
+                    i.public.cookie, i.public.reason
+                );
+            }
+            live
+        });
+    }
+
+    /// The inhibitions in force at `now`
+    pub(crate) fn active(&mut self, now: Instant) -> Vec<AutomationInhibition> {
+        self.expire(now);
+        self.active.iter().map(|i| i.public.clone()).collect()
+    }
+
+    /// Who is inhibiting at `now`, such as `benchmark (:1.42)`, `None` if nobody is
+    pub(crate) fn inhibited_by(&mut self, now: Instant) -> Option<String> {
+        let active = self.active(now);
+        if active.is_empty() {
+            return None;
+        }
+        Some(
+            active
+                .iter()
+                .map(|i| format!("{} ({})", i.reason, i.owner))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+}
+
+/// Check the inhibitions before `what` is done by itself. If any are in force it is
+/// recorded in the audit log as skipped and `true` is returned.
+pub(crate) async fn skip_if_inhibited(
+    registry: &Mutex<InhibitRegistry>,
+    audit: &AuditLog,
+    what: &str,
+) -> bool {
+    let by = match registry.lock().await.inhibited_by(Instant::now()) {
+        Some(by) => by,
+        None => return false,
+    };
+    record_skip(audit, what, &by);
+    true
+}
+
+/// Record in the audit log that `what` wasn't done as it was inhibited `by`
+pub(crate) fn record_skip(audit: &AuditLog, what: &str, by: &str) {
+    audit.record(
+        &Actor::Daemon,
+        &format!("{what}: skipped due to inhibition by {by}"),
+    );
+}
+
+impl CtrlGraphics {
+    /// Remove the automation inhibitions and signal filters of clients as they leave the bus.
+    /// Not started if there is no signal context, and so no connection, to watch.
+    pub fn start_inhibit_owner_watch(&self) {
+        let connection = match self.signal_ctxt.as_ref() {
+            Some(ctxt) => ctxt.connection().clone(),
+            None => return,
+        };
+        let registry = self.automation_inhibits.clone();
+        let watch = move || {
+            let connection = connection.clone();
+            let registry = registry.clone();
+            async move {
+                let proxy = match DBusProxy::new(&connection).await {
+                    Ok(proxy) => proxy,
+                    Err(err) => {
+                        warn!("inhibition owner watch: {err}");
+                        return;
+                    }
+                };
+                let mut changes = match proxy.receive_name_owner_changed().await {
+                    Ok(changes) => changes,
+                    Err(err) => {
+                        warn!("inhibition owner watch: {err}");
+                        return;
+                    }
+                };
+                while let Some(change) = changes.next().await {
+                    let args = match change.args() {
+                        Ok(args) => args,
+                        Err(_) => continue,
+                    };
+                    if args.new_owner().is_some() {
+                        continue;
+                    }
+                    for gone in registry.lock().await.owner_gone(args.name()) {
+                        info!(
+                            "Automation inhibition {} ({}) ended, {} left the bus",
+                            gone.cookie, gone.reason, gone.owner
+                        );
+                    }
+                    if signal_filter_owner_gone(args.name()) {
+                        info!("Signal filter of {} removed, it left the bus", args.name());
+                    }
+                }
+            }
+        };
+        self.tasks
+            .spawn("inhibition owner watch", RestartPolicy::WithBackoff, watch);
+    }
+}
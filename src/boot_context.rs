@@ -0,0 +1,150 @@
+use futures_util::future::BoxFuture;
+use log::{info, warn};
+use serde_derive::Serialize;
+
+use crate::{
+    actions::StagedAction,
+    error::GfxError,
+    logout_switch::{SessionProbe, SystemSessionProbe},
+    systemd::{is_systemd_unit_state, SystemdUnitState},
+    verify::DriftFinding,
+    DISPLAY_MANAGER,
+};
+
+/// Where `CtrlGraphics::reload` runs, which decides how much of the boot tasks it does
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum BootContext {
+    /// Nothing graphical has started yet, as when started at boot. All the boot tasks are
+    /// run.
+    AtBoot,
+    /// Started long after boot, such as by hand or from a recovery shell, with a graphical
+    /// session or the display manager already running, with what was found. Only the boot
+    /// tasks which leave them undisturbed are run.
+    LiveSession(String),
+}
+
+/// Asks about the sessions and the display manager, so the context can be tested
+pub(crate) trait BootContextProbe: SessionProbe {
+    /// Whether `display-manager.service` is active
+    fn display_manager_active(&self) -> Result<bool, GfxError>;
+}
+
+impl BootContextProbe for SystemSessionProbe {
+    fn display_manager_active(&self) -> Result<bool, GfxError> {
+        is_systemd_unit_state(SystemdUnitState::Active, DISPLAY_MANAGER)
+    }
+}
+
+/// A probe which only asks systemd, for `no_logind`
+pub(crate) struct NoLogindProbe;
+
+impl SessionProbe for NoLogindProbe {
+    fn graphical_sessions(&self) -> BoxFuture<'_, Result<Vec<String>, GfxError>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+}
+
+impl BootContextProbe for NoLogindProbe {
+    fn display_manager_active(&self) -> Result<bool, GfxError> {
+        SystemSessionProbe.display_manager_active()
+    }
+}
+
+impl BootContext {
+    /// Find out whether a graphical session or the display manager is already running. If
+    /// neither can be asked about it is taken to be boot, as it was before this was checked.
+    pub(crate) async fn classify(probe: &dyn BootContextProbe) -> Self {
+        match probe.graphical_sessions().await {
+            Ok(sessions) if !sessions.is_empty() => {
+                return Self::LiveSession(format!(
+                    "graphical sessions {} are open",
+                    sessions.join(", ")
+                ))
+            }
+            Ok(_) => {}
+            Err(err) => warn!("reload: could not list the sessions: {err}"),
+        }
+        match probe.display_manager_active() {
+            Ok(true) => Self::LiveSession(format!("{DISPLAY_MANAGER} is active")),
+            Ok(false) => Self::AtBoot,
+            Err(err) => {
+                warn!("reload: could not check {DISPLAY_MANAGER}: {err}");
+                Self::AtBoot
+            }
+        }
+    }
+}
+
+/// Whether a boot action leaves a running desktop alone: it only writes files which are
+/// read when a driver or Vulkan is next loaded, or is a marker. Loading or unloading
+/// drivers, rescanning or removing devices, hotplug, killing processes and starting or
+/// stopping units are not.
+pub(crate) fn safe_in_live_session(action: &StagedAction) -> bool {
+    matches!(
+        action,
+        StagedAction::WriteModprobeConf
+            | StagedAction::CheckVulkanIcd
+            | StagedAction::DevTreeManaged
+            | StagedAction::NoLogind
+            | StagedAction::NotNvidia
+            | StagedAction::None
+    )
+}
+
+/// The boot actions to run in `context`, and those skipped
+pub(crate) fn boot_subset(
+    context: &BootContext,
+    actions: Vec<StagedAction>,
+) -> (Vec<StagedAction>, Vec<StagedAction>) {
+    match context {
+        BootContext::AtBoot => (actions, Vec::new()),
+        BootContext::LiveSession(_) => actions.into_iter().partition(safe_in_live_session),
+    }
+}
+
+/// The findings after the reduced boot path which were left as found, as putting them
+/// right would disturb the session. The modprobe conf and switcheroo rule are written by
+/// the reduced path, and a stale initramfs has its own advisory.
+pub(crate) fn left_as_found(findings: Vec<DriftFinding>) -> Vec<DriftFinding> {
+    findings
+        .into_iter()
+        .filter(|finding| {
+            !matches!(
+                finding,
+                DriftFinding::ModprobeConf
+                    | DriftFinding::SwitcherooRule
+                    | DriftFinding::StaleInitramfs(_)
+            )
+        })
+        .collect()
+}
+
+/// Describe the reduced boot path for the boot outcome, and log it
+pub(crate) fn reduced_summary(
+    why: &str,
+    skipped: &[StagedAction],
+    left: &[DriftFinding],
+) -> String {
+    let mut summary = format!("{why}, only the boot tasks which don't disturb it were run");
+    if !skipped.is_empty() {
+        summary.push_str(&format!(
+            ", skipped {}",
+            skipped
+                .iter()
+                .map(|action| format!("{action:?}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if !left.is_empty() {
+        summary.push_str(&format!(
+            ", left as found: {}",
+            left.iter()
+                .map(|finding| finding.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        ));
+    }
+    info!("reload: reduced boot path, {summary}");
+    summary
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+/// The stats of every live `BoundedQueue`, listed by the memory report
+static REGISTRY: Mutex<Vec<Weak<BufferStats>>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct BufferStats {
+    name: &'static str,
+    capacity: usize,
+    len: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// A queue of at most `capacity` items, the oldest is dropped to make room for a new one.
+/// Anything the daemon keeps for as long as it runs, such as histories and logs, is held in
+/// one of these so it can't grow over months of uptime. Each queue is listed in the memory
+/// report from when it is made until it is dropped.
+#[derive(Debug)]
+pub(crate) struct BoundedQueue<T> {
+    items: VecDeque<T>,
+    stats: Arc<BufferStats>,
+}
+
+impl<T> BoundedQueue<T> {
+    /// An empty queue listed in the memory report as `name`
+    pub(crate) fn new(name: &'static str, capacity: usize) -> Self {
+        let stats = Arc::new(BufferStats {
+            name,
+            capacity,
+            len: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        registry.retain(|stats| stats.strong_count() > 0);
+        registry.push(Arc::downgrade(&stats));
+        Self {
+            items: VecDeque::new(),
+            stats,
+        }
+    }
+
+    /// Add `item` at the back, dropping the oldest item if the queue is full
+    pub(crate) fn push(&mut self, item: T) {
+        if self.stats.capacity == 0 {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.items.len() >= self.stats.capacity {
+            self.items.pop_front();
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.items.push_back(item);
+        self.update_len();
+    }
+
+    /// Replace the contents with `items`, of which only the last `capacity` are kept
+    pub(crate) fn replace(&mut self, items: impl IntoIterator<Item = T>) {
+        self.clear();
+        for item in items {
+            self.push(item);
+        }
+    }
+
+    /// The oldest item
+    pub(crate) fn front(&self) -> Option<&T> {
+        self.items.front()
+    }
+
+    /// Take the oldest item out, it isn't counted as dropped
+    pub(crate) fn pop_front(&mut self) -> Option<T> {
+        let item = self.items.pop_front();
+        self.update_len();
+        item
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.items.clear();
+        self.update_len();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The items dropped to make room since the queue was made
+    #[cfg(test)]
+    pub(crate) fn dropped(&self) -> u64 {
+        self.stats.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Oldest first
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
+    /// The items oldest first, the queue is no longer listed
+    pub(crate) fn into_vec(self) -> Vec<T> {
+        self.items.into()
+    }
+
+    fn update_len(&self) {
+        self.stats.len.store(self.items.len(), Ordering::Relaxed);
+    }
+}
+
+/// A buffer as listed in the memory report
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct BufferReport {
+    pub name: String,
+    pub capacity: u64,
+    pub len: u64,
+    /// Items dropped to make room since it was made
+    pub dropped: u64,
+}
+
+/// How much memory the daemon holds
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct MemoryReport {
+    /// The resident set size in KiB from `/proc/self/status`, 0 if it couldn't be read
+    pub rss_kib: u64,
+    /// Every live bounded buffer, in the order they were made
+    pub buffers: Vec<BufferReport>,
+}
+
+/// The `VmRSS` in KiB from the contents of a `/proc/<pid>/status`
+pub(crate) fn rss_kib_from(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+/// Report the buffers of the daemon and its resident set size
+pub fn memory_report() -> MemoryReport {
+    let buffers = {
+        let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        registry.retain(|stats| stats.strong_count() > 0);
+        registry
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|stats| BufferReport {
+                name: stats.name.to_string(),
+                capacity: stats.capacity as u64,
+                len: stats.len.load(Ordering::Relaxed) as u64,
+                dropped: stats.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    };
+    MemoryReport {
+        rss_kib: fs::read_to_string("/proc/self/status")
+            .ok()
+            .as_deref()
+            .and_then(rss_kib_from)
+            .unwrap_or_default(),
+        buffers,
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::Write;
+
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    pci_lock::PCI_LOCK_PATH, CONFIG_NVIDIA_VKICD, CONFIG_PATH, CONFIG_PATH_LEGACY, MODPROBE_PATH,
+    STATE_DIR, VERSION,
+};
+
+/// How supergfxd or supergfxctl was built, for bug reports
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Short git commit, or `unknown` if built outside a git checkout without
+    /// `SUPERGFXCTL_GIT_COMMIT` set
+    pub git_commit: String,
+    /// Enabled cargo features
+    pub features: Vec<String>,
+    /// e.g `rustc 1.80.0 (051478957 2024-07-21)`
+    pub rustc: String,
+    /// `YYYY-MM-DD`, from `SOURCE_DATE_EPOCH` if set
+    pub build_date: String,
+    /// Compiled in paths as `(name, path)`
+    pub paths: Vec<(String, String)>,
+    /// Paths in use which differ from the compiled in ones, as `(name, path)`
+    pub overrides: Vec<(String, String)>,
+}
+
+impl BuildInfo {
+    /// The build info of this binary, with no overrides
+    pub fn current() -> Self {
+        let features = [
+            ("daemon", cfg!(feature = "daemon")),
+            ("cli", cfg!(feature = "cli")),
+            ("zbus_tokio", cfg!(feature = "zbus_tokio")),
+        ];
+        let paths = [
+            ("config", CONFIG_PATH),
+            ("config_legacy", CONFIG_PATH_LEGACY),
+            ("modprobe", MODPROBE_PATH),
+            ("vulkan_icd", CONFIG_NVIDIA_VKICD),
+            ("state", STATE_DIR),
+            ("pci_lock", PCI_LOCK_PATH),
+        ];
+        Self {
+            version: VERSION.to_string(),
+            git_commit: env!("SUPERGFXCTL_GIT_COMMIT").to_string(),
+            features: features
+                .iter()
+                .filter(|(_, on)| *on)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            rustc: env!("SUPERGFXCTL_RUSTC_VERSION").to_string(),
+            build_date: env!("SUPERGFXCTL_BUILD_DATE").to_string(),
+            paths: paths
+                .iter()
+                .map(|(name, path)| (name.to_string(), path.to_string()))
+                .collect(),
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Only the version is known, as for a daemon without `BuildInfo`
+    pub fn version_only(version: String) -> Self {
+        Self {
+            version,
+            git_commit: "unknown".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn render_details(&self, out: &mut String) {
+        if self.rustc.is_empty() {
+            return;
+        }
+        writeln!(out, "  commit: {}", self.git_commit).ok();
+        writeln!(out, "  built: {} with {}", self.build_date, self.rustc).ok();
+        writeln!(out, "  features: {}", self.features.join(", ")).ok();
+        for (name, path) in &self.paths {
+            writeln!(out, "  {name}: {path}").ok();
+        }
+        for (name, path) in &self.overrides {
+            writeln!(out, "  {name} (in use): {path}").ok();
+        }
+    }
+}
+
+/// The output of `supergfxctl --version`. The daemon and client are labelled if their builds
+/// differ. If the daemon can't be reached only the client version is shown, with the reason.
+pub fn render_versions(client: &BuildInfo, daemon: Result<&BuildInfo, &str>) -> String {
+    let mut out = String::new();
+    match daemon {
+        Ok(daemon)
+            if daemon.version == client.version && daemon.git_commit == client.git_commit =>
+        {
+            writeln!(out, "{}", daemon.version).ok();
+            daemon.render_details(&mut out);
+        }
+        Ok(daemon) => {
+            writeln!(out, "supergfxd {} (daemon)", daemon.version).ok();
+            daemon.render_details(&mut out);
+            writeln!(
+                out,
+                "supergfxctl {} (client, commit {})",
+                client.version, client.git_commit
+            )
+            .ok();
+        }
+        Err(err) => {
+            writeln!(out, "supergfxctl {} (client)", client.version).ok();
+            writeln!(out, "supergfxd could not be reached: {err}").ok();
+        }
+    }
+    out
+}
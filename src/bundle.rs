@@ -0,0 +1,390 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::{
+    acpi_dgpu::acpi_dgpu_diagnostics, buffers::memory_report, controller::CtrlGraphics,
+    driver_override::DriverOverrides, error::GfxError, pci_device::DiscreetGpu,
+    signal_counters::signal_counters, systemd_notify, unit_dropins::DROPIN_PATH, KERNEL_CMDLINE,
+    MODPROBE_PATH, VERSION,
+};
+
+/// Config keys replaced with `"<redacted>"` in a bundle. Nothing in the config is secret
+/// yet, this is here so a future option can't leak into bug reports by accident.
+const REDACTED_CONFIG_KEYS: &[&str] = &[];
+
+/// Lines of the daemon journal included in a bundle
+const JOURNAL_LINES: &str = "500";
+
+/// Generated files owned by supergfxd, copied into the bundle as they are on disk
+const OWNED_FILES: &[&str] = &[MODPROBE_PATH, DROPIN_PATH];
+
+/// One file in a support bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleMember {
+    /// Path inside the bundle, e.g `status.json`
+    pub name: String,
+    pub data: Vec<u8>,
+    /// Why the real data is missing, in which case `data` is a placeholder
+    pub placeholder: Option<String>,
+}
+
+/// Everything useful for a bug report. Each section is collected on its own and a section
+/// that fails becomes a placeholder, the bundle is always produced.
+#[derive(Debug, Default)]
+pub struct SupportBundle {
+    pub members: Vec<BundleMember>,
+}
+
+impl SupportBundle {
+    /// Add a JSON section, or a placeholder noting why it is missing
+    pub fn add_json(&mut self, name: &str, section: Result<Value, String>) {
+        match section.and_then(|v| serde_json::to_vec_pretty(&v).map_err(|e| e.to_string())) {
+            Ok(data) => self.add(name, data, None),
+            Err(reason) => {
+                let data = serde_json::to_vec_pretty(&json!({ "unavailable": reason }))
+                    .unwrap_or_default();
+                self.add(name, data, Some(reason))
+            }
+        }
+    }
+
+    /// Add a text section, or a placeholder noting why it is missing
+    pub fn add_text(&mut self, name: &str, section: Result<String, String>) {
+        match section {
+            Ok(text) => self.add(name, text.into_bytes(), None),
+            Err(reason) => self.add(
+                name,
+                format!("Unavailable: {reason}\n").into_bytes(),
+                Some(reason),
+            ),
+        }
+    }
+
+    fn add(&mut self, name: &str, data: Vec<u8>, placeholder: Option<String>) {
+        if let Some(reason) = placeholder.as_ref() {
+            debug!("support bundle: {name} unavailable: {reason}");
+        }
+        self.members.push(BundleMember {
+            name: name.to_string(),
+            data,
+            placeholder,
+        });
+    }
+
+    /// Add `manifest.json` listing the versions and every member
+    pub fn add_manifest(&mut self, interface_hash: &str) {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let members: Vec<Value> = self
+            .members
+            .iter()
+            .map(|m| {
+                json!({
+                    "name": m.name,
+                    "size": m.data.len(),
+                    "placeholder": m.placeholder,
+                })
+            })
+            .collect();
+        let manifest = json!({
+            "supergfxd_version": VERSION,
+            "interface_hash": interface_hash,
+            "kernel": fs::read_to_string("/proc/sys/kernel/osrelease")
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default(),
+            "created_unix_s": created,
+            "members": members,
+        });
+        self.add_json("manifest.json", Ok(manifest));
+    }
+
+    /// Write the bundle. If `path` is an existing directory, or ends in `/`, the members are
+    /// written as plain files in it, otherwise as a `.tar.gz` at `path`. Returns the path
+    /// written.
+    pub fn write(&self, path: &Path) -> Result<PathBuf, GfxError> {
+        if path.is_dir() || path.to_string_lossy().ends_with('/') {
+            for member in &self.members {
+                let out = path.join(&member.name);
+                if let Some(dir) = out.parent() {
+                    fs::create_dir_all(dir).map_err(|e| GfxError::from_io(e, dir.into()))?;
+                }
+                fs::write(&out, &member.data).map_err(|e| GfxError::from_io(e, out.clone()))?;
+            }
+        } else {
+            let mut file = fs::File::create(path).map_err(|e| GfxError::from_io(e, path.into()))?;
+            file.write_all(&gzip_stored(&tar(&self.members)))
+                .and_then(|_| file.sync_all())
+                .map_err(|e| GfxError::from_io(e, path.into()))?;
+        }
+        Ok(path.to_path_buf())
+    }
+}
+
+/// Replace the values of `REDACTED_CONFIG_KEYS` in a serialized config
+pub(crate) fn redact_config(mut config: Value) -> Value {
+    if let Some(map) = config.as_object_mut() {
+        for key in REDACTED_CONFIG_KEYS {
+            if let Some(value) = map.get_mut(*key) {
+                *value = Value::String("<redacted>".to_string());
+            }
+        }
+    }
+    config
+}
+
+/// The PCI devices tracked for the dGPU and their current state
+fn device_inventory(dgpu: &DiscreetGpu) -> Value {
+    let registered = DriverOverrides::system().registered();
+    let devices: Vec<Value> = dgpu
+        .devices()
+        .iter()
+        .map(|dev| {
+            json!({
+                "name": dev.name(),
+                "pci_id": dev.pci_id(),
+                "vendor": <&str>::from(dev.vendor()),
+                "is_dgpu": dev.is_dgpu(),
+                "ignored": dgpu.is_ignored(dev),
+                "dev_path": dev.dev_path(),
+                "present": dev.dev_path().exists(),
+                "driver": dev.driver().ok(),
+                "runtime_status": dev.get_runtime_status().ok(),
+                "driver_override": dev.driver_override(),
+                // The mode supergfxd set it for, none if something else set it
+                "driver_override_for": registered
+                    .iter()
+                    .find(|r| r.device == dev.name() && r.pci_id == dev.pci_id())
+                    .map(|r| r.mode),
+            })
+        })
+        .collect();
+    json!({
+        "vendor": <&str>::from(dgpu.vendor()),
+        "generation": dgpu.generation(),
+        "devices": devices,
+        "registered_driver_overrides": registered,
+    })
+}
+
+fn run(cmd: &str, args: &[&str]) -> Result<String, String> {
+    let out = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| format!("{cmd}: {e}"))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{cmd} failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+fn not_available(section: &str) -> Result<Value, String> {
+    Err(format!("{section} is not available in supergfxd {VERSION}"))
+}
+
+impl CtrlGraphics {
+    /// Gather everything for a bug report into a bundle
+    pub async fn collect_support_bundle(&self) -> SupportBundle {
+        let mut bundle = SupportBundle::default();
+
+        let config = serde_json::to_value(&*self.config.lock().await);
+        bundle.add_json(
+            "config.json",
+            config.map(redact_config).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "build_info.json",
+            serde_json::to_value(self.get_build_info().await).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "status.json",
+            serde_json::to_value(self.get_status().await).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "devices.json",
+            Ok(device_inventory(&self.dgpu_snapshot().await)),
+        );
+        bundle.add_json(
+            "switcheroo.json",
+            serde_json::to_value(&*self.switcheroo.lock().await).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "power_blockers.json",
+            serde_json::to_value(&*self.power_blockers.lock().await).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "memory.json",
+            serde_json::to_value(memory_report()).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "link_info.json",
+            serde_json::to_value(self.get_link_info().await).map_err(|e| e.to_string()),
+        );
+        bundle.add_json(
+            "supported.json",
+            Ok(json!({
+                "profile": self.get_profile().await,
+                "modes": self.get_supported_modes().await,
+                "reason": self.get_supported_reason().await,
+                "degraded_hardware": self.get_degraded_hardware(),
+                "hotplug_downgrade": self.get_hotplug_downgrade().await,
+            })),
+        );
+        let (acpi_dgpu, config_write_error) = {
+            let config = self.config.lock().await;
+            (
+                acpi_dgpu_diagnostics(config.acpi_dgpu_off.as_ref()),
+                config.write_error.clone(),
+            )
+        };
+        bundle.add_json(
+            "diagnostics.json",
+            Ok(json!({
+                "tasks": self.tasks.roster(),
+                "runtime_pm_reprobes": *self.reprobes.lock().await,
+                "acpi_dgpu_off": acpi_dgpu,
+                "config_write_error": config_write_error,
+                "igpu_present": self.dgpu_snapshot().await.igpu_present(),
+                "system_class": self.dgpu_snapshot().await.system_class(),
+                "signal_counters": signal_counters(),
+                "attention": self.attention.lock().await.items(),
+            })),
+        );
+        bundle.add_json(
+            "boot_report.json",
+            match self.boot_outcome.lock().await.clone() {
+                Some(outcome) => Ok(json!({
+                    "status": systemd_notify::boot_status(&outcome),
+                    "outcome": outcome,
+                })),
+                None => Err("the boot tasks haven't run".to_string()),
+            },
+        );
+        for section in ["verify", "file_audit", "recent_log", "usage_stats"] {
+            bundle.add_json(&format!("{section}.json"), not_available(section));
+        }
+
+        // Blocking reads and subprocesses are kept off the executor
+        let files = tokio::task::spawn_blocking(move || {
+            let mut texts = vec![
+                (
+                    "journal.txt".to_string(),
+                    run(
+                        "journalctl",
+                        &["-b", "-u", "supergfxd", "-n", JOURNAL_LINES, "--no-pager"],
+                    ),
+                ),
+                (
+                    "cmdline.txt".to_string(),
+                    fs::read_to_string(KERNEL_CMDLINE).map_err(|e| e.to_string()),
+                ),
+            ];
+            for path in OWNED_FILES {
+                let name = format!("files{path}");
+                texts.push((
+                    name,
+                    fs::read_to_string(path).map_err(|e| format!("{path}: {e}")),
+                ));
+            }
+            texts
+        })
+        .await
+        .unwrap_or_default();
+        for (name, text) in files {
+            bundle.add_text(&name, text);
+        }
+
+        bundle.add_manifest(&self.get_capabilities().await.interface_hash);
+        bundle
+    }
+
+    /// Write a support bundle to `path`, see `SupportBundle::write`
+    pub async fn write_support_bundle(&self, path: &Path) -> Result<PathBuf, GfxError> {
+        let bundle = self.collect_support_bundle().await;
+        let path = path.to_path_buf();
+        let written = tokio::task::spawn_blocking(move || bundle.write(&path))
+            .await
+            .map_err(|e| GfxError::NotSupported(format!("support bundle: {e}")))??;
+        info!("Wrote support bundle to {}", written.display());
+        Ok(written)
+    }
+}
+
+/// Pack members into an uncompressed ustar archive
+pub(crate) fn tar(members: &[BundleMember]) -> Vec<u8> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut out = Vec::new();
+    for member in members {
+        let mut header = [0u8; 512];
+        let name = member.name.as_bytes();
+        header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", member.data.len()).as_bytes());
+        header[136..148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&member.data);
+        out.resize(out.len() + (512 - member.data.len() % 512) % 512, 0);
+    }
+    // End of archive
+    out.resize(out.len() + 1024, 0);
+    out
+}
+
+/// Wrap data in gzip using stored (uncompressed) deflate blocks. Bundles are small, this
+/// avoids a compression dependency while staying readable by `tar -xzf`.
+pub(crate) fn gzip_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+    let mut chunks = data.chunks(u16::MAX as usize).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
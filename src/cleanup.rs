@@ -0,0 +1,312 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+use crate::{
+    migrate::{moved_aside, IMPORT_BACKUP_DIR},
+    special_asus::{ASUS_MODULES_LOAD, ASUS_MODULES_LOAD_PATH},
+    switcheroo::{SWITCHEROO_RULE_HEADER, SWITCHEROO_RULE_PATH},
+    unit_dropins::{UnitReload, DROPIN_HEADER, DROPIN_PATH},
+    CONFIG_DIR, CONFIG_NVIDIA_VKICD, CONFIG_PATH_LEGACY, MODPROBE_HEADER, MODPROBE_PATH, STATE_DIR,
+};
+
+/// Where the locks, staged files and driver overrides are kept while supergfxd runs
+const RUNTIME_DIR: &str = "/run/supergfxd";
+/// The directories only supergfxd writes to, everything in them is its own
+const OWN_DIRS: &[&str] = &[CONFIG_DIR, STATE_DIR, RUNTIME_DIR];
+
+/// How a file found by `locate_artifacts` was checked to be one supergfxd wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ownership {
+    /// It starts with the header supergfxd writes
+    Header,
+    /// It is exactly what supergfxd writes, for a file without a header
+    Content,
+    /// It is in a directory only supergfxd writes to
+    OwnDirectory,
+    /// A file of another package or the user which supergfxd moved aside, put back on cleanup
+    Backup,
+    /// It is where supergfxd writes but doesn't carry its marker. Never removed.
+    Foreign,
+}
+
+impl fmt::Display for Ownership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ownership::Header => write!(f, "supergfxd header"),
+            Ownership::Content => write!(f, "content written by supergfxd"),
+            Ownership::OwnDirectory => write!(f, "supergfxd directory"),
+            Ownership::Backup => write!(f, "moved aside by supergfxd"),
+            Ownership::Foreign => write!(f, "not written by supergfxd"),
+        }
+    }
+}
+
+/// A file supergfxd may have written or moved aside
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    /// Where it is, under the root it was located in
+    pub path: PathBuf,
+    pub ownership: Ownership,
+    /// Where a `Backup` is put back, under the same root
+    pub restore_to: Option<PathBuf>,
+}
+
+/// What `apply_cleanup` did
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub removed: Vec<PathBuf>,
+    /// Each backup put back, as (where it was kept, where it is now)
+    pub restored: Vec<(PathBuf, PathBuf)>,
+    /// Files left as they were, with why
+    pub kept: Vec<(PathBuf, String)>,
+    /// Files which couldn't be removed or put back, with the error
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// `path` as found under `root`, which is `/` other than in tests
+fn under(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// How a file supergfxd writes outside its own directories is told apart from another
+enum Marker {
+    /// The first line it writes
+    Header(&'static str),
+    /// The whole of what it writes
+    Content(&'static [u8]),
+}
+
+/// A file supergfxd writes outside its own directories, if there is one at `path`
+fn shared_file(root: &Path, path: &str, marker: Marker) -> Option<Artifact> {
+    let path = under(root, Path::new(path));
+    let content = fs::read(&path).ok()?;
+    let ownership = match marker {
+        Marker::Header(header) if content.starts_with(header.as_bytes()) => Ownership::Header,
+        Marker::Content(written) if content == written => Ownership::Content,
+        _ => Ownership::Foreign,
+    };
+    Some(Artifact {
+        path,
+        ownership,
+        restore_to: None,
+    })
+}
+
+/// Every file below `dir`, in order of their paths
+fn files_below(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() && !path.is_symlink() {
+            files_below(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// A backup of a file which is put back on cleanup if it is there
+fn backup(root: &Path, kept: &Path, original: &Path) -> Option<Artifact> {
+    let kept = under(root, kept);
+    kept.exists().then(|| Artifact {
+        path: kept,
+        ownership: Ownership::Backup,
+        restore_to: Some(under(root, original)),
+    })
+}
+
+/// Find everything supergfxd wrote or moved aside on the system under `root`: the modprobe
+/// conf, the asus-nb-wmi modules-load conf, the switcheroo-control udev rule, the ordering
+/// drop-in, everything in its config, state and runtime directories, and the backups of the
+/// Vulkan ICD, the legacy config and the files of tools moved aside by `--import-from`. The
+/// backups come first so they are put back before the state directory holding some of them
+/// is emptied.
+pub fn locate_artifacts(root: &Path) -> Vec<Artifact> {
+    let mut artifacts = Vec::new();
+
+    let vk_inactive = format!("{CONFIG_NVIDIA_VKICD}_inactive");
+    artifacts.extend(backup(
+        root,
+        Path::new(&vk_inactive),
+        Path::new(CONFIG_NVIDIA_VKICD),
+    ));
+    let legacy_migrated = format!("{CONFIG_PATH_LEGACY}.migrated");
+    artifacts.extend(backup(
+        root,
+        Path::new(&legacy_migrated),
+        Path::new(CONFIG_PATH_LEGACY),
+    ));
+    let backups = under(root, &Path::new(STATE_DIR).join(IMPORT_BACKUP_DIR));
+    for (original, kept) in moved_aside(&backups) {
+        artifacts.push(Artifact {
+            path: kept,
+            ownership: Ownership::Backup,
+            restore_to: Some(under(root, &original)),
+        });
+    }
+
+    artifacts.extend(shared_file(
+        root,
+        MODPROBE_PATH,
+        Marker::Header(MODPROBE_HEADER),
+    ));
+    artifacts.extend(shared_file(
+        root,
+        ASUS_MODULES_LOAD_PATH,
+        Marker::Content(ASUS_MODULES_LOAD),
+    ));
+    artifacts.extend(shared_file(
+        root,
+        SWITCHEROO_RULE_PATH,
+        Marker::Header(SWITCHEROO_RULE_HEADER),
+    ));
+    artifacts.extend(shared_file(
+        root,
+        DROPIN_PATH,
+        Marker::Header(DROPIN_HEADER),
+    ));
+
+    for dir in OWN_DIRS {
+        let mut files = Vec::new();
+        files_below(&under(root, Path::new(dir)), &mut files);
+        for path in files {
+            if artifacts.iter().any(|artifact| artifact.path == path) {
+                continue;
+            }
+            artifacts.push(Artifact {
+                path,
+                ownership: Ownership::OwnDirectory,
+                restore_to: None,
+            });
+        }
+    }
+    artifacts
+}
+
+/// Remove the directories below `dir` which are empty once their own empty directories are
+/// gone, then `dir` itself if it is empty
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() && !path.is_symlink() {
+                remove_empty_dirs(&path);
+            }
+        }
+    }
+    fs::remove_dir(dir).ok();
+}
+
+/// Put back the backups and remove the files supergfxd was checked to own, from
+/// `locate_artifacts` under `root`. A `Foreign` file is never touched, nor is a backup put
+/// back over a file which has since been created. The emptied supergfxd directories and the
+/// drop-in directory are removed, and systemd is reloaded with `reload` if the drop-in was.
+pub fn apply_cleanup(
+    root: &Path,
+    artifacts: &[Artifact],
+    reload: &dyn UnitReload,
+) -> CleanupReport {
+    let mut report = CleanupReport::default();
+    for artifact in artifacts {
+        let path = &artifact.path;
+        match (&artifact.ownership, &artifact.restore_to) {
+            (Ownership::Foreign, _) => report
+                .kept
+                .push((path.clone(), artifact.ownership.to_string())),
+            (Ownership::Backup, Some(to)) if to.exists() => report.kept.push((
+                path.clone(),
+                format!("{} exists, it wasn't put back", to.display()),
+            )),
+            (Ownership::Backup, Some(to)) => {
+                let moved = to
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::rename(path, to))
+                    .or_else(|_| fs::copy(path, to).and_then(|_| fs::remove_file(path)));
+                match moved {
+                    Ok(()) => {
+                        info!("cleanup: put back {}", to.display());
+                        report.restored.push((path.clone(), to.clone()));
+                    }
+                    Err(err) => report.failed.push((path.clone(), err.to_string())),
+                }
+            }
+            _ => match fs::remove_file(path) {
+                Ok(()) => {
+                    info!("cleanup: removed {}", path.display());
+                    report.removed.push(path.clone());
+                }
+                Err(err) => report.failed.push((path.clone(), err.to_string())),
+            },
+        }
+    }
+
+    let dropin = under(root, Path::new(DROPIN_PATH));
+    if report.removed.contains(&dropin) {
+        if let Some(dir) = dropin.parent() {
+            fs::remove_dir(dir).ok();
+        }
+        if let Err(err) = reload.daemon_reload() {
+            warn!("cleanup: {err}");
+            report.failed.push((dropin, err.to_string()));
+        }
+    }
+    for dir in OWN_DIRS {
+        remove_empty_dirs(&under(root, Path::new(dir)));
+    }
+    report
+}
+
+/// The artifacts as listed by `supergfxctl --cleanup --dry-run`, each with what would be
+/// done to it and how its ownership was checked
+pub fn render_artifacts(artifacts: &[Artifact]) -> String {
+    if artifacts.is_empty() {
+        return "Nothing of supergfxd was found\n".to_string();
+    }
+    let mut out = String::new();
+    for artifact in artifacts {
+        let path = artifact.path.display();
+        let line = match (&artifact.ownership, &artifact.restore_to) {
+            (Ownership::Foreign, _) => format!("keep     {path} ({})", artifact.ownership),
+            (Ownership::Backup, Some(to)) => format!(
+                "restore  {path} -> {} ({})",
+                to.display(),
+                artifact.ownership
+            ),
+            _ => format!("remove   {path} ({})", artifact.ownership),
+        };
+        out += &line;
+        out.push('\n');
+    }
+    out
+}
+
+/// The summary printed by `supergfxctl --cleanup`
+pub fn render_report(report: &CleanupReport) -> String {
+    let mut out = String::new();
+    for (from, to) in &report.restored {
+        out += &format!("Put back {} from {}\n", to.display(), from.display());
+    }
+    for (path, why) in &report.kept {
+        out += &format!("Left {}: {why}\n", path.display());
+    }
+    for (path, err) in &report.failed {
+        out += &format!("Failed {}: {err}\n", path.display());
+    }
+    out += &format!(
+        "Removed {} files, put back {}, left {}, {} failed\n",
+        report.removed.len(),
+        report.restored.len(),
+        report.kept.len(),
+        report.failed.len()
+    );
+    out
+}
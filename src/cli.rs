@@ -1,21 +1,97 @@
 //! Basic CLI tool to control the `supergfxd` daemon
 
-use std::{env::args, process::Command};
+use std::{
+    env::args,
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use supergfxctl::{
-    actions::UserActionRequired, error::GfxError, pci_device::GfxMode,
-    zbus_proxy::DaemonProxyBlocking,
+    actions::UserActionRequired,
+    audit::format_timestamp,
+    build_info::{render_versions, BuildInfo},
+    cleanup::{apply_cleanup, locate_artifacts, render_artifacts, render_report},
+    cli_error::{CliError, DaemonUnit, ErrorClass},
+    cli_json::{error_json, JsonOutput},
+    completions::{
+        check_mode_supported, completion_script, hide_options, list_modes, parse_usage,
+        LIST_MODES_TIMEOUT,
+    },
+    config::GfxConfig,
+    controller::{GfxState, GfxStatus, PendingInfo, PlannedSwitch, SetModeOptions},
+    error::GfxError,
+    gpu_users::render_blocking_processes,
+    instance::{InstanceLock, INSTANCE_LOCK_PATH},
+    migrate::{
+        apply_import, latest_backup, plan_import, render_plan, undo_import, ForeignTool,
+        SystemctlUnits, IMPORT_BACKUP_DIR,
+    },
+    pci_device::{render_devices, GfxMode, ModeInfo},
+    pci_link::LinkInfo,
+    prime_env::run_offloaded,
+    self_test::SelfTestReport,
+    switch_readiness::SwitchReadiness,
+    timeout::with_timeout,
+    unit_dropins::{
+        apply_dropin, generate_unit_dropins, installed_units, remove_dropin, SystemctlReload,
+        DROPIN_PATH,
+    },
+    zbus_proxy::{DaemonProxyBlocking, NotifySwitchCompleteIterator, NotifySwitchProgressIterator},
+    CONFIG_PATH, STATE_DIR,
 };
 
 use gumdrop::Options;
 use zbus::{blocking::Connection, proxy::CacheProperties};
 
-#[derive(Default, Clone, Copy, Options)]
+/// Options left out of `--help`, for packagers rather than users
+const HIDDEN_OPTIONS: &[&str] = &["completions"];
+/// How long `--watch-switch` waits for the next step of a switch, a logout can take a while
+const WATCH_SWITCH_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long `--watch-switch` waits for the end of a switch which is no longer pending
+const WATCH_SWITCH_SETTLE: Duration = Duration::from_secs(1);
+
+#[derive(Default, Clone, PartialEq, Options)]
 struct CliStart {
     #[options(help = "print help message")]
     help: bool,
     #[options(meta = "", help = "Set graphics mode")]
     mode: Option<GfxMode>,
-    #[options(help = "Get supergfxd version")]
+    #[options(
+        no_short,
+        help = "Skip the delay before the display manager is stopped"
+    )]
+    no_delay: bool,
+    #[options(
+        no_short,
+        help = "Switch even if a program such as a firmware updater holds an inhibitor lock"
+    )]
+    ignore_inhibitors: bool,
+    #[options(
+        no_short,
+        help = "Only use the mode set with --mode until reboot, keeping the saved mode for boot"
+    )]
+    temporary: bool,
+    #[options(no_short, help = "Cancel a pending mode change if not yet started")]
+    cancel: bool,
+    #[options(no_short, help = "Rescan the PCI bus for a dGPU that dropped off")]
+    rescan: bool,
+    #[options(no_short, help = "Lock the mode to the current one (root only)")]
+    lock: bool,
+    #[options(no_short, help = "Unlock the mode (root only)")]
+    unlock: bool,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Show the last N mode and config changes and who made them (root only)"
+    )]
+    audit: Option<u32>,
+    #[options(
+        no_short,
+        help = "Connect to a supergfxd started with --debug-run on the session bus"
+    )]
+    session: bool,
+    #[options(help = "Get the supergfxd and supergfxctl versions and build info")]
     version: bool,
     #[options(help = "Get the current mode")]
     get: bool,
@@ -25,63 +101,533 @@ struct CliStart {
     vendor: bool,
     #[options(help = "Get the current power status")]
     status: bool,
+    #[options(
+        no_short,
+        help = "Get the mode, power status, pending change, vendor and supported modes at once"
+    )]
+    all: bool,
+    #[options(
+        no_short,
+        help = "Print the results of the queries as one JSON object, or the whole status without one. Errors are JSON too"
+    )]
+    json: bool,
+    #[options(
+        no_short,
+        meta = "PATH",
+        help = "Write a support bundle for bug reports to PATH (.tar.gz, or a directory) (root only)"
+    )]
+    bundle: Option<String>,
+    #[options(no_short, help = "Get the PCIe link state of the dGPU")]
+    link_info: bool,
+    #[options(
+        no_short,
+        help = "List the PCI functions of the dGPU, their power and driver"
+    )]
+    devices: bool,
+    #[options(
+        no_short,
+        help = "List the processes holding the dGPU open, which block a switch"
+    )]
+    blockers: bool,
+    #[options(
+        no_short,
+        meta = "MODE",
+        help = "Describe a mode, its risks and whether it is supported here"
+    )]
+    describe: Option<GfxMode>,
+    #[options(
+        no_short,
+        meta = "MODE",
+        help = "Say what keeps a switch to a mode from being made now, if anything"
+    )]
+    why_not: Option<GfxMode>,
+    #[options(
+        no_short,
+        meta = "MODE",
+        help = "List the actions a switch to a mode would perform, without switching"
+    )]
+    plan: Option<GfxMode>,
+    #[options(
+        no_short,
+        help = "Switch to another mode and back to check supergfxd works (root only)"
+    )]
+    self_test: bool,
+    #[options(
+        no_short,
+        help = "Run the self-test even while graphical sessions are active"
+    )]
+    force: bool,
     #[options(help = "Get the pending user action if any")]
     pend_action: bool,
     #[options(help = "Get the pending mode change if any")]
     pend_mode: bool,
+    #[options(
+        no_short,
+        help = "Get the pending mode change with who asked for it and how long ago"
+    )]
+    pend_info: bool,
+    #[options(
+        no_short,
+        help = "Print each step of a mode switch as it happens, until it ends. With --mode, of that switch"
+    )]
+    watch_switch: bool,
+    #[options(
+        no_short,
+        help = "List the modes which can be set, one per line, for shell completion"
+    )]
+    list_modes: bool,
+    #[options(
+        no_short,
+        help = "Run a command on the dGPU, e.g. `supergfxctl --run -- glxgears`"
+    )]
+    run: bool,
+    #[options(
+        no_short,
+        meta = "TOOL",
+        help = "Show the config equivalent to envycontrol or optimus-manager and the files of theirs to move aside"
+    )]
+    import_from: Option<String>,
+    #[options(
+        no_short,
+        help = "With --import-from, write the config and move the files aside (root only, with supergfxd stopped). With --generate-dropins, write the drop-in (root only)"
+    )]
+    apply: bool,
+    #[options(
+        no_short,
+        help = "Undo the last --import-from --apply (root only, with supergfxd stopped)"
+    )]
+    import_undo: bool,
+    #[options(
+        no_short,
+        help = "Show the systemd drop-in ordering supergfxd against the GPU services installed"
+    )]
+    generate_dropins: bool,
+    #[options(
+        no_short,
+        help = "Remove the drop-in written by --generate-dropins --apply (root only)"
+    )]
+    remove_dropins: bool,
+    #[options(
+        no_short,
+        help = "Remove everything supergfxd wrote and put back what it moved aside, for an uninstall (root only, with supergfxd stopped)"
+    )]
+    cleanup: bool,
+    #[options(
+        no_short,
+        help = "With --cleanup, list what would be done without doing it"
+    )]
+    dry_run: bool,
+    #[options(
+        no_short,
+        help = "When a command fails, print everything known about the error and a hint"
+    )]
+    verbose: bool,
+    #[options(no_short, meta = "SHELL")]
+    completions: Option<String>,
+    /// The command for `--run`, everything after `--`
+    #[options(free)]
+    command: Vec<String>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = args().skip(1).collect();
 
     match CliStart::parse_args_default(&args) {
+        Ok(command) if command.completions.is_some() || command.list_modes => {
+            // Must work without the daemon
+            if let Err(err) = print_completion_output(&command) {
+                exit_with_error(&err, &command);
+            }
+        }
+        Ok(command) if command.import_from.is_some() || command.import_undo => {
+            // Works on the files, with supergfxd stopped to apply
+            if let Err(err) = do_import(&command) {
+                exit_with_error(&err, &command);
+            }
+        }
+        Ok(command) if command.cleanup => {
+            // Works on the files, with supergfxd stopped to apply
+            if let Err(err) = do_cleanup(&command) {
+                exit_with_error(&err, &command);
+            }
+        }
+        Ok(command) if command.generate_dropins || command.remove_dropins => {
+            // Works on the unit files, without the daemon
+            if let Err(err) = do_dropins(&command) {
+                exit_with_error(&err, &command);
+            }
+        }
+        Ok(command) if command.json => {
+            if let Err(err) = do_json(&command) {
+                exit_with_error(&err, &command);
+            }
+        }
         Ok(command) => {
-            do_gfx(command).map_err(|err|{
-                eprintln!("Graphics mode change error.");
-                if !check_systemd_unit_enabled("supergfxd") {
-                    eprintln!("\x1b[0;31msupergfxd is not enabled, enable it with `systemctl enable supergfxd\x1b[0m");
-                } else if !check_systemd_unit_active("supergfxd") {
-                    eprintln!("\x1b[0;31msupergfxd is not running, start it with `systemctl start supergfxd\x1b[0m");
-                } else {
-                    eprintln!("Please check `journalctl -b -u supergfxd`, and `systemctl status supergfxd`");
-                    if let GfxError::Zbus(zbus::Error::MethodError(_,Some(text),_)) = &err {
-                                eprintln!("\x1b[0;31m{}\x1b[0m", text);
-                                std::process::exit(1);
-                    }
-                }
-                eprintln!("Error: {}", err);
-                std::process::exit(1);
-            }).ok();
+            if let Err(err) = do_gfx(command.clone()) {
+                exit_with_error(&err, &command);
+            }
         }
         Err(err) => {
-            eprintln!("Error: {}", err);
-            std::process::exit(1);
+            let class = ErrorClass::InvalidInput;
+            if args.iter().any(|arg| arg == "--json") {
+                println!(
+                    "{}",
+                    error_json(&CliError {
+                        class,
+                        code: None,
+                        dbus_name: None,
+                        message: err.to_string(),
+                        chain: Vec::new(),
+                    })
+                );
+            } else {
+                eprintln!("Error: {}", err);
+            }
+            std::process::exit(class.exit_code());
+        }
+    }
+
+    Ok(())
+}
+
+/// Print `err` as `CliError` renders it and exit with the code of its class. The supergfxd
+/// unit is only asked about if supergfxd couldn't be reached on the system bus.
+fn exit_with_error(err: &GfxError, command: &CliStart) -> ! {
+    let session = command.session;
+    let err =
+        CliError::from_error(err).with_unit(|| if session { None } else { DaemonUnit::query() });
+    if command.json {
+        // On stdout, where a script reading the JSON looks
+        println!("{}", error_json(&err));
+    } else {
+        eprint!("{}", err.render(command.verbose));
+    }
+    std::process::exit(err.exit_code());
+}
+
+/// `--json`: the results of the queries asked for, or the whole status if none was, as one
+/// object. Anything other than a query is refused, its output isn't JSON.
+fn do_json(command: &CliStart) -> Result<(), GfxError> {
+    let others = CliStart {
+        get: false,
+        supported: false,
+        vendor: false,
+        status: false,
+        all: false,
+        pend_action: false,
+        pend_mode: false,
+        pend_info: false,
+        json: false,
+        session: false,
+        verbose: false,
+        ..command.clone()
+    };
+    if others != CliStart::default() {
+        return Err(GfxError::NotSupported(
+            "--json only goes with --get, --supported, --vendor, --status, --all, --pend-action, --pend-mode and --pend-info".to_string(),
+        ));
+    }
+    let connection = if command.session {
+        Connection::session()
+    } else {
+        Connection::system()
+    }?;
+    let proxy = DaemonProxyBlocking::builder(&connection)
+        .cache_properties(CacheProperties::No)
+        .build()?;
+
+    let mut out = JsonOutput::default();
+    let everything = !(command.get
+        || command.supported
+        || command.vendor
+        || command.status
+        || command.all
+        || command.pend_action
+        || command.pend_mode
+        || command.pend_info);
+    if everything {
+        out.status(&proxy.status()?);
+        out.insert_mode("persistent_mode", proxy.persistent_mode()?);
+    }
+    if command.get {
+        out.insert_mode("mode", proxy.mode()?);
+        out.insert_mode("persistent_mode", proxy.persistent_mode()?);
+    }
+    if command.supported {
+        out.insert("supported", proxy.supported()?);
+        out.insert("supported_reason", proxy.supported_reason()?);
+    }
+    if command.vendor {
+        out.insert("vendor", proxy.vendor()?);
+    }
+    if command.status {
+        out.insert("power", proxy.power()?);
+        out.insert("mode_locked", proxy.mode_locked()?);
+    }
+    if command.all {
+        out.state(&proxy.state()?);
+    }
+    if command.pend_action {
+        out.insert("pending_action", proxy.pending_user_action()?);
+    }
+    if command.pend_mode {
+        out.insert_mode("pending_mode", proxy.pending_mode()?);
+    }
+    if command.pend_info {
+        out.pending_info(&proxy.pending_info()?);
+    }
+    println!("{}", out.render());
+    Ok(())
+}
+
+/// `--import-from` and `--import-undo`. Changes are made with the instance lock held, as
+/// supergfxd writes its config when it stops and must not be running.
+fn do_import(command: &CliStart) -> Result<(), GfxError> {
+    let backups = Path::new(STATE_DIR).join(IMPORT_BACKUP_DIR);
+    let hold_lock = || {
+        InstanceLock::acquire_at(Path::new(INSTANCE_LOCK_PATH)).map_err(|err| match err {
+            GfxError::AlreadyRunning(_) => GfxError::Import(
+                "supergfxd is running, stop it first with `systemctl stop supergfxd`".to_string(),
+            ),
+            err => err,
+        })
+    };
+
+    if command.import_undo {
+        let _lock = hold_lock()?;
+        let backup = latest_backup(&backups)
+            .ok_or_else(|| GfxError::Import("there is no import to undo".to_string()))?;
+        for step in undo_import(Path::new("/"), &backup, &SystemctlUnits)? {
+            println!("{step}");
+        }
+        println!("Import undone, start supergfxd again with `systemctl start supergfxd`");
+        return Ok(());
+    }
+
+    let tool: ForeignTool = command.import_from.as_deref().unwrap_or_default().parse()?;
+    let plan = plan_import(tool, Path::new("/"), GfxConfig::peek(CONFIG_PATH))?;
+    print!("{}", render_plan(&plan));
+    if !command.apply {
+        println!("\nNothing was changed, run again with --apply as root to do this");
+        return Ok(());
+    }
+
+    let _lock = hold_lock()?;
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let backup = backups.join(secs.to_string());
+    println!();
+    for step in apply_import(&plan, Path::new("/"), &backup, &SystemctlUnits)? {
+        println!("Done: {step}");
+    }
+    println!(
+        "Kept what was replaced in {}, undo with `supergfxctl --import-undo`. Start supergfxd again with `systemctl start supergfxd`",
+        backup.display()
+    );
+    Ok(())
+}
+
+/// `--generate-dropins` and `--remove-dropins`. Nothing is written without `--apply`.
+fn do_dropins(command: &CliStart) -> Result<(), GfxError> {
+    let root = Path::new("/");
+    if command.remove_dropins {
+        if remove_dropin(root, &SystemctlReload)? {
+            println!("Removed {DROPIN_PATH} and reloaded systemd");
+        } else {
+            println!("There is no {DROPIN_PATH} to remove");
         }
+        return Ok(());
     }
 
+    let installed = installed_units(root);
+    let dropin = match generate_unit_dropins(&installed) {
+        Some(dropin) => dropin,
+        None => {
+            println!("None of the units supergfxd needs ordering against are installed");
+            return Ok(());
+        }
+    };
+    println!(
+        "{DROPIN_PATH}:
+
+{dropin}"
+    );
+    if !command.apply {
+        println!("Nothing was written, run again with --apply as root to write it");
+        return Ok(());
+    }
+    let path = apply_dropin(root, &dropin, &SystemctlReload)?;
+    println!(
+        "Wrote {} and reloaded systemd, remove it with `supergfxctl --remove-dropins`",
+        path.display()
+    );
     Ok(())
 }
 
+/// `--cleanup`, with the instance lock held as supergfxd writes its config when it stops.
+/// Nothing is changed with `--dry-run`.
+fn do_cleanup(command: &CliStart) -> Result<(), GfxError> {
+    let root = Path::new("/");
+    if command.dry_run {
+        print!("{}", render_artifacts(&locate_artifacts(root)));
+        println!("\nNothing was changed, run again without --dry-run as root to do this");
+        return Ok(());
+    }
+    let _lock =
+        InstanceLock::acquire_at(Path::new(INSTANCE_LOCK_PATH)).map_err(|err| match err {
+            GfxError::AlreadyRunning(_) => GfxError::Cleanup(
+                "supergfxd is running, stop it first with `systemctl stop supergfxd`".to_string(),
+            ),
+            err => err,
+        })?;
+    let artifacts = locate_artifacts(root);
+    print!("{}", render_artifacts(&artifacts));
+    println!();
+    let report = apply_cleanup(root, &artifacts, &SystemctlReload);
+    print!("{}", render_report(&report));
+    if report.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(GfxError::Cleanup(format!(
+            "{} files couldn't be removed or put back",
+            report.failed.len()
+        )))
+    }
+}
+
 fn do_gfx(command: CliStart) -> Result<(), GfxError> {
-    if command.mode.is_none()
+    if !command.run && !command.command.is_empty() {
+        return Err(GfxError::NotSupported(format!(
+            "Unexpected argument {}, a command to run goes after `--run --`",
+            command.command[0]
+        )));
+    }
+    let no_other_flags = command.mode.is_none()
         && !command.get
-        && !command.version
         && !command.supported
         && !command.vendor
         && !command.status
+        && !command.all
         && !command.pend_action
         && !command.pend_mode
-        || command.help
-    {
-        println!("{}", command.self_usage());
+        && !command.pend_info
+        && !command.watch_switch
+        && !command.cancel
+        && !command.rescan
+        && !command.link_info
+        && !command.devices
+        && !command.blockers
+        && command.describe.is_none()
+        && command.why_not.is_none()
+        && command.plan.is_none()
+        && !command.run
+        && !command.self_test
+        && command.bundle.is_none()
+        && !command.lock
+        && !command.unlock
+        && command.audit.is_none();
+    let no_flags = no_other_flags && !command.version;
+    if command.help {
+        print!("{}", hide_options(command.self_usage(), HIDDEN_OPTIONS));
     }
 
-    let proxy = DaemonProxyBlocking::builder(&Connection::system()?)
+    let connection = if command.session {
+        Connection::session()
+    } else {
+        Connection::system()
+    };
+
+    // Works without the daemon, so a bug report can say what is installed
+    if command.version {
+        let daemon = connection
+            .as_ref()
+            .map_err(|err| err.to_string())
+            .and_then(|connection| daemon_build_info(connection).map_err(|err| err.to_string()));
+        print!(
+            "{}",
+            render_versions(
+                &BuildInfo::current(),
+                daemon.as_ref().map_err(|e| e.as_str())
+            )
+        );
+        if no_other_flags {
+            return Ok(());
+        }
+    }
+
+    let connection = connection?;
+    let proxy = DaemonProxyBlocking::builder(&connection)
         .cache_properties(CacheProperties::No)
         .build()?;
 
+    if command.run {
+        std::process::exit(run_offloaded(&proxy, &command.command)?);
+    }
+
+    if no_flags && !command.help {
+        print_status(&proxy.status()?);
+        println!("\nSee `supergfxctl --help` for options");
+    }
+
+    if command.cancel {
+        proxy.cancel_switch()?;
+        println!("Pending mode change cancelled");
+    }
+
+    if command.lock || command.unlock {
+        proxy.set_mode_lock(command.lock)?;
+        if command.lock {
+            println!("Graphics mode locked to {}", proxy.mode()?);
+        } else {
+            println!("Graphics mode unlocked");
+        }
+    }
+
+    if command.rescan {
+        if proxy.rescan_dgpu()? {
+            println!("dGPU is on the bus");
+        } else {
+            eprintln!("dGPU is still missing, a reboot (or suspend cycle) is likely required");
+            std::process::exit(1);
+        }
+    }
+
+    // Subscribed before the switch is started so none of its signals are missed
+    let switch_events = if command.watch_switch {
+        Some(subscribe_switch(
+            proxy.receive_notify_switch_progress()?,
+            proxy.receive_notify_switch_complete()?,
+        ))
+    } else {
+        None
+    };
+
     if let Some(mode) = command.mode {
-        let res = proxy.set_mode(&mode)?;
+        let options = SetModeOptions {
+            skip_pre_stop_delay: command.no_delay,
+            ignore_inhibitors: command.ignore_inhibitors,
+            persist: !command.temporary,
+        };
+        if let Err(err) = check_mode_supported(mode, &proxy.supported()?) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        let advisory = proxy.switch_advisory(&mode)?;
+        let res = proxy.set_mode_with_options(&mode, &options)?;
+        if !advisory.outputs_that_will_turn_off.is_empty() {
+            eprintln!(
+                "\x1b[0;33mWarning: these outputs are wired to the dGPU and will stop working in {mode}: {}\x1b[0m",
+                advisory.outputs_that_will_turn_off.join(", ")
+            );
+        }
+        if !advisory.initramfs_regeneration.is_empty() {
+            eprintln!(
+                "\x1b[0;33mNote: the initramfs has a copy of the modprobe conf this switch changes, regenerate it with `{}` before the next boot\x1b[0m",
+                advisory.initramfs_regeneration
+            );
+        }
         match res {
             UserActionRequired::SwitchToIntegrated => {
                 eprintln!("You must change to Integrated before you can change to {mode}",);
@@ -102,19 +648,37 @@ fn do_gfx(command: CliStart) -> Result<(), GfxError> {
             }
             UserActionRequired::AsusEgpuDisable => println!("{res:?}"),
         }
+        if command.temporary {
+            println!(
+                "{mode} is only used until reboot, the next boot is in {}",
+                proxy.persistent_mode()?
+            );
+        }
     }
 
-    if command.version {
-        let res = proxy.version()?;
-        println!("{}", res);
+    if let Some(events) = switch_events {
+        let pending = proxy.pending_mode()? != GfxMode::None;
+        if !watch_switch(&events, pending) {
+            std::process::exit(1);
+        }
     }
+
     if command.get {
         let res = proxy.mode()?;
-        println!("{res}");
+        let persistent = proxy.persistent_mode()?;
+        if res != persistent {
+            println!("{res} (temporary, boots into {persistent})");
+        } else {
+            println!("{res}");
+        }
     }
     if command.supported {
         let res = proxy.supported()?;
         println!("{:?}", res);
+        let reason = proxy.supported_reason()?;
+        if !reason.is_empty() {
+            println!("{reason}");
+        }
     }
     if command.vendor {
         let res = proxy.vendor()?;
@@ -123,6 +687,60 @@ fn do_gfx(command: CliStart) -> Result<(), GfxError> {
     if command.status {
         let res = proxy.power()?;
         println!("{}", <&str>::from(&res));
+        if proxy.mode_locked()? {
+            println!("Graphics mode is locked by the administrator");
+        }
+    }
+    if command.all {
+        print_state(&proxy.state()?);
+    }
+    if let Some(path) = command.bundle.as_ref() {
+        // The daemon doesn't share our working directory
+        let path = std::env::current_dir()
+            .map(|dir| dir.join(path))
+            .unwrap_or_else(|_| path.into());
+        let written = proxy.export_support_bundle(&path.to_string_lossy())?;
+        println!("Support bundle written to {written}");
+    }
+    if command.link_info {
+        print_link_info(&proxy.link_info()?);
+    }
+    if command.devices {
+        print!("{}", render_devices(&proxy.devices()?));
+    }
+    if command.blockers {
+        print!(
+            "{}",
+            render_blocking_processes(&proxy.blocking_processes()?)
+        );
+    }
+    if let Some(mode) = command.describe {
+        if let Some(info) = proxy.mode_info()?.iter().find(|info| info.mode == mode) {
+            print_mode_info(info);
+        }
+    }
+    if let Some(mode) = command.why_not {
+        print_readiness(&proxy.switch_readiness(&mode)?);
+    }
+    if let Some(mode) = command.plan {
+        print_plan(&proxy.get_switch_plan(&mode)?);
+    }
+    if command.self_test {
+        let report = proxy.self_test(command.force)?;
+        print_self_test(&report);
+        if !report.passed {
+            return Err(GfxError::NotSupported("The self-test failed".to_string()));
+        }
+    }
+    if let Some(count) = command.audit {
+        for record in proxy.audit_log(count)? {
+            println!(
+                "{} UTC  {:<12} {}",
+                format_timestamp(record.timestamp),
+                record.actor,
+                record.change
+            );
+        }
     }
     if command.pend_action {
         let res = proxy.pending_user_action()?;
@@ -132,30 +750,350 @@ fn do_gfx(command: CliStart) -> Result<(), GfxError> {
         let res = proxy.pending_mode()?;
         println!("{res}");
     }
+    if command.pend_info {
+        print_pending_info(&proxy.pending_info()?);
+    }
 
     Ok(())
 }
 
-fn check_systemd_unit_active(name: &str) -> bool {
-    if let Ok(out) = Command::new("systemctl")
-        .arg("is-active")
-        .arg(name)
-        .output()
-    {
-        let buf = String::from_utf8_lossy(&out.stdout);
-        return !buf.contains("inactive") && !buf.contains("failed");
+/// `--completions` or `--list-modes`. The modes are those the daemon supports, or every
+/// mode if it doesn't answer in time.
+fn print_completion_output(command: &CliStart) -> Result<(), GfxError> {
+    if let Some(shell) = command.completions.as_ref() {
+        let options = parse_usage(CliStart::usage(), HIDDEN_OPTIONS);
+        print!(
+            "{}",
+            completion_script(shell.parse()?, "supergfxctl", &options)
+        );
+    }
+    if command.list_modes {
+        let session = command.session;
+        let supported = with_timeout(LIST_MODES_TIMEOUT, move || {
+            let connection = if session {
+                Connection::session()
+            } else {
+                Connection::system()
+            }
+            .ok()?;
+            DaemonProxyBlocking::builder(&connection)
+                .cache_properties(CacheProperties::No)
+                .build()
+                .ok()?
+                .supported()
+                .ok()
+        });
+        print!("{}", list_modes(supported.as_deref()));
+    }
+    Ok(())
+}
+
+/// A signal of a mode switch, for `--watch-switch`
+enum SwitchEvent {
+    Progress {
+        action: String,
+        index: u32,
+        total: u32,
+    },
+    Complete {
+        mode: GfxMode,
+        success: bool,
+        outcome: String,
+    },
+}
+
+/// Forward the progress and completion signals of a switch to the returned channel, each
+/// iterator blocks so is read in its own thread
+fn subscribe_switch(
+    progress: NotifySwitchProgressIterator,
+    complete: NotifySwitchCompleteIterator,
+) -> Receiver<SwitchEvent> {
+    let (sender, receiver) = mpsc::channel();
+    let progress_sender = sender.clone();
+    thread::spawn(move || {
+        for signal in progress {
+            let args = match signal.args() {
+                Ok(args) => args,
+                Err(_) => continue,
+            };
+            let event = SwitchEvent::Progress {
+                action: args.current_action().to_string(),
+                index: *args.index(),
+                total: *args.total(),
+            };
+            if progress_sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        for signal in complete {
+            let args = match signal.args() {
+                Ok(args) => args,
+                Err(_) => continue,
+            };
+            let event = SwitchEvent::Complete {
+                mode: *args.mode(),
+                success: *args.success(),
+                outcome: args.outcome().to_string(),
+            };
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Print each step of a switch from `events` until it ends. `pending` is whether a switch
+/// was pending when the watch started, if not only the end of one which just finished is
+/// waited for. Returns `false` if the switch failed or the watch timed out.
+fn watch_switch(events: &Receiver<SwitchEvent>, pending: bool) -> bool {
+    let mut timeout = if pending {
+        WATCH_SWITCH_TIMEOUT
+    } else {
+        WATCH_SWITCH_SETTLE
+    };
+    loop {
+        match events.recv_timeout(timeout) {
+            Ok(SwitchEvent::Progress {
+                action,
+                index,
+                total,
+            }) => {
+                println!("[{index}/{total}] {action}");
+                timeout = WATCH_SWITCH_TIMEOUT;
+            }
+            Ok(SwitchEvent::Complete {
+                mode,
+                success,
+                outcome,
+            }) => {
+                if success {
+                    println!("Switch to {mode} completed");
+                } else {
+                    eprintln!("Switch to {mode} {outcome}");
+                }
+                return success;
+            }
+            Err(RecvTimeoutError::Timeout) if timeout == WATCH_SWITCH_SETTLE => {
+                println!("No mode switch is in progress");
+                return true;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                eprintln!(
+                    "No word from the switch for {}s, see `journalctl -b -u supergfxd`",
+                    timeout.as_secs()
+                );
+                return false;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                eprintln!("Lost the connection to supergfxd");
+                return false;
+            }
+        }
+    }
+}
+
+fn print_plan(plan: &PlannedSwitch) {
+    println!("Switch:         {} to {}", plan.from, plan.to);
+    println!("User action:    {}", <&str>::from(&plan.user_action));
+    if plan.actions.is_empty() {
+        println!("Nothing is done by supergfxd");
+        return;
+    }
+    println!("Actions:");
+    for (i, action) in plan.actions.iter().enumerate() {
+        println!("{:>3}. {action}", i + 1);
+    }
+}
+
+fn print_pending_info(info: &PendingInfo) {
+    if info.mode == GfxMode::None {
+        println!("No mode change is pending");
+        return;
+    }
+    let minutes = info.elapsed_s / 60;
+    let ago = match minutes {
+        0 => format!("{} seconds ago", info.elapsed_s),
+        1 => "1 minute ago".to_string(),
+        _ => format!("{minutes} minutes ago"),
+    };
+    let uid = if info.uid == u32::MAX {
+        String::new()
+    } else {
+        format!(" (uid {})", info.uid)
+    };
+    println!("Pending mode:   {}", info.mode);
+    println!("Pending action: {}", <&str>::from(&info.action));
+    println!(
+        "Requested:      {} UTC, {ago} by {}{uid}",
+        format_timestamp(info.requested_at),
+        info.requester
+    );
+}
+
+fn print_state(state: &GfxState) {
+    println!("Mode:           {}", state.mode);
+    println!("Power:          {}", <&str>::from(&state.power));
+    if state.pending_mode != GfxMode::None {
+        println!("Pending mode:   {}", state.pending_mode);
+        println!("Pending action: {}", <&str>::from(&state.pending_action));
+    }
+    println!("Vendor:         {}", <&str>::from(state.vendor));
+    println!("Supported:      {:?}", state.supported);
+}
+
+fn print_status(status: &GfxStatus) {
+    if status.mode_locked {
+        println!(
+            "Mode:           {} (locked by the administrator)",
+            status.mode
+        );
+    } else {
+        println!("Mode:           {}", status.mode);
+    }
+    if status.pending_mode != GfxMode::None {
+        println!("Pending mode:   {}", status.pending_mode);
+        println!("Pending action: {}", <&str>::from(&status.pending_action));
+    }
+    println!("Switch state:   {:?}", status.switch_state);
+    if !status.waiting_for.is_empty() {
+        println!("Waiting for:    {}", status.waiting_for.join(", "));
+    }
+    if !status.logout_timeout.is_empty() {
+        println!("Logout timeout: {}", status.logout_timeout);
+    }
+    for inhibition in &status.inhibitions {
+        println!(
+            "Inhibited by:   {} ({}) until {}",
+            inhibition.reason,
+            inhibition.owner,
+            format_timestamp(inhibition.expires)
+        );
+    }
+    println!("Vendor:         {}", <&str>::from(status.vendor));
+    println!("Power:          {}", <&str>::from(&status.power));
+    println!("Supported:      {:?}", status.supported);
+    if !status.initramfs_advisory.is_empty() {
+        println!("Initramfs:      {}", status.initramfs_advisory);
+    }
+    if !status.config_not_saved.is_empty() {
+        println!("Config unsaved: {}", status.config_not_saved);
+    }
+    if status.attention > 0 {
+        println!(
+            "Attention:      {} boot notices, see AttentionItems",
+            status.attention
+        );
+    }
+    if status.thermal.sampling {
+        println!(
+            "dGPU temp:      {}°C average{}",
+            status.thermal.average_c,
+            if status.thermal.advising {
+                ", running hot on battery"
+            } else {
+                ""
+            }
+        );
     }
-    false
 }
 
-fn check_systemd_unit_enabled(name: &str) -> bool {
-    if let Ok(out) = Command::new("systemctl")
-        .arg("is-enabled")
-        .arg(name)
-        .output()
-    {
-        let buf = String::from_utf8_lossy(&out.stdout);
-        return buf.contains("enabled");
+fn print_mode_info(info: &ModeInfo) {
+    println!("{}: {}", info.name, info.summary);
+    println!("{}", info.description);
+    println!("Usual action:   {}", <&str>::from(info.typical_action));
+    if !info.risks.is_empty() {
+        println!("Risks:          {}", info.risks.join(", "));
+    }
+    if info.supported {
+        println!("Supported:      yes");
+    } else {
+        println!("Supported:      no, {}", info.unsupported_reason);
+    }
+}
+
+fn print_readiness(readiness: &SwitchReadiness) {
+    if readiness.findings.is_empty() {
+        println!("{}: nothing keeps a switch from being made", readiness.mode);
+        return;
+    }
+    println!(
+        "{}: {}",
+        readiness.mode,
+        if readiness.ready {
+            "can be switched to with an override"
+        } else {
+            "can't be switched to now"
+        }
+    );
+    for finding in &readiness.findings {
+        let overridable = if finding.overridable {
+            " (can be overridden)"
+        } else {
+            ""
+        };
+        println!("  {}: {}{overridable}", finding.code, finding.message);
+    }
+}
+
+fn print_link_info(info: &LinkInfo) {
+    for (label, link) in [("dGPU", &info.dgpu), ("Port", &info.port)] {
+        if link.name.is_empty() {
+            continue;
+        }
+        println!("{label}:           {} ({})", link.name, link.runtime_status);
+        if !link.current_speed.is_empty() {
+            println!(
+                "  Link:         {} x{} (max {} x{})",
+                link.current_speed, link.current_width, link.max_speed, link.max_width
+            );
+        }
+        if link.aspm_enabled.is_empty() {
+            println!("  ASPM enabled: none");
+        } else {
+            println!("  ASPM enabled: {}", link.aspm_enabled.join(", "));
+        }
+    }
+    if !info.aspm_policy.is_empty() {
+        println!("ASPM policy:    {}", info.aspm_policy);
+    }
+    for note in &info.notes {
+        println!("Note: {note}");
+    }
+}
+
+/// The daemon build info, or only its version for a daemon older than `BuildInfo`
+fn daemon_build_info(connection: &Connection) -> Result<BuildInfo, GfxError> {
+    let proxy = DaemonProxyBlocking::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    match proxy.build_info() {
+        Ok(info) => Ok(info),
+        Err(_) => Ok(BuildInfo::version_only(proxy.version()?)),
+    }
+}
+
+fn print_self_test(report: &SelfTestReport) {
+    if report.test_mode == GfxMode::None {
+        println!(
+            "No mode can be switched to and back from {} without a logout or reboot, only checks were run",
+            report.original_mode
+        );
+    }
+    for phase in &report.phases {
+        let result = if phase.passed { "ok" } else { "FAILED" };
+        println!("{result:>6} {:>6}ms  {}", phase.duration_ms, phase.name);
+        if !phase.detail.is_empty() {
+            println!("               {}", phase.detail);
+        }
+    }
+    if report.restored {
+        println!("The original state was restored");
     }
-    false
+    println!(
+        "Self-test {}",
+        if report.passed { "passed" } else { "failed" }
+    );
 }
@@ -0,0 +1,416 @@
+use std::{fmt::Write, process::Command};
+
+use zbus::DBusError;
+
+use crate::{
+    error::{parse_daemon_message, GfxError},
+    DBUS_DEST_NAME,
+};
+
+/// The unit of the daemon, looked at when it can't be reached
+pub const DAEMON_UNIT: &str = "supergfxd.service";
+
+/// The kinds of failure supergfxctl tells apart, each with its own exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// supergfxd couldn't do what was asked, or something failed in supergfxctl
+    Failed,
+    /// A mode, option or value which isn't accepted
+    InvalidInput,
+    /// The bus or supergfxd refused the caller, such as for a method only root may call
+    PermissionDenied,
+    /// There is no supergfxd unit
+    NotInstalled,
+    /// supergfxd is installed but isn't running, or isn't on the bus
+    NotRunning,
+    /// supergfxd and supergfxctl don't agree on the dbus interface, such as after an update
+    /// which supergfxd wasn't restarted for
+    VersionMismatch,
+    /// The change was made but couldn't be saved, so it is lost when supergfxd restarts
+    NotPersisted,
+}
+
+impl ErrorClass {
+    /// The exit code of supergfxctl for the class
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Failed => 1,
+            ErrorClass::InvalidInput => 2,
+            ErrorClass::PermissionDenied => 3,
+            ErrorClass::NotInstalled => 4,
+            ErrorClass::NotRunning => 5,
+            ErrorClass::VersionMismatch => 6,
+            ErrorClass::NotPersisted => 7,
+        }
+    }
+
+    /// Where to look next, for the classes the message doesn't already say
+    fn pointer(self) -> Option<&'static str> {
+        match self {
+            ErrorClass::Failed => Some(
+                "Check `journalctl -b -u supergfxd`, and for a bug report attach a bundle written with `sudo supergfxctl --bundle PATH`",
+            ),
+            ErrorClass::VersionMismatch => Some(
+                "Restart supergfxd after an update with `systemctl restart supergfxd`, `supergfxctl --version` shows both versions",
+            ),
+            ErrorClass::NotPersisted => {
+                Some("Check the config file can be written, `journalctl -b -u supergfxd` has why it couldn't")
+            }
+            _ => None,
+        }
+    }
+
+    /// The class of an error the daemon sent with `code`, `None` for a code it doesn't
+    /// tell apart so the dbus error name decides
+    fn of_code(code: &str) -> Option<ErrorClass> {
+        match code {
+            "parse-mode"
+            | "parse-vendor"
+            | "not-supported"
+            | "invalid-input"
+            | "disabled-actions"
+            | "display-manager-units"
+            | "dock-profiles"
+            | "schedule"
+            | "modprobe-extra-options"
+            | "hotplug-unusable"
+            | "vfio-disabled"
+            | "no-igpu" => Some(ErrorClass::InvalidInput),
+            "cancel-not-allowed" | "mode-locked" | "debug-mode" => {
+                Some(ErrorClass::PermissionDenied)
+            }
+            "config-not-persisted" => Some(ErrorClass::NotPersisted),
+            _ => None,
+        }
+    }
+
+    /// The class of a dbus error, from the bus or the daemon
+    fn of_dbus_name(name: &str) -> ErrorClass {
+        match name
+            .strip_prefix("org.freedesktop.DBus.Error.")
+            .unwrap_or_default()
+        {
+            "AccessDenied" | "AuthFailed" | "InteractiveAuthorizationRequired" => {
+                ErrorClass::PermissionDenied
+            }
+            "InvalidArgs" | "NotSupported" => ErrorClass::InvalidInput,
+            "IOError" => ErrorClass::NotPersisted,
+            "ServiceUnknown" | "NameHasNoOwner" | "Spawn.ServiceNotFound" | "Disconnected" => {
+                ErrorClass::NotRunning
+            }
+            "UnknownMethod" | "UnknownInterface" | "UnknownObject" | "UnknownProperty"
+            | "InvalidSignature" | "PropertyReadOnly" => ErrorClass::VersionMismatch,
+            _ => ErrorClass::Failed,
+        }
+    }
+}
+
+/// Hints by the code of the error, printed with `--verbose`
+const HINTS: &[(&str, &str)] = &[
+    (
+        "mode-locked",
+        "An administrator locked the mode, unlock it with `sudo supergfxctl --unlock`",
+    ),
+    (
+        "cancel-not-allowed",
+        "With `cancel_requester_only` set only the user who asked for the switch, or root, can cancel it",
+    ),
+    (
+        "debug-mode",
+        "This supergfxd was started with --debug-run and changes nothing",
+    ),
+    (
+        "device-busy",
+        "A task stuck in the kernel on the dGPU can't be killed, usually only a reboot frees it. `journalctl -k` has the whole kernel log",
+    ),
+    (
+        "verify-failed",
+        "Check the filesystem of the file has space and isn't read-only with `df` and `mount`",
+    ),
+    (
+        "dgpu-off-bus",
+        "Try `supergfxctl --rescan`, otherwise reboot or suspend and resume",
+    ),
+    (
+        "vfio-disabled",
+        "Set `vfio_enable` to true in /etc/supergfxd/config.json and restart supergfxd",
+    ),
+    (
+        "vfio-builtin",
+        "Vfio needs the vfio modules built as modules, not into the kernel",
+    ),
+    (
+        "mux-discreet",
+        "Switch back with `supergfxctl --mode Hybrid` and reboot before changing to another mode",
+    ),
+    (
+        "no-switch-pending",
+        "There is nothing to cancel, `supergfxctl --pend-mode` shows a pending switch",
+    ),
+    (
+        "switch-committed",
+        "Follow the switch with `supergfxctl --watch-switch`, then switch back once it is done",
+    ),
+    (
+        "switch-in-progress",
+        "Follow the switch with `supergfxctl --watch-switch` and try again once it is done",
+    ),
+    (
+        "vfio-in-use",
+        "Shut down the VM the dGPU is passed through to, then switch",
+    ),
+    (
+        "shutting-down",
+        "Try again once supergfxd has restarted, `systemctl status supergfxd` shows when",
+    ),
+    (
+        "protected-gpu-users",
+        "Close the programs listed, or take them out of `never_kill` in the config",
+    ),
+    (
+        "logout-timeout",
+        "Log out of every graphical session, or raise `logout_timeout_s` in the config",
+    ),
+    ("no-igpu", "Turn the iGPU on in the BIOS to use this mode"),
+    (
+        "hotplug-unusable",
+        "Set `hotplug_type` to None in the config, this machine can't use the one asked for",
+    ),
+    (
+        "missing-module",
+        "Install the kernel modules of the driver for the running kernel",
+    ),
+    (
+        "pci-not-settled",
+        "Another tool may be removing or rescanning PCI devices, try again once it is done",
+    ),
+    (
+        "post-condition",
+        "An action didn't take effect with `strict_verify` set, a bundle from `sudo supergfxctl --bundle PATH` has what was seen",
+    ),
+    (
+        "hook",
+        "Check the `hook_command_pre_switch` and `hook_command_post_switch` commands of the config work when run by hand",
+    ),
+    (
+        "systemd-unit-timeout",
+        "A display manager didn't stop or start in time, see `systemctl status display-manager`",
+    ),
+    (
+        "config-not-persisted",
+        "The change is lost when supergfxd restarts unless the config file can be written",
+    ),
+    (
+        "invalid-input",
+        "Check the value given, `supergfxctl --help` lists the options",
+    ),
+];
+
+/// The hint for the error code `code`, if there is one
+pub fn hint(code: &str) -> Option<&'static str> {
+    HINTS
+        .iter()
+        .find(|(hinted, _)| *hinted == code)
+        .map(|(_, hint)| *hint)
+}
+
+/// The state of the supergfxd unit, from `systemctl show`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DaemonUnit {
+    /// `loaded`, or `not-found` if there is no such unit
+    pub load_state: String,
+    /// Such as `active`, `inactive` or `failed`
+    pub active_state: String,
+    /// Such as `enabled` or `disabled`
+    pub unit_file_state: String,
+}
+
+impl DaemonUnit {
+    /// Read the `Property=value` lines `systemctl show` prints
+    pub fn parse(show: &str) -> Self {
+        let mut unit = Self::default();
+        for (key, value) in show.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "LoadState" => unit.load_state = value.to_string(),
+                "ActiveState" => unit.active_state = value.to_string(),
+                "UnitFileState" => unit.unit_file_state = value.to_string(),
+                _ => {}
+            }
+        }
+        unit
+    }
+
+    /// Ask systemd for the state of `DAEMON_UNIT`, `None` if it can't be asked
+    pub fn query() -> Option<Self> {
+        let output = Command::new("systemctl")
+            .args([
+                "show",
+                "--property=LoadState,ActiveState,UnitFileState",
+                DAEMON_UNIT,
+            ])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(Self::parse(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// A failed command as supergfxctl reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliError {
+    pub class: ErrorClass,
+    /// The code the daemon sent with the error, such as `mode-locked`
+    pub code: Option<String>,
+    /// The name of the dbus error, such as `org.freedesktop.DBus.Error.AccessDenied`
+    pub dbus_name: Option<String>,
+    /// What went wrong, in one line
+    pub message: String,
+    /// Everything known about the error, outermost first, printed with `--verbose`
+    pub chain: Vec<String>,
+}
+
+impl CliError {
+    /// Take apart `err`, with the code and message of the daemon if it sent them
+    pub fn from_error(err: &GfxError) -> Self {
+        let mut cli_error = match err {
+            GfxError::Zbus(err) => Self::from_zbus(err),
+            GfxError::ZbusFdo(err) => Self::from_fdo(err),
+            err => Self {
+                class: ErrorClass::of_code(err.code()).unwrap_or(ErrorClass::Failed),
+                code: Some(err.code().to_string()),
+                dbus_name: None,
+                message: err.to_string(),
+                chain: Vec::new(),
+            },
+        };
+        cli_error.chain.insert(0, err.to_string());
+        cli_error
+    }
+
+    fn from_zbus(err: &zbus::Error) -> Self {
+        let local = |class: ErrorClass, message: String| Self {
+            class,
+            code: None,
+            dbus_name: None,
+            message,
+            chain: Vec::new(),
+        };
+        match err {
+            zbus::Error::MethodError(name, text, _) => {
+                Self::from_reply(name.as_str(), text.as_deref())
+            }
+            zbus::Error::FDO(err) => Self::from_fdo(err),
+            zbus::Error::InputOutput(io) if io.kind() == std::io::ErrorKind::PermissionDenied => {
+                local(
+                    ErrorClass::PermissionDenied,
+                    format!("Can't connect to the bus: {io}"),
+                )
+            }
+            zbus::Error::InputOutput(io) => local(
+                ErrorClass::Failed,
+                format!("Can't connect to the bus: {io}"),
+            ),
+            zbus::Error::Variant(_)
+            | zbus::Error::InvalidReply
+            | zbus::Error::InvalidField
+            | zbus::Error::MissingField
+            | zbus::Error::ExcessData
+            | zbus::Error::IncorrectEndian
+            | zbus::Error::InterfaceNotFound => local(
+                ErrorClass::VersionMismatch,
+                format!("The reply of supergfxd couldn't be read: {err}"),
+            ),
+            err => local(ErrorClass::Failed, err.to_string()),
+        }
+    }
+
+    fn from_fdo(err: &zbus::fdo::Error) -> Self {
+        match err {
+            zbus::fdo::Error::ZBus(err) => Self::from_zbus(err),
+            err => Self::from_reply(err.name().as_str(), err.description()),
+        }
+    }
+
+    /// An error reply named `name`, with the code of the daemon if `text` has one
+    fn from_reply(name: &str, text: Option<&str>) -> Self {
+        let (code, message) = parse_daemon_message(text.unwrap_or(name));
+        let class = code
+            .and_then(ErrorClass::of_code)
+            .unwrap_or_else(|| ErrorClass::of_dbus_name(name));
+        let message = if class == ErrorClass::NotRunning {
+            format!("{DBUS_DEST_NAME} can't be reached on the bus")
+        } else {
+            message.to_string()
+        };
+        Self {
+            class,
+            code: code.map(str::to_string),
+            dbus_name: Some(name.to_string()),
+            message,
+            chain: Vec::new(),
+        }
+    }
+
+    /// If supergfxd couldn't be reached, say why from the state of its unit given by `query`.
+    /// Nothing is queried for other errors.
+    pub fn with_unit(mut self, query: impl FnOnce() -> Option<DaemonUnit>) -> Self {
+        if self.class != ErrorClass::NotRunning {
+            return self;
+        }
+        let unit = match query() {
+            Some(unit) => unit,
+            None => return self,
+        };
+        self.message = if unit.load_state == "not-found" {
+            self.class = ErrorClass::NotInstalled;
+            "supergfxd is not installed, install it with your package manager or `make && sudo make install`".to_string()
+        } else if unit.active_state == "failed" {
+            "supergfxd failed, see `systemctl status supergfxd` and `journalctl -b -u supergfxd`"
+                .to_string()
+        } else if unit.active_state != "active" && unit.unit_file_state != "enabled" {
+            "supergfxd is not enabled, enable and start it with `systemctl enable --now supergfxd`"
+                .to_string()
+        } else if unit.active_state != "active" {
+            "supergfxd is not running, start it with `systemctl start supergfxd`".to_string()
+        } else {
+            "supergfxd is running but isn't on the bus, check `journalctl -b -u supergfxd`"
+                .to_string()
+        };
+        self.chain.push(format!(
+            "{DAEMON_UNIT}: LoadState={} ActiveState={} UnitFileState={}",
+            unit.load_state, unit.active_state, unit.unit_file_state
+        ));
+        self
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.class.exit_code()
+    }
+
+    /// The error in red on one line, then where to look next for the classes which need
+    /// it, which for a failure is only if supergfxd sent it. With `verbose` also the whole
+    /// chain and the hint for the code.
+    pub fn render(&self, verbose: bool) -> String {
+        let mut out = format!("\x1b[0;31mError: {}\x1b[0m", self.message);
+        if let Some(code) = &self.code {
+            write!(out, " [{code}]").ok();
+        }
+        out.push('\n');
+        let local_failure = self.class == ErrorClass::Failed && self.dbus_name.is_none();
+        if let Some(pointer) = self.class.pointer().filter(|_| !local_failure) {
+            writeln!(out, "{pointer}").ok();
+        }
+        if verbose {
+            for line in &self.chain {
+                writeln!(out, "  {line}").ok();
+            }
+            writeln!(out, "  {:?}, exit code {}", self.class, self.exit_code()).ok();
+            if let Some(hint) = self.code.as_deref().and_then(hint) {
+                writeln!(out, "\x1b[0;33mHint: {hint}\x1b[0m").ok();
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,91 @@
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    cli_error::CliError,
+    controller::{GfxState, GfxStatus, PendingInfo},
+    pci_device::GfxMode,
+};
+
+/// The fields which are a mode in `GfxStatus`, `GfxState` and `PendingInfo`
+const MODE_FIELDS: &[&str] = &["mode", "pending_mode"];
+
+/// A mode as JSON: its name as serde gives it, such as `"Hybrid"`, or `null` for
+/// `GfxMode::None`, as when no switch is pending
+pub fn mode_value(mode: GfxMode) -> Value {
+    if mode == GfxMode::None {
+        Value::Null
+    } else {
+        to_value(mode)
+    }
+}
+
+fn to_value(value: impl Serialize) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+/// `value` serialised as an object, with the modes in it as for `mode_value`
+fn object_of(value: impl Serialize) -> Map<String, Value> {
+    let mut fields = match to_value(value) {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    };
+    for key in MODE_FIELDS {
+        if let Some(field) = fields.get_mut(*key) {
+            if *field == to_value(GfxMode::None) {
+                *field = Value::Null;
+            }
+        }
+    }
+    fields
+}
+
+/// The results of the queries asked for with `supergfxctl --json`, printed together as one
+/// object for scripts and status bars. The enums are as their serde names, the same as in
+/// the config, such as `"Hybrid"` or `"Suspended"`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct JsonOutput(Map<String, Value>);
+
+impl JsonOutput {
+    pub fn insert(&mut self, key: &str, value: impl Serialize) {
+        self.0.insert(key.to_string(), to_value(value));
+    }
+
+    /// Add a mode, see `mode_value`
+    pub fn insert_mode(&mut self, key: &str, mode: GfxMode) {
+        self.0.insert(key.to_string(), mode_value(mode));
+    }
+
+    /// The fields of `--all`
+    pub fn state(&mut self, state: &GfxState) {
+        self.0.extend(object_of(state));
+    }
+
+    /// Every field of `Status`, for `--json` without a query
+    pub fn status(&mut self, status: &GfxStatus) {
+        self.0.extend(object_of(status));
+    }
+
+    /// The pending switch of `--pend-info` as the `pending` object
+    pub fn pending_info(&mut self, info: &PendingInfo) {
+        self.0
+            .insert("pending".to_string(), Value::Object(object_of(info)));
+    }
+
+    /// The object on one line
+    pub fn render(&self) -> String {
+        Value::Object(self.0.clone()).to_string()
+    }
+}
+
+/// A failed command with `--json`, on one line: the message as `error`, with the code the
+/// daemon sent if any, the class and the exit code
+pub fn error_json(err: &CliError) -> String {
+    json!({
+        "error": err.message,
+        "code": err.code,
+        "class": format!("{:?}", err.class),
+        "exit_code": err.exit_code(),
+    })
+    .to_string()
+}
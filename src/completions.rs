@@ -0,0 +1,275 @@
+use std::{fmt::Write, str::FromStr, time::Duration};
+
+use crate::{error::GfxError, pci_device::GfxMode};
+
+/// How long `--list-modes` waits for the daemon before listing every mode, short enough
+/// not to be noticed when pressing tab
+pub const LIST_MODES_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Every mode which can be set, listed when the daemon can't be asked
+pub const SETTABLE_MODES: [GfxMode; 6] = [
+    GfxMode::Hybrid,
+    GfxMode::Integrated,
+    GfxMode::NvidiaNoModeset,
+    GfxMode::Vfio,
+    GfxMode::AsusEgpu,
+    GfxMode::AsusMuxDgpu,
+];
+
+/// The shells completion scripts are generated for
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = GfxError;
+
+    fn from_str(s: &str) -> Result<Self, GfxError> {
+        match s.trim().to_lowercase().as_str() {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            _ => Err(GfxError::NotSupported(format!(
+                "No completions for {s}, the shells are bash, zsh and fish"
+            ))),
+        }
+    }
+}
+
+/// An option of the CLI as shown in its usage
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CliOption {
+    pub short: Option<char>,
+    pub long: String,
+    /// The value name such as `PATH`, `None` for a flag
+    pub meta: Option<String>,
+    pub help: String,
+}
+
+/// The options in a gumdrop usage text, leaving out those named in `hidden`
+pub fn parse_usage(usage: &str, hidden: &[&str]) -> Vec<CliOption> {
+    let mut options: Vec<CliOption> = Vec::new();
+    for line in usage.lines() {
+        let trimmed = line.trim_start();
+        if !trimmed.starts_with('-') {
+            // A help text too long for its column is wrapped onto the next line
+            if let Some(last) = options.last_mut() {
+                if last.help.is_empty() && line.starts_with("  ") {
+                    last.help = trimmed.to_string();
+                }
+            }
+            continue;
+        }
+        let (names, help) = match trimmed.find("  ") {
+            Some(i) => (&trimmed[..i], trimmed[i..].trim()),
+            None => (trimmed, ""),
+        };
+        let mut short = None;
+        let mut long = None;
+        let mut meta = None;
+        for word in names.split([',', ' ']).filter(|w| !w.is_empty()) {
+            if let Some(name) = word.strip_prefix("--") {
+                long = Some(name.to_string());
+            } else if let Some(name) = word.strip_prefix('-') {
+                short = name.chars().next();
+            } else {
+                meta = Some(word.to_string());
+            }
+        }
+        if let Some(long) = long.filter(|long| !hidden.contains(&long.as_str())) {
+            options.push(CliOption {
+                short,
+                long,
+                meta,
+                help: help.to_string(),
+            });
+        }
+    }
+    options
+}
+
+/// `usage` without the lines for the options in `hidden`
+pub fn hide_options(usage: &str, hidden: &[&str]) -> String {
+    usage
+        .lines()
+        .filter(|line| {
+            let trimmed = line.trim_start();
+            !hidden.iter().any(|name| {
+                trimmed
+                    .strip_prefix("--")
+                    .and_then(|rest| rest.strip_prefix(name))
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with(' '))
+            })
+        })
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// The `--list-modes` output, one mode per line: those in `supported`, or every mode which
+/// can be set if the daemon couldn't be asked
+pub fn list_modes(supported: Option<&[GfxMode]>) -> String {
+    let modes: Vec<GfxMode> = match supported {
+        Some(supported) => supported
+            .iter()
+            .copied()
+            .filter(|mode| *mode != GfxMode::None)
+            .collect(),
+        None => SETTABLE_MODES.to_vec(),
+    };
+    modes.iter().map(|mode| format!("{mode}\n")).collect()
+}
+
+/// Check `mode` is one of the `supported` modes before asking the daemon to switch, the
+/// error lists the supported modes
+pub fn check_mode_supported(mode: GfxMode, supported: &[GfxMode]) -> Result<(), GfxError> {
+    if supported.contains(&mode) {
+        return Ok(());
+    }
+    let names: Vec<String> = supported.iter().map(|mode| mode.to_string()).collect();
+    Err(GfxError::NotSupported(format!(
+        "{mode} is not supported on this system, the supported modes are: {}",
+        names.join(", ")
+    )))
+}
+
+/// Options which take a file path
+const PATH_OPTIONS: &[&str] = &["bundle"];
+/// Options which take a mode
+const MODE_OPTIONS: &[&str] = &["mode", "describe", "why-not", "plan"];
+
+fn names(option: &CliOption) -> String {
+    match option.short {
+        Some(short) => format!("-{short}|--{}", option.long),
+        None => format!("--{}", option.long),
+    }
+}
+
+/// The completion script for `shell`. Modes are completed by running `<bin> --list-modes`.
+pub fn completion_script(shell: Shell, bin: &str, options: &[CliOption]) -> String {
+    let func = format!("_{}", bin.replace('-', "_"));
+    let mut out = String::new();
+    match shell {
+        Shell::Bash => {
+            let words: Vec<String> = options
+                .iter()
+                .flat_map(|option| {
+                    option
+                        .short
+                        .map(|short| format!("-{short}"))
+                        .into_iter()
+                        .chain([format!("--{}", option.long)])
+                })
+                .collect();
+            writeln!(
+                out,
+                "# bash completion for {bin}, generated by `{bin} --completions bash`"
+            )
+            .ok();
+            writeln!(out, "{func}() {{").ok();
+            writeln!(out, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").ok();
+            writeln!(out, "    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").ok();
+            writeln!(out, "    case \"$prev\" in").ok();
+            for option in options {
+                if MODE_OPTIONS.contains(&option.long.as_str()) {
+                    writeln!(out, "        {})", names(option)).ok();
+                    writeln!(out, "            COMPREPLY=($(compgen -W \"$({bin} --list-modes 2>/dev/null)\" -- \"$cur\"))").ok();
+                    writeln!(out, "            return").ok();
+                    writeln!(out, "            ;;").ok();
+                } else if PATH_OPTIONS.contains(&option.long.as_str()) {
+                    writeln!(out, "        {})", names(option)).ok();
+                    writeln!(out, "            COMPREPLY=($(compgen -f -- \"$cur\"))").ok();
+                    writeln!(out, "            return").ok();
+                    writeln!(out, "            ;;").ok();
+                } else if option.meta.is_some() {
+                    writeln!(out, "        {})", names(option)).ok();
+                    writeln!(out, "            return").ok();
+                    writeln!(out, "            ;;").ok();
+                }
+            }
+            writeln!(out, "    esac").ok();
+            writeln!(
+                out,
+                "    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                words.join(" ")
+            )
+            .ok();
+            writeln!(out, "}}").ok();
+            writeln!(out, "complete -F {func} {bin}").ok();
+        }
+        Shell::Zsh => {
+            writeln!(out, "#compdef {bin}").ok();
+            writeln!(
+                out,
+                "# zsh completion for {bin}, generated by `{bin} --completions zsh`"
+            )
+            .ok();
+            writeln!(out, "{func}_modes() {{").ok();
+            writeln!(
+                out,
+                "    compadd -- ${{(f)\"$({bin} --list-modes 2>/dev/null)\"}}"
+            )
+            .ok();
+            writeln!(out, "}}").ok();
+            let mut specs = Vec::new();
+            for option in options {
+                let help = option.help.replace('\'', "'\\''").replace(['[', ']'], "");
+                let action = if MODE_OPTIONS.contains(&option.long.as_str()) {
+                    format!(":mode:{func}_modes")
+                } else if PATH_OPTIONS.contains(&option.long.as_str()) {
+                    ":path:_files".to_string()
+                } else if let Some(meta) = &option.meta {
+                    format!(":{}: ", meta.to_lowercase())
+                } else {
+                    String::new()
+                };
+                let spec = match option.short {
+                    Some(short) => format!(
+                        "'(-{short} --{long})'{{-{short},--{long}}}'[{help}]{action}'",
+                        long = option.long
+                    ),
+                    None => format!("'--{}[{help}]{action}'", option.long),
+                };
+                specs.push(spec);
+            }
+            writeln!(out, "{func}() {{").ok();
+            writeln!(
+                out,
+                "    _arguments \\\n        {}",
+                specs.join(" \\\n        ")
+            )
+            .ok();
+            writeln!(out, "}}").ok();
+            writeln!(out, "{func} \"$@\"").ok();
+        }
+        Shell::Fish => {
+            writeln!(
+                out,
+                "# fish completion for {bin}, generated by `{bin} --completions fish`"
+            )
+            .ok();
+            writeln!(out, "complete -c {bin} -f").ok();
+            for option in options {
+                let mut line = format!("complete -c {bin}");
+                if let Some(short) = option.short {
+                    write!(line, " -s {short}").ok();
+                }
+                write!(line, " -l {}", option.long).ok();
+                if MODE_OPTIONS.contains(&option.long.as_str()) {
+                    write!(line, " -x -a '({bin} --list-modes 2>/dev/null)'").ok();
+                } else if PATH_OPTIONS.contains(&option.long.as_str()) {
+                    write!(line, " -r -F").ok();
+                } else if option.meta.is_some() {
+                    write!(line, " -x").ok();
+                }
+                if !option.help.is_empty() {
+                    write!(line, " -d '{}'", option.help.replace('\'', "\\'")).ok();
+                }
+                writeln!(out, "{line}").ok();
+            }
+        }
+    }
+    out
+}
@@ -1,16 +1,32 @@
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use serde_derive::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use zbus::zvariant::Type;
 
-use crate::actions::UserActionRequired;
-use crate::config_old::{GfxConfig300, GfxConfig405, GfxConfig500};
+use crate::ac_automation::AcAutomation;
+use crate::acpi_dgpu::AcpiDgpuOff;
+use crate::actions::{validate_disabled_actions, NvidiaPowerdStart, UserActionRequired};
+use crate::config_old::{fixup_legacy_modes, GfxConfig300, GfxConfig405, GfxConfig500};
+use crate::controller::{PendingRequest, SwitchState};
+use crate::dock_automation::DockProfiles;
 use crate::error::GfxError;
-use crate::pci_device::{DiscreetGpu, GfxMode, HotplugType};
+use crate::logout_switch::{LogoutPolicy, LogoutTimeoutAction};
+use crate::pci_device::{Device, DiscreetGpu, GfxMode, HotplugType, NvidiaDriver};
+use crate::power_watch::POWER_POLL_FAST;
+use crate::sandbox::note_write;
+use crate::schedule::{validate_schedule, ScheduleEntry};
+use crate::thermal::{ThermalAdvisory, AVERAGE_WINDOW_S};
+use crate::validate::{self, InputClass};
+use crate::verified_write::write_verified;
 use crate::{
-    CONFIG_NVIDIA_VKICD, MODPROBE_INTEGRATED, MODPROBE_NVIDIA_BASE, MODPROBE_NVIDIA_DRM_MODESET_ON,
-    MODPROBE_PATH, MODPROBE_VFIO, MODPROBE_NVIDIA_EC_BKLT
+    CONFIG_NVIDIA_VKICD, CONFIG_PATH, CONFIG_PATH_LEGACY, DISPLAY_MANAGER, MODPROBE_INTEGRATED,
+    MODPROBE_NOUVEAU_BASE, MODPROBE_NVIDIA_BASE, MODPROBE_NVIDIA_DRM_MODESET_ON,
+    MODPROBE_NVIDIA_EC_BKLT, MODPROBE_PATH, MODPROBE_VFIO, STATE_DIR,
 };
 
 /// Cleaned config for passing over dbus only
@@ -23,6 +39,7 @@ pub struct GfxConfigDbus {
     pub no_logind: bool,
     pub logout_timeout_s: u64,
     pub hotplug_type: HotplugType,
+    pub display_manager_units: Vec<String>,
 }
 
 impl From<&GfxConfig> for GfxConfigDbus {
@@ -34,26 +51,51 @@ impl From<&GfxConfig> for GfxConfigDbus {
             always_reboot: c.always_reboot,
             no_logind: c.no_logind,
             logout_timeout_s: c.logout_timeout_s,
-            hotplug_type: c.hotplug_type,
+            hotplug_type: c.effective_hotplug_type(),
+            display_manager_units: c.display_manager_units.clone(),
         }
     }
 }
 
+/// A field of the config set to a new value, for `PreviewConfigChange`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Type)]
+pub struct ConfigChange {
+    /// The name of the field in the config file, such as `vfio_enable`
+    pub field: String,
+    /// The value as JSON, as it would be in the config file, such as `true` or `[".2"]`
+    pub value: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GfxConfig {
     #[serde(skip)]
     pub config_path: String,
     /// The current mode set, also applies on boot
     pub mode: GfxMode,
-    /// Only for temporary modes like compute or vfio
+    /// The mode in use until reboot, for a switch which wasn't persisted or to a temporary
+    /// mode like vfio
     #[serde(skip)]
     pub tmp_mode: Option<GfxMode>,
+    /// Where `tmp_mode` is kept so it outlasts a restart of supergfxd, see
+    /// `keep_tmp_mode_at`. `None` keeps it only in memory.
+    #[serde(skip)]
+    pub tmp_mode_path: Option<PathBuf>,
     /// Just for tracking the requested mode change in rebootless mode
     #[serde(skip)]
     pub pending_mode: Option<GfxMode>,
     /// Just for tracking the required user action
     #[serde(skip)]
     pub pending_action: Option<UserActionRequired>,
+    /// Who asked for the pending mode change and when
+    #[serde(skip)]
+    pub pending_request: Option<PendingRequest>,
+    /// Tracks the spawned switch task so a failed or panicked switch can be seen and recovered from
+    #[serde(skip)]
+    pub switch_state: SwitchState,
+    /// Why the last write of the config failed, so changes only last until supergfxd
+    /// restarts. Cleared once a write succeeds.
+    #[serde(skip)]
+    pub write_error: Option<String>,
     /// Set if vfio option is enabled. This requires the vfio drivers to be built as modules
     pub vfio_enable: bool,
     /// Save the VFIO mode so that it is reloaded on boot
@@ -66,112 +108,869 @@ pub struct GfxConfig {
     pub logout_timeout_s: u64,
     /// The type of method to use for hotplug. ASUS is... fiddly.
     pub hotplug_type: HotplugType,
+    /// Set if `hotplug_type` can't be used on this machine, with why. `None` is used in its
+    /// place while the file keeps what was set, see `effective_hotplug_type`.
+    #[serde(skip)]
+    pub hotplug_downgrade: Option<String>,
+    /// Seconds to wait after all sessions have ended and before the display manager is stopped,
+    /// during which the switch can still be cancelled. 0 = no delay.
+    #[serde(default)]
+    pub pre_stop_delay_s: u64,
+    /// Pin the system to `mode`, mode changes over dbus are refused. Boot tasks for `mode`
+    /// still run. Can only be changed by editing the file or by root.
+    #[serde(default)]
+    pub mode_locked: bool,
+    /// Only the user who asked for a pending switch, or root, may cancel it
+    #[serde(default)]
+    pub cancel_requester_only: bool,
+    /// Leave the vfio modules loaded when leaving Vfio and only unbind the dGPU from them,
+    /// for faster switches or when other devices use vfio
+    #[serde(default)]
+    pub vfio_keep_loaded: bool,
+    /// Suggest or switch to a mode when AC is plugged in or unplugged
+    #[serde(default)]
+    pub ac_automation: AcAutomation,
+    /// Suggest or switch to a mode when the machine is docked or undocked
+    #[serde(default)]
+    pub dock_profiles: DockProfiles,
+    /// Actions left out of every switch and boot plan, by name such as `StartDisplayManager`,
+    /// for distros which do that part of a switch themselves. Checked on load, a removal
+    /// which would leave a plan in an invalid order is refused.
+    #[serde(default)]
+    pub disabled_actions: Vec<String>,
+    /// Don't render the modprobe conf for the likely next mode ahead of a switch
+    #[serde(default)]
+    pub no_warm_staging: bool,
+    /// Hide the dGPU from switcheroo-control in modes where it can't be used, so desktop
+    /// "Launch using Discrete Graphics Card" menus only appear when it works
+    #[serde(default)]
+    pub manage_switcheroo: bool,
+    /// Check every this many hours that the mode is still applied, putting back what
+    /// supergfxd owns and reporting the rest. `None` to not check.
+    #[serde(default)]
+    pub periodic_verify_hours: Option<u64>,
+    /// Seconds the dGPU must stay awake in Hybrid on battery before the processes keeping
+    /// it awake are looked for. `0` to not look.
+    #[serde(default = "default_power_blocker_threshold")]
+    pub power_blocker_threshold_s: u64,
+    /// Check that each action of a switch took effect before going on, stopping the switch
+    /// at the first which didn't
+    #[serde(default)]
+    pub strict_verify: bool,
+    /// Processes the kill step of a switch must never kill, by exact name or absolute path
+    /// of the executable. A switch which would have to fails before killing anything.
+    #[serde(default)]
+    pub never_kill: Vec<String>,
+    /// Processes the kill step always kills, also when switching away from an AMD dGPU
+    /// which otherwise kills nothing
+    #[serde(default)]
+    pub kill_without_prompt: Vec<String>,
+    /// Suggest leaving AsusMuxDgpu when the dGPU runs hot on battery for a long time
+    #[serde(default)]
+    pub thermal_advisory: ThermalAdvisory,
+    /// Exit with an error instead of running on when the boot tasks fail, so systemd's
+    /// `Restart=` and `OnFailure=` can act on it
+    #[serde(default)]
+    pub exit_on_degraded_boot: bool,
+    /// Functions of the dGPU supergfxd never touches, such as a USB-C controller which
+    /// stops working until reboot once removed. Full PCI addresses such as `0000:01:00.2`,
+    /// or the function such as `.2`.
+    #[serde(default)]
+    pub ignored_functions: Vec<String>,
+    /// What to do when graphical sessions are still open after `logout_timeout_s`: `Fail`,
+    /// `ConvertToDeferred` to wait on until they end, or `ForceIfIdle` to switch anyway if
+    /// none of them have the dGPU open
+    #[serde(default)]
+    pub logout_timeout_action: LogoutTimeoutAction,
+    /// Seconds to wait after a switch started the display manager for it to show something.
+    /// If it doesn't, how to recover is written to the text consoles. `0` to not wait.
+    #[serde(default = "default_display_watchdog")]
+    pub display_watchdog_s: u64,
+    /// Experimental. The ACPI methods called through acpi_call to power the dGPU off in
+    /// Integrated and on again, for older ASUS laptops without `dgpu_disable`
+    #[serde(default)]
+    pub acpi_dgpu_off: Option<AcpiDgpuOff>,
+    /// Milliseconds between reads of the dGPU power status when it is polled, from 100 to
+    /// 10000. Where the kernel sends udev events for it they are used instead, with a
+    /// slower keep-alive poll.
+    #[serde(default = "default_status_poll_ms")]
+    pub status_poll_ms: u64,
+    /// The systemd units stopped before and started after a switch which needs a logout,
+    /// such as a greeter and the display manager of a second seat
+    #[serde(default = "default_display_manager_units")]
+    pub display_manager_units: Vec<String>,
+    /// How many times loading or unloading a driver is tried before a switch fails, from 1
+    /// to 10. The wait between tries starts at 200ms and doubles each time.
+    #[serde(default = "default_driver_retry_count")]
+    pub driver_retry_count: u32,
+    /// Whether a switch which restarts the display manager starts nvidia-powerd before it,
+    /// or after it is active and a delay
+    #[serde(default)]
+    pub nvidia_powerd_start: NvidiaPowerdStart,
+    /// Run with `sh -c` before a switch, with `SUPERGFXD_FROM`, `SUPERGFXD_TO` and
+    /// `SUPERGFXD_RESULT` (`pending`) set
+    #[serde(default)]
+    pub hook_command_pre_switch: Option<String>,
+    /// Run with `sh -c` after a switch, with `SUPERGFXD_FROM`, `SUPERGFXD_TO` and
+    /// `SUPERGFXD_RESULT` set to `completed`, `rolled_back`, `stalled` or `parked`
+    #[serde(default)]
+    pub hook_command_post_switch: Option<String>,
+    /// A pre-switch hook which fails or times out cancels the switch, rather than only
+    /// being logged
+    #[serde(default)]
+    pub hook_pre_blocking: bool,
+    /// Seconds a hook may run before it is killed and counted as failed
+    #[serde(default = "default_hook_timeout")]
+    pub hook_timeout_s: u64,
+    /// Boots after which an attention item which was never dismissed is dropped. `0` keeps
+    /// them until dismissed.
+    #[serde(default = "default_attention_expiry_boots")]
+    pub attention_expiry_boots: u32,
+    /// Make the device nodes of the nvidia driver with nvidia-modprobe, or mknod if it
+    /// isn't installed, when they are missing or users can't open them
+    #[serde(default)]
+    pub fix_nvidia_nodes: bool,
+    /// Watch for an eGPU enclosure being connected or removed. AsusEgpu is then only
+    /// supported while one is connected, and removing it in AsusEgpu switches to Integrated.
+    #[serde(default)]
+    pub egpu_detect: bool,
+    /// Modes to use from times of day, such as Integrated from 22:30 and Hybrid from 08:00.
+    /// Each is suggested as it comes due, and switched to if nobody would notice.
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+    /// Lines added to the generated modprobe conf by mode name, each a module and its
+    /// options such as `"Hybrid": ["nvidia NVreg_PreserveVideoMemoryAllocations=1"]`,
+    /// written as `options nvidia NVreg_PreserveVideoMemoryAllocations=1`
+    #[serde(default)]
+    pub modprobe_extra_options: HashMap<String, Vec<String>>,
+    /// Switch modes on a desktop with the dGPU as its only GPU, which otherwise only gets the
+    /// read-only queries, for a desktop with a MUX or an eGPU
+    #[serde(default)]
+    pub force_enable_on_desktop: bool,
+}
+
+fn default_display_manager_units() -> Vec<String> {
+    vec![DISPLAY_MANAGER.to_string()]
+}
+
+/// Check `display_manager_units` has at least one unit and each is a plain `.service` or
+/// `.target` unit name, as it is passed to `systemctl`
+pub(crate) fn validate_display_manager_units(units: &[String]) -> Result<(), GfxError> {
+    if units.is_empty() {
+        return Err(GfxError::DisplayManagerUnits(
+            "at least one unit is needed".to_string(),
+        ));
+    }
+    for unit in units {
+        validate::unit_name(unit).map_err(|err| GfxError::DisplayManagerUnits(err.to_string()))?;
+    }
+    Ok(())
+}
+
+/// The longest line of options for one module in `modprobe_extra_options`
+const MODPROBE_OPTIONS_MAX: usize = 1024;
+
+/// Check each key of `modprobe_extra_options` is a mode name and each entry is a module
+/// name followed by its options, on one line
+pub(crate) fn validate_modprobe_extra_options(
+    options: &HashMap<String, Vec<String>>,
+) -> Result<(), GfxError> {
+    let invalid = GfxError::ModprobeExtraOptions;
+    for (name, entries) in options {
+        if !GfxMode::wire_order()
+            .iter()
+            .any(|mode| *mode != GfxMode::None && mode.to_string() == *name)
+        {
+            return Err(invalid(format!("{name:?} is not a mode name")));
+        }
+        for entry in entries {
+            validate::text(entry, MODPROBE_OPTIONS_MAX)
+                .map_err(|err| invalid(format!("{name}: {err}")))?;
+            let mut words = entry.split_whitespace();
+            let module = words.next().unwrap_or_default();
+            validate::identifier(module)
+                .map_err(|err| invalid(format!("{name}: module name {err}")))?;
+            if words.next().is_none() {
+                return Err(invalid(format!("{name}: {entry:?} has no options")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The entries of `modprobe_extra_options` for `mode`
+pub(crate) fn modprobe_extra_for(
+    options: &HashMap<String, Vec<String>>,
+    mode: GfxMode,
+) -> &[String] {
+    options
+        .get(&mode.to_string())
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+fn default_power_blocker_threshold() -> u64 {
+    600
+}
+
+fn default_display_watchdog() -> u64 {
+    60
+}
+
+fn default_status_poll_ms() -> u64 {
+    POWER_POLL_FAST.as_millis() as u64
 }
 
+/// The bounds of `status_poll_ms`
+pub(crate) const STATUS_POLL_MS: RangeInclusive<u64> = 100..=10_000;
+
+fn default_driver_retry_count() -> u32 {
+    5
+}
+
+/// The longest hook command, which must be on one line
+const HOOK_COMMAND_MAX: usize = 4096;
+
+fn default_hook_timeout() -> u64 {
+    30
+}
+
+fn default_attention_expiry_boots() -> u32 {
+    5
+}
+
+/// The bounds of `driver_retry_count`
+pub(crate) const DRIVER_RETRY_COUNT: RangeInclusive<u32> = 1..=10;
+
+/// Where supergfxd keeps `tmp_mode`. `/run` is emptied at reboot, so a restart of
+/// supergfxd stays in the temporary mode while the next boot is back in the persisted one.
+pub const TMP_MODE_PATH: &str = "/run/supergfxd/tmp_mode";
+
 impl GfxConfig {
-    fn new(config_path: String) -> Self {
+    pub(crate) fn new(config_path: String) -> Self {
         Self {
             config_path,
             mode: GfxMode::Hybrid,
             tmp_mode: None,
+            tmp_mode_path: None,
             pending_mode: None,
             pending_action: None,
+            pending_request: None,
+            switch_state: SwitchState::Idle,
+            write_error: None,
             vfio_enable: false,
             vfio_save: false,
             always_reboot: false,
             no_logind: false,
             logout_timeout_s: 180,
             hotplug_type: HotplugType::None,
+            hotplug_downgrade: None,
+            pre_stop_delay_s: 0,
+            mode_locked: false,
+            cancel_requester_only: false,
+            vfio_keep_loaded: false,
+            ac_automation: AcAutomation::default(),
+            dock_profiles: DockProfiles::default(),
+            disabled_actions: Vec::new(),
+            no_warm_staging: false,
+            manage_switcheroo: false,
+            periodic_verify_hours: None,
+            power_blocker_threshold_s: default_power_blocker_threshold(),
+            strict_verify: false,
+            never_kill: Vec::new(),
+            kill_without_prompt: Vec::new(),
+            thermal_advisory: ThermalAdvisory::default(),
+            exit_on_degraded_boot: false,
+            ignored_functions: Vec::new(),
+            logout_timeout_action: LogoutTimeoutAction::Fail,
+            display_watchdog_s: default_display_watchdog(),
+            acpi_dgpu_off: None,
+            status_poll_ms: default_status_poll_ms(),
+            display_manager_units: default_display_manager_units(),
+            driver_retry_count: default_driver_retry_count(),
+            nvidia_powerd_start: NvidiaPowerdStart::default(),
+            hook_command_pre_switch: None,
+            hook_command_post_switch: None,
+            hook_pre_blocking: false,
+            hook_timeout_s: default_hook_timeout(),
+            attention_expiry_boots: default_attention_expiry_boots(),
+            fix_nvidia_nodes: false,
+            egpu_detect: false,
+            schedule: Vec::new(),
+            modprobe_extra_options: HashMap::new(),
+            force_enable_on_desktop: false,
         }
     }
 
+    /// Load the config from `CONFIG_PATH`, falling back to and migrating from `CONFIG_PATH_LEGACY`
+    pub fn load_default() -> Self {
+        Self::load_or_migrate(CONFIG_PATH.into(), CONFIG_PATH_LEGACY)
+    }
+
+    /// Load the config from `config_path`, creating its directory if required. If only
+    /// `legacy_path` exists it is read instead and migrated: the config is written to
+    /// `config_path` and the legacy file is kept with a `.migrated` suffix. If the migration
+    /// write fails the legacy file continues to be used.
+    pub fn load_or_migrate(config_path: String, legacy_path: &str) -> Self {
+        let legacy = Path::new(legacy_path);
+        if Path::new(&config_path).exists() || !legacy.exists() {
+            if let Some(dir) = Path::new(&config_path).parent() {
+                fs::create_dir_all(dir)
+                    .unwrap_or_else(|err| error!("Could not create {}: {}", dir.display(), err));
+            }
+            return Self::load(config_path);
+        }
+
+        info!("Migrating config from {legacy_path} to {config_path}");
+        let buf = fs::read_to_string(legacy).unwrap_or_else(|err| {
+            error!("Error reading {}: {}", legacy_path, err);
+            String::new()
+        });
+        let mut config = Self::parse(&buf, config_path);
+        let migrated = Path::new(&config.config_path)
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .map_err(|err| GfxError::from_io(err, config.config_path.clone().into()))
+            .and_then(|_| config.write_atomic());
+        match migrated {
+            Ok(_) => {
+                let backup = format!("{legacy_path}.migrated");
+                fs::rename(legacy, &backup).unwrap_or_else(|err| {
+                    warn!("Could not rename {legacy_path} to {backup}: {err}")
+                });
+            }
+            Err(err) => {
+                error!("Config migration failed, continuing with {legacy_path}: {err}");
+                config.config_path = legacy_path.to_string();
+            }
+        }
+        config
+    }
+
     /// `load` will attempt to read the config, and panic if the dir is missing
     pub fn load(config_path: String) -> Self {
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&config_path)
             .unwrap_or_else(|_| panic!("The directory {} is missing", config_path)); // okay to cause panic here
         let mut buf = String::new();
+        let mut config = if file.read_to_string(&mut buf).is_ok() {
+            Self::parse(&buf, config_path)
+        } else {
+            Self::new(config_path)
+        };
+        config.write().unwrap_or_else(|err| error!("load: {err}"));
+        config
+    }
+
+    /// Read the config at `config_path` without creating or writing it as `load` does. A
+    /// missing or unreadable file gives the defaults.
+    pub fn peek(config_path: &str) -> Self {
+        let buf = fs::read_to_string(config_path).unwrap_or_default();
+        Self::parse(&buf, config_path.to_string())
+    }
+
+    /// Parse the config, trying each older format in turn. Unreadable or empty data gives
+    /// the default config.
+    fn parse(buf: &str, config_path: String) -> Self {
+        let buf = &with_current_mode_names(buf);
         let mut config;
-        if let Ok(read_len) = file.read_to_string(&mut buf) {
-            if read_len == 0 {
-                config = Self::new(config_path);
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                config = data;
-                config.config_path = config_path;
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                let old: GfxConfig300 = data;
-                config = old.into();
-                config.config_path = config_path;
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                let old: GfxConfig405 = data;
-                config = old.into();
-                config.config_path = config_path;
-            } else if let Ok(data) = serde_json::from_str(&buf) {
-                let old: GfxConfig500 = data;
-                config = old.into();
-                config.config_path = config_path;
-            } else {
-                warn!("Could not deserialise {}, recreating", config_path);
-                config = GfxConfig::new(config_path);
-            }
+        if buf.is_empty() {
+            config = Self::new(config_path);
+        } else if let Ok(data) = serde_json::from_str(buf) {
+            config = data;
+            config.config_path = config_path;
+        } else if let Ok(data) = serde_json::from_str(buf) {
+            let old: GfxConfig300 = data;
+            config = old.into();
+            config.config_path = config_path;
+        } else if let Ok(data) = serde_json::from_str(buf) {
+            let old: GfxConfig405 = data;
+            config = old.into();
+            config.config_path = config_path;
+        } else if let Ok(data) = serde_json::from_str(buf) {
+            let old: GfxConfig500 = data;
+            config = old.into();
+            config.config_path = config_path;
         } else {
-            config = Self::new(config_path)
+            warn!("Could not deserialise {}, recreating", config_path);
+            config = GfxConfig::new(config_path);
+        }
+        if let Err(err) = validate_disabled_actions(&config) {
+            error!("{err}, no actions will be disabled");
+            config.disabled_actions.clear();
+        }
+        if let Some(Err(err)) = config.acpi_dgpu_off.as_ref().map(AcpiDgpuOff::validate) {
+            error!("{err}, the dGPU won't be powered off with acpi_call");
+            config.acpi_dgpu_off = None;
+        }
+        if let Err(err) = config.dock_profiles.validate() {
+            error!("{err}, nothing will be done when docked or undocked");
+            config.dock_profiles = DockProfiles::default();
+        }
+        if let Err(err) = validate_schedule(&config.schedule) {
+            error!("{err}, no schedule will be followed");
+            config.schedule.clear();
+        }
+        if let Err(err) = validate_modprobe_extra_options(&config.modprobe_extra_options) {
+            error!("{err}, no options will be added to the modprobe conf");
+            config.modprobe_extra_options.clear();
+        }
+        if let Err(err) = validate_display_manager_units(&config.display_manager_units) {
+            error!("{err}, {DISPLAY_MANAGER} is used");
+            config.display_manager_units = default_display_manager_units();
+        }
+        config
+            .ignored_functions
+            .retain(|entry| match validate::pci_function(entry) {
+                Ok(()) => true,
+                Err(err) => {
+                    error!("ignored_functions: {err}, it is left out");
+                    false
+                }
+            });
+        for hook in [
+            &mut config.hook_command_pre_switch,
+            &mut config.hook_command_post_switch,
+        ] {
+            if let Some(Err(err)) = hook
+                .as_deref()
+                .map(|command| validate::text(command, HOOK_COMMAND_MAX))
+            {
+                error!("{err}, the hook is not run");
+                *hook = None;
+            }
+        }
+        if !STATUS_POLL_MS.contains(&config.status_poll_ms) {
+            warn!(
+                "status_poll_ms {} is out of {}..={}, {}ms is used",
+                config.status_poll_ms,
+                STATUS_POLL_MS.start(),
+                STATUS_POLL_MS.end(),
+                config.status_poll().as_millis()
+            );
+        }
+        let window = config.thermal_advisory.average_window_s;
+        if !AVERAGE_WINDOW_S.contains(&window) {
+            warn!(
+                "thermal_advisory: average_window_s {window} is out of {}..={}, {}s is used",
+                AVERAGE_WINDOW_S.start(),
+                AVERAGE_WINDOW_S.end(),
+                config.thermal_advisory.average_window().as_secs()
+            );
+        }
+        if !DRIVER_RETRY_COUNT.contains(&config.driver_retry_count) {
+            warn!(
+                "driver_retry_count {} is out of {}..={}, {} is used",
+                config.driver_retry_count,
+                DRIVER_RETRY_COUNT.start(),
+                DRIVER_RETRY_COUNT.end(),
+                config.driver_attempts()
+            );
         }
-        config.write();
         config
     }
 
+    /// A copy with `changes` made, checked as the file is when loaded, without saving it. A
+    /// field which isn't in the file or a value which doesn't fit it fails with
+    /// `InvalidInput`.
+    pub(crate) fn with_changes(&self, changes: &[ConfigChange]) -> Result<GfxConfig, GfxError> {
+        let invalid = |detail: String| GfxError::InvalidInput(InputClass::Text, detail);
+        let mut value = serde_json::to_value(self).map_err(|err| invalid(err.to_string()))?;
+        let fields = value
+            .as_object_mut()
+            .ok_or_else(|| invalid("the config isn't an object".to_string()))?;
+        for change in changes {
+            validate::identifier(&change.field)?;
+            if !fields.contains_key(&change.field) {
+                return Err(GfxError::InvalidInput(
+                    InputClass::Identifier,
+                    format!("{} is not a config option", change.field),
+                ));
+            }
+            let new = serde_json::from_str(&change.value)
+                .map_err(|err| invalid(format!("{}: {err}", change.field)))?;
+            fields.insert(change.field.clone(), new);
+        }
+        let changed: GfxConfig =
+            serde_json::from_value(value).map_err(|err| invalid(err.to_string()))?;
+        validate_disabled_actions(&changed)?;
+        validate_display_manager_units(&changed.display_manager_units)?;
+        validate_modprobe_extra_options(&changed.modprobe_extra_options)?;
+        for entry in &changed.ignored_functions {
+            validate::pci_function(entry)?;
+        }
+        // Not in the file, these are the state of the running daemon
+        Ok(GfxConfig {
+            config_path: self.config_path.clone(),
+            tmp_mode: self.tmp_mode,
+            tmp_mode_path: self.tmp_mode_path.clone(),
+            pending_mode: self.pending_mode,
+            pending_action: self.pending_action,
+            pending_request: self.pending_request.clone(),
+            switch_state: self.switch_state,
+            write_error: self.write_error.clone(),
+            hotplug_downgrade: self.hotplug_downgrade.clone(),
+            ..changed
+        })
+    }
+
+    /// How many times a driver action is tried, `driver_retry_count` kept within its bounds
+    pub(crate) fn driver_attempts(&self) -> u32 {
+        self.driver_retry_count
+            .clamp(*DRIVER_RETRY_COUNT.start(), *DRIVER_RETRY_COUNT.end())
+    }
+
+    /// The entries of `modprobe_extra_options` for `mode`
+    pub(crate) fn modprobe_extra(&self, mode: GfxMode) -> &[String] {
+        modprobe_extra_for(&self.modprobe_extra_options, mode)
+    }
+
+    /// How often the dGPU power status is polled, `status_poll_ms` kept within its bounds
+    pub(crate) fn status_poll(&self) -> Duration {
+        Duration::from_millis(
+            self.status_poll_ms
+                .clamp(*STATUS_POLL_MS.start(), *STATUS_POLL_MS.end()),
+        )
+    }
+
+    /// The hotplug type in use: `None` if the configured one was found unusable on load
+    pub fn effective_hotplug_type(&self) -> HotplugType {
+        if self.hotplug_downgrade.is_some() {
+            HotplugType::None
+        } else {
+            self.hotplug_type
+        }
+    }
+
+    /// How a switch waits for the graphical sessions to end
+    pub(crate) fn logout_policy(&self) -> LogoutPolicy {
+        LogoutPolicy {
+            timeout_s: self.logout_timeout_s,
+            action: self.logout_timeout_action,
+        }
+    }
+
+    /// The mode in use: the temporary mode if one is set, otherwise the persisted `mode`
+    pub fn effective_mode(&self) -> GfxMode {
+        self.tmp_mode.unwrap_or(self.mode)
+    }
+
+    /// A switch to `mode` should only last until reboot. This is Vfio unless `vfio_save`
+    /// is set.
+    pub fn mode_is_temporary(&self, mode: GfxMode) -> bool {
+        mode == GfxMode::Vfio && !self.vfio_save
+    }
+
+    /// Record that the system is now in `mode`. A temporary mode only sets `tmp_mode` so the
+    /// persisted `mode`, which is used at boot, is left alone. Any other mode is persisted
+    /// and clears `tmp_mode`. The mode is set even if writing it fails.
+    pub fn set_switched_mode(&mut self, mode: GfxMode) -> Result<(), GfxError> {
+        if self.mode_is_temporary(mode) {
+            self.set_temporary_mode(mode)
+        } else {
+            self.tmp_mode = None;
+            self.mode = mode;
+            let cleared = self.write_tmp_mode();
+            self.write().and(cleared)
+        }
+    }
+
+    /// Record that the system is now in `mode` until reboot, as after a switch which wasn't
+    /// to be persisted. The persisted `mode` is left for the next boot. The mode is set even
+    /// if writing it to `tmp_mode_path` fails.
+    pub fn set_temporary_mode(&mut self, mode: GfxMode) -> Result<(), GfxError> {
+        self.tmp_mode = Some(mode);
+        self.write_tmp_mode()
+    }
+
+    /// Forget the pending switch, as when it ends or is dropped, leaving the switch in `state`
+    pub(crate) fn clear_pending(&mut self, state: SwitchState) {
+        self.pending_mode = None;
+        self.pending_action = None;
+        self.pending_request = None;
+        self.switch_state = state;
+    }
+
+    /// Correct the mode in use to `mode`, as the boot safety checks do, keeping it temporary
+    /// if it was. Nothing is written.
+    pub(crate) fn correct_effective_mode(&mut self, mode: GfxMode) {
+        match &mut self.tmp_mode {
+            Some(tmp_mode) => *tmp_mode = mode,
+            None => self.mode = mode,
+        }
+    }
+
+    /// Keep `tmp_mode` at `path` from now on, taking up the temporary mode kept there by an
+    /// earlier run of supergfxd in this boot if there is one
+    pub fn keep_tmp_mode_at(&mut self, path: &Path) {
+        self.tmp_mode_path = Some(path.to_path_buf());
+        self.tmp_mode = match fs::read_to_string(path) {
+            Ok(text) => match text.parse::<GfxMode>() {
+                Ok(mode) => {
+                    info!(
+                        "Still in the temporary mode {mode} kept at {}",
+                        path.display()
+                    );
+                    Some(mode)
+                }
+                Err(err) => {
+                    warn!("{}: {err}, ignoring it", path.display());
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+    }
+
+    /// Write `tmp_mode` to `tmp_mode_path`, or remove it there if there is none
+    fn write_tmp_mode(&self) -> Result<(), GfxError> {
+        let path = match &self.tmp_mode_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        note_write(path);
+        match self.tmp_mode {
+            Some(mode) => path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(path, mode.to_string())),
+            None => match fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            },
+        }
+        .map_err(|err| GfxError::from_io(err, path.clone()))
+    }
+
+    /// Re-read the config from disk. On any error the current values are kept.
     pub fn read(&mut self) {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(&self.config_path)
-            .unwrap_or_else(|err| panic!("Error reading {}: {}", self.config_path, err));
+        let mut file = match OpenOptions::new().read(true).open(&self.config_path) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Error reading {}: {}", self.config_path, err);
+                return;
+            }
+        };
         let mut buf = String::new();
         if let Ok(l) = file.read_to_string(&mut buf) {
             if l == 0 {
                 warn!("File is empty {}", self.config_path);
             } else {
-                let mut x: Self = serde_json::from_str(&buf)
-                    .unwrap_or_else(|_| panic!("Could not deserialise {}", self.config_path));
-                // copy over serde skipped values
-                x.tmp_mode = self.tmp_mode;
-                *self = x;
+                match serde_json::from_str::<Self>(&with_current_mode_names(&buf)) {
+                    Ok(mut x) => {
+                        if let Err(err) = validate_disabled_actions(&x) {
+                            error!("Not using {}: {err}", self.config_path);
+                            return;
+                        }
+                        if let Some(Err(err)) = x.acpi_dgpu_off.as_ref().map(AcpiDgpuOff::validate)
+                        {
+                            error!("Not using {}: {err}", self.config_path);
+                            return;
+                        }
+                        if let Err(err) = x.dock_profiles.validate() {
+                            error!("Not using {}: {err}", self.config_path);
+                            return;
+                        }
+                        if let Err(err) = validate_schedule(&x.schedule) {
+                            error!("Not using {}: {err}", self.config_path);
+                            return;
+                        }
+                        if let Err(err) = validate_modprobe_extra_options(&x.modprobe_extra_options)
+                        {
+                            error!("Not using {}: {err}", self.config_path);
+                            return;
+                        }
+                        if let Err(err) = validate_display_manager_units(&x.display_manager_units) {
+                            error!("Not using {}: {err}", self.config_path);
+                            return;
+                        }
+                        // copy over serde skipped values
+                        x.config_path = self.config_path.clone();
+                        x.tmp_mode = self.tmp_mode;
+                        x.tmp_mode_path = self.tmp_mode_path.clone();
+                        x.pending_mode = self.pending_mode;
+                        x.pending_action = self.pending_action;
+                        x.pending_request = self.pending_request.clone();
+                        x.switch_state = self.switch_state;
+                        x.write_error = self.write_error.clone();
+                        *self = x;
+                    }
+                    Err(err) => error!("Could not deserialise {}: {}", self.config_path, err),
+                }
             }
         }
     }
 
-    pub fn write(&self) {
-        let mut file = File::create(&self.config_path).expect("Couldn't overwrite config");
-        let json = serde_json::to_string_pretty(self).expect("Parse config to JSON failed");
-        file.write_all(json.as_bytes())
-            .unwrap_or_else(|err| error!("Could not write config: {}", err));
+    /// Write the config, remembering in `write_error` if it failed until a write succeeds
+    pub fn write(&mut self) -> Result<(), GfxError> {
+        match self.write_atomic() {
+            Ok(()) => {
+                if self.write_error.take().is_some() {
+                    info!("The config was written to {} again", self.config_path);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                error!("Could not write config: {}", err);
+                self.write_error = Some(err.to_string());
+                Err(GfxError::ConfigNotPersisted(err.to_string()))
+            }
+        }
+    }
+
+    /// Write to a temporary file then rename it over the config so that a crash part way
+    /// can't leave a truncated config. A config without a path, as made for the tests, is
+    /// refused rather than written to `.tmp` in the working directory.
+    pub(crate) fn write_atomic(&self) -> Result<(), GfxError> {
+        if self.config_path.is_empty() {
+            return Err(GfxError::Write(
+                self.config_path.clone(),
+                std::io::Error::new(std::io::ErrorKind::NotFound, "The config has no path"),
+            ));
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|err| {
+            GfxError::Write(
+                self.config_path.clone(),
+                std::io::Error::new(std::io::ErrorKind::Other, err),
+            )
+        })?;
+        let tmp_path = format!("{}.tmp", self.config_path);
+        note_write(Path::new(&self.config_path));
+        File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(json.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.config_path))
+            .map_err(|err| GfxError::Write(self.config_path.clone(), err))
     }
 }
 
-/// Creates the full modprobe.conf required for vfio pass-through
-fn create_vfio_conf(devices: &DiscreetGpu) -> Vec<u8> {
-    let mut vifo = MODPROBE_VFIO.to_vec();
-    for (f_count, func) in devices.devices().iter().enumerate() {
-        unsafe {
-            vifo.append(func.pci_id().to_owned().as_mut_vec());
+/// The dGPU functions last seen complete, one PCI name per line with the VGA function first
+const VFIO_FUNCTIONS_NAME: &str = "vfio_functions";
+
+pub(crate) fn read_known_functions(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Check that `devices` is the whole dGPU, so that vfio-pci gets all of it: the VGA function
+/// is there, and so are its other functions if it is `multifunction` or they were seen
+/// before in `known`. Returns the names with the VGA function first.
+pub(crate) fn check_vfio_functions(
+    devices: &[Device],
+    known: &[String],
+    multifunction: Option<bool>,
+) -> Result<Vec<String>, GfxError> {
+    if devices.is_empty() {
+        return Err(GfxError::VfioIncomplete(
+            "no dGPU functions were found".to_string(),
+        ));
+    }
+    let names: Vec<&str> = devices.iter().map(|dev| dev.name()).collect();
+    let vga = devices.iter().find(|dev| dev.is_dgpu()).ok_or_else(|| {
+        GfxError::VfioIncomplete(format!(
+            "the VGA function is missing, only {} found",
+            names.join(", ")
+        ))
+    })?;
+    if let Some(dev) = devices.iter().find(|dev| dev.pci_id().is_empty()) {
+        return Err(GfxError::VfioIncomplete(format!(
+            "{} has no PCI id",
+            dev.name()
+        )));
+    }
+    if multifunction == Some(true) && devices.len() == 1 {
+        return Err(GfxError::VfioIncomplete(format!(
+            "{} is a multifunction device but none of its other functions were found",
+            vga.name()
+        )));
+    }
+    if known.first().map(|name| name.as_str()) == Some(vga.name()) {
+        let missing: Vec<&str> = known
+            .iter()
+            .map(|name| name.as_str())
+            .filter(|name| !names.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(GfxError::VfioIncomplete(format!(
+                "{} found before but missing now",
+                missing.join(", ")
+            )));
         }
-        if f_count < devices.devices().len() - 1 {
-            vifo.append(&mut vec![b',']);
+    }
+    let mut ordered = vec![vga.name().to_string()];
+    ordered.extend(
+        names
+            .iter()
+            .filter(|name| **name != vga.name())
+            .map(|name| name.to_string()),
+    );
+    Ok(ordered)
+}
+
+/// Creates the full modprobe.conf required for vfio pass-through, refused if `devices` isn't
+/// the whole dGPU as checked by `check_vfio_functions`. Functions with the same id, such as
+/// on a card with two identical audio functions, are listed once.
+pub(crate) fn create_vfio_conf(
+    devices: &[Device],
+    known: &[String],
+    multifunction: Option<bool>,
+) -> Result<Vec<u8>, GfxError> {
+    check_vfio_functions(devices, known, multifunction)?;
+    let mut ids: Vec<String> = Vec::new();
+    for dev in devices {
+        let id = dev.pci_id().to_lowercase();
+        // Written into the conf, nothing but the id may end up on the line
+        validate::pci_id(&id)?;
+        if !ids.contains(&id) {
+            ids.push(id);
         }
     }
-    vifo.append(&mut vec![b',']);
 
     let mut conf = MODPROBE_INTEGRATED.to_vec();
-    conf.append(&mut vifo);
-    conf
+    conf.extend_from_slice(MODPROBE_VFIO);
+    conf.extend_from_slice(ids.join(",").as_bytes());
+    conf.push(b'\n');
+    Ok(conf)
+}
+
+/// Record the dGPU functions as the last complete set if they pass `check_vfio_functions`
+/// against the previous one
+pub(crate) fn remember_vfio_functions(device: &DiscreetGpu) {
+    let path = Path::new(STATE_DIR).join(VFIO_FUNCTIONS_NAME);
+    let known = read_known_functions(&path);
+    let multifunction = device
+        .snapshot()
+        .dgpu()
+        .and_then(|dev| dev.is_multifunction());
+    let managed: Vec<Device> = device.managed_devices().cloned().collect();
+    match check_vfio_functions(&managed, &known, multifunction) {
+        Ok(names) if names != known => {
+            let mut buf = names.join("\n");
+            buf.push('\n');
+            note_write(&path);
+            fs::create_dir_all(STATE_DIR)
+                .and_then(|_| fs::write(&path, buf))
+                .unwrap_or_else(|err| warn!("Could not write {}: {err}", path.display()));
+        }
+        Ok(_) => {}
+        Err(err) => debug!("Not recording the dGPU functions: {err}"),
+    }
 }
 
 pub(crate) fn check_vulkan_icd(mode: GfxMode) -> Result<(), GfxError> {
     let inactive_nv_icd: String = CONFIG_NVIDIA_VKICD.to_owned() + "_inactive";
     info!("check_vulkan_icd: checking for Vulkan ICD profiles...");
+    note_write(Path::new(CONFIG_NVIDIA_VKICD));
     if mode == GfxMode::Vfio || mode == GfxMode::Integrated {
         if std::path::Path::new(CONFIG_NVIDIA_VKICD).exists() {
             info!(
@@ -195,39 +994,104 @@ pub(crate) fn check_vulkan_icd(mode: GfxMode) -> Result<(), GfxError> {
     Ok(())
 }
 
-pub(crate) fn create_modprobe_conf(mode: GfxMode, device: &DiscreetGpu) -> Result<(), GfxError> {
+pub(crate) fn create_modprobe_conf(
+    mode: GfxMode,
+    device: &DiscreetGpu,
+    extra: &[String],
+) -> Result<(), GfxError> {
+    let content = match modprobe_conf(mode, device, extra)? {
+        Some(content) => content,
+        None => return Ok(()),
+    };
+
+    info!("create_modprobe_conf: writing {}", MODPROBE_PATH);
+    write_modprobe_conf_to(Path::new(MODPROBE_PATH), &content)?;
+    if mode == GfxMode::Vfio {
+        remember_vfio_functions(device);
+    }
+    Ok(())
+}
+
+/// Write a modprobe conf to `path`. A conf cut short would break the dGPU modes at the next
+/// boot, so the write is verified before it replaces the old one, see `write_verified`.
+pub(crate) fn write_modprobe_conf_to(path: &Path, content: &[u8]) -> Result<(), GfxError> {
+    write_verified(path, content)
+}
+
+/// Config JSON with any mode names from older releases replaced, so a config written by an
+/// older release is kept rather than recreated. Data which isn't JSON is returned as is.
+fn with_current_mode_names(buf: &str) -> String {
+    if let Ok(mut value) = serde_json::from_str::<serde_json::Value>(buf) {
+        if !fixup_legacy_modes(&mut value).is_empty() {
+            return value.to_string();
+        }
+    }
+    buf.to_string()
+}
+
+/// The modprobe conf for `mode` with the `extra` entries of `modprobe_extra_options` for it,
+/// `None` if the dGPU doesn't need one. Fails for Vfio if the dGPU functions are incomplete.
+pub(crate) fn modprobe_conf(
+    mode: GfxMode,
+    device: &DiscreetGpu,
+    extra: &[String],
+) -> Result<Option<Vec<u8>>, GfxError> {
+    render_modprobe_conf(mode, device, &known_vfio_functions(), extra)
+}
+
+/// The dGPU functions recorded by `remember_vfio_functions`
+pub(crate) fn known_vfio_functions() -> Vec<String> {
+    read_known_functions(&Path::new(STATE_DIR).join(VFIO_FUNCTIONS_NAME))
+}
+
+/// As `modprobe_conf` with the dGPU functions seen before as `known`, reading nothing
+pub(crate) fn render_modprobe_conf(
+    mode: GfxMode,
+    device: &DiscreetGpu,
+    known: &[String],
+    extra: &[String],
+) -> Result<Option<Vec<u8>>, GfxError> {
     if device.is_amd() || device.is_intel() {
-        return Ok(());
+        return Ok(None);
     }
+    let nouveau = device.nvidia_driver() == NvidiaDriver::Nouveau;
 
-    let content = match mode {
+    let mut content = match mode {
+        // The conf is still written, so one from another mode doesn't keep nouveau out
+        GfxMode::Hybrid | GfxMode::AsusEgpu | GfxMode::NvidiaNoModeset if nouveau => {
+            MODPROBE_NOUVEAU_BASE.to_vec()
+        }
         GfxMode::Hybrid | GfxMode::AsusEgpu | GfxMode::NvidiaNoModeset => {
             let mut base = MODPROBE_NVIDIA_BASE.to_vec();
             base.append(&mut MODPROBE_NVIDIA_DRM_MODESET_ON.to_vec());
             base.append(&mut MODPROBE_NVIDIA_EC_BKLT.to_vec());
             base
         }
-        GfxMode::Vfio => create_vfio_conf(device),
+        GfxMode::Vfio => create_vfio_conf(
+            &device.managed_devices().cloned().collect::<Vec<_>>(),
+            known,
+            device
+                .snapshot()
+                .dgpu()
+                .and_then(|dev| dev.is_multifunction()),
+        )?,
+        GfxMode::Integrated if nouveau => MODPROBE_INTEGRATED.to_vec(),
         GfxMode::Integrated => {
             let mut base = MODPROBE_INTEGRATED.to_vec();
             base.append(&mut MODPROBE_NVIDIA_DRM_MODESET_ON.to_vec());
-            base.append(&mut MODPROBE_NVIDIA_EC_BKLT.to_vec()); // only 
+            base.append(&mut MODPROBE_NVIDIA_EC_BKLT.to_vec()); // only
             base
         }
         GfxMode::None | GfxMode::AsusMuxDgpu => vec![],
     };
-
-    let mut file = std::fs::OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(MODPROBE_PATH)
-        .map_err(|err| GfxError::Path(MODPROBE_PATH.into(), err))?;
-
-    info!("create_modprobe_conf: writing {}", MODPROBE_PATH);
-    file.write_all(&content)
-        .and_then(|_| file.sync_all())
-        .map_err(|err| GfxError::Write(MODPROBE_PATH.into(), err))?;
-
-    Ok(())
+    if !extra.is_empty() && !content.is_empty() {
+        content.push(b'\n');
+    }
+    for entry in extra {
+        let mut words = entry.split_whitespace();
+        let module = words.next().unwrap_or_default();
+        let options = words.collect::<Vec<_>>().join(" ");
+        content.extend_from_slice(format!("options {module} {options}\n").as_bytes());
+    }
+    Ok(Some(content))
 }
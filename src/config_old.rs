@@ -1,10 +1,53 @@
+use log::warn;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::{
     config::GfxConfig,
     pci_device::{GfxMode, HotplugType},
 };
 
+/// Mode names from older releases and what they became. `Nvidia` (3.x) was renamed
+/// `Dedicated` in 4.0, both were dropped in 5.0 and map to Hybrid as in the 3.x migration. If
+/// the ASUS MUX is actually set to the dGPU the boot safety check moves the mode on to
+/// `AsusMuxDgpu`. `Compute` was dropped in 5.0.1, Hybrid is the mode which still offers
+/// compute on the dGPU. `Egpu` was renamed `AsusEgpu` in 5.1.
+const LEGACY_MODE_NAMES: &[(&str, GfxMode)] = &[
+    ("Nvidia", GfxMode::Hybrid),
+    ("Dedicated", GfxMode::Hybrid),
+    ("Compute", GfxMode::Hybrid),
+    ("Egpu", GfxMode::AsusEgpu),
+];
+
+/// Config keys which have held a mode
+const MODE_KEYS: &[&str] = &["mode", "gfx_mode"];
+
+/// The current mode for a mode name used by an older release
+pub fn legacy_mode(name: &str) -> Option<GfxMode> {
+    LEGACY_MODE_NAMES
+        .iter()
+        .find(|(legacy, _)| *legacy == name.trim())
+        .map(|(_, mode)| *mode)
+}
+
+/// Replace legacy mode names in a config with their current names so the config parses
+/// instead of being recreated. Returns the keys changed.
+pub(crate) fn fixup_legacy_modes(config: &mut Value) -> Vec<String> {
+    let mut fixed = Vec::new();
+    if let Some(map) = config.as_object_mut() {
+        for key in MODE_KEYS {
+            if let Some(value) = map.get_mut(*key) {
+                if let Some(mode) = value.as_str().and_then(legacy_mode) {
+                    warn!("Config {key} {value} is a deprecated mode name, using {mode:?}",);
+                    *value = Value::String(format!("{mode:?}"));
+                    fixed.push(key.to_string());
+                }
+            }
+        }
+    }
+    fixed
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
 pub enum GfxMode300 {
     Hybrid,
@@ -38,17 +81,9 @@ pub struct GfxConfig300 {
 impl From<GfxConfig300> for GfxConfig {
     fn from(old: GfxConfig300) -> Self {
         GfxConfig {
-            config_path: Default::default(),
             mode: old.gfx_mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.gfx_vfio_enable,
-            vfio_save: false,
-            always_reboot: false,
-            no_logind: false,
-            logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
+            ..GfxConfig::new(Default::default())
         }
     }
 }
@@ -65,17 +100,11 @@ pub struct GfxConfig402 {
 impl From<GfxConfig402> for GfxConfig {
     fn from(old: GfxConfig402) -> Self {
         GfxConfig {
-            config_path: Default::default(),
             mode: old.mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.vfio_enable,
             vfio_save: old.vfio_save,
             always_reboot: old.always_reboot,
-            no_logind: false,
-            logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
+            ..GfxConfig::new(Default::default())
         }
     }
 }
@@ -94,17 +123,13 @@ pub struct GfxConfig405 {
 impl From<GfxConfig405> for GfxConfig {
     fn from(old: GfxConfig405) -> Self {
         GfxConfig {
-            config_path: Default::default(),
             mode: old.mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.vfio_enable,
             vfio_save: old.vfio_save,
             always_reboot: old.always_reboot,
-            no_logind: false,
-            logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
+            no_logind: old.no_logind,
+            logout_timeout_s: old.logout_timeout_s,
+            ..GfxConfig::new(Default::default())
         }
     }
 }
@@ -124,17 +149,11 @@ pub struct GfxConfig500 {
 impl From<GfxConfig500> for GfxConfig {
     fn from(old: GfxConfig500) -> Self {
         GfxConfig {
-            config_path: Default::default(),
             mode: old.mode,
-            tmp_mode: Default::default(),
-            pending_mode: None,
-            pending_action: None,
             vfio_enable: old.vfio_enable,
             vfio_save: old.vfio_save,
             always_reboot: old.always_reboot,
-            no_logind: false,
-            logout_timeout_s: 180,
-            hotplug_type: HotplugType::None,
+            ..GfxConfig::new(Default::default())
         }
     }
 }
@@ -0,0 +1,220 @@
+use std::iter;
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    actions::UserActionRequired,
+    config::{render_modprobe_conf, ConfigChange, GfxConfig},
+    error::GfxError,
+    initramfs::{affects_early_boot, InitramfsTool},
+    pci_device::{DiscreetGpu, GfxMode},
+    prime_env::prime_env,
+    switch_plan::{plan_switch, PlanEnv},
+    switcheroo::{wanted_switcheroo, SwitcherooSystem},
+    zbus_iface::fnv1a_hex,
+};
+
+/// A file supergfxd generates from the config and the mode. No xorg config is generated,
+/// those found are only reported by the periodic verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub enum Artifact {
+    /// The modprobe conf, `/etc/modprobe.d/supergfxd.conf`
+    Modprobe,
+    /// The udev rule hiding the dGPU from switcheroo-control, see `manage_switcheroo`
+    Udev,
+    /// The variables to run an app on the dGPU, as `PrimeEnv` gives them
+    Environment,
+}
+
+impl Artifact {
+    pub(crate) const ALL: &'static [Artifact] =
+        &[Artifact::Modprobe, Artifact::Udev, Artifact::Environment];
+}
+
+/// How an artifact would change for one mode
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ArtifactChange {
+    pub artifact: Artifact,
+    pub mode: GfxMode,
+    /// The content with the change differs from the content now
+    pub changed: bool,
+    /// FNV-1a of the content with the change, empty if none is generated in this mode
+    pub hash: String,
+    /// The lines which would go, prefixed with `-`, then those which would come, prefixed
+    /// with `+`. Empty if unchanged.
+    pub diff: Vec<String>,
+}
+
+/// Something the user would have to do for a change to take effect, or would have to do
+/// differently afterwards
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ConfigAdvisory {
+    /// `initramfs`, `reboot` or `logout`
+    pub code: String,
+    pub message: String,
+}
+
+/// What a config change would do, from `PreviewConfigChange`
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ConfigPreview {
+    /// The mode in use
+    pub mode: GfxMode,
+    /// Each artifact for the mode in use, then for each other supported mode
+    pub artifacts: Vec<ArtifactChange>,
+    pub advisories: Vec<ConfigAdvisory>,
+}
+
+/// What the artifacts are generated from besides the config, read beforehand so that
+/// `preview_config_change` reads nothing
+pub(crate) struct PreviewEnv<'a> {
+    pub dgpu: &'a DiscreetGpu,
+    pub supported: &'a [GfxMode],
+    /// The dGPU functions seen before, for the Vfio modprobe conf
+    pub known_functions: &'a [String],
+    pub switcheroo: &'a dyn SwitcherooSystem,
+    /// The driver bound to the dGPU, for the environment
+    pub driver: Option<&'a str>,
+    /// The tool building an initramfs with a copy of the modprobe conf
+    pub initramfs: Option<InitramfsTool>,
+    pub plan: &'a PlanEnv,
+}
+
+/// The content of `artifact` for `mode` with `config`, `None` if nothing is generated
+pub(crate) fn render_artifact(
+    artifact: Artifact,
+    config: &GfxConfig,
+    mode: GfxMode,
+    env: &PreviewEnv,
+) -> Option<Vec<u8>> {
+    let mut dgpu = env.dgpu.clone();
+    dgpu.set_ignored_functions(config.ignored_functions.clone());
+    match artifact {
+        Artifact::Modprobe => render_modprobe_conf(
+            mode,
+            &dgpu,
+            env.known_functions,
+            config.modprobe_extra(mode),
+        )
+        .unwrap_or_else(|err| {
+            debug!("preview: no modprobe conf for {mode}: {err}");
+            None
+        }),
+        Artifact::Udev => wanted_switcheroo(env.switcheroo, config.manage_switcheroo, mode, &dgpu)
+            .1
+            .map(String::into_bytes),
+        Artifact::Environment => {
+            let vars = prime_env(mode, dgpu.vendor(), env.driver);
+            (!vars.is_empty()).then(|| {
+                vars.iter()
+                    .map(|(name, value)| format!("{name}={value}\n"))
+                    .collect::<String>()
+                    .into_bytes()
+            })
+        }
+    }
+}
+
+/// The lines of `before` not in `after` prefixed with `-`, then those of `after` not in
+/// `before` prefixed with `+`
+pub(crate) fn line_diff(before: Option<&[u8]>, after: Option<&[u8]>) -> Vec<String> {
+    let lines = |content: Option<&[u8]>| -> Vec<String> {
+        String::from_utf8_lossy(content.unwrap_or_default())
+            .lines()
+            .map(str::to_string)
+            .collect()
+    };
+    let (before, after) = (lines(before), lines(after));
+    let gone = before
+        .iter()
+        .filter(|line| !after.contains(line))
+        .map(|line| format!("-{line}"));
+    let come = after
+        .iter()
+        .filter(|line| !before.contains(line))
+        .map(|line| format!("+{line}"));
+    gone.chain(come).collect()
+}
+
+/// What making `changes` to `config` would change in the generated artifacts for the mode
+/// in use and each supported mode, and what it would call for. Nothing is read or written.
+pub(crate) fn preview_config_change(
+    config: &GfxConfig,
+    changes: &[ConfigChange],
+    env: &PreviewEnv,
+) -> Result<ConfigPreview, GfxError> {
+    let changed = config.with_changes(changes)?;
+    let current = config.effective_mode();
+    let modes: Vec<GfxMode> = iter::once(current)
+        .chain(
+            env.supported
+                .iter()
+                .copied()
+                .filter(|mode| *mode != current),
+        )
+        .collect();
+
+    let mut artifacts = Vec::new();
+    for &mode in &modes {
+        for &artifact in Artifact::ALL {
+            let before = render_artifact(artifact, config, mode, env);
+            let after = render_artifact(artifact, &changed, mode, env);
+            artifacts.push(ArtifactChange {
+                artifact,
+                mode,
+                changed: before != after,
+                hash: after.as_deref().map(fnv1a_hex).unwrap_or_default(),
+                diff: line_diff(before.as_deref(), after.as_deref()),
+            });
+        }
+    }
+
+    let mut advisories = Vec::new();
+    // The initramfs has the conf of the mode booted into
+    if let Some(tool) = env.initramfs {
+        let before = render_artifact(Artifact::Modprobe, config, config.mode, env);
+        let after = render_artifact(Artifact::Modprobe, &changed, changed.mode, env);
+        if affects_early_boot(before.as_deref(), after.as_deref()) {
+            advisories.push(ConfigAdvisory {
+                code: "initramfs".to_string(),
+                message: format!(
+                    "initramfs regeneration recommended ({})",
+                    tool.regenerate_command()
+                ),
+            });
+        }
+    }
+    if artifacts.iter().any(|change| {
+        change.artifact == Artifact::Modprobe && change.mode == current && change.changed
+    }) {
+        advisories.push(ConfigAdvisory {
+            code: "reboot".to_string(),
+            message: format!(
+                "The modprobe conf of {current} changes, it takes effect when the driver is next \
+                 loaded, on the next switch or reboot"
+            ),
+        });
+    }
+    let vendor = env.dgpu.vendor();
+    for &mode in modes.iter().skip(1) {
+        let before = plan_switch(config, vendor, current, mode, env.plan).user_action;
+        let after = plan_switch(&changed, vendor, current, mode, env.plan).user_action;
+        let code = match after {
+            _ if after == before => continue,
+            UserActionRequired::Reboot => "reboot",
+            UserActionRequired::Logout => "logout",
+            _ => continue,
+        };
+        advisories.push(ConfigAdvisory {
+            code: code.to_string(),
+            message: format!("A switch to {mode} would need {after} rather than {before}"),
+        });
+    }
+
+    Ok(ConfigPreview {
+        mode: current,
+        artifacts,
+        advisories,
+    })
+}
@@ -1,71 +1,1697 @@
-use futures_util::lock::Mutex;
-use log::{debug, info, warn};
+use futures_util::lock::{Mutex, OwnedMutexGuard};
+use log::{debug, error, info, trace, warn};
+use serde_derive::{Deserialize, Serialize};
 use std::{
-    sync::atomic::{AtomicBool, Ordering},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::{task::JoinHandle, time::sleep};
+use zbus::{
+    fdo::Properties,
+    object_server::{Interface, SignalEmitter},
+    zvariant::{Type, Value},
+};
+
+use crate::{
+    ac_automation::{power_source_in, PowerSource, POWER_SUPPLY_PATH},
+    actions::{Action, ActionSettings, StagedAction, UserActionRequired},
+    audit::{Actor, AuditLog},
+    automation_inhibit::{record_skip, AutomationInhibition, InhibitRegistry},
+    boot_context::{boot_subset, left_as_found, reduced_summary, BootContext, NoLogindProbe},
+    config_preview::{preview_config_change, ConfigPreview, PreviewEnv},
+    display_watchdog::{
+        console_message, failure_summary, watch_display, watched_step, write_consoles,
+        SystemHealthProbe, CONSOLE_DEV_PATH, CONSOLE_TTYS, WATCHDOG_POLL,
+    },
+    dock_automation::DockState,
+    driver_override::{clear_stale_overrides, DriverOverrides},
+    egpu_watch::egpu_present_in,
+    pci_device::{DeviceInfo, GfxPower, HotplugType, ModeInfo},
+    signal_counters::{emit_counted, Signal},
+    signal_filter::signal_targets,
+    supervisor::{spawn_supervised, RestartPolicy, TaskSupervisor},
+    switch_readiness::{preflight, PreflightInput, ReadinessGeneration, SwitchReadiness},
+};
+use crate::{
+    attention::{AttentionBoard, BootDeviations, ATTENTION_BOOT_FAILED},
+    error::GfxError,
+    gpu_users::{blocking_processes, vfio_users, BlockingProcess},
+    hotplug_check::{loaded_hotplug_downgrade, SystemHotplugProbe},
+    initramfs::{refresh_advisory, InitramfsWatch},
+    instance::InstanceLock,
+    logout_switch::{
+        after_session_end, wait_session_end, SessionEnd, SessionProbe, SystemSessionProbe,
+        CONFIRM_LOGOUT_WINDOW,
+    },
+    pci_device::{DiscreetGpu, GfxVendor, RuntimePowerManagement},
+    pci_link::LinkInfo,
+    power_blockers::{BlockerWatch, PowerBlocker, SystemBlockerScanner},
+    power_watch::{spawn_device_event_monitor, spawn_udev_monitor, PowerTrigger, PowerWatch},
+    prime_env::prime_env,
+    runtime_pm_guard::{
+        watch_reprobes, Debounce, ReprobeStats, SystemReprobe, REPROBE_DEBOUNCE,
+        REPROBE_DEBOUNCE_MAX,
+    },
+    shutdown::{Interrupted, ShutdownWait},
+    special_asus::{
+        apply_asus_toggles, asus_egpu_enable_exists, asus_gpu_mux_mode, evaluate_asus_mode,
+        reverify_mux, AsusEvaluation, AsusGpuMuxMode, AsusReadings, MuxReader, MuxReverify,
+        SafetyCheck, SystemMuxReader, MUX_REVERIFY_WINDOW,
+    },
+    special_vendor::{vendor_boot_safety_check, vendor_mux_exists, vendor_mux_on, SpecialToggle},
+    staging::WarmStaging,
+    switch_hooks::{hook_env, run_hook, run_post_hook, SwitchHooks, HOOK_RESULT_PENDING},
+    switch_plan::{
+        execute_plan, park_switch, plan_switch, PlanEnv, SwitchOutcome, SystemSwitchOps,
+        SWITCH_CANCELLABLE, SWITCH_CANCELLED, SWITCH_COMMITTED,
+    },
+    switcheroo::{update_switcheroo, SwitcherooStatus, SystemSwitcheroo},
+    sysfs::{Sysfs, ASUS_GPU_MUX_PATH},
+    systemd_notify::{self, switch_status},
+    thermal::{
+        notify_thermal_advice, thermal_watched, SystemTempSource, ThermalState, ThermalWatch,
+    },
+    verify::{find_drift, ExpectedState, ObservedState},
+    *,
+};
+
+use super::config::{
+    known_vfio_functions, modprobe_conf, remember_vfio_functions, ConfigChange, GfxConfig,
+};
+
+/// The state of the background task that performs a mode switch
+#[derive(Debug, Default, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum SwitchState {
+    /// No switch is running, a new switch can be requested
+    #[default]
+    Idle,
+    /// The staged actions for a switch are being performed
+    Switching,
+    /// The last switch failed part way, or its task panicked, and the dGPU may be in an
+    /// unexpected state. A new switch can still be requested.
+    Stalled,
+}
+
+/// What the boot tasks of `CtrlGraphics::reload` left the system in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum BootOutcome {
+    /// The boot tasks for the mode all succeeded
+    Ok(GfxMode),
+    /// No boot tasks were run, with why
+    Skipped(GfxMode, String),
+    /// The boot tasks succeeded but a safety check fell back to something other than what
+    /// was configured, with what it did
+    Fallback(GfxMode, String),
+    /// Some boot tasks failed, each with the error. Graphics may not work.
+    Degraded(GfxMode, Vec<String>),
+    /// The boot tasks couldn't be run, with the error
+    Failed(String),
+    /// A graphical session was already running so only the boot tasks which don't disturb
+    /// it were run, see `BootContext`, with what was skipped or left as found
+    Reduced(GfxMode, String),
+}
+
+impl BootOutcome {
+    /// The boot left graphics in a state which may not work
+    pub fn broken(&self) -> bool {
+        matches!(self, Self::Degraded(..) | Self::Failed(_))
+    }
+}
+
+/// What started a mode switch, sent with `NotifyModeChange`
+#[derive(Debug, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum SwitchInitiator {
+    /// A client asked for it over dbus
+    User,
+    /// supergfxd switched by itself, such as for `ac_automation`
+    Automation,
+    /// An entry of `schedule` came due
+    Schedule,
+}
+
+/// A consistent snapshot of the daemon state, for clients which poll
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct GfxStatus {
+    pub mode: GfxMode,
+    /// `GfxMode::None` if no switch is pending
+    pub pending_mode: GfxMode,
+    pub pending_action: UserActionRequired,
+    pub switch_state: SwitchState,
+    /// The mode is locked by the administrator and can't be changed
+    pub mode_locked: bool,
+    /// Programs holding inhibitor locks which the pending switch is waiting for, e.g
+    /// `fwupd (Firmware update)`
+    pub waiting_for: Vec<String>,
+    /// What `logout_timeout_action` did when graphical sessions were still open after
+    /// `logout_timeout_s`, for the pending switch. Empty if it didn't time out.
+    pub logout_timeout: String,
+    /// Clients stopping supergfxd from doing anything by itself, see `InhibitAutomation`
+    pub inhibitions: Vec<AutomationInhibition>,
+    pub vendor: GfxVendor,
+    pub power: GfxPower,
+    pub supported: Vec<GfxMode>,
+    /// Increased each time the dGPU devices are rediscovered, such as after a rescan, so
+    /// clients can tell the topology changed
+    pub topology_generation: u64,
+    /// The reminder to regenerate the initramfs as it has an old copy of the modprobe conf,
+    /// such as `initramfs regeneration recommended (dracut -f)`. Empty if none.
+    pub initramfs_advisory: String,
+    /// The dGPU temperature watched in AsusMuxDgpu, see `thermal_advisory` in the config
+    pub thermal: ThermalState,
+    /// Why the config couldn't be written, so changes only last until supergfxd restarts.
+    /// Empty once a write succeeds.
+    pub config_not_saved: String,
+    /// How many attention items are waiting to be dismissed, see `AttentionItems`
+    pub attention: u32,
+    /// Increased each time any of the other fields change, so that a client can skip
+    /// updating if it is the same as last time
+    pub generation: u64,
+}
+
+/// The mode, power and pending switch read together, for a client which shows them all
+/// at once. Unlike `GfxStatus` it is read from the hardware rather than the caches.
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct GfxState {
+    pub mode: GfxMode,
+    /// `GfxPower::Unknown` if the runtime status couldn't be read
+    pub power: GfxPower,
+    /// `GfxMode::None` if no switch is pending
+    pub pending_mode: GfxMode,
+    pub pending_action: UserActionRequired,
+    pub vendor: GfxVendor,
+    pub supported: Vec<GfxMode>,
+}
+
+/// Who asked for the pending switch and when, kept in the config while it is pending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRequest {
+    pub actor: Actor,
+    /// Seconds since the epoch
+    pub requested_at: u64,
+    pub since: Instant,
+}
+
+impl PendingRequest {
+    pub(crate) fn new(actor: Actor) -> Self {
+        Self {
+            actor,
+            requested_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            since: Instant::now(),
+        }
+    }
+
+    /// The requester as `PendingInfo` names it
+    fn requester(&self) -> String {
+        match self.actor {
+            Actor::Daemon => "automation".to_string(),
+            _ => self.actor.to_string(),
+        }
+    }
+}
+
+/// The pending switch and who asked for it
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct PendingInfo {
+    /// `GfxMode::None` if no switch is pending, the other fields are then empty
+    pub mode: GfxMode,
+    pub action: UserActionRequired,
+    /// Seconds since the epoch
+    pub requested_at: u64,
+    /// Seconds it has been pending for
+    pub elapsed_s: u64,
+    /// The bus name of the client which asked for it such as `:1.42`, or `automation`,
+    /// `schedule`, `boot` or `cmdline`
+    pub requester: String,
+    /// The uid of the client, `u32::MAX` if it isn't known or it wasn't a client
+    pub uid: u32,
+}
+
+impl Default for PendingInfo {
+    fn default() -> Self {
+        Self {
+            mode: GfxMode::None,
+            action: UserActionRequired::Nothing,
+            requested_at: 0,
+            elapsed_s: 0,
+            requester: String::new(),
+            uid: u32::MAX,
+        }
+    }
+}
+
+/// Check that the client with `uid` may cancel the switch `request` is for, when
+/// `cancel_requester_only` is set: only the same uid or root may. Switches the daemon
+/// started by itself can only be cancelled by root.
+pub(crate) fn may_cancel(
+    request: Option<&PendingRequest>,
+    uid: Option<u32>,
+) -> Result<(), GfxError> {
+    let request = match request {
+        Some(request) => request,
+        // Nothing to cancel, which is reported as such
+        None => return Ok(()),
+    };
+    match uid {
+        Some(0) => Ok(()),
+        Some(uid) if request.actor.uid() == Some(uid) => Ok(()),
+        _ => Err(GfxError::CancelNotAllowed(request.requester())),
+    }
+}
+
+/// The hardware state as last seen by the status notifier, so that it can be read without
+/// touching sysfs
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HardwareState {
+    pub profile: OperatingProfile,
+    pub vendor: GfxVendor,
+    pub topology_generation: u64,
+    pub power: GfxPower,
+    pub mux_discreet: bool,
+}
+
+impl HardwareState {
+    /// The power status as `Power` and `GfxStatus` report it
+    pub(crate) fn reported_power(&self) -> GfxPower {
+        if self.profile.is_inert() {
+            GfxPower::Unknown
+        } else if self.mux_discreet {
+            GfxPower::AsusMuxDiscreet
+        } else {
+            self.power
+        }
+    }
+}
+
+/// Backs `GfxStatus` snapshots and tracks their generation
+#[derive(Debug)]
+pub(crate) struct StatusCache {
+    pub hardware: HardwareState,
+    pub thermal: ThermalState,
+    last: Option<GfxStatus>,
+}
+
+impl StatusCache {
+    pub(crate) fn new(hardware: HardwareState) -> Self {
+        Self {
+            hardware,
+            thermal: ThermalState::default(),
+            last: None,
+        }
+    }
+
+    /// Set the generation of `status`, increasing it if anything differs from the last one
+    pub(crate) fn snapshot(&mut self, mut status: GfxStatus) -> GfxStatus {
+        let last_generation = self.last.as_ref().map_or(0, |last| last.generation);
+        status.generation = last_generation;
+        if self.last.as_ref() != Some(&status) {
+            status.generation = last_generation + 1;
+            self.last = Some(status.clone());
+        }
+        status
+    }
+}
+
+/// The actions a switch would perform, for `GetSwitchPlan`
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct PlannedSwitch {
+    pub from: GfxMode,
+    pub to: GfxMode,
+    /// What the user must do for the new mode to take effect
+    pub user_action: UserActionRequired,
+    /// The actions by their stable names, such as `StopDisplayManager` or `PreStopDelay(5)`.
+    /// Empty if the switch is left to the user and `user_action` says what they must do.
+    pub actions: Vec<String>,
+}
+
+/// Advice about the effects of a switch, which doesn't stop it from going ahead
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SwitchAdvisory {
+    /// Connected outputs wired to the dGPU, which go dark in the new mode, e.g. `HDMI-A-1`
+    pub outputs_that_will_turn_off: Vec<String>,
+    /// The command to regenerate the initramfs with after the switch, such as `dracut -f`,
+    /// as it has a copy of the modprobe conf the switch changes. Empty if not needed.
+    pub initramfs_regeneration: String,
+}
+
+impl SwitchAdvisory {
+    /// Build the advice for switching to `mode` given the connected outputs of the dGPU
+    pub(crate) fn for_switch(mode: GfxMode, dgpu_outputs: Vec<String>) -> Self {
+        // The dGPU is powered off or given to a VM in these
+        let dgpu_off = matches!(
+            mode,
+            GfxMode::Integrated | GfxMode::Vfio | GfxMode::AsusEgpu
+        );
+        Self {
+            outputs_that_will_turn_off: if dgpu_off { dgpu_outputs } else { Vec::new() },
+            initramfs_regeneration: String::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs_that_will_turn_off.is_empty() && self.initramfs_regeneration.is_empty()
+    }
+}
+
+/// Per call options for a mode switch
+#[derive(Debug, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub struct SetModeOptions {
+    /// Don't wait `pre_stop_delay_s` before stopping the display manager, for scripted switches
+    pub skip_pre_stop_delay: bool,
+    /// Stop the display manager even if another program holds a blocking shutdown or sleep
+    /// inhibitor lock, such as a firmware updater
+    pub ignore_inhibitors: bool,
+    /// Save the new mode so it is also used at boot. If not set the mode is only used until
+    /// reboot, a restart of supergfxd stays in it. A temporary mode such as Vfio without
+    /// `vfio_save` is never saved.
+    pub persist: bool,
+}
+
+impl Default for SetModeOptions {
+    fn default() -> Self {
+        Self {
+            skip_pre_stop_delay: false,
+            ignore_inhibitors: false,
+            persist: true,
+        }
+    }
+}
+
+impl SetModeOptions {
+    /// Adjust the planned actions for a switch according to the options
+    pub(crate) fn apply(&self, mut actions: Action) -> Action {
+        if let Action::StagedActions(list) = &mut actions {
+            if self.skip_pre_stop_delay {
+                list.retain(|action| !matches!(action, StagedAction::PreStopDelay(_)));
+            }
+            if !self.ignore_inhibitors {
+                if let Some(idx) = list
+                    .iter()
+                    .position(|action| *action == StagedAction::StopDisplayManager)
+                {
+                    list.insert(idx, StagedAction::WaitInhibitors);
+                }
+            }
+        }
+        actions
+    }
+}
+
+/// How often the supported modes are re-probed, ASUS sysfs paths can appear some time after
+/// the daemon starts if asus-nb-wmi loads late
+const SUPPORTED_MODES_POLL: Duration = Duration::from_secs(2);
+
+/// The reason given for a system without a dGPU, such as handhelds and mini-PCs with only an APU
+pub(crate) const NO_SWITCHABLE_GRAPHICS: &str = "This system has no switchable graphics";
+
+/// The reason given for a desktop with the dGPU as its only GPU, which supergfxd leaves alone
+pub(crate) const SINGLE_GPU_DESKTOP: &str = "This system does not have switchable graphics, the dGPU is the only GPU of this desktop. Set force_enable_on_desktop in the config if it has a MUX or an eGPU";
+
+/// The reason given for the modes which need an iGPU when none was found
+pub(crate) const NO_IGPU: &str = "No iGPU detected — check BIOS";
+
+/// The modes which turn the dGPU off or hand it to a VM, leaving the iGPU as the only GPU
+pub(crate) fn needs_igpu(mode: GfxMode) -> bool {
+    matches!(mode, GfxMode::Integrated | GfxMode::Vfio)
+}
+
+/// How the daemon operates on this system
+#[derive(Debug, Default, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum OperatingProfile {
+    /// There is a dGPU, or ASUS controls which can bring one back, so modes can be switched
+    #[default]
+    Switchable,
+    /// There is no dGPU and no ASUS dGPU controls. Only Integrated is supported and the
+    /// mode can not be changed, read-only queries still work.
+    NoDgpu,
+    /// A desktop with the dGPU as its only GPU and no MUX, such as one which got supergfxd
+    /// with a meta-package. Only Hybrid is supported, otherwise as `NoDgpu`.
+    Desktop,
+}
+
+impl OperatingProfile {
+    /// No mode can be switched to. The mutating calls are refused, the boot tasks are
+    /// skipped and the dGPU isn't polled.
+    pub fn is_inert(&self) -> bool {
+        *self != OperatingProfile::Switchable
+    }
+
+    /// The mode reported in an inert profile, which is what the only GPU is used as
+    pub fn fixed_mode(&self) -> Option<GfxMode> {
+        match self {
+            OperatingProfile::Switchable => None,
+            OperatingProfile::NoDgpu => Some(GfxMode::Integrated),
+            OperatingProfile::Desktop => Some(GfxMode::Hybrid),
+        }
+    }
+
+    /// Why no mode can be switched to in an inert profile
+    pub(crate) fn reason(&self) -> Option<&'static str> {
+        match self {
+            OperatingProfile::Switchable => None,
+            OperatingProfile::NoDgpu => Some(NO_SWITCHABLE_GRAPHICS),
+            OperatingProfile::Desktop => Some(SINGLE_GPU_DESKTOP),
+        }
+    }
+
+    /// Detect the profile from the tracked dGPU and the ASUS controls currently present
+    pub fn detect(dgpu: &DiscreetGpu) -> Self {
+        let mut errors = Vec::new();
+        ModeProbe::from_probes(dgpu, &Ok(false), &AsusProbes::read(), &mut errors).profile()
+    }
+}
+
+/// Settings for a daemon started with `--debug-run`, outside of systemd for development
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct DebugRun {
+    /// `--debug-allow-mutation`: allow calls which change the system, such as a mode switch
+    /// or a config write. Without it they fail with `GfxError::DebugMode`.
+    pub allow_mutation: bool,
+}
+
+/// The hardware and config state that decides which modes are supported
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub(crate) struct ModeProbe {
+    pub dgpu_found: bool,
+    pub vfio_enable: bool,
+    pub asus_dgpu_disable: bool,
+    pub asus_egpu_enable: bool,
+    pub asus_gpu_mux: bool,
+    /// The ASUS MUX is set to the dgpu, no other mode can be used until it is changed
+    pub asus_mux_discreet: bool,
+    /// A vendor toggle acting as a MUX, such as the Lenovo Legion G-Sync switch
+    pub vendor_mux: bool,
+    /// The vendor MUX toggle is on, as for `asus_mux_discreet`
+    pub vendor_mux_discreet: bool,
+    pub nvidia_modeset_off: bool,
+    /// The mode is locked by the administrator, it is the only one offered
+    pub locked_mode: Option<GfxMode>,
+    /// There is a dGPU but no iGPU, such as when it's turned off in the BIOS, so no mode in
+    /// which the dGPU is turned off is offered
+    pub igpu_missing: bool,
+    /// `egpu_detect` is on and no eGPU enclosure is connected, so AsusEgpu isn't offered
+    pub egpu_absent: bool,
+    /// The dGPU is the only GPU of a desktop, see `DiscreetGpu::single_gpu_desktop`
+    pub single_gpu_desktop: bool,
+}
+
+/// A probe for the supported modes which could not be made. The mode it checks for is
+/// left out of the supported list.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Type, Deserialize, Serialize)]
+pub struct ProbeError {
+    /// The probe, e.g `asus_gpu_mux_mode`
+    pub probe: String,
+    pub error: String,
+}
+
+/// The best-effort list of supported modes and the probes which failed while making it
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SupportedModes {
+    pub modes: Vec<GfxMode>,
+    pub errors: Vec<ProbeError>,
+}
+
+/// The outcome of a probe, `Err` with the reason if it couldn't be made
+pub(crate) type ProbeResult = Result<bool, String>;
+
+/// The ASUS sysfs paths which decide the supported modes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AsusProbes {
+    pub dgpu_disable: ProbeResult,
+    pub egpu_enable: ProbeResult,
+    pub gpu_mux: ProbeResult,
+    /// The MUX is set to the dGPU, only read if `gpu_mux` exists
+    pub mux_discreet: ProbeResult,
+}
+
+impl Default for AsusProbes {
+    fn default() -> Self {
+        Self {
+            dgpu_disable: Ok(false),
+            egpu_enable: Ok(false),
+            gpu_mux: Ok(false),
+            mux_discreet: Ok(false),
+        }
+    }
+}
+
+impl AsusProbes {
+    pub(crate) fn read() -> Self {
+        Self::read_in(&Sysfs::system())
+    }
+
+    /// As `read` with the attributes of `sysfs`. An error checking one, such as permission
+    /// denied on a parent, is an error rather than absent.
+    pub(crate) fn read_in(sysfs: &Sysfs) -> Self {
+        let gpu_mux = sysfs.gpu_mux.0.try_exists();
+        let mux_discreet = match gpu_mux {
+            Ok(true) => sysfs
+                .gpu_mux
+                .read()
+                .map(|mode| mode == AsusGpuMuxMode::Discreet)
+                .map_err(|err| err.to_string()),
+            _ => Ok(false),
+        };
+        Self {
+            dgpu_disable: sysfs.dgpu_disable.0.try_exists(),
+            egpu_enable: sysfs.egpu_enable.try_exists(),
+            gpu_mux,
+            mux_discreet,
+        }
+    }
+}
+
+/// `nvidia-drm.modeset=0` is set on the kernel cmdline
+pub(crate) fn read_nvidia_modeset_off() -> ProbeResult {
+    get_kernel_cmdline_nvidia_modeset()
+        .map(|modeset| modeset == Some(false))
+        .map_err(|err| err.to_string())
+}
+
+/// Caches the probes which read files between dbus calls, and logs each probe failure once
+/// for the life of the daemon. The ASUS paths are invalidated by the supported modes
+/// watcher, the kernel cmdline only by a reload as it can't change while running.
+#[derive(Debug, Default)]
+pub(crate) struct ProbeCache {
+    pub(crate) nvidia_modeset_off: Option<ProbeResult>,
+    pub(crate) asus: Option<AsusProbes>,
+    /// An eGPU enclosure is connected, only read while `egpu_detect` is on
+    pub(crate) egpu_present: Option<bool>,
+    logged: HashSet<ProbeError>,
+}
+
+impl ProbeCache {
+    /// Re-read the ASUS paths on the next probe
+    pub(crate) fn invalidate_hardware(&mut self) {
+        self.asus = None;
+        self.egpu_present = None;
+        Sysfs::system().invalidate();
+    }
+
+    /// Re-read everything on the next probe
+    pub(crate) fn invalidate(&mut self) {
+        self.asus = None;
+        self.egpu_present = None;
+        Sysfs::system().invalidate();
+        self.nvidia_modeset_off = None;
+    }
+
+    fn nvidia_modeset_off(&mut self) -> ProbeResult {
+        self.nvidia_modeset_off
+            .get_or_insert_with(read_nvidia_modeset_off)
+            .clone()
+    }
+
+    fn asus(&mut self) -> AsusProbes {
+        self.asus.get_or_insert_with(AsusProbes::read).clone()
+    }
+
+    fn egpu_present(&mut self) -> bool {
+        *self
+            .egpu_present
+            .get_or_insert_with(|| egpu_present_in(Path::new("/")))
+    }
+
+    /// Log the errors not seen before at warn, returns how many were
+    pub(crate) fn log_new(&mut self, errors: &[ProbeError]) -> usize {
+        let mut count = 0;
+        for err in errors {
+            if self.logged.insert(err.clone()) {
+                warn!("Probe {} failed: {}", err.probe, err.error);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+impl ModeProbe {
+    /// Probe the current state of the system, using the cached file probes in `cache`.
+    /// Failed probes are logged once and returned with the state, which treats them as
+    /// absent.
+    pub(crate) async fn probe(
+        dgpu: &Mutex<DiscreetGpu>,
+        config: &Mutex<GfxConfig>,
+        cache: &Mutex<ProbeCache>,
+    ) -> (Self, Vec<ProbeError>) {
+        let (vfio_enable, locked_mode, egpu_detect) = {
+            let config = config.lock().await;
+            (
+                config.vfio_enable,
+                config.mode_locked.then_some(config.mode),
+                config.egpu_detect,
+            )
+        };
+        let (nvidia_modeset_off, asus, egpu_absent) = {
+            let mut cache = cache.lock().await;
+            (
+                cache.nvidia_modeset_off(),
+                cache.asus(),
+                egpu_detect && !cache.egpu_present(),
+            )
+        };
+        let mut errors = Vec::new();
+        let probe = Self {
+            vfio_enable,
+            locked_mode,
+            egpu_absent,
+            ..Self::from_probes(&*dgpu.lock().await, &nvidia_modeset_off, &asus, &mut errors)
+        };
+        cache.lock().await.log_new(&errors);
+        (probe, errors)
+    }
+
+    /// Build the hardware state from the probe results, `vfio_enable`, `locked_mode` and
+    /// `egpu_absent` are left unset. A failed probe counts as `false` and is added to `errors`.
+    pub(crate) fn from_probes(
+        dgpu: &DiscreetGpu,
+        nvidia_modeset_off: &ProbeResult,
+        asus: &AsusProbes,
+        errors: &mut Vec<ProbeError>,
+    ) -> Self {
+        let mut check = |probe: &str, result: &ProbeResult| match result {
+            Ok(value) => *value,
+            Err(error) => {
+                errors.push(ProbeError {
+                    probe: probe.to_string(),
+                    error: error.clone(),
+                });
+                false
+            }
+        };
+        let mut probe = Self {
+            dgpu_found: !matches!(dgpu.vendor(), GfxVendor::Unknown),
+            vfio_enable: false,
+            asus_dgpu_disable: check("asus_dgpu_disable", &asus.dgpu_disable),
+            asus_egpu_enable: check("asus_egpu_enable", &asus.egpu_enable),
+            asus_gpu_mux: check("asus_gpu_mux", &asus.gpu_mux),
+            asus_mux_discreet: check("asus_gpu_mux_mode", &asus.mux_discreet),
+            vendor_mux: vendor_mux_exists(),
+            vendor_mux_discreet: vendor_mux_on(),
+            nvidia_modeset_off: check("kernel_cmdline", nvidia_modeset_off),
+            locked_mode: None,
+            igpu_missing: false,
+            egpu_absent: false,
+            single_gpu_desktop: dgpu.single_gpu_desktop(),
+        };
+        // A MUX set to the dGPU hides the iGPU, which is expected
+        probe.igpu_missing = probe.dgpu_found
+            && !dgpu.igpu_present()
+            && !probe.asus_mux_discreet
+            && !probe.vendor_mux_discreet;
+        probe
+    }
+
+    pub(crate) fn profile(&self) -> OperatingProfile {
+        if !self.dgpu_found && !self.asus_dgpu_disable && !self.asus_gpu_mux && !self.vendor_mux {
+            return OperatingProfile::NoDgpu;
+        }
+        if self.dgpu_found
+            && self.single_gpu_desktop
+            && !self.asus_dgpu_disable
+            && !self.asus_gpu_mux
+            && !self.vendor_mux
+        {
+            return OperatingProfile::Desktop;
+        }
+        OperatingProfile::Switchable
+    }
+
+    /// Why the supported modes are limited, if they are
+    pub(crate) fn supported_reason(&self) -> Option<&'static str> {
+        if self.asus_mux_discreet {
+            return Some("The ASUS GPU MUX is set to the dGPU, it must be changed back first");
+        }
+        if self.vendor_mux_discreet {
+            return Some("The vendor GPU MUX is set to the dGPU, it must be changed back first");
+        }
+        if let Some(reason) = self.profile().reason() {
+            return Some(reason);
+        }
+        if self.igpu_missing {
+            return Some(NO_IGPU);
+        }
+        if self.locked_mode.is_some() {
+            return Some("The graphics mode is locked by the administrator");
+        }
+        None
+    }
+
+    /// Why `mode` can't be used with this state, `None` if it is supported
+    pub(crate) fn unsupported_reason(&self, mode: GfxMode) -> Option<&'static str> {
+        if self.supported_modes().contains(&mode) {
+            return None;
+        }
+        if mode == GfxMode::None {
+            return Some("Not a mode that can be switched to");
+        }
+        if let Some(reason) = self.profile().reason() {
+            return Some(reason);
+        }
+        if self.igpu_missing && needs_igpu(mode) {
+            return Some(NO_IGPU);
+        }
+        if let Some(reason) = self.supported_reason() {
+            return Some(reason);
+        }
+        Some(match mode {
+            GfxMode::Vfio => "vfio_enable is off in the config",
+            GfxMode::AsusEgpu if self.asus_egpu_enable => "No eGPU enclosure is connected",
+            GfxMode::AsusEgpu => "No ASUS eGPU port was found",
+            GfxMode::AsusMuxDgpu => "No GPU MUX was found",
+            GfxMode::NvidiaNoModeset => "nvidia-drm.modeset=0 isn't on the kernel cmdline",
+            _ => "No dGPU was found",
+        })
+    }
+
+    /// The list of modes supported with this state
+    pub(crate) fn supported_modes(&self) -> Vec<GfxMode> {
+        if self.asus_mux_discreet || self.vendor_mux_discreet {
+            return vec![GfxMode::AsusMuxDgpu];
+        }
+
+        if !self.dgpu_found && !self.asus_dgpu_disable {
+            return vec![GfxMode::Integrated];
+        }
+        if self.profile() == OperatingProfile::Desktop {
+            return vec![GfxMode::Hybrid];
+        }
+
+        let mut list = vec![GfxMode::Integrated, GfxMode::Hybrid];
+        if self.vfio_enable {
+            list.push(GfxMode::Vfio);
+        }
+        if self.asus_egpu_enable && !self.egpu_absent {
+            list.push(GfxMode::AsusEgpu);
+        }
+        if self.asus_gpu_mux || self.vendor_mux {
+            list.push(GfxMode::AsusMuxDgpu);
+        }
+        if self.nvidia_modeset_off {
+            list.push(GfxMode::NvidiaNoModeset);
+        }
+        if self.igpu_missing {
+            list.retain(|mode| !needs_igpu(*mode));
+        }
+        if let Some(locked) = self.locked_mode {
+            list.retain(|mode| *mode == locked);
+        }
+        list
+    }
+}
+
+/// Store `current` as the last seen list of supported modes. Returns the new list if it
+/// differs from a previous probe, the first probe only sets the baseline.
+pub(crate) fn supported_modes_changed(
+    last: &mut Option<Vec<GfxMode>>,
+    current: Vec<GfxMode>,
+) -> Option<Vec<GfxMode>> {
+    match last.replace(current.clone()) {
+        Some(previous) if previous != current => Some(current),
+        _ => None,
+    }
+}
+
+/// Probe the supported modes and emit `notify_supported_changed` if they changed
+pub(crate) async fn recheck_supported_modes(
+    dgpu: &Mutex<DiscreetGpu>,
+    config: &Mutex<GfxConfig>,
+    last_supported: &Mutex<Option<Vec<GfxMode>>>,
+    cache: &Mutex<ProbeCache>,
+    signal_ctxt: Option<&SignalEmitter<'static>>,
+) {
+    let modes = ModeProbe::probe(dgpu, config, cache)
+        .await
+        .0
+        .supported_modes();
+    let changed = supported_modes_changed(&mut *last_supported.lock().await, modes);
+    if let Some(modes) = changed {
+        info!("Supported modes changed to {modes:?}");
+        if let Some(ctxt) = signal_ctxt {
+            emit_counted!(
+                ctxt,
+                Signal::SupportedChanged,
+                notify_supported_changed(&modes)
+            )
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+        }
+    }
+}
+
+/// The message sent when the dGPU is found to have dropped off the bus
+const DGPU_FELL_OFF_BUS: &str =
+    "dGPU dropped off the bus - a reboot (or suspend cycle) is likely required";
+
+/// The health of the dGPU hardware as far as can be seen from sysfs
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum DgpuHealth {
+    Ok,
+    /// The device is gone while its driver is still loaded and the mode expects it, e.g. an
+    /// nvidia Xid 79 after resume
+    FellOffBus,
+}
+
+impl DgpuHealth {
+    /// Classify the dgpu state. A missing device is only a fault if the mode expects the
+    /// dgpu to be bound to its driver, it is normal in Integrated.
+    pub(crate) fn classify(device_present: bool, module_loaded: bool, mode: GfxMode) -> Self {
+        let expects_dgpu = matches!(
+            mode,
+            GfxMode::Hybrid | GfxMode::NvidiaNoModeset | GfxMode::AsusEgpu | GfxMode::AsusMuxDgpu
+        );
+        if !device_present && module_loaded && expects_dgpu {
+            return Self::FellOffBus;
+        }
+        Self::Ok
+    }
+
+    /// Check the tracked dgpu, `None` if there is nothing to check. Only nvidia is checked
+    /// as amdgpu is also loaded for an AMD iGPU.
+    pub(crate) fn check(dgpu: &DiscreetGpu, mode: GfxMode) -> Option<Self> {
+        let present = dgpu.dgpu_present()?;
+        Some(Self::classify(
+            present,
+            dgpu.is_nvidia() && nvidia_module_loaded(),
+            mode,
+        ))
+    }
+}
+
+/// Record the result of a dgpu health check in `degraded`, notifying if it is a new fault
+async fn update_dgpu_health(
+    degraded: &AtomicBool,
+    health: Option<DgpuHealth>,
+    readiness: &ReadinessGeneration,
+    signal_ctxt: Option<&SignalEmitter<'static>>,
+) {
+    let health = match health {
+        Some(health) => health,
+        None => return,
+    };
+    let was_degraded = degraded.swap(health == DgpuHealth::FellOffBus, Ordering::AcqRel);
+    match (was_degraded, health) {
+        (false, DgpuHealth::FellOffBus) => {
+            error!("{DGPU_FELL_OFF_BUS}");
+            if let Some(ctxt) = signal_ctxt {
+                emit_counted!(ctxt, Signal::Error, notify_error(DGPU_FELL_OFF_BUS))
+                    .await
+                    .unwrap_or_else(|err| warn!("{}", err));
+            }
+            notify_readiness_changed(readiness, signal_ctxt).await;
+        }
+        (true, DgpuHealth::Ok) => {
+            info!("dGPU is back on the bus");
+            notify_readiness_changed(readiness, signal_ctxt).await;
+        }
+        _ => {}
+    }
+}
+
+/// Count a change which may change the answer of `switch_readiness`, and tell frontends to
+/// ask again with `notify_readiness_changed`
+async fn notify_readiness_changed(
+    readiness: &ReadinessGeneration,
+    signal_ctxt: Option<&SignalEmitter<'static>>,
+) {
+    let generation = readiness.bump();
+    if let Some(ctxt) = signal_ctxt {
+        emit_counted!(
+            ctxt,
+            Signal::ReadinessChanged,
+            notify_readiness_changed(generation)
+        )
+        .await
+        .unwrap_or_else(|err| warn!("notify_readiness_changed: {err}"));
+    }
+}
+
+/// Emit `PropertiesChanged` for the `PowerStatus` property, to the clients which asked for
+/// `power` or to everyone
+async fn notify_power_status(ctxt: &SignalEmitter<'static>, power: GfxPower) {
+    for target in signal_targets(ctxt, "power") {
+        let changed = HashMap::from([("PowerStatus", Value::from(power))]);
+        Properties::properties_changed(&target, CtrlGraphics::name(), changed, Cow::Borrowed(&[]))
+            .await
+            .unwrap_or_else(|err| trace!("notify_power_status: {err}"));
+    }
+}
+
+/// Emit `notify_boot_advisory` if there is a dbus connection, and log it
+async fn notify_boot_advisory(signal_ctxt: Option<&SignalEmitter<'static>>, advisory: &str) {
+    warn!("{advisory}");
+    if let Some(ctxt) = signal_ctxt {
+        emit_counted!(ctxt, Signal::BootAdvisory, notify_boot_advisory(advisory))
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+    }
+}
+
+/// Boot in Hybrid instead of `mode` if it needs the iGPU and none was found, as nothing would
+/// drive the display with the dGPU turned off. The change is saved and recorded. Returns the
+/// mode to boot in, with why it was changed if it was.
+pub(crate) fn igpu_boot_safety_check(
+    mode: GfxMode,
+    igpu_missing: bool,
+    config: &mut GfxConfig,
+    audit: &AuditLog,
+) -> (GfxMode, Option<String>) {
+    if !igpu_missing || !needs_igpu(mode) {
+        return (mode, None);
+    }
+    let fallback = GfxMode::Hybrid;
+    audit.record(
+        &Actor::Boot,
+        &format!("mode {mode} -> {fallback} by the iGPU boot safety check"),
+    );
+    config
+        .set_switched_mode(fallback)
+        .unwrap_or_else(|err| error!("igpu_boot_safety_check: {err}"));
+    (
+        fallback,
+        Some(format!("{NO_IGPU}, {mode} was changed to {fallback}")),
+    )
+}
+
+/// Boot in `mode` after the ASUS MUX turned out not to be discreet as assumed. Returns
+/// `false` if the mode was changed meanwhile and was left as it is.
+async fn correct_assumed_mux(
+    mode: GfxMode,
+    config: &Mutex<GfxConfig>,
+    dgpu: &Mutex<DiscreetGpu>,
+    audit: &AuditLog,
+) -> Result<bool, GfxError> {
+    let mut config = config.lock().await;
+    if config.effective_mode() != GfxMode::AsusMuxDgpu {
+        return Ok(false);
+    }
+    audit.record(
+        &Actor::Boot,
+        &format!(
+            "mode {} -> {mode}: gpu_mux_mode read as Optimus after it was assumed discreet",
+            GfxMode::AsusMuxDgpu
+        ),
+    );
+    config.correct_effective_mode(mode);
+    let mut dgpu = dgpu.lock().await;
+    let loop_exit = Arc::new(AtomicBool::new(false));
+    let settings = ActionSettings::from_config(&config);
+    for action in StagedAction::action_list_for_boot(&config, dgpu.vendor(), mode) {
+        action
+            .perform(mode, &mut dgpu, &settings, loop_exit.clone(), None)
+            .await
+            .unwrap_or_else(|err| error!("correct_assumed_mux: {err}"));
+    }
+    dgpu.set_runtime_pm(RuntimePowerManagement::Auto)?;
+    Ok(true)
+}
+
+/// Marks the instance lock as switching for as long as the switch task holds it
+struct SwitchingMark(Option<Arc<InstanceLock>>);
+
+impl SwitchingMark {
+    fn new(instance: Option<Arc<InstanceLock>>) -> Self {
+        if let Some(lock) = instance.as_ref() {
+            lock.set_switching(true);
+        }
+        Self(instance)
+    }
+}
+
+impl Drop for SwitchingMark {
+    fn drop(&mut self) {
+        if let Some(lock) = self.0.as_ref() {
+            lock.set_switching(false);
+        }
+    }
+}
+
+/// Performs the actions of a switch in its task, then records the new mode or the
+/// failure
+struct SwitchRunner {
+    ops: SystemSwitchOps,
+    dgpu: Arc<Mutex<DiscreetGpu>>,
+    config: Arc<Mutex<GfxConfig>>,
+    audit: Arc<AuditLog>,
+    staging: Arc<Mutex<WarmStaging>>,
+    switcheroo: Arc<Mutex<SwitcherooStatus>>,
+    logout_timeout: Arc<Mutex<String>>,
+    tasks: TaskSupervisor,
+    readiness: ReadinessGeneration,
+}
+
+impl SwitchRunner {
+    async fn run(
+        self,
+        mode: GfxMode,
+        actions: Vec<StagedAction>,
+        switch_token: Arc<AtomicU8>,
+        actor: Actor,
+        persist: bool,
+    ) {
+        let (hooks, from) = {
+            let config = self.config.lock().await;
+            (SwitchHooks::of(&config), config.effective_mode())
+        };
+        if let Some(command) = &hooks.pre {
+            let env = hook_env(from, mode, HOOK_RESULT_PENDING);
+            if let Err(err) = run_hook(command, &env, hooks.timeout).await {
+                if hooks.pre_blocking {
+                    self.cancel_for_hook(mode, from, &switch_token, &actor, &err)
+                        .await;
+                    return;
+                }
+                warn!("pre-switch hook: {err}, switching anyway");
+            }
+        }
+
+        let outcome = execute_plan(mode, &actions, &switch_token, &self.ops).await;
+        // Shown while the switch is pending, the audit log keeps it after
+        let logout_timeout = std::mem::take(&mut *self.logout_timeout.lock().await);
+        let logout_timeout = if logout_timeout.is_empty() {
+            logout_timeout
+        } else {
+            format!(": {logout_timeout}")
+        };
+        if outcome == SwitchOutcome::Cancelled {
+            // `cancel_switch` has already reset the pending state
+            self.notify_complete(mode, false, &outcome.describe()).await;
+            return;
+        }
+
+        let mut config = self.config.lock().await;
+        config.clear_pending(SwitchState::Idle);
+        if let Some(status) = switch_status(config.effective_mode(), mode, &outcome) {
+            systemd_notify::notify_status(&status);
+        }
+        // A parked switch is stopping for shutdown, there is nothing to come back
+        if let Some(after) =
+            watched_step(&actions).filter(|_| !matches!(outcome, SwitchOutcome::Parked { .. }))
+        {
+            let failed = match &outcome {
+                SwitchOutcome::RolledBack { failed } | SwitchOutcome::Stalled { failed, .. } => {
+                    Some(*failed)
+                }
+                _ => None,
+            };
+            self.spawn_display_watchdog(mode, after, failed, config.display_watchdog_s);
+        }
+        let mut not_persisted = None;
+        match outcome {
+            SwitchOutcome::Completed => {
+                if !persist {
+                    self.audit.record(
+                        &actor,
+                        &format!(
+                            "mode {} -> {mode} until reboot{logout_timeout}",
+                            config.effective_mode()
+                        ),
+                    );
+                } else if (!config.mode_is_temporary(mode) && config.mode != mode)
+                    || !logout_timeout.is_empty()
+                {
+                    self.audit.record(
+                        &actor,
+                        &format!("mode {} -> {mode}{logout_timeout}", config.mode),
+                    );
+                }
+                let from = config.effective_mode();
+                if from != mode {
+                    self.staging.lock().await.set_last_mode(from);
+                }
+                if persist {
+                    if let Err(err) = config.set_switched_mode(mode) {
+                        not_persisted =
+                            Some(format!("Mode {mode} applied but not persisted: {err}"));
+                    }
+                } else if let Err(err) = config.set_temporary_mode(mode) {
+                    not_persisted = Some(format!(
+                        "Mode {mode} applied but a restart of supergfxd won't stay in it: {err}"
+                    ));
+                }
+                let dgpu = self.dgpu.lock().await.clone();
+                *self.switcheroo.lock().await =
+                    update_switcheroo(&SystemSwitcheroo, config.manage_switcheroo, mode, &dgpu);
+                clear_stale_overrides(&DriverOverrides::system(), &dgpu, mode, &self.audit, &actor);
+            }
+            SwitchOutcome::Stalled { .. } => config.switch_state = SwitchState::Stalled,
+            SwitchOutcome::Parked { before } => {
+                self.audit.record(
+                    &actor,
+                    &format!(
+                        "mode {} -> {mode}: stopped for shutdown before {before:?}",
+                        config.mode
+                    ),
+                );
+                config.switch_state = SwitchState::Stalled;
+            }
+            SwitchOutcome::RolledBack { failed } => {
+                if !logout_timeout.is_empty() {
+                    self.audit.record(
+                        &actor,
+                        &format!(
+                            "mode {} -> {mode}: failed at {failed:?}{logout_timeout}",
+                            config.mode
+                        ),
+                    );
+                }
+            }
+            SwitchOutcome::Cancelled => {}
+        }
+        drop(config);
+        if let Some(msg) = not_persisted {
+            self.audit.record(&Actor::Daemon, &msg);
+            if let Some(ctxt) = &self.ops.signal_ctxt {
+                emit_counted!(ctxt, Signal::Error, notify_error(&msg))
+                    .await
+                    .unwrap_or_else(|err| warn!("switch task: {err}"));
+            }
+        }
+        self.notify_complete(mode, outcome.succeeded(), &outcome.describe())
+            .await;
+        notify_readiness_changed(&self.readiness, self.ops.signal_ctxt.as_ref()).await;
+        run_post_hook(&hooks, from, mode, &outcome).await;
+    }
+
+    /// Emit `NotifySwitchComplete` for the switch to `mode`, which ended as `outcome` says
+    async fn notify_complete(&self, mode: GfxMode, success: bool, outcome: &str) {
+        if let Some(ctxt) = &self.ops.signal_ctxt {
+            emit_counted!(
+                ctxt,
+                Signal::SwitchComplete,
+                notify_switch_complete(&mode, success, outcome)
+            )
+            .await
+            .unwrap_or_else(|err| warn!("switch task: {err}"));
+        }
+    }
+
+    /// Drop the switch from `from` to `mode` as `hook_pre_blocking` is set and the pre-switch
+    /// hook failed with `err`, unless it was cancelled while the hook ran
+    async fn cancel_for_hook(
+        &self,
+        mode: GfxMode,
+        from: GfxMode,
+        switch_token: &AtomicU8,
+        actor: &Actor,
+        err: &GfxError,
+    ) {
+        {
+            let mut config = self.config.lock().await;
+            if switch_token
+                .compare_exchange(
+                    SWITCH_CANCELLABLE,
+                    SWITCH_CANCELLED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                return;
+            }
+            config.clear_pending(SwitchState::Idle);
+        }
+        let msg = format!("mode {from} -> {mode}: cancelled by the pre-switch hook: {err}");
+        warn!("{msg}");
+        self.audit.record(actor, &msg);
+        if let Some(ctxt) = &self.ops.signal_ctxt {
+            emit_counted!(ctxt, Signal::Error, notify_error(&msg))
+                .await
+                .unwrap_or_else(|err| warn!("switch task: {err}"));
+        }
+        self.notify_complete(
+            mode,
+            false,
+            &format!("cancelled by the pre-switch hook: {err}"),
+        )
+        .await;
+        notify_readiness_changed(&self.readiness, self.ops.signal_ctxt.as_ref()).await;
+    }
+
+    /// Wait in the background for the graphical stack to come back after `after`. If it
+    /// doesn't within `timeout_s` an error is emitted, the service status set and how to
+    /// recover written to the text consoles.
+    fn spawn_display_watchdog(
+        &self,
+        mode: GfxMode,
+        after: StagedAction,
+        failed: Option<StagedAction>,
+        timeout_s: u64,
+    ) {
+        if timeout_s == 0 {
+            return;
+        }
+        let signal_ctxt = self.ops.signal_ctxt.clone();
+        let audit = self.audit.clone();
+        self.tasks
+            .spawn("display watchdog", RestartPolicy::Never, move || {
+                let signal_ctxt = signal_ctxt.clone();
+                let audit = audit.clone();
+                async move {
+                    let probe = SystemHealthProbe {
+                        sessions: SystemSessionProbe,
+                    };
+                    let timeout = Duration::from_secs(timeout_s);
+                    let evidence = match watch_display(&probe, &after, timeout, WATCHDOG_POLL).await
+                    {
+                        Ok(evidence) => {
+                            debug!("display watchdog: {evidence:?}");
+                            return;
+                        }
+                        Err(evidence) => evidence,
+                    };
+                    let missing = evidence.missing(&after);
+                    let summary = failure_summary(mode, &after, &missing);
+                    error!("{summary}");
+                    audit.record(&Actor::Daemon, &summary);
+                    systemd_notify::notify_status(&format!("mode={mode} {summary}"));
+                    if let Some(ctxt) = &signal_ctxt {
+                        emit_counted!(ctxt, Signal::Error, notify_error(&summary))
+                            .await
+                            .unwrap_or_else(|err| warn!("display watchdog: {err}"));
+                    }
+                    let message = console_message(mode, &after, failed.as_ref(), &missing, timeout);
+                    tokio::task::spawn_blocking(move || {
+                        write_consoles(Path::new(CONSOLE_DEV_PATH), CONSOLE_TTYS, &message)
+                    })
+                    .await
+                    .ok();
+                }
+            });
+    }
+}
+
+pub struct CtrlGraphics {
+    pub(crate) dgpu: Arc<Mutex<DiscreetGpu>>,
+    pub(crate) config: Arc<Mutex<GfxConfig>>,
+    loop_exit: Arc<AtomicBool>,
+    /// Cancellation state of the most recently started switch
+    switch_token: Arc<AtomicU8>,
+    /// Held by the switch task while it runs, so a new switch can wait for it to end
+    switch_running: Arc<Mutex<()>>,
+    /// The supported modes as of the last probe, used to detect changes
+    pub(crate) last_supported: Arc<Mutex<Option<Vec<GfxMode>>>>,
+    /// The probes for the supported modes which read files
+    pub(crate) probe_cache: Arc<Mutex<ProbeCache>>,
+    /// The dGPU dropped off the bus, only modes which don't use it can be set
+    pub(crate) degraded_hardware: Arc<AtomicBool>,
+    /// Cached state for `status()`, updated by the status notifier
+    pub(crate) status_cache: Arc<Mutex<StatusCache>>,
+    /// Owners of the inhibitor locks the pending switch is waiting for
+    switch_waiting_for: Arc<Mutex<Vec<String>>>,
+    /// What `logout_timeout_action` did for the pending switch, empty if the logout wait
+    /// didn't time out
+    logout_timeout: Arc<Mutex<String>>,
+    /// Used to emit signals from spawned tasks. Set by the daemon once the dbus connection is up.
+    pub(crate) signal_ctxt: Option<SignalEmitter<'static>>,
+    /// Set if the daemon was started with `--debug-run`
+    debug_run: Option<DebugRun>,
+    /// A client sent a mode value from an older numbering
+    pub(crate) legacy_mode_value_seen: Arc<AtomicBool>,
+    /// Counts mode switches requested over dbus, so automations can tell if the user
+    /// switched while they were waiting
+    pub(crate) user_switches: Arc<AtomicU64>,
+    /// Records mode, lock and config changes with who made them
+    pub(crate) audit: Arc<AuditLog>,
+    /// The modprobe conf rendered ahead of a switch to the likely next mode
+    pub(crate) staging: Arc<Mutex<WarmStaging>>,
+    /// The state of the switcheroo-control bridge as of the last mode change
+    pub(crate) switcheroo: Arc<Mutex<SwitcherooStatus>>,
+    /// The processes keeping the dGPU awake on battery, updated by the status notifier
+    pub(crate) power_blockers: Arc<Mutex<Vec<PowerBlocker>>>,
+    /// Clients which asked for nothing to be done automatically for a while
+    pub(crate) automation_inhibits: Arc<Mutex<InhibitRegistry>>,
+    /// The reminder to regenerate the initramfs after a switch changed the modprobe conf
+    pub(crate) initramfs: Arc<Mutex<InitramfsWatch>>,
+    /// What the boot did differently, until the user dismisses it
+    pub(crate) attention: Arc<Mutex<AttentionBoard>>,
+    /// The mode requested at boot if the ASUS MUX couldn't be read then and was assumed
+    /// discreet
+    mux_assumed_for: Option<GfxMode>,
+    /// The daemon is stopping, changes fail with `GfxError::ShuttingDown`
+    shutting_down: Arc<AtomicBool>,
+    /// logind said the system is about to sleep, and it hasn't woken yet
+    sleeping: Arc<AtomicBool>,
+    /// The lock held by this instance, marked while a switch runs
+    instance: Option<Arc<InstanceLock>>,
+    /// Owns the background tasks started from the controller
+    pub(crate) tasks: TaskSupervisor,
+    /// How the last `reload` went, for the support bundle
+    pub(crate) boot_outcome: Arc<Mutex<Option<BootOutcome>>>,
+    /// Counts the changes which may change the answer of `switch_readiness`
+    pub(crate) readiness: ReadinessGeneration,
+    /// How often a driver re-probe reset runtime PM, for the support bundle
+    pub(crate) reprobes: Arc<Mutex<ReprobeStats>>,
+    /// Whether the machine is docked as last checked, `None` if `dock_profiles` isn't set
+    pub(crate) dock_state: Arc<Mutex<Option<DockState>>>,
+}
+
+impl CtrlGraphics {
+    /// Find the dGPU and make the controller, marked as running with `--debug-run` if
+    /// `debug_run` is set. A debug run which may not change the system doesn't rescan the PCI
+    /// bus while finding the dGPU.
+    pub fn new(
+        config: Arc<Mutex<GfxConfig>>,
+        debug_run: Option<DebugRun>,
+    ) -> Result<CtrlGraphics, GfxError> {
+        let rescan = debug_run.map_or(true, |debug| debug.allow_mutation);
+        let mut ctrl = Self::from_dgpu(config, DiscreetGpu::new(rescan)?);
+        ctrl.debug_run = debug_run;
+        Ok(ctrl)
+    }
+
+    pub(crate) fn from_dgpu(config: Arc<Mutex<GfxConfig>>, dgpu: DiscreetGpu) -> CtrlGraphics {
+        let hardware = HardwareState {
+            profile: OperatingProfile::detect(&dgpu),
+            vendor: dgpu.vendor(),
+            topology_generation: dgpu.generation(),
+            power: GfxPower::Unknown,
+            mux_discreet: false,
+        };
+        CtrlGraphics {
+            dgpu: Arc::new(Mutex::new(dgpu)),
+            config,
+            loop_exit: Arc::new(AtomicBool::new(false)),
+            switch_token: Arc::new(AtomicU8::new(SWITCH_COMMITTED)),
+            switch_running: Arc::new(Mutex::new(())),
+            last_supported: Arc::new(Mutex::new(None)),
+            probe_cache: Arc::new(Mutex::new(ProbeCache::default())),
+            degraded_hardware: Arc::new(AtomicBool::new(false)),
+            status_cache: Arc::new(Mutex::new(StatusCache::new(hardware))),
+            switch_waiting_for: Arc::new(Mutex::new(Vec::new())),
+            logout_timeout: Arc::new(Mutex::new(String::new())),
+            signal_ctxt: None,
+            debug_run: None,
+            legacy_mode_value_seen: Arc::new(AtomicBool::new(false)),
+            user_switches: Arc::new(AtomicU64::new(0)),
+            audit: Arc::new(AuditLog::disabled()),
+            staging: Arc::new(Mutex::new(WarmStaging::system())),
+            switcheroo: Arc::new(Mutex::new(SwitcherooStatus::default())),
+            power_blockers: Arc::new(Mutex::new(Vec::new())),
+            automation_inhibits: Arc::new(Mutex::new(InhibitRegistry::default())),
+            initramfs: Arc::new(Mutex::new(InitramfsWatch::disabled())),
+            attention: Arc::new(Mutex::new(AttentionBoard::disabled())),
+            mux_assumed_for: None,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            sleeping: Arc::new(AtomicBool::new(false)),
+            instance: None,
+            tasks: TaskSupervisor::default(),
+            boot_outcome: Arc::new(Mutex::new(None)),
+            readiness: ReadinessGeneration::default(),
+            reprobes: Arc::new(Mutex::new(ReprobeStats::default())),
+            dock_state: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn dgpu_arc_clone(&self) -> Arc<Mutex<DiscreetGpu>> {
+        self.dgpu.clone()
+    }
+
+    /// A consistent view of the devices which can be read without holding the lock, see
+    /// `DiscreetGpu::snapshot`
+    pub(crate) async fn dgpu_snapshot(&self) -> DiscreetGpu {
+        self.dgpu.lock().await.clone()
+    }
+
+    /// Set the signal context used by tasks spawned from the controller
+    pub fn set_signal_context(&mut self, signal_ctxt: SignalEmitter<'static>) {
+        self.signal_ctxt = Some(signal_ctxt);
+    }
+
+    /// Set the instance lock, which is marked while a switch is running
+    pub fn set_instance_lock(&mut self, instance: Arc<InstanceLock>) {
+        self.instance = Some(instance);
+    }
+
+    /// Set the supervisor the background tasks are started under, the one the daemon
+    /// shuts down
+    pub fn set_task_supervisor(&mut self, tasks: TaskSupervisor) {
+        self.tasks = tasks;
+    }
+
+    /// Set the audit log, nothing is recorded until this is called
+    pub fn set_audit_log(&mut self, audit: AuditLog) {
+        self.audit = Arc::new(audit);
+    }
+
+    /// Watch the initramfs of the running system for the regeneration advisory, keeping it
+    /// in `STATE_DIR`. Nothing is watched until this is called.
+    pub async fn watch_initramfs(&mut self) {
+        self.initramfs = Arc::new(Mutex::new(InitramfsWatch::system()));
+        refresh_advisory(&self.initramfs).await;
+    }
+
+    /// Keep the attention items in `STATE_DIR` so they outlast a reboot, aging them if this
+    /// is a new boot. They are only kept in memory until this is called.
+    pub async fn keep_attention(&mut self) {
+        let expiry_boots = self.config.lock().await.attention_expiry_boots;
+        self.attention = Arc::new(Mutex::new(AttentionBoard::system(expiry_boots)));
+    }
+
+    /// Send the attention items with `notify_attention` if this is the first time a client
+    /// asked for the status since the daemon started
+    pub(crate) async fn announce_attention(&self) {
+        let items = match self.attention.lock().await.take_announcement() {
+            Some(items) => items,
+            None => return,
+        };
+        if let Some(ctxt) = &self.signal_ctxt {
+            emit_counted!(ctxt, Signal::Attention, notify_attention(&items))
+                .await
+                .unwrap_or_else(|err| warn!("announce_attention: {err}"));
+        }
+    }
 
-use crate::{
-    actions::{StagedAction, UserActionRequired},
-    pci_device::HotplugType,
-};
-use crate::{
-    error::GfxError,
-    pci_device::{DiscreetGpu, GfxVendor, RuntimePowerManagement},
-    special_asus::{asus_dgpu_disable_exists, asus_egpu_enable_exists},
-    *,
-};
+    /// Register each of `items`, by code and message, as an attention item of the boot into
+    /// `mode`
+    pub(crate) async fn register_attention(
+        &self,
+        mode: GfxMode,
+        items: Vec<(&'static str, String)>,
+    ) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut attention = self.attention.lock().await;
+        for (code, message) in items {
+            attention.register(code, message, mode, now);
+        }
+    }
 
-use super::config::GfxConfig;
+    /// Mark the controller as running with `--debug-run`
+    pub fn set_debug_run(&mut self, debug_run: DebugRun) {
+        self.debug_run = Some(debug_run);
+    }
 
-pub struct CtrlGraphics {
-    pub(crate) dgpu: Arc<Mutex<DiscreetGpu>>,
-    pub(crate) config: Arc<Mutex<GfxConfig>>,
-    loop_exit: Arc<AtomicBool>,
-}
+    /// Get if the daemon was started with `--debug-run`
+    pub(crate) fn is_debug_run(&self) -> bool {
+        self.debug_run.is_some()
+    }
 
-impl CtrlGraphics {
-    pub fn new(config: Arc<Mutex<GfxConfig>>) -> Result<CtrlGraphics, GfxError> {
-        Ok(CtrlGraphics {
-            dgpu: Arc::new(Mutex::new(DiscreetGpu::new()?)),
-            config,
-            loop_exit: Arc::new(AtomicBool::new(false)),
-        })
+    /// Fail with `GfxError::DebugMode` if this is a debug run which may not change the system,
+    /// or with `GfxError::ShuttingDown` once the daemon is stopping
+    pub(crate) fn check_mutation_allowed(&self) -> Result<(), GfxError> {
+        match self.debug_run {
+            Some(debug) if !debug.allow_mutation => Err(GfxError::DebugMode),
+            _ if self.shutting_down.load(Ordering::Acquire) => Err(GfxError::ShuttingDown),
+            _ => Ok(()),
+        }
     }
 
-    pub fn dgpu_arc_clone(&self) -> Arc<Mutex<DiscreetGpu>> {
-        self.dgpu.clone()
+    /// Use `HotplugType::None` if the configured `hotplug_type` can't be used here, recording
+    /// why in `hotplug_downgrade`. The boot tasks and switches then use the effective type.
+    async fn check_loaded_hotplug_type(&self) {
+        let dgpu = self.dgpu_snapshot().await;
+        let downgrade = if OperatingProfile::detect(&dgpu).is_inert() {
+            None
+        } else {
+            let hotplug_type = self.config.lock().await.hotplug_type;
+            loaded_hotplug_downgrade(hotplug_type, &SystemHotplugProbe { dgpu: &dgpu })
+        };
+        self.config.lock().await.hotplug_downgrade = downgrade.clone();
+        if let Some(reason) = downgrade {
+            error!("{reason}");
+            self.notify_hotplug_downgrade().await;
+        }
+    }
+
+    /// Tell frontends about a downgraded `hotplug_type` with `notify_boot_advisory`, if
+    /// there is one and the dbus connection is up
+    pub async fn notify_hotplug_downgrade(&self) {
+        if let (Some(reason), Some(ctxt)) = (self.get_hotplug_downgrade().await, &self.signal_ctxt)
+        {
+            emit_counted!(ctxt, Signal::BootAdvisory, notify_boot_advisory(&reason))
+                .await
+                .unwrap_or_else(|err| warn!("{}", err));
+        }
     }
 
-    /// Force re-init of all state, including reset of device state
-    pub async fn reload(&mut self) -> Result<(), GfxError> {
+    /// Why the configured `hotplug_type` isn't used, if it isn't
+    pub(crate) async fn get_hotplug_downgrade(&self) -> Option<String> {
+        self.config.lock().await.hotplug_downgrade.clone()
+    }
+
+    /// Hand the `ignored_functions` of the config to the dGPU, warning about entries which
+    /// match nothing that can be ignored
+    async fn apply_ignored_functions(&self) {
+        let ignored = self.config.lock().await.ignored_functions.clone();
+        let mut dgpu = self.dgpu.lock().await;
+        dgpu.set_ignored_functions(ignored);
+        for warning in dgpu.check_ignored_functions() {
+            warn!("{warning}");
+        }
+        for dev in dgpu.devices().iter().filter(|dev| dgpu.is_ignored(dev)) {
+            info!("Ignoring {} as set in ignored_functions", dev.name());
+        }
+    }
+
+    /// Hand the `force_enable_on_desktop` of the config to the dGPU, see
+    /// `DiscreetGpu::single_gpu_desktop`
+    pub(crate) async fn apply_force_enable_on_desktop(&self) {
+        let force = self.config.lock().await.force_enable_on_desktop;
+        let mut dgpu = self.dgpu.lock().await;
+        dgpu.set_force_enable_on_desktop(force);
+        if force && dgpu.system_class().is_desktop() && !dgpu.igpu_present() {
+            warn!("force_enable_on_desktop is set, modes can be switched on this desktop with a single GPU");
+        }
+    }
+
+    /// Force re-init of all state, including reset of device state. If a graphical session
+    /// is already running only the boot tasks which don't disturb it are run, see
+    /// `BootContext`. The outcome is kept for the support bundle.
+    pub async fn reload(&mut self) -> Result<BootOutcome, GfxError> {
+        let res = self.run_boot_tasks().await;
+        if let Err(err) = &res {
+            let mode = self.config.lock().await.effective_mode();
+            let message = format!("The boot tasks couldn't be run: {err}");
+            self.register_attention(mode, vec![(ATTENTION_BOOT_FAILED, message)])
+                .await;
+        }
+        *self.boot_outcome.lock().await = Some(match &res {
+            Ok(outcome) => outcome.clone(),
+            Err(err) => BootOutcome::Failed(err.to_string()),
+        });
+        res
+    }
+
+    async fn run_boot_tasks(&mut self) -> Result<BootOutcome, GfxError> {
+        self.probe_cache.lock().await.invalidate();
+        self.staging.lock().await.invalidate();
+        self.apply_ignored_functions().await;
+        self.apply_force_enable_on_desktop().await;
+        self.check_loaded_hotplug_type().await;
+        if self.check_mutation_allowed().is_err() {
+            info!("reload: Debug run, skipping boot tasks");
+            self.recheck_supported_modes().await;
+            let mode = self.config.lock().await.effective_mode();
+            return Ok(BootOutcome::Skipped(mode, "debug run".to_string()));
+        }
+        let profile = self.get_profile().await;
+        if let (Some(mode), Some(reason)) = (profile.fixed_mode(), profile.reason()) {
+            info!("reload: {reason}, running with the {profile:?} profile");
+            self.recheck_supported_modes().await;
+            return Ok(BootOutcome::Skipped(mode, reason.to_string()));
+        }
+
+        let context = if self.config.lock().await.no_logind {
+            BootContext::classify(&NoLogindProbe).await
+        } else {
+            BootContext::classify(&SystemSessionProbe).await
+        };
+
+        let igpu_missing = self.probe().await.igpu_missing;
         let mut config = self.config.lock().await;
         let vfio_enable = config.vfio_enable;
 
         let mode = get_kernel_cmdline_mode()?
             .map(|mode| {
                 warn!("reload: Graphic mode {:?} set on kernel cmdline", mode);
-                config.mode = mode;
-                config.write();
+                if mode != config.effective_mode() {
+                    self.audit.record(
+                        &Actor::Cmdline,
+                        &format!(
+                            "mode {} -> {mode} by supergfxd.mode on the kernel cmdline",
+                            config.effective_mode()
+                        ),
+                    );
+                }
+                // Still booted into, `status()` shows it wasn't saved
+                config
+                    .set_switched_mode(mode)
+                    .unwrap_or_else(|err| error!("reload: supergfxd.mode: {err}"));
                 mode
             })
             .unwrap_or(self.get_gfx_mode(&config)?);
+        let (mode, igpu_fallback) =
+            igpu_boot_safety_check(mode, igpu_missing, &mut config, &self.audit);
 
         if matches!(mode, GfxMode::Vfio) && !vfio_enable {
             warn!("reload: Tried to set vfio mode but it is not enabled");
-            return Ok(());
+            return Ok(BootOutcome::Skipped(
+                mode,
+                "Vfio is set but vfio_enable is off".to_string(),
+            ));
         }
 
         if matches!(mode, GfxMode::AsusEgpu) && !asus_egpu_enable_exists() {
             warn!("reload: Tried to set egpu mode but it is not supported");
-            return Ok(());
+            return Ok(BootOutcome::Skipped(
+                mode,
+                "AsusEgpu is set but not supported".to_string(),
+            ));
         }
 
-        let mut dgpu = self.dgpu.lock().await;
-        Self::do_boot_tasks(mode, &mut config, &mut dgpu).await?;
+        let (mode, failures, reduced) = {
+            let mut dgpu = self.dgpu.lock().await;
+            let (mode, mux_assumed_for, failures, reduced) =
+                Self::do_boot_tasks(mode, &mut config, &mut dgpu, &self.audit, &context).await?;
+            self.mux_assumed_for = mux_assumed_for;
+            *self.switcheroo.lock().await =
+                update_switcheroo(&SystemSwitcheroo, config.manage_switcheroo, mode, &dgpu);
+            // Compared against before a Vfio modprobe conf is written
+            remember_vfio_functions(&dgpu);
+            (mode, failures, reduced)
+        };
+        let downgrade = config.hotplug_downgrade.clone();
+        drop(config);
+        self.recheck_supported_modes().await;
+        if let Some(reason) = &igpu_fallback {
+            notify_boot_advisory(self.signal_ctxt.as_ref(), reason).await;
+        }
+
+        let deviations = BootDeviations {
+            failures: failures.clone(),
+            mux_assumed: self.mux_assumed_for.is_some(),
+            igpu_fallback: igpu_fallback.clone(),
+            hotplug_downgrade: downgrade.clone(),
+            reduced: reduced.clone(),
+        };
+        self.register_attention(mode, deviations.attention()).await;
 
         info!("reload: Reloaded gfx mode: {:?}", mode);
-        Ok(())
+        Ok(if !failures.is_empty() {
+            BootOutcome::Degraded(mode, failures)
+        } else if self.mux_assumed_for.is_some() {
+            BootOutcome::Fallback(
+                mode,
+                "gpu_mux_mode couldn't be read, the MUX is assumed discreet".to_string(),
+            )
+        } else if let Some(reason) = igpu_fallback {
+            BootOutcome::Fallback(mode, reason)
+        } else if let Some(reason) = downgrade {
+            BootOutcome::Fallback(mode, reason)
+        } else if let Some(reason) = reduced {
+            BootOutcome::Reduced(mode, reason)
+        } else {
+            BootOutcome::Ok(mode)
+        })
     }
 
     /// Associated method to get which mode is set
@@ -77,7 +1703,182 @@ impl CtrlGraphics {
         Ok(config.mode)
     }
 
-    ///
+    /// Plan a switch to `mode` from the current mode as `SetMode` would, without doing
+    /// anything or changing the pending state
+    pub(crate) async fn plan_switch_to(&self, mode: GfxMode) -> PlannedSwitch {
+        let vendor = self.dgpu.lock().await.vendor();
+        let config = self.config.lock().await;
+        let from = config.effective_mode();
+        let plan = plan_switch(&config, vendor, from, mode, &PlanEnv::probe(from))
+            .with_options(SetModeOptions::default());
+        PlannedSwitch {
+            from: plan.from,
+            to: plan.to,
+            user_action: plan.user_action,
+            actions: plan
+                .actions
+                .unwrap_or_default()
+                .iter()
+                .map(|action| action.to_string())
+                .collect(),
+        }
+    }
+
+    /// Get the advice for switching to `mode`, such as the external outputs that will stop
+    /// working
+    pub(crate) async fn get_switch_advisory(&self, mode: GfxMode) -> SwitchAdvisory {
+        let dgpu = self.dgpu_snapshot().await;
+        let mut advisory = SwitchAdvisory::for_switch(mode, dgpu.connected_outputs());
+        let extra = self.config.lock().await.modprobe_extra(mode).to_vec();
+        let initramfs = self.initramfs.lock().await;
+        if initramfs.tool().is_some() {
+            if let Ok(Some(conf)) = modprobe_conf(mode, &dgpu, &extra) {
+                let live = std::fs::read(MODPROBE_PATH).ok();
+                if let Some(command) = initramfs.regenerate_for(live.as_deref(), Some(&conf)) {
+                    advisory.initramfs_regeneration = command.to_string();
+                }
+            }
+        }
+        advisory
+    }
+
+    /// Get the variables to run an app on the dGPU in the current mode
+    pub(crate) async fn get_prime_env(&self) -> Result<Vec<(String, String)>, GfxError> {
+        let mode = self.get_gfx_mode(&*self.config.lock().await)?;
+        let dgpu = self.dgpu_snapshot().await;
+        Ok(prime_env(
+            mode,
+            dgpu.vendor(),
+            dgpu.dgpu_driver().as_deref(),
+        ))
+    }
+
+    /// What making `changes` to the config would change in the generated files, without
+    /// making them
+    pub(crate) async fn get_config_preview(
+        &self,
+        changes: &[ConfigChange],
+    ) -> Result<ConfigPreview, GfxError> {
+        let supported = self.get_supported_modes().await;
+        let config = self.config.lock().await.clone();
+        let dgpu = self.dgpu_snapshot().await;
+        let driver = dgpu.dgpu_driver();
+        let initramfs = self.initramfs.lock().await.tool();
+        let known_functions = known_vfio_functions();
+        let plan = PlanEnv::probe(config.effective_mode());
+        preview_config_change(
+            &config,
+            changes,
+            &PreviewEnv {
+                dgpu: &dgpu,
+                supported: &supported,
+                known_functions: &known_functions,
+                switcheroo: &SystemSwitcheroo,
+                driver: driver.as_deref(),
+                initramfs,
+                plan: &plan,
+            },
+        )
+    }
+
+    /// Get the PCIe link state of the dGPU and its port
+    pub(crate) async fn get_link_info(&self) -> LinkInfo {
+        self.dgpu_snapshot()
+            .await
+            .link_info()
+            .unwrap_or_else(|| LinkInfo {
+                notes: vec!["No dGPU is tracked".to_string()],
+                ..Default::default()
+            })
+    }
+
+    /// The dGPU functions which are present, empty if there are none such as after
+    /// `dgpu_disable`
+    pub(crate) async fn get_devices(&self) -> Vec<DeviceInfo> {
+        self.dgpu_snapshot()
+            .await
+            .devices()
+            .iter()
+            .filter(|dev| dev.dev_path().exists())
+            .map(DeviceInfo::of)
+            .collect()
+    }
+
+    /// The processes holding the dGPU device nodes, which would keep its driver from being
+    /// unloaded by a switch
+    pub(crate) async fn get_blocking_processes(&self) -> Vec<BlockingProcess> {
+        blocking_processes(&self.dgpu_snapshot().await)
+    }
+
+    /// Run the ASUS boot safety check against `sysfs` as it is now, changing nothing. The
+    /// MUX is read once by `mux`, one which can't be read is taken to be discreet as at boot.
+    pub(crate) async fn asus_safety_check_in(
+        &self,
+        sysfs: &Sysfs,
+        mux: &dyn MuxReader,
+    ) -> Result<(AsusEvaluation, SafetyCheck), GfxError> {
+        let readings = AsusReadings::read(sysfs, mux.read_mux())?;
+        let vendor = self.get_gfx_vendor().await;
+        let config = self.config.lock().await;
+        let current = config.effective_mode();
+        let evaluation = evaluate_asus_mode(
+            current,
+            config.effective_hotplug_type() == HotplugType::Asus,
+            &readings,
+        );
+        let switch_required = evaluation.mode != current;
+        let action = if switch_required {
+            plan_switch(
+                &config,
+                vendor,
+                current,
+                evaluation.mode,
+                &PlanEnv::probe(current),
+            )
+            .user_action
+        } else {
+            UserActionRequired::Nothing
+        };
+        let check = SafetyCheck {
+            current,
+            mode: evaluation.mode,
+            reasons: evaluation.reasons.clone(),
+            enable_dgpu: evaluation.enable_dgpu,
+            switch_required,
+            action,
+        };
+        Ok((evaluation, check))
+    }
+
+    /// Turn `dgpu_disable` off if the ASUS safety check says it must be. Returns the mode
+    /// the check says a switch must be made to, `None` if the mode is right. The switch
+    /// itself is left to the caller so it goes through the usual plan.
+    pub(crate) async fn apply_safety_toggles(
+        &self,
+        sysfs: &Sysfs,
+        mux: &dyn MuxReader,
+        actor: &Actor,
+    ) -> Result<Option<GfxMode>, GfxError> {
+        self.check_mutation_allowed()?;
+        let (evaluation, check) = self.asus_safety_check_in(sysfs, mux).await?;
+        for reason in &check.reasons {
+            info!("apply_safety_toggles: {reason}");
+        }
+        if evaluation.enable_dgpu {
+            self.audit
+                .record(actor, "dgpu_disable turned off by the ASUS safety check");
+        }
+        let mode = apply_asus_toggles(&evaluation).await?;
+        Ok((mode != check.current).then_some(mode))
+    }
+
+    /// Get the mode which will be set on the next boot, this differs from the current mode
+    /// if a temporary mode is in use
+    pub(crate) async fn get_persistent_mode(&self) -> GfxMode {
+        self.config.lock().await.mode
+    }
+
+    /// Get the mode a switch is in progress to, `GfxMode::None` if no switch is pending
     pub(crate) async fn get_pending_mode(&self) -> GfxMode {
         let config = self.config.lock().await;
         if let Some(mode) = config.pending_mode {
@@ -86,7 +1887,7 @@ impl CtrlGraphics {
         GfxMode::None
     }
 
-    ///
+    /// Get the action the user must take for a pending switch to complete
     pub(crate) async fn get_pending_user_action(&self) -> UserActionRequired {
         let config = self.config.lock().await;
         if let Some(action) = config.pending_action {
@@ -95,35 +1896,458 @@ impl CtrlGraphics {
         UserActionRequired::Nothing
     }
 
+    /// Get the pending switch with who asked for it and how long ago
+    pub(crate) async fn get_pending_info(&self) -> PendingInfo {
+        let config = self.config.lock().await;
+        match (config.pending_mode, &config.pending_request) {
+            (Some(mode), Some(request)) => PendingInfo {
+                mode,
+                action: config.pending_action.unwrap_or(UserActionRequired::Nothing),
+                requested_at: request.requested_at,
+                elapsed_s: request.since.elapsed().as_secs(),
+                requester: request.requester(),
+                uid: request.actor.uid().unwrap_or(u32::MAX),
+            },
+            _ => PendingInfo::default(),
+        }
+    }
+
+    /// Get the state of the switch task
+    pub(crate) async fn get_switch_state(&self) -> SwitchState {
+        self.config.lock().await.switch_state
+    }
+
+    /// Get a snapshot of the current state. Only cached state is used so this never
+    /// touches sysfs.
+    pub(crate) async fn get_status(&self) -> GfxStatus {
+        let (mode, pending_mode, pending_action, switch_state, mode_locked, config_not_saved) = {
+            let config = self.config.lock().await;
+            (
+                config.effective_mode(),
+                config.pending_mode.unwrap_or(GfxMode::None),
+                config.pending_action.unwrap_or(UserActionRequired::Nothing),
+                config.switch_state,
+                config.mode_locked,
+                config.write_error.clone().unwrap_or_default(),
+            )
+        };
+        let supported = self.last_supported.lock().await.clone().unwrap_or_default();
+        let waiting_for = self.switch_waiting_for.lock().await.clone();
+        let logout_timeout = self.logout_timeout.lock().await.clone();
+        let inhibitions = self.automation_inhibits.lock().await.active(Instant::now());
+        let initramfs_advisory = self
+            .initramfs
+            .lock()
+            .await
+            .advisory()
+            .map(|advisory| advisory.message())
+            .unwrap_or_default();
+        let attention = self.attention.lock().await.items().len() as u32;
+
+        let mut cache = self.status_cache.lock().await;
+        let hardware = cache.hardware;
+        let thermal = cache.thermal;
+        let power = hardware.reported_power();
+        let mode = if let Some(mode) = hardware.profile.fixed_mode() {
+            mode
+        } else if hardware.mux_discreet {
+            GfxMode::AsusMuxDgpu
+        } else {
+            mode
+        };
+        cache.snapshot(GfxStatus {
+            mode,
+            pending_mode,
+            pending_action,
+            switch_state,
+            mode_locked,
+            waiting_for,
+            logout_timeout,
+            inhibitions,
+            vendor: hardware.vendor,
+            power,
+            supported,
+            topology_generation: hardware.topology_generation,
+            initramfs_advisory,
+            thermal,
+            config_not_saved,
+            attention,
+            generation: 0,
+        })
+    }
+
+    /// Get the mode, power, pending switch, vendor and supported modes, read under a single
+    /// lock of the dGPU and config so they agree with each other. A power status which
+    /// can't be read is `GfxPower::Unknown` rather than an error.
+    pub(crate) async fn get_state(&self) -> GfxState {
+        let (nvidia_modeset_off, asus, egpu_present) = {
+            let mut cache = self.probe_cache.lock().await;
+            (cache.nvidia_modeset_off(), cache.asus(), cache.egpu_present)
+        };
+        let mut errors = Vec::new();
+        let state = {
+            let dgpu = self.dgpu.lock().await;
+            let config = self.config.lock().await;
+            let probe = ModeProbe {
+                vfio_enable: config.vfio_enable,
+                locked_mode: config.mode_locked.then_some(config.mode),
+                // Not read here, the eGPU watcher keeps it once started
+                egpu_absent: config.egpu_detect && egpu_present == Some(false),
+                ..ModeProbe::from_probes(&dgpu, &nvidia_modeset_off, &asus, &mut errors)
+            };
+            let (mode, power) = if let Some(mode) = probe.profile().fixed_mode() {
+                (mode, GfxPower::Unknown)
+            } else if probe.asus_mux_discreet || probe.vendor_mux_discreet {
+                (GfxMode::AsusMuxDgpu, GfxPower::AsusMuxDiscreet)
+            } else {
+                let power = dgpu.get_runtime_status().unwrap_or_else(|err| {
+                    warn!("get_state: {err}");
+                    GfxPower::Unknown
+                });
+                (config.effective_mode(), power)
+            };
+            GfxState {
+                mode,
+                power,
+                pending_mode: config.pending_mode.unwrap_or(GfxMode::None),
+                pending_action: config.pending_action.unwrap_or(UserActionRequired::Nothing),
+                vendor: dgpu.vendor(),
+                supported: probe.supported_modes(),
+            }
+        };
+        self.probe_cache.lock().await.log_new(&errors);
+        state
+    }
+
+    async fn probe(&self) -> ModeProbe {
+        ModeProbe::probe(&self.dgpu, &self.config, &self.probe_cache)
+            .await
+            .0
+    }
+
     /// Associated method to get list of supported modes
     pub(crate) async fn get_supported_modes(&self) -> Vec<GfxMode> {
-        let mut list = vec![GfxMode::Integrated, GfxMode::Hybrid];
+        self.probe().await.supported_modes()
+    }
 
-        let dgpu = self.dgpu.lock().await;
-        if matches!(dgpu.vendor(), GfxVendor::Unknown) && !asus_dgpu_disable_exists() {
-            return vec![GfxMode::Integrated];
-        }
+    /// The documentation of every mode, and whether each is supported
+    pub(crate) async fn get_mode_info(&self) -> Vec<ModeInfo> {
+        let probe = self.probe().await;
+        GfxMode::wire_order()
+            .iter()
+            .map(|mode| ModeInfo::new(*mode, probe.unsupported_reason(*mode).map(String::from)))
+            .collect()
+    }
 
-        let config = self.config.lock().await;
-        if config.vfio_enable {
-            list.push(GfxMode::Vfio);
+    /// Get the supported modes with the probes which failed while listing them
+    pub(crate) async fn get_supported_with_errors(&self) -> SupportedModes {
+        let (probe, errors) = ModeProbe::probe(&self.dgpu, &self.config, &self.probe_cache).await;
+        SupportedModes {
+            modes: probe.supported_modes(),
+            errors,
         }
+    }
 
-        if asus_egpu_enable_exists() {
-            list.push(GfxMode::AsusEgpu);
-        }
+    /// Get why the supported modes are limited, empty if they aren't
+    pub(crate) async fn get_supported_reason(&self) -> String {
+        self.probe()
+            .await
+            .supported_reason()
+            .unwrap_or_default()
+            .to_string()
+    }
 
-        if asus_gpu_mux_exists() {
-            list.push(GfxMode::AsusMuxDgpu);
-        }
+    /// Get how the daemon is operating on this system
+    pub(crate) async fn get_profile(&self) -> OperatingProfile {
+        OperatingProfile::detect(&self.dgpu_snapshot().await)
+    }
 
-        if let Ok(Some(res)) = get_kernel_cmdline_nvidia_modeset() {
-            if !res {
-                list.push(GfxMode::NvidiaNoModeset);
-            }
-        }
+    /// Re-probe the supported modes and notify if they changed since the last probe
+    pub(crate) async fn recheck_supported_modes(&self) {
+        recheck_supported_modes(
+            &self.dgpu,
+            &self.config,
+            &self.last_supported,
+            &self.probe_cache,
+            self.signal_ctxt.as_ref(),
+        )
+        .await;
+    }
 
-        list
+    /// Periodically re-probe the supported modes so that hardware which appears after
+    /// startup, such as the ASUS sysfs paths, is picked up and frontends are notified
+    pub fn start_supported_modes_watcher(&self) {
+        let dgpu = self.dgpu.clone();
+        let config = self.config.clone();
+        let last_supported = self.last_supported.clone();
+        let probe_cache = self.probe_cache.clone();
+        let signal_ctxt = self.signal_ctxt.clone();
+        self.tasks.spawn(
+            "supported modes watcher",
+            RestartPolicy::WithBackoff,
+            move || {
+                let dgpu = dgpu.clone();
+                let config = config.clone();
+                let last_supported = last_supported.clone();
+                let probe_cache = probe_cache.clone();
+                let signal_ctxt = signal_ctxt.clone();
+                async move {
+                    loop {
+                        probe_cache.lock().await.invalidate_hardware();
+                        recheck_supported_modes(
+                            &dgpu,
+                            &config,
+                            &last_supported,
+                            &probe_cache,
+                            signal_ctxt.as_ref(),
+                        )
+                        .await;
+                        sleep(SUPPORTED_MODES_POLL).await;
+                    }
+                }
+            },
+        )
+    }
+
+    /// Put runtime PM back to `auto` when a driver re-probing the dGPU functions, such as
+    /// nvidia after a GSP error, resets it to `on`. Checked once the udev events of the
+    /// re-probe settle, see `watch_reprobes`.
+    pub fn start_runtime_pm_guard(&self) {
+        let dgpu = self.dgpu.clone();
+        let config = self.config.clone();
+        let reprobes = self.reprobes.clone();
+        self.tasks
+            .spawn("runtime PM guard", RestartPolicy::WithBackoff, move || {
+                let dgpu = dgpu.clone();
+                let config = config.clone();
+                let reprobes = reprobes.clone();
+                async move {
+                    let functions: Vec<String> = dgpu
+                        .lock()
+                        .await
+                        .managed_devices()
+                        .map(|dev| dev.name().to_string())
+                        .collect();
+                    let events = match spawn_device_event_monitor(functions.clone()) {
+                        Some(events) => events,
+                        None => {
+                            info!("runtime PM guard: no udev events to watch");
+                            return;
+                        }
+                    };
+                    watch_reprobes(
+                        events,
+                        &functions,
+                        Debounce::new(REPROBE_DEBOUNCE, REPROBE_DEBOUNCE_MAX),
+                        &SystemReprobe { dgpu, config },
+                        &reprobes,
+                    )
+                    .await;
+                }
+            })
+    }
+
+    /// Watch the dgpu power status, emitting `notify_gfx_status` when it changes, and check
+    /// that the dgpu hasn't dropped off the bus. Status is read on udev events for the dgpu
+    /// where the kernel sends them, otherwise it is polled, see `PowerWatch`. While the dGPU
+    /// is kept awake in Hybrid on battery the processes holding it are found, see
+    /// `BlockerWatch`, and in AsusMuxDgpu its temperature is watched, see `ThermalWatch`.
+    pub fn start_notify_status(&self) {
+        let dgpu = self.dgpu.clone();
+        let config = self.config.clone();
+        let degraded = self.degraded_hardware.clone();
+        let status_cache = self.status_cache.clone();
+        let power_blockers = self.power_blockers.clone();
+        let inhibits = self.automation_inhibits.clone();
+        let audit = self.audit.clone();
+        let readiness = self.readiness.clone();
+        let signal_ctxt = self.signal_ctxt.clone();
+        self.tasks
+            .spawn("status notifier", RestartPolicy::WithBackoff, move || {
+                let dgpu = dgpu.clone();
+                let config = config.clone();
+                let degraded = degraded.clone();
+                let status_cache = status_cache.clone();
+                let power_blockers = power_blockers.clone();
+                let inhibits = inhibits.clone();
+                let audit = audit.clone();
+                let readiness = readiness.clone();
+                let signal_ctxt = signal_ctxt.clone();
+                async move {
+                    let names = dgpu
+                        .lock()
+                        .await
+                        .devices()
+                        .iter()
+                        .filter(|dev| dev.is_dgpu())
+                        .map(|dev| dev.name().to_string())
+                        .collect();
+                    let mut watch = PowerWatch::new(spawn_udev_monitor(names));
+                    let mut trigger = PowerTrigger::Start;
+                    let mut last_status = GfxPower::Unknown;
+                    let mut last_reported = None;
+                    let mut last_profile = OperatingProfile::Switchable;
+                    let mut blocker_watch = BlockerWatch::new(Duration::ZERO);
+                    power_blockers.lock().await.clear();
+                    let mut thermal_watch = ThermalWatch::new(Default::default());
+                    loop {
+                        let (mode, threshold, thermal_config, poll) = {
+                            let config = config.lock().await;
+                            (
+                                config.effective_mode(),
+                                config.power_blocker_threshold_s,
+                                config.thermal_advisory.clone(),
+                                config.status_poll(),
+                            )
+                        };
+                        watch.set_fast_poll(poll);
+                        let (s, health, hardware, thermal_advice) = {
+                            // Not held across the sysfs reads, a refresh swaps in a new snapshot
+                            let dgpu = dgpu.lock().await.clone();
+                            let profile = OperatingProfile::detect(&dgpu);
+                            if profile != last_profile {
+                                info!("Notify: operating profile is {profile:?}");
+                                last_profile = profile;
+                                notify_readiness_changed(&readiness, signal_ctxt.as_ref()).await;
+                            }
+                            // Nothing to poll, and no point filling the log with errors
+                            let (s, health) = if profile.is_inert() {
+                                (GfxPower::Unknown, None)
+                            } else {
+                                let s = dgpu
+                                    .get_runtime_status()
+                                    .map_err(|e| trace!("{e}"))
+                                    .unwrap_or(GfxPower::Unknown);
+                                (s, DgpuHealth::check(&dgpu, mode))
+                            };
+                            let hardware = HardwareState {
+                                profile,
+                                vendor: dgpu.vendor(),
+                                topology_generation: dgpu.generation(),
+                                power: s,
+                                mux_discreet: matches!(
+                                    asus_gpu_mux_mode(),
+                                    Ok(AsusGpuMuxMode::Discreet)
+                                ) || vendor_mux_on(),
+                            };
+                            let on_battery = power_source_in(Path::new(POWER_SUPPLY_PATH))
+                                == Some(PowerSource::Battery);
+                            // Only looked for when the dGPU is awake, the scan reads every fd
+                            blocker_watch.set_threshold(Duration::from_secs(threshold));
+                            let watched =
+                                s == GfxPower::Active && mode == GfxMode::Hybrid && on_battery;
+                            let wall = SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .map_or(0, |d| d.as_secs());
+                            if blocker_watch.observe(
+                                Instant::now(),
+                                wall,
+                                s,
+                                watched,
+                                &mut SystemBlockerScanner { dgpu: &dgpu },
+                            ) {
+                                *power_blockers.lock().await = blocker_watch.blockers();
+                            }
+                            thermal_watch.set_config(thermal_config);
+                            let thermal_advice = thermal_watch.observe(
+                                Instant::now(),
+                                s == GfxPower::Active
+                                    && (thermal_watched(mode) || hardware.mux_discreet),
+                                on_battery,
+                                &mut SystemTempSource { dgpu: &dgpu },
+                            );
+                            (s, health, hardware, thermal_advice)
+                        };
+                        {
+                            let mut cache = status_cache.lock().await;
+                            cache.hardware = hardware;
+                            cache.thermal = thermal_watch.state();
+                        }
+                        let reported = hardware.reported_power();
+                        if last_reported != Some(reported) {
+                            last_reported = Some(reported);
+                            if let Some(ctxt) = &signal_ctxt {
+                                notify_power_status(ctxt, reported).await;
+                            }
+                        }
+                        let inhibited_by = inhibits.lock().await.inhibited_by(Instant::now());
+                        if let (Some(average), Some(ctxt)) = (thermal_advice, &signal_ctxt) {
+                            match &inhibited_by {
+                                Some(by) => record_skip(
+                                    &audit,
+                                    &format!("thermal advisory at {average}°C"),
+                                    by,
+                                ),
+                                None => notify_thermal_advice(ctxt, average).await,
+                            }
+                        }
+                        update_dgpu_health(&degraded, health, &readiness, signal_ctxt.as_ref())
+                            .await;
+                        watch.set_held(inhibited_by.is_some());
+                        if watch.record(trigger, s != last_status) {
+                            if let Some(by) = &inhibited_by {
+                                record_skip(&audit, "switch to the fast power poll", by);
+                            }
+                        }
+                        if s != last_status {
+                            last_status = s;
+                            debug!("Notify: dGPU status = {s:?} (from {trigger})");
+                            if let Some(ctxt) = &signal_ctxt {
+                                emit_counted!(
+                                    ctxt,
+                                    Signal::GfxStatus,
+                                    notify_gfx_status(&last_status)
+                                )
+                                .await
+                                .map_err(|e| trace!("{e}"))
+                                .ok();
+                            }
+                        }
+                        trigger = watch.wait().await;
+                    }
+                }
+            })
+    }
+
+    /// Re-check the dgpu health against the mode in use, as the status notifier does, and
+    /// if it has dropped off the bus record it and notify
+    pub(crate) async fn check_dgpu_health(&self) -> Result<(), GfxError> {
+        let mode = self.config.lock().await.effective_mode();
+        let health = DgpuHealth::check(&self.dgpu_snapshot().await, mode);
+        update_dgpu_health(
+            &self.degraded_hardware,
+            health,
+            &self.readiness,
+            self.signal_ctxt.as_ref(),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Get if the dgpu has dropped off the bus
+    pub(crate) fn get_degraded_hardware(&self) -> bool {
+        self.degraded_hardware.load(Ordering::Acquire)
+    }
+
+    /// Rescan the PCI bus to try to bring back a dgpu which dropped off it. Returns `true`
+    /// if the dgpu is healthy afterwards.
+    pub async fn try_recover_dgpu(&mut self) -> Result<bool, GfxError> {
+        self.check_mutation_allowed()?;
+        let mode = self.get_gfx_mode(&*self.config.lock().await)?;
+        {
+            let mut dgpu = self.dgpu.lock().await;
+            StagedAction::RescanPci
+                .perform(
+                    mode,
+                    &mut dgpu,
+                    &ActionSettings::default(),
+                    self.loop_exit.clone(),
+                    None,
+                )
+                .await?;
+        }
+        self.check_dgpu_health().await?;
+        Ok(!self.get_degraded_hardware())
     }
 
     /// Associated method to get which vendor the dgpu is from
@@ -132,43 +2356,198 @@ impl CtrlGraphics {
         dgpu.vendor()
     }
 
-    /// Perform boot tasks required to set last saved mode
+    /// Perform boot tasks required to set last saved mode, or those of them which don't
+    /// disturb a session running in `context`. Returns the mode set after the safety
+    /// checks, the mode requested if the ASUS MUX couldn't be read and was assumed
+    /// discreet, the boot tasks which failed with their errors, and what the reduced boot
+    /// path skipped or left as found if it was used.
     async fn do_boot_tasks(
         mut mode: GfxMode,
         config: &mut GfxConfig,
         device: &mut DiscreetGpu,
-    ) -> Result<(), GfxError> {
+        audit: &AuditLog,
+        context: &BootContext,
+    ) -> Result<(GfxMode, Option<GfxMode>, Vec<String>, Option<String>), GfxError> {
+        let mut mux_assumed_for = None;
         debug!(
             "do_mode_setup_tasks(mode:{mode:?}, vfio_enable:{}, asus_use_dgpu_disable: {:?})",
-            config.vfio_enable, config.hotplug_type
+            config.vfio_enable,
+            config.effective_hotplug_type()
         );
         // Absolutely must check the ASUS dgpu_disable and gpu mux sanity on boot
-        if let Ok(checked_mode) =
-            asus_boot_safety_check(mode, config.hotplug_type == HotplugType::Asus)
+        if let Ok(checked) =
+            asus_boot_safety_check(mode, config.effective_hotplug_type() == HotplugType::Asus)
                 .await
                 .map_err(|e| {
                     error!("asus_boot_safety_check errored: {e}");
                 })
         {
-            config.mode = checked_mode;
+            let checked_mode = checked.mode;
+            if checked.mux_assumed {
+                audit.record(
+                    &Actor::Boot,
+                    &format!(
+                        "mode {mode} -> {checked_mode}: gpu_mux_mode couldn't be read, assumed discreet"
+                    ),
+                );
+                mux_assumed_for = Some(mode);
+            } else if checked_mode != mode {
+                audit.record(
+                    &Actor::Boot,
+                    &format!("mode {mode} -> {checked_mode} by the ASUS boot safety check"),
+                );
+            }
+            config.correct_effective_mode(checked_mode);
             mode = checked_mode;
         }
+        let toggles = SpecialToggle::discover();
+        for toggle in &toggles {
+            info!(
+                "Found vendor toggle {} at {}",
+                toggle.def.name,
+                toggle.path.display()
+            );
+        }
+        let checked_mode = vendor_boot_safety_check(mode, &toggles);
+        if checked_mode != mode {
+            audit.record(
+                &Actor::Boot,
+                &format!("mode {mode} -> {checked_mode} by the vendor MUX boot safety check"),
+            );
+        }
+        config.correct_effective_mode(checked_mode);
+        mode = checked_mode;
 
         let loop_exit = Arc::new(AtomicBool::new(false));
 
         let actions = StagedAction::action_list_for_boot(config, device.vendor(), mode);
+        let (actions, skipped) = boot_subset(context, actions);
+        let settings = ActionSettings::from_config(config);
 
+        let mut failures = Vec::new();
         for action in actions {
-            let res = action.perform(mode, device, loop_exit.clone()).await;
+            let res = action
+                .perform(mode, device, &settings, loop_exit.clone(), None)
+                .await;
 
             match res {
                 Ok(_) => {}
-                Err(e) => error!("Action thread errored: {e}"),
+                Err(e) => {
+                    error!("Action thread errored: {e}");
+                    failures.push(format!("{action:?}: {e}"));
+                }
             }
         }
 
-        device.set_runtime_pm(RuntimePowerManagement::Auto)?;
-        Ok(())
+        // Left from before a restart, such as of a Vfio mode which was never switched out of
+        clear_stale_overrides(
+            &DriverOverrides::system(),
+            device,
+            mode,
+            audit,
+            &Actor::Boot,
+        );
+
+        let reduced = match context {
+            BootContext::AtBoot => {
+                device.set_runtime_pm(RuntimePowerManagement::Auto)?;
+                None
+            }
+            BootContext::LiveSession(why) => {
+                // Runtime PM and the rest are left as the session has them, what differs
+                // from the mode is reported
+                let expected = ExpectedState::for_mode(config, mode, device, &SystemSwitcheroo);
+                let observed = ObservedState::read(device, &SystemSwitcheroo);
+                let left = left_as_found(find_drift(&expected, &observed));
+                audit.record(&Actor::Boot, &format!("reduced boot path: {why}"));
+                Some(reduced_summary(why, &skipped, &left))
+            }
+        };
+        Ok((mode, mux_assumed_for, failures, reduced))
+    }
+
+    /// If the ASUS MUX was assumed discreet at boot, read it again for a while in the
+    /// background. Should it turn out to be in Optimus the mode is corrected, and frontends
+    /// are told either way with `notify_boot_advisory`.
+    pub fn start_mux_reverify(&self) -> Option<JoinHandle<()>> {
+        let requested = self.mux_assumed_for?;
+        let config = self.config.clone();
+        let dgpu = self.dgpu.clone();
+        let audit = self.audit.clone();
+        let signal_ctxt = self.signal_ctxt.clone();
+        let panic_ctxt = self.signal_ctxt.clone();
+        let task = async move {
+            notify_boot_advisory(
+                signal_ctxt.as_ref(),
+                &format!(
+                    "{ASUS_GPU_MUX_PATH} couldn't be read at boot, the MUX was assumed discreet and the mode set to {}",
+                    GfxMode::AsusMuxDgpu
+                ),
+            )
+            .await;
+            let asus_use_dgpu_disable =
+                config.lock().await.effective_hotplug_type() == HotplugType::Asus;
+            let advisory = match reverify_mux(
+                &SystemMuxReader,
+                requested,
+                asus_use_dgpu_disable,
+                MUX_REVERIFY_WINDOW,
+            )
+            .await
+            {
+                Ok(MuxReverify::Confirmed) => {
+                    info!("start_mux_reverify: The MUX is discreet as assumed");
+                    "The MUX is discreet as assumed at boot".to_string()
+                }
+                Ok(MuxReverify::Corrected(mode)) => {
+                    match correct_assumed_mux(mode, &config, &dgpu, &audit).await {
+                        Ok(true) => {
+                            if let Some(ctxt) = &signal_ctxt {
+                                emit_counted!(ctxt, Signal::Gfx, notify_gfx(&mode))
+                                    .await
+                                    .unwrap_or_else(|err| warn!("start_mux_reverify: {err}"));
+                            }
+                            format!("The MUX is in Optimus, not discreet as assumed at boot, the mode was corrected to {mode}")
+                        }
+                        Ok(false) => format!("The MUX is in Optimus, not discreet as assumed at boot. The mode was changed since, {mode} wasn't set"),
+                        Err(err) => {
+                            error!("start_mux_reverify: {err}");
+                            format!("The MUX is in Optimus, not discreet as assumed at boot. Correcting the mode to {mode} failed: {err}")
+                        }
+                    }
+                }
+                Ok(MuxReverify::Unreadable) => {
+                    error!(
+                        "start_mux_reverify: {ASUS_GPU_MUX_PATH} still can't be read after {}s",
+                        MUX_REVERIFY_WINDOW.as_secs()
+                    );
+                    format!(
+                        "{ASUS_GPU_MUX_PATH} still can't be read, the mode stays {}. A reboot may be needed",
+                        GfxMode::AsusMuxDgpu
+                    )
+                }
+                Err(err) => {
+                    error!("start_mux_reverify: {err}");
+                    format!("Checking the MUX again failed: {err}")
+                }
+            };
+            notify_boot_advisory(signal_ctxt.as_ref(), &advisory).await;
+        };
+        Some(spawn_supervised(
+            "MUX re-verify",
+            task,
+            move |msg| async move {
+                if let Some(ctxt) = panic_ctxt {
+                    emit_counted!(
+                        &ctxt,
+                        Signal::Error,
+                        notify_error(&format!("MUX re-verify: {msg}"))
+                    )
+                    .await
+                    .ok();
+                }
+            },
+        ))
     }
 
     /// Initiates a mode change by starting a thread that will wait until all
@@ -177,88 +2556,513 @@ impl CtrlGraphics {
     ///
     /// For manually calling (not on boot/startup) via dbus
     pub async fn set_gfx_mode(&mut self, mode: GfxMode) -> Result<UserActionRequired, GfxError> {
-        mode_support_check(&mode)?;
+        self.set_gfx_mode_with_options(mode, SetModeOptions::default(), &Actor::Daemon)
+            .await
+    }
 
+    /// As `set_gfx_mode` but with per call options, and who asked for the switch for the
+    /// audit log
+    pub async fn set_gfx_mode_with_options(
+        &mut self,
+        mode: GfxMode,
+        options: SetModeOptions,
+        actor: &Actor,
+    ) -> Result<UserActionRequired, GfxError> {
+        self.check_switch_allowed(mode).await?;
         self.loop_exit.store(false, Ordering::Release);
 
         let vendor = self.dgpu.lock().await.vendor();
-        let user_action_required;
-        let actions;
+        let plan = {
+            let config = self.config.lock().await;
+            let from = config.effective_mode();
+            plan_switch(&config, vendor, from, mode, &PlanEnv::probe(from)).with_options(options)
+        };
+        if !options.persist && plan.user_action == UserActionRequired::Reboot {
+            return Err(GfxError::NotSupported(format!(
+                "The switch to {mode} needs a reboot, so it can't be only until reboot"
+            )));
+        }
+
+        // Start a thread to perform the actions on then return the user action required
+        // First, stop all threads
+        self.loop_exit.store(true, Ordering::Release);
+
+        let actions = match plan.actions {
+            Some(actions) => actions,
+            None => {
+                if mode == plan.from && options.persist {
+                    self.persist_current_mode(mode, actor).await?;
+                }
+                return Ok(plan.user_action);
+            }
+        };
+        let until_reboot = if options.persist { "" } else { " until reboot" };
+        self.audit.record(
+            actor,
+            &format!("mode {} -> {mode}{until_reboot} requested", plan.from),
+        );
+        self.start_switch_with(
+            mode,
+            plan.user_action,
+            actions,
+            actor.clone(),
+            options.persist,
+        )
+        .await?;
+        Ok(plan.user_action)
+    }
+
+    /// Save `mode`, which is in use until reboot, so it is also used at boot. A switch to
+    /// the mode in use has nothing else to do. A temporary mode such as Vfio without
+    /// `vfio_save` stays temporary.
+    pub(crate) async fn persist_current_mode(
+        &self,
+        mode: GfxMode,
+        actor: &Actor,
+    ) -> Result<(), GfxError> {
+        let mut config = self.config.lock().await;
+        if config.tmp_mode.is_none() || config.mode_is_temporary(mode) {
+            return Ok(());
+        }
+        self.audit
+            .record(actor, &format!("mode {} -> {mode}", config.mode));
+        config.set_switched_mode(mode)
+    }
+
+    /// Check a switch to `mode` may be requested now, failing with the first blocker which
+    /// isn't overridable
+    async fn check_switch_allowed(&self, mode: GfxMode) -> Result<(), GfxError> {
+        let input = self.preflight_input(mode, true, None).await?;
+        match preflight(&input, mode)
+            .into_iter()
+            .find(|blocker| !blocker.overridable())
         {
-            let mut config = self.config.lock().await;
-            let from = config.mode;
+            Some(blocker) => Err(blocker.into()),
+            None => Ok(()),
+        }
+    }
 
-            if config.always_reboot {
-                user_action_required = UserActionRequired::Reboot;
-            } else {
-                user_action_required = UserActionRequired::mode_change_action(mode, config.mode);
+    /// Read what the pre-flight checks of a switch to `mode` look at. A switch re-checks the
+    /// dGPU health first, recording it, while `switch_readiness` only reads it.
+    async fn preflight_input(
+        &self,
+        mode: GfxMode,
+        refresh_health: bool,
+        plan_refused: Option<UserActionRequired>,
+    ) -> Result<PreflightInput, GfxError> {
+        let mutation = self.check_mutation_allowed();
+        let dgpu = self.dgpu_snapshot().await;
+        let (current, locked_to, switching_to) = {
+            let config = self.config.lock().await;
+            (
+                config.effective_mode(),
+                config.mode_locked.then_some(config.mode),
+                config
+                    .pending_mode
+                    .filter(|_| config.switch_state == SwitchState::Switching),
+            )
+        };
+        // Reads /proc, without the dGPU locked
+        let vfio_users = if current == GfxMode::Vfio && mode != GfxMode::Vfio {
+            vfio_users(&dgpu)
+                .iter()
+                .map(|user| user.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let degraded = if refresh_health && mutation.is_ok() {
+            self.check_dgpu_health().await?;
+            self.get_degraded_hardware()
+        } else {
+            match DgpuHealth::check(&dgpu, current) {
+                Some(health) => health == DgpuHealth::FellOffBus,
+                None => self.get_degraded_hardware(),
             }
-            actions = StagedAction::action_list_for_switch(&config, vendor, from, mode);
+        };
+        Ok(PreflightInput {
+            debug_mode: matches!(mutation, Err(GfxError::DebugMode)),
+            shutting_down: matches!(mutation, Err(GfxError::ShuttingDown)),
+            sleeping: self.sleeping.load(Ordering::Acquire),
+            switching_to,
+            switch_committed: self.switch_token.load(Ordering::Acquire) != SWITCH_CANCELLABLE,
+            no_dgpu: OperatingProfile::detect(&dgpu).reason(),
+            no_igpu: self.probe().await.igpu_missing,
+            locked_to,
+            unsupported: mode_support_check(&mode).err().map(|err| match err {
+                GfxError::NotSupported(reason) => reason,
+                err => err.to_string(),
+            }),
+            degraded,
+            vfio_users,
+            plan_refused,
+        })
+    }
 
-            config.pending_mode = Some(mode);
-            config.pending_action = Some(user_action_required);
+    /// Record that the system is about to sleep, or has woken, as logind said
+    pub async fn set_sleeping(&self, sleeping: bool) {
+        if self.sleeping.swap(sleeping, Ordering::AcqRel) != sleeping {
+            notify_readiness_changed(&self.readiness, self.signal_ctxt.as_ref()).await;
         }
+    }
 
-        // Start a thread to perform the actions on then return the user action required
-        // First, stop all threads
+    /// Whether a switch to `mode` would be made if asked for now, and what keeps it from
+    /// being made if not. Only reads, nothing is recorded or changed.
+    pub async fn get_switch_readiness(&self, mode: GfxMode) -> Result<SwitchReadiness, GfxError> {
+        // Read first, so a change while checking gives a newer generation
+        let generation = self.readiness.get();
+        let vendor = self.dgpu_snapshot().await.vendor();
+        let from = self.config.lock().await.effective_mode();
+        // Reads sysfs, without the config locked
+        let env = PlanEnv::probe(from);
+        let plan = plan_switch(&*self.config.lock().await, vendor, from, mode, &env);
+        let plan_refused = match (plan.actions, plan.user_action) {
+            (None, UserActionRequired::Nothing) | (Some(_), _) => None,
+            (None, action) => Some(action),
+        };
+        let input = self.preflight_input(mode, false, plan_refused).await?;
+        Ok(SwitchReadiness::new(
+            mode,
+            generation,
+            &preflight(&input, mode),
+        ))
+    }
+
+    /// Switch to `mode` as soon as the graphical logind `session` ends, for a user who has
+    /// confirmed a logout in order to switch. If it was the last graphical session the
+    /// switch doesn't wait for the others, otherwise it waits as usual. If the session is
+    /// still open after `CONFIRM_LOGOUT_WINDOW`, such as when the logout was cancelled,
+    /// the switch is dropped.
+    pub async fn switch_after_logout(
+        &mut self,
+        mode: GfxMode,
+        session: String,
+        actor: &Actor,
+    ) -> Result<UserActionRequired, GfxError> {
+        self.switch_after_logout_with(
+            mode,
+            session,
+            Arc::new(SystemSessionProbe),
+            CONFIRM_LOGOUT_WINDOW,
+            actor,
+        )
+        .await
+        .map(|(user_action, _)| user_action)
+    }
+
+    /// As `switch_after_logout` with the sessions from `probe`, returns the task
+    /// waiting for the session if one was started
+    pub(crate) async fn switch_after_logout_with(
+        &mut self,
+        mode: GfxMode,
+        session: String,
+        probe: Arc<dyn SessionProbe + Send>,
+        window: Duration,
+        actor: &Actor,
+    ) -> Result<(UserActionRequired, Option<JoinHandle<()>>), GfxError> {
+        self.check_switch_allowed(mode).await?;
+        if !probe.graphical_sessions().await?.contains(&session) {
+            return Err(GfxError::NotSupported(format!(
+                "Session {session} is not an open graphical session"
+            )));
+        }
+
+        let vendor = self.dgpu.lock().await.vendor();
+        let plan = {
+            let config = self.config.lock().await;
+            let from = config.effective_mode();
+            plan_switch(&config, vendor, from, mode, &PlanEnv::probe(from))
+        };
+        if plan.actions.is_none() {
+            return Ok((plan.user_action, None));
+        }
+        // Stop any wait loop of an earlier switch
         self.loop_exit.store(true, Ordering::Release);
+        self.audit.record(
+            actor,
+            &format!(
+                "mode {} -> {mode} requested after logout of session {session}",
+                plan.from
+            ),
+        );
 
-        match actions {
-            actions::Action::UserAction(u) => return Ok(u),
-            actions::Action::StagedActions(actions) => {
-                let dgpu = self.dgpu.clone();
-                // This atomixc is to force an exit of any loops
-                let loop_exit = self.loop_exit.clone();
-                let config = self.config.clone();
-                // This will block if required to wait for logouts, so run concurrently.
-                tokio::spawn(async move {
-                    let mut failed = false;
-                    for action in actions {
-                        debug!("Doing action: {action:?}");
-                        let mut dgpu = dgpu.lock().await;
-
-                        let res = action.perform(mode, &mut dgpu, loop_exit.clone()).await;
-                        match res {
-                            Ok(_) => {}
-                            Err(GfxError::SystemdUnitWaitTimeout(e)) => {
-                                error!("Action thread errored: {e}");
-                                failed = true;
-                                break;
-                            }
-                            Err(e) => {
-                                error!("Action thread errored: {e}");
-                                failed = true;
-                            }
-                        }
+        let (switch_token, running) = self.pend_switch(mode, plan.user_action, actor).await?;
+        let runner = self.switch_runner(vendor);
+        let config = self.config.clone();
+        let actor = actor.clone();
+        info!("Switching to {mode} once session {session} ends");
+        let handle = self.spawn_switch_task(async move {
+            let _running = running;
+            let end = match wait_session_end(&*probe, &session, window, &switch_token).await {
+                Ok(end) => end,
+                Err(e) => {
+                    warn!("switch_after_logout: {e}, waiting for all sessions instead");
+                    SessionEnd::Ended { others: true }
+                }
+            };
+            match end {
+                // `cancel_switch` has already reset the pending state
+                SessionEnd::Cancelled => return,
+                SessionEnd::Expired => {
+                    let mut config = config.lock().await;
+                    if switch_token
+                        .compare_exchange(
+                            SWITCH_CANCELLABLE,
+                            SWITCH_CANCELLED,
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        )
+                        .is_ok()
+                    {
+                        info!("Session {session} didn't end, dropping the switch to {mode}");
+                        config.clear_pending(SwitchState::Idle);
                     }
+                    return;
+                }
+                SessionEnd::Ended { .. } => {}
+            }
+            // Planned again as the machine may have changed while waiting
+            let actions = {
+                let config = config.lock().await;
+                let from = config.effective_mode();
+                plan_switch(&config, vendor, from, mode, &PlanEnv::probe(from))
+                    .with_options(SetModeOptions::default())
+                    .actions
+                    .unwrap_or_default()
+            };
+            runner
+                .run(
+                    mode,
+                    after_session_end(actions, end),
+                    switch_token,
+                    actor,
+                    true,
+                )
+                .await;
+        });
+        Ok((plan.user_action, Some(handle)))
+    }
 
-                    let mut config = config.lock().await;
-                    config.pending_mode = None;
-                    config.pending_action = None;
-                    if !failed {
-                        config.mode = mode;
-                        config.write();
-                    } else {
-                        let from = config.mode;
-                        let actions =
-                            StagedAction::action_list_for_switch(&config, vendor, mode, from);
-                        if let actions::Action::StagedActions(actions) = actions {
-                            for action in actions {
-                                debug!("Doing action: {action:?}");
-                                let mut dgpu = dgpu.lock().await;
-                                if let Err(e) =
-                                    action.perform(mode, &mut dgpu, loop_exit.clone()).await
-                                {
-                                    error!("Action thread errored fallback failed: {e}");
-                                    return;
-                                }
-                            }
-                        }
+    /// Mark `mode` as pending as asked for by `actor`, returning the token to cancel the
+    /// switch with and the guard its task holds while it runs. A switch which is still
+    /// running is cancelled and waited for, unless it has committed, which refuses this one.
+    async fn pend_switch(
+        &mut self,
+        mode: GfxMode,
+        user_action_required: UserActionRequired,
+        actor: &Actor,
+    ) -> Result<(Arc<AtomicU8>, OwnedMutexGuard<()>), GfxError> {
+        let running = match self.switch_running.try_lock_owned() {
+            Some(running) => running,
+            None => {
+                match self.switch_token.compare_exchange(
+                    SWITCH_CANCELLABLE,
+                    SWITCH_CANCELLED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    // Already cancelled, the task is on its way out
+                    Ok(_) | Err(SWITCH_CANCELLED) => {}
+                    Err(_) => {
+                        let pending = self.config.lock().await.pending_mode;
+                        return Err(GfxError::SwitchInProgress(pending.unwrap_or(mode)));
                     }
-                });
+                }
+                info!("Dropping the pending switch for the switch to {mode}");
+                // Break out of any wait loop the switch is in
+                self.loop_exit.store(true, Ordering::Release);
+                self.switch_running.clone().lock_owned().await
+            }
+        };
+        {
+            let mut config = self.config.lock().await;
+            config.pending_mode = Some(mode);
+            config.pending_action = Some(user_action_required);
+            config.pending_request = Some(PendingRequest::new(actor.clone()));
+            config.switch_state = SwitchState::Switching;
+        }
+        self.switch_token = Arc::new(AtomicU8::new(SWITCH_CANCELLABLE));
+        Ok((self.switch_token.clone(), running))
+    }
+
+    fn switch_runner(&self, vendor: GfxVendor) -> SwitchRunner {
+        SwitchRunner {
+            ops: SystemSwitchOps {
+                dgpu: self.dgpu.clone(),
+                config: self.config.clone(),
+                vendor,
+                // This atomic is to force an exit of any loops
+                loop_exit: self.loop_exit.clone(),
+                waiting_for: self.switch_waiting_for.clone(),
+                logout_timeout: self.logout_timeout.clone(),
+                staging: self.staging.clone(),
+                initramfs: self.initramfs.clone(),
+                signal_ctxt: self.signal_ctxt.clone(),
+            },
+            dgpu: self.dgpu.clone(),
+            config: self.config.clone(),
+            audit: self.audit.clone(),
+            staging: self.staging.clone(),
+            switcheroo: self.switcheroo.clone(),
+            logout_timeout: self.logout_timeout.clone(),
+            tasks: self.tasks.clone(),
+            readiness: self.readiness.clone(),
+        }
+    }
+
+    /// Mark `mode` as pending and spawn the task which performs `actions`, recording the mode
+    /// change as made by `actor`. The task will block if required to wait for logouts. The mode
+    /// is only recorded until reboot unless `persist` is set. A switch still pending is dropped
+    /// for this one, see `pend_switch`.
+    pub(crate) async fn start_switch_with(
+        &mut self,
+        mode: GfxMode,
+        user_action_required: UserActionRequired,
+        actions: Vec<StagedAction>,
+        actor: Actor,
+        persist: bool,
+    ) -> Result<JoinHandle<()>, GfxError> {
+        let (switch_token, running) = self.pend_switch(mode, user_action_required, &actor).await?;
+        let vendor = self.dgpu.lock().await.vendor();
+        let runner = self.switch_runner(vendor);
+        Ok(self.spawn_switch_task(async move {
+            let _running = running;
+            runner
+                .run(mode, actions, switch_token, actor, persist)
+                .await
+        }))
+    }
+
+    /// Lock or unlock the mode to the one currently configured. The caller must check that
+    /// `actor` is allowed to. `GfxError::ConfigNotPersisted` if the lock was changed but
+    /// couldn't be saved.
+    pub async fn set_mode_locked(&mut self, locked: bool, actor: &Actor) -> Result<(), GfxError> {
+        self.check_mutation_allowed()?;
+        let written;
+        {
+            let mut config = self.config.lock().await;
+            if config.mode_locked == locked {
+                return Ok(());
+            }
+            config.mode_locked = locked;
+            written = config.write();
+            self.audit.record(
+                actor,
+                &format!(
+                    "mode {} {}",
+                    if locked { "locked to" } else { "unlocked from" },
+                    config.mode
+                ),
+            );
+        }
+        self.recheck_supported_modes().await;
+        notify_readiness_changed(&self.readiness, self.signal_ctxt.as_ref()).await;
+        written
+    }
+
+    /// Get if the mode is locked by the administrator
+    pub(crate) async fn get_mode_locked(&self) -> bool {
+        self.config.lock().await.mode_locked
+    }
+
+    /// Cancel a pending switch. This only succeeds while the switch has not yet changed
+    /// anything, e.g. while waiting for logout or during the `pre_stop_delay_s` countdown.
+    pub async fn cancel_pending_switch(&mut self) -> Result<(), GfxError> {
+        let mut config = self.config.lock().await;
+        if config.switch_state != SwitchState::Switching {
+            return Err(GfxError::NoSwitchPending);
+        }
+        match self.switch_token.compare_exchange(
+            SWITCH_CANCELLABLE,
+            SWITCH_CANCELLED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // Break out of any wait loop the switch is in
+                self.loop_exit.store(true, Ordering::Release);
+                info!("Cancelled switch to {:?}", config.pending_mode);
+                config.clear_pending(SwitchState::Idle);
+                Ok(())
+            }
+            Err(_) => Err(GfxError::SwitchCommitted),
+        }
+    }
+
+    /// As `cancel_pending_switch` for a dbus client with `uid`. With `cancel_requester_only`
+    /// set only the client which asked for the switch, or root, may cancel it.
+    pub async fn cancel_pending_switch_by(&mut self, uid: Option<u32>) -> Result<(), GfxError> {
+        {
+            let config = self.config.lock().await;
+            if config.cancel_requester_only {
+                may_cancel(config.pending_request.as_ref(), uid)?;
+            }
+        }
+        self.cancel_pending_switch().await
+    }
+
+    /// Refuse any further change, and stop a running switch at its next safe point. A switch
+    /// which hasn't changed anything yet is cancelled, one which has is parked after the
+    /// action it is performing. Finish with `ShutdownWait::finish`.
+    pub async fn begin_shutdown(&self) -> ShutdownWait {
+        self.shutting_down.store(true, Ordering::Release);
+        notify_readiness_changed(&self.readiness, self.signal_ctxt.as_ref()).await;
+        let mut config = self.config.lock().await;
+        let interrupted = match config.pending_mode {
+            Some(mode) if config.switch_state == SwitchState::Switching => {
+                if park_switch(&self.switch_token) == SWITCH_CANCELLED {
+                    // Break out of any wait loop the switch is in
+                    self.loop_exit.store(true, Ordering::Release);
+                    config.clear_pending(SwitchState::Idle);
+                    Interrupted::Cancelled(mode)
+                } else {
+                    Interrupted::Parked(mode)
+                }
             }
+            _ => Interrupted::Nothing,
+        };
+        ShutdownWait {
+            config: self.config.clone(),
+            signal_ctxt: self.signal_ctxt.clone(),
+            interrupted,
         }
+    }
 
-        Ok(user_action_required)
+    /// Run the body of a switch in a supervised task. If the task panics the pending
+    /// state is cleared, the switch is marked as `Stalled` so that a new switch can be
+    /// requested, and a `notify_error` signal is emitted.
+    pub(crate) fn spawn_switch_task<F>(&self, task: F) -> JoinHandle<()>
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let config = self.config.clone();
+        let loop_exit = self.loop_exit.clone();
+        let signal_ctxt = self.signal_ctxt.clone();
+        let mark = SwitchingMark::new(self.instance.clone());
+        let task = async move {
+            let _mark = mark;
+            task.await
+        };
+        spawn_supervised("switch task", task, move |msg| async move {
+            // Release anything blocked on this switch
+            loop_exit.store(true, Ordering::Release);
+            {
+                let mut config = config.lock().await;
+                warn!(
+                    "switch task: clearing pending mode {:?} after panic",
+                    config.pending_mode
+                );
+                config.clear_pending(SwitchState::Stalled);
+            }
+            if let Some(ctxt) = signal_ctxt {
+                emit_counted!(
+                    &ctxt,
+                    Signal::Error,
+                    notify_error(&format!("Mode switch failed: {msg}"))
+                )
+                .await
+                .unwrap_or_else(|err| warn!("{}", err));
+            }
+        })
     }
 }
@@ -1,22 +1,103 @@
-use std::{env, sync::Arc, time::Duration};
+use std::{
+    env,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use futures_util::{lock::Mutex, StreamExt};
-use log::{error, info, trace};
+use gumdrop::Options;
+use log::{error, info, warn};
 use logind_zbus::manager::ManagerProxy;
 use supergfxctl::{
-    config::GfxConfig,
-    controller::CtrlGraphics,
+    audit::AuditLog,
+    config::{GfxConfig, TMP_MODE_PATH},
+    controller::{BootOutcome, CtrlGraphics, DebugRun},
     error::GfxError,
-    pci_device::{DiscreetGpu, GfxMode, GfxPower, HotplugType},
+    instance::{
+        check_interface, request_daemon_name, serve_interface, InstanceLock, INSTANCE_LOCK_PATH,
+    },
+    pci_device::{GfxMode, HotplugType},
+    sandbox::{enable_drift_check, machine_profile},
+    shutdown::SHUTDOWN_GRACE,
     special_asus::{asus_dgpu_disable_exists, asus_dgpu_set_disabled},
-    CONFIG_PATH, DBUS_DEST_NAME, DBUS_IFACE_PATH, VERSION,
+    supervisor::{RestartPolicy, TaskSupervisor},
+    systemd_notify, DBUS_IFACE_PATH, VERSION,
 };
-use tokio::time::sleep;
+use tokio::signal::unix::{signal, SignalKind};
 use zbus::Connection;
-use zbus::{object_server::SignalEmitter, zvariant::ObjectPath};
+use zbus::{names::BusName, object_server::SignalEmitter};
+
+/// Environment variable with the config file to use for a debug run
+const DEBUG_CONFIG_ENV: &str = "SUPERGFXD_CONFIG";
+
+#[derive(Default, Options)]
+struct DaemonArgs {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        no_short,
+        help = "Run outside of systemd for development, on the session bus and with changes to the system disabled"
+    )]
+    debug_run: bool,
+    #[options(no_short, help = "Allow --debug-run as root")]
+    debug_allow_root: bool,
+    #[options(no_short, help = "Allow a --debug-run to change the system")]
+    debug_allow_mutation: bool,
+    #[options(
+        no_short,
+        help = "Print the systemd sandboxing this machine needs, after detecting the hardware"
+    )]
+    emit_sandbox_profile: bool,
+    #[options(
+        no_short,
+        help = "Warn of every write not covered by the sandbox annotations"
+    )]
+    sandbox_check: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), GfxError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match DaemonArgs::parse_args_default(&args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if args.help {
+        println!("{}", DaemonArgs::usage());
+        return Ok(());
+    }
+    if args.emit_sandbox_profile {
+        match machine_profile() {
+            Ok(profile) => print!("{profile}"),
+            Err(err) => {
+                eprintln!("Error: {err}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if (args.debug_allow_root || args.debug_allow_mutation) && !args.debug_run {
+        eprintln!("--debug-allow-root and --debug-allow-mutation require --debug-run");
+        std::process::exit(1);
+    }
+    let debug_run = if args.debug_run {
+        // The effective uid owns the process's own /proc entry
+        let is_root = std::fs::metadata("/proc/self").map_or(false, |m| m.uid() == 0);
+        if is_root && !args.debug_allow_root {
+            eprintln!("--debug-run as root could change your session, add --debug-allow-root if this is intended");
+            std::process::exit(1);
+        }
+        Some(DebugRun {
+            allow_mutation: args.debug_allow_mutation,
+        })
+    } else {
+        None
+    };
+
     let mut logger = env_logger::Builder::new();
     logger
         .parse_default_env()
@@ -30,7 +111,7 @@ async fn main() -> Result<(), GfxError> {
         None => false,
     };
 
-    if !is_service {
+    if !is_service && debug_run.is_none() {
         println!("supergfxd schould be only run from the right systemd service");
         println!(
             "do not run in your terminal, if you need an logs please use journalctl -b -u supergfxd"
@@ -40,110 +121,231 @@ async fn main() -> Result<(), GfxError> {
     }
 
     info!("Daemon version: {VERSION}");
+    if args.sandbox_check {
+        warn!("Sandbox check on, writes not covered by the annotations are logged");
+        enable_drift_check(Path::new("/"));
+    }
+    if let Some(debug) = debug_run {
+        warn!(
+            "Debug run, changes to the system allowed: {}",
+            debug.allow_mutation
+        );
+    }
+
+    start_daemon(debug_run).await
+}
+
+/// The config file for a debug run, never the one used by the system service
+fn debug_config_path() -> String {
+    env::var_os(DEBUG_CONFIG_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join("supergfxd-debug.json"))
+        .to_string_lossy()
+        .to_string()
+}
 
-    start_daemon().await
+/// The instance lock, a debug run has its own so it can run beside the service
+fn instance_lock_path(debug_run: Option<DebugRun>) -> PathBuf {
+    if debug_run.is_some() {
+        env::temp_dir().join("supergfxd-debug.lock")
+    } else {
+        PathBuf::from(INSTANCE_LOCK_PATH)
+    }
 }
 
-async fn start_daemon() -> Result<(), GfxError> {
+async fn start_daemon(debug_run: Option<DebugRun>) -> Result<(), GfxError> {
+    let instance = match InstanceLock::acquire_at(&instance_lock_path(debug_run)) {
+        Ok(lock) => Arc::new(lock),
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    // Handled from here so a stop during the boot tasks waits for them
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
     // Start zbus server
-    let connection = Connection::system().await?;
-    // Request dbus name after finishing initalizing all functions
-    connection.request_name(DBUS_DEST_NAME).await?;
+    let connection = if debug_run.is_some() {
+        Connection::session().await?
+    } else {
+        Connection::system().await?
+    };
 
-    let config = GfxConfig::load(CONFIG_PATH.into());
-    let use_logind = !config.no_logind;
+    let config = if debug_run.is_some() {
+        let path = debug_config_path();
+        info!("Debug run using config {path}");
+        GfxConfig::load(path)
+    } else {
+        let mut config = GfxConfig::load_default();
+        // A debug run must not write to the runtime directory
+        config.keep_tmp_mode_at(Path::new(TMP_MODE_PATH));
+        config
+    };
+    // The logind watcher writes dgpu_disable on resume
+    let use_logind = !config.no_logind && debug_run.map_or(true, |debug| debug.allow_mutation);
     let config = Arc::new(Mutex::new(config));
 
+    // Owns the background tasks, they are cancelled on the way out
+    let tasks = TaskSupervisor::default();
     if use_logind {
-        start_logind_tasks(config.clone()).await;
+        start_logind_tasks(&tasks, config.clone(), connection.clone());
     }
 
+    let boot_status;
     // Graphics switching requires some checks on boot specifically for g-sync capable laptops
-    match CtrlGraphics::new(config.clone()) {
+    match CtrlGraphics::new(config.clone(), debug_run) {
         Ok(mut ctrl) => {
-            ctrl.reload()
-                .await
-                .unwrap_or_else(|err| error!("Gfx controller: {}", err));
-
-            let signal_context = SignalEmitter::new(&connection, DBUS_IFACE_PATH)?;
-            start_notify_status(ctrl.dgpu_arc_clone(), signal_context)
-                .await
-                .ok();
-
-            connection
-                .object_server()
-                .at(&ObjectPath::from_str_unchecked(DBUS_IFACE_PATH), ctrl)
-                .await
-                // .map_err(|err| {
-                //     warn!("{}: add_to_server {}", path, err);
-                //     err
-                // })
-                .ok();
-        }
-        Err(err) => {
-            error!("Gfx control: {}", err);
+            ctrl.set_instance_lock(instance.clone());
+            ctrl.set_task_supervisor(tasks.clone());
+            // A debug run must not write to the system state directory
+            if debug_run.is_none() {
+                ctrl.set_audit_log(AuditLog::system());
+                ctrl.watch_initramfs().await;
+                ctrl.keep_attention().await;
+            }
+            let outcome = ctrl.reload().await.unwrap_or_else(|err| {
+                error!("Gfx controller: {}", err);
+                BootOutcome::Failed(err.to_string())
+            });
+            boot_status = systemd_notify::boot_status(&outcome);
+            if outcome.broken() && config.lock().await.exit_on_degraded_boot {
+                error!(
+                    "Boot tasks failed and exit_on_degraded_boot is set, exiting: {boot_status}"
+                );
+                systemd_notify::notify_status(&boot_status);
+                std::process::exit(1);
+            }
+
+            let signal_context = match SignalEmitter::new(&connection, DBUS_IFACE_PATH) {
+                Ok(ctxt) => ctxt,
+                Err(err) => {
+                    exit_unserved(&GfxError::DbusInterface(format!(
+                        "no signal context for {DBUS_IFACE_PATH}: {err}"
+                    )));
+                }
+            };
+            ctrl.set_signal_context(signal_context);
+            ctrl.notify_hotplug_downgrade().await;
+            ctrl.start_mux_reverify();
+            ctrl.start_supported_modes_watcher();
+            ctrl.start_notify_status();
+            ctrl.start_ac_automation();
+            ctrl.start_dock_automation();
+            ctrl.start_egpu_watch();
+            ctrl.start_schedule();
+            ctrl.start_inhibit_owner_watch();
+            if debug_run.is_none() {
+                // A debug run must not write to /run
+                ctrl.start_warm_staging();
+                ctrl.start_periodic_verify();
+                ctrl.start_runtime_pm_guard();
+            }
+
+            if let Err(err) = serve_interface(&connection, ctrl).await {
+                exit_unserved(&err);
+            }
         }
+        // Without the controller there is no interface to serve
+        Err(err) => exit_unserved(&err),
+    }
+    // A call through the bus to the unique name, so clients never get a name without the
+    // interface behind it
+    let unique_name = connection.unique_name().map(BusName::from);
+    if let Err(err) = check_interface(&connection, unique_name).await {
+        exit_unserved(&err);
     }
     // Request dbus name after finishing initalizing all functions
-    connection.request_name(DBUS_DEST_NAME).await?;
-
-    // Loop to check errors and iterate zbus server
-    loop {
-        sleep(Duration::from_secs(1)).await;
+    if let Err(err) = request_daemon_name(&connection).await {
+        error!("{err}");
+        std::process::exit(1);
     }
-}
+    systemd_notify::notify(&systemd_notify::state_message(&[
+        ("READY", "1"),
+        ("STATUS", &boot_status),
+    ]));
 
-async fn start_notify_status(
-    dgpu: Arc<Mutex<DiscreetGpu>>,
-    signal_ctxt: SignalEmitter<'static>,
-) -> Result<(), GfxError> {
-    tokio::spawn(async move {
-        let mut last_status = GfxPower::Unknown;
-        loop {
-            let s = dgpu
-                .lock()
-                .await
-                .get_runtime_status()
-                .map_err(|e| trace!("{e}"))
-                .unwrap_or(GfxPower::Unknown);
-            if s != last_status {
-                last_status = s;
-                trace!("Notify: dGPU status = {s:?}");
-                CtrlGraphics::notify_gfx_status(&signal_ctxt, &last_status)
-                    .await
-                    .map_err(|e| trace!("{e}"))
-                    .ok();
+    let exit_code = tasks
+        .wait_for_stop(async {
+            tokio::select! {
+                _ = sigterm.recv() => info!("Received SIGTERM, stopping"),
+                _ = sigint.recv() => info!("Received SIGINT, stopping"),
             }
-            sleep(Duration::from_secs(1)).await;
+        })
+        .await;
+    systemd_notify::notify("STOPPING=1");
+    if let Ok(iface) = connection
+        .object_server()
+        .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
+        .await
+    {
+        let wait = iface.get().await.begin_shutdown().await;
+        if !wait.finish(SHUTDOWN_GRACE).await {
+            error!("The mode switch didn't stop in time, the next start will restore the configured mode");
         }
-    });
+    }
+    tasks.shutdown().await;
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
 
-async fn start_logind_tasks(config: Arc<Mutex<GfxConfig>>) {
-    let connection = Connection::system()
-        .await
-        .expect("Controller could not create dbus connection");
+/// Exit when the dbus interface can't be served, rather than run on with nothing to reach
+fn exit_unserved(err: &GfxError) -> ! {
+    error!("{err}");
+    systemd_notify::notify_status(&format!("FAILED: {err}"));
+    std::process::exit(1);
+}
 
-    let manager = ManagerProxy::new(&connection)
+/// Tell the controller the system is about to sleep or has woken, once it is served
+async fn set_sleeping(server: &Connection, sleeping: bool) {
+    if let Ok(iface) = server
+        .object_server()
+        .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
         .await
-        .expect("Controller could not create ManagerProxy");
-
-    tokio::spawn(async move {
-        if let Ok(mut notif) = manager.receive_prepare_for_sleep().await {
-            while let Some(event) = notif.next().await {
-                if let Ok(args) = event.args() {
-                    if !args.start() {
-                        // on_wake();
-                        let config = config.lock().await;
-                        if config.mode == GfxMode::Integrated
-                            && config.hotplug_type == HotplugType::Asus
-                            && asus_dgpu_disable_exists()
-                        {
-                            info!("logind task: Waking from suspend, setting dgpu_disable");
-                            asus_dgpu_set_disabled(true)
-                                .map_err(|e| error!("logind task: {e}"))
-                                .ok();
+    {
+        iface.get().await.set_sleeping(sleeping).await;
+    }
+}
+
+fn start_logind_tasks(tasks: &TaskSupervisor, config: Arc<Mutex<GfxConfig>>, server: Connection) {
+    tasks.spawn("logind watcher", RestartPolicy::WithBackoff, move || {
+        let config = config.clone();
+        let server = server.clone();
+        async move {
+            let connection = match Connection::system().await {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("logind task: could not create dbus connection: {e}");
+                    return;
+                }
+            };
+            let manager = match ManagerProxy::new(&connection).await {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("logind task: could not create ManagerProxy: {e}");
+                    return;
+                }
+            };
+
+            if let Ok(mut notif) = manager.receive_prepare_for_sleep().await {
+                while let Some(event) = notif.next().await {
+                    if let Ok(args) = event.args() {
+                        set_sleeping(&server, *args.start()).await;
+                        if !args.start() {
+                            // on_wake();
+                            let config = config.lock().await;
+                            if config.mode == GfxMode::Integrated
+                                && config.effective_hotplug_type() == HotplugType::Asus
+                                && asus_dgpu_disable_exists()
+                            {
+                                info!("logind task: Waking from suspend, setting dgpu_disable");
+                                asus_dgpu_set_disabled(true)
+                                    .await
+                                    .map_err(|e| error!("logind task: {e}"))
+                                    .ok();
+                            }
                         }
                     }
                 }
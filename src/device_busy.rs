@@ -0,0 +1,200 @@
+use std::{
+    fs,
+    io::{ErrorKind, Read},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buffers::BoundedQueue,
+    error::{BusyDevice, GfxError},
+    timeout::blocking_with_timeout,
+    PROC_PATH,
+};
+
+const KMSG_PATH: &str = "/dev/kmsg";
+/// How long looking for what keeps a PCI function busy may take, so a failed unbind or
+/// remove is reported soon even when the kernel is wedged on the device
+const GATHER_TIME: Duration = Duration::from_secs(1);
+/// How many of the kernel log lines which mention the function are kept
+pub(crate) const KMSG_TAIL_LINES: usize = 20;
+/// The longest `/dev/kmsg` record, a read into a shorter buffer fails
+const KMSG_RECORD_MAX: usize = 8192;
+/// Kernel functions a task waiting on a PCI function, its driver or its runtime PM sleeps in
+const DEVICE_WAITS: &[&str] = &["pci_", "rpm_", "pm_runtime_", "device_release_driver"];
+
+/// The name and state from the `stat` of a process, `pid (comm) state ...`. The name can
+/// hold spaces and parentheses itself.
+fn comm_and_state(stat: &str) -> Option<(&str, char)> {
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let state = stat.get(close + 1..)?.trim_start().chars().next()?;
+    Some((stat.get(open + 1..close)?, state))
+}
+
+/// The kernel function in a `stack` line, `[<0>] rpm_resume+0x1a5/0x6e0`
+fn stack_function(line: &str) -> Option<&str> {
+    line.split_whitespace().nth(1)?.split('+').next()
+}
+
+/// Whether a task sleeping in the kernel function `function` waits on `address` or `driver`
+fn waits_on(function: &str, address: &str, driver: Option<&str>) -> bool {
+    !function.is_empty()
+        && (function.contains(address)
+            || driver.map_or(false, |driver| function.contains(driver))
+            || DEVICE_WAITS.iter().any(|wait| function.starts_with(wait)))
+}
+
+/// The processes under `proc_root` in uninterruptible sleep (state `D`) in a kernel
+/// function naming `address`, `driver`, PCI or runtime PM, from their `wchan` or else their
+/// `stack` where it can be read. As `pid 1234 (nvidia-smi) in rpm_resume`, sorted by pid.
+/// Only `stat`, `wchan` and `stack` are read as they don't block on a stuck process, and
+/// the scan stops at `deadline`.
+pub(crate) fn d_state_suspects_in(
+    proc_root: &Path,
+    address: &str,
+    driver: Option<&str>,
+    deadline: Instant,
+) -> Vec<String> {
+    let entries = match fs::read_dir(proc_root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut suspects: Vec<(u32, String)> = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        if Instant::now() >= deadline {
+            break;
+        }
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        let dir = entry.path();
+        let stat = fs::read_to_string(dir.join("stat")).unwrap_or_default();
+        let comm = match comm_and_state(&stat) {
+            Some((comm, 'D')) => comm,
+            _ => continue,
+        };
+        let wchan = fs::read_to_string(dir.join("wchan")).unwrap_or_default();
+        let wchan = wchan.trim();
+        let function = if waits_on(wchan, address, driver) {
+            Some(wchan.to_string())
+        } else {
+            fs::read_to_string(dir.join("stack"))
+                .unwrap_or_default()
+                .lines()
+                .filter_map(stack_function)
+                .find(|function| waits_on(function, address, driver))
+                .map(str::to_string)
+        };
+        if let Some(function) = function {
+            suspects.push((pid, format!("pid {pid} ({comm}) in {function}")));
+        }
+    }
+    suspects.sort_by_key(|(pid, _)| *pid);
+    suspects.into_iter().map(|(_, suspect)| suspect).collect()
+}
+
+/// The message of a kernel log line, `priority,seq,usec,flags;message`. `None` for the
+/// `KEY=value` lines after it, which start with a space.
+fn kmsg_message(line: &str) -> Option<&str> {
+    if line.starts_with(' ') {
+        return None;
+    }
+    line.split_once(';').map(|(_, message)| message)
+}
+
+/// Keep the message of `line` in `tail` if it mentions `address`
+fn keep_mentions(tail: &mut BoundedQueue<String>, line: &str, address: &str) {
+    if let Some(message) = kmsg_message(line).filter(|message| message.contains(address)) {
+        tail.push(message.to_string());
+    }
+}
+
+/// The last `KMSG_TAIL_LINES` messages of the kernel log at `kmsg` which mention `address`,
+/// oldest first. `/dev/kmsg` gives a record for each read and, as it is opened
+/// non-blocking, `WouldBlock` once all were read rather than waiting for the next. The read
+/// stops at `deadline`.
+pub(crate) fn kmsg_tail_in(kmsg: &Path, address: &str, deadline: Instant) -> Vec<String> {
+    let mut file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(kmsg)
+    {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let mut tail = BoundedQueue::new("kmsg_tail", KMSG_TAIL_LINES);
+    let mut buf = vec![0; KMSG_RECORD_MAX];
+    let mut pending = String::new();
+    while Instant::now() < deadline {
+        let read = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            // The record was overwritten in the ring buffer before it was read, the next
+            // read gives the oldest left
+            Err(err) if err.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        pending.push_str(&String::from_utf8_lossy(&buf[..read]));
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            keep_mentions(&mut tail, line.trim_end_matches('\n'), address);
+        }
+    }
+    keep_mentions(&mut tail, &pending, address);
+    tail.into_vec()
+}
+
+/// The tasks stuck on `address` and the kernel log lines about it, see
+/// `d_state_suspects_in` and `kmsg_tail_in`
+pub(crate) fn busy_context_in(
+    proc_root: &Path,
+    kmsg: &Path,
+    address: &str,
+    driver: Option<&str>,
+    deadline: Instant,
+) -> (Vec<String>, Vec<String>) {
+    (
+        d_state_suspects_in(proc_root, address, driver, deadline),
+        kmsg_tail_in(kmsg, address, deadline),
+    )
+}
+
+/// `err` from `action` on the PCI function `address` as `GfxError::DeviceBusy`, with what
+/// keeps it busy as far as can be found in `GATHER_TIME`. It is gathered on the blocking pool
+/// and given up on after twice that, should a read of `/proc` block after all.
+pub(crate) async fn device_busy(
+    action: &str,
+    address: &str,
+    driver: Option<String>,
+    runtime_status: Option<String>,
+    err: GfxError,
+) -> GfxError {
+    let (suspects, kmsg_tail) = {
+        let address = address.to_string();
+        let driver = driver.clone();
+        blocking_with_timeout(GATHER_TIME * 2, move || {
+            Some(busy_context_in(
+                Path::new(PROC_PATH),
+                Path::new(KMSG_PATH),
+                &address,
+                driver.as_deref(),
+                Instant::now() + GATHER_TIME,
+            ))
+        })
+        .await
+        .unwrap_or_default()
+    };
+    GfxError::DeviceBusy(Box::new(BusyDevice {
+        action: action.to_string(),
+        device: address.to_string(),
+        driver,
+        runtime_status,
+        suspects,
+        kmsg_tail,
+        error: err.to_string(),
+    }))
+}
@@ -0,0 +1,214 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use log::{debug, info, warn};
+
+use crate::{
+    actions::StagedAction, boot_context::BootContextProbe, pci_device::GfxMode,
+    sandbox::note_write, DISPLAY_MANAGER,
+};
+
+/// The DRM connectors, such as `card1-eDP-1`, each with an `enabled` attribute
+pub(crate) const DRM_CLASS_PATH: &str = "/sys/class/drm";
+/// Where the consoles written to are, `tty1` to `tty{CONSOLE_TTYS}`
+pub(crate) const CONSOLE_DEV_PATH: &str = "/dev";
+/// The VTs logind starts a getty on by default, `NAutoVTs`
+pub(crate) const CONSOLE_TTYS: u32 = 6;
+/// How often the evidence is read while waiting
+pub(crate) const WATCHDOG_POLL: Duration = Duration::from_secs(2);
+
+/// What is looked at to tell whether the graphical stack came back after a switch
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct HealthEvidence {
+    /// `display-manager.service` is active
+    pub display_manager_active: bool,
+    /// The x11 or wayland sessions, a greeter's included. Empty with `no_logind`, which
+    /// doesn't ask logind.
+    pub graphical_sessions: Vec<String>,
+    /// The DRM connectors with a display enabled on them, such as `card1-eDP-1`
+    pub enabled_connectors: Vec<String>,
+}
+
+impl HealthEvidence {
+    /// What a healthy graphical stack has but this doesn't. The sessions are only asked for
+    /// after `StartDisplayManager`, `no_logind` doesn't use logind.
+    pub(crate) fn missing(&self, after: &StagedAction) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if !self.display_manager_active {
+            missing.push("an active display-manager.service");
+        }
+        if *after == StagedAction::StartDisplayManager && self.graphical_sessions.is_empty() {
+            missing.push("a graphical login or greeter session");
+        }
+        if self.enabled_connectors.is_empty() {
+            missing.push("a display enabled on any DRM card");
+        }
+        missing
+    }
+}
+
+/// Reads the evidence, so the watchdog can be tested
+pub(crate) trait HealthProbe: Sync {
+    fn evidence(&self, with_sessions: bool) -> BoxFuture<'_, HealthEvidence>;
+}
+
+/// The evidence as the running system has it
+pub(crate) struct SystemHealthProbe<P: BootContextProbe> {
+    pub sessions: P,
+}
+
+impl<P: BootContextProbe> HealthProbe for SystemHealthProbe<P> {
+    fn evidence(&self, with_sessions: bool) -> BoxFuture<'_, HealthEvidence> {
+        Box::pin(async move {
+            let graphical_sessions = if with_sessions {
+                self.sessions
+                    .graphical_sessions()
+                    .await
+                    .map_err(|err| debug!("display watchdog: sessions: {err}"))
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            HealthEvidence {
+                display_manager_active: self
+                    .sessions
+                    .display_manager_active()
+                    .map_err(|err| debug!("display watchdog: {DISPLAY_MANAGER}: {err}"))
+                    .unwrap_or(false),
+                graphical_sessions,
+                enabled_connectors: enabled_connectors(Path::new(DRM_CLASS_PATH)),
+            }
+        })
+    }
+}
+
+/// The DRM connectors under `root`, `/sys/class/drm`, with `enabled` set
+pub(crate) fn enabled_connectors(root: &Path) -> Vec<String> {
+    let mut connectors: Vec<String> = fs::read_dir(root)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    let enabled = fs::read_to_string(entry.path().join("enabled")).ok()?;
+                    (name.starts_with("card") && name.contains('-') && enabled.trim() == "enabled")
+                        .then_some(name)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    connectors.sort();
+    connectors
+}
+
+/// Wait up to `timeout` for the graphical stack to come back after `after`, reading the
+/// evidence every `poll`. `Err` with the last evidence read if it didn't.
+pub(crate) async fn watch_display(
+    probe: &dyn HealthProbe,
+    after: &StagedAction,
+    timeout: Duration,
+    poll: Duration,
+) -> Result<HealthEvidence, HealthEvidence> {
+    let with_sessions = *after == StagedAction::StartDisplayManager;
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let evidence = probe.evidence(with_sessions).await;
+        if evidence.missing(after).is_empty() {
+            return Ok(evidence);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(evidence);
+        }
+        tokio::time::sleep(poll).await;
+    }
+}
+
+/// The message written to the consoles when the graphical stack didn't come back after a
+/// switch to `mode`. `failed` is the action the switch failed at if it was rolled back or
+/// stalled.
+pub(crate) fn console_message(
+    mode: GfxMode,
+    after: &StagedAction,
+    failed: Option<&StagedAction>,
+    missing: &[&str],
+    waited: Duration,
+) -> String {
+    let mut message = format!(
+        "\nsupergfxd: the graphical session did not come back after switching to {mode}.\n\n"
+    );
+    match failed {
+        Some(failed) => message.push_str(&format!(
+            "The switch failed at {failed:?} and was undone as far as it could be.\n"
+        )),
+        None => message.push_str("All the steps of the switch succeeded.\n"),
+    }
+    let step = match after {
+        StagedAction::StartDisplayManager => format!("started {DISPLAY_MANAGER}"),
+        _ => "finished the switch with no_logind set".to_string(),
+    };
+    message.push_str(&format!(
+        "supergfxd {step} and waited {}s, but there is still no:\n",
+        waited.as_secs()
+    ));
+    for item in missing {
+        message.push_str(&format!("  - {item}\n"));
+    }
+    message.push_str(&format!(
+        "\nTo recover, log in on a text console such as this one (Ctrl+Alt+F3) and run:\n  supergfxctl --mode {}\n  journalctl -b -u supergfxd\nthen reboot if the display still doesn't come back.\n\n",
+        GfxMode::Integrated
+    ));
+    message
+}
+
+/// Write `message` to each of `tty1` to `tty{count}` in `dev`. A tty which is missing,
+/// can't be opened or would block is skipped. Returns those written to.
+pub(crate) fn write_consoles(dev: &Path, count: u32, message: &str) -> Vec<String> {
+    let mut written = Vec::new();
+    for n in 1..=count {
+        let name = format!("tty{n}");
+        let path = dev.join(&name);
+        note_write(&path);
+        let res = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+            .open(&path)
+            .and_then(|mut tty| tty.write_all(message.as_bytes()));
+        match res {
+            Ok(()) => written.push(name),
+            Err(err) => debug!("write_consoles: {}: {err}", path.display()),
+        }
+    }
+    if written.is_empty() {
+        warn!("write_consoles: no console could be written to");
+    } else {
+        info!("write_consoles: wrote to {}", written.join(", "));
+    }
+    written
+}
+
+/// The action after which the watchdog waits for the graphical stack, if the switch had one
+pub(crate) fn watched_step(actions: &[StagedAction]) -> Option<StagedAction> {
+    actions
+        .iter()
+        .find(|action| {
+            matches!(
+                action,
+                StagedAction::StartDisplayManager | StagedAction::NoLogind
+            )
+        })
+        .copied()
+}
+
+/// Summary of a failed watch for the error signal and the service status
+pub(crate) fn failure_summary(mode: GfxMode, after: &StagedAction, missing: &[&str]) -> String {
+    format!(
+        "display watchdog: no working display after {after:?} for {mode}, missing {}",
+        missing.join(", ")
+    )
+}
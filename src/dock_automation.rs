@@ -0,0 +1,412 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::lock::Mutex;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::{sleep, timeout, Instant};
+use zbus::{object_server::SignalEmitter, zvariant::Type};
+
+use crate::{
+    ac_automation::{
+        decide_mode, switch_by_automation, AcContext, AcDecision, AcProbe, Debounce, SystemProbe,
+        POWER_SUPPLY_PATH,
+    },
+    automation_inhibit::skip_if_inhibited,
+    config::GfxConfig,
+    controller::{CtrlGraphics, SwitchInitiator, SwitchState},
+    error::GfxError,
+    pci_device::GfxMode,
+    power_watch::spawn_dock_monitor,
+    signal_counters::{emit_counted, Signal},
+    supervisor::RestartPolicy,
+    DBUS_IFACE_PATH,
+};
+
+pub(crate) const USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
+pub(crate) const THUNDERBOLT_DEVICES_PATH: &str = "/sys/bus/thunderbolt/devices";
+/// How often the dock is checked without udev events, and the keep-alive otherwise
+const DOCK_POLL: Duration = Duration::from_secs(5);
+
+/// Whether the machine is in its dock
+#[derive(Debug, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum DockState {
+    Docked,
+    Undocked,
+}
+
+/// What to use while docked or undocked
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DockProfile {
+    /// The mode to suggest, or switch to, e.g `Hybrid` docked and `Integrated` undocked
+    pub mode: GfxMode,
+    /// Let `ac_automation` suggest modes in this state. Off stops AC being plugged in or
+    /// unplugged suggesting anything, such as while docked.
+    #[serde(default = "default_true")]
+    pub ac_automation: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Suggest, and optionally switch to, a mode when the machine is docked or undocked. The
+/// dock is detected by any of `devices` being present, or a power supply of the dock if
+/// `power_supply` is set. Switching without asking follows
+/// `ac_automation.auto_apply_when_no_sessions`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DockProfiles {
+    /// USB or Thunderbolt devices which are in the dock, as `vendor:product` in hex such as
+    /// `17ef:a396`
+    pub devices: Vec<String>,
+    /// An online power supply named or typed `Dock` also means docked
+    pub power_supply: bool,
+    /// Used while docked, `None` suggests nothing
+    pub docked: Option<DockProfile>,
+    /// Used while undocked, `None` suggests nothing
+    pub undocked: Option<DockProfile>,
+    /// Seconds the dock state must stay the same before anything is done, so a flapping
+    /// connection doesn't cause a string of switches
+    pub hold_s: u64,
+}
+
+impl Default for DockProfiles {
+    fn default() -> Self {
+        Self {
+            devices: Vec::new(),
+            power_supply: false,
+            docked: None,
+            undocked: None,
+            hold_s: 5,
+        }
+    }
+}
+
+impl DockProfiles {
+    /// A profile is set for either state
+    pub fn enabled(&self) -> bool {
+        self.docked.is_some() || self.undocked.is_some()
+    }
+
+    pub(crate) fn profile_for(&self, state: DockState) -> Option<&DockProfile> {
+        match state {
+            DockState::Docked => self.docked.as_ref(),
+            DockState::Undocked => self.undocked.as_ref(),
+        }
+    }
+
+    /// Whether `ac_automation` may act in `state`. Before the dock was first checked it may.
+    pub(crate) fn ac_automation_allowed(&self, state: Option<DockState>) -> bool {
+        state
+            .and_then(|state| self.profile_for(state))
+            .map_or(true, |profile| profile.ac_automation)
+    }
+
+    /// The device ids, each checked
+    pub(crate) fn device_ids(&self) -> Result<Vec<(u16, u16)>, GfxError> {
+        self.devices
+            .iter()
+            .map(|id| parse_device_id(id).map_err(GfxError::DockProfiles))
+            .collect()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), GfxError> {
+        self.device_ids()?;
+        if !self.enabled() {
+            return Ok(());
+        }
+        if self.devices.is_empty() && !self.power_supply {
+            return Err(GfxError::DockProfiles(
+                "a profile is set but nothing to detect the dock with, set devices or power_supply"
+                    .to_string(),
+            ));
+        }
+        for (state, profile) in [
+            (DockState::Docked, &self.docked),
+            (DockState::Undocked, &self.undocked),
+        ] {
+            if profile.as_ref().map(|profile| profile.mode) == Some(GfxMode::None) {
+                return Err(GfxError::DockProfiles(format!(
+                    "the {state:?} profile has no mode"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a `vendor:product` id of four hex digits each, such as `17ef:a396`
+pub(crate) fn parse_device_id(id: &str) -> Result<(u16, u16), String> {
+    let parse = |part: &str| {
+        (part.len() == 4)
+            .then(|| u16::from_str_radix(part, 16).ok())
+            .flatten()
+    };
+    id.split_once(':')
+        .and_then(|(vendor, product)| Some((parse(vendor)?, parse(product)?)))
+        .ok_or_else(|| {
+            format!("{id:?} is not a device id, use vendor:product in hex such as 17ef:a396")
+        })
+}
+
+/// Read a sysfs id such as `17ef` or `0x108`
+fn read_id(path: PathBuf) -> Option<u16> {
+    let id = fs::read_to_string(path).ok()?;
+    let id = id.trim();
+    u16::from_str_radix(id.strip_prefix("0x").unwrap_or(id), 16).ok()
+}
+
+/// The `vendor:product` ids of the devices in `dir`, read from the attributes named
+fn device_ids_in(dir: &Path, vendor: &str, product: &str) -> Vec<(u16, u16)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            Some((read_id(path.join(vendor))?, read_id(path.join(product))?))
+        })
+        .collect()
+}
+
+/// A power supply of a dock is online in `dir`
+fn dock_power_supply_in(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|e| e.ok()).any(|entry| {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        let online = fs::read_to_string(path.join("online")).map_or(true, |s| s.trim() == "1");
+        (name.contains("dock") || kind.trim() == "Dock") && online
+    })
+}
+
+/// Check whether the dock of `profiles` is there, with the sysfs of the system under `root`
+/// which is `/` other than in tests
+pub(crate) fn dock_state_in(root: &Path, profiles: &DockProfiles) -> DockState {
+    let under = |path: &str| root.join(path.strip_prefix('/').unwrap_or(path));
+    let wanted = profiles.device_ids().unwrap_or_default();
+    let docked = (!wanted.is_empty()
+        && device_ids_in(&under(USB_DEVICES_PATH), "idVendor", "idProduct")
+            .into_iter()
+            .chain(device_ids_in(
+                &under(THUNDERBOLT_DEVICES_PATH),
+                "vendor",
+                "device",
+            ))
+            .any(|id| wanted.contains(&id)))
+        || (profiles.power_supply && dock_power_supply_in(&under(POWER_SUPPLY_PATH)));
+    if docked {
+        DockState::Docked
+    } else {
+        DockState::Undocked
+    }
+}
+
+/// Emitted with `NotifyDockSuggestion` when the machine is docked or undocked
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct DockSuggestion {
+    pub mode: GfxMode,
+    pub dock: DockState,
+    /// supergfxd is switching to `mode` itself
+    pub applying: bool,
+    /// Why the switch isn't being made automatically, empty if `applying`
+    pub reason: String,
+}
+
+/// A switch is running or waiting, such as for a logout
+pub(crate) fn switch_busy(config: &GfxConfig) -> bool {
+    config.pending_mode.is_some() || config.switch_state == SwitchState::Switching
+}
+
+/// The dock change to act on. While a switch is `busy` a change which has lasted the hold
+/// time is kept until it is done.
+pub(crate) fn take_when_idle(
+    debounce: &mut Debounce<DockState>,
+    busy: bool,
+    now: Instant,
+    user_switches: u64,
+) -> Option<DockState> {
+    if busy {
+        return None;
+    }
+    debounce.take_ready(now, user_switches)
+}
+
+/// Decide what to do now that the machine is in `state`, as `ac_automation` does
+pub(crate) async fn decide_dock(
+    profiles: &DockProfiles,
+    auto_apply: bool,
+    state: DockState,
+    ctx: &AcContext,
+    probe: &dyn AcProbe,
+) -> AcDecision {
+    decide_mode(
+        profiles.profile_for(state).map(|profile| profile.mode),
+        auto_apply,
+        false,
+        ctx,
+        probe,
+    )
+    .await
+}
+
+/// Act on a dock change which has lasted the hold time
+async fn on_dock_transition(
+    ctxt: &SignalEmitter<'static>,
+    profiles: &DockProfiles,
+    auto_apply: bool,
+    state: DockState,
+) -> Result<(), GfxError> {
+    let iface = ctxt
+        .connection()
+        .object_server()
+        .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
+        .await?;
+    let (ctx, probe, inhibits, audit) = {
+        let ctrl = iface.get().await;
+        (
+            ctrl.get_ac_context().await,
+            SystemProbe {
+                dgpu: ctrl.dgpu_arc_clone(),
+            },
+            ctrl.automation_inhibits.clone(),
+            ctrl.audit.clone(),
+        )
+    };
+    let decision = decide_dock(profiles, auto_apply, state, &ctx, &probe).await;
+    let suggestion = match &decision {
+        AcDecision::Nothing => return Ok(()),
+        AcDecision::Suggest { mode, reason } => DockSuggestion {
+            mode: *mode,
+            dock: state,
+            applying: false,
+            reason: reason.clone(),
+        },
+        AcDecision::Apply(mode) => DockSuggestion {
+            mode: *mode,
+            dock: state,
+            applying: true,
+            reason: String::new(),
+        },
+    };
+    let what = if suggestion.applying {
+        format!("dock_profiles {state:?}: switch to {}", suggestion.mode)
+    } else {
+        format!("dock_profiles {state:?}: suggest {}", suggestion.mode)
+    };
+    if skip_if_inhibited(&inhibits, &audit, &what).await {
+        return Ok(());
+    }
+    if suggestion.applying {
+        info!(
+            "Dock automation: {state:?}, switching to {}",
+            suggestion.mode
+        );
+    } else {
+        info!(
+            "Dock automation: {state:?}, suggesting {}: {}",
+            suggestion.mode, suggestion.reason
+        );
+    }
+    emit_counted!(
+        ctxt,
+        Signal::DockSuggestion,
+        notify_dock_suggestion(&suggestion)
+    )
+    .await?;
+    if let AcDecision::Apply(mode) = decision {
+        switch_by_automation(ctxt, &iface, mode, SwitchInitiator::Automation).await?;
+    }
+    Ok(())
+}
+
+/// Watch the dock and act on changes as set in `dock_profiles`
+async fn run_dock_automation(
+    config: Arc<Mutex<GfxConfig>>,
+    user_switches: Arc<AtomicU64>,
+    dock_state: Arc<Mutex<Option<DockState>>>,
+    ctxt: SignalEmitter<'static>,
+) {
+    let mut events = spawn_dock_monitor();
+    let mut debounce = Debounce::new(Duration::ZERO);
+    loop {
+        let (profiles, auto_apply, busy) = {
+            let config = config.lock().await;
+            (
+                config.dock_profiles.clone(),
+                config.ac_automation.auto_apply_when_no_sessions,
+                switch_busy(&config),
+            )
+        };
+        debounce.set_hold(Duration::from_secs(profiles.hold_s));
+        let switches = user_switches.load(Ordering::Acquire);
+        // Nothing is read until a profile is set
+        if profiles.enabled() {
+            let state = dock_state_in(Path::new("/"), &profiles);
+            if dock_state.lock().await.replace(state).is_none() {
+                info!("Dock automation: starting {state:?}");
+            }
+            debounce.observe(state, Instant::now(), switches);
+        } else {
+            *dock_state.lock().await = None;
+        }
+        if let Some(state) = take_when_idle(&mut debounce, busy, Instant::now(), switches) {
+            on_dock_transition(&ctxt, &profiles, auto_apply, state)
+                .await
+                .unwrap_or_else(|err| warn!("Dock automation: {err}"));
+        }
+
+        let wait = debounce
+            .deadline()
+            .map_or(DOCK_POLL, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            })
+            .min(DOCK_POLL);
+        match events.as_mut() {
+            Some(rx) => {
+                if let Ok(None) = timeout(wait, rx.recv()).await {
+                    warn!("Dock automation: udev monitor stopped, polling instead");
+                    events = None;
+                }
+            }
+            None => sleep(wait).await,
+        }
+    }
+}
+
+impl CtrlGraphics {
+    /// Watch for the machine being docked or undocked and suggest or switch modes as set
+    /// in `dock_profiles`. Not started if there is no signal context to notify with.
+    pub fn start_dock_automation(&self) {
+        let ctxt = match self.signal_ctxt.clone() {
+            Some(ctxt) => ctxt,
+            None => return,
+        };
+        let config = self.config.clone();
+        let user_switches = self.user_switches.clone();
+        let dock_state = self.dock_state.clone();
+        self.tasks
+            .spawn("dock automation", RestartPolicy::WithBackoff, move || {
+                run_dock_automation(
+                    config.clone(),
+                    user_switches.clone(),
+                    dock_state.clone(),
+                    ctxt.clone(),
+                )
+            });
+    }
+}
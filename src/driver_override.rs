@@ -0,0 +1,198 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    audit::{Actor, AuditLog},
+    error::GfxError,
+    pci_device::{Device, DiscreetGpu, GfxMode},
+    sandbox::note_write,
+};
+
+/// Where the overrides set by supergfxd are registered. They don't outlast a reboot, so
+/// neither does the registry.
+pub(crate) const DRIVER_OVERRIDES_PATH: &str = "/run/supergfxd/driver_overrides.json";
+
+/// A `driver_override` supergfxd set, and the mode it was set for
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct OverrideRecord {
+    /// The PCI function, such as `0000:01:00.0`
+    pub device: String,
+    /// The `Vendor:Device` id of the function, so a different device at the same address
+    /// isn't cleared
+    pub pci_id: String,
+    pub driver: String,
+    pub mode: GfxMode,
+}
+
+/// The `driver_override`s supergfxd has set. Every override is set through here so it is
+/// cleared once the mode which needed it is left, and is cleared at boot if it is still
+/// registered from before a restart. Overrides set by anything else are never changed.
+#[derive(Debug, Clone)]
+pub(crate) struct DriverOverrides {
+    path: PathBuf,
+}
+
+impl DriverOverrides {
+    pub(crate) fn system() -> Self {
+        Self::at(Path::new(DRIVER_OVERRIDES_PATH))
+    }
+
+    pub(crate) fn at(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// The registered overrides, empty if there are none or the registry can't be read
+    pub(crate) fn registered(&self) -> Vec<OverrideRecord> {
+        fs::read(&self.path)
+            .ok()
+            .and_then(|data| {
+                serde_json::from_slice(&data)
+                    .map_err(|err| warn!("{}: {err}", self.path.display()))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    fn save(&self, records: &[OverrideRecord]) -> Result<(), GfxError> {
+        note_write(&self.path);
+        if records.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(GfxError::from_io(err, self.path.clone()))
+                }
+                _ => Ok(()),
+            };
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|err| GfxError::from_io(err, dir.to_path_buf()))?;
+        }
+        let data = serde_json::to_vec_pretty(records)
+            .map_err(|err| GfxError::NotSupported(format!("driver overrides: {err}")))?;
+        fs::write(&self.path, data).map_err(|err| GfxError::from_io(err, self.path.clone()))
+    }
+
+    /// Set the `driver_override` of `dev` to `driver` for `mode`. It is registered before it
+    /// is written, so it is cleared even if supergfxd stops in between.
+    pub(crate) fn set(&self, dev: &Device, driver: &str, mode: GfxMode) -> Result<(), GfxError> {
+        let mut records = self.registered();
+        records.retain(|r| r.device != dev.name());
+        records.push(OverrideRecord {
+            device: dev.name().to_string(),
+            pci_id: dev.pci_id().to_string(),
+            driver: driver.to_string(),
+            mode,
+        });
+        self.save(&records)?;
+        dev.set_driver_override(Some(driver))?;
+        info!(
+            "driver_override of {} set to {driver} for {mode}",
+            dev.name()
+        );
+        Ok(())
+    }
+
+    /// Clear the registered overrides `stale` picks, of the functions in `devices`. An
+    /// override which is no longer what supergfxd set, changed by someone else since, is
+    /// left as it is, and one of a function which is gone went with it. Returns those
+    /// cleared.
+    pub(crate) fn clear(
+        &self,
+        devices: &[Device],
+        stale: impl Fn(&OverrideRecord) -> bool,
+    ) -> Result<Vec<OverrideRecord>, GfxError> {
+        let records = self.registered();
+        if records.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (cleared, mut kept): (Vec<_>, Vec<_>) = records.into_iter().partition(|r| stale(r));
+        let mut done = Vec::new();
+        let mut failed = None;
+        for record in cleared {
+            let dev = match devices
+                .iter()
+                .find(|dev| dev.name() == record.device && dev.pci_id() == record.pci_id)
+            {
+                Some(dev) => dev,
+                None => {
+                    debug!(
+                        "driver_override of {} went with the function",
+                        record.device
+                    );
+                    continue;
+                }
+            };
+            if dev.driver_override().as_deref() != Some(record.driver.as_str()) {
+                info!(
+                    "driver_override of {} is no longer {}, leaving it",
+                    record.device, record.driver
+                );
+                continue;
+            }
+            match dev.set_driver_override(None) {
+                Ok(()) => {
+                    info!(
+                        "driver_override {} of {} cleared, it was set for {}",
+                        record.driver, record.device, record.mode
+                    );
+                    done.push(record);
+                }
+                Err(err) => {
+                    warn!(
+                        "could not clear driver_override of {}: {err}",
+                        record.device
+                    );
+                    kept.push(record);
+                    failed = Some(err);
+                }
+            }
+        }
+        self.save(&kept)?;
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(done),
+        }
+    }
+
+    /// Clear the overrides set for a mode other than `mode`, after switching to it or at
+    /// boot
+    pub(crate) fn clear_for_mode(
+        &self,
+        devices: &[Device],
+        mode: GfxMode,
+    ) -> Result<Vec<OverrideRecord>, GfxError> {
+        self.clear(devices, |record| record.mode != mode)
+    }
+}
+
+/// Clear the overrides `mode` doesn't need after a switch or at boot, and record those
+/// cleared in the audit log. Failing to is only logged, the override is kept registered so
+/// it is tried again.
+pub(crate) fn clear_stale_overrides(
+    overrides: &DriverOverrides,
+    dgpu: &DiscreetGpu,
+    mode: GfxMode,
+    audit: &AuditLog,
+    actor: &Actor,
+) {
+    match overrides.clear_for_mode(dgpu.devices(), mode) {
+        Ok(cleared) => {
+            for record in cleared {
+                audit.record(
+                    actor,
+                    &format!(
+                        "driver_override {} of {} cleared, it was set for {}",
+                        record.driver, record.device, record.mode
+                    ),
+                );
+            }
+        }
+        Err(err) => warn!("clear_stale_overrides: {err}"),
+    }
+}
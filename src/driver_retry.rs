@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    process::{Command, Output},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{error::GfxError, vfio::ModuleUse, DriverAction};
+
+/// The delay before the second attempt of a driver action, doubled for each one after. With
+/// the default of 5 attempts they are spread over 3 seconds.
+pub(crate) const DRIVER_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+const PROC_MODULES: &str = "/proc/modules";
+
+/// Runs `modprobe` and `rmmod`, so the retries can be tested without them
+pub(crate) trait DriverCommand: Sync {
+    fn run(&self, action: DriverAction, driver: &str) -> std::io::Result<Output>;
+    /// The contents of `/proc/modules`
+    fn proc_modules(&self) -> std::io::Result<String>;
+}
+
+/// `DriverCommand` with the system tools
+pub(crate) struct SystemDriverCommand;
+
+impl DriverCommand for SystemDriverCommand {
+    fn run(&self, action: DriverAction, driver: &str) -> std::io::Result<Output> {
+        Command::new(<&str>::from(action)).arg(driver).output()
+    }
+
+    fn proc_modules(&self) -> std::io::Result<String> {
+        fs::read_to_string(PROC_MODULES)
+    }
+}
+
+/// How the module `name` is used from the contents of `/proc/modules`, `None` if it isn't
+/// listed. A line is `name size refcount holders state address`, with the holders as
+/// `a,b,` or `-` if there are none. Dashes in `name` are read as underscores as the kernel
+/// lists them.
+pub(crate) fn proc_module_use(proc_modules: &str, name: &str) -> Option<ModuleUse> {
+    let name = name.replace('-', "_");
+    proc_modules.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != name {
+            return None;
+        }
+        let refcnt = fields.nth(1).and_then(|count| count.parse().ok());
+        let holders = fields
+            .next()
+            .unwrap_or("-")
+            .split(',')
+            .filter(|holder| !holder.is_empty() && *holder != "-")
+            .map(String::from)
+            .collect();
+        Some(ModuleUse { refcnt, holders })
+    })
+}
+
+/// Do `action` for `driver` with `cmd`, trying up to `attempts` times while it fails for a
+/// reason which may pass, such as the module being in use until nvidia-powerd or the
+/// compositor lets go of the dGPU. The wait starts at `delay` and doubles after each
+/// attempt. The error after the last attempt has the users of the module from
+/// `/proc/modules`.
+pub(crate) async fn driver_action_with_retry(
+    cmd: &dyn DriverCommand,
+    driver: &str,
+    action: DriverAction,
+    attempts: u32,
+    mut delay: Duration,
+) -> Result<(), GfxError> {
+    let attempts = attempts.max(1);
+    let action_name = <&str>::from(action);
+    let mut attempt = 1;
+    loop {
+        let output = cmd
+            .run(action, driver)
+            .map_err(|err| GfxError::Command(format!("{action_name} {driver}"), err))?;
+        if output.status.success() {
+            debug!("Did {action_name} for driver {driver}");
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.ends_with("is not currently loaded\n") {
+            debug!("Driver {driver} was not loaded, skipping {action_name}");
+            return Ok(());
+        }
+        if stderr.ends_with("is builtin.\n") {
+            return Err(GfxError::VfioBuiltin);
+        }
+        if stderr.ends_with("Permission denied\n") {
+            warn!("{action_name} {driver} failed: {stderr:?}");
+            warn!("It may be safe to ignore the above error, run `lsmod |grep {driver}` to confirm modules loaded");
+            return Ok(());
+        }
+        if stderr.contains(&format!("Module {driver} not found")) {
+            return Err(GfxError::MissingModule(driver.into()));
+        }
+        if attempt >= attempts {
+            let mut msg =
+                format!("{action_name} {driver} failed after {attempts} attempts: {stderr:?}");
+            let used = cmd
+                .proc_modules()
+                .ok()
+                .and_then(|modules| proc_module_use(&modules, driver));
+            if let Some(used) = used {
+                let refcnt = used
+                    .refcnt
+                    .map_or_else(|| "unknown".to_string(), |count| count.to_string());
+                let holders = if used.holders.is_empty() {
+                    "none".to_string()
+                } else {
+                    used.holders.join(", ")
+                };
+                msg.push_str(&format!(", refcount {refcnt}, holders {holders}"));
+            }
+            return Err(GfxError::Modprobe(msg));
+        }
+        debug!(
+            "{action_name} {driver} failed (attempt {attempt} of {attempts}), retrying in {delay:?}: {stderr:?}"
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
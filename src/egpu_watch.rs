@@ -0,0 +1,155 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::lock::Mutex;
+use log::{info, warn};
+use tokio::time::{sleep, timeout};
+use zbus::object_server::SignalEmitter;
+
+use crate::{
+    ac_automation::switch_by_automation,
+    config::GfxConfig,
+    controller::{recheck_supported_modes, CtrlGraphics, ProbeCache, SwitchInitiator},
+    error::GfxError,
+    pci_device::GfxMode,
+    power_watch::{spawn_egpu_monitor, EGPU_PCI_VENDORS},
+    signal_counters::{emit_counted, Signal},
+    supervisor::RestartPolicy,
+    sysfs::PCI_BUS_PATH,
+    DBUS_IFACE_PATH,
+};
+
+/// The PCI class of display controllers, as the start of sysfs `class`
+const DISPLAY_CLASS: &str = "0x03";
+/// How often the enclosure is looked for without udev events, and the keep-alive otherwise
+const EGPU_POLL: Duration = Duration::from_secs(5);
+/// How long the PCI events of a connection get to settle before the functions are read
+const EGPU_SETTLE: Duration = Duration::from_millis(500);
+
+/// Whether the PCI function in `dir`, or a bridge upstream of it, is removable. The kernel
+/// marks the functions behind an external facing port, such as Thunderbolt or USB4, so.
+fn behind_removable_port(dir: &Path) -> bool {
+    dir.ancestors()
+        .take_while(|dir| dir.join("vendor").exists())
+        .any(|dir| {
+            fs::read_to_string(dir.join("removable")).map_or(false, |s| s.trim() == "removable")
+        })
+}
+
+/// Whether an Nvidia or AMD GPU is connected behind a removable port, with the sysfs of the
+/// system under `root` which is `/` other than in tests
+pub(crate) fn egpu_present_in(root: &Path) -> bool {
+    let devices = root
+        .join(PCI_BUS_PATH.strip_prefix('/').unwrap_or(PCI_BUS_PATH))
+        .join("devices");
+    let entries = match fs::read_dir(devices) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|e| e.ok()).any(|entry| {
+        let dir: PathBuf = fs::canonicalize(entry.path()).unwrap_or_else(|_| entry.path());
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap_or_default();
+        // sysfs has the vendor as `0x10de`, udev as `10DE`
+        let vendor = read("vendor");
+        let vendor = vendor.trim().trim_start_matches("0x");
+        EGPU_PCI_VENDORS
+            .iter()
+            .any(|wanted| vendor.eq_ignore_ascii_case(wanted))
+            && read("class").trim().starts_with(DISPLAY_CLASS)
+            && behind_removable_port(&dir)
+    })
+}
+
+/// Act on the enclosure being connected or removed: tell frontends, offer or withdraw
+/// AsusEgpu, and leave it for Integrated if the enclosure was removed while in use
+async fn on_egpu_change(ctxt: &SignalEmitter<'static>, present: bool) -> Result<(), GfxError> {
+    let iface = ctxt
+        .connection()
+        .object_server()
+        .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
+        .await?;
+    let (dgpu, config, last_supported, probe_cache) = {
+        let ctrl = iface.get().await;
+        (
+            ctrl.dgpu.clone(),
+            ctrl.config.clone(),
+            ctrl.last_supported.clone(),
+            ctrl.probe_cache.clone(),
+        )
+    };
+    emit_counted!(ctxt, Signal::EgpuPresence, notify_egpu_presence(present)).await?;
+    recheck_supported_modes(&dgpu, &config, &last_supported, &probe_cache, Some(ctxt)).await;
+    if !present && config.lock().await.effective_mode() == GfxMode::AsusEgpu {
+        warn!("eGPU watch: the enclosure was removed in AsusEgpu, switching to Integrated");
+        switch_by_automation(
+            ctxt,
+            &iface,
+            GfxMode::Integrated,
+            SwitchInitiator::Automation,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Watch for an eGPU enclosure being connected or removed while `egpu_detect` is on
+async fn run_egpu_watch(
+    config: Arc<Mutex<GfxConfig>>,
+    probe_cache: Arc<Mutex<ProbeCache>>,
+    ctxt: SignalEmitter<'static>,
+) {
+    let mut events = spawn_egpu_monitor();
+    let mut last = None;
+    loop {
+        // Nothing is read until it is turned on
+        if config.lock().await.egpu_detect {
+            let present = egpu_present_in(Path::new("/"));
+            probe_cache.lock().await.egpu_present = Some(present);
+            match last.replace(present) {
+                None => info!("eGPU watch: starting, enclosure connected: {present}"),
+                Some(was) if was != present => {
+                    info!("eGPU watch: enclosure connected: {present}");
+                    on_egpu_change(&ctxt, present)
+                        .await
+                        .unwrap_or_else(|err| warn!("eGPU watch: {err}"));
+                }
+                Some(_) => {}
+            }
+        } else {
+            last = None;
+        }
+
+        match events.as_mut() {
+            Some(rx) => match timeout(EGPU_POLL, rx.recv()).await {
+                Ok(Some(())) => sleep(EGPU_SETTLE).await,
+                Ok(None) => {
+                    warn!("eGPU watch: udev monitor stopped, polling instead");
+                    events = None;
+                }
+                Err(_) => {}
+            },
+            None => sleep(EGPU_POLL).await,
+        }
+    }
+}
+
+impl CtrlGraphics {
+    /// Watch for an eGPU enclosure being connected or removed, as set with `egpu_detect`.
+    /// Not started if there is no signal context to notify with.
+    pub fn start_egpu_watch(&self) {
+        let ctxt = match self.signal_ctxt.clone() {
+            Some(ctxt) => ctxt,
+            None => return,
+        };
+        let config = self.config.clone();
+        let probe_cache = self.probe_cache.clone();
+        self.tasks
+            .spawn("eGPU watch", RestartPolicy::WithBackoff, move || {
+                run_egpu_watch(config.clone(), probe_cache.clone(), ctxt.clone())
+            });
+    }
+}
@@ -1,7 +1,13 @@
 use std::fmt;
 use std::{error, path::PathBuf};
 
-use crate::actions::StagedAction;
+use crate::{
+    actions::StagedAction,
+    instance::InstanceInfo,
+    pci_device::{GfxMode, HotplugType},
+    validate::InputClass,
+    DBUS_DEST_NAME,
+};
 
 #[derive(Debug)]
 pub enum GfxError {
@@ -26,12 +32,190 @@ pub enum GfxError {
     ZbusFdo(zbus::fdo::Error),
     /// `IncorrectActionOrder(this_action, last_action)`
     IncorrectActionOrder(StagedAction, StagedAction),
+    NoSwitchPending,
+    SwitchCommitted,
+    /// `cancel_requester_only` is set and the caller didn't ask for the switch, with who did
+    CancelNotAllowed(String),
+    /// The dGPU is gone from the PCI bus while its driver is still loaded
+    DgpuFellOffBus,
+    /// The administrator has locked the mode to this one
+    ModeLocked(GfxMode),
+    /// The daemon is a `--debug-run` without `--debug-allow-mutation`
+    DebugMode,
+    /// The daemon is stopping and takes no more changes
+    ShuttingDown,
+    /// Another supergfxd holds the instance lock, with what it wrote in it if it could be read
+    AlreadyRunning(Option<InstanceInfo>),
+    /// The dbus name is owned by another process, with its pid if it could be found
+    DbusNameTaken(Option<u32>),
+    /// The dbus interface couldn't be served, or a call to it failed, with why
+    DbusInterface(String),
+    /// The dGPU functions did not all come back, or kept changing, after a PCI rescan
+    PciNotSettled,
+    /// A vendor toggle is missing, was refused by an interlock, or didn't take the value
+    SpecialToggle(String),
+    /// `disabled_actions` in the config names an action which can't be removed
+    DisabledActions(String),
+    /// Some of the dGPU functions are missing, so a Vfio modprobe conf would only pass part
+    /// of the GPU to vfio-pci
+    VfioIncomplete(String),
+    /// With `strict_verify` an action didn't take effect,
+    /// `PostCondition(action, expected, observed)`
+    PostCondition(StagedAction, String, String),
+    /// Processes in `never_kill` are using the dGPU, so the kill step stopped the switch.
+    /// `ProtectedGpuUsers(protected, others)`, the others with how they were classed.
+    ProtectedGpuUsers(Vec<String>, Vec<String>),
+    /// The `hotplug_type` can't be used on this machine, with why
+    HotplugUnusable(HotplugType, String),
+    /// Importing the settings of another tool failed, or there was nothing to import
+    Import(String),
+    /// The graphical sessions were still open when `logout_timeout_s` passed, and
+    /// `logout_timeout_action` dropped the switch. Says which sessions were open.
+    LogoutTimeout(String),
+    /// An automation inhibition couldn't be added or removed, with why
+    InvalidInhibition(String),
+    /// The ordering drop-in for `supergfxd.service` couldn't be written or removed, with why
+    UnitDropin(String),
+    /// `acpi_dgpu_off` is malformed, can't be used on this machine, or the call failed
+    AcpiCall(String),
+    /// The change was made but the config couldn't be written, so it only lasts until
+    /// supergfxd restarts. With the write error.
+    ConfigNotPersisted(String),
+    /// `dock_profiles` is malformed, with why
+    DockProfiles(String),
+    /// `schedule` is malformed or ambiguous, with why
+    Schedule(String),
+    /// `modprobe_extra_options` names an unknown mode or has a malformed entry, with why
+    ModprobeExtraOptions(String),
+    /// `display_manager_units` is empty or has a malformed unit name, with why
+    DisplayManagerUnits(String),
+    /// The mode needs the iGPU, which wasn't found, such as when it's turned off in the BIOS
+    NoIgpu(GfxMode),
+    /// `supergfxctl --cleanup` couldn't run or left files it should have removed, with why
+    Cleanup(String),
+    /// A pre or post-switch hook failed or timed out, with why
+    Hook(String),
+    /// A string from a client or the config isn't valid for what it is used as, with why
+    InvalidInput(InputClass, String),
+    /// A device node of the nvidia driver couldn't be made, with why
+    NvidiaNodes(String),
+    /// A file read back after it was written differs from what was written, so it wasn't
+    /// put in place. With the path.
+    VerifyFailed(String),
+    /// Unbinding or removing a PCI function failed, with what is known of why
+    DeviceBusy(Box<BusyDevice>),
+    /// A switch to this mode has started changing the system and must finish first
+    SwitchInProgress(GfxMode),
+    /// The dGPU is in Vfio with its vfio nodes held open, by a VM, with the processes
+    VfioInUse(Vec<String>),
+    /// logind said the system is about to sleep, and it hasn't woken yet
+    SleepImminent,
 }
 
+/// A PCI function which couldn't be unbound or removed. With the driver bound to it, its
+/// runtime status, the tasks in uninterruptible sleep on it and the last kernel log lines
+/// which mention it, as far as they could be found, and the error itself.
+#[derive(Debug)]
+pub struct BusyDevice {
+    pub action: String,
+    pub device: String,
+    pub driver: Option<String>,
+    pub runtime_status: Option<String>,
+    pub suspects: Vec<String>,
+    pub kmsg_tail: Vec<String>,
+    pub error: String,
+}
+
+/// What the daemon starts the text of its error replies with
+pub const DAEMON_ERROR_PREFIX: &str = "GFX fail: ";
+
 impl GfxError {
     pub fn from_io(error: std::io::Error, detail: PathBuf) -> Self {
         Self::Io(detail, error)
     }
+
+    /// A name for the kind of error which doesn't change between releases, sent with the
+    /// error over dbus so clients needn't match the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            GfxError::ParseVendor => "parse-vendor",
+            GfxError::ParseMode => "parse-mode",
+            GfxError::DgpuNotFound => "dgpu-not-found",
+            GfxError::Udev(..) => "udev",
+            GfxError::SystemdUnitAction(_) => "systemd-unit",
+            GfxError::SystemdUnitWaitTimeout(_) => "systemd-unit-timeout",
+            GfxError::AsusGpuMuxModeDiscreet => "mux-discreet",
+            GfxError::VfioBuiltin => "vfio-builtin",
+            GfxError::VfioDisabled => "vfio-disabled",
+            GfxError::MissingModule(_) => "missing-module",
+            GfxError::Modprobe(_) => "modprobe",
+            GfxError::Command(..) => "command",
+            GfxError::Path(..) | GfxError::Read(..) | GfxError::Write(..) | GfxError::Io(..) => {
+                "io"
+            }
+            GfxError::NotSupported(_) => "not-supported",
+            GfxError::Zbus(_) | GfxError::ZbusFdo(_) => "dbus",
+            GfxError::IncorrectActionOrder(..) => "action-order",
+            GfxError::NoSwitchPending => "no-switch-pending",
+            GfxError::SwitchCommitted => "switch-committed",
+            GfxError::CancelNotAllowed(_) => "cancel-not-allowed",
+            GfxError::DgpuFellOffBus => "dgpu-off-bus",
+            GfxError::ModeLocked(_) => "mode-locked",
+            GfxError::DebugMode => "debug-mode",
+            GfxError::ShuttingDown => "shutting-down",
+            GfxError::AlreadyRunning(_) => "already-running",
+            GfxError::DbusNameTaken(_) => "dbus-name-taken",
+            GfxError::DbusInterface(_) => "dbus-interface",
+            GfxError::PciNotSettled => "pci-not-settled",
+            GfxError::SpecialToggle(_) => "special-toggle",
+            GfxError::DisabledActions(_) => "disabled-actions",
+            GfxError::VfioIncomplete(_) => "vfio-incomplete",
+            GfxError::PostCondition(..) => "post-condition",
+            GfxError::ProtectedGpuUsers(..) => "protected-gpu-users",
+            GfxError::HotplugUnusable(..) => "hotplug-unusable",
+            GfxError::Import(_) => "import",
+            GfxError::LogoutTimeout(_) => "logout-timeout",
+            GfxError::InvalidInhibition(_) => "invalid-inhibition",
+            GfxError::UnitDropin(_) => "unit-dropin",
+            GfxError::AcpiCall(_) => "acpi-call",
+            GfxError::ConfigNotPersisted(_) => "config-not-persisted",
+            GfxError::DockProfiles(_) => "dock-profiles",
+            GfxError::Schedule(_) => "schedule",
+            GfxError::ModprobeExtraOptions(_) => "modprobe-extra-options",
+            GfxError::DisplayManagerUnits(_) => "display-manager-units",
+            GfxError::NoIgpu(_) => "no-igpu",
+            GfxError::Cleanup(_) => "cleanup",
+            GfxError::Hook(_) => "hook",
+            GfxError::InvalidInput(..) => "invalid-input",
+            GfxError::NvidiaNodes(_) => "nvidia-nodes",
+            GfxError::VerifyFailed(_) => "verify-failed",
+            GfxError::DeviceBusy(_) => "device-busy",
+            GfxError::SwitchInProgress(_) => "switch-in-progress",
+            GfxError::VfioInUse(_) => "vfio-in-use",
+            GfxError::SleepImminent => "sleep-imminent",
+        }
+    }
+
+    /// The text of the dbus error reply, the code in brackets then the message, such as
+    /// `GFX fail: [mode-locked] The graphics mode is locked to Hybrid by the administrator`
+    pub fn daemon_message(&self) -> String {
+        format!("{DAEMON_ERROR_PREFIX}[{}] {self}", self.code())
+    }
+}
+
+/// The code and message of an error reply made with `GfxError::daemon_message`. The reply
+/// of an older daemon has no code, and other text is given back as the message.
+pub fn parse_daemon_message(text: &str) -> (Option<&str>, &str) {
+    let text = text.strip_prefix(DAEMON_ERROR_PREFIX).unwrap_or(text);
+    if let Some((code, message)) = text
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+    {
+        if !code.is_empty() && code.chars().all(|c| c.is_ascii_lowercase() || c == '-') {
+            return (Some(code), message);
+        }
+    }
+    (None, text)
 }
 
 impl fmt::Display for GfxError {
@@ -86,6 +270,153 @@ impl fmt::Display for GfxError {
                 f,
                 "The order of actions is incorrect: {last_action:?} should not be before {this_action:?}"
             ),
+            GfxError::NoSwitchPending => write!(f, "There is no mode switch in progress"),
+            GfxError::DgpuFellOffBus => write!(
+                f,
+                "The dGPU dropped off the bus, only Integrated can be used until it is back. Try RescanDgpu first, otherwise a reboot (or suspend cycle) is likely required"
+            ),
+            GfxError::PciNotSettled => write!(
+                f,
+                "The dGPU did not settle on the PCI bus after a rescan, another tool may be removing or rescanning PCI devices"
+            ),
+            GfxError::SpecialToggle(detail) => write!(f, "{detail}"),
+            GfxError::DisabledActions(detail) => write!(f, "disabled_actions: {detail}"),
+            GfxError::VfioIncomplete(detail) => write!(
+                f,
+                "Not writing the vfio modprobe conf as the dGPU functions are incomplete: {detail}"
+            ),
+            GfxError::PostCondition(action, expected, observed) => write!(
+                f,
+                "{action:?} did not take effect: expected {expected}, found {observed}"
+            ),
+            GfxError::ProtectedGpuUsers(protected, others) => {
+                write!(
+                    f,
+                    "Processes in never_kill are using the dGPU, nothing was killed: {}",
+                    protected.join(", ")
+                )?;
+                if !others.is_empty() {
+                    write!(f, ". Also using it: {}", others.join(", "))?;
+                }
+                Ok(())
+            }
+            GfxError::HotplugUnusable(hotplug_type, reason) => {
+                write!(f, "hotplug_type {hotplug_type:?} can't be used: {reason}")
+            }
+            GfxError::Import(detail) => write!(f, "Import: {detail}"),
+            GfxError::LogoutTimeout(detail) => write!(f, "{detail}"),
+            GfxError::InvalidInhibition(detail) => {
+                write!(f, "Automation inhibition: {detail}")
+            }
+            GfxError::UnitDropin(detail) => write!(f, "Unit drop-in: {detail}"),
+            GfxError::AcpiCall(detail) => write!(f, "ACPI call: {detail}"),
+            GfxError::DockProfiles(detail) => write!(f, "dock_profiles: {detail}"),
+            GfxError::Schedule(detail) => write!(f, "schedule: {detail}"),
+            GfxError::ModprobeExtraOptions(detail) => {
+                write!(f, "modprobe_extra_options: {detail}")
+            }
+            GfxError::DisplayManagerUnits(detail) => write!(f, "display_manager_units: {detail}"),
+            GfxError::ConfigNotPersisted(detail) => write!(
+                f,
+                "Applied but not saved, it is lost when supergfxd restarts: {detail}"
+            ),
+            GfxError::DebugMode => write!(
+                f,
+                "supergfxd is a debug run, changes to the system are disabled. Start it with --debug-allow-mutation to allow them"
+            ),
+            GfxError::AlreadyRunning(Some(holder)) => write!(
+                f,
+                "Another supergfxd (pid {}) is already running{}, not starting",
+                holder.pid,
+                if holder.switching {
+                    " and switching modes"
+                } else {
+                    ""
+                }
+            ),
+            GfxError::AlreadyRunning(None) => {
+                write!(f, "Another supergfxd is already running, not starting")
+            }
+            GfxError::DbusNameTaken(Some(pid)) => write!(
+                f,
+                "The dbus name {DBUS_DEST_NAME} is already owned by pid {pid}, not starting"
+            ),
+            GfxError::DbusNameTaken(None) => write!(
+                f,
+                "The dbus name {DBUS_DEST_NAME} is already owned, not starting"
+            ),
+            GfxError::DbusInterface(detail) => {
+                write!(f, "The dbus interface isn't usable, not starting: {detail}")
+            }
+            GfxError::ShuttingDown => write!(
+                f,
+                "supergfxd is shutting down, try again once it has restarted"
+            ),
+            GfxError::ModeLocked(mode) => write!(
+                f,
+                "The graphics mode is locked to {mode} by the administrator"
+            ),
+            GfxError::CancelNotAllowed(requester) => write!(
+                f,
+                "The mode switch was requested by {requester}, only the same user or root can cancel it"
+            ),
+            GfxError::SwitchCommitted => write!(
+                f,
+                "The mode switch has already started changing the system and can not be cancelled"
+            ),
+            GfxError::NoIgpu(mode) => write!(
+                f,
+                "{mode} needs the iGPU, which wasn't detected and may be turned off in the BIOS. The dGPU is the only GPU and can't be turned off"
+            ),
+            GfxError::Cleanup(detail) => write!(f, "Cleanup: {detail}"),
+            GfxError::Hook(detail) => write!(f, "Switch hook: {detail}"),
+            GfxError::InvalidInput(class, detail) => write!(f, "Invalid {class}: {detail}"),
+            GfxError::NvidiaNodes(detail) => write!(f, "nvidia device nodes: {detail}"),
+            GfxError::VerifyFailed(path) => write!(
+                f,
+                "{path} read back different from what was written, it was left as it was. Is the filesystem full?"
+            ),
+            GfxError::DeviceBusy(busy) => {
+                let BusyDevice {
+                    action,
+                    device,
+                    driver,
+                    runtime_status,
+                    suspects,
+                    kmsg_tail,
+                    error,
+                } = busy.as_ref();
+                write!(f, "Could not {action} {device}: {error}. ")?;
+                match driver {
+                    Some(driver) => write!(f, "Bound to {driver}")?,
+                    None => write!(f, "No driver bound")?,
+                }
+                if let Some(status) = runtime_status {
+                    write!(f, ", runtime status {status}")?;
+                }
+                if suspects.is_empty() {
+                    write!(f, ". No task is stuck on it")?;
+                } else {
+                    write!(f, ". Stuck on it: {}", suspects.join(", "))?;
+                }
+                if !kmsg_tail.is_empty() {
+                    write!(f, ". Kernel log: {}", kmsg_tail.join(" | "))?;
+                }
+                Ok(())
+            }
+            GfxError::SwitchInProgress(mode) => write!(
+                f,
+                "A switch to {mode} is changing the system, wait for it to finish"
+            ),
+            GfxError::VfioInUse(users) => write!(
+                f,
+                "The dGPU is passed through to a VM, stop it first. Held by: {}",
+                users.join(", ")
+            ),
+            GfxError::SleepImminent => write!(
+                f,
+                "The system is about to sleep, switch once it has woken"
+            ),
         }
     }
 }
@@ -0,0 +1,280 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxVendor},
+    vfio::driver_name,
+    PROC_PATH,
+};
+
+const DEV_PATH: &str = "/dev";
+const PASSWD_PATH: &str = "/etc/passwd";
+/// Services which hold the dGPU open for as long as they run. A switch stops them itself.
+const GPU_SERVICES: &[&str] = &["nvidia-persistenced", "nvidia-powerd"];
+
+/// A process with a dGPU device node open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GpuUser {
+    pub pid: u32,
+    /// The process name from `/proc/<pid>/comm`
+    pub comm: String,
+    /// The executable from `/proc/<pid>/exe`, `None` if it can't be read
+    pub exe: Option<PathBuf>,
+}
+
+impl fmt::Display for GpuUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.comm, self.pid)
+    }
+}
+
+/// The device nodes under `dev_root` for the PCI functions at `dev_paths`: their DRM card
+/// and render nodes, and for Nvidia every `nvidia*` node as those aren't tied to one device
+pub(crate) fn device_nodes_in(
+    dev_root: &Path,
+    dev_paths: &[PathBuf],
+    nvidia: bool,
+) -> Vec<PathBuf> {
+    let mut nodes = Vec::new();
+    for dev_path in dev_paths {
+        if let Ok(entries) = fs::read_dir(dev_path.join("drm")) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with("card") || name.starts_with("renderD") {
+                    nodes.push(dev_root.join("dri").join(name));
+                }
+            }
+        }
+    }
+    if nvidia {
+        if let Ok(entries) = fs::read_dir(dev_root) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if entry.file_name().to_string_lossy().starts_with("nvidia") {
+                    nodes.push(entry.path());
+                }
+            }
+        }
+    }
+    nodes.sort();
+    nodes
+}
+
+/// The vfio device nodes under `dev_root` for those of the PCI functions at `dev_paths`
+/// bound to vfio-pci: the node of their IOMMU group, and their iommufd node where the kernel
+/// has one. A VM given the dGPU holds one of them open.
+pub(crate) fn vfio_nodes_in(dev_root: &Path, dev_paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut nodes = Vec::new();
+    for dev_path in dev_paths {
+        if driver_name(dev_path).as_deref() != Some("vfio-pci") {
+            continue;
+        }
+        if let Some(group) = fs::read_link(dev_path.join("iommu_group"))
+            .ok()
+            .and_then(|group| group.file_name().map(|name| name.to_owned()))
+        {
+            nodes.push(dev_root.join("vfio").join(group));
+        }
+        if let Ok(entries) = fs::read_dir(dev_path.join("vfio-dev")) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                nodes.push(dev_root.join("vfio/devices").join(entry.file_name()));
+            }
+        }
+    }
+    nodes.sort();
+    nodes.dedup();
+    nodes
+}
+
+/// The first of `nodes` the process at `pid_dir` has open in an fd, or else mapped into its
+/// memory as a driver can still be held through a mapping after the fd was closed
+fn held_node(pid_dir: &Path, nodes: &[PathBuf]) -> Option<PathBuf> {
+    let open = fs::read_dir(pid_dir.join("fd")).ok().and_then(|fds| {
+        fds.filter_map(|fd| fd.ok())
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .find(|target| nodes.contains(target))
+    });
+    open.or_else(|| {
+        // `address perms offset dev inode path`
+        fs::read_to_string(pid_dir.join("maps"))
+            .ok()?
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(5))
+            .map(PathBuf::from)
+            .find(|path| nodes.contains(path))
+    })
+}
+
+/// The processes under `proc_root` holding any of `nodes`, with their pid, directory and the
+/// node held, sorted by pid. Processes which can't be read are left out.
+fn holders_in(proc_root: &Path, nodes: &[PathBuf]) -> Vec<(u32, PathBuf, PathBuf)> {
+    if nodes.is_empty() {
+        return Vec::new();
+    }
+    let entries = match fs::read_dir(proc_root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut holders: Vec<(u32, PathBuf, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_string_lossy().parse().ok()?;
+            let node = held_node(&entry.path(), nodes)?;
+            Some((pid, entry.path(), node))
+        })
+        .collect();
+    holders.sort_by_key(|(pid, _, _)| *pid);
+    holders
+}
+
+/// The process name from `comm` of the process at `pid_dir`
+fn read_comm(pid_dir: &Path) -> String {
+    fs::read_to_string(pid_dir.join("comm"))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// The processes under `proc_root` with any of `nodes` open or mapped, sorted by pid.
+/// Processes which can't be read, and the services in `GPU_SERVICES`, are left out.
+pub(crate) fn gpu_users_in(proc_root: &Path, nodes: &[PathBuf]) -> Vec<GpuUser> {
+    holders_in(proc_root, nodes)
+        .into_iter()
+        .filter_map(|(pid, dir, _)| {
+            let comm = read_comm(&dir);
+            if GPU_SERVICES.contains(&comm.as_str()) {
+                return None;
+            }
+            let exe = fs::read_link(dir.join("exe")).ok().map(|exe| {
+                // Replaced or removed since it started
+                let exe = exe.to_string_lossy();
+                PathBuf::from(exe.trim_end_matches(" (deleted)"))
+            });
+            Some(GpuUser { pid, comm, exe })
+        })
+        .collect()
+}
+
+/// The device nodes of the dGPU
+fn dgpu_nodes(device: &DiscreetGpu) -> Vec<PathBuf> {
+    let dev_paths: Vec<PathBuf> = device
+        .managed_devices()
+        .map(|dev| dev.dev_path().clone())
+        .collect();
+    device_nodes_in(
+        Path::new(DEV_PATH),
+        &dev_paths,
+        device.vendor() == GfxVendor::Nvidia,
+    )
+}
+
+/// The processes using the dGPU
+pub(crate) fn dgpu_users(device: &DiscreetGpu) -> Vec<GpuUser> {
+    gpu_users_in(Path::new(PROC_PATH), &dgpu_nodes(device))
+}
+
+/// The processes with the vfio nodes of the dGPU open, such as a VM it is passed through to
+pub(crate) fn vfio_users(device: &DiscreetGpu) -> Vec<GpuUser> {
+    let dev_paths: Vec<PathBuf> = device
+        .managed_devices()
+        .map(|dev| dev.dev_path().clone())
+        .collect();
+    gpu_users_in(
+        Path::new(PROC_PATH),
+        &vfio_nodes_in(Path::new(DEV_PATH), &dev_paths),
+    )
+}
+
+/// A process holding a dGPU device node, which keeps its driver from being unloaded
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct BlockingProcess {
+    pub pid: u32,
+    /// The process name from `/proc/<pid>/comm`
+    pub comm: String,
+    /// The device node it has open or mapped, such as `/dev/nvidia0`
+    pub path: String,
+}
+
+impl fmt::Display for BlockingProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}) on {}", self.comm, self.pid, self.path)
+    }
+}
+
+/// The processes under `proc_root` holding any of `nodes`, sorted by pid. Unlike
+/// `gpu_users_in` the GPU services are included, as they block an unload just the same.
+pub(crate) fn blocking_processes_in(proc_root: &Path, nodes: &[PathBuf]) -> Vec<BlockingProcess> {
+    holders_in(proc_root, nodes)
+        .into_iter()
+        .map(|(pid, dir, node)| BlockingProcess {
+            pid,
+            comm: read_comm(&dir),
+            path: node.to_string_lossy().to_string(),
+        })
+        .collect()
+}
+
+/// The processes holding the dGPU device nodes
+pub(crate) fn blocking_processes(device: &DiscreetGpu) -> Vec<BlockingProcess> {
+    blocking_processes_in(Path::new(PROC_PATH), &dgpu_nodes(device))
+}
+
+/// `err` from unloading the dGPU driver with the processes which still hold it, when it failed
+/// as the module was in use. Other errors are returned as they are.
+pub(crate) fn with_blocking_processes(err: GfxError, processes: &[BlockingProcess]) -> GfxError {
+    match err {
+        GfxError::Modprobe(detail) if !processes.is_empty() => {
+            let held: Vec<String> = processes.iter().map(|p| p.to_string()).collect();
+            GfxError::Modprobe(format!("{detail}, held open by: {}", held.join(", ")))
+        }
+        err => err,
+    }
+}
+
+/// `blocking_processes` as a table for `supergfxctl --blockers`
+pub fn render_blocking_processes(processes: &[BlockingProcess]) -> String {
+    if processes.is_empty() {
+        return "Nothing has the dGPU open\n".to_string();
+    }
+    let mut out = format!("{:>8}  {:<16}  {}\n", "PID", "Process", "Device");
+    for process in processes {
+        out += &format!(
+            "{:>8}  {:<16}  {}\n",
+            process.pid, process.comm, process.path
+        );
+    }
+    out
+}
+
+/// The name of the user running process `pid` under `proc_root`, from its real uid and the
+/// `passwd` file. The uid if it has no name, empty if the process can't be read.
+pub(crate) fn process_user_in(proc_root: &Path, passwd: &Path, pid: u32) -> String {
+    let status =
+        fs::read_to_string(proc_root.join(pid.to_string()).join("status")).unwrap_or_default();
+    let uid = match status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().next())
+    {
+        Some(uid) => uid.to_string(),
+        None => return String::new(),
+    };
+    fs::read_to_string(passwd)
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            (fields.nth(1)? == uid).then(|| name.to_string())
+        })
+        .unwrap_or(uid)
+}
+
+/// The user running process `pid`
+pub(crate) fn process_user(pid: u32) -> String {
+    process_user_in(Path::new(PROC_PATH), Path::new(PASSWD_PATH), pid)
+}
@@ -0,0 +1,100 @@
+use log::warn;
+
+use crate::{
+    error::GfxError,
+    pci_device::{DiscreetGpu, HotplugType},
+    sysfs::{Sysfs, ASUS_DGPU_DISABLE_PATH},
+};
+
+/// Whether a `hotplug_type` can be used on this machine
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum HotplugCheck {
+    Usable,
+    /// It can't be used, and why
+    Unusable(String),
+    /// The probe failed, it may or may not work
+    Unknown(String),
+}
+
+/// The hardware `check_hotplug_type` looks at, so it can be tested
+pub(crate) trait HotplugProbe {
+    /// ASUS `dgpu_disable` exists
+    fn asus_dgpu_disable(&self) -> Result<bool, String>;
+    /// A power file was found for the slot of the dGPU
+    fn slot_power(&self) -> Result<bool, String>;
+}
+
+/// `HotplugProbe` on the running system
+pub(crate) struct SystemHotplugProbe<'a> {
+    pub dgpu: &'a DiscreetGpu,
+}
+
+impl HotplugProbe for SystemHotplugProbe<'_> {
+    fn asus_dgpu_disable(&self) -> Result<bool, String> {
+        Sysfs::system().dgpu_disable.0.try_exists()
+    }
+
+    fn slot_power(&self) -> Result<bool, String> {
+        if !self.dgpu.devices().iter().any(|dev| dev.is_dgpu()) {
+            return Err("the dGPU wasn't found".to_string());
+        }
+        Ok(self.dgpu.hotplug_capable())
+    }
+}
+
+/// Check `hotplug_type` can be used with what `probe` finds
+pub(crate) fn check_hotplug_type(
+    hotplug_type: HotplugType,
+    probe: &dyn HotplugProbe,
+) -> HotplugCheck {
+    let (found, missing) = match hotplug_type {
+        HotplugType::None => return HotplugCheck::Usable,
+        HotplugType::Asus => (
+            probe.asus_dgpu_disable(),
+            format!("{ASUS_DGPU_DISABLE_PATH} doesn't exist, this isn't an ASUS laptop with dgpu_disable"),
+        ),
+        HotplugType::Std => (
+            probe.slot_power(),
+            "the dGPU isn't in a PCI slot with a power control file".to_string(),
+        ),
+    };
+    match found {
+        Ok(true) => HotplugCheck::Usable,
+        Ok(false) => HotplugCheck::Unusable(missing),
+        Err(err) => HotplugCheck::Unknown(err),
+    }
+}
+
+/// Check a `hotplug_type` asked for over dbus. Refused if it can't be used, allowed if the
+/// probe failed.
+pub(crate) fn check_requested_hotplug_type(
+    hotplug_type: HotplugType,
+    probe: &dyn HotplugProbe,
+) -> Result<(), GfxError> {
+    match check_hotplug_type(hotplug_type, probe) {
+        HotplugCheck::Unusable(reason) => Err(GfxError::HotplugUnusable(hotplug_type, reason)),
+        HotplugCheck::Unknown(err) => {
+            warn!("Could not check hotplug_type {hotplug_type:?} can be used: {err}");
+            Ok(())
+        }
+        HotplugCheck::Usable => Ok(()),
+    }
+}
+
+/// The reason to use `HotplugType::None` in place of the `hotplug_type` loaded from the
+/// config, `None` if it can be used or the probe failed
+pub(crate) fn loaded_hotplug_downgrade(
+    hotplug_type: HotplugType,
+    probe: &dyn HotplugProbe,
+) -> Option<String> {
+    match check_hotplug_type(hotplug_type, probe) {
+        HotplugCheck::Unusable(reason) => Some(format!(
+            "hotplug_type {hotplug_type:?} can't be used, using None: {reason}"
+        )),
+        HotplugCheck::Unknown(err) => {
+            warn!("Could not check hotplug_type {hotplug_type:?} can be used, keeping it: {err}");
+            None
+        }
+        HotplugCheck::Usable => None,
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::lock::Mutex;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+use zbus::{object_server::SignalEmitter, zvariant::Type, Connection};
+
+use crate::{
+    error::GfxError,
+    signal_counters::{emit_counted, Signal},
+};
+
+/// How long a switch waits for blocking inhibitors to be released, the same as the default
+/// `logout_timeout_s`
+const INHIBITOR_WAIT_TIMEOUT: Duration = Duration::from_secs(180);
+const INHIBITOR_POLL: Duration = Duration::from_millis(500);
+/// The grace given to delay inhibitors, the logind default for `InhibitDelayMaxSec`
+const INHIBITOR_DELAY_GRACE: Duration = Duration::from_secs(5);
+
+/// Inhibitor classes that mean a critical section, such as a firmware flash or a package
+/// transaction. `idle` and the `handle-*` keys are held by desktops for screen blanking and
+/// are not a reason to hold off a switch.
+const CRITICAL_CLASSES: &[&str] = &["shutdown", "sleep"];
+
+/// Inhibitor owners which are part of the desktop session. They hold locks for the whole
+/// session, which ends with the display manager anyway.
+const SESSION_MANAGERS: &[&str] = &[
+    "gnome shell",
+    "gnome-session",
+    "gnome-settings-daemon",
+    "org.gnome.settingsdaemon.power",
+    "ksmserver",
+    "kde power management system",
+    "powerdevil",
+    "plasmashell",
+    "xfce4-session",
+    "xfce4-power-manager",
+    "xfce power manager",
+    "cinnamon-session",
+    "mate-session",
+    "mate-power-manager",
+    "lxqt-session",
+    "supergfxd",
+];
+
+/// One lock from logind `ListInhibitors`, signature `(ssssuu)`
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct Inhibitor {
+    /// Colon separated classes, e.g `shutdown:sleep`
+    pub what: String,
+    /// The program holding the lock, e.g `fwupd`
+    pub who: String,
+    /// e.g `Firmware update`
+    pub why: String,
+    /// `block` or `delay`
+    pub mode: String,
+    pub uid: u32,
+    pub pid: u32,
+}
+
+/// What an inhibitor means for a switch which is about to stop the display manager
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum InhibitorEffect {
+    /// Not relevant to a switch
+    Ignore,
+    /// Give it the standard delay grace then go ahead
+    Delay,
+    /// Wait until it is released
+    Block,
+}
+
+impl Inhibitor {
+    /// The decision table:
+    ///
+    /// | who             | what has shutdown or sleep | mode  | effect |
+    /// |-----------------|----------------------------|-------|--------|
+    /// | session manager | any                        | any   | Ignore |
+    /// | other           | no                         | any   | Ignore |
+    /// | other           | yes                        | block | Block  |
+    /// | other           | yes                        | delay | Delay  |
+    /// | other           | yes                        | other | Ignore |
+    pub fn effect(&self) -> InhibitorEffect {
+        let who = self.who.to_lowercase();
+        if SESSION_MANAGERS.contains(&who.as_str()) {
+            return InhibitorEffect::Ignore;
+        }
+        if !self
+            .what
+            .split(':')
+            .any(|class| CRITICAL_CLASSES.contains(&class))
+        {
+            return InhibitorEffect::Ignore;
+        }
+        match self.mode.as_str() {
+            "block" => InhibitorEffect::Block,
+            "delay" => InhibitorEffect::Delay,
+            _ => InhibitorEffect::Ignore,
+        }
+    }
+
+    /// e.g `fwupd (Firmware update)`
+    pub fn describe(&self) -> String {
+        if self.why.is_empty() {
+            self.who.clone()
+        } else {
+            format!("{} ({})", self.who, self.why)
+        }
+    }
+}
+
+/// The owners of the blocking inhibitors, and if any delay inhibitor is held
+pub(crate) fn blocking_inhibitors(inhibitors: &[Inhibitor]) -> (Vec<String>, bool) {
+    let mut blockers = Vec::new();
+    let mut delay = false;
+    for inhibitor in inhibitors {
+        match inhibitor.effect() {
+            InhibitorEffect::Block => {
+                let owner = inhibitor.describe();
+                if !blockers.contains(&owner) {
+                    blockers.push(owner);
+                }
+            }
+            InhibitorEffect::Delay => delay = true,
+            InhibitorEffect::Ignore => {}
+        }
+    }
+    (blockers, delay)
+}
+
+async fn list_inhibitors(connection: &Connection) -> Result<Vec<Inhibitor>, GfxError> {
+    let reply = connection
+        .call_method(
+            Some("org.freedesktop.login1"),
+            "/org/freedesktop/login1",
+            Some("org.freedesktop.login1.Manager"),
+            "ListInhibitors",
+            &(),
+        )
+        .await?;
+    Ok(reply.body().deserialize()?)
+}
+
+async fn set_waiting_for(
+    waiting_for: &Mutex<Vec<String>>,
+    owners: Vec<String>,
+    signal_ctxt: Option<&SignalEmitter<'static>>,
+) {
+    let mut current = waiting_for.lock().await;
+    if *current == owners {
+        return;
+    }
+    if owners.is_empty() {
+        info!("wait_inhibitors: no longer waiting");
+    } else {
+        info!("wait_inhibitors: waiting for: {}", owners.join(", "));
+    }
+    if let Some(ctxt) = signal_ctxt {
+        emit_counted!(ctxt, Signal::SwitchWaiting, notify_switch_waiting(&owners))
+            .await
+            .unwrap_or_else(|err| warn!("wait_inhibitors: {err}"));
+    }
+    *current = owners;
+}
+
+/// Wait until no other program holds a blocking shutdown or sleep inhibitor, then give any
+/// delay inhibitors their grace. The owners being waited for are kept in `waiting_for`.
+/// Returns early if `loop_exit` is set. If logind can't be asked the switch goes ahead.
+pub(crate) async fn wait_inhibitors(
+    loop_exit: Arc<AtomicBool>,
+    waiting_for: &Mutex<Vec<String>>,
+    signal_ctxt: Option<&SignalEmitter<'static>>,
+) -> Result<(), GfxError> {
+    loop_exit.store(false, Ordering::Release);
+    let connection = Connection::system().await?;
+    let start = Instant::now();
+
+    let mut delay = false;
+    while !loop_exit.load(Ordering::Acquire) {
+        let inhibitors = match list_inhibitors(&connection).await {
+            Ok(inhibitors) => inhibitors,
+            Err(err) => {
+                warn!("wait_inhibitors: could not list inhibitors, continuing: {err}");
+                break;
+            }
+        };
+        let (blockers, has_delay) = blocking_inhibitors(&inhibitors);
+        delay = has_delay;
+        if blockers.is_empty() {
+            break;
+        }
+        if start.elapsed() > INHIBITOR_WAIT_TIMEOUT {
+            let detail = format!(
+                "Inhibitor locks still held after {} seconds by: {}",
+                INHIBITOR_WAIT_TIMEOUT.as_secs(),
+                blockers.join(", ")
+            );
+            set_waiting_for(waiting_for, Vec::new(), signal_ctxt).await;
+            warn!("wait_inhibitors: {detail}");
+            return Err(GfxError::SystemdUnitWaitTimeout(detail));
+        }
+        set_waiting_for(waiting_for, blockers, signal_ctxt).await;
+        sleep(INHIBITOR_POLL).await;
+    }
+    set_waiting_for(waiting_for, Vec::new(), signal_ctxt).await;
+
+    if delay && !loop_exit.load(Ordering::Acquire) {
+        debug!(
+            "wait_inhibitors: delay inhibitors held, waiting {}s",
+            INHIBITOR_DELAY_GRACE.as_secs()
+        );
+        sleep(INHIBITOR_DELAY_GRACE).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,476 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use futures_util::lock::Mutex;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use zbus::object_server::SignalEmitter;
+
+use crate::{
+    sandbox::note_write,
+    signal_counters::{emit_counted, Signal},
+    MODPROBE_PATH, STATE_DIR,
+};
+
+/// The advisory is kept here so it outlasts a restart of the daemon
+const ADVISORY_NAME: &str = "initramfs_advisory.json";
+/// Read in this order, later settings override earlier ones. Directories are read for
+/// `*.conf` files.
+const DRACUT_CONFS: &[&str] = &[
+    "usr/lib/dracut/dracut.conf.d",
+    "etc/dracut.conf",
+    "etc/dracut.conf.d",
+];
+const MKINITCPIO_CONFS: &[&str] = &["etc/mkinitcpio.conf", "etc/mkinitcpio.conf.d"];
+/// Where the mkinitcpio images are built
+const BOOT_PATH: &str = "/boot";
+/// The modprobe conf directives which change what modprobe does. Anything else in the conf,
+/// such as comments, doesn't matter to an initramfs.
+const MODPROBE_DIRECTIVES: &[&str] = &[
+    "alias",
+    "blacklist",
+    "install",
+    "options",
+    "remove",
+    "softdep",
+];
+
+/// A tool which builds the initramfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) enum InitramfsTool {
+    Dracut,
+    Mkinitcpio,
+}
+
+impl InitramfsTool {
+    /// What the user runs to regenerate the initramfs. supergfxd never runs it itself.
+    pub(crate) fn regenerate_command(self) -> &'static str {
+        match self {
+            Self::Dracut => "dracut -f",
+            Self::Mkinitcpio => "mkinitcpio -P",
+        }
+    }
+}
+
+/// The initramfs tool in use under `root`, if the initramfs it builds has a copy of
+/// `/etc/modprobe.d`. Both can be installed, mkinitcpio is taken to be the one in use if it
+/// has presets.
+pub(crate) fn detect_in(root: &Path) -> Option<InitramfsTool> {
+    let dracut = root.join("usr/bin/dracut").exists();
+    let mkinitcpio = root.join("usr/bin/mkinitcpio").exists();
+    let presets = fs::read_dir(root.join("etc/mkinitcpio.d")).map_or(false, |entries| {
+        entries
+            .filter_map(|e| e.ok())
+            .any(|e| e.path().extension().map_or(false, |ext| ext == "preset"))
+    });
+    let (tool, embeds) = if mkinitcpio && (presets || !dracut) {
+        let confs = read_confs(root, MKINITCPIO_CONFS);
+        (
+            InitramfsTool::Mkinitcpio,
+            mkinitcpio_embeds_modprobe(&confs),
+        )
+    } else if dracut {
+        let confs = read_confs(root, DRACUT_CONFS);
+        (InitramfsTool::Dracut, dracut_embeds_modprobe(&confs))
+    } else {
+        return None;
+    };
+    debug!("initramfs: built by {tool:?}, has /etc/modprobe.d: {embeds}");
+    embeds.then_some(tool)
+}
+
+/// The contents of the conf files at `paths` under `root`, a directory is read for its
+/// `*.conf` files in name order
+fn read_confs(root: &Path, paths: &[&str]) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = root.join(path);
+        if path.is_dir() {
+            let mut confs: Vec<PathBuf> = fs::read_dir(&path)
+                .map(|entries| {
+                    entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| e.path())
+                        .filter(|p| p.extension().map_or(false, |ext| ext == "conf"))
+                        .collect()
+                })
+                .unwrap_or_default();
+            confs.sort();
+            files.extend(confs);
+        } else {
+            files.push(path);
+        }
+    }
+    files
+        .iter()
+        .filter_map(|file| fs::read_to_string(file).ok())
+        .collect()
+}
+
+/// The `key=value` or `key+=value` settings of a shell style conf, with the quotes taken
+/// off the value, as `(key, appended, value)`
+fn conf_settings(conf: &str) -> Vec<(&str, bool, &str)> {
+    conf.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.starts_with('#') {
+                return None;
+            }
+            let (key, value) = line.split_once('=')?;
+            let (key, appended) = match key.strip_suffix('+') {
+                Some(key) => (key, true),
+                None => (key, false),
+            };
+            Some((key.trim(), appended, value.trim().trim_matches(['"', '\''])))
+        })
+        .collect()
+}
+
+/// Whether dracut, with `confs` in the order it reads them, puts `/etc/modprobe.d` in the
+/// initramfs. Its kernel-modules module does in hostonly mode, which most distros set, or
+/// a conf can add it to `install_items`.
+pub(crate) fn dracut_embeds_modprobe(confs: &[String]) -> bool {
+    let mut hostonly = false;
+    let mut omitted = false;
+    let mut installed = false;
+    for (key, appended, value) in confs.iter().flat_map(|conf| conf_settings(conf)) {
+        match key {
+            "hostonly" => hostonly = value == "yes",
+            "omit_dracutmodules" => {
+                let omits = value.split_whitespace().any(|m| m == "kernel-modules");
+                omitted = omits || (appended && omitted);
+            }
+            "install_items" => {
+                let installs = value
+                    .split_whitespace()
+                    .any(|item| item.starts_with("/etc/modprobe.d"));
+                installed = installs || (appended && installed);
+            }
+            _ => {}
+        }
+    }
+    installed || (hostonly && !omitted)
+}
+
+/// Whether mkinitcpio, with `confs` in the order it reads them, puts `/etc/modprobe.d` in
+/// the initramfs. The `modconf` hook does, the last `HOOKS` set is used.
+pub(crate) fn mkinitcpio_embeds_modprobe(confs: &[String]) -> bool {
+    let mut hooks = Vec::new();
+    for conf in confs {
+        let mut lines = conf.lines();
+        while let Some(line) = lines.next() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let mut value = match line.strip_prefix("HOOKS=") {
+                Some(value) => value.to_string(),
+                None => continue,
+            };
+            // An array may be split over several lines
+            if value.starts_with('(') {
+                while !value.contains(')') {
+                    match lines.next() {
+                        Some(next) => {
+                            value.push(' ');
+                            value.push_str(next.split('#').next().unwrap_or_default());
+                        }
+                        None => break,
+                    }
+                }
+            }
+            hooks = value
+                .split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '"' | '\''))
+                .filter(|hook| !hook.is_empty())
+                .map(|hook| hook.to_string())
+                .collect();
+        }
+    }
+    hooks.iter().any(|hook| hook == "modconf")
+}
+
+/// The directives modprobe acts on in a modprobe conf, in order
+pub(crate) fn modprobe_directives(conf: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(conf)
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| {
+            line.split(' ')
+                .next()
+                .map_or(false, |word| MODPROBE_DIRECTIVES.contains(&word))
+        })
+        .collect()
+}
+
+/// Whether changing a modprobe conf from `before` to `after` changes what modprobe does in
+/// an initramfs with a copy of it. `None` is no file.
+pub(crate) fn affects_early_boot(before: Option<&[u8]>, after: Option<&[u8]>) -> bool {
+    modprobe_directives(before.unwrap_or_default())
+        != modprobe_directives(after.unwrap_or_default())
+}
+
+/// Whether `path`, relative to the root of the initramfs such as
+/// `etc/modprobe.d/supergfxd.conf`, is in a listing from `lsinitrd` (`ls -l` style) or
+/// `lsinitcpio -l` (a path per line)
+pub(crate) fn listing_contains(listing: &str, path: &str) -> bool {
+    listing.lines().any(|line| {
+        line.split_whitespace()
+            .last()
+            .map_or(false, |last| last.trim_start_matches("./") == path)
+    })
+}
+
+/// The newest mkinitcpio image in `dir`, leaving out the fallback images
+pub(crate) fn newest_image_in(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.starts_with("initramfs-") && name.ends_with(".img") && !name.contains("fallback")
+        })
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .max()
+        .map(|(_, path)| path)
+}
+
+/// The command is in the `PATH`
+fn command_available(name: &str) -> bool {
+    let path = env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/usr/sbin".into());
+    env::split_paths(&path).any(|dir| dir.join(name).is_file())
+}
+
+fn run(cmd: &str, args: &[&str]) -> Option<Vec<u8>> {
+    let out = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| debug!("initramfs: {cmd}: {e}"))
+        .ok()?;
+    if !out.status.success() {
+        debug!(
+            "initramfs: {cmd} failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        return None;
+    }
+    Some(out.stdout)
+}
+
+/// The copy of `MODPROBE_PATH` in the initramfs built by `tool`, `Some(None)` if it has no
+/// copy. `None` if the initramfs can't be read, the tools to list it aren't installed.
+fn initramfs_copy(tool: InitramfsTool) -> Option<Option<Vec<u8>>> {
+    let conf = MODPROBE_PATH.trim_start_matches('/');
+    match tool {
+        InitramfsTool::Dracut => {
+            if !command_available("lsinitrd") {
+                return None;
+            }
+            // The image of the running kernel
+            let listing = run("lsinitrd", &[])?;
+            if !listing_contains(&String::from_utf8_lossy(&listing), conf) {
+                return Some(None);
+            }
+            run("lsinitrd", &["-f", conf]).map(Some)
+        }
+        InitramfsTool::Mkinitcpio => {
+            if !command_available("lsinitcpio") {
+                return None;
+            }
+            let image = newest_image_in(Path::new(BOOT_PATH))?;
+            let image = image.to_string_lossy();
+            let listing = run("lsinitcpio", &["-l", &image])?;
+            if !listing_contains(&String::from_utf8_lossy(&listing), conf) {
+                return Some(None);
+            }
+            // lsinitcpio can't print a file, bsdtar which it is built on can
+            if !command_available("bsdtar") {
+                return None;
+            }
+            run("bsdtar", &["-xOf", &image, conf]).map(Some)
+        }
+    }
+}
+
+/// A reminder to regenerate the initramfs, as a switch changed the modprobe conf it has a
+/// copy of and the next boot would use the old one
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct InitramfsAdvisory {
+    pub tool: InitramfsTool,
+    /// Seconds since the epoch when the conf was changed
+    pub since: u64,
+}
+
+impl InitramfsAdvisory {
+    pub(crate) fn message(&self) -> String {
+        format!(
+            "initramfs regeneration recommended ({})",
+            self.tool.regenerate_command()
+        )
+    }
+}
+
+/// Tracks the initramfs advisory from the switch which raised it until the initramfs has
+/// the current conf, or the user dismisses it
+#[derive(Debug, Default)]
+pub(crate) struct InitramfsWatch {
+    /// Where the advisory is kept, `None` to keep it in memory only
+    path: Option<PathBuf>,
+    /// The tool building an initramfs with a copy of `/etc/modprobe.d`
+    tool: Option<InitramfsTool>,
+    advisory: Option<InitramfsAdvisory>,
+}
+
+impl InitramfsWatch {
+    /// Watch nothing, as for a debug run
+    pub(crate) fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Watch the initramfs of the running system, with the advisory in `STATE_DIR`
+    pub(crate) fn system() -> Self {
+        Self::new(
+            Path::new(STATE_DIR).join(ADVISORY_NAME),
+            detect_in(Path::new("/")),
+        )
+    }
+
+    /// Load an advisory kept at `path`
+    pub(crate) fn new(path: PathBuf, tool: Option<InitramfsTool>) -> Self {
+        let advisory = fs::read_to_string(&path)
+            .ok()
+            .and_then(|buf| serde_json::from_str(&buf).ok());
+        Self {
+            path: Some(path),
+            tool,
+            advisory,
+        }
+    }
+
+    pub(crate) fn tool(&self) -> Option<InitramfsTool> {
+        self.tool
+    }
+
+    pub(crate) fn advisory(&self) -> Option<&InitramfsAdvisory> {
+        self.advisory.as_ref()
+    }
+
+    /// The command to regenerate the initramfs with if changing the modprobe conf from
+    /// `before` to `after` would call for it
+    pub(crate) fn regenerate_for(
+        &self,
+        before: Option<&[u8]>,
+        after: Option<&[u8]>,
+    ) -> Option<&'static str> {
+        let tool = self.tool?;
+        affects_early_boot(before, after).then(|| tool.regenerate_command())
+    }
+
+    /// The modprobe conf was changed from `before` to `after` at `now`, seconds since the
+    /// epoch. Returns `true` if that raised the advisory.
+    pub(crate) fn conf_changed(
+        &mut self,
+        before: Option<&[u8]>,
+        after: Option<&[u8]>,
+        now: u64,
+    ) -> bool {
+        let tool = match self.tool {
+            Some(tool) => tool,
+            None => return false,
+        };
+        if self.advisory.is_some() || !affects_early_boot(before, after) {
+            return false;
+        }
+        self.advisory = Some(InitramfsAdvisory { tool, since: now });
+        self.save();
+        true
+    }
+
+    /// Drop the advisory, returns `false` if there was none
+    pub(crate) fn clear(&mut self) -> bool {
+        if self.advisory.take().is_none() {
+            return false;
+        }
+        self.save();
+        true
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        note_write(path);
+        let res = match &self.advisory {
+            Some(advisory) => path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(path, serde_json::to_string(advisory).unwrap_or_default())),
+            None => fs::remove_file(path).or_else(|err| {
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(err)
+                }
+            }),
+        };
+        res.unwrap_or_else(|err| warn!("initramfs: {}: {err}", path.display()));
+    }
+}
+
+/// Whether the initramfs of `tool` still has a different copy of the conf, `None` if it
+/// can't be read. Runs the listing tools, so is kept off the executor.
+async fn initramfs_stale(tool: InitramfsTool) -> Option<bool> {
+    tokio::task::spawn_blocking(move || {
+        let copy = initramfs_copy(tool)?;
+        let live = fs::read(MODPROBE_PATH).ok();
+        Some(affects_early_boot(copy.as_deref(), live.as_deref()))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Clear the advisory if the initramfs now has the current conf. An initramfs which can't
+/// be read leaves it until it is dismissed.
+pub(crate) async fn refresh_advisory(watch: &Mutex<InitramfsWatch>) {
+    let tool = match watch.lock().await.advisory() {
+        Some(advisory) => advisory.tool,
+        None => return,
+    };
+    if initramfs_stale(tool).await == Some(false) && watch.lock().await.clear() {
+        info!("initramfs: regenerated with the current {MODPROBE_PATH}");
+    }
+}
+
+/// Raise the advisory if writing the modprobe conf over `before` changed what the
+/// initramfs copy of it does, emitting `NotifyInitramfsAdvisory`
+pub(crate) async fn modprobe_conf_written(
+    watch: &Mutex<InitramfsWatch>,
+    before: Option<Vec<u8>>,
+    ctxt: Option<&SignalEmitter<'static>>,
+) {
+    let after = fs::read(MODPROBE_PATH).ok();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let message = {
+        let mut watch = watch.lock().await;
+        if !watch.conf_changed(before.as_deref(), after.as_deref(), now) {
+            return;
+        }
+        watch.advisory().map(|advisory| advisory.message())
+    };
+    if let Some(message) = message {
+        warn!("{MODPROBE_PATH} changed, {message}");
+        if let Some(ctxt) = ctxt {
+            emit_counted!(
+                ctxt,
+                Signal::InitramfsAdvisory,
+                notify_initramfs_advisory(&message)
+            )
+            .await
+            .unwrap_or_else(|err| warn!("initramfs: {err}"));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::time::timeout;
+use zbus::{
+    fdo::{DBusProxy, RequestNameFlags, RequestNameReply},
+    names::BusName,
+    proxy::CacheProperties,
+    Connection,
+};
+
+use crate::{
+    controller::CtrlGraphics, error::GfxError, sandbox::note_write, zbus_proxy::DaemonProxy,
+    DBUS_DEST_NAME, DBUS_IFACE_PATH,
+};
+
+/// Held with `flock(LOCK_EX)` by the running supergfxd, so that a second one exits before it
+/// touches anything
+pub const INSTANCE_LOCK_PATH: &str = "/run/supergfxd/instance.lock";
+/// How long the call of `check_interface` gets to return
+pub const INTERFACE_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the instance holding the lock wrote in it, shown by one which can't take it
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct InstanceInfo {
+    pub pid: u32,
+    /// A mode switch is running. The holder doesn't let go of the lock or the dbus name until
+    /// it has finished or parked the switch, see `CtrlGraphics::begin_shutdown`.
+    pub switching: bool,
+}
+
+impl InstanceInfo {
+    fn render(&self) -> String {
+        format!("pid={}\nswitching={}\n", self.pid, self.switching)
+    }
+
+    pub(crate) fn parse(content: &str) -> Option<Self> {
+        let mut pid = None;
+        let mut switching = false;
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("pid", value)) => pid = value.trim().parse().ok(),
+                Some(("switching", value)) => switching = value.trim() == "true",
+                _ => {}
+            }
+        }
+        Some(Self {
+            pid: pid?,
+            switching,
+        })
+    }
+}
+
+/// The lock of the running instance, released when dropped or the process exits
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Take the lock at `path`. Fails with `GfxError::AlreadyRunning` if another live
+    /// instance holds it.
+    pub fn acquire_at(path: &Path) -> Result<Self, GfxError> {
+        note_write(path);
+        let mut file = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .read(true)
+                    .write(true)
+                    .open(path)
+            })
+            .map_err(|err| GfxError::from_io(err, path.into()))?;
+
+        // SAFETY: the fd is valid for the life of `file`
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(GfxError::from_io(err, path.into()));
+            }
+            let mut content = String::new();
+            file.read_to_string(&mut content).ok();
+            return Err(GfxError::AlreadyRunning(InstanceInfo::parse(&content)));
+        }
+
+        let lock = Self {
+            file,
+            path: path.into(),
+        };
+        lock.write_info(false);
+        info!("Holding the instance lock {}", path.display());
+        Ok(lock)
+    }
+
+    /// Record whether a mode switch is running, for an instance which fails to start
+    pub fn set_switching(&self, switching: bool) {
+        self.write_info(switching);
+    }
+
+    fn write_info(&self, switching: bool) {
+        let info = InstanceInfo {
+            pid: std::process::id(),
+            switching,
+        };
+        let mut file = &self.file;
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(info.render().as_bytes()))
+            .unwrap_or_else(|err| {
+                warn!(
+                    "InstanceLock: could not write {}: {err}",
+                    self.path.display()
+                )
+            });
+    }
+}
+
+/// Take the dbus name. It is requested without queueing and without allowing replacement,
+/// so no other instance can take it from this one. Fails with `GfxError::DbusNameTaken` if
+/// it is already owned.
+pub async fn request_daemon_name(connection: &Connection) -> Result<(), GfxError> {
+    match connection
+        .request_name_with_flags(DBUS_DEST_NAME, RequestNameFlags::DoNotQueue.into())
+        .await
+    {
+        Ok(RequestNameReply::PrimaryOwner) | Ok(RequestNameReply::AlreadyOwner) => Ok(()),
+        Ok(_) | Err(zbus::Error::NameTaken) => {
+            Err(GfxError::DbusNameTaken(name_owner_pid(connection).await))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The pid of the process owning the daemon name, if it can be found
+async fn name_owner_pid(connection: &Connection) -> Option<u32> {
+    let dbus = DBusProxy::new(connection).await.ok()?;
+    let owner = dbus
+        .get_name_owner(DBUS_DEST_NAME.try_into().ok()?)
+        .await
+        .ok()?;
+    dbus.get_connection_unix_process_id(BusName::from(owner))
+        .await
+        .ok()
+}
+
+/// Serve `ctrl` at `DBUS_IFACE_PATH`. Fails with `GfxError::DbusInterface` if the object
+/// server refuses it, or already serves the interface there.
+pub async fn serve_interface(connection: &Connection, ctrl: CtrlGraphics) -> Result<(), GfxError> {
+    match connection.object_server().at(DBUS_IFACE_PATH, ctrl).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(GfxError::DbusInterface(format!(
+            "{DBUS_DEST_NAME} is already served at {DBUS_IFACE_PATH}"
+        ))),
+        Err(err) => Err(GfxError::DbusInterface(format!(
+            "{DBUS_IFACE_PATH} could not be served: {err}"
+        ))),
+    }
+}
+
+/// Call `Version` on the interface through `connection`, to make sure clients can reach it
+/// before supergfxd says it is ready. The daemon calls its own unique name as `destination`,
+/// so the call goes through the bus and back. `None` keeps the daemon name, for a peer to
+/// peer connection. Returns the version.
+pub async fn check_interface(
+    connection: &Connection,
+    destination: Option<BusName<'_>>,
+) -> Result<String, GfxError> {
+    let unreachable = |detail: String| {
+        GfxError::DbusInterface(format!("{DBUS_IFACE_PATH} can't be reached: {detail}"))
+    };
+    let mut builder = DaemonProxy::builder(connection).cache_properties(CacheProperties::No);
+    if let Some(destination) = destination {
+        builder = builder.destination(destination)?;
+    }
+    let proxy = builder.build().await?;
+    match timeout(INTERFACE_CHECK_TIMEOUT, proxy.version()).await {
+        Ok(Ok(version)) => Ok(version),
+        Ok(Err(err)) => Err(unreachable(err.to_string())),
+        Err(_) => Err(unreachable(format!(
+            "no reply within {}s",
+            INTERFACE_CHECK_TIMEOUT.as_secs()
+        ))),
+    }
+}
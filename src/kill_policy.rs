@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::{
+    config::GfxConfig,
+    error::GfxError,
+    gpu_users::{dgpu_users, GpuUser},
+    pci_device::DiscreetGpu,
+};
+
+/// How the kill step of a switch treats a process using the dGPU
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum KillClass {
+    /// Listed in `never_kill`, the switch fails rather than kill it
+    NeverKill,
+    /// Listed in `kill_without_prompt`, always killed
+    KillWithoutPrompt,
+    /// In neither list, killed if the action kills unlisted processes
+    Default,
+}
+
+impl KillClass {
+    /// The name used in logs and errors, the config key for the listed ones
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::NeverKill => "never_kill",
+            Self::KillWithoutPrompt => "kill_without_prompt",
+            Self::Default => "default",
+        }
+    }
+}
+
+/// The `never_kill` and `kill_without_prompt` lists of the config
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KillPolicy {
+    pub never_kill: Vec<String>,
+    pub kill_without_prompt: Vec<String>,
+}
+
+impl KillPolicy {
+    pub fn from_config(config: &GfxConfig) -> Self {
+        Self {
+            never_kill: config.never_kill.clone(),
+            kill_without_prompt: config.kill_without_prompt.clone(),
+        }
+    }
+
+    /// The class of `user`. `never_kill` wins if it is in both lists.
+    pub(crate) fn classify(&self, user: &GpuUser) -> KillClass {
+        let listed = |list: &[String]| list.iter().any(|entry| entry_matches(entry, user));
+        if listed(&self.never_kill) {
+            KillClass::NeverKill
+        } else if listed(&self.kill_without_prompt) {
+            KillClass::KillWithoutPrompt
+        } else {
+            KillClass::Default
+        }
+    }
+}
+
+/// An absolute path `entry` matches the executable of `user` exactly. Any other matches its
+/// `comm` or the file name of its executable exactly, never part of a name.
+pub(crate) fn entry_matches(entry: &str, user: &GpuUser) -> bool {
+    let entry = entry.trim();
+    if entry.is_empty() {
+        return false;
+    }
+    let exe = user.exe.as_deref();
+    if entry.starts_with('/') {
+        return exe == Some(Path::new(entry));
+    }
+    user.comm == entry
+        || exe
+            .and_then(|exe| exe.file_name())
+            .map_or(false, |name| name == entry)
+}
+
+/// What the kill step does with one process
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct KillDecision {
+    pub user: GpuUser,
+    pub class: KillClass,
+    pub kill: bool,
+}
+
+/// Classify each of `users` and decide which to kill, logging every decision. Unlisted
+/// processes are killed if `kill_default` is set. Fails with `GfxError::ProtectedGpuUsers`
+/// before anything is killed if any is in `never_kill`.
+pub(crate) fn decide_kills(
+    policy: &KillPolicy,
+    users: &[GpuUser],
+    kill_default: bool,
+) -> Result<Vec<KillDecision>, GfxError> {
+    let decisions: Vec<KillDecision> = users
+        .iter()
+        .map(|user| {
+            let class = policy.classify(user);
+            let kill = match class {
+                KillClass::NeverKill => false,
+                KillClass::KillWithoutPrompt => true,
+                KillClass::Default => kill_default,
+            };
+            info!(
+                "kill step: {user} is {}, {}",
+                class.name(),
+                if kill { "to be killed" } else { "left running" }
+            );
+            KillDecision {
+                user: user.clone(),
+                class,
+                kill,
+            }
+        })
+        .collect();
+
+    if decisions.iter().any(|d| d.class == KillClass::NeverKill) {
+        let (protected, others): (Vec<_>, Vec<_>) = decisions
+            .iter()
+            .partition(|d| d.class == KillClass::NeverKill);
+        return Err(GfxError::ProtectedGpuUsers(
+            protected.iter().map(|d| d.user.to_string()).collect(),
+            others
+                .iter()
+                .map(|d| format!("{} ({})", d.user, d.class.name()))
+                .collect(),
+        ));
+    }
+    Ok(decisions)
+}
+
+/// Kill the processes using the dGPU as `policy` decides, see `decide_kills`
+pub(crate) fn kill_gpu_users(
+    policy: &KillPolicy,
+    device: &DiscreetGpu,
+    kill_default: bool,
+) -> Result<(), GfxError> {
+    for decision in decide_kills(policy, &dgpu_users(device), kill_default)? {
+        if !decision.kill {
+            continue;
+        }
+        // SAFETY: kill takes no pointers
+        if unsafe { libc::kill(decision.user.pid as libc::pid_t, libc::SIGKILL) } != 0 {
+            warn!(
+                "kill step: killing {} failed: {}",
+                decision.user,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
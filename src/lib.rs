@@ -6,10 +6,15 @@ use std::{
     str::FromStr,
 };
 
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
 use pci_device::GfxVendor;
 
-use crate::{error::GfxError, pci_device::GfxMode, special_asus::*};
+use crate::{
+    driver_retry::{driver_action_with_retry, SystemDriverCommand, DRIVER_RETRY_DELAY},
+    error::GfxError,
+    pci_device::GfxMode,
+    special_asus::*,
+};
 
 /// The configuration for graphics. This should be saved and loaded on boot.
 pub mod config;
@@ -21,6 +26,8 @@ pub mod error;
 /// Special-case functions for check/read/write of key functions on unique laptops
 /// such as the G-Sync mode available on some ASUS ROG laptops
 pub mod special_asus;
+/// Data-driven sysfs toggles for other vendors, such as the Lenovo Legion G-Sync switch
+pub mod special_vendor;
 
 /// Defined DBUS Interface for supergfxctl
 pub mod zbus_iface;
@@ -29,6 +36,14 @@ pub mod zbus_proxy;
 
 /// System interface helpers.
 pub mod pci_device;
+/// PCIe link power state of the dGPU
+pub mod pci_link;
+/// Serialising PCI remove and rescan with other tools
+pub mod pci_lock;
+/// Event driven watching of the dGPU power status
+mod power_watch;
+/// Loading, binding and unloading the vfio modules
+mod vfio;
 
 /// Systemd helpers
 pub mod systemd;
@@ -36,13 +51,155 @@ pub mod systemd;
 /// The actual actions that supergfx uses for each step
 pub mod actions;
 
+/// Support bundles for bug reports
+pub mod bundle;
+
+/// Version, commit and compiled in paths of a build
+pub mod build_info;
+
+/// A switch round-trip to check supergfxd works on this machine
+pub mod self_test;
+
+/// Inhibitor locks held by other programs which a switch waits for
+pub mod inhibitors;
+
+/// Panic-safe wrappers for spawned tasks
+pub mod supervisor;
+
+/// Suggesting or switching modes when AC is plugged in or unplugged
+pub mod ac_automation;
+
+/// Shell completion and client side checks for supergfxctl
+pub mod completions;
+
+/// Finding the processes which have the dGPU open
+pub mod gpu_users;
+
+/// The record of who changed the mode or config, and when
+pub mod audit;
+
+/// Rendering the generated files for the likely next mode ahead of a switch
+pub mod staging;
+
+/// Keeping switcheroo-control's discrete GPU launch option in line with the mode
+pub mod switcheroo;
+
+/// Checking the applied mode hasn't drifted, and putting it back
+mod verify;
+
+/// Finding what keeps the dGPU awake on battery
+pub mod power_blockers;
+
+/// What the boot did differently, kept until the user dismisses it
+pub mod attention;
+/// Reminding to regenerate an initramfs which has a copy of the modprobe conf
+mod initramfs;
+
+/// The commands run before and after a switch
+mod switch_hooks;
+/// Planning a mode switch, and carrying the plan out
+mod switch_plan;
+
+/// Waiting for the graphical sessions to end before a switch, and switching once the
+/// session of a user who confirmed a logout ends
+pub mod logout_switch;
+
+/// Bounded queues for everything the daemon keeps while it runs, and a report of them
+pub mod buffers;
+
+/// Stopping the daemon without leaving a switch half done
+pub mod shutdown;
+
+/// The environment variables to run an app on the dGPU, and running it with them
+pub mod prime_env;
+
+/// Telling systemd the daemon is ready or stopping
+pub mod systemd_notify;
+
+/// Making sure only one supergfxd runs at a time, and that it can be reached over dbus
+pub mod instance;
+
+/// Which processes using the dGPU a switch kills
+pub mod kill_policy;
+
+/// Advising to leave AsusMuxDgpu when the dGPU runs hot on battery
+pub mod thermal;
+
+/// Checking the configured hotplug type can be used on this machine
+mod hotplug_check;
+
+/// Importing the settings of envycontrol or optimus-manager
+pub mod migrate;
+
+/// Powering the dGPU off through acpi_call on ASUS laptops without `dgpu_disable`
+pub mod acpi_dgpu;
+/// Clients stopping supergfxd from doing anything by itself for a while
+pub mod automation_inhibit;
+/// Running the boot tasks when a graphical session is already running
+pub mod boot_context;
+/// Finding and removing everything supergfxd wrote, for a clean uninstall
+pub mod cleanup;
+/// How supergfxctl reports a failed command, with an exit code for each kind of failure
+pub mod cli_error;
+/// The `--json` output of supergfxctl, the queries asked for as one object
+pub mod cli_json;
+/// What a config change would do to the files supergfxd generates
+pub mod config_preview;
+/// What keeps a PCI function busy when unbinding or removing it fails
+mod device_busy;
+/// Checking the display came back after a switch, with help on the consoles if not
+pub mod display_watchdog;
+/// Suggesting or switching modes when the machine is docked or undocked
+pub mod dock_automation;
+/// Driver overrides set by supergfxd, cleared when no longer wanted
+pub mod driver_override;
+/// Loading and unloading the GPU drivers, retried while the module is in use
+mod driver_retry;
+/// Noticing an eGPU enclosure being connected or removed
+pub mod egpu_watch;
+/// Checking the nvidia driver made device nodes apps can open, and making them if not
+mod nvidia_nodes;
+/// What each power status means for each dGPU vendor
+pub mod power_semantics;
+/// Putting runtime PM back after a driver re-probe reset it
+pub mod runtime_pm_guard;
+/// The sandbox profile generated from what each operation writes, and the drift check of it
+pub mod sandbox;
+/// Suggesting or switching modes at the times of day set in the config
+pub mod schedule;
+/// Counting the signals emitted, so a client can tell if it missed any
+pub mod signal_counters;
+/// Which signal categories each client asked for, and sending the signals to them
+pub mod signal_filter;
+/// Whether a switch would be made now, and what keeps it from being made
+pub mod switch_readiness;
+/// Typed sysfs attributes, with whether each exists cached
+pub mod sysfs;
+/// What kind of machine supergfxd runs on, a desktop or a laptop
+pub mod system_class;
+/// Running a query which may block, giving up on it after a while
+pub mod timeout;
+/// The systemd drop-in ordering supergfxd against the other GPU services installed
+pub mod unit_dropins;
+/// Strict checks of the strings which come from clients or the config
+pub mod validate;
+/// Writing a file which must not be left cut short, such as the modprobe conf
+mod verified_write;
+
 #[cfg(test)]
 mod tests;
 
 /// Helper to expose the current crate version to external code
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
-/// Generic path that is used to save the daemon config state
-pub const CONFIG_PATH: &str = "/etc/supergfxd.conf";
+/// The directory for the config and other files packagers or users may add
+pub const CONFIG_DIR: &str = "/etc/supergfxd";
+/// Generic path that is used to save the daemon config state. Preferred over
+/// `CONFIG_PATH_LEGACY` when it exists.
+pub const CONFIG_PATH: &str = "/etc/supergfxd/config.json";
+/// The config file used before `CONFIG_DIR`. Migrated to `CONFIG_PATH` on first write.
+pub const CONFIG_PATH_LEGACY: &str = "/etc/supergfxd.conf";
+/// The directory for state generated at runtime, such as history or stats
+pub const STATE_DIR: &str = "/var/lib/supergfxd";
 /// Destination name to be used in the daemon when setting up DBUS connection
 pub const DBUS_DEST_NAME: &str = "org.supergfxctl.Daemon";
 /// Generic icd-profile (vulkan)
@@ -51,12 +208,21 @@ pub const CONFIG_NVIDIA_VKICD: &str = "/usr/share/vulkan/icd.d/nvidia_icd.json";
 pub const DBUS_IFACE_PATH: &str = "/org/supergfxctl/Gfx";
 
 pub const KERNEL_CMDLINE: &str = "/proc/cmdline";
+/// Where the kernel lists the processes, read to find who uses the dGPU
+pub(crate) const PROC_PATH: &str = "/proc";
 
 const SLOTS: &str = "/sys/bus/pci/slots";
 
-const NOUVEAU_DRIVERS: [&str; 1] = ["nouveau"];
+const NVIDIA_DRIVERS: [&str; 5] = [
+    "nvidia_drm",
+    "nvidia_modeset",
+    "nvidia_uvm",
+    "nvidia",
+    "nvidia_wmi_ec_backlight",
+];
 
-const NVIDIA_DRIVERS: [&str; 5] = ["nvidia_drm", "nvidia_modeset", "nvidia_uvm", "nvidia", "nvidia_wmi_ec_backlight"];
+/// The open source driver for Nvidia GPUs
+const NOUVEAU_DRIVER: &str = "nouveau";
 
 const VFIO_DRIVERS: [&str; 6] = [
     "vfio_pci",
@@ -69,7 +235,11 @@ const VFIO_DRIVERS: [&str; 6] = [
 
 const DISPLAY_MANAGER: &str = "display-manager.service";
 
+const NVIDIA_MODULE_PATH: &str = "/sys/module/nvidia";
+
 const MODPROBE_PATH: &str = "/etc/modprobe.d/supergfxd.conf";
+/// The first line of every modprobe conf supergfxd writes
+const MODPROBE_HEADER: &str = "# Automatically generated by supergfxd";
 
 static MODPROBE_NVIDIA_BASE: &[u8] = br#"# Automatically generated by supergfxd
 blacklist nouveau
@@ -93,6 +263,10 @@ blacklist nvidia
 blacklist nvidia-wmi-ec-backlight
 "#;
 
+/// Under nouveau nothing is blacklisted while the dGPU is in use
+static MODPROBE_NOUVEAU_BASE: &[u8] = br#"# Automatically generated by supergfxd
+"#;
+
 static MODPROBE_NVIDIA_EC_BKLT: &[u8] = br#"
 options nvidia-wmi-ec-backlight force=1
 "#;
@@ -123,74 +297,20 @@ fn mode_support_check(mode: &GfxMode) -> Result<(), GfxError> {
     Ok(())
 }
 
-/// Add or remove driver modules
-fn do_driver_action(driver: &str, action: DriverAction) -> Result<(), GfxError> {
-    let mut cmd = Command::new(<&str>::from(action));
-    cmd.arg(driver);
-
-    let mut count = 0;
-    const MAX_TRIES: i32 = 6;
-    loop {
-        if count > MAX_TRIES {
-            let msg = format!(
-                "{} {} failed for unknown reason",
-                <&str>::from(action),
-                driver
-            );
-            error!("{}", msg);
-            break; //Err(GfxError::Modprobe(msg));
-        }
-
-        let output = cmd
-            .output()
-            .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-        if !output.status.success() {
-            if output
-                .stderr
-                .ends_with("is not currently loaded\n".as_bytes())
-            {
-                debug!(
-                    "Driver {driver} was not loaded, skipping {}",
-                    <&str>::from(action)
-                );
-                break;
-            }
-            if output.stderr.ends_with("is builtin.\n".as_bytes()) {
-                return Err(GfxError::VfioBuiltin);
-            }
-            if output.stderr.ends_with("Permission denied\n".as_bytes()) {
-                warn!(
-                    "{} {} failed: {:?}",
-                    <&str>::from(action),
-                    driver,
-                    String::from_utf8_lossy(&output.stderr)
-                );
-                warn!("It may be safe to ignore the above error, run `lsmod |grep {}` to confirm modules loaded", driver);
-                break;
-            }
-            if String::from_utf8_lossy(&output.stderr)
-                .contains(&format!("Module {} not found", driver))
-            {
-                return Err(GfxError::MissingModule(driver.into()));
-            }
-            if count >= MAX_TRIES {
-                let msg = format!(
-                    "{} {} failed: {:?}",
-                    <&str>::from(action),
-                    driver,
-                    String::from_utf8_lossy(&output.stderr)
-                );
-                return Err(GfxError::Modprobe(msg));
-            }
-        } else if output.status.success() {
-            debug!("Did {} for driver {driver}", <&str>::from(action));
-            break;
-        }
-
-        count += 1;
-        std::thread::sleep(std::time::Duration::from_millis(50));
-    }
-    Ok(())
+/// Add or remove driver modules, trying up to `attempts` times with a growing wait between
+async fn do_driver_action(
+    driver: &str,
+    action: DriverAction,
+    attempts: u32,
+) -> Result<(), GfxError> {
+    driver_action_with_retry(
+        &SystemDriverCommand,
+        driver,
+        action,
+        attempts,
+        DRIVER_RETRY_DELAY,
+    )
+    .await
 }
 
 pub fn toggle_nvidia_powerd(run: bool, vendor: GfxVendor) -> Result<(), GfxError> {
@@ -224,53 +344,19 @@ pub fn toggle_nvidia_persistenced(run: bool, vendor: GfxVendor) -> Result<(), Gf
 
         let status = cmd.status()?;
         if !status.success() {
-            warn!("{run} nvidia-persistenced.service failed: {:?}", status.code());
+            warn!(
+                "{run} nvidia-persistenced.service failed: {:?}",
+                status.code()
+            );
         }
         debug!("Did {:?}", cmd.get_args());
     }
     Ok(())
 }
 
-pub fn kill_nvidia_lsof() -> Result<(), GfxError> {
-    if !PathBuf::from("/dev/nvidia0").exists() {
-        return Ok(());
-    }
-
-    if !PathBuf::from("/usr/bin/lsof").exists() {
-        warn!("The lsof util is missing from your system, please ensure it is available so processes hogging Nvidia can be nuked");
-        return Ok(());
-    }
-
-    let mut cmd = Command::new("lsof");
-    cmd.arg("/dev/nvidia0");
-
-    let output = cmd
-        .output()
-        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-
-    let st = String::from_utf8_lossy(&output.stdout);
-
-    for line in st.lines() {
-        let mut split = line.split_whitespace();
-        if let Some(c) = split.next() {
-            if let Some(pid) = split.next() {
-                if let Ok(pid) = pid.parse::<u32>() {
-                    warn!("pid {pid} ({c}) is holding /dev/nvidia0. Killing");
-                    let mut cmd = Command::new("kill");
-                    cmd.arg("-9");
-                    cmd.arg(format!("{pid}"));
-                    let status = cmd
-                        .status()
-                        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-                    if !status.success() {
-                        warn!("Killing pid {pid} failed");
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(())
+/// Whether the nvidia kernel module is loaded
+pub fn nvidia_module_loaded() -> bool {
+    Path::new(NVIDIA_MODULE_PATH).exists()
 }
 
 pub fn get_kernel_cmdline_mode() -> Result<Option<GfxMode>, GfxError> {
@@ -319,9 +405,12 @@ pub fn get_kernel_cmdline_nvidia_modeset() -> Result<Option<bool>, GfxError> {
 
 pub fn find_slot_power(address: &str) -> Result<PathBuf, GfxError> {
     let mut buf = Vec::new();
-    let path = PathBuf::from_str(SLOTS).unwrap();
-    for path in path.read_dir()? {
-        let path = path.unwrap().path();
+    let path = PathBuf::from(SLOTS);
+    for path in path
+        .read_dir()
+        .map_err(|e| GfxError::from_io(e, path.clone()))?
+    {
+        let path = path?.path();
 
         let mut address_path = path.to_path_buf();
         address_path.push("address");
@@ -0,0 +1,363 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use futures_util::{future::BoxFuture, lock::Mutex};
+use log::{debug, info, warn};
+use logind_zbus::{
+    manager::ManagerProxy,
+    session::{SessionClass, SessionProxy, SessionState, SessionType},
+};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+use zbus::{fdo::DBusProxy, names::BusName, object_server::SignalEmitter, Connection};
+
+use crate::{
+    actions::{graphical_session_ids, StagedAction},
+    error::GfxError,
+    gpu_users::{dgpu_users, GpuUser},
+    pci_device::DiscreetGpu,
+    signal_counters::{emit_counted, Signal},
+    switch_plan::SWITCH_CANCELLED,
+};
+
+/// How long the session of a confirmed logout has to end before the switch is dropped, the
+/// user probably cancelled the logout dialog
+pub(crate) const CONFIRM_LOGOUT_WINDOW: Duration = Duration::from_secs(60);
+/// How often logind is asked if the session has ended
+pub(crate) const SESSION_POLL: Duration = Duration::from_millis(250);
+
+/// How often the seconds left of `logout_timeout_s` are emitted with `NotifyPendingLogout`
+pub(crate) const PENDING_LOGOUT_NOTICE: Duration = Duration::from_secs(10);
+
+/// A logind session as read for the wait for the logout. A property which couldn't be read
+/// is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LogindSession {
+    pub id: String,
+    pub kind: Option<SessionType>,
+    pub class: Option<SessionClass>,
+    pub state: Option<SessionState>,
+    /// `XDG_SESSION_DESKTOP` of the session, such as `sway`, empty if it has none
+    pub desktop: String,
+}
+
+/// Whether `session` keeps a switch waiting for the logout, which it does if it is graphical
+/// and active or online, as a session on another VT still has its compositor running. A
+/// session of type x11, wayland or mir is graphical, and so is one of type tty or
+/// unspecified with a desktop, as greetd and others register compositor sessions that way.
+/// Greeter and lock screen sessions belong to the display manager, which is stopped anyway,
+/// and a closing session has been logged out of. A class logind-zbus doesn't know, such as
+/// `user-early`, is taken as a user session.
+pub(crate) fn session_blocks_logout(session: &LogindSession) -> bool {
+    let graphical = match session.kind {
+        Some(SessionType::X11 | SessionType::Wayland | SessionType::MIR) => true,
+        _ => !session.desktop.trim().is_empty(),
+    };
+    let own_session = !matches!(
+        session.class,
+        Some(SessionClass::Greeter | SessionClass::LockScreen)
+    );
+    let open = matches!(
+        session.state,
+        Some(SessionState::Online | SessionState::Active)
+    );
+    graphical && own_session && open
+}
+
+/// The whole seconds left of `timeout_s` after `elapsed`, rounded up
+pub(crate) fn logout_seconds_left(timeout_s: u64, elapsed: Duration) -> u64 {
+    let left = Duration::from_secs(timeout_s).saturating_sub(elapsed);
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+/// Asks logind about the sessions, so the wait can be tested
+pub(crate) trait SessionProbe: Sync {
+    /// The ids of the graphical sessions which are active or online. A closing session
+    /// is not, it has been logged out of.
+    fn graphical_sessions(&self) -> BoxFuture<'_, Result<Vec<String>, GfxError>>;
+}
+
+/// The sessions as logind has them
+pub(crate) struct SystemSessionProbe;
+
+impl SessionProbe for SystemSessionProbe {
+    fn graphical_sessions(&self) -> BoxFuture<'_, Result<Vec<String>, GfxError>> {
+        Box::pin(graphical_session_ids())
+    }
+}
+
+/// A process with the dGPU open, and its logind session if it is in one
+pub(crate) type DgpuHolder = (GpuUser, Option<String>);
+
+/// Finds the processes with the dGPU open and their sessions, so that `ForceIfIdle` can be
+/// tested
+pub(crate) trait HolderProbe: Sync {
+    /// The processes with the dGPU open
+    fn dgpu_holders(&self) -> BoxFuture<'_, Result<Vec<DgpuHolder>, GfxError>>;
+}
+
+/// The processes with the dGPU open on the running system, from their fds
+pub(crate) struct SystemHolderProbe {
+    pub dgpu: DiscreetGpu,
+}
+
+impl HolderProbe for SystemHolderProbe {
+    fn dgpu_holders(&self) -> BoxFuture<'_, Result<Vec<DgpuHolder>, GfxError>> {
+        Box::pin(async move {
+            let connection = Connection::system().await?;
+            let manager = ManagerProxy::new(&connection).await?;
+            let mut holders = Vec::new();
+            for user in dgpu_users(&self.dgpu) {
+                // Fails for a process which isn't in a session, such as a system service
+                let session = match manager.get_session_by_PID(user.pid).await {
+                    Ok(path) => {
+                        let session = SessionProxy::builder(&connection)
+                            .path(path)?
+                            .build()
+                            .await?;
+                        Some(session.id().await?)
+                    }
+                    Err(_) => None,
+                };
+                holders.push((user, session));
+            }
+            Ok(holders)
+        })
+    }
+}
+
+/// The logind session of the process which sent a dbus message
+pub(crate) async fn session_of_sender(
+    connection: &Connection,
+    sender: BusName<'_>,
+) -> Result<String, GfxError> {
+    let pid = DBusProxy::new(connection)
+        .await?
+        .get_connection_unix_process_id(sender)
+        .await?;
+    // logind is on the system bus whichever bus the message came in on
+    let system = Connection::system().await?;
+    let path = ManagerProxy::new(&system)
+        .await?
+        .get_session_by_PID(pid)
+        .await?;
+    let session = SessionProxy::builder(&system).path(path)?.build().await?;
+    Ok(session.id().await?)
+}
+
+/// How waiting for a session to end finished
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum SessionEnd {
+    /// The session ended. `others` is whether other graphical sessions are still open, which
+    /// the switch must then wait for as usual.
+    Ended { others: bool },
+    /// The session didn't end in time
+    Expired,
+    /// The switch was cancelled while waiting
+    Cancelled,
+}
+
+/// Wait for the graphical `session` to end, for at most `window`. Stops early if `token`
+/// is cancelled.
+pub(crate) async fn wait_session_end(
+    probe: &dyn SessionProbe,
+    session: &str,
+    window: Duration,
+    token: &AtomicU8,
+) -> Result<SessionEnd, GfxError> {
+    let deadline = Instant::now() + window;
+    loop {
+        if token.load(Ordering::Acquire) == SWITCH_CANCELLED {
+            return Ok(SessionEnd::Cancelled);
+        }
+        let sessions = probe.graphical_sessions().await?;
+        if !sessions.iter().any(|id| id == session) {
+            debug!("wait_session_end: session {session} ended, open: {sessions:?}");
+            return Ok(SessionEnd::Ended {
+                others: !sessions.is_empty(),
+            });
+        }
+        if Instant::now() >= deadline {
+            info!(
+                "wait_session_end: session {session} still open after {}s",
+                window.as_secs()
+            );
+            return Ok(SessionEnd::Expired);
+        }
+        sleep(SESSION_POLL).await;
+    }
+}
+
+/// What to do when graphical sessions are still open after `logout_timeout_s`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LogoutTimeoutAction {
+    /// Drop the switch, saying which sessions were open
+    #[default]
+    Fail,
+    /// Keep the switch pending until the sessions end, however long that takes. It can
+    /// still be cancelled.
+    ConvertToDeferred,
+    /// Switch without waiting any longer if none of the open sessions have the dGPU open,
+    /// otherwise drop the switch as `Fail` does
+    ForceIfIdle,
+}
+
+/// How long to wait for all graphical sessions to end, and what to do after
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LogoutPolicy {
+    /// `0` to wait for as long as it takes
+    pub timeout_s: u64,
+    pub action: LogoutTimeoutAction,
+}
+
+impl Default for LogoutPolicy {
+    fn default() -> Self {
+        Self {
+            timeout_s: 180,
+            action: LogoutTimeoutAction::Fail,
+        }
+    }
+}
+
+/// What `LogoutTimeoutAction` did at the timeout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LogoutTimeoutOutcome {
+    /// The switch was dropped
+    Dropped,
+    /// The switch waits on for the sessions to end
+    Deferred,
+    /// None of the sessions had the dGPU open, the switch goes on without waiting
+    Forced,
+    /// Processes in the sessions had the dGPU open, so the switch was dropped
+    Refused(Vec<String>),
+}
+
+/// Says what `outcome` did with the `sessions` still open at the timeout of `policy`, for
+/// the status, the `NotifyLogoutTimeout` signal and the audit log
+pub(crate) fn logout_timeout_message(
+    policy: LogoutPolicy,
+    sessions: &[String],
+    outcome: &LogoutTimeoutOutcome,
+) -> String {
+    let done = match outcome {
+        LogoutTimeoutOutcome::Dropped => "the switch was dropped".to_string(),
+        LogoutTimeoutOutcome::Deferred => "the switch waits until they end".to_string(),
+        LogoutTimeoutOutcome::Forced => {
+            "none of them have the dGPU open, switching without waiting".to_string()
+        }
+        LogoutTimeoutOutcome::Refused(holders) => format!(
+            "the switch was dropped as they have the dGPU open: {}",
+            holders.join(", ")
+        ),
+    };
+    format!(
+        "logout_timeout_s ({}s) passed with sessions {} still open, logout_timeout_action {:?}: {done}",
+        policy.timeout_s,
+        sessions.join(", "),
+        policy.action
+    )
+}
+
+/// Decide what to do with the `sessions` still open at the timeout of `policy`. For
+/// `ForceIfIdle` the processes holding the dGPU are only looked for now.
+pub(crate) async fn logout_timeout_outcome(
+    policy: LogoutPolicy,
+    sessions: &[String],
+    holders: &dyn HolderProbe,
+) -> Result<LogoutTimeoutOutcome, GfxError> {
+    Ok(match policy.action {
+        LogoutTimeoutAction::Fail => LogoutTimeoutOutcome::Dropped,
+        LogoutTimeoutAction::ConvertToDeferred => LogoutTimeoutOutcome::Deferred,
+        LogoutTimeoutAction::ForceIfIdle => {
+            let in_sessions: Vec<String> = holders
+                .dgpu_holders()
+                .await?
+                .into_iter()
+                .filter_map(|(user, session)| {
+                    let session = session.filter(|s| sessions.contains(s))?;
+                    Some(format!("{user} in session {session}"))
+                })
+                .collect();
+            if in_sessions.is_empty() {
+                LogoutTimeoutOutcome::Forced
+            } else {
+                LogoutTimeoutOutcome::Refused(in_sessions)
+            }
+        }
+    })
+}
+
+/// Wait for all graphical sessions to end, as decided by `session_blocks_logout`. Until
+/// `policy.timeout_s` passes the seconds left are emitted with `NotifyPendingLogout` every
+/// `PENDING_LOGOUT_NOTICE`. Once it has passed with some still open `policy.action` decides
+/// what happens, which is put in `report` and emitted with `NotifyLogoutTimeout`. Returns
+/// early if `loop_exit` is set, e.g. by a cancel request.
+pub(crate) async fn wait_logout(
+    sessions: &dyn SessionProbe,
+    holders: &dyn HolderProbe,
+    policy: LogoutPolicy,
+    loop_exit: &AtomicBool,
+    report: &Mutex<String>,
+    signal_ctxt: Option<&SignalEmitter<'_>>,
+) -> Result<(), GfxError> {
+    loop_exit.store(false, Ordering::Release);
+    let timeout = Duration::from_secs(policy.timeout_s);
+    let start = Instant::now();
+    let mut deferred = false;
+    let mut next_notice = Duration::ZERO;
+
+    while !loop_exit.load(Ordering::Acquire) {
+        let open = sessions.graphical_sessions().await?;
+        if open.is_empty() {
+            break;
+        }
+        let elapsed = start.elapsed();
+        if !deferred && policy.timeout_s != 0 && elapsed >= next_notice && elapsed <= timeout {
+            next_notice += PENDING_LOGOUT_NOTICE;
+            let left = logout_seconds_left(policy.timeout_s, elapsed);
+            debug!("wait_logout: {left}s left for sessions {open:?}");
+            if let Some(ctxt) = signal_ctxt {
+                emit_counted!(ctxt, Signal::PendingLogout, notify_pending_logout(left))
+                    .await
+                    .unwrap_or_else(|err| warn!("wait_logout: {err}"));
+            }
+        }
+        if !deferred && policy.timeout_s != 0 && start.elapsed() > timeout {
+            let outcome = logout_timeout_outcome(policy, &open, holders).await?;
+            let message = logout_timeout_message(policy, &open, &outcome);
+            warn!("wait_logout: {message}");
+            *report.lock().await = message.clone();
+            if let Some(ctxt) = signal_ctxt {
+                emit_counted!(ctxt, Signal::LogoutTimeout, notify_logout_timeout(&message))
+                    .await
+                    .unwrap_or_else(|err| warn!("wait_logout: {err}"));
+            }
+            match outcome {
+                LogoutTimeoutOutcome::Deferred => deferred = true,
+                LogoutTimeoutOutcome::Forced => break,
+                LogoutTimeoutOutcome::Dropped | LogoutTimeoutOutcome::Refused(_) => {
+                    return Err(GfxError::LogoutTimeout(message));
+                }
+            }
+        }
+        sleep(SESSION_POLL).await;
+    }
+
+    loop_exit.store(false, Ordering::Release);
+    debug!("wait_logout: loop exited");
+    Ok(())
+}
+
+/// Drop the wait for all graphical sessions to end from the actions of a switch, if the
+/// session waited for was the last one
+pub(crate) fn after_session_end(
+    mut actions: Vec<StagedAction>,
+    end: SessionEnd,
+) -> Vec<StagedAction> {
+    if end == (SessionEnd::Ended { others: false }) {
+        actions.retain(|action| *action != StagedAction::WaitLogout);
+    }
+    actions
+}
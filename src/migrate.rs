@@ -0,0 +1,640 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::{
+    ac_automation::AcAutomation,
+    config::GfxConfig,
+    error::GfxError,
+    pci_device::GfxMode,
+    systemd::{do_systemd_unit_action, SystemdUnitAction, SYSTEMD_UNIT_DIRS},
+};
+
+/// Directory under `STATE_DIR` where each import keeps what it moved aside, in a directory
+/// named for the time of the import
+pub const IMPORT_BACKUP_DIR: &str = "import-backup";
+/// What an import did, one step per line, so it can be undone
+const MANIFEST_NAME: &str = "manifest";
+/// The manifest once the import has been undone
+const MANIFEST_UNDONE_NAME: &str = "manifest.undone";
+/// The name the replaced supergfxd config is kept under in the backup
+const CONFIG_BACKUP_NAME: &str = "config.json";
+
+/// EnvyControl writes this at the top of every file it creates. Its files are in generic
+/// places such as `/etc/X11/xorg.conf`, so only those with it are touched.
+const ENVYCONTROL_MARKER: &str = "Automatically generated by EnvyControl";
+const ENVYCONTROL_BIN: &str = "/usr/bin/envycontrol";
+const ENVYCONTROL_BLACKLIST: &str = "/etc/modprobe.d/blacklist-nvidia.conf";
+const ENVYCONTROL_UDEV_INTEGRATED: &str = "/lib/udev/rules.d/50-remove-nvidia.rules";
+const ENVYCONTROL_UDEV_PM: &str = "/lib/udev/rules.d/80-nvidia-pm.rules";
+const ENVYCONTROL_XORG: &str = "/etc/X11/xorg.conf";
+const ENVYCONTROL_EXTRA_XORG: &str = "/etc/X11/xorg.conf.d/10-nvidia.conf";
+const ENVYCONTROL_MODESET: &str = "/etc/modprobe.d/nvidia.conf";
+const ENVYCONTROL_LIGHTDM_SCRIPT: &str = "/etc/lightdm/nvidia.sh";
+const ENVYCONTROL_LIGHTDM_CONFIG: &str = "/etc/lightdm/lightdm.conf.d/20-nvidia.conf";
+/// Every file EnvyControl may have written
+const ENVYCONTROL_FILES: &[&str] = &[
+    ENVYCONTROL_BLACKLIST,
+    ENVYCONTROL_UDEV_INTEGRATED,
+    ENVYCONTROL_UDEV_PM,
+    ENVYCONTROL_XORG,
+    ENVYCONTROL_EXTRA_XORG,
+    ENVYCONTROL_MODESET,
+    ENVYCONTROL_LIGHTDM_SCRIPT,
+    ENVYCONTROL_LIGHTDM_CONFIG,
+];
+/// Xorg options EnvyControl can add for the dGPU, which supergfxd has no equivalent for
+const ENVYCONTROL_XORG_OPTIONS: &[&str] = &[
+    "ForceCompositionPipeline",
+    "ForceFullCompositionPipeline",
+    "Coolbits",
+];
+
+/// The config of the user, over the defaults of the package
+const OPTIMUS_MANAGER_CONF: &str = "/etc/optimus-manager/optimus-manager.conf";
+/// The defaults installed by the package, read but never moved
+const OPTIMUS_MANAGER_DEFAULTS: &str = "/usr/share/optimus-manager.conf";
+/// Written by optimus-manager when it sets up Xorg for a mode
+const OPTIMUS_MANAGER_XORG: &str = "/etc/X11/xorg.conf.d/10-optimus-manager.conf";
+const OPTIMUS_MANAGER_UNIT: &str = "optimus-manager.service";
+
+/// A tool whose settings can be imported
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ForeignTool {
+    EnvyControl,
+    OptimusManager,
+}
+
+impl FromStr for ForeignTool {
+    type Err = GfxError;
+
+    fn from_str(s: &str) -> Result<Self, GfxError> {
+        match s.to_lowercase().as_str() {
+            "envycontrol" => Ok(Self::EnvyControl),
+            "optimus-manager" | "optimus_manager" => Ok(Self::OptimusManager),
+            _ => Err(GfxError::Import(format!(
+                "can't import from {s:?}, only from envycontrol or optimus-manager"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ForeignTool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EnvyControl => write!(f, "envycontrol"),
+            Self::OptimusManager => write!(f, "optimus-manager"),
+        }
+    }
+}
+
+/// What was found of a tool on the system
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ForeignState {
+    /// The mode it sets at boot in its own words, e.g `integrated`
+    pub mode: Option<String>,
+    /// The modes on battery and on AC, for optimus-manager's `auto`
+    pub auto_modes: Option<(String, String)>,
+    /// Whether it sets nvidia-drm modeset, if it says
+    pub modeset: Option<bool>,
+    /// Its settings which have no equivalent in supergfxd
+    pub unmapped: Vec<String>,
+    /// The files it wrote, by absolute path on the system
+    pub files: Vec<PathBuf>,
+    /// Files in places it writes to, but which it didn't write
+    pub unrecognised: Vec<PathBuf>,
+    /// Its systemd units which are enabled
+    pub units: Vec<String>,
+}
+
+/// The modes of the tools and the supergfxd mode for each, with what differs if anything.
+/// `intel` is what optimus-manager called `integrated` before 1.4.
+pub const MODE_MAP: &[(&str, GfxMode, Option<&str>)] = &[
+    ("integrated", GfxMode::Integrated, None),
+    ("intel", GfxMode::Integrated, None),
+    ("hybrid", GfxMode::Hybrid, None),
+    (
+        "nvidia",
+        GfxMode::Hybrid,
+        Some("supergfxd has no mode where the dGPU renders the desktop without a MUX. Hybrid is used, run programs on the dGPU with `supergfxctl --run`, or use AsusMuxDgpu on a laptop with a MUX"),
+    ),
+];
+
+/// The supergfxd mode for the mode of a tool, see `MODE_MAP`. Hybrid without nvidia-drm
+/// modeset is NvidiaNoModeset.
+pub fn map_mode(mode: &str, modeset: Option<bool>) -> Option<(GfxMode, Option<&'static str>)> {
+    let (_, gfx_mode, note) = MODE_MAP
+        .iter()
+        .find(|(name, _, _)| name.eq_ignore_ascii_case(mode.trim()))?;
+    if *gfx_mode == GfxMode::Hybrid && modeset == Some(false) {
+        return Some((GfxMode::NvidiaNoModeset, *note));
+    }
+    Some((*gfx_mode, *note))
+}
+
+/// `path` on the system as found under `root`, which is `/` other than in tests
+fn under(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Read what `tool` left on the system under `root`
+pub fn detect(tool: ForeignTool, root: &Path) -> ForeignState {
+    match tool {
+        ForeignTool::EnvyControl => read_envycontrol(root),
+        ForeignTool::OptimusManager => read_optimus_manager(root),
+    }
+}
+
+/// The mode of EnvyControl is known from the files it wrote, as `envycontrol --query` does.
+/// Hybrid without RTD3 leaves no files, so is only known if it is installed.
+pub(crate) fn read_envycontrol(root: &Path) -> ForeignState {
+    let mut found = ForeignState::default();
+    let mut contents = BTreeMap::new();
+    for file in ENVYCONTROL_FILES {
+        match fs::read_to_string(under(root, Path::new(file))) {
+            Ok(content) if content.contains(ENVYCONTROL_MARKER) => {
+                found.files.push(PathBuf::from(file));
+                contents.insert(*file, content);
+            }
+            Ok(_) => found.unrecognised.push(PathBuf::from(file)),
+            Err(_) => {}
+        }
+    }
+    let has = |file: &str| contents.contains_key(file);
+    found.mode = if has(ENVYCONTROL_BLACKLIST) && has(ENVYCONTROL_UDEV_INTEGRATED) {
+        Some("integrated".to_string())
+    } else if has(ENVYCONTROL_XORG) && has(ENVYCONTROL_MODESET) {
+        Some("nvidia".to_string())
+    } else if !found.files.is_empty() || under(root, Path::new(ENVYCONTROL_BIN)).exists() {
+        Some("hybrid".to_string())
+    } else {
+        None
+    };
+
+    if let Some(modprobe) = contents.get(ENVYCONTROL_MODESET) {
+        if modprobe.contains("nvidia-drm modeset=1") {
+            found.modeset = Some(true);
+        }
+        if modprobe.contains("NVreg_DynamicPowerManagement") {
+            found.unmapped.push(
+                "RTD3 power management (NVreg_DynamicPowerManagement): supergfxd turns on runtime PM for the dGPU in Hybrid itself"
+                    .to_string(),
+            );
+        }
+    }
+    for file in [ENVYCONTROL_XORG, ENVYCONTROL_EXTRA_XORG] {
+        for line in contents.get(file).map_or("", |s| s.as_str()).lines() {
+            let line = line.trim();
+            if line.starts_with("Option")
+                && ENVYCONTROL_XORG_OPTIONS
+                    .iter()
+                    .any(|option| line.contains(&format!("\"{option}\"")))
+            {
+                found.unmapped.push(format!(
+                    "Xorg `{line}`: supergfxd doesn't write Xorg configs, add it to a file in /etc/X11/xorg.conf.d"
+                ));
+            }
+        }
+    }
+    found
+}
+
+/// The `(section, key)` settings of an ini file, later ones replacing earlier
+pub(crate) fn parse_ini(text: &str) -> BTreeMap<(String, String), String> {
+    let mut settings = BTreeMap::new();
+    let mut section = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_lowercase();
+        } else if let Some((key, value)) = line.split_once('=') {
+            settings.insert(
+                (section.clone(), key.trim().to_lowercase()),
+                value.trim().to_string(),
+            );
+        }
+    }
+    settings
+}
+
+/// The systemd `unit` is enabled under `root`, linked from a `.wants` directory
+fn unit_enabled(root: &Path, unit: &str) -> bool {
+    SYSTEMD_UNIT_DIRS
+        .iter()
+        .filter_map(|dir| fs::read_dir(under(root, Path::new(dir))).ok())
+        .flat_map(|entries| entries.filter_map(|e| e.ok()))
+        .any(|entry| {
+            entry.file_name().to_string_lossy().ends_with(".wants")
+                && entry.path().join(unit).symlink_metadata().is_ok()
+        })
+}
+
+/// optimus-manager reads its defaults and then the config of the user over them
+pub(crate) fn read_optimus_manager(root: &Path) -> ForeignState {
+    let mut found = ForeignState::default();
+    let mut settings = BTreeMap::new();
+    for file in [OPTIMUS_MANAGER_DEFAULTS, OPTIMUS_MANAGER_CONF] {
+        if let Ok(text) = fs::read_to_string(under(root, Path::new(file))) {
+            settings.extend(parse_ini(&text));
+            if file == OPTIMUS_MANAGER_CONF {
+                found.files.push(PathBuf::from(file));
+            }
+        }
+    }
+    if under(root, Path::new(OPTIMUS_MANAGER_XORG)).exists() {
+        found.files.push(PathBuf::from(OPTIMUS_MANAGER_XORG));
+    }
+    if unit_enabled(root, OPTIMUS_MANAGER_UNIT) {
+        found.units.push(OPTIMUS_MANAGER_UNIT.to_string());
+    }
+    if settings.is_empty() {
+        return found;
+    }
+
+    let get = |section: &str, key: &str| {
+        settings
+            .get(&(section.to_string(), key.to_string()))
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    };
+    // The defaults of optimus-manager 1.4 where the files don't say
+    let mode = get("optimus", "startup_mode").unwrap_or("integrated");
+    if mode == "auto" {
+        found.auto_modes = Some((
+            get("optimus", "startup_auto_battery_mode")
+                .unwrap_or("integrated")
+                .to_string(),
+            get("optimus", "startup_auto_extpower_mode")
+                .unwrap_or("nvidia")
+                .to_string(),
+        ));
+    }
+    found.mode = Some(mode.to_string());
+    found.modeset = get("nvidia", "modeset").map(|v| v == "yes");
+
+    if let Some(switching) = get("optimus", "switching").filter(|v| *v != "none") {
+        found.unmapped.push(format!(
+            "[optimus] switching={switching}: supergfxd powers the dGPU down itself, with hotplug_type for the slot power"
+        ));
+    }
+    if let Some(dpm) = get("nvidia", "dynamic_power_management").filter(|v| *v != "no") {
+        found.unmapped.push(format!(
+            "[nvidia] dynamic_power_management={dpm}: supergfxd turns on runtime PM for the dGPU in Hybrid itself"
+        ));
+    }
+    if let Some(options) = get("nvidia", "options") {
+        found.unmapped.push(format!(
+            "[nvidia] options={options}: supergfxd doesn't write Xorg configs, add them to a file in /etc/X11/xorg.conf.d"
+        ));
+    }
+    found
+}
+
+/// One step of an import
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ImportAction {
+    /// Write the proposed supergfxd config to this path, keeping the old one in the backup
+    WriteConfig(PathBuf),
+    /// Move a file of the tool into the backup
+    MoveAside(PathBuf),
+    /// Disable a systemd unit of the tool
+    DisableUnit(String),
+}
+
+impl fmt::Display for ImportAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WriteConfig(path) => write!(f, "write {}", path.display()),
+            Self::MoveAside(path) => write!(f, "move {} to the backup", path.display()),
+            Self::DisableUnit(unit) => write!(f, "disable {unit}"),
+        }
+    }
+}
+
+/// What an import of a tool would do
+#[derive(Debug, Clone)]
+pub struct ImportPlan {
+    pub tool: ForeignTool,
+    pub found: ForeignState,
+    /// The supergfxd config before the import
+    pub current: GfxConfig,
+    /// The supergfxd config equivalent to the tool
+    pub config: GfxConfig,
+    /// What couldn't be carried over, and anything else to know
+    pub notes: Vec<String>,
+    pub actions: Vec<ImportAction>,
+}
+
+/// Work out the supergfxd config equivalent to `tool` as found under `root`, starting from
+/// the `current` config, and which of its files and units to move aside. Nothing is changed.
+pub fn plan_import(
+    tool: ForeignTool,
+    root: &Path,
+    current: GfxConfig,
+) -> Result<ImportPlan, GfxError> {
+    let found = detect(tool, root);
+    if found.mode.is_none() && found.files.is_empty() && found.units.is_empty() {
+        return Err(GfxError::Import(format!(
+            "{tool} isn't installed and none of its files were found"
+        )));
+    }
+
+    let mut config = current.clone();
+    let mut notes = Vec::new();
+    let mut map = |mode: &str| match map_mode(mode, found.modeset) {
+        Some((gfx_mode, note)) => {
+            if let Some(note) = note {
+                notes.push(format!("{tool} mode {mode}: {note}"));
+            }
+            Some(gfx_mode)
+        }
+        None => {
+            notes.push(format!(
+                "{tool} mode {mode} has no equivalent, the mode is left as it is"
+            ));
+            None
+        }
+    };
+    if let Some((battery, ac)) = &found.auto_modes {
+        config.ac_automation = AcAutomation {
+            on_battery: map(battery),
+            on_ac: map(ac),
+            ..current.ac_automation.clone()
+        };
+        if let Some(mode) = config.ac_automation.on_battery {
+            config.mode = mode;
+        }
+        notes.push(format!(
+            "{tool} picked the mode at boot by the power source, ac_automation suggests the mode when the power source changes instead. Set its auto_apply_when_no_sessions to switch without asking"
+        ));
+    } else if let Some(mode) = found.mode.as_deref().and_then(&mut map) {
+        config.mode = mode;
+    }
+    notes.extend(found.unmapped.iter().cloned());
+    for file in &found.unrecognised {
+        notes.push(format!(
+            "{} is left alone, {tool} didn't write it",
+            file.display()
+        ));
+    }
+    if found
+        .files
+        .iter()
+        .any(|file| file.starts_with("/etc/modprobe.d"))
+    {
+        notes.push(
+            "If the initramfs has a copy of /etc/modprobe.d, regenerate it after the import"
+                .to_string(),
+        );
+    }
+
+    let mut actions = Vec::new();
+    if !config_diff(&current, &config).is_empty()
+        || !under(root, Path::new(&current.config_path)).exists()
+    {
+        actions.push(ImportAction::WriteConfig(PathBuf::from(
+            &current.config_path,
+        )));
+    }
+    actions.extend(found.files.iter().cloned().map(ImportAction::MoveAside));
+    actions.extend(found.units.iter().cloned().map(ImportAction::DisableUnit));
+    Ok(ImportPlan {
+        tool,
+        found,
+        current,
+        config,
+        notes,
+        actions,
+    })
+}
+
+/// The settings which differ between `old` and `new`, as `-` and `+` lines of their json
+pub fn config_diff(old: &GfxConfig, new: &GfxConfig) -> Vec<String> {
+    let (old, new) = match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(Value::Object(old)), Ok(Value::Object(new))) => (old, new),
+        _ => return Vec::new(),
+    };
+    let mut lines = Vec::new();
+    for (key, value) in &new {
+        match old.get(key) {
+            Some(was) if was == value => {}
+            Some(was) => {
+                lines.push(format!("- \"{key}\": {was}"));
+                lines.push(format!("+ \"{key}\": {value}"));
+            }
+            None => lines.push(format!("+ \"{key}\": {value}")),
+        }
+    }
+    lines
+}
+
+/// The plan as shown to the user before anything is done
+pub fn render_plan(plan: &ImportPlan) -> String {
+    let mut out = format!(
+        "Found {}, boot mode {}\n",
+        plan.tool,
+        plan.found.mode.as_deref().unwrap_or("unknown")
+    );
+    for file in &plan.found.files {
+        out += &format!("  {}\n", file.display());
+    }
+    for unit in &plan.found.units {
+        out += &format!("  {unit}\n");
+    }
+    let diff = config_diff(&plan.current, &plan.config);
+    if diff.is_empty() {
+        out += &format!("\n{} needs no changes\n", plan.current.config_path);
+    } else {
+        out += &format!("\nChanges to {}:\n", plan.current.config_path);
+        for line in diff {
+            out += &format!("{line}\n");
+        }
+    }
+    if !plan.notes.is_empty() {
+        out += "\nNotes:\n";
+        for note in &plan.notes {
+            out += &format!("  {note}\n");
+        }
+    }
+    out += "\nActions:\n";
+    for action in &plan.actions {
+        out += &format!("  {action}\n");
+    }
+    out
+}
+
+/// Enables and disables systemd units for an import, so it can be tested without systemctl
+pub trait UnitControl {
+    fn set_enabled(&self, unit: &str, enabled: bool) -> Result<(), GfxError>;
+}
+
+/// `UnitControl` with `systemctl`
+pub struct SystemctlUnits;
+
+impl UnitControl for SystemctlUnits {
+    fn set_enabled(&self, unit: &str, enabled: bool) -> Result<(), GfxError> {
+        let action = if enabled {
+            SystemdUnitAction::Enable
+        } else {
+            SystemdUnitAction::Disable
+        };
+        do_systemd_unit_action(action, unit)
+    }
+}
+
+/// Move `from` to `to`, copying if they are on different filesystems
+fn move_file(from: &Path, to: &Path) -> Result<(), GfxError> {
+    fs::rename(from, to)
+        .or_else(|_| fs::copy(from, to).and_then(|_| fs::remove_file(from)))
+        .map_err(|err| GfxError::from_io(err, from.into()))
+}
+
+/// Append a step to the manifest in `backup`, as soon as it is done so a failed import can
+/// still be undone
+fn record(backup: &Path, fields: &[&str]) -> Result<(), GfxError> {
+    let path = backup.join(MANIFEST_NAME);
+    let mut manifest = fs::read_to_string(&path).unwrap_or_default();
+    manifest += &fields.join("\t");
+    manifest.push('\n');
+    fs::write(&path, manifest).map_err(|err| GfxError::from_io(err, path))
+}
+
+/// Carry out `plan` on the system under `root`, keeping what is replaced or moved in the
+/// new directory `backup`. Returns each step done. Stops at the first step which fails,
+/// what was done before it can be undone with `undo_import`.
+pub fn apply_import(
+    plan: &ImportPlan,
+    root: &Path,
+    backup: &Path,
+    units: &dyn UnitControl,
+) -> Result<Vec<String>, GfxError> {
+    fs::create_dir_all(backup).map_err(|err| GfxError::from_io(err, backup.into()))?;
+    let mut done = Vec::new();
+    for (idx, action) in plan.actions.iter().enumerate() {
+        match action {
+            ImportAction::WriteConfig(path) => {
+                let target = under(root, path);
+                let kept = if target.exists() {
+                    fs::copy(&target, backup.join(CONFIG_BACKUP_NAME))
+                        .map_err(|err| GfxError::from_io(err, target.clone()))?;
+                    CONFIG_BACKUP_NAME
+                } else {
+                    "-"
+                };
+                record(backup, &["config", &path.to_string_lossy(), kept])?;
+                if let Some(dir) = target.parent() {
+                    fs::create_dir_all(dir).map_err(|err| GfxError::from_io(err, dir.into()))?;
+                }
+                let mut config = plan.config.clone();
+                config.config_path = target.to_string_lossy().to_string();
+                config.write_atomic()?;
+            }
+            ImportAction::MoveAside(path) => {
+                let name = format!(
+                    "{idx}-{}",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                );
+                move_file(&under(root, path), &backup.join(&name))?;
+                record(backup, &["moved", &path.to_string_lossy(), &name])?;
+            }
+            ImportAction::DisableUnit(unit) => {
+                units.set_enabled(unit, false)?;
+                record(backup, &["unit", unit])?;
+            }
+        }
+        info!("import from {}: {action}", plan.tool);
+        done.push(action.to_string());
+    }
+    Ok(done)
+}
+
+/// The newest import under `backups` which hasn't been undone
+pub fn latest_backup(backups: &Path) -> Option<PathBuf> {
+    fs::read_dir(backups)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let secs: u64 = e.file_name().to_str()?.parse().ok()?;
+            Some((secs, e.path()))
+        })
+        .filter(|(_, path)| path.join(MANIFEST_NAME).exists())
+        .max_by_key(|(secs, _)| *secs)
+        .map(|(_, path)| path)
+}
+
+/// The files moved aside by the imports under `backups` which haven't been undone, as
+/// (where it was, where it is kept)
+pub(crate) fn moved_aside(backups: &Path) -> Vec<(PathBuf, PathBuf)> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(backups)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    dirs.sort();
+    let mut moved = Vec::new();
+    for dir in dirs {
+        let manifest = fs::read_to_string(dir.join(MANIFEST_NAME)).unwrap_or_default();
+        for line in manifest.lines() {
+            if let ["moved", path, name] = line.split('\t').collect::<Vec<_>>().as_slice() {
+                moved.push((PathBuf::from(path), dir.join(name)));
+            }
+        }
+    }
+    moved
+}
+
+/// Undo the import kept in `backup` on the system under `root`, last step first. Returns
+/// each step undone. A moved file is not put back over one which has since been created.
+pub fn undo_import(
+    root: &Path,
+    backup: &Path,
+    units: &dyn UnitControl,
+) -> Result<Vec<String>, GfxError> {
+    let manifest_path = backup.join(MANIFEST_NAME);
+    let manifest = fs::read_to_string(&manifest_path)
+        .map_err(|err| GfxError::from_io(err, manifest_path.clone()))?;
+    let mut done = Vec::new();
+    for line in manifest.lines().rev() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["config", path, kept] => {
+                let target = under(root, Path::new(path));
+                if *kept == "-" {
+                    fs::remove_file(&target).map_err(|err| GfxError::from_io(err, target))?;
+                    done.push(format!("remove {path}"));
+                } else {
+                    move_file(&backup.join(kept), &target)?;
+                    done.push(format!("restore {path}"));
+                }
+            }
+            ["moved", path, name] => {
+                let target = under(root, Path::new(path));
+                if target.exists() {
+                    warn!("import undo: {path} exists, leaving {name} in the backup");
+                    done.push(format!(
+                        "leave {path} as it is, it was created again. The old one is {}",
+                        backup.join(name).display()
+                    ));
+                    continue;
+                }
+                move_file(&backup.join(name), &target)?;
+                done.push(format!("put back {path}"));
+            }
+            ["unit", unit] => {
+                units.set_enabled(unit, true)?;
+                done.push(format!("enable {unit}"));
+            }
+            _ => warn!("import undo: skipping unknown manifest line {line:?}"),
+        }
+    }
+    fs::rename(&manifest_path, backup.join(MANIFEST_UNDONE_NAME))
+        .map_err(|err| GfxError::from_io(err, manifest_path))?;
+    Ok(done)
+}
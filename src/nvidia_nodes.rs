@@ -0,0 +1,248 @@
+use std::{
+    fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::time::{sleep, Instant};
+
+use crate::error::GfxError;
+
+/// Where the device nodes are
+pub(crate) const DEV_DIR: &str = "/dev";
+/// The nodes the nvidia driver must have for apps to use the dGPU
+pub(crate) const NVIDIA_NODES: &[&str] = &["nvidia0", "nvidiactl", "nvidia-modeset"];
+/// The node of `nvidia_uvm`, for CUDA, only looked for while the module is loaded
+pub(crate) const NVIDIA_UVM_NODE: &str = "nvidia-uvm";
+const NVIDIA_UVM_MODULE_PATH: &str = "/sys/module/nvidia_uvm";
+/// The major number of the nodes in `NVIDIA_NODES`, from the driver README
+const NVIDIA_MAJOR: u32 = 195;
+/// The mode the driver gives its nodes unless `NVreg_DeviceFileMode` is set
+const NVIDIA_NODE_MODE: u32 = 0o666;
+/// Where distros install nvidia-modprobe
+const NVIDIA_MODPROBE_PATHS: &[&str] = &["/usr/bin/nvidia-modprobe", "/usr/sbin/nvidia-modprobe"];
+/// How long udev gets to make the nodes after the driver is loaded
+const NODE_SETTLE: Duration = Duration::from_secs(2);
+const NODE_SETTLE_POLL: Duration = Duration::from_millis(100);
+
+/// What is wrong with a device node of the nvidia driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NodeProblem {
+    Missing,
+    /// Neither its group nor everyone else can read and write it, with its mode
+    Permissions(u32),
+}
+
+/// A device node of the nvidia driver which apps can't use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NodeFinding {
+    pub node: PathBuf,
+    pub problem: NodeProblem,
+}
+
+impl fmt::Display for NodeFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node = self.node.display();
+        match self.problem {
+            NodeProblem::Missing => write!(
+                f,
+                "{node} is missing although the nvidia driver is loaded, so apps run on the \
+                 iGPU. The udev rules of the nvidia utils package (nvidia-utils on Arch, \
+                 x11-drivers/nvidia-drivers on Gentoo) or nvidia-modprobe are likely missing"
+            ),
+            NodeProblem::Permissions(mode) => write!(
+                f,
+                "{node} has mode {mode:04o}, which users can't open, so apps run on the iGPU. \
+                 Check NVreg_DeviceFileMode and NVreg_DeviceFileGID in /etc/modprobe.d"
+            ),
+        }
+    }
+}
+
+/// Whether users can open a node with `mode`: read and write for its group, such as
+/// `video`, or for everyone
+pub(crate) fn usable_mode(mode: u32) -> bool {
+    mode & 0o060 == 0o060 || mode & 0o006 == 0o006
+}
+
+/// Whether `nvidia_uvm` is loaded
+pub(crate) fn nvidia_uvm_loaded() -> bool {
+    Path::new(NVIDIA_UVM_MODULE_PATH).exists()
+}
+
+/// The nodes under `dev` apps can't use, with `nvidia-uvm` if `uvm_loaded`
+pub(crate) fn check_nvidia_nodes(dev: &Path, uvm_loaded: bool) -> Vec<NodeFinding> {
+    let uvm = uvm_loaded.then_some(NVIDIA_UVM_NODE);
+    NVIDIA_NODES
+        .iter()
+        .copied()
+        .chain(uvm)
+        .filter_map(|name| {
+            let node = dev.join(name);
+            let problem = match fs::metadata(&node) {
+                Err(_) => NodeProblem::Missing,
+                Ok(meta) => {
+                    let mode = meta.permissions().mode() & 0o7777;
+                    if usable_mode(mode) {
+                        return None;
+                    }
+                    NodeProblem::Permissions(mode)
+                }
+            };
+            Some(NodeFinding { node, problem })
+        })
+        .collect()
+}
+
+/// Makes the nodes, so it can be tested without root
+pub(crate) trait NodeFixer {
+    /// Run nvidia-modprobe to make the nodes, with the uvm one if `uvm`. `false` if it
+    /// isn't installed.
+    fn nvidia_modprobe(&self, uvm: bool) -> Result<bool, GfxError>;
+    /// The major number of the character devices `name` in `/proc/devices`
+    fn char_major(&self, name: &str) -> Option<u32>;
+    fn mknod(&self, node: &Path, major: u32, minor: u32) -> Result<(), GfxError>;
+    fn chmod(&self, node: &Path, mode: u32) -> Result<(), GfxError>;
+}
+
+/// `NodeFixer` of the running system
+pub(crate) struct SystemNodeFixer;
+
+impl NodeFixer for SystemNodeFixer {
+    fn nvidia_modprobe(&self, uvm: bool) -> Result<bool, GfxError> {
+        let path = match NVIDIA_MODPROBE_PATHS.iter().find(|p| Path::new(p).exists()) {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let mut cmd = Command::new(path);
+        cmd.args(["-c", "0", "-m"]);
+        if uvm {
+            cmd.arg("-u");
+        }
+        let status = cmd
+            .status()
+            .map_err(|err| GfxError::Command(path.to_string(), err))?;
+        if !status.success() {
+            return Err(GfxError::NvidiaNodes(format!(
+                "{path} exited with {:?}",
+                status.code()
+            )));
+        }
+        debug!("Did {path} {:?}", cmd.get_args());
+        Ok(true)
+    }
+
+    fn char_major(&self, name: &str) -> Option<u32> {
+        char_major_in(&fs::read_to_string("/proc/devices").ok()?, name)
+    }
+
+    fn mknod(&self, node: &Path, major: u32, minor: u32) -> Result<(), GfxError> {
+        let status = Command::new("mknod")
+            .arg(node)
+            .args(["c", &major.to_string(), &minor.to_string()])
+            .status()
+            .map_err(|err| GfxError::Command("mknod".to_string(), err))?;
+        if !status.success() {
+            return Err(GfxError::NvidiaNodes(format!(
+                "mknod {} exited with {:?}",
+                node.display(),
+                status.code()
+            )));
+        }
+        Ok(())
+    }
+
+    fn chmod(&self, node: &Path, mode: u32) -> Result<(), GfxError> {
+        fs::set_permissions(node, fs::Permissions::from_mode(mode))
+            .map_err(|err| GfxError::Write(node.display().to_string(), err))
+    }
+}
+
+/// The major number of the `Character devices` named `name` in the text of `/proc/devices`
+pub(crate) fn char_major_in(devices: &str, name: &str) -> Option<u32> {
+    devices
+        .lines()
+        .skip_while(|line| !line.starts_with("Character devices"))
+        .take_while(|line| !line.starts_with("Block devices"))
+        .find_map(|line| {
+            let mut words = line.split_whitespace();
+            let major = words.next()?.parse().ok()?;
+            (words.next() == Some(name)).then_some(major)
+        })
+}
+
+/// The major and minor number of the node `name`, from the driver README
+fn node_numbers(fixer: &dyn NodeFixer, name: &str) -> Option<(u32, u32)> {
+    match name {
+        "nvidiactl" => Some((NVIDIA_MAJOR, 255)),
+        "nvidia-modeset" => Some((NVIDIA_MAJOR, 254)),
+        NVIDIA_UVM_NODE => fixer.char_major(NVIDIA_UVM_NODE).map(|major| (major, 0)),
+        _ => name
+            .strip_prefix("nvidia")
+            .and_then(|minor| minor.parse().ok())
+            .map(|minor| (NVIDIA_MAJOR, minor)),
+    }
+}
+
+/// Put right `findings` under `dev`, for `fix_nvidia_nodes`. nvidia-modprobe is run if it
+/// is installed, what it didn't fix is made with mknod and given the driver's default mode.
+/// Returns what is still wrong.
+pub(crate) fn fix_nvidia_nodes(
+    fixer: &dyn NodeFixer,
+    dev: &Path,
+    uvm_loaded: bool,
+    mut findings: Vec<NodeFinding>,
+) -> Vec<NodeFinding> {
+    if findings.is_empty() {
+        return findings;
+    }
+    match fixer.nvidia_modprobe(uvm_loaded) {
+        Ok(true) => {
+            info!("fix_nvidia_nodes: ran nvidia-modprobe");
+            findings = check_nvidia_nodes(dev, uvm_loaded);
+        }
+        Ok(false) => debug!("fix_nvidia_nodes: nvidia-modprobe isn't installed"),
+        Err(err) => warn!("fix_nvidia_nodes: {err}"),
+    }
+    for finding in &findings {
+        let name = finding
+            .node
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let made = match finding.problem {
+            NodeProblem::Missing => match node_numbers(fixer, &name) {
+                Some((major, minor)) => fixer.mknod(&finding.node, major, minor),
+                None => Err(GfxError::NvidiaNodes(format!(
+                    "no device number for {}",
+                    finding.node.display()
+                ))),
+            },
+            NodeProblem::Permissions(_) => Ok(()),
+        };
+        match made.and_then(|_| fixer.chmod(&finding.node, NVIDIA_NODE_MODE)) {
+            Ok(()) => info!("fix_nvidia_nodes: made {}", finding.node.display()),
+            Err(err) => warn!("fix_nvidia_nodes: {err}"),
+        }
+    }
+    check_nvidia_nodes(dev, uvm_loaded)
+}
+
+/// After the nvidia driver was loaded for a mode which uses the dGPU, wait for udev to
+/// make its nodes, then put them right if `fix` is set. Returns what is still wrong.
+pub(crate) async fn settle_nvidia_nodes(fix: bool) -> Vec<NodeFinding> {
+    let dev = Path::new(DEV_DIR);
+    let start = Instant::now();
+    let mut findings = check_nvidia_nodes(dev, nvidia_uvm_loaded());
+    while !findings.is_empty() && start.elapsed() < NODE_SETTLE {
+        sleep(NODE_SETTLE_POLL).await;
+        findings = check_nvidia_nodes(dev, nvidia_uvm_loaded());
+    }
+    if fix {
+        findings = fix_nvidia_nodes(&SystemNodeFixer, dev, nvidia_uvm_loaded(), findings);
+    }
+    findings
+}
@@ -2,23 +2,32 @@ use log::{debug, info, trace, warn};
 use std::fmt::Display;
 use std::fs::{self, OpenOptions};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
-use std::{fs::write, path::PathBuf};
+use std::sync::Arc;
 
+use crate::actions::UserActionRequired;
+use crate::config_old::legacy_mode;
+use crate::device_busy::device_busy;
 use crate::error::GfxError;
+use crate::pci_link::{LinkInfo, ASPM_POLICY_PATH};
+use crate::power_semantics::derive_power;
+use crate::sandbox::note_write;
 use crate::special_asus::{
     asus_dgpu_disable_exists, asus_dgpu_disabled, asus_gpu_mux_exists, asus_gpu_mux_mode,
     AsusGpuMuxMode,
 };
+use crate::sysfs::Sysfs;
+use crate::system_class::SystemClass;
+use crate::vfio::driver_name;
 use crate::{
-    do_driver_action, find_connected_displays, find_slot_power, DriverAction, NVIDIA_DRIVERS,
+    do_driver_action, find_connected_displays, find_slot_power, DriverAction, NOUVEAU_DRIVER,
+    NVIDIA_DRIVERS,
 };
 
 use serde_derive::{Deserialize, Serialize};
-use zbus::zvariant::Type;
-
-const PCI_BUS_PATH: &str = "/sys/bus/pci";
+use zbus::zvariant::{OwnedValue, Type, Value};
 
 #[derive(Debug, Type, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
 pub enum HotplugType {
@@ -67,6 +76,37 @@ pub enum GfxPower {
     Unknown,
 }
 
+impl GfxPower {
+    /// In the order of the enum, which is how it is sent over dbus
+    const WIRE_ORDER: [GfxPower; 6] = [
+        GfxPower::Active,
+        GfxPower::Suspended,
+        GfxPower::Off,
+        GfxPower::AsusDisabled,
+        GfxPower::AsusMuxDiscreet,
+        GfxPower::Unknown,
+    ];
+}
+
+/// For the `PowerStatus` dbus property, sent as its index as it is as a method result
+impl From<GfxPower> for Value<'_> {
+    fn from(power: GfxPower) -> Self {
+        Value::U32(power as u32)
+    }
+}
+
+impl TryFrom<OwnedValue> for GfxPower {
+    type Error = zbus::zvariant::Error;
+
+    fn try_from(value: OwnedValue) -> Result<Self, Self::Error> {
+        let index = u32::try_from(value)?;
+        GfxPower::WIRE_ORDER
+            .get(index as usize)
+            .copied()
+            .ok_or(zbus::zvariant::Error::IncorrectType)
+    }
+}
+
 impl FromStr for GfxPower {
     type Err = GfxError;
 
@@ -104,6 +144,46 @@ pub enum GfxVendor {
     AsusDgpuDisabled,
 }
 
+/// Which driver stack runs an Nvidia dGPU
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum NvidiaDriver {
+    /// The nvidia modules
+    Proprietary,
+    Nouveau,
+    /// Neither is loaded, as in Integrated. Treated as `Proprietary`.
+    #[default]
+    None,
+}
+
+impl NvidiaDriver {
+    /// The modules loaded and unloaded for the dGPU, in unload order
+    pub(crate) fn modules(self) -> &'static [&'static str] {
+        match self {
+            Self::Nouveau => &[NOUVEAU_DRIVER],
+            Self::Proprietary | Self::None => &NVIDIA_DRIVERS,
+        }
+    }
+}
+
+/// Which Nvidia driver is loaded, from `/proc/modules` and `/sys/module` under `root`, which
+/// is `/` other than in tests. The proprietary one wins if somehow both are.
+pub(crate) fn nvidia_driver_in(root: &Path) -> NvidiaDriver {
+    let modules = fs::read_to_string(root.join("proc/modules")).unwrap_or_default();
+    let loaded = |name: &str| {
+        modules
+            .lines()
+            .any(|line| line.split_whitespace().next() == Some(name))
+            || root.join("sys/module").join(name).join("refcnt").exists()
+    };
+    if loaded("nvidia") {
+        NvidiaDriver::Proprietary
+    } else if loaded(NOUVEAU_DRIVER) {
+        NvidiaDriver::Nouveau
+    } else {
+        NvidiaDriver::None
+    }
+}
+
 impl From<u16> for GfxVendor {
     fn from(vendor: u16) -> Self {
         match vendor {
@@ -189,15 +269,199 @@ impl FromStr for GfxMode {
             "Vfio" => Ok(GfxMode::Vfio),
             "AsusEgpu" => Ok(GfxMode::AsusEgpu),
             "AsusMuxDgpu" => Ok(GfxMode::AsusMuxDgpu),
-            _ => Err(GfxError::ParseMode),
+            name => legacy_mode(name).ok_or(GfxError::ParseMode),
+        }
+    }
+}
+
+impl GfxMode {
+    /// Every mode in dbus wire order
+    pub fn wire_order() -> &'static [GfxMode] {
+        &Self::WIRE_ORDER
+    }
+
+    /// The modes in dbus wire order, a mode is sent as its index
+    const WIRE_ORDER: [GfxMode; 7] = [
+        GfxMode::Hybrid,
+        GfxMode::Integrated,
+        GfxMode::NvidiaNoModeset,
+        GfxMode::Vfio,
+        GfxMode::AsusEgpu,
+        GfxMode::AsusMuxDgpu,
+        GfxMode::None,
+    ];
+
+    /// The mode for a value received over dbus. Releases before 5.1 numbered the modes
+    /// differently (`Egpu` was 3 in 5.0), but those values are also valid now and can't be
+    /// told apart, so only a value past the last mode is known to be from an old client.
+    pub fn from_wire(value: u32) -> Result<Self, GfxError> {
+        Self::WIRE_ORDER
+            .get(value as usize)
+            .copied()
+            .ok_or(GfxError::ParseMode)
+    }
+
+    /// The documentation of the mode for frontends to show
+    pub fn doc(self) -> &'static ModeDoc {
+        // Matched rather than searched so a new mode can't be added without its entry
+        let index = match self {
+            GfxMode::Hybrid => 0,
+            GfxMode::Integrated => 1,
+            GfxMode::NvidiaNoModeset => 2,
+            GfxMode::Vfio => 3,
+            GfxMode::AsusEgpu => 4,
+            GfxMode::AsusMuxDgpu => 5,
+            GfxMode::None => 6,
+        };
+        &MODE_DOCS[index]
+    }
+}
+
+/// Outputs wired to the dGPU, such as HDMI on many laptops, don't work in the mode
+pub const RISK_EXTERNAL_PORTS_OFF: &str = "EXTERNAL_PORTS_OFF";
+/// Changing to or from the mode takes a reboot
+pub const RISK_REQUIRES_REBOOT: &str = "REQUIRES_REBOOT";
+/// Changing to the mode ends the graphical sessions
+pub const RISK_REQUIRES_LOGOUT: &str = "REQUIRES_LOGOUT";
+/// The dGPU stays powered, which shortens battery life
+pub const RISK_HIGHER_POWER_DRAW: &str = "HIGHER_POWER_DRAW";
+/// Apps on the host can't use the dGPU
+pub const RISK_DGPU_UNAVAILABLE: &str = "DGPU_UNAVAILABLE";
+/// The mode must be enabled in the config or on the kernel cmdline before it can be used
+pub const RISK_NEEDS_SETUP: &str = "NEEDS_SETUP";
+/// The eGPU must be disconnected only after leaving the mode
+pub const RISK_UNPLUG_AFTER_SWITCH: &str = "UNPLUG_AFTER_SWITCH";
+
+/// Every risk code a mode can list, codes are never renamed or reused
+pub const RISK_CODES: &[&str] = &[
+    RISK_EXTERNAL_PORTS_OFF,
+    RISK_REQUIRES_REBOOT,
+    RISK_REQUIRES_LOGOUT,
+    RISK_HIGHER_POWER_DRAW,
+    RISK_DGPU_UNAVAILABLE,
+    RISK_NEEDS_SETUP,
+    RISK_UNPLUG_AFTER_SWITCH,
+];
+
+/// What a mode is for and what using it costs
+#[derive(Debug, PartialEq, Eq)]
+pub struct ModeDoc {
+    pub mode: GfxMode,
+    pub summary: &'static str,
+    pub description: &'static str,
+    /// What a switch to the mode from Hybrid usually asks of the user
+    pub typical_action: UserActionRequired,
+    /// Stable codes from `RISK_CODES`
+    pub risks: &'static [&'static str],
+}
+
+/// The documentation of each mode, in wire order
+pub const MODE_DOCS: [ModeDoc; 7] = [
+    ModeDoc {
+        mode: GfxMode::Hybrid,
+        summary: "The iGPU drives the desktop, apps can offload to the dGPU",
+        description: "The dGPU is powered when an app uses it and suspends when idle. This is \
+            the usual mode for a laptop with switchable graphics.",
+        typical_action: UserActionRequired::Nothing,
+        risks: &[],
+    },
+    ModeDoc {
+        mode: GfxMode::Integrated,
+        summary: "Only the iGPU is used, the dGPU is powered off",
+        description: "The dGPU drivers are unloaded and the device removed or powered off for \
+            the best battery life. Outputs wired to the dGPU stop working.",
+        typical_action: UserActionRequired::Logout,
+        risks: &[
+            RISK_REQUIRES_LOGOUT,
+            RISK_EXTERNAL_PORTS_OFF,
+            RISK_DGPU_UNAVAILABLE,
+        ],
+    },
+    ModeDoc {
+        mode: GfxMode::NvidiaNoModeset,
+        summary: "Hybrid for nvidia with nvidia-drm.modeset=0",
+        description: "As Hybrid, for systems booted with nvidia-drm.modeset=0 which lets the \
+            nvidia drivers be unloaded without a logout.",
+        typical_action: UserActionRequired::Nothing,
+        risks: &[RISK_NEEDS_SETUP],
+    },
+    ModeDoc {
+        mode: GfxMode::Vfio,
+        summary: "The dGPU is bound to vfio-pci for passthrough to a VM",
+        description: "The dGPU is detached from the host drivers and bound to vfio-pci so a \
+            virtual machine can use it. It must be enabled with vfio_enable in the config, and \
+            is entered from Integrated.",
+        typical_action: UserActionRequired::SwitchToIntegrated,
+        risks: &[
+            RISK_NEEDS_SETUP,
+            RISK_DGPU_UNAVAILABLE,
+            RISK_EXTERNAL_PORTS_OFF,
+        ],
+    },
+    ModeDoc {
+        mode: GfxMode::AsusEgpu,
+        summary: "An ASUS XG Mobile eGPU is used instead of the internal dGPU",
+        description: "The ASUS eGPU port is enabled and the internal dGPU is disabled. Switch \
+            out of the mode before unplugging the eGPU.",
+        typical_action: UserActionRequired::Logout,
+        risks: &[
+            RISK_REQUIRES_LOGOUT,
+            RISK_HIGHER_POWER_DRAW,
+            RISK_UNPLUG_AFTER_SWITCH,
+        ],
+    },
+    ModeDoc {
+        mode: GfxMode::AsusMuxDgpu,
+        summary: "The GPU MUX routes the internal display to the dGPU",
+        description: "The firmware MUX is set so the dGPU drives the internal display, for \
+            lower latency in games. The iGPU isn't used and the dGPU is always powered.",
+        typical_action: UserActionRequired::Reboot,
+        risks: &[RISK_REQUIRES_REBOOT, RISK_HIGHER_POWER_DRAW],
+    },
+    ModeDoc {
+        mode: GfxMode::None,
+        summary: "No mode, reported when the mode is unknown",
+        description: "Not a mode that can be switched to. It is reported while there is no \
+            pending mode or when the mode couldn't be worked out.",
+        typical_action: UserActionRequired::Nothing,
+        risks: &[],
+    },
+];
+
+/// A mode's documentation and whether this system supports it
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ModeInfo {
+    pub mode: GfxMode,
+    /// The variant name, as given to `supergfxctl --mode`
+    pub name: String,
+    pub summary: String,
+    pub description: String,
+    pub typical_action: UserActionRequired,
+    pub risks: Vec<String>,
+    pub supported: bool,
+    /// Why the mode can't be used here, empty if it is supported
+    pub unsupported_reason: String,
+}
+
+impl ModeInfo {
+    pub fn new(mode: GfxMode, unsupported_reason: Option<String>) -> Self {
+        let doc = mode.doc();
+        Self {
+            mode,
+            name: format!("{mode:?}"),
+            summary: doc.summary.to_string(),
+            description: doc.description.to_string(),
+            typical_action: doc.typical_action,
+            risks: doc.risks.iter().map(|risk| risk.to_string()).collect(),
+            supported: unsupported_reason.is_none(),
+            unsupported_reason: unsupported_reason.unwrap_or_default(),
         }
     }
 }
 
 /// Will rescan the device tree, which adds all removed devices back
 pub fn rescan_pci_bus() -> Result<(), GfxError> {
-    let path = PathBuf::from(PCI_BUS_PATH).join("rescan");
-    write(&path, "1").map_err(|e| GfxError::from_io(e, path))
+    Sysfs::system().pci_rescan.rescan()
 }
 
 fn lscpi(vendor_device: &str) -> Result<String, GfxError> {
@@ -223,6 +487,113 @@ pub fn lscpi_dgpu_check(label: &str) -> bool {
     false
 }
 
+/// A PCI function address as used for sysfs names, `domain:bus:slot.function`
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub struct PciAddress {
+    pub domain: u32,
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Parse a sysfs PCI device name such as `0000:01:00.1`
+    pub fn parse(name: &str) -> Option<Self> {
+        let (device, function) = name.rsplit_once('.')?;
+        let mut parts = device.split(':');
+        let domain = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let slot = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let function = u8::from_str_radix(function, 16).ok()?;
+        if parts.next().is_some() || slot > 0x1f || function > 7 {
+            return None;
+        }
+        Some(Self {
+            domain,
+            bus,
+            slot,
+            function,
+        })
+    }
+
+    /// Both addresses are functions of the same physical device
+    pub fn same_device(&self, other: &Self) -> bool {
+        self.domain == other.domain && self.bus == other.bus && self.slot == other.slot
+    }
+}
+
+/// Whether `entry` of `ignored_functions` names the PCI function `name`. An entry is a full
+/// address such as `0000:01:00.2`, one without the domain such as `01:00.2`, or the suffix of
+/// a function of the dGPU such as `.2`.
+pub(crate) fn ignored_entry_matches(entry: &str, name: &str) -> bool {
+    let addr = match PciAddress::parse(name) {
+        Some(addr) => addr,
+        None => return false,
+    };
+    let entry = entry.trim();
+    if let Some(function) = entry.strip_prefix('.') {
+        return u8::from_str_radix(function, 16).ok() == Some(addr.function);
+    }
+    PciAddress::parse(entry).or_else(|| PciAddress::parse(&format!("0000:{entry}"))) == Some(addr)
+}
+
+/// Where the PCI functions are looked through for the iGPU
+const PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+
+/// Whether there is an iGPU among the PCI functions under `devices`, such as
+/// `/sys/bus/pci/devices`: an Intel or AMD display function which isn't part of the dGPU
+/// `dgpu`. The iGPU is usually `boot_vga`, but AMD ones don't always have the attribute so it
+/// isn't required. There is none when the iGPU is turned off in the BIOS, leaving the dGPU
+/// as the only GPU. If `devices` can't be read the iGPU is assumed present.
+pub(crate) fn igpu_present_in(devices: &Path, dgpu: &str) -> bool {
+    let dgpu = PciAddress::parse(dgpu);
+    let entries = match fs::read_dir(devices) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Could not read {devices:?} to look for the iGPU: {err}");
+            return true;
+        }
+    };
+    entries.flatten().any(|entry| {
+        let read = |attr: &str| fs::read_to_string(entry.path().join(attr)).unwrap_or_default();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let of_dgpu = matches!(
+            (PciAddress::parse(&name), dgpu),
+            (Some(addr), Some(dgpu)) if addr.same_device(&dgpu)
+        );
+        !of_dgpu
+            && read("class").trim().starts_with("0x03")
+            && matches!(read("vendor").trim(), "0x8086" | "0x1002")
+    })
+}
+
+/// Pick the dGPU out of `candidates` along with every other function of the same device,
+/// sorted by address. The order of `candidates` doesn't matter. If there is more than one
+/// dGPU the one with the lowest address is used.
+pub(crate) fn dgpu_functions(candidates: Vec<Device>) -> Vec<Device> {
+    let mut candidates: Vec<(PciAddress, Device)> = candidates
+        .into_iter()
+        .filter_map(|dev| match PciAddress::parse(&dev.name) {
+            Some(addr) => Some((addr, dev)),
+            None => {
+                warn!("Could not parse PCI address {:?}", dev.name);
+                None
+            }
+        })
+        .collect();
+    candidates.sort_by_key(|(addr, _)| *addr);
+
+    let dgpu = match candidates.iter().find(|(_, dev)| dev.is_dgpu) {
+        Some((addr, _)) => *addr,
+        None => return Vec::new(),
+    };
+    candidates
+        .into_iter()
+        .filter(|(addr, _)| addr.same_device(&dgpu))
+        .map(|(_, dev)| dev)
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 pub struct Device {
     /// Concrete path to the device control
@@ -231,7 +602,7 @@ pub struct Device {
     hotplug_path: Option<PathBuf>,
     vendor: GfxVendor,
     is_dgpu: bool,
-    /// System name given by kerne, e.g `0000:01:00.0`
+    /// System name given by kernel, e.g `0000:01:00.0`
     name: String,
     /// Vendor:Device, typically used only for VFIO setup
     pci_id: String,
@@ -254,9 +625,23 @@ impl Device {
         &self.pci_id
     }
 
+    /// The power attribute of the hotplug slot the device is in, if it is in one
+    pub(crate) fn hotplug_path(&self) -> Option<&Path> {
+        self.hotplug_path.as_deref()
+    }
+
+    /// The multifunction bit of the PCI header type, set if the device has other functions
+    /// such as HDMI audio. `None` if the config space can't be read.
+    pub fn is_multifunction(&self) -> Option<bool> {
+        // The header type is at 0x0e, which is within what non-root can read
+        let config = fs::read(self.dev_path.join("config")).ok()?;
+        config.get(0x0e).map(|header| header & 0x80 != 0)
+    }
+
     fn set_hotplug(&self, state: HotplugState) -> Result<(), GfxError> {
         if let Some(path) = self.hotplug_path.as_ref() {
             info!("set_hotplug: Setting hotplug power to {state:?}");
+            note_write(path);
             let mut file = OpenOptions::new()
                 .write(true)
                 .open(path)
@@ -269,8 +654,7 @@ impl Device {
     }
 
     pub fn find() -> Result<Vec<Self>, GfxError> {
-        let mut devices = Vec::new();
-        let mut parent = String::new();
+        let mut candidates = Vec::new();
 
         let mut enumerator = udev::Enumerator::new().map_err(|err| {
             warn!("{}", err);
@@ -282,14 +666,8 @@ impl Device {
             GfxError::Udev("match_subsystem failed".into(), err)
         })?;
 
-        let get_parent = |dev: &udev::Device| -> String {
-            dev.sysname()
-                .to_string_lossy()
-                .trim_end_matches(char::is_numeric)
-                .trim_end_matches('.')
-                .to_string()
-        };
-
+        // Enumeration order isn't guaranteed, so collect every Nvidia or AMD function first
+        // and then pick out the dGPU and the other functions of the same device
         for device in enumerator.scan_devices().map_err(|err| {
             warn!("{}", err);
             GfxError::Udev("scan_devices failed".into(), err)
@@ -369,44 +747,42 @@ impl Device {
                                 };
                             }
 
-                            if dgpu || !parent.is_empty() && sysname.contains(&parent) {
-                                let mut hotplug_path = None;
-                                if dgpu {
-                                    info!("Found dgpu {id} at {:?}", device.sysname());
-                                    match find_slot_power(&sysname) {
-                                        Ok(slot) => hotplug_path = Some(slot),
-                                        Err(e) => {
-                                            if let Ok(c) = asus_gpu_mux_mode() {
-                                                debug!(
-                                                    "Laptop is in dGPU MUX mode? {}",
-                                                    c == AsusGpuMuxMode::Discreet
-                                                );
-                                            } else {
-                                                debug!(
-                                                    "Laptop does not have a hotplug dgpu: {e:?}"
-                                                );
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    info!("Found additional device {id} at {:?}", device.sysname());
-                                }
-                                parent = get_parent(&device);
-                                devices.push(Self {
-                                    dev_path: PathBuf::from(device.syspath()),
-                                    hotplug_path,
-                                    vendor: vendor.into(),
-                                    is_dgpu: dgpu,
-                                    name: sysname.to_string(),
-                                    pci_id: id.to_string(),
-                                });
-                            }
+                            candidates.push(Self {
+                                dev_path: PathBuf::from(device.syspath()),
+                                hotplug_path: None,
+                                vendor: vendor.into(),
+                                is_dgpu: dgpu,
+                                name: sysname.to_string(),
+                                pci_id: id.to_string(),
+                            });
                         }
                     }
                 }
             }
-            if !parent.is_empty() && !sysname.contains(&parent) {
-                break;
+        }
+
+        let mut devices = dgpu_functions(candidates);
+        for device in devices.iter_mut() {
+            if device.is_dgpu {
+                info!("Found dgpu {} at {:?}", device.pci_id, device.name);
+                match find_slot_power(&device.name) {
+                    Ok(slot) => device.hotplug_path = Some(slot),
+                    Err(e) => {
+                        if let Ok(c) = asus_gpu_mux_mode() {
+                            debug!(
+                                "Laptop is in dGPU MUX mode? {}",
+                                c == AsusGpuMuxMode::Discreet
+                            );
+                        } else {
+                            debug!("Laptop does not have a hotplug dgpu: {e:?}");
+                        }
+                    }
+                }
+            } else {
+                info!(
+                    "Found additional device {} at {:?}",
+                    device.pci_id, device.name
+                );
             }
         }
 
@@ -417,6 +793,40 @@ impl Device {
         Ok(devices)
     }
 
+    /// A device built without sysfs, for testing
+    #[cfg(test)]
+    pub(crate) fn mock(name: &str, vendor: GfxVendor, is_dgpu: bool) -> Self {
+        Self {
+            dev_path: PathBuf::from(crate::sysfs::PCI_BUS_PATH)
+                .join("devices")
+                .join(name),
+            hotplug_path: None,
+            vendor,
+            is_dgpu,
+            name: name.to_string(),
+            pci_id: String::new(),
+        }
+    }
+
+    /// Point a mock device at `dev_path`, such as a directory standing in for sysfs
+    #[cfg(test)]
+    pub(crate) fn with_dev_path(mut self, dev_path: &Path) -> Self {
+        self.dev_path = dev_path.to_path_buf();
+        self
+    }
+
+    /// Set the `Vendor:Device` id of a mock device
+    #[cfg(test)]
+    pub(crate) fn with_pci_id(mut self, pci_id: &str) -> Self {
+        self.pci_id = pci_id.to_string();
+        self
+    }
+
+    /// System name given by kernel, e.g `0000:01:00.0`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     /// Read a file underneath the sys object
     fn read_file(path: PathBuf) -> Result<String, GfxError> {
         let path = path.canonicalize()?;
@@ -435,6 +845,7 @@ impl Device {
     /// Write a file underneath the sys object
     fn write_file(path: PathBuf, data: &[u8]) -> Result<(), GfxError> {
         let path = path.canonicalize()?;
+        note_write(&path);
         let mut file = fs::OpenOptions::new()
             .write(true)
             .open(&path)
@@ -459,26 +870,55 @@ impl Device {
         Ok(())
     }
 
+    /// The power status from `power/runtime_status` and `power_state`, see `derive_power`
+    /// for what it means for each vendor
     pub fn get_runtime_status(&self) -> Result<GfxPower, GfxError> {
-        let mut path = self.dev_path.clone();
-        path.push("power");
-        path.push("runtime_status");
-        trace!("get_runtime_status: {path:?}");
-        match Self::read_file(path) {
-            Ok(inner) => GfxPower::from_str(inner.as_str()),
-            Err(_) => Ok(GfxPower::Off),
-        }
+        let runtime_status = Self::read_file(self.dev_path.join("power/runtime_status")).ok();
+        let power_state = Self::read_file(self.dev_path.join("power_state")).ok();
+        trace!(
+            "get_runtime_status: {:?} runtime_status {runtime_status:?}, power_state {power_state:?}",
+            self.dev_path
+        );
+        Ok(derive_power(
+            self.vendor,
+            runtime_status.as_deref(),
+            power_state.as_deref(),
+        ))
     }
 
     pub fn driver(&self) -> std::io::Result<PathBuf> {
         fs::canonicalize(self.dev_path.join("driver"))
     }
 
-    pub fn unbind(&self) -> Result<(), GfxError> {
+    /// The driver set in `driver_override`, which is the only one that can bind the
+    /// function. `None` if there is none, or the function has no `driver_override`.
+    pub fn driver_override(&self) -> Option<String> {
+        let value = Self::read_file(self.dev_path.join("driver_override")).ok()?;
+        let value = value.trim();
+        if value.is_empty() || value == "(null)" {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    }
+
+    /// Set `driver_override` to `driver`, or clear it with `None`. It lasts until cleared
+    /// or reboot, so supergfxd sets it through `DriverOverrides` which clears it again.
+    pub fn set_driver_override(&self, driver: Option<&str>) -> Result<(), GfxError> {
+        trace!("set_driver_override: {} {driver:?}", self.name);
+        let path = self.dev_path.join("driver_override");
+        note_write(&path);
+        fs::write(&path, driver.unwrap_or("\n")).map_err(|e| GfxError::from_io(e, path))
+    }
+
+    pub async fn unbind(&self) -> Result<(), GfxError> {
         if let Ok(mut path) = self.driver() {
             if path.exists() {
                 path.push("unbind");
-                return Self::write_file(path, self.name.as_bytes());
+                if let Err(err) = Self::write_file(path, self.name.as_bytes()) {
+                    return Err(self.busy_error("unbind", err).await);
+                }
+                return Ok(());
             }
         }
         info!(
@@ -488,11 +928,14 @@ impl Device {
         Ok(())
     }
 
-    pub fn remove(&self) -> Result<(), GfxError> {
+    pub async fn remove(&self) -> Result<(), GfxError> {
         if self.dev_path.exists() {
             let mut path = self.dev_path.clone();
             path.push("remove");
-            return Self::write_file(path, "1".as_bytes());
+            if let Err(err) = Self::write_file(path, "1".as_bytes()) {
+                return Err(self.busy_error("remove", err).await);
+            }
+            return Ok(());
         }
         info!(
             "remove path {:?} did not exist, device removed already?",
@@ -500,6 +943,21 @@ impl Device {
         );
         Ok(())
     }
+
+    /// `err` from `action` on this function with what keeps it busy, see `device_busy`
+    async fn busy_error(&self, action: &str, err: GfxError) -> GfxError {
+        let runtime_status = Self::read_file(self.dev_path.join("power/runtime_status"))
+            .ok()
+            .map(|status| status.trim().to_string());
+        device_busy(
+            action,
+            &self.name,
+            driver_name(&self.dev_path),
+            runtime_status,
+            err,
+        )
+        .await
+    }
 }
 
 /// Control whether a device uses, or does not use, runtime power management.
@@ -531,20 +989,197 @@ impl From<&str> for RuntimePowerManagement {
     }
 }
 
+/// A PCI function of the dGPU as it is now, for the `Devices` dbus method
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct DeviceInfo {
+    /// Such as `10de:1f9d`
+    pub pci_id: String,
+    /// Such as `0000:01:00.0`
+    pub sysname: String,
+    pub vendor: GfxVendor,
+    /// The VGA function, the others are such as its audio or USB-C controller
+    pub is_dgpu: bool,
+    pub power: GfxPower,
+    /// The driver bound, empty if none
+    pub driver: String,
+}
+
+impl DeviceInfo {
+    pub fn of(dev: &Device) -> Self {
+        Self {
+            pci_id: dev.pci_id().to_string(),
+            sysname: dev.name().to_string(),
+            vendor: dev.vendor(),
+            is_dgpu: dev.is_dgpu(),
+            power: dev.get_runtime_status().unwrap_or(GfxPower::Unknown),
+            driver: driver_name(dev.dev_path()).unwrap_or_default(),
+        }
+    }
+}
+
+/// `devices` as a table with a header, for `supergfxctl --devices`
+pub fn render_devices(devices: &[DeviceInfo]) -> String {
+    if devices.is_empty() {
+        return "No dGPU functions are present\n".to_string();
+    }
+    let rows: Vec<[String; 6]> = devices
+        .iter()
+        .map(|dev| {
+            [
+                dev.sysname.clone(),
+                dev.pci_id.clone(),
+                <&str>::from(dev.vendor).to_string(),
+                if dev.is_dgpu { "dGPU" } else { "" }.to_string(),
+                <&str>::from(&dev.power).to_string(),
+                dev.driver.clone(),
+            ]
+        })
+        .collect();
+    let header = ["Device", "PCI id", "Vendor", "Role", "Power", "Driver"].map(String::from);
+    let mut widths = [0; 6];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        out += line.join("  ").trim_end();
+        out += "\n";
+    }
+    out
+}
+
+/// The graphics devices as found by one discovery. Never changed once made, a refresh
+/// makes a new one with the next generation.
+#[derive(Debug)]
+pub struct DeviceSnapshot {
+    vendor: GfxVendor,
+    /// Index of the dGPU in `devices`. Not trusted to be in range, use `dgpu()`.
+    dgpu_index: usize,
+    devices: Vec<Device>,
+    /// Increased each time the devices are rediscovered
+    generation: u64,
+    /// An iGPU was found alongside the dGPU, see `igpu_present_in`. Always set if there is
+    /// no dGPU.
+    igpu_present: bool,
+}
+
+impl DeviceSnapshot {
+    pub fn vendor(&self) -> GfxVendor {
+        self.vendor
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The device at `dgpu_index`, which may not be a dGPU if none was found
+    fn tracked(&self) -> Option<&Device> {
+        self.devices.get(self.dgpu_index)
+    }
+
+    /// The tracked dGPU, `None` if there is none
+    pub fn dgpu(&self) -> Option<&Device> {
+        self.tracked().filter(|dev| dev.is_dgpu())
+    }
+}
+
 /// Collection of all graphics devices. Functions intend to work on the device
 /// determined to be the discreet GPU only.
+///
+/// The devices are held in a `DeviceSnapshot` which is swapped whole on refresh, so a clone
+/// is a cheap and consistent view that can be read without holding the lock on the
+/// original across sysfs IO.
 #[derive(Clone)]
 pub struct DiscreetGpu {
-    vendor: GfxVendor,
-    dgpu_index: usize,
-    devices: Vec<Device>,
+    snapshot: Arc<DeviceSnapshot>,
+    /// The `ignored_functions` of the config, kept across refreshes
+    ignored: Vec<String>,
+    /// The driver stack of an Nvidia dGPU, kept across refreshes while none is loaded
+    nvidia_driver: NvidiaDriver,
+    /// What kind of machine this is, read once in `new`
+    system_class: SystemClass,
+    /// The `force_enable_on_desktop` of the config, kept across refreshes
+    force_enable_on_desktop: bool,
 }
 
 impl DiscreetGpu {
-    pub fn new() -> Result<DiscreetGpu, GfxError> {
-        info!("DiscreetGpu::new: Rescanning PCI bus");
+    /// Find the dGPU, rescanning the PCI bus first if `rescan` is set so functions removed
+    /// by an earlier switch are found again
+    pub fn new(rescan: bool) -> Result<DiscreetGpu, GfxError> {
+        if rescan {
+            info!("DiscreetGpu::new: Rescanning PCI bus");
+            rescan_pci_bus()?;
+        }
+        let mut dgpu = Self::from_snapshot(Self::discover(0));
+        dgpu.detect_nvidia_driver();
+        dgpu.system_class = SystemClass::read();
+        info!("DiscreetGpu::new: {:?}", dgpu.system_class);
+        Ok(dgpu)
+    }
+
+    /// Rescan the PCI bus and rediscover the devices, swapping in a new snapshot
+    pub fn refresh(&mut self) -> Result<(), GfxError> {
+        info!("DiscreetGpu::refresh: Rescanning PCI bus");
         rescan_pci_bus()?;
+        self.snapshot = Arc::new(Self::discover(self.generation() + 1));
+        self.detect_nvidia_driver();
+        Ok(())
+    }
+
+    /// Look for the loaded Nvidia driver. What was found before is kept while neither is
+    /// loaded, such as after switching to Integrated.
+    fn detect_nvidia_driver(&mut self) {
+        if !self.is_nvidia() {
+            return;
+        }
+        let driver = nvidia_driver_in(Path::new("/"));
+        if driver != NvidiaDriver::None && driver != self.nvidia_driver {
+            info!("DiscreetGpu: the dGPU is run by the {driver:?} driver");
+            self.nvidia_driver = driver;
+        }
+    }
+
+    /// The driver stack of an Nvidia dGPU, `NvidiaDriver::None` until one was seen loaded
+    pub fn nvidia_driver(&self) -> NvidiaDriver {
+        self.nvidia_driver
+    }
+
+    /// Use `driver` as if it was detected, for testing without sysfs
+    #[cfg(test)]
+    pub(crate) fn with_nvidia_driver(mut self, driver: NvidiaDriver) -> Self {
+        self.nvidia_driver = driver;
+        self
+    }
+
+    /// Use `system_class` as if it was read, for testing without sysfs
+    #[cfg(test)]
+    pub(crate) fn with_system_class(mut self, system_class: SystemClass) -> Self {
+        self.system_class = system_class;
+        self
+    }
+
+    fn from_snapshot(snapshot: DeviceSnapshot) -> Self {
+        Self {
+            snapshot: Arc::new(snapshot),
+            ignored: Vec::new(),
+            nvidia_driver: NvidiaDriver::None,
+            system_class: SystemClass::default(),
+            force_enable_on_desktop: false,
+        }
+    }
 
+    fn discover(generation: u64) -> DeviceSnapshot {
         if let Ok(device) = Device::find() {
             let mut vendor = GfxVendor::Unknown;
             let mut dgpu_index = 0;
@@ -554,13 +1189,21 @@ impl DiscreetGpu {
                     vendor = dev.vendor();
                 }
             }
-            Ok(Self {
+            let igpu_present = match device.get(dgpu_index).filter(|dev| dev.is_dgpu()) {
+                Some(dgpu) => igpu_present_in(Path::new(PCI_DEVICES_PATH), dgpu.name()),
+                None => true,
+            };
+            if !igpu_present {
+                warn!("DiscreetGpu::new: no iGPU found, it may be turned off in the BIOS");
+            }
+            DeviceSnapshot {
                 vendor,
                 dgpu_index,
                 devices: device,
-            })
+                generation,
+                igpu_present,
+            }
         } else {
-            warn!("DiscreetGpu::new: no devices??");
             let mut vendor = GfxVendor::Unknown;
             if asus_dgpu_disable_exists() && asus_dgpu_disabled().unwrap_or(false) {
                 warn!("ASUS dGPU appears to be disabled");
@@ -574,54 +1217,227 @@ impl DiscreetGpu {
             {
                 warn!("ASUS GPU MUX is in discreet mode");
                 vendor = GfxVendor::Nvidia;
+            } else if asus_dgpu_disable_exists() || asus_gpu_mux_exists() {
+                warn!("DiscreetGpu::new: no devices??");
+            } else {
+                info!("DiscreetGpu::new: no dGPU or ASUS dGPU controls found");
             }
-            Ok(Self {
+            DeviceSnapshot {
                 vendor,
                 dgpu_index: 0,
                 devices: Vec::new(),
-            })
+                generation,
+                igpu_present: true,
+            }
         }
     }
 
+    /// A dGPU with no devices, for testing without sysfs
+    #[cfg(test)]
+    pub(crate) fn mock(vendor: GfxVendor) -> Self {
+        Self::mock_devices(vendor, 0, Vec::new(), 0)
+    }
+
+    /// A dGPU with the given devices, for testing without sysfs. `dgpu_index` isn't checked.
+    #[cfg(test)]
+    pub(crate) fn mock_devices(
+        vendor: GfxVendor,
+        dgpu_index: usize,
+        devices: Vec<Device>,
+        generation: u64,
+    ) -> Self {
+        Self::from_snapshot(DeviceSnapshot {
+            vendor,
+            dgpu_index,
+            devices,
+            generation,
+            igpu_present: true,
+        })
+    }
+
+    /// The same devices with no iGPU found, for testing without sysfs
+    #[cfg(test)]
+    pub(crate) fn without_igpu(mut self) -> Self {
+        self.snapshot = Arc::new(DeviceSnapshot {
+            vendor: self.vendor(),
+            dgpu_index: self.snapshot.dgpu_index,
+            devices: self.devices().to_vec(),
+            generation: self.generation(),
+            igpu_present: false,
+        });
+        self
+    }
+
+    /// Swap in new devices as `refresh` does, for testing without sysfs
+    #[cfg(test)]
+    pub(crate) fn set_mock_devices(&mut self, dgpu_index: usize, devices: Vec<Device>) {
+        self.snapshot = Arc::new(DeviceSnapshot {
+            vendor: self.vendor(),
+            dgpu_index,
+            devices,
+            generation: self.generation() + 1,
+            igpu_present: self.igpu_present(),
+        });
+    }
+
+    /// The current devices. They stay the same for as long as the snapshot is held, even if
+    /// this is refreshed.
+    pub fn snapshot(&self) -> Arc<DeviceSnapshot> {
+        self.snapshot.clone()
+    }
+
     pub fn vendor(&self) -> GfxVendor {
-        self.vendor
+        self.snapshot.vendor
     }
 
     pub fn devices(&self) -> &[Device] {
-        &self.devices
+        &self.snapshot.devices
+    }
+
+    /// Increased each time the devices are rediscovered, so a change of topology can be seen
+    pub fn generation(&self) -> u64 {
+        self.snapshot.generation
+    }
+
+    /// Leave the functions named by `entries` alone, see `ignored_entry_matches`
+    pub fn set_ignored_functions(&mut self, entries: Vec<String>) {
+        self.ignored = entries;
+    }
+
+    /// The function is in `ignored_functions`. The dGPU function itself is never ignored.
+    pub fn is_ignored(&self, dev: &Device) -> bool {
+        !dev.is_dgpu()
+            && self
+                .ignored
+                .iter()
+                .any(|entry| ignored_entry_matches(entry, dev.name()))
+    }
+
+    /// The functions supergfxd manages, all but those in `ignored_functions`. Everything which
+    /// changes the functions, or expects them in a state afterwards, goes through this.
+    pub fn managed_devices(&self) -> impl DoubleEndedIterator<Item = &Device> + '_ {
+        self.devices().iter().filter(|dev| !self.is_ignored(dev))
+    }
+
+    /// A warning for each entry of `ignored_functions` which matches none of the functions,
+    /// or only the dGPU function, which can't be ignored
+    pub fn check_ignored_functions(&self) -> Vec<String> {
+        let names: Vec<&str> = self.devices().iter().map(|dev| dev.name()).collect();
+        self.ignored
+            .iter()
+            .filter_map(|entry| {
+                let matched: Vec<&Device> = self
+                    .devices()
+                    .iter()
+                    .filter(|dev| ignored_entry_matches(entry, dev.name()))
+                    .collect();
+                if matched.is_empty() {
+                    Some(format!(
+                        "ignored_functions: {entry:?} matches none of the dGPU functions ({})",
+                        names.join(", ")
+                    ))
+                } else if matched.iter().all(|dev| dev.is_dgpu()) {
+                    Some(format!(
+                        "ignored_functions: {entry:?} is the dGPU {}, which can't be ignored",
+                        matched[0].name()
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The driver bound to the dGPU, `None` if there is none or no dGPU is tracked
+    pub fn dgpu_driver(&self) -> Option<String> {
+        self.snapshot
+            .dgpu()
+            .and_then(|dev| driver_name(dev.dev_path()))
+    }
+
+    /// Whether an iGPU was found alongside the dGPU when the devices were discovered
+    pub fn igpu_present(&self) -> bool {
+        self.snapshot.igpu_present
+    }
+
+    /// What kind of machine this is, see `SystemClass`
+    pub fn system_class(&self) -> SystemClass {
+        self.system_class
+    }
+
+    /// Switch on a desktop with a single GPU anyway, see `single_gpu_desktop`
+    pub fn set_force_enable_on_desktop(&mut self, force: bool) {
+        self.force_enable_on_desktop = force;
+    }
+
+    /// This is a desktop with no iGPU, so the dGPU is its only GPU and turning it off
+    /// leaves no display, unless `force_enable_on_desktop` is set for a desktop with a MUX or
+    /// an eGPU
+    pub fn single_gpu_desktop(&self) -> bool {
+        self.system_class.is_desktop() && !self.igpu_present() && !self.force_enable_on_desktop
+    }
+
+    /// Whether the tracked dGPU is still in sysfs, `None` if no dGPU is tracked
+    pub fn dgpu_present(&self) -> Option<bool> {
+        self.snapshot.dgpu().map(|dev| dev.dev_path().exists())
+    }
+
+    /// The connectors of the dGPU with something plugged in, e.g. `HDMI-A-1`. Empty if there
+    /// are none or they can't be read.
+    pub fn connected_outputs(&self) -> Vec<String> {
+        self.snapshot
+            .dgpu()
+            .and_then(|dev| find_connected_displays(dev.dev_path()).ok())
+            .unwrap_or_default()
+    }
+
+    /// The PCIe link state of the dGPU and the port it is on, read without waking it. `None`
+    /// if no dGPU is tracked.
+    pub fn link_info(&self) -> Option<LinkInfo> {
+        self.snapshot
+            .dgpu()
+            .map(|dev| LinkInfo::read(dev.dev_path(), Path::new(ASPM_POLICY_PATH)))
     }
 
     pub fn is_nvidia(&self) -> bool {
-        self.vendor == GfxVendor::Nvidia
+        self.vendor() == GfxVendor::Nvidia
     }
 
     pub fn is_amd(&self) -> bool {
-        self.vendor == GfxVendor::Amd
+        self.vendor() == GfxVendor::Amd
     }
 
     pub fn is_intel(&self) -> bool {
-        self.vendor == GfxVendor::Intel
+        self.vendor() == GfxVendor::Intel
     }
 
     pub fn get_runtime_status(&self) -> Result<GfxPower, GfxError> {
-        if !self.devices.is_empty() {
-            trace!("get_runtime_status: {:?}", self.devices[self.dgpu_index]);
-            if self.vendor == GfxVendor::AsusDgpuDisabled {
+        self.runtime_status_in(&Sysfs::system())
+    }
+
+    /// As `get_runtime_status` with the ASUS attributes of `sysfs`
+    pub(crate) fn runtime_status_in(&self, sysfs: &Sysfs) -> Result<GfxPower, GfxError> {
+        let vendor = self.vendor();
+        if let Some(tracked) = self.snapshot.tracked() {
+            trace!("get_runtime_status: {:?}", tracked);
+            if vendor == GfxVendor::AsusDgpuDisabled {
                 //warn!("ASUS dgpu status: {:?}", self.vendor);
                 return Ok(GfxPower::AsusDisabled);
-            } else if self.vendor != GfxVendor::Unknown {
-                return self.devices[self.dgpu_index].get_runtime_status();
+            } else if vendor != GfxVendor::Unknown {
+                return tracked.get_runtime_status();
             }
-        } else if asus_dgpu_disable_exists() {
-            if let Ok(disabled) = asus_dgpu_disabled() {
+        } else if !self.devices().is_empty() {
+            warn!("get_runtime_status: the dGPU index is out of range");
+        } else if sysfs.dgpu_disable.exists() {
+            if let Ok(disabled) = sysfs.dgpu_disable.disabled() {
                 trace!("No dGPU tracked. Maybe booted with dgpu_disable=1 or gpu_mux_mode=0");
                 // info!("Is ASUS laptop, dgpu_disable = {disabled}");
                 if disabled {
                     return Ok(GfxPower::AsusDisabled);
                 }
             }
-        } else if asus_gpu_mux_exists() {
-            if let Ok(mode) = asus_gpu_mux_mode() {
+        } else if sysfs.gpu_mux.exists() {
+            if let Ok(mode) = sysfs.gpu_mux.read() {
                 if mode == AsusGpuMuxMode::Discreet {
                     return Ok(GfxPower::AsusMuxDiscreet);
                 }
@@ -634,22 +1450,22 @@ impl DiscreetGpu {
     }
 
     pub fn set_runtime_pm(&self, pm: RuntimePowerManagement) -> Result<(), GfxError> {
-        debug!("set_runtime_pm: pm = {:?}, {:?}", pm, self.devices);
-        if self.devices.is_empty() {
+        debug!("set_runtime_pm: pm = {:?}, {:?}", pm, self.devices());
+        if self.devices().is_empty() {
             warn!("set_runtime_pm: Did not have dGPU handle");
             return Ok(());
         }
         if !matches!(
-            self.vendor,
+            self.vendor(),
             GfxVendor::Unknown | GfxVendor::AsusDgpuDisabled
         ) {
-            for dev in self.devices.iter() {
+            for dev in self.managed_devices() {
                 dev.set_runtime_pm(pm)?;
                 info!("set_runtime_pm: Set PM on {:?} to {pm:?}", dev.dev_path());
             }
             return Ok(());
         }
-        if self.vendor == GfxVendor::AsusDgpuDisabled {
+        if self.vendor() == GfxVendor::AsusDgpuDisabled {
             info!("set_runtime_pm: ASUS dgpu_disable set, ignoring");
             return Ok(());
         }
@@ -658,8 +1474,15 @@ impl DiscreetGpu {
         ))
     }
 
+    /// The dGPU is in a slot with kernel hotplug support
+    pub fn hotplug_capable(&self) -> bool {
+        self.devices()
+            .iter()
+            .any(|dev| dev.is_dgpu() && dev.hotplug_path.is_some())
+    }
+
     pub fn set_hotplug(&self, state: HotplugState) -> Result<(), GfxError> {
-        for dev in self.devices.iter() {
+        for dev in self.devices().iter() {
             if dev.is_dgpu() {
                 dev.set_hotplug(state)?;
                 break;
@@ -668,15 +1491,15 @@ impl DiscreetGpu {
         Ok(())
     }
 
-    pub fn unbind(&self) -> Result<(), GfxError> {
-        if self.vendor != GfxVendor::Unknown {
-            for dev in self.devices.iter().rev() {
-                dev.unbind()?;
+    pub async fn unbind(&self) -> Result<(), GfxError> {
+        if self.vendor() != GfxVendor::Unknown {
+            for dev in self.managed_devices().rev() {
+                dev.unbind().await?;
                 info!("Unbound {:?}", dev.dev_path())
             }
             return Ok(());
         }
-        if self.vendor == GfxVendor::AsusDgpuDisabled {
+        if self.vendor() == GfxVendor::AsusDgpuDisabled {
             return Ok(());
         }
         Err(GfxError::NotSupported(
@@ -684,10 +1507,10 @@ impl DiscreetGpu {
         ))
     }
 
-    pub fn remove(&self) -> Result<(), GfxError> {
-        if self.vendor != GfxVendor::Unknown {
-            for dev in self.devices.iter().rev() {
-                dev.remove()?;
+    pub async fn remove(&self) -> Result<(), GfxError> {
+        if self.vendor() != GfxVendor::Unknown {
+            for dev in self.managed_devices().rev() {
+                dev.remove().await?;
                 info!("Removed {:?}", dev.dev_path())
             }
             return Ok(());
@@ -697,20 +1520,25 @@ impl DiscreetGpu {
         ))
     }
 
-    pub fn unbind_remove(&self) -> Result<(), GfxError> {
-        self.unbind()?;
-        self.remove()
+    pub async fn unbind_remove(&self) -> Result<(), GfxError> {
+        self.unbind().await?;
+        self.remove().await
     }
 
-    pub fn do_driver_action(&self, action: DriverAction) -> Result<(), GfxError> {
+    /// Do `action` for each of the dGPU drivers, each tried up to `attempts` times
+    pub async fn do_driver_action(
+        &self,
+        action: DriverAction,
+        attempts: u32,
+    ) -> Result<(), GfxError> {
         debug!(
             "do_driver_action: action = {}, {:?}",
             <&str>::from(action),
-            self.devices
+            self.devices()
         );
         if self.is_nvidia() {
-            for driver in NVIDIA_DRIVERS.iter() {
-                do_driver_action(driver, action)?;
+            for driver in self.nvidia_driver.modules() {
+                do_driver_action(driver, action, attempts).await?;
             }
         }
         Ok(())
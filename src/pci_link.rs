@@ -0,0 +1,185 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::trace;
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::pci_device::PciAddress;
+
+/// The ASPM policy chosen on the kernel command line or at runtime, the active one is in brackets
+pub const ASPM_POLICY_PATH: &str = "/sys/module/pcie_aspm/parameters/policy";
+
+/// Link state files in the `link/` directory of a PCIe device, each is `1` if enabled
+const ASPM_LINK_STATES: &[&str] = &[
+    "l0s_aspm",
+    "l1_aspm",
+    "l1_1_aspm",
+    "l1_2_aspm",
+    "l1_1_pcipm",
+    "l1_2_pcipm",
+    "clkpm",
+];
+
+/// Link speed and width attributes. These read the config space of the device.
+const LINK_SPEED_ATTRS: &[&str] = &[
+    "current_link_speed",
+    "current_link_width",
+    "max_link_speed",
+    "max_link_width",
+];
+
+/// Whether a sysfs attribute of a PCI device can be read while the device is runtime
+/// suspended without waking it or reading garbage from a powered off device. Attributes
+/// backed by state the kernel already holds are safe, config space reads are not.
+pub(crate) fn readable_while_suspended(attr: &str) -> bool {
+    match attr.split_once('/') {
+        Some(("power", _)) => true,
+        Some(("link", state)) => ASPM_LINK_STATES.contains(&state),
+        Some(_) => false,
+        None => matches!(attr, "vendor" | "device" | "class"),
+    }
+}
+
+/// The state of one end of the dGPU link
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct PciLinkState {
+    /// System name given by kernel, e.g `0000:01:00.0`
+    pub name: String,
+    /// `power/runtime_status`, e.g `active` or `suspended`
+    pub runtime_status: String,
+    /// e.g `8.0 GT/s PCIe`, empty if not read
+    pub current_speed: String,
+    /// e.g `8`, empty if not read
+    pub current_width: String,
+    pub max_speed: String,
+    pub max_width: String,
+    /// Enabled link states from `link/`, e.g `l1_aspm` or `l1_2_aspm`
+    pub aspm_enabled: Vec<String>,
+}
+
+/// PCIe link state of the dGPU and the port it hangs off
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct LinkInfo {
+    pub dgpu: PciLinkState,
+    /// The platform port upstream of the dGPU. AMD dGPUs have an internal switch which is
+    /// skipped over. Empty name if there is none.
+    pub port: PciLinkState,
+    /// The ASPM policy in effect, e.g `default` or `powersupersave`
+    pub aspm_policy: String,
+    /// Why some data is missing, such as attributes skipped to avoid waking the dGPU
+    pub notes: Vec<String>,
+}
+
+impl LinkInfo {
+    /// Read the link state of the device at `dgpu_path` without waking it
+    pub fn read(dgpu_path: &Path, policy_path: &Path) -> Self {
+        let mut notes = Vec::new();
+        let dgpu = read_link_state(dgpu_path, &mut notes);
+        let port = match upstream_port(dgpu_path) {
+            Some(port) => read_link_state(&port, &mut notes),
+            None => {
+                notes.push("No upstream PCIe port found".to_string());
+                PciLinkState::default()
+            }
+        };
+        let aspm_policy = fs::read_to_string(policy_path)
+            .ok()
+            .and_then(|policy| active_aspm_policy(&policy))
+            .unwrap_or_default();
+        Self {
+            dgpu,
+            port,
+            aspm_policy,
+            notes,
+        }
+    }
+}
+
+/// Pick the policy in brackets out of `default [performance] powersave powersupersave`
+pub(crate) fn active_aspm_policy(policy: &str) -> Option<String> {
+    policy
+        .split_whitespace()
+        .find_map(|p| p.strip_prefix('[').and_then(|p| p.strip_suffix(']')))
+        .map(|p| p.to_string())
+}
+
+/// Find the port the device is plugged in to. Bridges with the same vendor as the device
+/// are part of the card (AMD dGPUs have an internal switch) and are skipped.
+pub(crate) fn upstream_port(dev_path: &Path) -> Option<PathBuf> {
+    let dev_path = dev_path.canonicalize().ok()?;
+    let vendor = read_attr(&dev_path, "vendor");
+    let mut parent = dev_path.parent()?;
+    loop {
+        parent
+            .file_name()
+            .and_then(|name| PciAddress::parse(&name.to_string_lossy()))?;
+        let grandparent = parent.parent()?;
+        let grandparent_is_pci = grandparent
+            .file_name()
+            .and_then(|name| PciAddress::parse(&name.to_string_lossy()))
+            .is_some();
+        if vendor.is_some() && read_attr(parent, "vendor") == vendor && grandparent_is_pci {
+            parent = grandparent;
+        } else {
+            return Some(parent.to_path_buf());
+        }
+    }
+}
+
+fn read_attr(dev_path: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dev_path.join(attr))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+fn read_link_state(dev_path: &Path, notes: &mut Vec<String>) -> PciLinkState {
+    let name = dev_path
+        .canonicalize()
+        .unwrap_or_else(|_| dev_path.to_path_buf())
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    trace!("read_link_state: {dev_path:?}");
+    let runtime_status = read_attr(dev_path, "power/runtime_status").unwrap_or_default();
+    // `unsupported` means runtime PM is off for the device, so it is always powered
+    let awake = matches!(runtime_status.as_str(), "active" | "unsupported");
+
+    let mut speeds = Vec::with_capacity(LINK_SPEED_ATTRS.len());
+    for attr in LINK_SPEED_ATTRS {
+        if awake || readable_while_suspended(attr) {
+            speeds.push(read_attr(dev_path, attr).unwrap_or_default());
+        } else {
+            speeds.push(String::new());
+        }
+    }
+    if !awake {
+        let status = if runtime_status.is_empty() {
+            "unknown"
+        } else {
+            &runtime_status
+        };
+        notes.push(format!(
+            "{name} is {status}, link speed and width were not read to avoid waking it"
+        ));
+    }
+
+    let aspm_enabled = ASPM_LINK_STATES
+        .iter()
+        .filter(|state| read_attr(dev_path, &format!("link/{state}")).as_deref() == Some("1"))
+        .map(|state| state.to_string())
+        .collect();
+
+    let mut speeds = speeds.into_iter();
+    PciLinkState {
+        name,
+        runtime_status,
+        current_speed: speeds.next().unwrap_or_default(),
+        current_width: speeds.next().unwrap_or_default(),
+        max_speed: speeds.next().unwrap_or_default(),
+        max_width: speeds.next().unwrap_or_default(),
+        aspm_enabled,
+    }
+}
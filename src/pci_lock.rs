@@ -0,0 +1,200 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    os::unix::io::AsRawFd,
+    path::Path,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::time::{sleep, Instant};
+
+use crate::{error::GfxError, pci_device::PciAddress, sandbox::note_write};
+
+/// Advisory lock taken with `flock(LOCK_EX)` around PCI remove and rescan. Other tools which
+/// remove or rescan PCI devices (such as bolt or udev rules) can take it to avoid racing us.
+pub const PCI_LOCK_PATH: &str = "/run/supergfxd/pci.lock";
+/// How long to wait for another holder of the lock before going ahead without it
+const PCI_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const PCI_LOCK_POLL: Duration = Duration::from_millis(100);
+
+/// Time between the enumerations compared to decide the bus has settled
+pub(crate) const PCI_SETTLE_INTERVAL: Duration = Duration::from_millis(250);
+/// Enumerations taken before giving up on the bus settling
+pub(crate) const PCI_SETTLE_MAX_CHECKS: u32 = 12;
+
+const PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+
+/// Held while removing or rescanning PCI devices, the lock is released on drop
+pub struct PciLock {
+    file: Option<File>,
+}
+
+impl PciLock {
+    /// Take the lock at `PCI_LOCK_PATH`, waiting up to `PCI_LOCK_TIMEOUT` for another holder.
+    /// If the lock can't be taken a warning is logged and the work goes ahead unlocked, as
+    /// a stuck external tool must not prevent switching.
+    pub async fn acquire() -> Self {
+        Self::acquire_at(Path::new(PCI_LOCK_PATH), PCI_LOCK_TIMEOUT).await
+    }
+
+    pub(crate) async fn acquire_at(path: &Path, timeout: Duration) -> Self {
+        note_write(path);
+        let file = match path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(path)
+            }) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("PciLock: could not open {}: {err}", path.display());
+                return Self { file: None };
+            }
+        };
+
+        let start = Instant::now();
+        let mut waited = false;
+        loop {
+            // SAFETY: the fd is valid for the life of `file`
+            let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+            if res == 0 {
+                if waited {
+                    info!(
+                        "PciLock: waited {}ms for another holder of {}",
+                        start.elapsed().as_millis(),
+                        path.display()
+                    );
+                }
+                return Self { file: Some(file) };
+            }
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                warn!("PciLock: could not lock {}: {err}", path.display());
+                return Self { file: None };
+            }
+            if start.elapsed() >= timeout {
+                warn!(
+                    "PciLock: {} is still held after {}s, continuing without it",
+                    path.display(),
+                    timeout.as_secs()
+                );
+                return Self { file: None };
+            }
+            if !waited {
+                debug!(
+                    "PciLock: {} is held by another process, waiting",
+                    path.display()
+                );
+                waited = true;
+            }
+            sleep(PCI_LOCK_POLL).await;
+        }
+    }
+
+    /// Whether the lock is held, `false` if it was skipped
+    pub fn is_held(&self) -> bool {
+        self.file.is_some()
+    }
+}
+
+/// Tracks successive enumerations of the dGPU functions after a rescan. The bus has settled
+/// once every expected function is present and two enumerations in a row are the same.
+#[derive(Debug, Default)]
+pub(crate) struct SettleTracker {
+    expected: Vec<String>,
+    last: Option<Vec<String>>,
+    /// Enumerations seen so far
+    pub checks: u32,
+    /// Times an enumeration differed from the one before it
+    pub changes: u32,
+}
+
+impl SettleTracker {
+    pub fn new(expected: &[String]) -> Self {
+        Self {
+            expected: expected.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the next enumeration, returns `true` once the bus has settled
+    pub fn push(&mut self, mut snapshot: Vec<String>) -> bool {
+        snapshot.sort();
+        self.checks += 1;
+        let settled = match self.last.as_ref() {
+            Some(last) if *last == snapshot => {
+                self.expected.iter().all(|name| snapshot.contains(name))
+            }
+            Some(_) => {
+                self.changes += 1;
+                false
+            }
+            None => false,
+        };
+        self.last = Some(snapshot);
+        settled
+    }
+}
+
+/// The PCI functions currently on the bus which belong to the same device as `dgpu`
+fn enumerate_functions(dgpu: &PciAddress) -> Vec<String> {
+    fs::read_dir(PCI_DEVICES_PATH)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| PciAddress::parse(name).map_or(false, |a| a.same_device(dgpu)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// After a rescan wait for all of `expected` (the names of the dGPU functions) to be back
+/// and for the enumeration to stop changing, which it may if another tool is also removing
+/// or rescanning devices.
+pub(crate) async fn wait_for_pci_settle(expected: &[String]) -> Result<(), GfxError> {
+    let dgpu = match expected.iter().find_map(|name| PciAddress::parse(name)) {
+        Some(dgpu) => dgpu,
+        None => return Ok(()),
+    };
+    wait_for_settle(expected, || enumerate_functions(&dgpu)).await
+}
+
+pub(crate) async fn wait_for_settle<F>(
+    expected: &[String],
+    mut enumerate: F,
+) -> Result<(), GfxError>
+where
+    F: FnMut() -> Vec<String>,
+{
+    let start = Instant::now();
+    let mut tracker = SettleTracker::new(expected);
+    while tracker.checks < PCI_SETTLE_MAX_CHECKS {
+        if tracker.push(enumerate()) {
+            if tracker.changes > 0 {
+                warn!(
+                    "wait_for_settle: PCI enumeration was unstable ({} changes), settled after {}ms",
+                    tracker.changes,
+                    start.elapsed().as_millis()
+                );
+            } else {
+                info!(
+                    "wait_for_settle: PCI bus settled after {}ms",
+                    start.elapsed().as_millis()
+                );
+            }
+            return Ok(());
+        }
+        sleep(PCI_SETTLE_INTERVAL).await;
+    }
+    warn!(
+        "wait_for_settle: PCI bus did not settle after {}ms ({} changes)",
+        start.elapsed().as_millis(),
+        tracker.changes
+    );
+    Err(GfxError::PciNotSettled)
+}
@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    buffers::BoundedQueue,
+    gpu_users::{dgpu_users, process_user},
+    pci_device::{DiscreetGpu, GfxPower},
+};
+
+/// How often the blockers are scanned again while the dGPU stays awake. Also the most
+/// often a scan is made, as it reads the fds of every process.
+pub(crate) const BLOCKER_REFRESH: Duration = Duration::from_secs(300);
+/// The most blockers kept from a scan
+pub(crate) const MAX_POWER_BLOCKERS: usize = 64;
+
+/// A process with the dGPU open while it was kept awake on battery
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct PowerBlocker {
+    pub pid: u32,
+    /// The process name from `/proc/<pid>/comm`
+    pub comm: String,
+    /// The user running it
+    pub user: String,
+    /// Seconds since the epoch when it was first seen keeping the dGPU awake
+    pub first_seen: u64,
+}
+
+/// Finds the processes with the dGPU open, so the watch can be tested
+pub(crate) trait BlockerScanner {
+    /// The processes found, `first_seen` is filled in by the watch
+    fn scan(&mut self) -> Vec<PowerBlocker>;
+}
+
+/// The fd scan of `gpu_users` on the running system
+pub(crate) struct SystemBlockerScanner<'a> {
+    pub dgpu: &'a DiscreetGpu,
+}
+
+impl BlockerScanner for SystemBlockerScanner<'_> {
+    fn scan(&mut self) -> Vec<PowerBlocker> {
+        dgpu_users(self.dgpu)
+            .into_iter()
+            .map(|user| PowerBlocker {
+                pid: user.pid,
+                user: process_user(user.pid),
+                comm: user.comm,
+                first_seen: 0,
+            })
+            .collect()
+    }
+}
+
+/// Decides when to look for the processes keeping the dGPU awake. Once it has been
+/// `Active` for `threshold` while that matters (Hybrid on battery) the processes with it
+/// open are scanned, then again every `BLOCKER_REFRESH` for as long as that lasts. Nothing
+/// is scanned while it is suspended or off, and the blockers are cleared once it is.
+#[derive(Debug)]
+pub(crate) struct BlockerWatch {
+    threshold: Duration,
+    /// When the dGPU was first seen `Active` since it was last suspended
+    active_since: Option<Instant>,
+    last_scan: Option<Instant>,
+    blockers: BoundedQueue<PowerBlocker>,
+}
+
+impl BlockerWatch {
+    pub(crate) fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            active_since: None,
+            last_scan: None,
+            blockers: BoundedQueue::new("power_blockers", MAX_POWER_BLOCKERS),
+        }
+    }
+
+    pub(crate) fn set_threshold(&mut self, threshold: Duration) {
+        self.threshold = threshold;
+    }
+
+    /// The processes found by the last scan
+    pub(crate) fn blockers(&self) -> Vec<PowerBlocker> {
+        self.blockers.iter().cloned().collect()
+    }
+
+    /// Record the dGPU `power` read at `now`, `wall` seconds since the epoch. `watched` is
+    /// whether a dGPU kept awake matters, such as Hybrid on battery. Returns `true` if the
+    /// blockers changed.
+    pub(crate) fn observe(
+        &mut self,
+        now: Instant,
+        wall: u64,
+        power: GfxPower,
+        watched: bool,
+        scanner: &mut dyn BlockerScanner,
+    ) -> bool {
+        if power != GfxPower::Active {
+            // Unknown is a failed read, not a sign the dGPU went to sleep
+            if power == GfxPower::Unknown {
+                return false;
+            }
+            self.active_since = None;
+            self.last_scan = None;
+            if self.blockers.is_empty() {
+                return false;
+            }
+            info!("PowerBlockers: the dGPU is {power:?}, clearing the blockers");
+            self.blockers.clear();
+            return true;
+        }
+        let since = *self.active_since.get_or_insert(now);
+        if !watched || self.threshold.is_zero() || now.duration_since(since) < self.threshold {
+            return false;
+        }
+        if self
+            .last_scan
+            .map_or(false, |last| now.duration_since(last) < BLOCKER_REFRESH)
+        {
+            return false;
+        }
+        self.last_scan = Some(now);
+        let mut found = scanner.scan();
+        for blocker in &mut found {
+            blocker.first_seen = self
+                .blockers
+                .iter()
+                .find(|old| old.pid == blocker.pid && old.comm == blocker.comm)
+                .map_or(wall, |old| old.first_seen);
+        }
+        if self.blockers.iter().eq(found.iter()) {
+            return false;
+        }
+        info!(
+            "PowerBlockers: the dGPU has been active for {}s on battery, held open by: {}",
+            now.duration_since(since).as_secs(),
+            found
+                .iter()
+                .map(|b| format!("{} ({}, {})", b.comm, b.pid, b.user))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.blockers.replace(found);
+        true
+    }
+}
@@ -0,0 +1,99 @@
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::pci_device::{GfxPower, GfxVendor};
+
+/// Increased each time what a `GfxPower` means for a vendor changes, so frontends can
+/// tell which table the daemon uses without going by its version
+pub const POWER_SEMANTICS_VERSION: u32 = 1;
+
+/// The `power_state` of a PCI device which has no power at all
+const D3COLD: &str = "D3cold";
+
+/// Whether `Off` is only reported with evidence of D3cold for `vendor`. For Nvidia a
+/// `runtime_status` which can't be read means the dGPU was removed from the bus by hotplug
+/// or `dgpu_disable`, as it always has.
+fn off_needs_d3cold(vendor: GfxVendor) -> bool {
+    !matches!(vendor, GfxVendor::Nvidia | GfxVendor::AsusDgpuDisabled)
+}
+
+/// The power status of a dGPU function of `vendor` from its sysfs attributes,
+/// `power/runtime_status` and `power_state`, each `None` if it couldn't be read.
+///
+/// For Nvidia a `runtime_status` which can't be read is `Off`. For the other vendors `Off`
+/// is only reported when `power_state` is `D3cold`, a suspended function which isn't is
+/// `Suspended`, and one which can't be read is `Unknown`.
+pub(crate) fn derive_power(
+    vendor: GfxVendor,
+    runtime_status: Option<&str>,
+    power_state: Option<&str>,
+) -> GfxPower {
+    if !off_needs_d3cold(vendor) {
+        return match runtime_status {
+            Some(status) => status.parse().unwrap_or(GfxPower::Unknown),
+            None => GfxPower::Off,
+        };
+    }
+    if power_state.map(str::trim) == Some(D3COLD) {
+        return GfxPower::Off;
+    }
+    match runtime_status.map(|s| s.parse().unwrap_or(GfxPower::Unknown)) {
+        Some(GfxPower::Off) | Some(GfxPower::Suspended) => GfxPower::Suspended,
+        Some(GfxPower::Active) => GfxPower::Active,
+        _ => GfxPower::Unknown,
+    }
+}
+
+/// What each `GfxPower` means for the dGPU of a vendor, from `PowerSemantics` on the dbus
+/// interface
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct PowerSemantics {
+    pub vendor: GfxVendor,
+    /// `POWER_SEMANTICS_VERSION` of the daemon
+    pub version: u32,
+    /// Every `GfxPower` with what it means for `vendor`
+    pub mapping: Vec<(GfxPower, String)>,
+}
+
+impl PowerSemantics {
+    pub fn for_vendor(vendor: GfxVendor) -> Self {
+        let (suspended, off, unknown) = if off_needs_d3cold(vendor) {
+            (
+                "runtime_status is suspended and power_state isn't D3cold, the dGPU may still draw some power",
+                "power_state is D3cold, the dGPU has no power",
+                "runtime_status couldn't be read or is neither active nor suspended",
+            )
+        } else {
+            (
+                "runtime_status is suspended, the dGPU is asleep with runtime power management",
+                "runtime_status couldn't be read, the dGPU was removed from the bus by hotplug or dgpu_disable",
+                "runtime_status is neither active nor suspended, such as resuming",
+            )
+        };
+        let meaning = |power: GfxPower| match power {
+            GfxPower::Active => "runtime_status is active",
+            GfxPower::Suspended => suspended,
+            GfxPower::Off => off,
+            GfxPower::AsusDisabled => "the dGPU is disabled with the ASUS dgpu_disable",
+            GfxPower::AsusMuxDiscreet => {
+                "the ASUS MUX is set to the dGPU, which drives the display"
+            }
+            GfxPower::Unknown => unknown,
+        };
+        Self {
+            vendor,
+            version: POWER_SEMANTICS_VERSION,
+            mapping: [
+                GfxPower::Active,
+                GfxPower::Suspended,
+                GfxPower::Off,
+                GfxPower::AsusDisabled,
+                GfxPower::AsusMuxDiscreet,
+                GfxPower::Unknown,
+            ]
+            .into_iter()
+            .map(|power| (power, meaning(power).to_string()))
+            .collect(),
+        }
+    }
+}
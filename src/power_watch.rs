@@ -0,0 +1,308 @@
+use std::{fmt, os::unix::io::AsRawFd, time::Duration};
+
+use log::{debug, info, trace, warn};
+use tokio::{
+    sync::mpsc::{channel, error::TrySendError, Receiver},
+    time::{sleep, timeout},
+};
+
+/// Poll interval when there are no udev events to wait on, the same as before events were
+/// used
+pub(crate) const POWER_POLL_FAST: Duration = Duration::from_secs(1);
+/// Keep-alive poll while udev events are being watched, for changes which emit none
+pub(crate) const POWER_POLL_KEEPALIVE: Duration = Duration::from_secs(10);
+/// How often the udev thread checks if the watcher has gone away
+const UDEV_POLL_MS: i32 = 1000;
+
+/// A udev event on a PCI function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DeviceEvent {
+    /// Such as `bind` or `change`
+    pub action: String,
+    /// Such as `0000:01:00.0`
+    pub function: String,
+}
+
+/// What woke the status notifier to read the dGPU power status
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum PowerTrigger {
+    /// The first read after starting
+    Start,
+    /// A udev event for one of the dGPU functions
+    Event,
+    /// The poll timer
+    Poll,
+}
+
+impl fmt::Display for PowerTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Start => write!(f, "start"),
+            Self::Event => write!(f, "udev event"),
+            Self::Poll => write!(f, "poll"),
+        }
+    }
+}
+
+/// Decides when the dGPU power status is read. With a udev event source status is read
+/// on each event, with a slow keep-alive poll. If the keep-alive poll finds a change the
+/// events missed, this platform doesn't emit them for power changes and the fast poll is
+/// used from then on. Without an event source it is the fast poll only.
+pub(crate) struct PowerWatch {
+    events: Option<Receiver<()>>,
+    /// A change was seen by a poll but not an event
+    missed_events: bool,
+    /// Transitions first seen after an event
+    pub event_transitions: u32,
+    /// Transitions first seen by a poll
+    pub poll_transitions: u32,
+    /// Automation is inhibited, the interval isn't changed
+    held: bool,
+    /// A change to the fast poll was skipped while held
+    held_skipped: bool,
+    /// The fast poll interval, `status_poll_ms` of the config
+    fast: Duration,
+}
+
+impl PowerWatch {
+    pub fn new(events: Option<Receiver<()>>) -> Self {
+        Self {
+            events,
+            missed_events: false,
+            event_transitions: 0,
+            poll_transitions: 0,
+            held: false,
+            held_skipped: false,
+            fast: POWER_POLL_FAST,
+        }
+    }
+
+    /// Use `fast` as the fast poll interval
+    pub fn set_fast_poll(&mut self, fast: Duration) {
+        self.fast = fast;
+    }
+
+    /// Keep the interval as it is while `held`, such as while automation is inhibited
+    pub fn set_held(&mut self, held: bool) {
+        self.held = held;
+        if !held {
+            self.held_skipped = false;
+        }
+    }
+
+    /// The time waited for an event before polling
+    pub fn interval(&self) -> Duration {
+        if self.events.is_some() && !self.missed_events {
+            POWER_POLL_KEEPALIVE
+        } else {
+            self.fast
+        }
+    }
+
+    /// Wait for the next event or poll
+    pub async fn wait(&mut self) -> PowerTrigger {
+        let interval = self.interval();
+        let rx = match self.events.as_mut() {
+            Some(rx) => rx,
+            None => {
+                sleep(interval).await;
+                return PowerTrigger::Poll;
+            }
+        };
+        match timeout(interval, rx.recv()).await {
+            Ok(Some(())) => {
+                // Several events from one change need only one read
+                while rx.try_recv().is_ok() {}
+                PowerTrigger::Event
+            }
+            Ok(None) => {
+                warn!("PowerWatch: udev monitor stopped, polling instead");
+                self.events = None;
+                PowerTrigger::Poll
+            }
+            Err(_) => PowerTrigger::Poll,
+        }
+    }
+
+    /// Record if the read after `trigger` found the status changed. Returns `true` the
+    /// first time a change to the fast poll is skipped while held.
+    pub fn record(&mut self, trigger: PowerTrigger, changed: bool) -> bool {
+        if !changed {
+            return false;
+        }
+        match trigger {
+            PowerTrigger::Start => {}
+            PowerTrigger::Event => self.event_transitions += 1,
+            PowerTrigger::Poll => {
+                self.poll_transitions += 1;
+                if self.events.is_some() && !self.missed_events && self.held {
+                    let first = !self.held_skipped;
+                    self.held_skipped = true;
+                    return first;
+                }
+                if self.events.is_some() && !self.missed_events {
+                    info!(
+                        "PowerWatch: a power change was only seen by polling, polling every {}ms",
+                        self.fast.as_millis()
+                    );
+                    self.missed_events = true;
+                }
+            }
+        }
+        debug!(
+            "PowerWatch: transitions seen by udev events: {}, by polling: {}",
+            self.event_transitions, self.poll_transitions
+        );
+        false
+    }
+}
+
+/// Watch udev for events on the PCI functions in `names`, such as `0000:01:00.0`. The
+/// monitor socket is blocking so it is read on its own thread, which ends when the
+/// receiver is dropped. `None` if the monitor can't be created.
+pub(crate) fn spawn_udev_monitor(names: Vec<String>) -> Option<Receiver<()>> {
+    if names.is_empty() {
+        return None;
+    }
+    spawn_monitor("udev power monitor", &["pci"], 1, move |event| {
+        let sysname = event.sysname().to_string_lossy();
+        names
+            .iter()
+            .any(|name| sysname == name.as_str())
+            .then_some(())
+    })
+}
+
+/// Watch udev for events on any power supply, such as AC being plugged in
+pub(crate) fn spawn_power_supply_monitor() -> Option<Receiver<()>> {
+    spawn_monitor("udev power supply monitor", &["power_supply"], 1, |_| {
+        Some(())
+    })
+}
+
+/// Watch udev for the events on the PCI functions in `names`, with what each was
+pub(crate) fn spawn_device_event_monitor(names: Vec<String>) -> Option<Receiver<DeviceEvent>> {
+    if names.is_empty() {
+        return None;
+    }
+    spawn_monitor("udev device event monitor", &["pci"], 16, move |event| {
+        let function = event.sysname().to_string_lossy().to_string();
+        names.contains(&function).then(|| DeviceEvent {
+            action: event.event_type().to_string(),
+            function,
+        })
+    })
+}
+
+/// Watch udev for USB and Thunderbolt devices being added or removed, and changes of power
+/// supplies, for the dock detection
+pub(crate) fn spawn_dock_monitor() -> Option<Receiver<()>> {
+    spawn_monitor(
+        "udev dock monitor",
+        &["usb", "thunderbolt", "power_supply"],
+        1,
+        |event| {
+            matches!(
+                event.event_type(),
+                udev::EventType::Add | udev::EventType::Remove | udev::EventType::Change
+            )
+            .then_some(())
+        },
+    )
+}
+
+/// PCI vendor ids an eGPU enclosure may hold a GPU of, as in the udev `PCI_ID`: Nvidia
+/// and AMD
+pub(crate) const EGPU_PCI_VENDORS: &[&str] = &["10DE", "1002"];
+
+/// Watch udev for PCI functions of an Nvidia or AMD GPU being added or removed, as when an
+/// eGPU enclosure is connected or disconnected
+pub(crate) fn spawn_egpu_monitor() -> Option<Receiver<()>> {
+    spawn_monitor("udev egpu monitor", &["pci"], 1, |event| {
+        if !matches!(
+            event.event_type(),
+            udev::EventType::Add | udev::EventType::Remove
+        ) {
+            return None;
+        }
+        let id = event.property_value("PCI_ID")?.to_string_lossy();
+        let vendor = id.split(':').next().unwrap_or_default();
+        EGPU_PCI_VENDORS
+            .iter()
+            .any(|wanted| vendor.eq_ignore_ascii_case(wanted))
+            .then_some(())
+    })
+}
+
+/// Watch udev for events in `subsystems`, sending what `filter` makes of each one it
+/// doesn't drop on a channel of `capacity`, on a thread called `thread`
+fn spawn_monitor<T, F>(
+    thread: &str,
+    subsystems: &'static [&'static str],
+    capacity: usize,
+    filter: F,
+) -> Option<Receiver<T>>
+where
+    T: Send + 'static,
+    F: Fn(&udev::Event) -> Option<T> + Send + 'static,
+{
+    let (tx, rx) = channel(capacity);
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name(thread.to_string())
+        .spawn(move || {
+            let socket = match udev::MonitorBuilder::new()
+                .and_then(|builder| {
+                    subsystems.iter().try_fold(builder, |builder, subsystem| {
+                        builder.match_subsystem(subsystem)
+                    })
+                })
+                .and_then(|builder| builder.listen())
+            {
+                Ok(socket) => {
+                    ready_tx.send(true).ok();
+                    socket
+                }
+                Err(err) => {
+                    warn!("spawn_udev_monitor: {err}");
+                    ready_tx.send(false).ok();
+                    return;
+                }
+            };
+            let mut fds = libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            while !tx.is_closed() {
+                // SAFETY: `fds` points to one valid pollfd for the duration of the call
+                let res = unsafe { libc::poll(&mut fds, 1, UDEV_POLL_MS) };
+                if res < 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    warn!("spawn_udev_monitor: poll: {err}");
+                    return;
+                }
+                for event in socket.iter() {
+                    let sent = match filter(&event) {
+                        Some(sent) => sent,
+                        None => continue,
+                    };
+                    trace!(
+                        "spawn_udev_monitor: {} {:?}",
+                        event.event_type(),
+                        event.sysname()
+                    );
+                    // A full channel already has a wake-up queued
+                    if let Err(TrySendError::Closed(_)) = tx.try_send(sent) {
+                        return;
+                    }
+                }
+            }
+        })
+        .map_err(|err| warn!("spawn_udev_monitor: {err}"))
+        .ok()?;
+    ready_rx.recv().unwrap_or(false).then_some(rx)
+}
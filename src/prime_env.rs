@@ -0,0 +1,123 @@
+use std::{
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus},
+};
+
+use crate::{
+    error::GfxError,
+    pci_device::{GfxMode, GfxVendor},
+    zbus_proxy::DaemonProxyBlocking,
+};
+
+/// GLX and Vulkan offload to the proprietary nvidia driver, as in the PRIME render offload
+/// chapter of the nvidia README
+pub const NVIDIA_OFFLOAD_ENV: &[(&str, &str)] = &[
+    ("__NV_PRIME_RENDER_OFFLOAD", "1"),
+    ("__GLX_VENDOR_LIBRARY_NAME", "nvidia"),
+    ("__VK_LAYER_NV_optimus", "NVIDIA_only"),
+];
+
+/// Mesa offload, for nouveau and amdgpu
+pub const MESA_OFFLOAD_ENV: &[(&str, &str)] = &[("DRI_PRIME", "1")];
+
+/// The driver apps are offloaded to, which decides the variables
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OffloadDriver {
+    Nvidia,
+    Nouveau,
+    Amdgpu,
+}
+
+impl OffloadDriver {
+    /// From the driver bound to the dGPU, or the vendor if none is bound yet. `None` if the
+    /// dGPU is bound to something apps can't offload to, such as vfio-pci.
+    pub fn detect(vendor: GfxVendor, driver: Option<&str>) -> Option<Self> {
+        match driver {
+            Some("nvidia") => Some(Self::Nvidia),
+            Some("nouveau") => Some(Self::Nouveau),
+            Some("amdgpu") | Some("radeon") => Some(Self::Amdgpu),
+            Some(_) => None,
+            None => match vendor {
+                GfxVendor::Nvidia => Some(Self::Nvidia),
+                GfxVendor::Amd => Some(Self::Amdgpu),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn env(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Nvidia => NVIDIA_OFFLOAD_ENV,
+            Self::Nouveau | Self::Amdgpu => MESA_OFFLOAD_ENV,
+        }
+    }
+}
+
+/// Apps can be offloaded to the dGPU in `mode`. In AsusMuxDgpu everything already runs on
+/// it.
+pub fn offload_possible(mode: GfxMode) -> bool {
+    matches!(
+        mode,
+        GfxMode::Hybrid | GfxMode::NvidiaNoModeset | GfxMode::AsusEgpu
+    )
+}
+
+/// The variables to run an app on the dGPU, empty if that isn't possible in `mode`
+pub fn prime_env(mode: GfxMode, vendor: GfxVendor, driver: Option<&str>) -> Vec<(String, String)> {
+    if !offload_possible(mode) {
+        return Vec::new();
+    }
+    OffloadDriver::detect(vendor, driver)
+        .map(|driver| {
+            driver
+                .env()
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Where `supergfxctl --run` gets the variables from, so it can be tested without a daemon
+pub trait PrimeEnvSource {
+    fn mode(&self) -> Result<GfxMode, GfxError>;
+    fn prime_env(&self) -> Result<Vec<(String, String)>, GfxError>;
+}
+
+impl PrimeEnvSource for DaemonProxyBlocking<'_> {
+    fn mode(&self) -> Result<GfxMode, GfxError> {
+        Ok(DaemonProxyBlocking::mode(self)?)
+    }
+
+    fn prime_env(&self) -> Result<Vec<(String, String)>, GfxError> {
+        Ok(DaemonProxyBlocking::prime_env(self)?)
+    }
+}
+
+/// Run `command`, the program then its arguments, on the dGPU and return its exit code
+pub fn run_offloaded(source: &dyn PrimeEnvSource, command: &[String]) -> Result<i32, GfxError> {
+    let (program, args) = command.split_first().ok_or_else(|| {
+        GfxError::NotSupported("No command to run, use `supergfxctl --run -- <command>`".into())
+    })?;
+    let env = source.prime_env()?;
+    if env.is_empty() {
+        return Err(GfxError::NotSupported(format!(
+            "Apps can't be run on the dGPU in {} mode, switch to Hybrid first",
+            source.mode()?
+        )));
+    }
+    let status = Command::new(program)
+        .args(args)
+        .envs(env)
+        .status()
+        .map_err(|err| GfxError::Command(program.clone(), err))?;
+    Ok(exit_code(status))
+}
+
+/// The exit code a shell would give for `status`, 128 plus the signal if it was killed
+pub(crate) fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
@@ -0,0 +1,255 @@
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{future::BoxFuture, lock::Mutex};
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use tokio::{
+    sync::mpsc::Receiver,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    config::GfxConfig,
+    controller::SwitchState,
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxMode, GfxVendor, RuntimePowerManagement},
+    power_watch::DeviceEvent,
+};
+
+/// How long the events of a re-probe are waited out before runtime PM is checked, each
+/// further event waits again
+pub(crate) const REPROBE_DEBOUNCE: Duration = Duration::from_secs(3);
+/// The longest runtime PM is left unchecked while the events keep coming
+pub(crate) const REPROBE_DEBOUNCE_MAX: Duration = Duration::from_secs(30);
+
+/// The udev actions after which a driver may have reset `power/control` to `on`, such as
+/// nvidia re-probing after a GSP error or `nvidia-bug-report`
+const REPROBE_ACTIONS: &[&str] = &["add", "bind", "change"];
+
+/// Whether `event` is a re-probe of one of the managed `functions`
+pub(crate) fn is_reprobe(event: &DeviceEvent, functions: &[String]) -> bool {
+    REPROBE_ACTIONS.contains(&event.action.as_str()) && functions.contains(&event.function)
+}
+
+/// Waits out the events of one re-probe, up to a limit so constant events can't put off the
+/// check for good
+#[derive(Debug, Clone)]
+pub(crate) struct Debounce {
+    window: Duration,
+    max: Duration,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Debounce {
+    pub(crate) fn new(window: Duration, max: Duration) -> Self {
+        Self {
+            window,
+            max,
+            first: None,
+            last: None,
+        }
+    }
+
+    pub(crate) fn event(&mut self, now: Instant) {
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    /// When the check is due, `None` with no events waiting
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => Some((last + self.window).min(first + self.max)),
+            _ => None,
+        }
+    }
+
+    /// Whether the check is due at `now`, starting over if it is
+    pub(crate) fn take(&mut self, now: Instant) -> bool {
+        match self.deadline() {
+            Some(deadline) if deadline <= now => {
+                self.first = None;
+                self.last = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What decides whether runtime PM is put back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReprobeState {
+    pub mode: GfxMode,
+    pub vendor: GfxVendor,
+    pub switch_state: SwitchState,
+}
+
+/// Why runtime PM isn't put back in `state`, `None` if it is. It is only put back where
+/// supergfxd sets it to `auto` itself, and never while a switch is changing the devices.
+pub(crate) fn reassert_skipped(state: &ReprobeState) -> Option<&'static str> {
+    if state.switch_state == SwitchState::Switching {
+        return Some("a switch is running");
+    }
+    if matches!(
+        state.vendor,
+        GfxVendor::Unknown | GfxVendor::AsusDgpuDisabled
+    ) {
+        return Some("the dGPU is disabled or unknown");
+    }
+    if state.mode == GfxMode::Vfio {
+        // The functions are bound and unbound by the VM tooling, which owns their PM
+        return Some("the dGPU is left to vfio-pci in Vfio");
+    }
+    None
+}
+
+/// The devices runtime PM is put back on, so the guard can be tested
+pub(crate) trait ReprobeSystem: Sync {
+    fn state(&self) -> BoxFuture<'_, ReprobeState>;
+    /// `(function, power/control)` of each managed function which has one
+    fn runtime_pm(&self) -> BoxFuture<'_, Vec<(String, String)>>;
+    fn set_auto(&self, function: String) -> BoxFuture<'_, Result<(), GfxError>>;
+}
+
+/// How often re-probes reset runtime PM, for the support bundle diagnostics. A count which
+/// keeps growing is a driver re-probing over and over.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ReprobeStats {
+    /// Re-probe events on the managed functions
+    pub events: u64,
+    /// Checks which found runtime PM reset and put it back
+    pub corrections: u64,
+    /// Checks not made, such as while a switch ran
+    pub skipped: u64,
+    /// Seconds since the epoch of the last correction
+    pub last_correction: Option<u64>,
+    /// What the last correction changed, such as `0000:01:00.0 on -> auto`
+    pub last_changed: Vec<String>,
+}
+
+/// Put runtime PM back to `auto` on each function a re-probe left otherwise, if the mode
+/// wants it. Returns what was changed.
+pub(crate) async fn reassert_runtime_pm(
+    system: &dyn ReprobeSystem,
+    stats: &Mutex<ReprobeStats>,
+) -> Vec<String> {
+    let state = system.state().await;
+    if let Some(why) = reassert_skipped(&state) {
+        debug!("reassert_runtime_pm: not checked, {why}");
+        stats.lock().await.skipped += 1;
+        return Vec::new();
+    }
+    let auto = <&str>::from(RuntimePowerManagement::Auto);
+    let mut changed = Vec::new();
+    for (function, control) in system.runtime_pm().await {
+        if control == auto {
+            continue;
+        }
+        match system.set_auto(function.clone()).await {
+            Ok(()) => changed.push(format!("{function} {control} -> {auto}")),
+            Err(err) => warn!("reassert_runtime_pm: {function}: {err}"),
+        }
+    }
+    if !changed.is_empty() {
+        let mut stats = stats.lock().await;
+        stats.corrections += 1;
+        stats.last_correction = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+        stats.last_changed = changed.clone();
+        info!(
+            "reassert_runtime_pm: a driver re-probe reset runtime PM in {}, put back {} (correction {})",
+            state.mode,
+            changed.join(", "),
+            stats.corrections
+        );
+    }
+    changed
+}
+
+/// Check runtime PM once the re-probe events of `functions` from `events` settle, until the
+/// event source ends
+pub(crate) async fn watch_reprobes(
+    mut events: Receiver<DeviceEvent>,
+    functions: &[String],
+    mut debounce: Debounce,
+    system: &dyn ReprobeSystem,
+    stats: &Mutex<ReprobeStats>,
+) {
+    loop {
+        let event = match debounce.deadline() {
+            Some(deadline) => match timeout_at(deadline, events.recv()).await {
+                Ok(event) => event,
+                Err(_) => {
+                    if debounce.take(Instant::now()) {
+                        reassert_runtime_pm(system, stats).await;
+                    }
+                    continue;
+                }
+            },
+            None => events.recv().await,
+        };
+        match event {
+            Some(event) if is_reprobe(&event, functions) => {
+                debug!("watch_reprobes: {} {}", event.action, event.function);
+                stats.lock().await.events += 1;
+                debounce.event(Instant::now());
+            }
+            Some(_) => {}
+            None => {
+                warn!("watch_reprobes: udev monitor stopped");
+                return;
+            }
+        }
+    }
+}
+
+/// The dGPU and mode as the daemon has them
+pub(crate) struct SystemReprobe {
+    pub dgpu: Arc<Mutex<DiscreetGpu>>,
+    pub config: Arc<Mutex<GfxConfig>>,
+}
+
+impl ReprobeSystem for SystemReprobe {
+    fn state(&self) -> BoxFuture<'_, ReprobeState> {
+        Box::pin(async move {
+            let vendor = self.dgpu.lock().await.vendor();
+            let config = self.config.lock().await;
+            ReprobeState {
+                mode: config.effective_mode(),
+                vendor,
+                switch_state: config.switch_state,
+            }
+        })
+    }
+
+    fn runtime_pm(&self) -> BoxFuture<'_, Vec<(String, String)>> {
+        Box::pin(async move {
+            let dgpu = self.dgpu.lock().await.clone();
+            dgpu.managed_devices()
+                .filter_map(|dev| {
+                    fs::read_to_string(dev.dev_path().join("power").join("control"))
+                        .ok()
+                        .map(|control| (dev.name().to_string(), control.trim().to_string()))
+                })
+                .collect()
+        })
+    }
+
+    fn set_auto(&self, function: String) -> BoxFuture<'_, Result<(), GfxError>> {
+        Box::pin(async move {
+            let dgpu = self.dgpu.lock().await.clone();
+            let res = match dgpu.managed_devices().find(|dev| dev.name() == function) {
+                Some(dev) => dev.set_runtime_pm(RuntimePowerManagement::Auto),
+                None => Ok(()),
+            };
+            res
+        })
+    }
+}
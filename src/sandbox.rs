@@ -0,0 +1,511 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    future::Future,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::warn;
+
+use crate::{
+    acpi_dgpu::{apply_acpi_dgpu_boot, ACPI_CALL_PATH},
+    actions::StagedAction,
+    config::GfxConfig,
+    controller::{read_nvidia_modeset_off, AsusProbes, ModeProbe},
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxMode, GfxVendor, HotplugType},
+    special_asus::ASUS_MODULES_LOAD_PATH,
+    special_vendor::{glob_match, resolve_glob, BUILTIN_TOGGLES},
+    switch_plan::{plan_switch, PlanEnv},
+    switcheroo::SWITCHEROO_RULE_PATH,
+    sysfs::{
+        ASUS_DGPU_DISABLE_PATH, ASUS_EGPU_ALT_ENABLE_PATH, ASUS_EGPU_ENABLE_PATH, ASUS_GPU_MUX_PATH,
+    },
+    CONFIG_DIR, CONFIG_PATH, STATE_DIR,
+};
+
+/// The first line of the profile
+pub const PROFILE_HEADER: &str = "# Automatically generated by supergfxd --emit-sandbox-profile";
+
+/// The PCI bus: driver bind and unbind, slots and rescan
+const PCI_BUS: &str = "/sys/bus/pci";
+/// The hotplug slots, under `PCI_BUS`
+const PCI_SLOTS: &str = "/sys/bus/pci/slots";
+/// The PCI functions themselves: `remove`, `driver_override` and `power/control`
+const PCI_DEVICES: &str = "/sys/devices/pci*";
+/// Where `WriteModprobeConf` writes `supergfxd.conf`
+const MODPROBE_DIR: &str = "/etc/modprobe.d";
+/// Where `CheckVulkanIcd` renames `nvidia_icd.json`
+const VULKAN_ICD_DIR: &str = "/usr/share/vulkan/icd.d";
+/// The runtime directory holding the locks, the staged files, the driver overrides and the
+/// temporary mode
+const RUNTIME_DIR: &str = "/run/supergfxd";
+/// The consoles the display watchdog writes to
+const CONSOLE_TTYS: &[&str] = &[
+    "/dev/tty1",
+    "/dev/tty2",
+    "/dev/tty3",
+    "/dev/tty4",
+    "/dev/tty5",
+    "/dev/tty6",
+];
+
+/// What an operation writes and which capabilities it needs. The daemon runs as root, so
+/// only the capabilities beyond owning the files it writes are listed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    /// Paths written. A directory covers everything in it, `*` matches any part of one
+    /// path component.
+    pub writes: &'static [&'static str],
+    pub caps: &'static [&'static str],
+    /// Device nodes opened for writing
+    pub devices: &'static [&'static str],
+}
+
+/// The annotation of each staged action. Anything it writes which isn't covered here is
+/// drift, see `enable_drift_check`.
+pub fn action_access(action: StagedAction) -> Access {
+    match action {
+        StagedAction::WaitLogout => Access {
+            // The processes holding the dGPU, from /proc/<pid>/fd
+            caps: &["CAP_DAC_READ_SEARCH", "CAP_SYS_PTRACE"],
+            ..Default::default()
+        },
+        StagedAction::KillNvidia | StagedAction::KillAmd => Access {
+            caps: &["CAP_DAC_READ_SEARCH", "CAP_KILL", "CAP_SYS_PTRACE"],
+            ..Default::default()
+        },
+        StagedAction::LoadGpuDrivers | StagedAction::UnloadGpuDrivers => Access {
+            caps: &["CAP_SYS_MODULE"],
+            ..Default::default()
+        },
+        // The driver overrides set are recorded in the runtime directory
+        StagedAction::LoadVfioDrivers | StagedAction::UnloadVfioDrivers => Access {
+            writes: &[PCI_BUS, PCI_DEVICES, RUNTIME_DIR],
+            caps: &["CAP_SYS_MODULE"],
+            ..Default::default()
+        },
+        StagedAction::ReleaseVfioDevices | StagedAction::UnbindRemoveGpu => Access {
+            writes: &[PCI_BUS, PCI_DEVICES, RUNTIME_DIR],
+            ..Default::default()
+        },
+        StagedAction::UnbindGpu => Access {
+            writes: &[PCI_BUS],
+            ..Default::default()
+        },
+        // With the PCI lock held
+        StagedAction::RescanPci => Access {
+            writes: &[PCI_BUS, RUNTIME_DIR],
+            ..Default::default()
+        },
+        StagedAction::HotplugUnplug | StagedAction::HotplugPlug => Access {
+            writes: &[PCI_SLOTS],
+            ..Default::default()
+        },
+        StagedAction::AsusDgpuDisable | StagedAction::AsusDgpuEnable => Access {
+            writes: &[ASUS_DGPU_DISABLE_PATH],
+            ..Default::default()
+        },
+        StagedAction::AsusEgpuDisable | StagedAction::AsusEgpuEnable => Access {
+            writes: &[ASUS_EGPU_ENABLE_PATH, ASUS_EGPU_ALT_ENABLE_PATH],
+            ..Default::default()
+        },
+        StagedAction::AsusMuxIgpu | StagedAction::AsusMuxDgpu => Access {
+            writes: &[ASUS_GPU_MUX_PATH],
+            ..Default::default()
+        },
+        StagedAction::SpecialToggleOn(id) | StagedAction::SpecialToggleOff(id) => Access {
+            writes: BUILTIN_TOGGLES
+                .iter()
+                .find(|def| def.id == id)
+                .map_or(&[][..], |def| std::slice::from_ref(&def.path_glob)),
+            ..Default::default()
+        },
+        StagedAction::AcpiDgpuOff | StagedAction::AcpiDgpuOn => Access {
+            writes: &[ACPI_CALL_PATH],
+            ..Default::default()
+        },
+        StagedAction::WriteModprobeConf => Access {
+            // A staged conf is renamed from the runtime directory, the vfio functions and
+            // the initramfs advisory are kept in the state directory
+            writes: &[MODPROBE_DIR, RUNTIME_DIR, STATE_DIR],
+            ..Default::default()
+        },
+        StagedAction::CheckVulkanIcd => Access {
+            writes: &[VULKAN_ICD_DIR],
+            ..Default::default()
+        },
+        // systemctl only talks to systemd over dbus, the rest don't touch the system
+        StagedAction::StopDisplayManager
+        | StagedAction::StartDisplayManager
+        | StagedAction::WaitDisplayManager(_)
+        | StagedAction::EnableNvidiaPersistenced
+        | StagedAction::DisableNvidiaPersistenced
+        | StagedAction::EnableNvidiaPowerd
+        | StagedAction::DisableNvidiaPowerd
+        | StagedAction::PreStopDelay(_)
+        | StagedAction::WaitInhibitors
+        | StagedAction::DevTreeManaged
+        | StagedAction::NoLogind
+        | StagedAction::NotNvidia
+        | StagedAction::None => Access::default(),
+    }
+}
+
+/// When a base operation is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Needed {
+    Always,
+    /// `hotplug_type` is `Asus`, `asus.conf` is written if `dgpu_disable` is missing
+    AsusHotplug,
+    /// `fix_nvidia_nodes` is set
+    FixNvidiaNodes,
+}
+
+/// An operation of the daemon which isn't a staged action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BaseAccess {
+    pub what: &'static str,
+    pub needed: Needed,
+    pub access: Access,
+}
+
+/// The operations of the daemon outside of the staged actions, which are checked against
+/// these instead
+pub const BASE_ACCESS: &[BaseAccess] = &[
+    BaseAccess {
+        what: "the config",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[CONFIG_DIR],
+            caps: &[],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the audit log, initramfs advisory and vfio functions",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[STATE_DIR],
+            caps: &[],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the instance and PCI locks, staged files and driver overrides",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[RUNTIME_DIR],
+            caps: &[],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the switcheroo-control udev rule",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[SWITCHEROO_RULE_PATH],
+            caps: &[],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "runtime power management of the dGPU",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[PCI_DEVICES],
+            caps: &[],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the processes using the dGPU",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[],
+            caps: &["CAP_DAC_READ_SEARCH", "CAP_SYS_PTRACE"],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the display watchdog",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[],
+            caps: &[],
+            devices: CONSOLE_TTYS,
+        },
+    },
+    BaseAccess {
+        what: "the self-test restoring the modprobe conf",
+        needed: Needed::Always,
+        access: Access {
+            writes: &[MODPROBE_DIR],
+            caps: &[],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the nvidia device nodes made with fix_nvidia_nodes",
+        needed: Needed::FixNvidiaNodes,
+        access: Access {
+            writes: &[],
+            caps: &["CAP_MKNOD"],
+            devices: &[],
+        },
+    },
+    BaseAccess {
+        what: "the asus-nb-wmi modules-load conf",
+        needed: Needed::AsusHotplug,
+        access: Access {
+            writes: &[ASUS_MODULES_LOAD_PATH],
+            caps: &[],
+            devices: &[],
+        },
+    },
+];
+
+/// The base operations done with `config`
+pub fn base_access(config: &GfxConfig) -> Vec<&'static BaseAccess> {
+    BASE_ACCESS
+        .iter()
+        .filter(|base| match base.needed {
+            Needed::Always => true,
+            Needed::AsusHotplug => config.effective_hotplug_type() == HotplugType::Asus,
+            Needed::FixNvidiaNodes => config.fix_nvidia_nodes,
+        })
+        .collect()
+}
+
+/// Every action of the switches between `modes` and of booting into each, without
+/// duplicates. `env` gives the state of the machine for a switch from a mode.
+pub(crate) fn planned_actions(
+    config: &GfxConfig,
+    vendor: GfxVendor,
+    modes: &[GfxMode],
+    env: &dyn Fn(GfxMode) -> PlanEnv,
+) -> Vec<StagedAction> {
+    let mut actions = Vec::new();
+    for &from in modes {
+        let env = env(from);
+        let mut boot = StagedAction::action_list_for_boot(config, vendor, from);
+        apply_acpi_dgpu_boot(
+            config.acpi_dgpu_off.as_ref(),
+            env.acpi_call,
+            from,
+            &mut boot,
+        );
+        actions.extend(boot);
+        for &to in modes.iter().filter(|&&to| to != from) {
+            if let Some(plan) = plan_switch(config, vendor, from, to, &env).actions {
+                actions.extend(plan);
+            }
+        }
+    }
+    let mut unique = Vec::new();
+    for action in actions {
+        if !unique.contains(&action) {
+            unique.push(action);
+        }
+    }
+    unique
+}
+
+/// `path` with `root` taken off, as it would be on a running system
+fn on_system(root: &Path, path: &Path) -> String {
+    Path::new("/")
+        .join(path.strip_prefix(root).unwrap_or(path))
+        .display()
+        .to_string()
+}
+
+/// The systemd directives confining supergfxd to what `actions` and `base` need, with the
+/// globs of the annotations resolved under `root`, which is `/` other than in tests
+pub fn generate_sandbox_profile(
+    actions: &[StagedAction],
+    base: &[&BaseAccess],
+    root: &Path,
+) -> String {
+    let mut reasons: Vec<(String, Access)> = actions
+        .iter()
+        .map(|action| (format!("{action:?}"), action_access(*action)))
+        .collect();
+    reasons.extend(base.iter().map(|base| (base.what.to_string(), base.access)));
+
+    let mut writes: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut caps = BTreeSet::new();
+    let mut devices = BTreeSet::new();
+    for (why, access) in &reasons {
+        for path in access.writes {
+            writes.entry(path).or_default().push(why);
+        }
+        caps.extend(access.caps.iter().copied());
+        devices.extend(access.devices.iter().copied());
+    }
+
+    let mut profile = format!(
+        "{PROFILE_HEADER}\n# Review it, then install it as a drop-in of supergfxd.service\n\n[Service]\n"
+    );
+    profile += "ProtectSystem=strict\nProtectKernelTunables=yes\nProtectControlGroups=yes\nPrivateTmp=yes\n";
+    if !caps.contains("CAP_SYS_MODULE") {
+        profile += "ProtectKernelModules=yes\n";
+    }
+    for (path, whys) in writes {
+        writeln!(profile, "# {}", whys.join(", ")).ok();
+        if path.contains('*') {
+            let found = resolve_glob(root, path);
+            if found.is_empty() {
+                writeln!(profile, "# {path} isn't on this machine").ok();
+            }
+            for found in found {
+                writeln!(profile, "ReadWritePaths={}", on_system(root, &found)).ok();
+            }
+        } else {
+            // A missing path would stop the unit from starting, `-` ignores it
+            let missing = if root.join(&path[1..]).exists() {
+                ""
+            } else {
+                "-"
+            };
+            writeln!(profile, "ReadWritePaths={missing}{path}").ok();
+        }
+    }
+    writeln!(
+        profile,
+        "CapabilityBoundingSet={}",
+        caps.into_iter().collect::<Vec<_>>().join(" ")
+    )
+    .ok();
+    profile += "DevicePolicy=closed\n";
+    for device in devices {
+        writeln!(profile, "DeviceAllow={device} w").ok();
+    }
+    profile
+}
+
+/// The profile for this machine: its dGPU, the modes it supports and the config at
+/// `CONFIG_PATH`
+pub fn machine_profile() -> Result<String, GfxError> {
+    let config = GfxConfig::peek(CONFIG_PATH);
+    let dgpu = DiscreetGpu::new(true)?;
+    let mut probe = ModeProbe::from_probes(
+        &dgpu,
+        &read_nvidia_modeset_off(),
+        &AsusProbes::read(),
+        &mut Vec::new(),
+    );
+    probe.vfio_enable = config.vfio_enable;
+    // Every mode the hardware has, not only those usable with the MUX as it is now
+    probe.asus_mux_discreet = false;
+    probe.vendor_mux_discreet = false;
+    let modes = probe.supported_modes();
+    let actions = planned_actions(&config, dgpu.vendor(), &modes, &PlanEnv::probe);
+    let modes: Vec<String> = modes.iter().map(|mode| mode.to_string()).collect();
+    Ok(format!(
+        "# {} dGPU, modes {}\n{}",
+        <&str>::from(dgpu.vendor()),
+        modes.join(", "),
+        generate_sandbox_profile(&actions, &base_access(&config), Path::new("/"))
+    ))
+}
+
+/// `path` is covered by the annotated `pattern`: each component of the pattern matches
+/// the same of `path`, which may go on past it
+pub(crate) fn covers(pattern: &str, path: &Path) -> bool {
+    let mut path = path.components().filter_map(|c| match c {
+        Component::Normal(name) => Some(name.to_string_lossy()),
+        _ => None,
+    });
+    pattern
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .all(|component| matches!(path.next(), Some(name) if glob_match(component, &name)))
+}
+
+/// A write not covered by the annotation of the action doing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriftMiss {
+    /// `None` outside of a staged action
+    pub action: Option<StagedAction>,
+    /// As it would be on a running system
+    pub path: PathBuf,
+}
+
+struct DriftCheck {
+    root: PathBuf,
+    misses: Vec<DriftMiss>,
+}
+
+static DRIFT_CHECK: Mutex<Option<DriftCheck>> = Mutex::new(None);
+
+tokio::task_local! {
+    /// The staged action being performed, for `note_write`
+    static CURRENT_ACTION: StagedAction;
+}
+
+/// Check every write against the annotations from now on, warning of those not covered.
+/// Only writes under `root` are checked, it is `/` other than in tests.
+pub fn enable_drift_check(root: &Path) {
+    if let Ok(mut check) = DRIFT_CHECK.lock() {
+        *check = Some(DriftCheck {
+            root: root.to_path_buf(),
+            misses: Vec::new(),
+        });
+    }
+}
+
+/// Stop checking, returning the writes which weren't covered
+pub fn disable_drift_check() -> Vec<DriftMiss> {
+    DRIFT_CHECK
+        .lock()
+        .ok()
+        .and_then(|mut check| check.take())
+        .map(|check| check.misses)
+        .unwrap_or_default()
+}
+
+/// Run `f` as `action`, so the writes it does are checked against its annotation
+pub(crate) async fn in_action<F: Future>(action: StagedAction, f: F) -> F::Output {
+    CURRENT_ACTION.scope(action, f).await
+}
+
+/// Check a write of `path` if the drift check is on
+pub(crate) fn note_write(path: &Path) {
+    let mut guard = match DRIFT_CHECK.lock() {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    let check = match guard.as_mut() {
+        Some(check) => check,
+        None => return,
+    };
+    let path = match path.strip_prefix(&check.root) {
+        Ok(path) => Path::new("/").join(path),
+        Err(_) => return,
+    };
+    let action = CURRENT_ACTION.try_with(|action| *action).ok();
+    let covered = match action {
+        Some(action) => action_access(action)
+            .writes
+            .iter()
+            .any(|pattern| covers(pattern, &path)),
+        None => BASE_ACCESS
+            .iter()
+            .flat_map(|base| base.access.writes)
+            .any(|pattern| covers(pattern, &path)),
+    };
+    if !covered {
+        match action {
+            Some(action) => warn!(
+                "sandbox drift: {action:?} wrote {}, which its annotation doesn't cover",
+                path.display()
+            ),
+            None => warn!(
+                "sandbox drift: {} was written, which no annotation covers",
+                path.display()
+            ),
+        }
+        check.misses.push(DriftMiss { action, path });
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::lock::Mutex;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::sleep;
+use zbus::{object_server::SignalEmitter, zvariant::Type};
+
+use crate::{
+    ac_automation::{
+        decide_mode, switch_by_automation, AcContext, AcDecision, AcProbe, SystemProbe,
+    },
+    automation_inhibit::skip_if_inhibited,
+    config::GfxConfig,
+    controller::{CtrlGraphics, SwitchInitiator},
+    dock_automation::switch_busy,
+    error::GfxError,
+    pci_device::GfxMode,
+    signal_counters::{emit_counted, Signal},
+    supervisor::RestartPolicy,
+    DBUS_IFACE_PATH,
+};
+
+const DAY_S: i64 = 24 * 3600;
+/// The longest the schedule sleeps for, so a change of the clock or the config is noticed
+const SCHEDULE_CHECK: Duration = Duration::from_secs(60);
+/// A step of the clock longer than this between two checks was a suspend or the clock being
+/// set, and the entries it passed are skipped rather than all run at once
+const SCHEDULE_MAX_STEP_S: i64 = 3 * 60;
+
+/// Use `mode` from `at` each day, such as `{ "at": "22:30", "mode": "Integrated" }`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ScheduleEntry {
+    /// The local time of day as `HH:MM`, 24 hour
+    pub at: String,
+    pub mode: GfxMode,
+}
+
+/// Parse a local time of day such as `08:00` or `22:30` to minutes past midnight
+pub(crate) fn parse_time_of_day(at: &str) -> Result<u32, String> {
+    let invalid = || format!("{at:?} is not a time of day, use HH:MM such as 22:30");
+    let (hour, minute) = at.split_once(':').ok_or_else(invalid)?;
+    let digits = |part: &str, len: std::ops::RangeInclusive<usize>| {
+        (len.contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| part.parse::<u32>().ok())
+            .flatten()
+    };
+    match (digits(hour, 1..=2), digits(minute, 2..=2)) {
+        (Some(hour), Some(minute)) if hour < 24 && minute < 60 => Ok(hour * 60 + minute),
+        _ => Err(invalid()),
+    }
+}
+
+/// Check each entry of `schedule` has a time of day and a mode it can switch to, and that
+/// no two are ambiguous: at the same time, or one after the other with the same mode
+pub(crate) fn validate_schedule(schedule: &[ScheduleEntry]) -> Result<(), GfxError> {
+    let mut times = Vec::with_capacity(schedule.len());
+    for entry in schedule {
+        let minute = parse_time_of_day(&entry.at).map_err(GfxError::Schedule)?;
+        match entry.mode {
+            GfxMode::None => {
+                return Err(GfxError::Schedule(format!(
+                    "the {} entry has no mode",
+                    entry.at
+                )))
+            }
+            // Only suggested by automations, a reboot is never made for one
+            GfxMode::AsusMuxDgpu => {
+                return Err(GfxError::Schedule(format!(
+                    "the {} entry is for AsusMuxDgpu, which needs a reboot",
+                    entry.at
+                )))
+            }
+            _ => {}
+        }
+        times.push((minute, entry));
+    }
+    times.sort_by_key(|(minute, _)| *minute);
+    for pair in times.windows(2) {
+        let ((a, first), (b, second)) = (pair[0], pair[1]);
+        if a == b {
+            return Err(GfxError::Schedule(format!(
+                "{} and {} are the same time",
+                first.at, second.at
+            )));
+        }
+    }
+    if times.len() > 1 {
+        // Each day wraps around to the first entry
+        for (i, (_, entry)) in times.iter().enumerate() {
+            let (_, previous) = times[(i + times.len() - 1) % times.len()];
+            if previous.mode == entry.mode {
+                return Err(GfxError::Schedule(format!(
+                    "the {} entry repeats the {} of the {} entry before it",
+                    entry.at, entry.mode, previous.at
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The schedule as minutes past midnight, checked. Empty if it is invalid.
+fn schedule_minutes(schedule: &[ScheduleEntry]) -> Vec<(u32, GfxMode)> {
+    if validate_schedule(schedule).is_err() {
+        return Vec::new();
+    }
+    schedule
+        .iter()
+        .filter_map(|entry| Some((parse_time_of_day(&entry.at).ok()?, entry.mode)))
+        .collect()
+}
+
+/// The offset of local time from UTC, so triggers can be found across DST changes
+pub(crate) trait LocalZone {
+    /// Seconds east of UTC at `utc`, in seconds since the epoch
+    fn offset_at(&self, utc: i64) -> i64;
+}
+
+/// The time zone of the system, as `localtime_r` reads it
+pub(crate) struct SystemZone;
+
+impl LocalZone for SystemZone {
+    fn offset_at(&self, utc: i64) -> i64 {
+        let time = utc as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+/// When `minute` past midnight of the local `day`, in days since the epoch, is. A time which
+/// happens twice as the clocks go back is the first of them. One skipped as the clocks go
+/// forward is as far after the change as it would have been after the hour before it.
+pub(crate) fn local_to_utc(zone: &dyn LocalZone, day: i64, minute: u32) -> i64 {
+    let local = day * DAY_S + i64::from(minute) * 60;
+    // The zone has at most one change within a day of the time
+    let before = zone.offset_at(local - DAY_S);
+    let after = zone.offset_at(local + DAY_S);
+    [before, after]
+        .into_iter()
+        .map(|offset| local - offset)
+        .filter(|utc| zone.offset_at(*utc) == local - utc)
+        .min()
+        .unwrap_or(local - before)
+}
+
+/// The local day `utc` is in, in days since the epoch
+fn local_day(zone: &dyn LocalZone, utc: i64) -> i64 {
+    (utc + zone.offset_at(utc)).div_euclid(DAY_S)
+}
+
+/// Every time an entry of `schedule` comes due after `from` and up to `to`, in order, with
+/// its mode
+pub(crate) fn triggers_between(
+    schedule: &[(u32, GfxMode)],
+    from: i64,
+    to: i64,
+    zone: &dyn LocalZone,
+) -> Vec<(i64, GfxMode)> {
+    let mut triggers: Vec<(i64, GfxMode)> = (local_day(zone, from) - 1..=local_day(zone, to) + 1)
+        .flat_map(|day| {
+            schedule
+                .iter()
+                .map(move |(minute, mode)| (local_to_utc(zone, day, *minute), *mode))
+        })
+        .filter(|(utc, _)| *utc > from && *utc <= to)
+        .collect();
+    triggers.sort_by_key(|(utc, _)| *utc);
+    triggers.dedup_by_key(|(utc, _)| *utc);
+    triggers
+}
+
+/// The next time an entry of `schedule` comes due after `now`, with its mode
+pub(crate) fn next_trigger(
+    schedule: &[(u32, GfxMode)],
+    now: i64,
+    zone: &dyn LocalZone,
+) -> Option<(i64, GfxMode)> {
+    // Every entry comes due within the next two days, whatever the zone does
+    triggers_between(schedule, now, now + 2 * DAY_S + 3600, zone)
+        .into_iter()
+        .next()
+}
+
+/// Finds the entries which came due between two looks at the clock
+#[derive(Debug, Default)]
+pub(crate) struct ScheduleClock {
+    /// When the clock was last looked at, `None` before the first look
+    last: Option<i64>,
+}
+
+impl ScheduleClock {
+    /// Look at the clock, which reads `now`. The mode of the last entry which came due since
+    /// the last look, if any. Nothing is due on the first look, nor after the clock was set
+    /// back, nor after a step longer than `SCHEDULE_MAX_STEP_S` as from a suspend: the next
+    /// trigger is then found from `now`.
+    pub fn tick(
+        &mut self,
+        schedule: &[(u32, GfxMode)],
+        now: i64,
+        zone: &dyn LocalZone,
+    ) -> Option<GfxMode> {
+        let last = self.last.replace(now)?;
+        if now < last {
+            debug!("Schedule: the clock was set back by {}s", last - now);
+            return None;
+        }
+        let due = triggers_between(schedule, last, now, zone);
+        if now - last > SCHEDULE_MAX_STEP_S {
+            if !due.is_empty() {
+                info!(
+                    "Schedule: the clock jumped {}s, as after a suspend, skipping {} entries",
+                    now - last,
+                    due.len()
+                );
+            }
+            return None;
+        }
+        due.last().map(|(_, mode)| *mode)
+    }
+}
+
+/// A schedule entry which came due, held while a switch is running or waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HeldEntry {
+    pub mode: GfxMode,
+    /// The user switch count when it came due
+    pub user_switches: u64,
+}
+
+/// The entry to act on. While a switch is `busy` it is held until it is done, and it is
+/// dropped if the user switched modes since it came due.
+pub(crate) fn take_when_idle(
+    held: &mut Option<HeldEntry>,
+    busy: bool,
+    user_switches: u64,
+) -> Option<GfxMode> {
+    if busy {
+        return None;
+    }
+    let entry = held.take()?;
+    if entry.user_switches != user_switches {
+        info!(
+            "Schedule: the mode was changed since {} came due, doing nothing",
+            entry.mode
+        );
+        return None;
+    }
+    Some(entry.mode)
+}
+
+/// Decide what to do now that an entry for `mode` came due. It is always suggested, and
+/// switched to only when no graphical sessions are active and nothing uses the dGPU.
+pub(crate) async fn decide_schedule(
+    mode: GfxMode,
+    ctx: &AcContext,
+    probe: &dyn AcProbe,
+) -> AcDecision {
+    decide_mode(Some(mode), true, false, ctx, probe).await
+}
+
+/// Emitted with `NotifyScheduleSuggestion` when an entry of `schedule` comes due
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ScheduleSuggestion {
+    pub mode: GfxMode,
+    /// supergfxd is switching to `mode` itself
+    pub applying: bool,
+    /// Why the switch isn't being made automatically, empty if `applying`
+    pub reason: String,
+}
+
+/// Act on an entry for `mode` which came due
+async fn on_schedule(ctxt: &SignalEmitter<'static>, mode: GfxMode) -> Result<(), GfxError> {
+    let iface = ctxt
+        .connection()
+        .object_server()
+        .interface::<_, CtrlGraphics>(DBUS_IFACE_PATH)
+        .await?;
+    let (ctx, probe, inhibits, audit) = {
+        let ctrl = iface.get().await;
+        (
+            ctrl.get_ac_context().await,
+            SystemProbe {
+                dgpu: ctrl.dgpu_arc_clone(),
+            },
+            ctrl.automation_inhibits.clone(),
+            ctrl.audit.clone(),
+        )
+    };
+    let suggestion = match decide_schedule(mode, &ctx, &probe).await {
+        AcDecision::Nothing => return Ok(()),
+        AcDecision::Suggest { mode, reason } => ScheduleSuggestion {
+            mode,
+            applying: false,
+            reason,
+        },
+        AcDecision::Apply(mode) => ScheduleSuggestion {
+            mode,
+            applying: true,
+            reason: String::new(),
+        },
+    };
+    let what = if suggestion.applying {
+        format!("schedule: switch to {mode}")
+    } else {
+        format!("schedule: suggest {mode}")
+    };
+    if skip_if_inhibited(&inhibits, &audit, &what).await {
+        return Ok(());
+    }
+    if suggestion.applying {
+        info!("Schedule: switching to {mode}");
+    } else {
+        info!("Schedule: suggesting {mode}: {}", suggestion.reason);
+    }
+    emit_counted!(
+        ctxt,
+        Signal::ScheduleSuggestion,
+        notify_schedule_suggestion(&suggestion)
+    )
+    .await?;
+    if suggestion.applying {
+        switch_by_automation(ctxt, &iface, mode, SwitchInitiator::Schedule).await?;
+    }
+    Ok(())
+}
+
+/// Seconds since the epoch
+fn epoch_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Follow `schedule`, acting on each entry as it comes due
+async fn run_schedule(
+    config: Arc<Mutex<GfxConfig>>,
+    user_switches: Arc<AtomicU64>,
+    ctxt: SignalEmitter<'static>,
+) {
+    let mut clock = ScheduleClock::default();
+    let mut held = None;
+    loop {
+        let (schedule, busy) = {
+            let config = config.lock().await;
+            (schedule_minutes(&config.schedule), switch_busy(&config))
+        };
+        let switches = user_switches.load(Ordering::Acquire);
+        let now = epoch_now();
+        if let Some(mode) = clock.tick(&schedule, now, &SystemZone) {
+            held = Some(HeldEntry {
+                mode,
+                user_switches: switches,
+            });
+        }
+        if let Some(mode) = take_when_idle(&mut held, busy, switches) {
+            on_schedule(&ctxt, mode)
+                .await
+                .unwrap_or_else(|err| warn!("Schedule: {err}"));
+        }
+
+        let wait = next_trigger(&schedule, now, &SystemZone)
+            .map_or(SCHEDULE_CHECK, |(at, _)| {
+                Duration::from_secs((at - now).max(1) as u64)
+            })
+            .min(SCHEDULE_CHECK);
+        sleep(wait).await;
+    }
+}
+
+impl CtrlGraphics {
+    /// Suggest or switch modes as the entries of `schedule` come due. Not started if there
+    /// is no signal context to notify with.
+    pub fn start_schedule(&self) {
+        let ctxt = match self.signal_ctxt.clone() {
+            Some(ctxt) => ctxt,
+            None => return,
+        };
+        let config = self.config.clone();
+        let user_switches = self.user_switches.clone();
+        self.tasks
+            .spawn("schedule", RestartPolicy::WithBackoff, move || {
+                run_schedule(config.clone(), user_switches.clone(), ctxt.clone())
+            });
+    }
+}
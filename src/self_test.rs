@@ -0,0 +1,556 @@
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::sleep;
+use zbus::zvariant::Type;
+
+use crate::{
+    actions::{Action, StagedAction, UserActionRequired},
+    audit::Actor,
+    config::modprobe_conf,
+    controller::{CtrlGraphics, SetModeOptions, SwitchState},
+    error::GfxError,
+    nvidia_module_loaded,
+    pci_device::{GfxMode, GfxVendor, HotplugType},
+    sandbox::note_write,
+    special_asus::asus_dgpu_disable_exists,
+    MODPROBE_PATH,
+};
+
+/// How long each switch of the self-test may take before it is considered failed
+const SELF_TEST_SWITCH_TIMEOUT: Duration = Duration::from_secs(120);
+const SELF_TEST_SWITCH_POLL: Duration = Duration::from_millis(100);
+
+/// One step of a self-test
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SelfTestPhase {
+    /// e.g `switch to Vfio`
+    pub name: String,
+    pub passed: bool,
+    pub duration_ms: u64,
+    /// What was checked or why it failed
+    pub detail: String,
+}
+
+/// The result of `SelfTest`
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub original_mode: GfxMode,
+    /// The mode switched to and back from, `None` if no switch could be made without a
+    /// logout or reboot and only the checks which change nothing were run
+    pub test_mode: GfxMode,
+    pub phases: Vec<SelfTestPhase>,
+    /// Something was left changed and the recorded state was put back
+    pub restored: bool,
+}
+
+/// The state a self-test must leave as it found it
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SystemSnapshot {
+    /// The mode in use
+    pub mode: GfxMode,
+    /// The mode in the config file, used at boot
+    pub config_mode: GfxMode,
+    /// Contents of `MODPROBE_PATH`, `None` if it doesn't exist
+    pub modprobe_conf: Option<String>,
+    /// `name=driver` of each dGPU function
+    pub dgpu_drivers: Vec<String>,
+    pub nvidia_loaded: bool,
+    pub vfio_loaded: bool,
+}
+
+impl SystemSnapshot {
+    /// The differences from `before`, empty if there are none
+    pub fn diff(&self, before: &Self) -> Vec<String> {
+        let mut diff = Vec::new();
+        if self.mode != before.mode {
+            diff.push(format!("mode is {} was {}", self.mode, before.mode));
+        }
+        if self.config_mode != before.config_mode {
+            diff.push(format!(
+                "config mode is {} was {}",
+                self.config_mode, before.config_mode
+            ));
+        }
+        if self.modprobe_conf != before.modprobe_conf {
+            diff.push(format!("{MODPROBE_PATH} changed"));
+        }
+        if self.dgpu_drivers != before.dgpu_drivers {
+            diff.push(format!(
+                "dGPU drivers are {:?} were {:?}",
+                self.dgpu_drivers, before.dgpu_drivers
+            ));
+        }
+        if self.nvidia_loaded != before.nvidia_loaded {
+            diff.push(format!("nvidia loaded is {}", self.nvidia_loaded));
+        }
+        if self.vfio_loaded != before.vfio_loaded {
+            diff.push(format!("vfio-pci loaded is {}", self.vfio_loaded));
+        }
+        diff
+    }
+}
+
+/// The mode a self-test switches to and back from
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct TestTarget {
+    pub mode: GfxMode,
+    /// The switch there and back can be made without a logout or reboot. If not only the
+    /// checks which change nothing are run against `mode`.
+    pub round_trip: bool,
+}
+
+/// The staged actions of a plan, `None` for a user action
+fn staged(plan: Action) -> Option<Vec<StagedAction>> {
+    match plan {
+        Action::StagedActions(actions) => Some(actions),
+        Action::UserAction(_) => None,
+    }
+}
+
+/// Pick the cheapest mode to test with: the one with the shortest plans there and back
+/// which need no logout or reboot. If there is none the first other supported mode is
+/// used for the checks only.
+pub fn choose_target<F>(from: GfxMode, supported: &[GfxMode], plan: F) -> Option<TestTarget>
+where
+    F: Fn(GfxMode, GfxMode) -> Action,
+{
+    let candidates: Vec<GfxMode> = supported
+        .iter()
+        .copied()
+        .filter(|mode| *mode != from && !matches!(mode, GfxMode::AsusMuxDgpu | GfxMode::None))
+        .collect();
+    let mut best: Option<(usize, GfxMode)> = None;
+    for to in &candidates {
+        if UserActionRequired::mode_change_action(*to, from) != UserActionRequired::Nothing
+            || UserActionRequired::mode_change_action(from, *to) != UserActionRequired::Nothing
+        {
+            continue;
+        }
+        let (there, back) = match (staged(plan(from, *to)), staged(plan(*to, from))) {
+            (Some(there), Some(back)) => (there, back),
+            _ => continue,
+        };
+        if there.iter().chain(back.iter()).any(|action| {
+            matches!(
+                action,
+                StagedAction::WaitLogout | StagedAction::StopDisplayManager
+            )
+        }) {
+            continue;
+        }
+        let cost = there.len() + back.len();
+        if best.map_or(true, |(best_cost, _)| cost < best_cost) {
+            best = Some((cost, *to));
+        }
+    }
+    match best {
+        Some((_, mode)) => Some(TestTarget {
+            mode,
+            round_trip: true,
+        }),
+        None => candidates.first().map(|mode| TestTarget {
+            mode: *mode,
+            round_trip: false,
+        }),
+    }
+}
+
+/// What a self-test acts on, the daemon or a mock in tests
+pub trait SelfTestEnv: Send {
+    fn snapshot(&mut self) -> BoxFuture<'_, Result<SystemSnapshot, GfxError>>;
+    /// Checks for a switch from `from` to `to` which change nothing, as `(name, result)`
+    fn checks(
+        &mut self,
+        from: GfxMode,
+        to: GfxMode,
+    ) -> BoxFuture<'_, Vec<(String, Result<String, String>)>>;
+    /// Switch to `mode` and wait for the switch to finish
+    fn switch(&mut self, mode: GfxMode) -> BoxFuture<'_, Result<(), GfxError>>;
+    /// Put back the recorded state
+    fn restore<'a>(&'a mut self, before: &'a SystemSnapshot)
+        -> BoxFuture<'a, Result<(), GfxError>>;
+}
+
+/// Collects the phases of a self-test
+#[derive(Default)]
+struct Phases {
+    phases: Vec<SelfTestPhase>,
+}
+
+impl Phases {
+    fn push(&mut self, name: String, start: Instant, result: Result<String, String>) -> bool {
+        let passed = result.is_ok();
+        let detail = result.unwrap_or_else(|e| e);
+        if passed {
+            info!("self-test: {name}: passed {detail}");
+        } else {
+            warn!("self-test: {name}: failed {detail}");
+        }
+        self.phases.push(SelfTestPhase {
+            name,
+            passed,
+            duration_ms: start.elapsed().as_millis() as u64,
+            detail,
+        });
+        passed
+    }
+
+    fn passed(&self) -> bool {
+        self.phases.iter().all(|p| p.passed)
+    }
+}
+
+/// Run a self-test: record the state, run the checks, switch to the target and back if it
+/// can be done without a logout, then compare the state with the recording. If anything
+/// was left changed the recorded state is restored.
+pub async fn run_self_test<E: SelfTestEnv>(
+    env: &mut E,
+    from: GfxMode,
+    target: Option<TestTarget>,
+) -> SelfTestReport {
+    let mut phases = Phases::default();
+    let mut report = SelfTestReport {
+        passed: false,
+        original_mode: from,
+        test_mode: GfxMode::None,
+        phases: Vec::new(),
+        restored: false,
+    };
+
+    let start = Instant::now();
+    let before = match env.snapshot().await {
+        Ok(before) => {
+            phases.push("snapshot before".to_string(), start, Ok(String::new()));
+            before
+        }
+        Err(err) => {
+            phases.push("snapshot before".to_string(), start, Err(err.to_string()));
+            report.phases = phases.phases;
+            return report;
+        }
+    };
+
+    let to = target.map_or(from, |t| t.mode);
+    for (name, result) in env.checks(from, to).await {
+        phases.push(name, Instant::now(), result);
+    }
+
+    let mut switched = false;
+    if let Some(target) = target.filter(|t| t.round_trip && phases.passed()) {
+        report.test_mode = target.mode;
+        switched = true;
+        let start = Instant::now();
+        let res = env.switch(target.mode).await;
+        if phases.push(
+            format!("switch to {}", target.mode),
+            start,
+            res.map(|_| String::new()).map_err(|e| e.to_string()),
+        ) {
+            let start = Instant::now();
+            let res = env.switch(from).await;
+            phases.push(
+                format!("switch back to {from}"),
+                start,
+                res.map(|_| String::new()).map_err(|e| e.to_string()),
+            );
+        }
+    }
+
+    let start = Instant::now();
+    let changed = match env.snapshot().await {
+        Ok(after) => {
+            let diff = after.diff(&before);
+            let changed = !diff.is_empty();
+            phases.push(
+                "compare with snapshot".to_string(),
+                start,
+                if changed {
+                    Err(diff.join(", "))
+                } else {
+                    Ok(String::new())
+                },
+            );
+            changed
+        }
+        Err(err) => {
+            phases.push("snapshot after".to_string(), start, Err(err.to_string()));
+            true
+        }
+    };
+
+    if switched && (changed || !phases.passed()) {
+        let start = Instant::now();
+        let res = match env.restore(&before).await {
+            Ok(_) => match env.snapshot().await {
+                Ok(after) => {
+                    let diff = after.diff(&before);
+                    if diff.is_empty() {
+                        Ok(String::new())
+                    } else {
+                        Err(format!("still changed: {}", diff.join(", ")))
+                    }
+                }
+                Err(err) => Err(err.to_string()),
+            },
+            Err(err) => Err(err.to_string()),
+        };
+        report.restored = phases.push("restore".to_string(), start, res);
+    }
+
+    report.passed = phases.passed();
+    report.phases = phases.phases;
+    report
+}
+
+/// Modules needed in `mode`, checked with `modinfo`
+fn required_modules(mode: GfxMode, vendor: GfxVendor) -> Vec<&'static str> {
+    match mode {
+        GfxMode::Vfio => vec!["vfio-pci"],
+        GfxMode::Hybrid | GfxMode::NvidiaNoModeset | GfxMode::AsusEgpu | GfxMode::AsusMuxDgpu => {
+            match vendor {
+                GfxVendor::Nvidia => vec!["nvidia"],
+                GfxVendor::Amd => vec!["amdgpu"],
+                _ => vec![],
+            }
+        }
+        GfxMode::Integrated | GfxMode::None => vec![],
+    }
+}
+
+fn module_available(module: &str) -> bool {
+    Command::new("modinfo")
+        .args(["-n", module])
+        .output()
+        .map_or(false, |out| out.status.success())
+}
+
+/// The daemon as a self-test environment
+pub(crate) struct CtrlSelfTest<'a> {
+    pub ctrl: &'a mut CtrlGraphics,
+}
+
+impl CtrlSelfTest<'_> {
+    async fn take_snapshot(&self) -> Result<SystemSnapshot, GfxError> {
+        let (mode, config_mode) = {
+            let config = self.ctrl.config.lock().await;
+            (config.effective_mode(), config.mode)
+        };
+        let dgpu_drivers = self
+            .ctrl
+            .dgpu
+            .lock()
+            .await
+            .devices()
+            .iter()
+            .map(|dev| {
+                let driver = dev
+                    .driver()
+                    .ok()
+                    .and_then(|d| d.file_name().map(|n| n.to_string_lossy().to_string()))
+                    .unwrap_or_else(|| "none".to_string());
+                format!("{}={driver}", dev.name())
+            })
+            .collect();
+        Ok(SystemSnapshot {
+            mode,
+            config_mode,
+            modprobe_conf: fs::read_to_string(MODPROBE_PATH).ok(),
+            dgpu_drivers,
+            nvidia_loaded: nvidia_module_loaded(),
+            vfio_loaded: Path::new("/sys/module/vfio_pci").exists(),
+        })
+    }
+
+    async fn run_checks(
+        &self,
+        from: GfxMode,
+        to: GfxMode,
+    ) -> Vec<(String, Result<String, String>)> {
+        let config = self.ctrl.config.lock().await.clone();
+        let dgpu = self.ctrl.dgpu_snapshot().await;
+        let vendor = dgpu.vendor();
+        let mut checks = Vec::new();
+
+        let describe = |plan: Action| match plan {
+            Action::StagedActions(actions) => format!("{} actions", actions.len()),
+            Action::UserAction(action) => <&str>::from(action).to_string(),
+        };
+        checks.push((
+            format!("plan {from} to {to}"),
+            Ok(format!(
+                "there: {}, back: {}",
+                describe(StagedAction::action_list_for_switch(
+                    &config, vendor, from, to
+                )),
+                describe(StagedAction::action_list_for_switch(
+                    &config, vendor, to, from
+                ))
+            )),
+        ));
+
+        let render = std::env::temp_dir().join(format!(
+            "supergfxd-self-test-{}-modprobe.conf",
+            std::process::id()
+        ));
+        let rendered = match modprobe_conf(to, &dgpu, config.modprobe_extra(to)) {
+            Ok(Some(content)) => fs::write(&render, &content)
+                .and_then(|_| fs::read(&render))
+                .map_err(|e| format!("{}: {e}", render.display()))
+                .and_then(|read| {
+                    if read == content {
+                        Ok(format!("{} bytes", content.len()))
+                    } else {
+                        Err("rendered file did not read back".to_string())
+                    }
+                }),
+            Ok(None) => Ok("not needed for this dGPU".to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        fs::remove_file(&render).ok();
+        checks.push((format!("render modprobe conf for {to}"), rendered));
+
+        let missing: Vec<&str> = required_modules(to, vendor)
+            .into_iter()
+            .filter(|module| !module_available(module))
+            .collect();
+        checks.push((
+            "driver availability".to_string(),
+            if missing.is_empty() {
+                Ok(String::new())
+            } else {
+                Err(format!("missing modules: {}", missing.join(", ")))
+            },
+        ));
+
+        let hotplug = match config.effective_hotplug_type() {
+            HotplugType::None => Ok("not used".to_string()),
+            HotplugType::Std if dgpu.hotplug_capable() => Ok("kernel hotplug".to_string()),
+            HotplugType::Std => Err("hotplug_type is Std but the dGPU slot has no hotplug".into()),
+            HotplugType::Asus if asus_dgpu_disable_exists() => Ok("ASUS dgpu_disable".into()),
+            HotplugType::Asus => Err("hotplug_type is Asus but dgpu_disable is missing".into()),
+        };
+        checks.push(("hotplug capability".to_string(), hotplug));
+        checks
+    }
+
+    async fn do_switch(&mut self, mode: GfxMode) -> Result<(), GfxError> {
+        let options = SetModeOptions {
+            skip_pre_stop_delay: true,
+            ..Default::default()
+        };
+        self.ctrl
+            .set_gfx_mode_with_options(mode, options, &Actor::Daemon)
+            .await?;
+        let start = Instant::now();
+        loop {
+            {
+                let config = self.ctrl.config.lock().await;
+                match config.switch_state {
+                    SwitchState::Idle if config.effective_mode() == mode => return Ok(()),
+                    SwitchState::Idle => {
+                        return Err(GfxError::NotSupported(format!(
+                            "The switch to {mode} did not complete, the mode is {}",
+                            config.effective_mode()
+                        )))
+                    }
+                    SwitchState::Stalled => {
+                        return Err(GfxError::NotSupported(format!(
+                            "The switch to {mode} stalled"
+                        )))
+                    }
+                    _ => {}
+                }
+            }
+            if start.elapsed() > SELF_TEST_SWITCH_TIMEOUT {
+                return Err(GfxError::NotSupported(format!(
+                    "The switch to {mode} took more than {}s",
+                    SELF_TEST_SWITCH_TIMEOUT.as_secs()
+                )));
+            }
+            sleep(SELF_TEST_SWITCH_POLL).await;
+        }
+    }
+
+    async fn do_restore(&mut self, before: &SystemSnapshot) -> Result<(), GfxError> {
+        let mode = self.ctrl.config.lock().await.effective_mode();
+        if mode != before.mode {
+            warn!("self-test: restoring mode {}", before.mode);
+            self.do_switch(before.mode).await?;
+        }
+        {
+            let mut config = self.ctrl.config.lock().await;
+            if config.mode != before.config_mode {
+                warn!("self-test: restoring config mode {}", before.config_mode);
+                config.mode = before.config_mode;
+                config.write()?;
+            }
+        }
+        if fs::read_to_string(MODPROBE_PATH).ok() != before.modprobe_conf {
+            warn!("self-test: restoring {MODPROBE_PATH}");
+            note_write(Path::new(MODPROBE_PATH));
+            match before.modprobe_conf.as_ref() {
+                Some(content) => fs::write(MODPROBE_PATH, content)
+                    .map_err(|e| GfxError::Write(MODPROBE_PATH.into(), e))?,
+                None => fs::remove_file(MODPROBE_PATH)
+                    .map_err(|e| GfxError::Write(MODPROBE_PATH.into(), e))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SelfTestEnv for CtrlSelfTest<'_> {
+    fn snapshot(&mut self) -> BoxFuture<'_, Result<SystemSnapshot, GfxError>> {
+        Box::pin(self.take_snapshot())
+    }
+
+    fn checks(
+        &mut self,
+        from: GfxMode,
+        to: GfxMode,
+    ) -> BoxFuture<'_, Vec<(String, Result<String, String>)>> {
+        Box::pin(self.run_checks(from, to))
+    }
+
+    fn switch(&mut self, mode: GfxMode) -> BoxFuture<'_, Result<(), GfxError>> {
+        Box::pin(self.do_switch(mode))
+    }
+
+    fn restore<'a>(
+        &'a mut self,
+        before: &'a SystemSnapshot,
+    ) -> BoxFuture<'a, Result<(), GfxError>> {
+        Box::pin(self.do_restore(before))
+    }
+}
+
+impl CtrlGraphics {
+    /// Run a self-test against the running system, see `run_self_test`. The caller must
+    /// check that it is allowed.
+    pub async fn run_self_test(&mut self) -> Result<SelfTestReport, GfxError> {
+        self.check_mutation_allowed()?;
+        if self.config.lock().await.pending_mode.is_some() {
+            return Err(GfxError::NotSupported(
+                "A mode switch is in progress".to_string(),
+            ));
+        }
+        let from = self.config.lock().await.effective_mode();
+        let supported = self.get_supported_modes().await;
+        let config = self.config.lock().await.clone();
+        let vendor = self.get_gfx_vendor().await;
+        let target = choose_target(from, &supported, |from, to| {
+            StagedAction::action_list_for_switch(&config, vendor, from, to)
+        });
+        info!("self-test: starting from {from} with {target:?}");
+        Ok(run_self_test(&mut CtrlSelfTest { ctrl: self }, from, target).await)
+    }
+}
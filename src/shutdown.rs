@@ -0,0 +1,93 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::lock::Mutex;
+use log::{error, info, warn};
+use tokio::time::{sleep, Instant};
+use zbus::object_server::SignalEmitter;
+
+use crate::{
+    config::GfxConfig,
+    controller::SwitchState,
+    pci_device::GfxMode,
+    signal_counters::{emit_counted, Signal},
+};
+
+/// How long a parked switch has to finish the action it is performing. Less than the
+/// `TimeoutStopSec` of the service, so the config is written before systemd kills the daemon.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+/// How often the switch is checked while waiting for it to stop
+const PARK_POLL: Duration = Duration::from_millis(100);
+
+/// What a shutdown did to the switch which was running
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Interrupted {
+    /// No switch was running
+    Nothing,
+    /// The switch to this mode hadn't changed anything yet and was cancelled
+    Cancelled(GfxMode),
+    /// The switch to this mode had started changing the system, it stops after the action
+    /// it is performing
+    Parked(GfxMode),
+}
+
+/// The rest of a shutdown started with `CtrlGraphics::begin_shutdown`
+pub struct ShutdownWait {
+    pub(crate) config: Arc<Mutex<GfxConfig>>,
+    pub(crate) signal_ctxt: Option<SignalEmitter<'static>>,
+    pub interrupted: Interrupted,
+}
+
+impl ShutdownWait {
+    /// Wait up to `grace` for a parked switch to stop, then write the config and emit
+    /// `NotifyShutdown`. Returns `false` if the switch was still running after `grace`.
+    pub async fn finish(self, grace: Duration) -> bool {
+        let stopped = match self.interrupted {
+            Interrupted::Parked(_) => wait_switch_stopped(&self.config, grace).await,
+            Interrupted::Nothing | Interrupted::Cancelled(_) => true,
+        };
+        let summary = interrupted_summary(self.interrupted, stopped);
+        if !summary.is_empty() {
+            info!("shutdown: {summary}");
+        }
+        self.config
+            .lock()
+            .await
+            .write()
+            .unwrap_or_else(|err| error!("shutdown: {err}"));
+        if let Some(ctxt) = self.signal_ctxt.as_ref() {
+            emit_counted!(ctxt, Signal::Shutdown, notify_shutdown(&summary))
+                .await
+                .unwrap_or_else(|err| warn!("{}", err));
+        }
+        stopped
+    }
+}
+
+/// Wait up to `grace` for the switch to no longer be `Switching`
+pub(crate) async fn wait_switch_stopped(config: &Mutex<GfxConfig>, grace: Duration) -> bool {
+    let deadline = Instant::now() + grace;
+    loop {
+        if config.lock().await.switch_state != SwitchState::Switching {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            warn!("shutdown: switch still running after {}s", grace.as_secs());
+            return false;
+        }
+        sleep(PARK_POLL).await;
+    }
+}
+
+/// The `NotifyShutdown` text for what happened to the switch, empty if none was running
+pub(crate) fn interrupted_summary(interrupted: Interrupted, stopped: bool) -> String {
+    match interrupted {
+        Interrupted::Nothing => String::new(),
+        Interrupted::Cancelled(mode) => format!("Switch to {mode} cancelled"),
+        Interrupted::Parked(mode) if stopped => format!(
+            "Switch to {mode} stopped part way, the configured mode is put back on the next start"
+        ),
+        Interrupted::Parked(mode) => {
+            format!("Switch to {mode} was still running when supergfxd stopped")
+        }
+    }
+}
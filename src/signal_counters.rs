@@ -0,0 +1,247 @@
+use std::sync::Mutex;
+
+use serde_derive::{Deserialize, Serialize};
+use zbus::{object_server::SignalEmitter, zvariant::Type};
+
+use crate::{controller::CtrlGraphics, signal_filter::signal_targets};
+
+/// The signals of the daemon which are counted. `NotifyEvent` isn't one, it carries the
+/// sequence of the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Signal {
+    GfxStatus,
+    Gfx,
+    ModeChange,
+    Suggestion,
+    DockSuggestion,
+    EgpuPresence,
+    ScheduleSuggestion,
+    Action,
+    SwitchAdvisory,
+    SwitchWaiting,
+    LogoutTimeout,
+    PendingLogout,
+    SupportedChanged,
+    SwitchCountdown,
+    SwitchProgress,
+    SwitchComplete,
+    Drift,
+    InitramfsAdvisory,
+    BootAdvisory,
+    Attention,
+    ReadinessChanged,
+    Error,
+    Shutdown,
+}
+
+impl Signal {
+    pub(crate) const ALL: &'static [Signal] = &[
+        Signal::GfxStatus,
+        Signal::Gfx,
+        Signal::ModeChange,
+        Signal::Suggestion,
+        Signal::DockSuggestion,
+        Signal::EgpuPresence,
+        Signal::ScheduleSuggestion,
+        Signal::Action,
+        Signal::SwitchAdvisory,
+        Signal::SwitchWaiting,
+        Signal::LogoutTimeout,
+        Signal::PendingLogout,
+        Signal::SupportedChanged,
+        Signal::SwitchCountdown,
+        Signal::SwitchProgress,
+        Signal::SwitchComplete,
+        Signal::Drift,
+        Signal::InitramfsAdvisory,
+        Signal::BootAdvisory,
+        Signal::Attention,
+        Signal::ReadinessChanged,
+        Signal::Error,
+        Signal::Shutdown,
+    ];
+
+    /// The category of `SIGNAL_CATEGORIES` a client asks for with `SetSignalFilter` to get
+    /// the signal. `NotifyEvent` is in `events`.
+    pub(crate) fn category(self) -> &'static str {
+        match self {
+            Signal::GfxStatus => "power",
+            Signal::Gfx | Signal::ModeChange | Signal::Action | Signal::SupportedChanged => "mode",
+            Signal::SwitchAdvisory
+            | Signal::SwitchCountdown
+            | Signal::SwitchProgress
+            | Signal::SwitchComplete
+            | Signal::ReadinessChanged => "progress",
+            Signal::SwitchWaiting | Signal::LogoutTimeout | Signal::PendingLogout => "waiting",
+            Signal::Suggestion
+            | Signal::DockSuggestion
+            | Signal::EgpuPresence
+            | Signal::ScheduleSuggestion => "suggestions",
+            Signal::Drift => "drift",
+            Signal::InitramfsAdvisory | Signal::BootAdvisory | Signal::Attention => "advisories",
+            Signal::Error => "errors",
+            Signal::Shutdown => "daemon",
+        }
+    }
+
+    /// The dbus name of the signal
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Signal::GfxStatus => "NotifyGfxStatus",
+            Signal::Gfx => "NotifyGfx",
+            Signal::ModeChange => "NotifyModeChange",
+            Signal::Suggestion => "NotifySuggestion",
+            Signal::DockSuggestion => "NotifyDockSuggestion",
+            Signal::EgpuPresence => "NotifyEgpuPresence",
+            Signal::ScheduleSuggestion => "NotifyScheduleSuggestion",
+            Signal::Action => "NotifyAction",
+            Signal::SwitchAdvisory => "NotifySwitchAdvisory",
+            Signal::SwitchWaiting => "NotifySwitchWaiting",
+            Signal::LogoutTimeout => "NotifyLogoutTimeout",
+            Signal::PendingLogout => "NotifyPendingLogout",
+            Signal::SupportedChanged => "NotifySupportedChanged",
+            Signal::SwitchCountdown => "NotifySwitchCountdown",
+            Signal::SwitchProgress => "NotifySwitchProgress",
+            Signal::SwitchComplete => "NotifySwitchComplete",
+            Signal::Drift => "NotifyDrift",
+            Signal::InitramfsAdvisory => "NotifyInitramfsAdvisory",
+            Signal::BootAdvisory => "NotifyBootAdvisory",
+            Signal::Attention => "NotifyAttention",
+            Signal::ReadinessChanged => "NotifyReadinessChanged",
+            Signal::Error => "NotifyError",
+            Signal::Shutdown => "NotifyShutdown",
+        }
+    }
+}
+
+/// The count of every signal emitted since supergfxd started, from `SignalCounters`. A
+/// client which keeps the sequence of the last `NotifyEvent` it saw can tell after it
+/// reconnects to the bus whether it missed anything, and read `Status` again if it did.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SignalCounters {
+    /// Increased once for each signal emitted, and sent with it in `NotifyEvent`
+    pub sequence: u64,
+    /// How many times each signal was emitted by its dbus name, such as `NotifyGfx`. Every
+    /// counted signal is listed, including those not emitted yet.
+    pub signals: Vec<(String, u64)>,
+}
+
+impl SignalCounters {
+    /// Whether anything was emitted since the `NotifyEvent` with the sequence `last_seen`.
+    /// A sequence lower than it means supergfxd restarted, which is also a miss.
+    pub fn missed_since(&self, last_seen: u64) -> bool {
+        self.sequence != last_seen
+    }
+
+    /// How many times the signal named `signal` was emitted
+    pub fn count(&self, signal: &str) -> u64 {
+        self.signals
+            .iter()
+            .find(|(name, _)| name == signal)
+            .map_or(0, |(_, count)| *count)
+    }
+}
+
+/// The sequence and the count of each signal
+#[derive(Debug)]
+pub(crate) struct SignalLedger {
+    sequence: u64,
+    counts: Vec<(Signal, u64)>,
+}
+
+impl SignalLedger {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sequence: 0,
+            counts: Vec::new(),
+        }
+    }
+
+    /// Count an emission of `signal`, returns its sequence
+    pub(crate) fn stamp(&mut self, signal: Signal) -> u64 {
+        self.sequence += 1;
+        match self
+            .counts
+            .iter_mut()
+            .find(|(counted, _)| *counted == signal)
+        {
+            Some((_, count)) => *count += 1,
+            None => self.counts.push((signal, 1)),
+        }
+        self.sequence
+    }
+
+    pub(crate) fn counters(&self) -> SignalCounters {
+        SignalCounters {
+            sequence: self.sequence,
+            signals: Signal::ALL
+                .iter()
+                .map(|signal| {
+                    let count = self
+                        .counts
+                        .iter()
+                        .find(|(counted, _)| counted == signal)
+                        .map_or(0, |(_, count)| *count);
+                    (signal.name().to_string(), count)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The signals emitted for as long as the daemon runs
+static LEDGER: Mutex<SignalLedger> = Mutex::new(SignalLedger::new());
+
+/// The counters of the signals emitted so far
+pub(crate) fn signal_counters() -> SignalCounters {
+    LEDGER.lock().unwrap_or_else(|e| e.into_inner()).counters()
+}
+
+/// Count an emission of `signal`, returns its sequence for `NotifyEvent`
+pub(crate) fn stamp_signal(signal: Signal) -> u64 {
+    LEDGER
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .stamp(signal)
+}
+
+/// Emit `NotifyEvent` for the emission of `signal` with `sequence`, to the clients which
+/// asked for `events` or to everyone
+pub(crate) async fn emit_event(
+    ctxt: &SignalEmitter<'_>,
+    signal: Signal,
+    sequence: u64,
+) -> zbus::Result<()> {
+    let mut sent = Ok(());
+    for target in signal_targets(ctxt, "events") {
+        sent = sent.and(CtrlGraphics::notify_event(&target, signal.name(), sequence).await);
+    }
+    sent
+}
+
+/// Emit the signal `CtrlGraphics::$notify` with `$arg`s, such as
+/// `emit_counted!(ctxt, Signal::Gfx, notify_gfx(&mode))`, counting it as `$signal`, then
+/// `NotifyEvent` with its sequence. It is sent to the clients which asked for its category
+/// with `SetSignalFilter`, or to everyone if none did. Every signal but `NotifyEvent` is
+/// emitted through here so the counters match what was sent. One which fails to send is
+/// still counted, as a client didn't get it. Evaluates to a future.
+macro_rules! emit_counted {
+    ($ctxt:expr, $signal:expr, $notify:ident($($arg:expr),* $(,)?)) => {
+        async {
+            let ctxt: &zbus::object_server::SignalEmitter<'_> = $ctxt;
+            let signal: $crate::signal_counters::Signal = $signal;
+            let sequence = $crate::signal_counters::stamp_signal(signal);
+            let mut sent: zbus::Result<()> = Ok(());
+            for target in $crate::signal_filter::signal_targets(ctxt, signal.category()) {
+                sent = sent.and(
+                    $crate::controller::CtrlGraphics::$notify(&target, $($arg),*).await,
+                );
+            }
+            match sent {
+                Ok(()) => $crate::signal_counters::emit_event(ctxt, signal, sequence).await,
+                Err(err) => Err(err),
+            }
+        }
+    };
+}
+pub(crate) use emit_counted;
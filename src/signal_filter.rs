@@ -0,0 +1,149 @@
+use std::sync::Mutex;
+
+use serde_derive::{Deserialize, Serialize};
+use zbus::{names::BusName, object_server::SignalEmitter, zvariant::Type};
+
+use crate::{error::GfxError, validate::InputClass};
+
+/// The categories a client can ask for with `SetSignalFilter`, see `Signal::category`
+pub const SIGNAL_CATEGORIES: &[&str] = &[
+    "power",
+    "mode",
+    "progress",
+    "waiting",
+    "suggestions",
+    "drift",
+    "advisories",
+    "errors",
+    "daemon",
+    "events",
+];
+
+/// The categories of signals a client asked for, in `Capabilities`
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SignalFilter {
+    /// The unique bus name of the client, such as `:1.42`
+    pub owner: String,
+    pub categories: Vec<String>,
+}
+
+/// Who a signal of a category is sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SignalRoute {
+    /// To everyone listening, as no client asked for the category
+    Broadcast,
+    /// Only to the clients which asked for the category, by their unique bus names
+    Unicast(Vec<String>),
+}
+
+/// The signal filters of the clients. A category no client asked for is broadcast as
+/// before filters existed, one some client asked for is only sent to those which did. A
+/// filter ends when its client sets an empty one or leaves the bus.
+#[derive(Debug)]
+pub(crate) struct SignalFilters {
+    filters: Vec<SignalFilter>,
+}
+
+impl SignalFilters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            filters: Vec::new(),
+        }
+    }
+
+    /// Set the filter of `owner` to `categories`, replacing the one it had. Empty removes
+    /// it. Fails without changing anything if a category isn't one of `SIGNAL_CATEGORIES`.
+    pub(crate) fn set(&mut self, owner: &str, categories: &[String]) -> Result<(), GfxError> {
+        for category in categories {
+            if !SIGNAL_CATEGORIES.contains(&category.as_str()) {
+                return Err(GfxError::InvalidInput(
+                    InputClass::Identifier,
+                    format!(
+                        "{category:?} is not a signal category, one of {}",
+                        SIGNAL_CATEGORIES.join(", ")
+                    ),
+                ));
+            }
+        }
+        self.filters.retain(|filter| filter.owner != owner);
+        if categories.is_empty() {
+            return Ok(());
+        }
+        let mut wanted: Vec<String> = Vec::new();
+        for category in categories {
+            if !wanted.contains(category) {
+                wanted.push(category.clone());
+            }
+        }
+        self.filters.push(SignalFilter {
+            owner: owner.to_string(),
+            categories: wanted,
+        });
+        Ok(())
+    }
+
+    /// Remove the filter of `owner`, which has left the bus. Returns whether it had one.
+    pub(crate) fn owner_gone(&mut self, owner: &str) -> bool {
+        let before = self.filters.len();
+        self.filters.retain(|filter| filter.owner != owner);
+        self.filters.len() != before
+    }
+
+    /// Who a signal of `category` is sent to
+    pub(crate) fn route(&self, category: &str) -> SignalRoute {
+        let owners: Vec<String> = self
+            .filters
+            .iter()
+            .filter(|filter| filter.categories.iter().any(|c| c == category))
+            .map(|filter| filter.owner.clone())
+            .collect();
+        if owners.is_empty() {
+            SignalRoute::Broadcast
+        } else {
+            SignalRoute::Unicast(owners)
+        }
+    }
+
+    pub(crate) fn filters(&self) -> Vec<SignalFilter> {
+        self.filters.clone()
+    }
+}
+
+/// The filters of the clients connected to the daemon
+static FILTERS: Mutex<SignalFilters> = Mutex::new(SignalFilters::new());
+
+fn with_filters<T>(f: impl FnOnce(&mut SignalFilters) -> T) -> T {
+    f(&mut FILTERS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Set the filter of the client `owner`, see `SignalFilters::set`
+pub(crate) fn set_signal_filter(owner: &str, categories: &[String]) -> Result<(), GfxError> {
+    with_filters(|filters| filters.set(owner, categories))
+}
+
+/// Remove the filter of `owner`, which has left the bus
+pub(crate) fn signal_filter_owner_gone(owner: &str) -> bool {
+    with_filters(|filters| filters.owner_gone(owner))
+}
+
+/// The filters set by the clients connected now
+pub(crate) fn signal_filters() -> Vec<SignalFilter> {
+    with_filters(|filters| filters.filters())
+}
+
+/// The emitters a signal of `category` is sent with: `ctxt` itself to broadcast it, or a
+/// copy addressed to each client which asked for it. A client whose name can't be a bus
+/// name is skipped.
+pub(crate) fn signal_targets(
+    ctxt: &SignalEmitter<'_>,
+    category: &str,
+) -> Vec<SignalEmitter<'static>> {
+    match with_filters(|filters| filters.route(category)) {
+        SignalRoute::Broadcast => vec![ctxt.to_owned()],
+        SignalRoute::Unicast(owners) => owners
+            .into_iter()
+            .filter_map(|owner| BusName::try_from(owner).ok())
+            .map(|owner| ctxt.to_owned().set_destination(owner))
+            .collect(),
+    }
+}
@@ -1,22 +1,30 @@
 use log::{debug, error, info, warn};
-use std::{
-    fs::OpenOptions,
-    io::{Read, Write},
-    path::Path,
-    time::Duration,
-};
-use tokio::time::sleep;
+use serde_derive::{Deserialize, Serialize};
+use std::{fs, io::Write, path::Path, time::Duration};
+use tokio::time::{sleep, Instant};
+use zbus::zvariant::Type;
 
 use crate::{
+    actions::UserActionRequired,
     error::GfxError,
     pci_device::{rescan_pci_bus, GfxMode},
+    sandbox::note_write,
+    sysfs::{Sysfs, SysfsPath, ASUS_DGPU_DISABLE_PATH, ASUS_GPU_MUX_PATH},
 };
 
-const ASUS_DGPU_DISABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/dgpu_disable";
-const ASUS_EGPU_ENABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/egpu_enable";
-const ASUS_GPU_MUX_PATH: &str = "/sys/devices/platform/asus-nb-wmi/gpu_mux_mode";
-
-const ASUS_EGPU_ALT_ENABLE_PATH: &str = "/sys/bus/platform/devices/asus-nb-wmi/egpu_enable";
+/// Time for the devices to finish powering up or down before a toggle is changed
+const ASUS_TOGGLE_SETTLE: Duration = Duration::from_millis(500);
+/// Time for the devices to wake after a toggle before the PCI bus is rescanned
+const ASUS_TOGGLE_WAKE: Duration = Duration::from_millis(50);
+/// How long `gpu_mux_mode` is retried for at boot. Some models fail the read with EIO for
+/// the first few seconds after asus-wmi loads, until the EC is ready.
+pub(crate) const MUX_READ_WINDOW: Duration = Duration::from_secs(5);
+/// The first wait between reads of `gpu_mux_mode`, doubled after each failed read
+const MUX_READ_BACKOFF: Duration = Duration::from_millis(50);
+/// The longest wait between reads of `gpu_mux_mode`
+const MUX_READ_MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// How long `gpu_mux_mode` is read again for after boot if it had to be assumed
+pub(crate) const MUX_REVERIFY_WINDOW: Duration = Duration::from_secs(120);
 
 pub const ASUS_MODULES_LOAD_PATH: &str = "/etc/modules-load.d/asus.conf";
 pub const ASUS_MODULES_LOAD: &[u8] = br#"
@@ -31,6 +39,7 @@ pub fn create_asus_modules_load_conf() -> Result<bool, GfxError> {
         return Ok(true);
     }
 
+    note_write(Path::new(ASUS_MODULES_LOAD_PATH));
     let mut file = std::fs::OpenOptions::new()
         .create(true)
         .truncate(true)
@@ -71,150 +80,185 @@ impl From<char> for AsusGpuMuxMode {
 }
 
 pub fn asus_gpu_mux_exists() -> bool {
-    Path::new(ASUS_GPU_MUX_PATH).exists()
+    Sysfs::system().gpu_mux.exists()
 }
 
 pub fn asus_gpu_mux_mode() -> Result<AsusGpuMuxMode, GfxError> {
-    let path = ASUS_GPU_MUX_PATH;
-    let mut file = OpenOptions::new()
-        .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(path.into(), err))?;
-
-    let mut data = Vec::new();
-    let res = file
-        .read_to_end(&mut data)
-        .map_err(|err| GfxError::Read(path.into(), err))?;
-    if res == 0 {
-        return Err(GfxError::Read(
-            "Failed to read gpu_mux_mode".to_owned(),
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Could not read"),
-        ));
+    Sysfs::system().gpu_mux.read()
+}
+
+/// A read of `gpu_mux_mode`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum MuxRead {
+    Mode(AsusGpuMuxMode),
+    /// The attribute exists but can't be read yet, the EC isn't ready
+    NotReady,
+    /// The laptop has no MUX
+    Absent,
+}
+
+impl MuxRead {
+    /// Tell a failed read of an attribute which `exists` apart from a missing MUX
+    pub(crate) fn from_read(exists: bool, read: Result<AsusGpuMuxMode, GfxError>) -> Self {
+        match read {
+            Ok(mode) => Self::Mode(mode),
+            Err(_) if !exists => Self::Absent,
+            Err(err) => {
+                debug!("gpu_mux_mode can't be read yet: {err}");
+                Self::NotReady
+            }
+        }
     }
+}
+
+/// Reads `gpu_mux_mode`, so the retries at boot can be tested
+pub(crate) trait MuxReader: Send + Sync {
+    fn read_mux(&self) -> MuxRead;
+}
+
+/// The MUX as asus-wmi has it
+pub(crate) struct SystemMuxReader;
 
-    if let Some(d) = (data[0] as char).to_digit(10) {
-        return Ok(AsusGpuMuxMode::from(d as i8));
+impl MuxReader for SystemMuxReader {
+    fn read_mux(&self) -> MuxRead {
+        let sysfs = Sysfs::system();
+        // Checked again each time, the attribute can appear once asus-wmi has loaded
+        let exists = sysfs.gpu_mux.0.refresh().unwrap_or(false);
+        MuxRead::from_read(exists, sysfs.gpu_mux.read())
+    }
+}
+
+/// Read the MUX, retrying with a backoff for up to `window` while it exists but can't be
+/// read. Returns `MuxRead::NotReady` if it still couldn't be read.
+pub(crate) async fn wait_mux_mode(reader: &dyn MuxReader, window: Duration) -> MuxRead {
+    let deadline = Instant::now() + window;
+    let mut backoff = MUX_READ_BACKOFF;
+    loop {
+        let read = reader.read_mux();
+        if read != MuxRead::NotReady || Instant::now() >= deadline {
+            return read;
+        }
+        sleep(backoff.min(deadline - Instant::now())).await;
+        backoff = (backoff * 2).min(MUX_READ_MAX_BACKOFF);
     }
-    Err(GfxError::Read(
-        "Failed to read gpu_mux_mode".to_owned(),
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "Could not read"),
-    ))
 }
 
 pub fn asus_gpu_mux_set_igpu(igpu_on: bool) -> Result<(), GfxError> {
     debug!("asus_gpu_mux_set_igpu: {igpu_on}");
-    asus_gpu_toggle(igpu_on, ASUS_GPU_MUX_PATH)?;
+    Sysfs::system().gpu_mux.set_igpu(igpu_on)?;
     debug!("asus_gpu_mux_set_igpu: success");
     Ok(())
 }
 
 pub fn asus_dgpu_disable_exists() -> bool {
-    if Path::new(ASUS_DGPU_DISABLE_PATH).exists() {
-        return true;
-    }
-    false
+    Sysfs::system().dgpu_disable.exists()
 }
 
 pub fn asus_dgpu_disabled() -> Result<bool, GfxError> {
-    let path = Path::new(ASUS_DGPU_DISABLE_PATH);
-    let mut file = OpenOptions::new()
-        .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(ASUS_DGPU_DISABLE_PATH.to_string(), err))?;
-    let mut buf = String::new();
-    file.read_to_string(&mut buf)?;
-    if buf.contains('1') {
-        return Ok(true);
+    Sysfs::system().dgpu_disable.disabled()
+}
+
+/// The ASUS toggle state which a switch plan depends on, read when the switch is planned
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct AsusToggleState {
+    /// `dgpu_disable` is set, the internal dGPU is off the PCI bus
+    pub dgpu_disabled: bool,
+}
+
+impl AsusToggleState {
+    /// Read the toggles from sysfs, a missing toggle reads as off
+    pub fn read() -> Self {
+        Self::read_with(|path| fs::read_to_string(path).ok())
+    }
+
+    /// As `read` with `read_attr` returning the contents of a sysfs attribute, `None` if it
+    /// is missing
+    pub fn read_with(read_attr: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            dgpu_disabled: read_attr(ASUS_DGPU_DISABLE_PATH).map_or(false, |s| s.contains('1')),
+        }
     }
-    Ok(false)
 }
 
 /// Special ASUS only feature. On toggle to `off` it will rescan the PCI bus.
-pub fn asus_dgpu_set_disabled(disabled: bool) -> Result<(), GfxError> {
+pub async fn asus_dgpu_set_disabled(disabled: bool) -> Result<(), GfxError> {
     // Do not try to set it again if it has already been changed
     if asus_dgpu_disabled()? == disabled {
         debug!("asus_dgpu_set_disabled: already set to {disabled}. Early return");
         return Ok(());
     }
     debug!("asus_dgpu_set_disabled: {disabled}");
-    // There is a sleep here because this function is generally called after a hotplug
-    // enable, and the deivces require at least a touch of time to finish powering up/down
-    std::thread::sleep(Duration::from_millis(500));
-    // Need to set, scan, set to ensure mode is correctly set
-    asus_gpu_toggle(disabled, ASUS_DGPU_DISABLE_PATH)?;
-    if !disabled {
-        // Purposefully blocking here. Need to force enough time for things to wake
-        std::thread::sleep(Duration::from_millis(50));
-        rescan_pci_bus()?;
-    }
+    asus_settle_and_toggle(
+        disabled,
+        &Sysfs::system().dgpu_disable.0,
+        !disabled,
+        ASUS_TOGGLE_SETTLE,
+    )
+    .await?;
     debug!("asus_dgpu_set_disabled: success");
     Ok(())
 }
 
 pub fn asus_egpu_enable_path() -> &'static str {
-    if Path::new(ASUS_EGPU_ALT_ENABLE_PATH).exists() {
-        return ASUS_EGPU_ALT_ENABLE_PATH;
-    }
-
-    return ASUS_EGPU_ENABLE_PATH;
+    Sysfs::system().egpu_enable.attr().name()
 }
 
 pub fn asus_egpu_enable_exists() -> bool {
-    if Path::new(ASUS_EGPU_ENABLE_PATH).exists() {
-        return true;
-    }
-    if Path::new(ASUS_EGPU_ALT_ENABLE_PATH).exists() {
-        return true;
-    }
-    false
+    Sysfs::system().egpu_enable.exists()
 }
 
 pub fn asus_egpu_enabled() -> Result<bool, GfxError> {
-    let path = Path::new(asus_egpu_enable_path());
-    let mut file = OpenOptions::new()
-        .read(true)
-        .open(path)
-        .map_err(|err| GfxError::Path(asus_egpu_enable_path().to_string(), err))?;
-    let mut buf = String::new();
-    file.read_to_string(&mut buf)?;
-    if buf.contains('1') {
-        return Ok(true);
-    }
-    Ok(false)
+    Sysfs::system().egpu_enable.enabled()
 }
 
 /// Special ASUS only feature. On toggle to `on` it will rescan the PCI bus.
-pub fn asus_egpu_set_enabled(enabled: bool) -> Result<(), GfxError> {
+pub async fn asus_egpu_set_enabled(enabled: bool) -> Result<(), GfxError> {
     if asus_egpu_enabled()? == enabled {
         // Do not try to set it again if it has already been changedif asus_egpu_enabled()? {
         return Ok(());
     }
     debug!("asus_egpu_set_enabled: {enabled}");
+    let sysfs = Sysfs::system();
+    asus_settle_and_toggle(
+        enabled,
+        sysfs.egpu_enable.attr(),
+        enabled,
+        ASUS_TOGGLE_SETTLE,
+    )
+    .await?;
+    debug!("asus_egpu_set_enabled: success");
+    Ok(())
+}
+
+/// Wait `settle` then write the toggle `attr`, optionally rescanning the PCI bus after.
+/// The waits don't block the executor so dbus calls are still answered meanwhile.
+pub(crate) async fn asus_settle_and_toggle(
+    status: bool,
+    attr: &SysfsPath,
+    rescan: bool,
+    settle: Duration,
+) -> Result<(), GfxError> {
     // There is a sleep here because this function is generally called after a hotplug
-    // enable, and the deivces require at least a touch of time to finish powering up
-    std::thread::sleep(Duration::from_millis(500));
+    // enable, and the deivces require at least a touch of time to finish powering up/down
+    sleep(settle).await;
     // Need to set, scan, set to ensure mode is correctly set
-    asus_gpu_toggle(enabled, asus_egpu_enable_path())?;
-    if enabled {
-        // Purposefully blocking here. Need to force enough time for things to wake
-        std::thread::sleep(Duration::from_millis(50));
+    attr.write(if status { "1" } else { "0" })?;
+    if rescan {
+        // Need to force enough time for things to wake
+        sleep(ASUS_TOGGLE_WAKE).await;
         rescan_pci_bus()?;
     }
-    debug!("asus_egpu_set_enabled: success");
     Ok(())
 }
 
-fn asus_gpu_toggle(status: bool, path: &str) -> Result<(), GfxError> {
-    let pathbuf = Path::new(path);
-    let mut file = OpenOptions::new()
-        .write(true)
-        .open(pathbuf)
-        .map_err(|err| GfxError::Path(path.to_string(), err))?;
-    let status = if status { 1 } else { 0 };
-    file.write_all(status.to_string().as_bytes())
-        .map_err(|err| GfxError::Write(path.to_string(), err))?;
-    debug!("switched {path} to {status}");
-    Ok(())
+/// The outcome of `asus_boot_safety_check`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct AsusBootCheck {
+    /// The mode to boot in
+    pub mode: GfxMode,
+    /// `gpu_mux_mode` couldn't be read, so the MUX was assumed to be discreet and `mode` is
+    /// `AsusMuxDgpu`. It must be read again once it can be, see `reverify_mux`.
+    pub mux_assumed: bool,
 }
 
 /// To be called in main reload code. Specific actions required for asus laptops depending
@@ -225,10 +269,30 @@ fn asus_gpu_toggle(status: bool, path: &str) -> Result<(), GfxError> {
 pub async fn asus_boot_safety_check(
     mode: GfxMode,
     asus_use_dgpu_disable: bool,
-) -> Result<GfxMode, GfxError> {
+) -> Result<AsusBootCheck, GfxError> {
+    asus_boot_safety_check_with(
+        mode,
+        asus_use_dgpu_disable,
+        &SystemMuxReader,
+        MUX_READ_WINDOW,
+    )
+    .await
+}
+
+/// As `asus_boot_safety_check` with the MUX read by `mux`, retried for up to `window` while
+/// the EC isn't ready. If it can't be read in that time the MUX is assumed to be discreet,
+/// booting the dGPU in Hybrid while the MUX is discreet is what the check must prevent.
+pub(crate) async fn asus_boot_safety_check_with(
+    mode: GfxMode,
+    asus_use_dgpu_disable: bool,
+    mux: &dyn MuxReader,
+    window: Duration,
+) -> Result<AsusBootCheck, GfxError> {
     debug!("asus_reload: asus_use_dgpu_disable: {asus_use_dgpu_disable}");
     // This is a bit of a crap cycle to ensure that dgpu_disable is there before setting it.
-    if asus_use_dgpu_disable && !asus_dgpu_disable_exists() {
+    let sysfs = Sysfs::system();
+    let dgpu_disable = &sysfs.dgpu_disable.0;
+    if asus_use_dgpu_disable && !dgpu_disable.refresh().unwrap_or(false) {
         if !create_asus_modules_load_conf()? {
             warn!(
                 "asus_boot_safety_check: Reboot required due to {} creation",
@@ -239,64 +303,236 @@ pub async fn asus_boot_safety_check(
         }
         warn!("asus_boot_safety_check: HotPlug type Asus is set but asus-wmi appear not loaded yet. Trying for 2 seconds. If there are issues you may need to add asus_nb_wmi to modules.load.d");
         let mut count = 2000 / 50;
-        while !asus_dgpu_disable_exists() && count != 0 {
+        while !dgpu_disable.refresh().unwrap_or(false) && count != 0 {
             sleep(Duration::from_millis(50)).await;
             count -= 1;
         }
     }
 
-    if asus_gpu_mux_exists() {
-        match asus_gpu_mux_mode()? {
-            AsusGpuMuxMode::Discreet => {
-                if asus_dgpu_disable_exists() && asus_dgpu_disabled()? {
-                    error!("asus_boot_safety_check: dgpu_disable is on while gpu_mux_mode is descrete, can't continue safely, attempting to set dgpu_disable off");
-                    asus_dgpu_set_disabled(false)?;
-                } else {
-                    info!("asus_boot_safety_check: dgpu_disable is off");
-                }
-                return Ok(GfxMode::AsusMuxDgpu);
-            }
-            AsusGpuMuxMode::Optimus => {
-                if mode == GfxMode::AsusMuxDgpu {
-                    warn!("asus_boot_safety_check: MUX is in Optimus mode but mode is set to AsusMuxDgpu. Switching to Hybrid");
-                    return Ok(GfxMode::Hybrid);
-                }
-            }
-        }
+    let mux = wait_mux_mode(mux, window).await;
+    let mux_assumed = mux == MuxRead::NotReady;
+    if mux_assumed {
+        error!(
+            "asus_boot_safety_check: {ASUS_GPU_MUX_PATH} still can't be read after {}s, assuming the MUX is discreet. It will be read again once the EC is ready",
+            window.as_secs()
+        );
     }
+    Ok(AsusBootCheck {
+        mode: asus_boot_mode(mode, asus_use_dgpu_disable, mux).await?,
+        mux_assumed,
+    })
+}
 
-    // Need to always check if dgpu_disable exists since GA401I series and older doesn't have this
-    if asus_dgpu_disable_exists() {
-        let dgpu_disabled = asus_dgpu_disabled()?;
-        // If dgpu_disable is hard set then users won't have a dgpu at all, try set dgpu enabled
-        if !asus_use_dgpu_disable && dgpu_disabled {
-            warn!("It appears dgpu_disable is true on boot with HotPlug type not set to Asus, will attempt to re-enable dgpu");
-            if asus_dgpu_set_disabled(false)
-                .map_err(|e| error!("asus_dgpu_set_disabled: {e:?}"))
-                .is_ok()
-            {
-                return Ok(GfxMode::Hybrid);
+/// The ASUS attributes the safety check decides the mode from, as read
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) struct AsusReadings {
+    pub mux: MuxRead,
+    /// `dgpu_disable`, `None` if the laptop doesn't have it
+    pub dgpu_disabled: Option<bool>,
+    /// `egpu_enable`, `None` if the laptop doesn't have it
+    pub egpu_enabled: Option<bool>,
+}
+
+impl AsusReadings {
+    /// Read the toggles of `sysfs`, with the MUX as already read
+    pub(crate) fn read(sysfs: &Sysfs, mux: MuxRead) -> Result<Self, GfxError> {
+        Ok(Self {
+            mux,
+            dgpu_disabled: if sysfs.dgpu_disable.exists() {
+                Some(sysfs.dgpu_disable.disabled()?)
+            } else {
+                None
+            },
+            egpu_enabled: if sysfs.egpu_enable.exists() {
+                Some(sysfs.egpu_enable.enabled()?)
             } else {
-                return Ok(GfxMode::Integrated);
+                None
+            },
+        })
+    }
+}
+
+/// What the ASUS safety check found, before anything is changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AsusEvaluation {
+    /// The mode which should be active
+    pub mode: GfxMode,
+    /// The attribute values which decided `mode`, empty if the requested mode stands
+    pub reasons: Vec<String>,
+    /// `dgpu_disable` must be turned off for `mode`
+    pub enable_dgpu: bool,
+    /// The mode if turning `dgpu_disable` off fails, `None` if that is an error
+    pub fallback: Option<GfxMode>,
+}
+
+impl AsusEvaluation {
+    fn keep(mode: GfxMode) -> Self {
+        Self {
+            mode,
+            reasons: Vec::new(),
+            enable_dgpu: false,
+            fallback: None,
+        }
+    }
+
+    fn change(mode: GfxMode, reason: String) -> Self {
+        Self {
+            mode,
+            reasons: vec![reason],
+            enable_dgpu: false,
+            fallback: None,
+        }
+    }
+}
+
+/// The ASUS safety check run on demand, for the `SafetyCheck` dbus method
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
+pub struct SafetyCheck {
+    /// The mode now
+    pub current: GfxMode,
+    /// The mode the ASUS attributes say should be active
+    pub mode: GfxMode,
+    /// The attribute values which decided `mode`, empty if nothing is wrong
+    pub reasons: Vec<String>,
+    /// `dgpu_disable` would be turned off
+    pub enable_dgpu: bool,
+    /// A switch to `mode` is needed
+    pub switch_required: bool,
+    /// What the user must do for that switch, `Nothing` if there is none
+    pub action: UserActionRequired,
+}
+
+/// Decide the mode to be in for the toggles and the MUX as read, changing nothing. A MUX
+/// which can't be read is taken to be discreet.
+pub(crate) fn evaluate_asus_mode(
+    mode: GfxMode,
+    asus_use_dgpu_disable: bool,
+    readings: &AsusReadings,
+) -> AsusEvaluation {
+    let mux_discreet = match readings.mux {
+        MuxRead::Absent => None,
+        MuxRead::Mode(AsusGpuMuxMode::Discreet) => {
+            Some(format!("{ASUS_GPU_MUX_PATH} is 0, the MUX is discreet"))
+        }
+        MuxRead::NotReady => Some(format!(
+            "{ASUS_GPU_MUX_PATH} can't be read, the MUX is assumed discreet"
+        )),
+        MuxRead::Mode(AsusGpuMuxMode::Optimus) => {
+            if mode == GfxMode::AsusMuxDgpu {
+                return AsusEvaluation::change(
+                    GfxMode::Hybrid,
+                    format!(
+                        "{ASUS_GPU_MUX_PATH} is 1, the MUX is in Optimus but the mode is {mode}"
+                    ),
+                );
             }
-        } else if dgpu_disabled && mode != GfxMode::Integrated {
-            warn!("asus_boot_safety_check: dgpu_disable is on but the mode isn't Integrated, setting mode to Integrated");
-            return Ok(GfxMode::Integrated);
+            None
+        }
+    };
+    if let Some(reason) = mux_discreet {
+        let mut evaluation = AsusEvaluation::keep(GfxMode::AsusMuxDgpu);
+        if mode != GfxMode::AsusMuxDgpu {
+            evaluation.reasons.push(reason);
+        }
+        if readings.dgpu_disabled == Some(true) {
+            evaluation.reasons.push(format!(
+                "{ASUS_DGPU_DISABLE_PATH} is 1 while the MUX is discreet, it must be turned off"
+            ));
+            evaluation.enable_dgpu = true;
+        }
+        return evaluation;
+    }
+
+    // dgpu_disable is missing on the GA401I series and older
+    if readings.dgpu_disabled == Some(true) {
+        // If dgpu_disable is hard set then users won't have a dgpu at all
+        if !asus_use_dgpu_disable {
+            return AsusEvaluation {
+                mode: GfxMode::Hybrid,
+                reasons: vec![format!(
+                    "{ASUS_DGPU_DISABLE_PATH} is 1 while hotplug_type isn't Asus, the dGPU must be enabled again"
+                )],
+                enable_dgpu: true,
+                fallback: Some(GfxMode::Integrated),
+            };
+        } else if mode != GfxMode::Integrated {
+            return AsusEvaluation::change(
+                GfxMode::Integrated,
+                format!("{ASUS_DGPU_DISABLE_PATH} is 1 but the mode is {mode}"),
+            );
         }
     }
 
-    if asus_egpu_enable_exists() {
-        if asus_egpu_enabled()? && mode != GfxMode::AsusEgpu {
-            warn!("asus_boot_safety_check: egpu_enable is on but the mode isn't AsusEgpu, setting mode to AsusEgpu");
-            return Ok(GfxMode::AsusEgpu);
-        } else if asus_use_dgpu_disable // using asus hotplug?
-            && asus_dgpu_disable_exists()
-            && asus_dgpu_disabled()?
-        // and dgpu is disabled?
-        {
-            return Ok(GfxMode::Integrated); // really should be in this mode if dgpu disabled
+    if readings.egpu_enabled == Some(true) && mode != GfxMode::AsusEgpu {
+        return AsusEvaluation::change(
+            GfxMode::AsusEgpu,
+            format!("egpu_enable is 1 but the mode is {mode}"),
+        );
+    }
+    AsusEvaluation::keep(mode)
+}
+
+/// The mode to boot in for the toggles and the MUX as read, turning `dgpu_disable` off
+/// where the check says it must be
+async fn asus_boot_mode(
+    mode: GfxMode,
+    asus_use_dgpu_disable: bool,
+    mux: MuxRead,
+) -> Result<GfxMode, GfxError> {
+    let readings = AsusReadings::read(&Sysfs::system(), mux)?;
+    let evaluation = evaluate_asus_mode(mode, asus_use_dgpu_disable, &readings);
+    for reason in &evaluation.reasons {
+        warn!("asus_boot_safety_check: {reason}");
+    }
+    if evaluation.mode != mode {
+        warn!(
+            "asus_boot_safety_check: setting mode to {}",
+            evaluation.mode
+        );
+    }
+    apply_asus_toggles(&evaluation).await
+}
+
+/// Turn `dgpu_disable` off if `evaluation` says it must be, returning the mode to be in
+pub(crate) async fn apply_asus_toggles(evaluation: &AsusEvaluation) -> Result<GfxMode, GfxError> {
+    if !evaluation.enable_dgpu {
+        return Ok(evaluation.mode);
+    }
+    info!("asus_boot_safety_check: turning dgpu_disable off");
+    match (asus_dgpu_set_disabled(false).await, evaluation.fallback) {
+        (Ok(()), _) => Ok(evaluation.mode),
+        (Err(err), Some(fallback)) => {
+            error!("asus_dgpu_set_disabled: {err:?}");
+            Ok(fallback)
         }
+        (Err(err), None) => Err(err),
     }
+}
+
+/// What reading the MUX again after it was assumed discreet at boot found
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub(crate) enum MuxReverify {
+    /// The MUX is discreet, as assumed
+    Confirmed,
+    /// The MUX is in Optimus, this is the mode which should have been booted in
+    Corrected(GfxMode),
+    /// It still couldn't be read
+    Unreadable,
+}
 
-    Ok(mode)
+/// Read the MUX again for up to `window` after it was assumed discreet at boot with
+/// `requested` as the mode
+pub(crate) async fn reverify_mux(
+    mux: &dyn MuxReader,
+    requested: GfxMode,
+    asus_use_dgpu_disable: bool,
+    window: Duration,
+) -> Result<MuxReverify, GfxError> {
+    match wait_mux_mode(mux, window).await {
+        MuxRead::Mode(AsusGpuMuxMode::Discreet) => Ok(MuxReverify::Confirmed),
+        read @ MuxRead::Mode(AsusGpuMuxMode::Optimus) => Ok(MuxReverify::Corrected(
+            asus_boot_mode(requested, asus_use_dgpu_disable, read).await?,
+        )),
+        MuxRead::NotReady | MuxRead::Absent => Ok(MuxReverify::Unreadable),
+    }
 }
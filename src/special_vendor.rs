@@ -0,0 +1,328 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::time::sleep;
+
+use crate::{
+    actions::{Action, StagedAction},
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxMode},
+    sandbox::note_write,
+};
+
+/// Time for the devices to finish powering up or down before a toggle is changed, as for
+/// the ASUS toggles
+const TOGGLE_SETTLE: Duration = Duration::from_millis(500);
+/// Writes tried before giving up on a toggle which doesn't read back the written value
+const TOGGLE_WRITE_ATTEMPTS: u32 = 3;
+const TOGGLE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A condition which must hold for a toggle to be changed safely
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Interlock {
+    /// The dGPU must be on the PCI bus. Some firmware fails to hand over the display if
+    /// the MUX is flipped while the dGPU has been removed.
+    DgpuPresent,
+    /// No driver may be bound to the dGPU, for toggles which cut its power
+    DgpuDriverUnbound,
+}
+
+impl fmt::Display for Interlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DgpuPresent => {
+                write!(f, "the dGPU must be on the PCI bus, switch to Hybrid first")
+            }
+            Self::DgpuDriverUnbound => write!(f, "the dGPU driver must be unloaded"),
+        }
+    }
+}
+
+/// A vendor sysfs switch analogous to the ASUS `gpu_mux_mode` or `dgpu_disable`
+#[derive(Debug, PartialEq, Eq)]
+pub struct SpecialToggleDef {
+    /// Stable id used in staged actions and capabilities, e.g `legion_gsync`
+    pub id: &'static str,
+    pub name: &'static str,
+    /// Absolute path to the attribute, `*` matches any part of one path component
+    pub path_glob: &'static str,
+    pub on_value: &'static str,
+    pub off_value: &'static str,
+    /// The change only takes effect after a reboot
+    pub requires_reboot: bool,
+    /// The modes in which the toggle is on, it is off in all others
+    pub on_modes: &'static [GfxMode],
+    pub interlocks: &'static [Interlock],
+}
+
+/// The toggles known to supergfxd. The ASUS toggles are handled in `special_asus`.
+pub const BUILTIN_TOGGLES: &[SpecialToggleDef] = &[SpecialToggleDef {
+    // Lenovo Legion with the LenovoLegionLinux driver. `1` hands the internal display to
+    // the dGPU (hybrid off), like the ASUS MUX, and needs a reboot.
+    id: "legion_gsync",
+    name: "Lenovo Legion G-Sync",
+    path_glob: "/sys/bus/platform/drivers/legion/*/gsync",
+    on_value: "1",
+    off_value: "0",
+    requires_reboot: true,
+    on_modes: &[GfxMode::AsusMuxDgpu],
+    interlocks: &[Interlock::DgpuPresent],
+}];
+
+/// A toggle found on this machine
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpecialToggle {
+    pub def: &'static SpecialToggleDef,
+    pub path: PathBuf,
+}
+
+impl SpecialToggle {
+    /// Find the built-in toggles present on this machine
+    pub fn discover() -> Vec<Self> {
+        discover_in(Path::new("/"), BUILTIN_TOGGLES)
+    }
+
+    /// Find a built-in toggle by id
+    pub fn find(id: &str) -> Option<Self> {
+        Self::discover().into_iter().find(|t| t.def.id == id)
+    }
+
+    /// The toggle replaces the ASUS MUX, it is on in `AsusMuxDgpu`
+    pub fn is_mux(&self) -> bool {
+        self.def.on_modes.contains(&GfxMode::AsusMuxDgpu)
+    }
+
+    /// `None` if it can't be read or has neither the on nor off value
+    pub fn is_on(&self) -> Option<bool> {
+        let value = read_value(&self.path).ok()?;
+        if value == self.def.on_value {
+            Some(true)
+        } else if value == self.def.off_value {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Check the interlocks against the dGPU state
+    pub(crate) fn check_interlocks(
+        &self,
+        dgpu_present: bool,
+        dgpu_driver_bound: bool,
+    ) -> Result<(), GfxError> {
+        for interlock in self.def.interlocks {
+            let ok = match interlock {
+                Interlock::DgpuPresent => dgpu_present,
+                Interlock::DgpuDriverUnbound => !dgpu_driver_bound,
+            };
+            if !ok {
+                return Err(GfxError::SpecialToggle(format!(
+                    "{} can't be changed now, {interlock}",
+                    self.def.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the toggle, doing nothing if it is already set. The value is read back after
+    /// each write and the write retried if it didn't take.
+    pub(crate) async fn set(&self, on: bool, settle: Duration) -> Result<(), GfxError> {
+        let value = if on {
+            self.def.on_value
+        } else {
+            self.def.off_value
+        };
+        if self.is_on() == Some(on) {
+            debug!("special toggle {}: already set to {value}", self.def.id);
+            return Ok(());
+        }
+        // As for the ASUS toggles the devices need a moment to finish powering up or down
+        sleep(settle).await;
+        for attempt in 1..=TOGGLE_WRITE_ATTEMPTS {
+            note_write(&self.path);
+            fs::write(&self.path, value)
+                .map_err(|err| GfxError::Write(self.path.display().to_string(), err))?;
+            if self.is_on() == Some(on) {
+                info!(
+                    "special toggle {}: set {} to {value}",
+                    self.def.id,
+                    self.path.display()
+                );
+                return Ok(());
+            }
+            warn!(
+                "special toggle {}: {} did not read back {value}, attempt {attempt}",
+                self.def.id,
+                self.path.display()
+            );
+            sleep(TOGGLE_RETRY_DELAY).await;
+        }
+        Err(GfxError::SpecialToggle(format!(
+            "{} did not change to {value} after {TOGGLE_WRITE_ATTEMPTS} writes",
+            self.def.name
+        )))
+    }
+}
+
+fn read_value(path: &Path) -> Result<String, GfxError> {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .map_err(|err| GfxError::Read(path.display().to_string(), err))
+}
+
+/// A vendor MUX toggle is present on this machine
+pub fn vendor_mux_exists() -> bool {
+    SpecialToggle::discover().iter().any(|t| t.is_mux())
+}
+
+/// A vendor MUX toggle is on, the dGPU drives the internal display
+pub fn vendor_mux_on() -> bool {
+    SpecialToggle::discover()
+        .iter()
+        .any(|t| t.is_mux() && t.is_on() == Some(true))
+}
+
+/// Match one path component against a pattern where `*` matches any run of characters
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    if parts.is_empty() {
+        return rest.is_empty();
+    }
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Expand a glob under `root`, matches are sorted
+pub(crate) fn resolve_glob(root: &Path, glob: &str) -> Vec<PathBuf> {
+    let mut paths = vec![root.to_path_buf()];
+    for component in glob.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for path in &paths {
+            if component.contains('*') {
+                if let Ok(entries) = fs::read_dir(path) {
+                    for entry in entries.filter_map(|e| e.ok()) {
+                        if glob_match(component, &entry.file_name().to_string_lossy()) {
+                            next.push(entry.path());
+                        }
+                    }
+                }
+            } else {
+                let path = path.join(component);
+                if path.exists() {
+                    next.push(path);
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.sort();
+    paths
+}
+
+/// Find which of `defs` are present with `root` as the filesystem root. The first match
+/// of a glob is used.
+pub(crate) fn discover_in(root: &Path, defs: &'static [SpecialToggleDef]) -> Vec<SpecialToggle> {
+    defs.iter()
+        .filter_map(|def| {
+            resolve_glob(root, def.path_glob)
+                .into_iter()
+                .next()
+                .map(|path| SpecialToggle { def, path })
+        })
+        .collect()
+}
+
+/// Add the toggles which change state between `from` and `to` to a switch plan. A toggle
+/// takes the place of the ASUS MUX action the plan has for the same change, as the two
+/// aren't found on one machine, otherwise it is added last.
+pub(crate) fn apply_toggles(
+    toggles: &[SpecialToggle],
+    from: GfxMode,
+    to: GfxMode,
+    mut actions: Action,
+) -> Action {
+    if let Action::StagedActions(list) = &mut actions {
+        for toggle in toggles {
+            let was_on = toggle.def.on_modes.contains(&from);
+            let (action, asus) = match (was_on, toggle.def.on_modes.contains(&to)) {
+                (false, true) => (
+                    StagedAction::SpecialToggleOn(toggle.def.id),
+                    StagedAction::AsusMuxDgpu,
+                ),
+                (true, false) => (
+                    StagedAction::SpecialToggleOff(toggle.def.id),
+                    StagedAction::AsusMuxIgpu,
+                ),
+                _ => continue,
+            };
+            match list.iter().position(|a| *a == asus) {
+                Some(idx) if toggle.is_mux() => list[idx] = action,
+                _ => list.push(action),
+            }
+        }
+    }
+    actions
+}
+
+/// Set the built-in toggle `id`, checking its interlocks first
+pub(crate) async fn special_toggle_set(
+    id: &str,
+    on: bool,
+    device: &DiscreetGpu,
+) -> Result<(), GfxError> {
+    let toggle = SpecialToggle::find(id)
+        .ok_or_else(|| GfxError::SpecialToggle(format!("The toggle {id} was not found")))?;
+    let dgpu = device.devices().iter().find(|dev| dev.is_dgpu());
+    let dgpu_present = dgpu.map_or(false, |dev| dev.dev_path().exists());
+    let dgpu_driver_bound = dgpu_present && dgpu.map_or(false, |dev| dev.driver().is_ok());
+    toggle.check_interlocks(dgpu_present, dgpu_driver_bound)?;
+    toggle.set(on, TOGGLE_SETTLE).await?;
+    if toggle.def.requires_reboot {
+        info!("special toggle {id}: a reboot is required for the change to take effect");
+    }
+    Ok(())
+}
+
+/// As `asus_boot_safety_check` for the vendor MUX toggles. The firmware decides which GPU
+/// drives the display, so the mode must follow the toggle.
+pub fn vendor_boot_safety_check(mode: GfxMode, toggles: &[SpecialToggle]) -> GfxMode {
+    for toggle in toggles.iter().filter(|t| t.is_mux()) {
+        match toggle.is_on() {
+            Some(true) if mode != GfxMode::AsusMuxDgpu => {
+                warn!(
+                    "vendor_boot_safety_check: {} is on but the mode isn't AsusMuxDgpu, setting mode to AsusMuxDgpu",
+                    toggle.def.name
+                );
+                return GfxMode::AsusMuxDgpu;
+            }
+            Some(false) if mode == GfxMode::AsusMuxDgpu => {
+                warn!(
+                    "vendor_boot_safety_check: {} is off but mode is set to AsusMuxDgpu. Switching to Hybrid",
+                    toggle.def.name
+                );
+                return GfxMode::Hybrid;
+            }
+            _ => {}
+        }
+    }
+    mode
+}
@@ -0,0 +1,286 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::lock::Mutex;
+use log::{debug, info, warn};
+use tokio::time::sleep;
+
+use crate::{
+    config::{
+        modprobe_conf, modprobe_extra_for, remember_vfio_functions, write_modprobe_conf_to,
+        GfxConfig,
+    },
+    controller::{CtrlGraphics, ModeProbe, ProbeCache, SwitchState},
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxMode},
+    sandbox::note_write,
+    supervisor::RestartPolicy,
+    MODPROBE_PATH,
+};
+
+/// Generated files for the likely next mode are rendered to `<dir>/<mode>/` ahead of a switch.
+/// Nothing here is read by anything but supergfxd.
+pub const STAGING_DIR: &str = "/run/supergfxd/staged";
+const STAGED_MODPROBE_NAME: &str = "modprobe.conf";
+/// How often the staged files are checked while idle
+const STAGING_POLL: Duration = Duration::from_secs(30);
+
+/// The staged modprobe conf for `mode` under `dir`
+pub(crate) fn staged_modprobe_path(dir: &Path, mode: GfxMode) -> PathBuf {
+    dir.join(mode.to_string()).join(STAGED_MODPROBE_NAME)
+}
+
+/// The mode most likely to be switched to next from `current`: the last mode used if it is
+/// supported, otherwise the other one of Hybrid and Integrated
+pub(crate) fn likely_next_mode(
+    current: GfxMode,
+    last: Option<GfxMode>,
+    supported: &[GfxMode],
+) -> Option<GfxMode> {
+    let fallback = match current {
+        GfxMode::Hybrid => GfxMode::Integrated,
+        _ => GfxMode::Hybrid,
+    };
+    last.filter(|last| *last != current && *last != GfxMode::None)
+        .into_iter()
+        .chain([fallback])
+        .find(|mode| *mode != current && supported.contains(mode))
+}
+
+/// A file rendered ahead of a switch
+#[derive(Debug, Clone)]
+struct StagedFile {
+    mode: GfxMode,
+    path: PathBuf,
+    /// How long rendering and syncing it took, the time saved by installing it instead
+    staged_in: Duration,
+}
+
+/// Pre-rendered generated files for the mode most likely to be switched to next, so that the
+/// write during a switch, while the user looks at a black screen, is a rename. Staging never
+/// touches the live paths.
+#[derive(Debug)]
+pub(crate) struct WarmStaging {
+    dir: PathBuf,
+    live_path: PathBuf,
+    staged: Option<StagedFile>,
+    /// The mode switched away from last
+    last_mode: Option<GfxMode>,
+}
+
+impl WarmStaging {
+    pub(crate) fn new(dir: PathBuf, live_path: PathBuf) -> Self {
+        Self {
+            dir,
+            live_path,
+            staged: None,
+            last_mode: None,
+        }
+    }
+
+    /// Staging in `STAGING_DIR` for the modprobe conf at `MODPROBE_PATH`
+    pub(crate) fn system() -> Self {
+        Self::new(STAGING_DIR.into(), MODPROBE_PATH.into())
+    }
+
+    pub(crate) fn staged_mode(&self) -> Option<GfxMode> {
+        self.staged.as_ref().map(|staged| staged.mode)
+    }
+
+    pub(crate) fn last_mode(&self) -> Option<GfxMode> {
+        self.last_mode
+    }
+
+    pub(crate) fn set_last_mode(&mut self, mode: GfxMode) {
+        self.last_mode = Some(mode);
+    }
+
+    /// Drop anything staged, such as after a config change
+    pub(crate) fn invalidate(&mut self) {
+        if self.staged.take().is_some() {
+            debug!("WarmStaging: dropping the staged files");
+        }
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .unwrap_or_else(|err| warn!("Could not remove {}: {err}", self.dir.display()));
+        }
+    }
+
+    /// Render `content` as the modprobe conf for `mode`, replacing anything staged
+    pub(crate) fn stage(&mut self, mode: GfxMode, content: &[u8]) -> Result<(), GfxError> {
+        self.invalidate();
+        let start = Instant::now();
+        let path = staged_modprobe_path(&self.dir, mode);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| GfxError::from_io(err, dir.into()))?;
+        }
+        write_modprobe_conf_to(&path, content)?;
+        let staged_in = start.elapsed();
+        debug!("WarmStaging: staged {} in {staged_in:?}", path.display());
+        self.staged = Some(StagedFile {
+            mode,
+            path,
+            staged_in,
+        });
+        Ok(())
+    }
+
+    /// The staged conf is for `mode` and still matches `content`, as rendered now
+    pub(crate) fn is_current(&self, mode: GfxMode, content: &[u8]) -> bool {
+        match &self.staged {
+            Some(staged) if staged.mode == mode => {
+                fs::read(&staged.path).map_or(false, |staged| staged == content)
+            }
+            _ => false,
+        }
+    }
+
+    /// Move the staged conf over the live one if it is current for `mode` and `content`,
+    /// returning the time this saved. `None` if nothing current is staged, in which case it
+    /// is dropped and the caller must write the conf itself.
+    pub(crate) fn install(
+        &mut self,
+        mode: GfxMode,
+        content: &[u8],
+    ) -> Result<Option<Duration>, GfxError> {
+        if !self.is_current(mode, content) {
+            if self.staged_mode() == Some(mode) {
+                info!("WarmStaging: the staged conf for {mode} is stale, regenerating");
+            }
+            self.invalidate();
+            return Ok(None);
+        }
+        let staged = match self.staged.take() {
+            Some(staged) => staged,
+            None => return Ok(None),
+        };
+        let start = Instant::now();
+        note_write(&self.live_path);
+        // The staging dir is usually on a different filesystem, then the rename fails and it
+        // is copied beside the live path and renamed from there
+        if fs::rename(&staged.path, &self.live_path).is_err() {
+            let mut tmp = self.live_path.as_os_str().to_owned();
+            tmp.push(".staged");
+            let tmp = PathBuf::from(tmp);
+            fs::copy(&staged.path, &tmp)
+                .and_then(|_| fs::File::open(&tmp)?.sync_all())
+                .and_then(|_| fs::rename(&tmp, &self.live_path))
+                .map_err(|err| GfxError::Write(self.live_path.display().to_string(), err))?;
+        }
+        self.invalidate();
+        Ok(Some(staged.staged_in.saturating_sub(start.elapsed())))
+    }
+
+    /// Write the modprobe conf for `mode`, from the staged conf if it is current
+    pub(crate) fn write_modprobe_conf(
+        &mut self,
+        mode: GfxMode,
+        device: &DiscreetGpu,
+        extra: &[String],
+    ) -> Result<(), GfxError> {
+        let content = match modprobe_conf(mode, device, extra)? {
+            Some(content) => content,
+            None => return Ok(()),
+        };
+        match self.install(mode, &content) {
+            Ok(Some(saved)) => {
+                info!("WriteModprobeConf: installed the staged conf for {mode}, {saved:?} saved");
+            }
+            res => {
+                if let Err(err) = res {
+                    warn!("WriteModprobeConf: {err}, writing it instead");
+                }
+                info!("WriteModprobeConf: writing {}", self.live_path.display());
+                write_modprobe_conf_to(&self.live_path, &content)?;
+            }
+        }
+        if mode == GfxMode::Vfio {
+            remember_vfio_functions(device);
+        }
+        Ok(())
+    }
+}
+
+/// Stage the files for the likely next mode if the daemon is idle and they aren't already
+pub(crate) async fn stage_likely_next(
+    dgpu: &Mutex<DiscreetGpu>,
+    config: &Mutex<GfxConfig>,
+    probe_cache: &Mutex<ProbeCache>,
+    staging: &Mutex<WarmStaging>,
+) -> Result<(), GfxError> {
+    let (current, extra_options) = {
+        let config = config.lock().await;
+        if config.no_warm_staging {
+            staging.lock().await.invalidate();
+            return Ok(());
+        }
+        if config.switch_state != SwitchState::Idle {
+            return Ok(());
+        }
+        (
+            config.effective_mode(),
+            config.modprobe_extra_options.clone(),
+        )
+    };
+    let supported = ModeProbe::probe(dgpu, config, probe_cache)
+        .await
+        .0
+        .supported_modes();
+    let mut staging = staging.lock().await;
+    let next = match likely_next_mode(current, staging.last_mode(), &supported) {
+        Some(next) => next,
+        None => return Ok(()),
+    };
+    let extra = modprobe_extra_for(&extra_options, next);
+    let content = match modprobe_conf(next, &dgpu.lock().await.clone(), extra) {
+        Ok(Some(content)) => content,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            // Such as the dGPU functions being incomplete, the switch reports it if it happens
+            debug!("Warm staging: not staging {next}: {err}");
+            return Ok(());
+        }
+    };
+    if !staging.is_current(next, &content) {
+        staging.stage(next, &content)?;
+    }
+    Ok(())
+}
+
+async fn run_warm_staging(
+    dgpu: Arc<Mutex<DiscreetGpu>>,
+    config: Arc<Mutex<GfxConfig>>,
+    probe_cache: Arc<Mutex<ProbeCache>>,
+    staging: Arc<Mutex<WarmStaging>>,
+) {
+    loop {
+        stage_likely_next(&dgpu, &config, &probe_cache, &staging)
+            .await
+            .unwrap_or_else(|err| warn!("Warm staging: {err}"));
+        sleep(STAGING_POLL).await;
+    }
+}
+
+impl CtrlGraphics {
+    /// While idle, keep the generated files for the likely next mode staged so a switch to
+    /// it only has to rename them into place. Does nothing if `no_warm_staging` is set.
+    pub fn start_warm_staging(&self) {
+        let dgpu = self.dgpu.clone();
+        let config = self.config.clone();
+        let probe_cache = self.probe_cache.clone();
+        let staging = self.staging.clone();
+        self.tasks
+            .spawn("warm staging", RestartPolicy::WithBackoff, move || {
+                run_warm_staging(
+                    dgpu.clone(),
+                    config.clone(),
+                    probe_cache.clone(),
+                    staging.clone(),
+                )
+            })
+    }
+}
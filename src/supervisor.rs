@@ -0,0 +1,291 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::FutureExt;
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinHandle},
+    time::{sleep, Instant},
+};
+use zbus::zvariant::Type;
+
+/// The first delay before a failed long-lived task is restarted
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// The restart delay doubles on each failure up to this limit
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// A task that ran for at least this long is considered healthy again and the backoff is reset
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(300);
+
+/// Pull a readable message out of a panic payload
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Describe why a task ended without returning. Consumes the error to get at the payload.
+pub fn join_error_message(err: JoinError) -> String {
+    if err.is_panic() {
+        panic_message(&*err.into_panic())
+    } else {
+        err.to_string()
+    }
+}
+
+/// Run `task` to completion in its own tokio task. If it panics the panic is caught,
+/// logged with `name` as context, and `on_panic` is awaited with the panic message so
+/// that any state the task was responsible for can be repaired.
+pub fn spawn_supervised<T, R, Fut>(name: &'static str, task: T, on_panic: R) -> JoinHandle<()>
+where
+    T: Future<Output = ()> + Send + 'static,
+    R: FnOnce(String) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let handle = tokio::spawn(task);
+    tokio::spawn(async move {
+        if let Err(err) = handle.await {
+            let msg = join_error_message(err);
+            error!("{name}: task panicked: {msg}");
+            on_panic(msg).await;
+        }
+    })
+}
+
+/// The exit code of the daemon when a task with `RestartPolicy::EscalateToExit` ended,
+/// so that systemd restarts it
+pub const ESCALATION_EXIT_CODE: i32 = 3;
+
+/// What the supervisor does when a task ends, whether it panicked or returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub enum RestartPolicy {
+    /// Leave it ended
+    Never,
+    /// Start it again after an exponential backoff which resets once a run has stayed up
+    /// for a while
+    WithBackoff,
+    /// Stop the daemon, for a task it can't work without
+    EscalateToExit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub enum TaskState {
+    Running,
+    /// Ended and waiting out the backoff before it is started again
+    Restarting,
+    /// Finished by itself or cancelled on shutdown
+    Stopped,
+    /// Ended and left ended by its policy
+    Failed,
+}
+
+/// A task in the roster of the supervisor, see `Tasks` on the dbus interface
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct TaskInfo {
+    pub name: String,
+    pub policy: RestartPolicy,
+    pub state: TaskState,
+    /// How many times it has been started again
+    pub restarts: u32,
+    /// Why it last ended, empty if it never has
+    pub last_error: String,
+}
+
+struct SupervisedTask {
+    info: TaskInfo,
+    handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Roster {
+    tasks: Vec<SupervisedTask>,
+    stopping: bool,
+}
+
+/// Owns the long-lived background tasks of the daemon. Each is registered with a name and
+/// a `RestartPolicy` which is applied when it panics or returns. The roster is served by
+/// `Tasks` and put in the support bundle. On shutdown the tasks are cancelled in the
+/// reverse of the order they were started, so a task is stopped before those it was
+/// started after. Clones share the roster.
+#[derive(Clone)]
+pub struct TaskSupervisor {
+    roster: Arc<Mutex<Roster>>,
+    escalation: Arc<watch::Sender<Option<String>>>,
+}
+
+impl Default for TaskSupervisor {
+    fn default() -> Self {
+        Self {
+            roster: Arc::new(Mutex::new(Roster::default())),
+            escalation: Arc::new(watch::channel(None).0),
+        }
+    }
+}
+
+impl TaskSupervisor {
+    fn with_roster<T>(&self, f: impl FnOnce(&mut Roster) -> T) -> T {
+        f(&mut self.roster.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn update(&self, id: usize, f: impl FnOnce(&mut TaskInfo)) {
+        self.with_roster(|roster| f(&mut roster.tasks[id].info));
+    }
+
+    /// Start a task under `name`. `make_task` is called to create a fresh future for each
+    /// run, and `policy` decides what happens when a run ends. Does nothing once
+    /// `shutdown` has started.
+    pub fn spawn<M, Fut>(&self, name: &str, policy: RestartPolicy, mut make_task: M)
+    where
+        M: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let id = match self.with_roster(|roster| {
+            if roster.stopping {
+                return None;
+            }
+            roster.tasks.push(SupervisedTask {
+                info: TaskInfo {
+                    name: name.to_string(),
+                    policy,
+                    state: TaskState::Running,
+                    restarts: 0,
+                    last_error: String::new(),
+                },
+                handle: None,
+            });
+            Some(roster.tasks.len() - 1)
+        }) {
+            Some(id) => id,
+            None => {
+                warn!("{name}: not started, the daemon is stopping");
+                return;
+            }
+        };
+
+        let supervisor = self.clone();
+        let name = name.to_string();
+        let handle = tokio::spawn(async move {
+            let mut backoff = RESTART_BACKOFF_MIN;
+            loop {
+                let started = Instant::now();
+                let (state, error) = match AssertUnwindSafe(make_task()).catch_unwind().await {
+                    Ok(_) if policy == RestartPolicy::Never => {
+                        info!("{name}: task finished");
+                        (TaskState::Stopped, String::new())
+                    }
+                    Ok(_) => {
+                        warn!("{name}: task exited unexpectedly");
+                        (TaskState::Failed, "exited unexpectedly".to_string())
+                    }
+                    Err(payload) => {
+                        let msg = panic_message(&*payload);
+                        error!("{name}: task panicked: {msg}");
+                        (TaskState::Failed, format!("panicked: {msg}"))
+                    }
+                };
+
+                if policy != RestartPolicy::WithBackoff {
+                    supervisor.update(id, |info| {
+                        info.state = state;
+                        if !error.is_empty() {
+                            info.last_error = error.clone();
+                        }
+                    });
+                    if policy == RestartPolicy::EscalateToExit {
+                        error!("{name}: the daemon can't run without it, stopping");
+                        supervisor
+                            .escalation
+                            .send_replace(Some(format!("{name}: {error}")));
+                    }
+                    return;
+                }
+
+                if started.elapsed() >= RESTART_BACKOFF_RESET {
+                    backoff = RESTART_BACKOFF_MIN;
+                }
+                supervisor.update(id, |info| {
+                    info.state = TaskState::Restarting;
+                    info.last_error = error;
+                });
+                warn!("{name}: restarting in {}s", backoff.as_secs());
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+                supervisor.update(id, |info| {
+                    info.state = TaskState::Running;
+                    info.restarts += 1;
+                });
+            }
+        });
+        self.with_roster(|roster| {
+            if roster.stopping {
+                handle.abort();
+            } else {
+                roster.tasks[id].handle = Some(handle);
+            }
+        });
+    }
+
+    /// The registered tasks in the order they were started
+    pub fn roster(&self) -> Vec<TaskInfo> {
+        self.with_roster(|roster| roster.tasks.iter().map(|t| t.info.clone()).collect())
+    }
+
+    /// Wait for a task with `RestartPolicy::EscalateToExit` to end, returning which and why
+    pub async fn escalated(&self) -> String {
+        let mut escalation = self.escalation.subscribe();
+        loop {
+            if let Some(reason) = escalation.borrow_and_update().clone() {
+                return reason;
+            }
+            if escalation.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Wait for `stop`, such as a signal, or for a task to escalate. Returns the exit code
+    /// for the daemon: 0 after `stop`, `ESCALATION_EXIT_CODE` after an escalation.
+    pub async fn wait_for_stop(&self, stop: impl Future<Output = ()>) -> i32 {
+        tokio::select! {
+            _ = stop => 0,
+            reason = self.escalated() => {
+                error!("Stopping as a task escalated: {reason}");
+                ESCALATION_EXIT_CODE
+            }
+        }
+    }
+
+    /// Cancel every task, the last started first, and wait for each to end. Tasks can't
+    /// be started after this.
+    pub async fn shutdown(&self) {
+        let handles: Vec<_> = self.with_roster(|roster| {
+            roster.stopping = true;
+            roster
+                .tasks
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(id, task)| task.handle.take().map(|h| (id, h)))
+                .rev()
+                .collect()
+        });
+        for (id, handle) in handles {
+            handle.abort();
+            handle.await.ok();
+            self.update(id, |info| {
+                if matches!(info.state, TaskState::Running | TaskState::Restarting) {
+                    info.state = TaskState::Stopped;
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::{process::Stdio, time::Duration};
+
+use log::{debug, warn};
+use tokio::process::Command;
+
+use crate::{config::GfxConfig, error::GfxError, pci_device::GfxMode, switch_plan::SwitchOutcome};
+
+/// `SUPERGFXD_RESULT` for the pre-switch hook, as the switch hasn't been made yet
+pub(crate) const HOOK_RESULT_PENDING: &str = "pending";
+
+/// The commands run around a switch, from the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SwitchHooks {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+    /// A pre-switch hook which fails cancels the switch
+    pub pre_blocking: bool,
+    pub timeout: Duration,
+}
+
+impl SwitchHooks {
+    pub(crate) fn of(config: &GfxConfig) -> Self {
+        Self {
+            pre: config.hook_command_pre_switch.clone(),
+            post: config.hook_command_post_switch.clone(),
+            pre_blocking: config.hook_pre_blocking,
+            timeout: Duration::from_secs(config.hook_timeout_s),
+        }
+    }
+}
+
+/// `SUPERGFXD_RESULT` for the post-switch hook after a switch ended with `outcome`
+pub(crate) fn hook_result(outcome: &SwitchOutcome) -> &'static str {
+    match outcome {
+        SwitchOutcome::Completed => "completed",
+        SwitchOutcome::Cancelled => "cancelled",
+        SwitchOutcome::RolledBack { .. } => "rolled_back",
+        SwitchOutcome::Stalled { .. } => "stalled",
+        SwitchOutcome::Parked { .. } => "parked",
+    }
+}
+
+/// The environment a hook is run with for a switch from `from` to `to`
+pub(crate) fn hook_env(from: GfxMode, to: GfxMode, result: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("SUPERGFXD_FROM", from.to_string()),
+        ("SUPERGFXD_TO", to.to_string()),
+        ("SUPERGFXD_RESULT", result.to_string()),
+    ]
+}
+
+/// Run `command` with `sh -c` and `env` added to the environment of supergfxd. It fails if
+/// it can't be started, exits with an error, or is still running after `timeout`, in which
+/// case it is killed.
+pub(crate) async fn run_hook(
+    command: &str,
+    env: &[(&'static str, String)],
+    timeout: Duration,
+) -> Result<(), GfxError> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (*key, value)))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, child)
+        .await
+        .map_err(|_| GfxError::Hook(format!("{command:?} timed out after {timeout:?}")))?
+        .map_err(|err| GfxError::Command(command.to_string(), err))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        debug!("hook {command:?}: {}", stdout.trim());
+    }
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(GfxError::Hook(format!(
+        "{command:?} failed with {}: {}",
+        output.status,
+        stderr.trim()
+    )))
+}
+
+/// Run the post-switch hook for a switch from `from` to `to` which ended with `outcome`, if
+/// there is one. A failure is only logged.
+pub(crate) async fn run_post_hook(
+    hooks: &SwitchHooks,
+    from: GfxMode,
+    to: GfxMode,
+    outcome: &SwitchOutcome,
+) {
+    if let Some(command) = &hooks.post {
+        let env = hook_env(from, to, hook_result(outcome));
+        if let Err(err) = run_hook(command, &env, hooks.timeout).await {
+            warn!("post-switch hook: {err}");
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+use futures_util::{future::BoxFuture, lock::Mutex};
+use log::{debug, error, info, warn};
+use zbus::object_server::SignalEmitter;
+
+use crate::{
+    acpi_dgpu::{acpi_dgpu_usable, apply_acpi_dgpu},
+    actions::{Action, ActionSettings, Readback, StagedAction, SystemReadback, UserActionRequired},
+    config::GfxConfig,
+    controller::SetModeOptions,
+    error::GfxError,
+    inhibitors::wait_inhibitors,
+    initramfs::{modprobe_conf_written, InitramfsWatch},
+    logout_switch::{wait_logout, SystemHolderProbe, SystemSessionProbe},
+    pci_device::{DiscreetGpu, GfxMode, GfxVendor},
+    sandbox::in_action,
+    signal_counters::{emit_counted, Signal},
+    special_asus::AsusToggleState,
+    special_vendor::{apply_toggles, SpecialToggle},
+    staging::WarmStaging,
+    MODPROBE_PATH,
+};
+
+/// The switch has only done things which can be walked away from
+pub(crate) const SWITCH_CANCELLABLE: u8 = 0;
+/// The switch has started changing the system and must run to the end
+pub(crate) const SWITCH_COMMITTED: u8 = 1;
+/// The switch was cancelled before it was committed
+pub(crate) const SWITCH_CANCELLED: u8 = 2;
+/// The daemon is shutting down, the committed switch stops after the action it is doing
+pub(crate) const SWITCH_PARKED: u8 = 3;
+
+/// Mark a switch as past the point where it can be cancelled. Returns `false` if it was
+/// already cancelled.
+pub(crate) fn commit_switch(token: &AtomicU8) -> bool {
+    token.compare_exchange(
+        SWITCH_CANCELLABLE,
+        SWITCH_COMMITTED,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) != Err(SWITCH_CANCELLED)
+}
+
+/// Stop a switch for shutdown. A switch which hasn't changed anything yet is cancelled, a
+/// committed one is parked. Returns the state the switch was left in.
+pub(crate) fn park_switch(token: &AtomicU8) -> u8 {
+    if token
+        .compare_exchange(
+            SWITCH_CANCELLABLE,
+            SWITCH_CANCELLED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_ok()
+    {
+        return SWITCH_CANCELLED;
+    }
+    match token.compare_exchange(
+        SWITCH_COMMITTED,
+        SWITCH_PARKED,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => SWITCH_PARKED,
+        Err(state) => state,
+    }
+}
+
+/// The state of the machine a switch plan depends on, read before planning so that
+/// `plan_switch` itself touches nothing
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub(crate) struct PlanEnv {
+    /// Only read when leaving `AsusEgpu`
+    pub asus: AsusToggleState,
+    /// The vendor toggles found on this machine
+    pub toggles: Vec<SpecialToggle>,
+    /// The acpi_call module is loaded and there is no `dgpu_disable`, so the methods of
+    /// `acpi_dgpu_off` can be called
+    pub acpi_call: bool,
+}
+
+impl PlanEnv {
+    /// Read what a switch away from `from` needs to know
+    pub(crate) fn probe(from: GfxMode) -> Self {
+        Self {
+            asus: if from == GfxMode::AsusEgpu {
+                AsusToggleState::read()
+            } else {
+                AsusToggleState::default()
+            },
+            toggles: SpecialToggle::discover(),
+            acpi_call: acpi_dgpu_usable(),
+        }
+    }
+}
+
+/// What switching `from` one mode `to` another takes
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct SwitchPlan {
+    pub from: GfxMode,
+    pub to: GfxMode,
+    /// What the user must do for the new mode to take effect
+    pub user_action: UserActionRequired,
+    /// The actions to perform, `None` if the switch is left to the user and `user_action`
+    /// says what they must do
+    pub actions: Option<Vec<StagedAction>>,
+}
+
+impl SwitchPlan {
+    /// Adjust the actions for the per call options
+    pub(crate) fn with_options(mut self, options: SetModeOptions) -> Self {
+        if let Some(actions) = self.actions.take() {
+            if let Action::StagedActions(actions) = options.apply(Action::StagedActions(actions)) {
+                self.actions = Some(actions);
+            }
+        }
+        self
+    }
+}
+
+/// Plan the switch `from` the effective mode `to` a new one. Nothing is read from or done
+/// to the system, everything needed is in `config` and `env`.
+pub(crate) fn plan_switch(
+    config: &GfxConfig,
+    vendor: GfxVendor,
+    from: GfxMode,
+    to: GfxMode,
+    env: &PlanEnv,
+) -> SwitchPlan {
+    let actions = apply_acpi_dgpu(
+        config.acpi_dgpu_off.as_ref(),
+        env.acpi_call,
+        from,
+        to,
+        apply_toggles(
+            &env.toggles,
+            from,
+            to,
+            StagedAction::action_list_for_switch_with(config, vendor, from, to, env.asus),
+        ),
+    );
+    let (user_action, actions) = match actions {
+        Action::UserAction(user_action) => (user_action, None),
+        Action::StagedActions(actions) => {
+            let user_action = if config.always_reboot {
+                UserActionRequired::Reboot
+            } else {
+                UserActionRequired::mode_change_action(to, from)
+            };
+            (user_action, Some(actions))
+        }
+    };
+    SwitchPlan {
+        from,
+        to,
+        user_action,
+        actions,
+    }
+}
+
+/// How a switch ended
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum SwitchOutcome {
+    Completed,
+    /// Cancelled before anything was changed
+    Cancelled,
+    /// `failed` failed and the switch was undone
+    RolledBack {
+        failed: StagedAction,
+    },
+    /// `failed` failed, and so did `rollback_failed` while undoing the switch. The machine
+    /// is in neither mode.
+    Stalled {
+        failed: StagedAction,
+        rollback_failed: StagedAction,
+    },
+    /// Stopped for shutdown before `before`, with the display manager started again if the
+    /// switch had stopped it. The boot tasks put the configured mode back on the next start.
+    Parked {
+        before: StagedAction,
+    },
+}
+
+/// What a switch does to the system, so that `execute_plan` can be tested
+pub(crate) trait SwitchOps: Sync {
+    /// Perform one action of the switch to `mode`
+    fn perform(&self, action: StagedAction, mode: GfxMode) -> BoxFuture<'_, Result<(), GfxError>>;
+    /// The actions which undo a failed switch to `mode`, planned with the system as it is
+    /// after the failure
+    fn rollback(&self, mode: GfxMode) -> BoxFuture<'_, Vec<StagedAction>>;
+    /// Report that `action`, the `index`th of `total` counting from 1, is about to be
+    /// performed
+    fn progress(&self, _action: StagedAction, _index: u32, _total: u32) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Perform the `actions` of a switch to `mode`, undoing it if any fail. Cancellable actions
+/// are skipped once `token` is cancelled, the first one which isn't commits the switch.
+/// Once `token` is parked nothing more is done but starting the display manager again.
+pub(crate) async fn execute_plan(
+    mode: GfxMode,
+    actions: &[StagedAction],
+    token: &AtomicU8,
+    ops: &dyn SwitchOps,
+) -> SwitchOutcome {
+    let mut failed = None;
+    for (i, &action) in actions.iter().enumerate() {
+        if token.load(Ordering::Acquire) == SWITCH_PARKED {
+            info!("Switch to {mode} parked for shutdown before {action:?}");
+            if display_manager_left_stopped(&actions[..i]) {
+                ops.perform(StagedAction::StartDisplayManager, mode)
+                    .await
+                    .unwrap_or_else(|e| error!("Could not restart the display manager: {e}"));
+            }
+            return SwitchOutcome::Parked { before: action };
+        }
+        let cancelled = if action.is_cancellable() {
+            token.load(Ordering::Acquire) == SWITCH_CANCELLED
+        } else {
+            !commit_switch(token)
+        };
+        if cancelled {
+            info!("Switch to {mode} cancelled before {action:?}");
+            return SwitchOutcome::Cancelled;
+        }
+
+        debug!("Doing action: {action:?}");
+        ops.progress(action, i as u32 + 1, actions.len() as u32)
+            .await;
+        if let Err(e) = ops.perform(action, mode).await {
+            error!("Action thread errored: {e}");
+            // `logout_timeout_action` dropped the switch while waiting for logout, nothing
+            // has been changed so there is nothing to undo
+            if matches!(e, GfxError::LogoutTimeout(_)) && failed.is_none() {
+                return SwitchOutcome::RolledBack { failed: action };
+            }
+            failed.get_or_insert(action);
+            // The display manager didn't stop or start, carrying on would pull the dGPU
+            // from under a session. With `strict_verify` nothing is done after an action
+            // which didn't take effect, and nothing after the kill step refused to kill.
+            if matches!(
+                e,
+                GfxError::SystemdUnitWaitTimeout(_)
+                    | GfxError::PostCondition(..)
+                    | GfxError::ProtectedGpuUsers(..)
+            ) {
+                break;
+            }
+        }
+    }
+    if !commit_switch(token) {
+        info!("Switch to {mode} cancelled");
+        return SwitchOutcome::Cancelled;
+    }
+
+    let failed = match failed {
+        Some(failed) => failed,
+        None => return SwitchOutcome::Completed,
+    };
+    for action in ops.rollback(mode).await {
+        debug!("Doing action: {action:?}");
+        if let Err(e) = ops.perform(action, mode).await {
+            error!("Action thread errored fallback failed: {e}");
+            return SwitchOutcome::Stalled {
+                failed,
+                rollback_failed: action,
+            };
+        }
+    }
+    SwitchOutcome::RolledBack { failed }
+}
+
+/// The display manager was stopped by the `done` actions and not started again
+fn display_manager_left_stopped(done: &[StagedAction]) -> bool {
+    done.iter().rev().find(|action| {
+        matches!(
+            action,
+            StagedAction::StopDisplayManager | StagedAction::StartDisplayManager
+        )
+    }) == Some(&StagedAction::StopDisplayManager)
+}
+
+impl SwitchOutcome {
+    pub(crate) fn succeeded(&self) -> bool {
+        *self == SwitchOutcome::Completed
+    }
+
+    /// How the switch ended, such as `failed at UnloadGpuDrivers and was undone`
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            SwitchOutcome::Completed => "completed".to_string(),
+            SwitchOutcome::Cancelled => "cancelled".to_string(),
+            SwitchOutcome::RolledBack { failed } => format!("failed at {failed} and was undone"),
+            SwitchOutcome::Stalled {
+                failed,
+                rollback_failed,
+            } => format!("failed at {failed}, then undoing it failed at {rollback_failed}"),
+            SwitchOutcome::Parked { before } => format!("stopped for shutdown before {before}"),
+        }
+    }
+}
+
+/// The `SwitchOps` of the running daemon
+pub(crate) struct SystemSwitchOps {
+    pub dgpu: Arc<Mutex<DiscreetGpu>>,
+    pub config: Arc<Mutex<GfxConfig>>,
+    pub vendor: GfxVendor,
+    pub loop_exit: Arc<AtomicBool>,
+    pub waiting_for: Arc<Mutex<Vec<String>>>,
+    /// What `logout_timeout_action` did, for the status
+    pub logout_timeout: Arc<Mutex<String>>,
+    pub staging: Arc<Mutex<WarmStaging>>,
+    pub initramfs: Arc<Mutex<InitramfsWatch>>,
+    pub signal_ctxt: Option<SignalEmitter<'static>>,
+}
+
+impl SwitchOps for SystemSwitchOps {
+    fn perform(&self, action: StagedAction, mode: GfxMode) -> BoxFuture<'_, Result<(), GfxError>> {
+        Box::pin(in_action(action, async move {
+            self.perform_unchecked(action, mode).await?;
+            let (strict_verify, settings) = {
+                let config = self.config.lock().await;
+                (config.strict_verify, ActionSettings::from_config(&config))
+            };
+            let dgpu = self.dgpu.lock().await;
+            verify_if_strict(
+                strict_verify,
+                action,
+                mode,
+                &dgpu,
+                &settings,
+                &SystemReadback,
+            )
+            .await
+        }))
+    }
+
+    fn progress(&self, action: StagedAction, index: u32, total: u32) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(ctxt) = &self.signal_ctxt {
+                emit_counted!(
+                    ctxt,
+                    Signal::SwitchProgress,
+                    notify_switch_progress(&action.to_string(), index, total)
+                )
+                .await
+                .unwrap_or_else(|err| warn!("switch progress: {err}"));
+            }
+        })
+    }
+
+    fn rollback(&self, mode: GfxMode) -> BoxFuture<'_, Vec<StagedAction>> {
+        Box::pin(async move {
+            let config = self.config.lock().await;
+            let from = config.effective_mode();
+            plan_switch(&config, self.vendor, mode, from, &PlanEnv::probe(mode))
+                .actions
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl SystemSwitchOps {
+    /// Perform `action` without checking it took effect
+    async fn perform_unchecked(&self, action: StagedAction, mode: GfxMode) -> Result<(), GfxError> {
+        if action == StagedAction::WaitLogout {
+            // The dGPU isn't locked while waiting, which can take as long as the sessions
+            // stay open
+            let policy = self.config.lock().await.logout_policy();
+            let holders = SystemHolderProbe {
+                dgpu: self.dgpu.lock().await.clone(),
+            };
+            wait_logout(
+                &SystemSessionProbe,
+                &holders,
+                policy,
+                &self.loop_exit,
+                &self.logout_timeout,
+                self.signal_ctxt.as_ref(),
+            )
+            .await
+        } else if action == StagedAction::WaitInhibitors {
+            // Doesn't need the dgpu, and reports who it is waiting for in the status
+            wait_inhibitors(
+                self.loop_exit.clone(),
+                &self.waiting_for,
+                self.signal_ctxt.as_ref(),
+            )
+            .await
+        } else if action == StagedAction::WriteModprobeConf {
+            // A rename if the conf for this mode was staged while idle
+            let settings = ActionSettings::from_config(&*self.config.lock().await);
+            let dgpu = self.dgpu.lock().await;
+            let before = std::fs::read(MODPROBE_PATH).ok();
+            let res = self.staging.lock().await.write_modprobe_conf(
+                mode,
+                &dgpu,
+                settings.modprobe_extra(mode),
+            );
+            if res.is_ok() {
+                modprobe_conf_written(&self.initramfs, before, self.signal_ctxt.as_ref()).await;
+            }
+            res
+        } else {
+            let settings = ActionSettings::from_config(&*self.config.lock().await);
+            let mut dgpu = self.dgpu.lock().await;
+            action
+                .perform(
+                    mode,
+                    &mut dgpu,
+                    &settings,
+                    self.loop_exit.clone(),
+                    self.signal_ctxt.as_ref(),
+                )
+                .await
+        }
+    }
+}
+
+/// Check `action` took effect with `StagedAction::verify_post` if `strict_verify` is set
+pub(crate) async fn verify_if_strict(
+    strict_verify: bool,
+    action: StagedAction,
+    mode: GfxMode,
+    dgpu: &DiscreetGpu,
+    settings: &ActionSettings,
+    sys: &dyn Readback,
+) -> Result<(), GfxError> {
+    if !strict_verify {
+        return Ok(());
+    }
+    action.verify_post(mode, dgpu, settings, sys).await
+}
@@ -0,0 +1,229 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use serde_derive::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::{
+    actions::UserActionRequired, controller::needs_igpu, error::GfxError, pci_device::GfxMode,
+};
+
+/// Why a switch would be refused now. A switch and `SwitchReadiness` both check with
+/// `preflight`, so they can't disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Blocker {
+    /// Started with `--debug-run` without mutations allowed
+    DebugMode,
+    ShuttingDown,
+    /// The system is about to sleep
+    SleepImminent,
+    /// A switch to this mode is waiting, for the logout or inhibitor locks, and hasn't
+    /// changed anything yet. A new switch replaces it.
+    SwitchPending(GfxMode),
+    /// A switch to this mode has started changing the system
+    SwitchInProgress(GfxMode),
+    /// An inert profile, `NoDgpu` or `Desktop`, there is nothing to switch. With why.
+    NoDgpu(&'static str),
+    /// The mode needs the iGPU, which wasn't found
+    NoIgpu(GfxMode),
+    /// The mode is locked to this one
+    ModeLocked(GfxMode),
+    /// The mode can't be used on this machine, with why
+    NotSupported(String),
+    /// The dGPU dropped off the bus, only Integrated can be set
+    DgpuFellOffBus,
+    /// The dGPU is in Vfio and a VM has it open, with the processes
+    VfioInUse(Vec<String>),
+    /// The switch can't be made from the current mode, this must be done first
+    PlanRefused(UserActionRequired),
+}
+
+impl Blocker {
+    /// A code for the blocker which doesn't change between releases
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Self::DebugMode => "debug_mode",
+            Self::ShuttingDown => "shutting_down",
+            Self::SleepImminent => "sleep_imminent",
+            Self::SwitchPending(_) => "switch_pending",
+            Self::SwitchInProgress(_) => "switch_in_progress",
+            Self::NoDgpu(_) => "no_dgpu",
+            Self::NoIgpu(_) => "no_igpu",
+            Self::ModeLocked(_) => "mode_locked",
+            Self::NotSupported(_) => "mode_not_supported",
+            Self::DgpuFellOffBus => "dgpu_fell_off_bus",
+            Self::VfioInUse(_) => "vfio_in_use",
+            Self::PlanRefused(UserActionRequired::AsusEgpuDisable) => "asus_egpu_disable_first",
+            Self::PlanRefused(_) => "switch_to_integrated_first",
+        }
+    }
+
+    /// Whether a switch is made anyway. Only a pending switch is, which the new one replaces,
+    /// a switch doesn't fail with such a blocker.
+    pub(crate) fn overridable(&self) -> bool {
+        matches!(self, Self::SwitchPending(_))
+    }
+
+    /// What a frontend shows for the blocker, the message of the error `SetMode` fails with
+    pub(crate) fn message(&self) -> String {
+        match self {
+            Self::PlanRefused(action) => <&str>::from(action).to_string(),
+            Self::SwitchPending(mode) => {
+                format!("A switch to {mode} is waiting to start, a new switch replaces it")
+            }
+            _ => GfxError::from(self.clone()).to_string(),
+        }
+    }
+}
+
+impl From<Blocker> for GfxError {
+    fn from(blocker: Blocker) -> Self {
+        match blocker {
+            Blocker::DebugMode => GfxError::DebugMode,
+            Blocker::ShuttingDown => GfxError::ShuttingDown,
+            Blocker::SleepImminent => GfxError::SleepImminent,
+            Blocker::SwitchPending(mode) | Blocker::SwitchInProgress(mode) => {
+                GfxError::SwitchInProgress(mode)
+            }
+            Blocker::NoDgpu(reason) => GfxError::NotSupported(reason.to_string()),
+            Blocker::NoIgpu(mode) => GfxError::NoIgpu(mode),
+            Blocker::ModeLocked(mode) => GfxError::ModeLocked(mode),
+            Blocker::NotSupported(reason) => GfxError::NotSupported(reason),
+            Blocker::DgpuFellOffBus => GfxError::DgpuFellOffBus,
+            Blocker::VfioInUse(users) => GfxError::VfioInUse(users),
+            Blocker::PlanRefused(action) => {
+                GfxError::NotSupported(<&str>::from(action).to_string())
+            }
+        }
+    }
+}
+
+/// What the pre-flight checks look at, read by `CtrlGraphics::preflight_input` without
+/// holding a lock across sysfs or dbus reads
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct PreflightInput {
+    pub debug_mode: bool,
+    pub shutting_down: bool,
+    /// logind said the system is about to sleep, and it hasn't woken yet
+    pub sleeping: bool,
+    /// The mode a switch is underway to, if one is
+    pub switching_to: Option<GfxMode>,
+    /// The switch underway has started changing the system, so it can't be replaced
+    pub switch_committed: bool,
+    /// Why the profile is inert, if it is, see `OperatingProfile::reason`
+    pub no_dgpu: Option<&'static str>,
+    /// There is a dGPU but no iGPU, see `ModeProbe::igpu_missing`
+    pub no_igpu: bool,
+    /// The mode the config is locked to, if it is locked
+    pub locked_to: Option<GfxMode>,
+    /// Why the mode asked for can't be used here, if it can't
+    pub unsupported: Option<String>,
+    pub degraded: bool,
+    /// The processes with the vfio nodes of the dGPU open, when switching away from Vfio
+    pub vfio_users: Vec<String>,
+    /// What the plan for the switch needs done first, if it can't be made from the current
+    /// mode. A switch answers with this as the action required instead of failing.
+    pub plan_refused: Option<UserActionRequired>,
+}
+
+/// Everything which keeps a switch to `mode` from being made now, in the order a switch
+/// checks them. A switch fails with the first which isn't `Blocker::overridable`.
+pub(crate) fn preflight(input: &PreflightInput, mode: GfxMode) -> Vec<Blocker> {
+    let mut blockers = Vec::new();
+    if input.debug_mode {
+        blockers.push(Blocker::DebugMode);
+    } else if input.shutting_down {
+        blockers.push(Blocker::ShuttingDown);
+    }
+    if input.sleeping {
+        blockers.push(Blocker::SleepImminent);
+    }
+    if let Some(switching_to) = input.switching_to {
+        blockers.push(if input.switch_committed {
+            Blocker::SwitchInProgress(switching_to)
+        } else {
+            Blocker::SwitchPending(switching_to)
+        });
+    }
+    if let Some(reason) = input.no_dgpu {
+        blockers.push(Blocker::NoDgpu(reason));
+    }
+    if input.no_igpu && needs_igpu(mode) {
+        blockers.push(Blocker::NoIgpu(mode));
+    }
+    if let Some(locked_to) = input.locked_to {
+        blockers.push(Blocker::ModeLocked(locked_to));
+    }
+    if let Some(reason) = &input.unsupported {
+        blockers.push(Blocker::NotSupported(reason.clone()));
+    }
+    if input.degraded && mode != GfxMode::Integrated {
+        blockers.push(Blocker::DgpuFellOffBus);
+    }
+    if !input.vfio_users.is_empty() {
+        blockers.push(Blocker::VfioInUse(input.vfio_users.clone()));
+    }
+    if let Some(action) = input.plan_refused {
+        blockers.push(Blocker::PlanRefused(action));
+    }
+    blockers
+}
+
+/// One thing keeping a switch from being made, from `SwitchReadiness`
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ReadinessFinding {
+    /// Such as `mode_locked`, which doesn't change between releases
+    pub code: String,
+    pub message: String,
+    /// Whether an option or `--force` gets past it
+    pub overridable: bool,
+}
+
+/// Whether a switch to `mode` would be made if asked for now
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct SwitchReadiness {
+    pub mode: GfxMode,
+    /// No finding which can't be overridden
+    pub ready: bool,
+    /// Changes whenever the answer for any mode may have, also sent with
+    /// `NotifyReadinessChanged`
+    pub generation: u64,
+    pub findings: Vec<ReadinessFinding>,
+}
+
+impl SwitchReadiness {
+    pub(crate) fn new(mode: GfxMode, generation: u64, blockers: &[Blocker]) -> Self {
+        Self {
+            mode,
+            ready: blockers.iter().all(Blocker::overridable),
+            generation,
+            findings: blockers
+                .iter()
+                .map(|blocker| ReadinessFinding {
+                    code: blocker.code().to_string(),
+                    message: blocker.message(),
+                    overridable: blocker.overridable(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Counts the changes which may change a `SwitchReadiness`: the mode, the mode lock, a
+/// switch starting or ending, the dGPU falling off the bus or coming back, the operating
+/// profile, sleep and shutdown
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ReadinessGeneration(Arc<AtomicU64>);
+
+impl ReadinessGeneration {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Count a change, returning the new generation
+    pub(crate) fn bump(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::AcqRel) + 1
+    }
+}
@@ -0,0 +1,194 @@
+use std::{fs, path::Path, process::Command};
+
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxMode, GfxVendor},
+    sandbox::note_write,
+    validate,
+};
+
+/// Hides the dGPU from switcheroo-control. A udev rule rather than talking to switcheroo over
+/// dbus, as it has no method to hide a GPU and re-reads the udev properties on every change
+/// event. Under `/run` so a stale rule can't outlive a boot.
+pub const SWITCHEROO_RULE_PATH: &str = "/run/udev/rules.d/61-supergfxd-switcheroo.rules";
+/// The first line of the rule
+pub(crate) const SWITCHEROO_RULE_HEADER: &str =
+    "# Written by supergfxd, the dGPU is unusable in this mode";
+/// Where the switcheroo-control unit is installed by distros
+const SWITCHEROO_UNIT_PATHS: &[&str] = &[
+    "/usr/lib/systemd/system/switcheroo-control.service",
+    "/lib/systemd/system/switcheroo-control.service",
+    "/etc/systemd/system/switcheroo-control.service",
+];
+
+/// The state of the switcheroo-control bridge, for diagnostics
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SwitcherooStatus {
+    /// `manage_switcheroo` is off
+    #[default]
+    Disabled,
+    /// switcheroo-control isn't installed, nothing to do
+    NotInstalled,
+    /// The dGPU is offered for "Launch using Discrete Graphics Card"
+    DgpuShown,
+    /// The dGPU is hidden from switcheroo-control as it can't be used in this mode
+    DgpuHidden,
+    /// The rule could not be written or udev could not be triggered
+    Failed(String),
+}
+
+/// Whether apps can be launched on the dGPU in `mode`. Not in Integrated or Vfio, nor if
+/// the ASUS dGPU is disabled.
+pub(crate) fn dgpu_launch_allowed(mode: GfxMode, vendor: GfxVendor) -> bool {
+    if vendor == GfxVendor::AsusDgpuDisabled {
+        return false;
+    }
+    match mode {
+        GfxMode::Hybrid | GfxMode::NvidiaNoModeset | GfxMode::AsusEgpu | GfxMode::AsusMuxDgpu => {
+            true
+        }
+        GfxMode::Integrated | GfxMode::Vfio | GfxMode::None => false,
+    }
+}
+
+/// The udev rule hiding the PCI functions `names` from switcheroo-control. A name which
+/// isn't a PCI address is left out, so nothing else can end up in the rule.
+pub(crate) fn exclude_rule(names: &[String]) -> String {
+    let mut rule = format!("{SWITCHEROO_RULE_HEADER}\n");
+    for name in names {
+        if let Err(err) = validate::pci_address(name) {
+            warn!("switcheroo: {err}, not written to the rule");
+            continue;
+        }
+        rule.push_str(&format!(
+            "SUBSYSTEM==\"drm\", KERNELS==\"{name}\", ENV{{SWITCHEROO_CONTROL_EXCLUDE}}=\"1\"\n"
+        ));
+    }
+    rule
+}
+
+/// What the bridge needs from the system, so the decisions can be tested
+pub(crate) trait SwitcherooSystem {
+    /// switcheroo-control is installed
+    fn installed(&self) -> bool;
+    /// The rule currently written, `None` if there is none
+    fn current_rule(&self) -> Option<String>;
+    /// Write `rule`, or remove it if `None`
+    fn write_rule(&self, rule: Option<&str>) -> Result<(), GfxError>;
+    /// Have udev reload its rules and send change events for the DRM devices
+    fn trigger(&self) -> Result<(), GfxError>;
+}
+
+/// The status for `mode` and the rule it needs, `None` for no rule
+pub(crate) fn wanted_switcheroo(
+    system: &dyn SwitcherooSystem,
+    enabled: bool,
+    mode: GfxMode,
+    dgpu: &DiscreetGpu,
+) -> (SwitcherooStatus, Option<String>) {
+    if !enabled {
+        (SwitcherooStatus::Disabled, None)
+    } else if !system.installed() {
+        debug!("switcheroo: switcheroo-control is not installed");
+        (SwitcherooStatus::NotInstalled, None)
+    } else if dgpu_launch_allowed(mode, dgpu.vendor()) {
+        (SwitcherooStatus::DgpuShown, None)
+    } else {
+        let names: Vec<String> = dgpu
+            .managed_devices()
+            .map(|dev| dev.name().to_string())
+            .collect();
+        (SwitcherooStatus::DgpuHidden, Some(exclude_rule(&names)))
+    }
+}
+
+/// Show or hide the dGPU from switcheroo-control for `mode`. Nothing is changed if the rule
+/// is already right. If switcheroo-control isn't installed, or the bridge is off, any rule
+/// left by an earlier run is removed.
+pub(crate) fn update_switcheroo(
+    system: &dyn SwitcherooSystem,
+    enabled: bool,
+    mode: GfxMode,
+    dgpu: &DiscreetGpu,
+) -> SwitcherooStatus {
+    let (status, wanted) = wanted_switcheroo(system, enabled, mode, dgpu);
+    if system.current_rule() == wanted {
+        return status;
+    }
+    info!(
+        "switcheroo: {} the dGPU for {mode}",
+        if wanted.is_some() {
+            "hiding"
+        } else {
+            "showing"
+        }
+    );
+    match system
+        .write_rule(wanted.as_deref())
+        .and_then(|_| system.trigger())
+    {
+        Ok(()) => status,
+        Err(err) => {
+            warn!("switcheroo: {err}");
+            SwitcherooStatus::Failed(err.to_string())
+        }
+    }
+}
+
+/// The bridge on the running system
+pub(crate) struct SystemSwitcheroo;
+
+impl SystemSwitcheroo {
+    fn udevadm(args: &[&str]) -> Result<(), GfxError> {
+        let mut cmd = Command::new("udevadm");
+        cmd.args(args);
+        let status = cmd
+            .status()
+            .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
+        if !status.success() {
+            return Err(GfxError::Command(
+                format!("{:?}", cmd),
+                std::io::Error::new(std::io::ErrorKind::Other, format!("{status}")),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SwitcherooSystem for SystemSwitcheroo {
+    fn installed(&self) -> bool {
+        SWITCHEROO_UNIT_PATHS
+            .iter()
+            .any(|path| Path::new(path).exists())
+    }
+
+    fn current_rule(&self) -> Option<String> {
+        fs::read_to_string(SWITCHEROO_RULE_PATH).ok()
+    }
+
+    fn write_rule(&self, rule: Option<&str>) -> Result<(), GfxError> {
+        let path = Path::new(SWITCHEROO_RULE_PATH);
+        note_write(path);
+        match rule {
+            Some(rule) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|err| GfxError::from_io(err, dir.into()))?;
+                }
+                fs::write(path, rule)
+                    .map_err(|err| GfxError::Write(SWITCHEROO_RULE_PATH.into(), err))
+            }
+            None if path.exists() => {
+                fs::remove_file(path).map_err(|err| GfxError::from_io(err, path.into()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn trigger(&self) -> Result<(), GfxError> {
+        Self::udevadm(&["control", "--reload"])?;
+        Self::udevadm(&["trigger", "--action=change", "--subsystem-match=drm"])
+    }
+}
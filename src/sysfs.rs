@@ -0,0 +1,289 @@
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::debug;
+
+use crate::{error::GfxError, sandbox::note_write, special_asus::AsusGpuMuxMode};
+
+pub(crate) const ASUS_DGPU_DISABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/dgpu_disable";
+pub(crate) const ASUS_EGPU_ENABLE_PATH: &str = "/sys/devices/platform/asus-nb-wmi/egpu_enable";
+/// Where newer kernels have `egpu_enable`, used over `ASUS_EGPU_ENABLE_PATH` if it exists
+pub(crate) const ASUS_EGPU_ALT_ENABLE_PATH: &str =
+    "/sys/bus/platform/devices/asus-nb-wmi/egpu_enable";
+pub(crate) const ASUS_GPU_MUX_PATH: &str = "/sys/devices/platform/asus-nb-wmi/gpu_mux_mode";
+/// The PCI bus, with its `devices` and `drivers`
+pub(crate) const PCI_BUS_PATH: &str = "/sys/bus/pci";
+const PCI_RESCAN_PATH: &str = "/sys/bus/pci/rescan";
+
+/// The existence of a path hasn't been checked since it was last invalidated
+const EXISTS_UNKNOWN: u8 = 0;
+const EXISTS_PRESENT: u8 = 1;
+const EXISTS_ABSENT: u8 = 2;
+
+/// A sysfs attribute whose existence is checked once and then cached, until invalidated
+/// with `Sysfs::invalidate`. Reads and writes go to the file each time.
+#[derive(Debug)]
+pub(crate) struct SysfsPath {
+    /// The path on a running system, used in errors and logs
+    name: &'static str,
+    /// `name` under the root
+    path: PathBuf,
+    exists: AtomicU8,
+    /// Counts the existence checks made on the filesystem, shared by all the paths
+    checks: Arc<AtomicUsize>,
+}
+
+impl SysfsPath {
+    fn new(root: &Path, name: &'static str, checks: Arc<AtomicUsize>) -> Self {
+        Self {
+            name,
+            path: root.join(name.strip_prefix('/').unwrap_or(name)),
+            exists: AtomicU8::new(EXISTS_UNKNOWN),
+            checks,
+        }
+    }
+
+    /// The path on a running system, such as `/sys/devices/platform/asus-nb-wmi/dgpu_disable`
+    pub(crate) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the attribute exists. An error checking, such as permission denied on a
+    /// parent, is an error rather than absent and isn't cached.
+    pub(crate) fn try_exists(&self) -> Result<bool, String> {
+        match self.exists.load(Ordering::Acquire) {
+            EXISTS_PRESENT => Ok(true),
+            EXISTS_ABSENT => Ok(false),
+            _ => self.refresh(),
+        }
+    }
+
+    /// As `try_exists`, an error checking reads as absent
+    pub(crate) fn exists(&self) -> bool {
+        self.try_exists().unwrap_or(false)
+    }
+
+    /// Check the filesystem now, such as while waiting for a module to add the attribute
+    pub(crate) fn refresh(&self) -> Result<bool, String> {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        let exists = self
+            .path
+            .try_exists()
+            .map_err(|err| format!("{}: {err}", self.name))?;
+        self.set_exists(exists);
+        Ok(exists)
+    }
+
+    fn set_exists(&self, exists: bool) {
+        let state = if exists {
+            EXISTS_PRESENT
+        } else {
+            EXISTS_ABSENT
+        };
+        self.exists.store(state, Ordering::Release);
+    }
+
+    fn invalidate(&self) {
+        self.exists.store(EXISTS_UNKNOWN, Ordering::Release);
+    }
+
+    /// A missing file found by a read or write is remembered as absent
+    fn note_error(&self, err: &std::io::Error) {
+        if err.kind() == ErrorKind::NotFound {
+            self.set_exists(false);
+        }
+    }
+
+    /// Read the attribute. `GfxError::Path` if it can't be opened, `GfxError::Read` if it
+    /// can't be read.
+    pub(crate) fn read(&self) -> Result<String, GfxError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(&self.path)
+            .map_err(|err| {
+                self.note_error(&err);
+                GfxError::Path(self.name.to_string(), err)
+            })?;
+        self.set_exists(true);
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)
+            .map_err(|err| GfxError::Read(self.name.to_string(), err))?;
+        Ok(buf)
+    }
+
+    /// Write `value` to the attribute. `GfxError::Path` if it can't be opened,
+    /// `GfxError::Write` if it can't be written.
+    pub(crate) fn write(&self, value: &str) -> Result<(), GfxError> {
+        note_write(&self.path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .map_err(|err| {
+                self.note_error(&err);
+                GfxError::Path(self.name.to_string(), err)
+            })?;
+        self.set_exists(true);
+        file.write_all(value.as_bytes())
+            .map_err(|err| GfxError::Write(self.name.to_string(), err))?;
+        debug!("wrote {value} to {}", self.name);
+        Ok(())
+    }
+}
+
+/// `dgpu_disable` of asus-nb-wmi, takes the internal dGPU off the PCI bus
+#[derive(Debug)]
+pub(crate) struct AsusDgpuDisable(pub SysfsPath);
+
+impl AsusDgpuDisable {
+    pub(crate) fn exists(&self) -> bool {
+        self.0.exists()
+    }
+
+    pub(crate) fn disabled(&self) -> Result<bool, GfxError> {
+        Ok(self.0.read()?.contains('1'))
+    }
+}
+
+/// `egpu_enable` of asus-nb-wmi, at either of the places kernels have had it
+#[derive(Debug)]
+pub(crate) struct AsusEgpuEnable {
+    pub main: SysfsPath,
+    pub alt: SysfsPath,
+}
+
+impl AsusEgpuEnable {
+    /// The attribute used, the newer place if it exists
+    pub(crate) fn attr(&self) -> &SysfsPath {
+        if self.alt.exists() {
+            &self.alt
+        } else {
+            &self.main
+        }
+    }
+
+    pub(crate) fn try_exists(&self) -> Result<bool, String> {
+        Ok(self.main.try_exists()? || self.alt.try_exists()?)
+    }
+
+    pub(crate) fn exists(&self) -> bool {
+        self.main.exists() || self.alt.exists()
+    }
+
+    pub(crate) fn enabled(&self) -> Result<bool, GfxError> {
+        Ok(self.attr().read()?.contains('1'))
+    }
+}
+
+/// `gpu_mux_mode` of asus-nb-wmi, `0` for the dGPU driving the panel
+#[derive(Debug)]
+pub(crate) struct GpuMuxMode(pub SysfsPath);
+
+impl GpuMuxMode {
+    pub(crate) fn exists(&self) -> bool {
+        self.0.exists()
+    }
+
+    pub(crate) fn read(&self) -> Result<AsusGpuMuxMode, GfxError> {
+        let data = self.0.read()?;
+        match data.chars().next().and_then(|c| c.to_digit(10)) {
+            Some(d) => Ok(AsusGpuMuxMode::from(d as i8)),
+            None => Err(GfxError::Read(
+                "Failed to read gpu_mux_mode".to_owned(),
+                std::io::Error::new(ErrorKind::InvalidData, "Could not read"),
+            )),
+        }
+    }
+
+    /// `1` puts the iGPU on the panel, `0` the dGPU
+    pub(crate) fn set_igpu(&self, igpu_on: bool) -> Result<(), GfxError> {
+        self.0.write(if igpu_on { "1" } else { "0" })
+    }
+}
+
+/// `/sys/bus/pci/rescan`, which adds back removed devices
+#[derive(Debug)]
+pub(crate) struct PciRescan(pub SysfsPath);
+
+impl PciRescan {
+    pub(crate) fn rescan(&self) -> Result<(), GfxError> {
+        note_write(self.0.path());
+        std::fs::write(self.0.path(), "1")
+            .map_err(|err| GfxError::from_io(err, PathBuf::from(self.0.name())))
+    }
+}
+
+/// The sysfs attributes supergfxd uses, made once and shared, under a root which is `/`
+/// other than in tests. The existence of each is cached: the supported modes watcher and
+/// `reload` invalidate it, so a status poll or dbus query doesn't stat them each time.
+#[derive(Debug)]
+pub(crate) struct Sysfs {
+    pub dgpu_disable: AsusDgpuDisable,
+    pub egpu_enable: AsusEgpuEnable,
+    pub gpu_mux: GpuMuxMode,
+    pub pci_rescan: PciRescan,
+    checks: Arc<AtomicUsize>,
+}
+
+/// The `Sysfs` of the running system, made on first use
+static SYSTEM: Mutex<Option<Arc<Sysfs>>> = Mutex::new(None);
+
+impl Sysfs {
+    /// The attributes under `root`
+    pub(crate) fn at(root: &Path) -> Self {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let path = |name: &'static str| SysfsPath::new(root, name, checks.clone());
+        Self {
+            dgpu_disable: AsusDgpuDisable(path(ASUS_DGPU_DISABLE_PATH)),
+            egpu_enable: AsusEgpuEnable {
+                main: path(ASUS_EGPU_ENABLE_PATH),
+                alt: path(ASUS_EGPU_ALT_ENABLE_PATH),
+            },
+            gpu_mux: GpuMuxMode(path(ASUS_GPU_MUX_PATH)),
+            pci_rescan: PciRescan(path(PCI_RESCAN_PATH)),
+            checks,
+        }
+    }
+
+    /// The attributes of the running system
+    pub(crate) fn system() -> Arc<Sysfs> {
+        let mut system = SYSTEM.lock().unwrap_or_else(|err| err.into_inner());
+        system
+            .get_or_insert_with(|| Arc::new(Sysfs::at(Path::new("/"))))
+            .clone()
+    }
+
+    /// Check whether each attribute exists again on next use, such as after asus-wmi was
+    /// loaded
+    pub(crate) fn invalidate(&self) {
+        debug!(
+            "Sysfs::invalidate: {} existence checks made so far",
+            self.checks.load(Ordering::Relaxed)
+        );
+        for path in [
+            &self.dgpu_disable.0,
+            &self.egpu_enable.main,
+            &self.egpu_enable.alt,
+            &self.gpu_mux.0,
+            &self.pci_rescan.0,
+        ] {
+            path.invalidate();
+        }
+    }
+
+    /// The existence checks made on the filesystem so far
+    #[cfg(test)]
+    pub(crate) fn exists_checks(&self) -> usize {
+        self.checks.load(Ordering::Relaxed)
+    }
+}
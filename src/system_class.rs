@@ -0,0 +1,76 @@
+use std::{fs, path::Path};
+
+use serde_derive::Serialize;
+
+use crate::ac_automation::POWER_SUPPLY_PATH;
+
+/// The SMBIOS chassis type of the machine
+const CHASSIS_TYPE_PATH: &str = "/sys/class/dmi/id/chassis_type";
+
+/// The kind of enclosure, from the SMBIOS chassis type
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Chassis {
+    /// Laptop, notebook, convertible, tablet and the other portables
+    Portable,
+    /// Desktop, tower, mini PC, all-in-one and server
+    Desktop,
+    /// Not given, or a type which says neither, such as `Other` or `Unknown`
+    #[default]
+    Unknown,
+}
+
+impl Chassis {
+    /// From the number in `chassis_type`, see the SMBIOS spec for the list
+    pub(crate) fn from_chassis_type(chassis_type: &str) -> Self {
+        match chassis_type.trim().parse::<u8>() {
+            Ok(8..=11 | 14 | 30..=32) => Chassis::Portable,
+            Ok(3..=7 | 13 | 15..=17 | 23 | 24 | 28 | 35 | 36) => Chassis::Desktop,
+            _ => Chassis::Unknown,
+        }
+    }
+}
+
+/// What kind of machine supergfxd runs on, read once at startup to tell a desktop, where
+/// the dGPU may be the only GPU, from a laptop with switchable graphics
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SystemClass {
+    pub chassis: Chassis,
+    /// A system battery was found, not the battery of a peripheral such as a mouse
+    pub battery: bool,
+}
+
+impl SystemClass {
+    /// Read the class from the `chassis_type` file and the supplies under `power_supply`
+    pub(crate) fn read_in(chassis_type: &Path, power_supply: &Path) -> Self {
+        Self {
+            chassis: Chassis::from_chassis_type(
+                &fs::read_to_string(chassis_type).unwrap_or_default(),
+            ),
+            battery: battery_present_in(power_supply),
+        }
+    }
+
+    /// Read the class of this machine
+    pub fn read() -> Self {
+        Self::read_in(Path::new(CHASSIS_TYPE_PATH), Path::new(POWER_SUPPLY_PATH))
+    }
+
+    /// A desktop or server chassis without a battery. An unknown chassis isn't one, so a
+    /// laptop with a DMI table which doesn't say keeps every mode.
+    pub fn is_desktop(&self) -> bool {
+        self.chassis == Chassis::Desktop && !self.battery
+    }
+}
+
+/// Whether there is a system battery among the supplies under `dir`. One with `scope` set to
+/// `Device` powers a peripheral, such as a wireless mouse, and doesn't count.
+pub(crate) fn battery_present_in(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|e| e.ok()).any(|entry| {
+        let read = |attr: &str| fs::read_to_string(entry.path().join(attr)).unwrap_or_default();
+        read("type").trim() == "Battery" && read("scope").trim() != "Device"
+    })
+}
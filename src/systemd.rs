@@ -1,6 +1,7 @@
 use crate::error::GfxError;
 use log::info;
-use std::process::Command;
+use std::{path::Path, process::Command, time::Duration};
+use tokio::time::{sleep, Instant};
 
 /// An action for `systemctl`
 #[derive(Debug, Copy, Clone)]
@@ -8,6 +9,8 @@ pub enum SystemdUnitAction {
     Stop,
     Start,
     Restart,
+    Enable,
+    Disable,
 }
 
 impl From<SystemdUnitAction> for &str {
@@ -16,14 +19,18 @@ impl From<SystemdUnitAction> for &str {
             SystemdUnitAction::Stop => "stop",
             SystemdUnitAction::Start => "start",
             SystemdUnitAction::Restart => "restart",
+            SystemdUnitAction::Enable => "enable",
+            SystemdUnitAction::Disable => "disable",
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum SystemdUnitState {
     Active,
     Inactive,
+    /// The unit exited with an error or was killed, as `systemctl is-active` reports it
+    Failed,
 }
 
 impl From<SystemdUnitState> for &str {
@@ -31,6 +38,7 @@ impl From<SystemdUnitState> for &str {
         match s {
             SystemdUnitState::Active => "active",
             SystemdUnitState::Inactive => "inactive",
+            SystemdUnitState::Failed => "failed",
         }
     }
 }
@@ -51,6 +59,21 @@ pub fn do_systemd_unit_action(action: SystemdUnitAction, unit: &str) -> Result<(
     Ok(())
 }
 
+/// Have systemd re-read the unit files. Blocks while the command is run.
+pub fn systemd_daemon_reload() -> Result<(), GfxError> {
+    let mut cmd = Command::new("systemctl");
+    cmd.arg("daemon-reload");
+    info!("Running systemctl daemon-reload");
+    let status = cmd
+        .status()
+        .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
+    if !status.success() {
+        let msg = format!("systemctl daemon-reload failed: {status:?}");
+        return Err(GfxError::SystemdUnitAction(msg));
+    }
+    Ok(())
+}
+
 /// Get systemd unit state. Blocks while command is run.
 pub fn is_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<bool, GfxError> {
     let mut cmd = Command::new("systemctl");
@@ -66,25 +89,48 @@ pub fn is_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<bool
     Ok(false)
 }
 
-/// Wait for a systemd unit to change to `state`. Checks state every 250ms for 3 seconds. Blocks while running wait.
-pub fn wait_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<(), GfxError> {
-    let mut cmd = Command::new("systemctl");
-    cmd.arg("is-active");
-    cmd.arg(unit);
+/// Where systemd units are installed
+pub(crate) const SYSTEMD_UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
 
-    let mut count = 0;
+/// Whether the unit file for `unit` is installed
+pub fn is_systemd_unit_installed(unit: &str) -> bool {
+    SYSTEMD_UNIT_DIRS
+        .iter()
+        .any(|dir| Path::new(dir).join(unit).exists())
+}
 
-    while count <= (4 * 3) {
-        // 3 seconds max
-        let output = cmd
-            .output()
-            .map_err(|err| GfxError::Command(format!("{:?}", cmd), err))?;
-        if output.stdout.starts_with(<&str>::from(state).as_bytes()) {
+/// How long to wait for a systemd unit to change state
+const SYSTEMD_UNIT_WAIT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often to check the state of a systemd unit while waiting
+const SYSTEMD_UNIT_WAIT_POLL: Duration = Duration::from_millis(250);
+
+/// Wait for a systemd unit to change to `state`. Checks state every 250ms for 3 seconds.
+/// The executor isn't blocked between checks.
+pub async fn wait_systemd_unit_state(state: SystemdUnitState, unit: &str) -> Result<(), GfxError> {
+    let deadline = Instant::now() + SYSTEMD_UNIT_WAIT_TIMEOUT;
+    loop {
+        if is_systemd_unit_state(state, unit)? {
             return Ok(());
         }
-        // fine to block here, nobody doing shit now
-        std::thread::sleep(std::time::Duration::from_millis(250));
-        count += 1;
+        if Instant::now() >= deadline {
+            return Err(GfxError::SystemdUnitWaitTimeout(<&str>::from(state).into()));
+        }
+        sleep(SYSTEMD_UNIT_WAIT_POLL).await;
     }
-    Err(GfxError::SystemdUnitWaitTimeout(<&str>::from(state).into()))
+}
+
+/// As `wait_systemd_unit_state` for callers outside of an async runtime. Panics if called
+/// from within one.
+pub fn wait_systemd_unit_state_blocking(
+    state: SystemdUnitState,
+    unit: &str,
+) -> Result<(), GfxError> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?
+        .block_on(wait_systemd_unit_state(state, unit))
 }
@@ -0,0 +1,84 @@
+use std::{env, ffi::OsStr, io, os::unix::net::UnixDatagram};
+
+use log::{debug, warn};
+
+use crate::{controller::BootOutcome, pci_device::GfxMode, switch_plan::SwitchOutcome};
+
+/// Environment variable systemd sets to the socket a service sends its state to
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// Tell systemd about the state of the daemon, such as `READY=1` or `STOPPING=1`. Does
+/// nothing if not started by systemd with `NotifyAccess=` set.
+pub fn notify(state: &str) {
+    if let Some(socket) = env::var_os(NOTIFY_SOCKET_ENV) {
+        send_to(&socket, state)
+            .map(|_| ())
+            .unwrap_or_else(|err| warn!("sd_notify: could not send {state}: {err}"));
+    }
+}
+
+/// Set the `STATUS=` systemd shows for the service
+pub fn notify_status(status: &str) {
+    notify(&state_message(&[("STATUS", status)]));
+}
+
+/// Send `state` to the notify `socket`. Returns `false` if it wasn't sent as the socket is
+/// abstract, which isn't supported.
+pub(crate) fn send_to(socket: &OsStr, state: &str) -> io::Result<bool> {
+    if socket.to_string_lossy().starts_with('@') {
+        debug!("sd_notify: abstract notify sockets are not supported, not sending {state}");
+        return Ok(false);
+    }
+    UnixDatagram::unbound()?.send_to(state.as_bytes(), socket)?;
+    Ok(true)
+}
+
+/// A notify message of `KEY=value` lines. Newlines in a value would start a new
+/// assignment, so they are replaced by spaces.
+pub fn state_message(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{key}={}\n", value.replace('\n', " ")))
+        .collect()
+}
+
+/// The `STATUS=` for how the boot tasks went, such as `mode=Hybrid ok`
+pub fn boot_status(outcome: &BootOutcome) -> String {
+    match outcome {
+        BootOutcome::Ok(mode) => format!("mode={mode} ok"),
+        BootOutcome::Skipped(mode, reason) => {
+            format!("mode={mode} boot tasks skipped: {reason}")
+        }
+        BootOutcome::Fallback(mode, reason) => {
+            format!("mode={mode} safe-mode fallback active: {reason}")
+        }
+        BootOutcome::Degraded(mode, failures) => {
+            format!("mode={mode} DEGRADED: {}", failures.join("; "))
+        }
+        BootOutcome::Failed(err) => format!("DEGRADED: boot tasks failed: {err}"),
+        BootOutcome::Reduced(mode, reason) => {
+            format!("mode={mode} reduced boot path: {reason}")
+        }
+    }
+}
+
+/// The `STATUS=` after a switch from `from` to `to`, `None` if it was cancelled and nothing
+/// changed
+pub(crate) fn switch_status(from: GfxMode, to: GfxMode, outcome: &SwitchOutcome) -> Option<String> {
+    Some(match outcome {
+        SwitchOutcome::Completed => format!("mode={to} ok"),
+        SwitchOutcome::Cancelled => return None,
+        SwitchOutcome::RolledBack { failed } => {
+            format!("mode={from} ok, switch to {to} failed at {failed:?} and was undone")
+        }
+        SwitchOutcome::Stalled {
+            failed,
+            rollback_failed,
+        } => format!(
+            "mode={from} DEGRADED: switch to {to} failed at {failed:?}, undoing it failed at {rollback_failed:?}"
+        ),
+        SwitchOutcome::Parked { before } => {
+            format!("mode={from} switch to {to} stopped for shutdown before {before:?}")
+        }
+    })
+}
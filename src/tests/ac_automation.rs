@@ -0,0 +1,410 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use futures_util::future::BoxFuture;
+    use tokio::time::Instant;
+
+    use crate::{
+        ac_automation::{
+            decide, power_source_in, AcAutomation, AcContext, AcDebounce, AcDecision, AcProbe,
+            PowerSource,
+        },
+        error::GfxError,
+        pci_device::GfxMode,
+    };
+
+    struct MockProbe {
+        /// `None` fails the session check
+        sessions_active: Option<bool>,
+        dgpu_users: Vec<String>,
+    }
+
+    impl AcProbe for MockProbe {
+        fn sessions_active(&self) -> BoxFuture<'_, Result<bool, GfxError>> {
+            Box::pin(async move {
+                self.sessions_active
+                    .ok_or_else(|| GfxError::NotSupported("no logind".to_string()))
+            })
+        }
+
+        fn dgpu_users(&self) -> BoxFuture<'_, Vec<String>> {
+            Box::pin(async move { self.dgpu_users.clone() })
+        }
+    }
+
+    fn automation(auto_apply: bool) -> AcAutomation {
+        AcAutomation {
+            on_battery: Some(GfxMode::Integrated),
+            on_ac: Some(GfxMode::Hybrid),
+            auto_apply_when_no_sessions: auto_apply,
+            ..Default::default()
+        }
+    }
+
+    fn context(mode: GfxMode) -> AcContext {
+        AcContext {
+            mode,
+            supported: vec![GfxMode::Hybrid, GfxMode::Integrated, GfxMode::AsusMuxDgpu],
+            switching: false,
+            mode_locked: false,
+            always_reboot: false,
+            mutation_allowed: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn decisions() {
+        let suggest = |mode, reason: &str| AcDecision::Suggest {
+            mode,
+            reason: reason.to_string(),
+        };
+        let idle = MockProbe {
+            sessions_active: Some(false),
+            dgpu_users: Vec::new(),
+        };
+        let busy = MockProbe {
+            sessions_active: Some(false),
+            dgpu_users: vec!["blender (1234)".to_string()],
+        };
+        let sessions = MockProbe {
+            sessions_active: Some(true),
+            dgpu_users: Vec::new(),
+        };
+        let no_logind = MockProbe {
+            sessions_active: None,
+            dgpu_users: Vec::new(),
+        };
+        let locked = AcContext {
+            mode_locked: true,
+            ..context(GfxMode::Hybrid)
+        };
+        let switching = AcContext {
+            switching: true,
+            ..context(GfxMode::Hybrid)
+        };
+        let debug = AcContext {
+            mutation_allowed: false,
+            ..context(GfxMode::Hybrid)
+        };
+        let reboot = AcContext {
+            always_reboot: true,
+            ..context(GfxMode::Hybrid)
+        };
+        let unsupported = AcContext {
+            supported: vec![GfxMode::Hybrid],
+            ..context(GfxMode::Hybrid)
+        };
+        let mux_on_ac = AcAutomation {
+            on_ac: Some(GfxMode::AsusMuxDgpu),
+            ..automation(true)
+        };
+        let with_sessions = AcAutomation {
+            apply_with_sessions: true,
+            ..automation(false)
+        };
+
+        let table: Vec<(
+            &str,
+            AcAutomation,
+            PowerSource,
+            AcContext,
+            &MockProbe,
+            AcDecision,
+        )> = vec![
+            (
+                "already in the mode",
+                automation(true),
+                PowerSource::Battery,
+                context(GfxMode::Integrated),
+                &idle,
+                AcDecision::Nothing,
+            ),
+            (
+                "nothing set for the source",
+                AcAutomation {
+                    on_ac: None,
+                    ..automation(true)
+                },
+                PowerSource::Ac,
+                context(GfxMode::Integrated),
+                &idle,
+                AcDecision::Nothing,
+            ),
+            (
+                "suggest only",
+                automation(false),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &idle,
+                suggest(GfxMode::Integrated, "automatic switching is off"),
+            ),
+            (
+                "nothing would be disrupted",
+                automation(true),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &idle,
+                AcDecision::Apply(GfxMode::Integrated),
+            ),
+            (
+                "back on AC",
+                automation(true),
+                PowerSource::Ac,
+                context(GfxMode::Integrated),
+                &idle,
+                AcDecision::Apply(GfxMode::Hybrid),
+            ),
+            (
+                "sessions active",
+                automation(true),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &sessions,
+                suggest(GfxMode::Integrated, "graphical sessions are active"),
+            ),
+            (
+                "session check failed",
+                automation(true),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &no_logind,
+                suggest(
+                    GfxMode::Integrated,
+                    "could not check for graphical sessions: no logind",
+                ),
+            ),
+            (
+                "dGPU in use",
+                automation(true),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &busy,
+                suggest(GfxMode::Integrated, "the dGPU is in use by blender (1234)"),
+            ),
+            (
+                "mode locked",
+                automation(true),
+                PowerSource::Battery,
+                locked.clone(),
+                &idle,
+                suggest(GfxMode::Integrated, "the mode is locked"),
+            ),
+            (
+                "switch in progress",
+                automation(true),
+                PowerSource::Battery,
+                switching,
+                &idle,
+                suggest(GfxMode::Integrated, "a switch is already in progress"),
+            ),
+            (
+                "debug run",
+                automation(true),
+                PowerSource::Battery,
+                debug,
+                &idle,
+                suggest(GfxMode::Integrated, "this is a debug run"),
+            ),
+            (
+                "unsupported",
+                automation(true),
+                PowerSource::Battery,
+                unsupported,
+                &idle,
+                suggest(GfxMode::Integrated, "Integrated is not supported right now"),
+            ),
+            (
+                "always reboot",
+                automation(true),
+                PowerSource::Battery,
+                reboot,
+                &idle,
+                suggest(
+                    GfxMode::Integrated,
+                    "Reboot required to complete mode change",
+                ),
+            ),
+            (
+                "with sessions, pending the logout",
+                with_sessions.clone(),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &sessions,
+                AcDecision::Apply(GfxMode::Integrated),
+            ),
+            (
+                "with sessions, the dGPU users end with them",
+                with_sessions.clone(),
+                PowerSource::Battery,
+                context(GfxMode::Hybrid),
+                &busy,
+                AcDecision::Apply(GfxMode::Integrated),
+            ),
+            (
+                "with sessions, no logout needed",
+                with_sessions.clone(),
+                PowerSource::Battery,
+                context(GfxMode::NvidiaNoModeset),
+                &sessions,
+                AcDecision::Apply(GfxMode::Integrated),
+            ),
+            (
+                "with sessions, no logout needed but the dGPU in use",
+                with_sessions.clone(),
+                PowerSource::Battery,
+                context(GfxMode::NvidiaNoModeset),
+                &busy,
+                suggest(GfxMode::Integrated, "the dGPU is in use by blender (1234)"),
+            ),
+            (
+                "with sessions, mode locked",
+                with_sessions,
+                PowerSource::Battery,
+                locked,
+                &sessions,
+                suggest(GfxMode::Integrated, "the mode is locked"),
+            ),
+            (
+                "needs a reboot",
+                mux_on_ac,
+                PowerSource::Ac,
+                context(GfxMode::Hybrid),
+                &idle,
+                suggest(
+                    GfxMode::AsusMuxDgpu,
+                    "Reboot required to complete mode change",
+                ),
+            ),
+        ];
+
+        for (name, automation, source, ctx, probe, expected) in table {
+            assert_eq!(
+                decide(&automation, source, &ctx, probe).await,
+                expected,
+                "{name}"
+            );
+        }
+    }
+
+    const HOLD: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_holds_a_change() {
+        let mut debounce = AcDebounce::new(HOLD);
+        let start = Instant::now();
+        // The first reading is the starting state
+        debounce.observe(PowerSource::Ac, start, 0);
+        assert_eq!(debounce.deadline(), None);
+        assert_eq!(debounce.take_ready(start, 0), None);
+
+        debounce.observe(PowerSource::Battery, start, 0);
+        assert_eq!(debounce.deadline(), Some(start + HOLD));
+        // Readings of the same source don't restart the hold
+        debounce.observe(PowerSource::Battery, start + HOLD / 2, 0);
+        assert_eq!(debounce.take_ready(start + HOLD / 2, 0), None);
+        assert_eq!(
+            debounce.take_ready(start + HOLD, 0),
+            Some(PowerSource::Battery)
+        );
+        // Only acted on once
+        debounce.observe(PowerSource::Battery, start + HOLD * 2, 0);
+        assert_eq!(debounce.take_ready(start + HOLD * 3, 0), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_ignores_flapping() {
+        let mut debounce = AcDebounce::new(HOLD);
+        let start = Instant::now();
+        debounce.observe(PowerSource::Ac, start, 0);
+        for i in 1..=10 {
+            let source = if i % 2 == 0 {
+                PowerSource::Ac
+            } else {
+                PowerSource::Battery
+            };
+            debounce.observe(source, start + Duration::from_secs(i), 0);
+        }
+        // Ended back on AC, where it started
+        assert_eq!(debounce.deadline(), None);
+        assert_eq!(debounce.take_ready(start + HOLD * 2, 0), None);
+
+        // A change restarts the hold from when it was last seen
+        let later = start + HOLD * 2;
+        debounce.observe(PowerSource::Battery, later, 0);
+        debounce.observe(PowerSource::Ac, later + Duration::from_secs(1), 0);
+        debounce.observe(PowerSource::Battery, later + Duration::from_secs(2), 0);
+        assert_eq!(
+            debounce.deadline(),
+            Some(later + Duration::from_secs(2) + HOLD)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_cancelled_by_user_switch() {
+        let mut debounce = AcDebounce::new(HOLD);
+        let start = Instant::now();
+        debounce.observe(PowerSource::Ac, start, 3);
+        debounce.observe(PowerSource::Battery, start, 3);
+        // The user switched during the hold
+        assert_eq!(debounce.take_ready(start + HOLD, 4), None);
+        assert_eq!(debounce.deadline(), None);
+
+        // The next change is acted on as normal
+        debounce.observe(PowerSource::Ac, start + HOLD, 4);
+        assert_eq!(
+            debounce.take_ready(start + HOLD * 2, 4),
+            Some(PowerSource::Ac)
+        );
+    }
+
+    fn fake_supplies(name: &str, supplies: &[(&str, &str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        for (supply, kind, online) in supplies {
+            let path = dir.join(supply);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("type"), format!("{kind}\n")).unwrap();
+            fs::write(path.join("online"), format!("{online}\n")).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn power_source_from_sysfs() {
+        let dir = fake_supplies(
+            "ac-online",
+            &[("ACAD", "Mains", "1"), ("BAT1", "Battery", "1")],
+        );
+        assert_eq!(power_source_in(&dir), Some(PowerSource::Ac));
+
+        let dir = fake_supplies(
+            "ac-offline",
+            &[
+                ("ACAD", "Mains", "0"),
+                ("BAT1", "Battery", "1"),
+                ("ucsi-source-psy-USBC000:001", "USB", "1"),
+            ],
+        );
+        assert_eq!(power_source_in(&dir), Some(PowerSource::Battery));
+
+        // A desktop, or a laptop without a Mains supply, is never on battery
+        let dir = fake_supplies("no-mains", &[("BAT1", "Battery", "1")]);
+        assert_eq!(power_source_in(&dir), None);
+        assert_eq!(power_source_in(&dir.join("missing")), None);
+    }
+
+    #[test]
+    fn config_defaults() {
+        let automation: AcAutomation =
+            serde_json::from_str(r#"{"on_battery": "Integrated"}"#).unwrap();
+        assert_eq!(automation.on_battery, Some(GfxMode::Integrated));
+        assert_eq!(automation.on_ac, None);
+        assert!(!automation.auto_apply_when_no_sessions);
+        assert_eq!(automation.hold_s, 10);
+        assert!(automation.enabled());
+        assert!(!AcAutomation::default().enabled());
+    }
+}
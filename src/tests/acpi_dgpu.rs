@@ -0,0 +1,285 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io::{Error, ErrorKind},
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        acpi_dgpu::{
+            acpi_dgpu_set, apply_acpi_dgpu, apply_acpi_dgpu_boot, parse_result, validate_method,
+            AcpiCallFile, AcpiDgpuOff,
+        },
+        actions::{Action, StagedAction},
+        config::GfxConfig,
+        error::GfxError,
+        pci_device::{GfxMode, GfxVendor, HotplugType},
+        special_asus::AsusToggleState,
+        switch_plan::{plan_switch, PlanEnv},
+        sysfs::Sysfs,
+    };
+
+    /// `/proc/acpi/call` answering each write with the next of `results`
+    struct FakeAcpiCall {
+        loaded: bool,
+        results: RefCell<Vec<&'static str>>,
+        called: RefCell<Vec<String>>,
+        last: RefCell<String>,
+    }
+
+    impl FakeAcpiCall {
+        fn new(results: &[&'static str]) -> Self {
+            Self {
+                loaded: true,
+                results: RefCell::new(results.iter().rev().copied().collect()),
+                called: RefCell::new(Vec::new()),
+                last: RefCell::new("not called\0".to_string()),
+            }
+        }
+    }
+
+    impl AcpiCallFile for FakeAcpiCall {
+        fn path(&self) -> &Path {
+            Path::new("/proc/acpi/call")
+        }
+
+        fn exists(&self) -> bool {
+            self.loaded
+        }
+
+        fn write(&self, method: &str) -> std::io::Result<()> {
+            if !self.loaded {
+                return Err(Error::from(ErrorKind::NotFound));
+            }
+            self.called.borrow_mut().push(method.to_string());
+            *self.last.borrow_mut() = self.results.borrow_mut().pop().unwrap_or("0x0").into();
+            Ok(())
+        }
+
+        fn read(&self) -> std::io::Result<String> {
+            Ok(self.last.borrow().clone())
+        }
+    }
+
+    fn methods() -> AcpiDgpuOff {
+        AcpiDgpuOff {
+            method_off: "\\_SB.PCI0.GPP0.PG00._OFF".to_string(),
+            method_on: "\\_SB.PCI0.GPP0.PG00._ON".to_string(),
+        }
+    }
+
+    fn sysfs(name: &str) -> (PathBuf, Sysfs) {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-acpi_dgpu-{name}",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&root).ok();
+        let sysfs = Sysfs::at(&root);
+        (root, sysfs)
+    }
+
+    #[test]
+    fn method_validation() {
+        for ok in [
+            "\\_SB.PCI0.GPP0.PG00._OFF",
+            "\\_SB.PCI0.PEG0.PEGP.SGOF",
+            "\\_SB.PCI0.RP01._ON",
+        ] {
+            assert_eq!(validate_method(ok), Ok(()), "{ok}");
+        }
+        for bad in [
+            "",
+            "_SB.PCI0._OFF",
+            "\\_OFF",
+            "\\_SB.PCI0.GPP0.PG00._OFF 0x1",
+            "\\_SB.PCI0..PG00",
+            "\\_SB.PCI00X._OFF",
+            "\\_sb.pci0._OFF",
+            "\\_SB.0PCI._OFF",
+            "\\_SB.PCI0._OFF;\n\\_SB.PCI0._ON",
+        ] {
+            assert!(validate_method(bad).is_err(), "{bad:?}");
+        }
+
+        let bad = AcpiDgpuOff {
+            method_on: "\\_SB.PCI0.GPP0.PG00._ON 1".to_string(),
+            ..methods()
+        };
+        assert!(matches!(bad.validate(), Err(GfxError::AcpiCall(_))));
+    }
+
+    #[test]
+    fn results() {
+        assert_eq!(parse_result("\\M._OFF", "0x0\0").unwrap(), "0x0");
+        assert_eq!(
+            parse_result("\\M._OFF", "{0x01, 0x00}").unwrap(),
+            "{0x01, 0x00}"
+        );
+        let err = parse_result("\\M._OFF", "Error: AE_NOT_FOUND\0").unwrap_err();
+        assert_eq!(err.to_string(), "ACPI call: \\M._OFF failed: AE_NOT_FOUND");
+        assert!(parse_result("\\M._OFF", "not called\0").is_err());
+        assert!(parse_result("\\M._OFF", "").is_err());
+    }
+
+    #[test]
+    fn calls_and_guard_rails() {
+        let (root, sysfs) = sysfs("calls");
+        let call = FakeAcpiCall::new(&["0x0", "Error: AE_NOT_FOUND"]);
+        assert_eq!(
+            acpi_dgpu_set(&methods(), false, &sysfs, &call).unwrap(),
+            "0x0"
+        );
+        let err = acpi_dgpu_set(&methods(), true, &sysfs, &call).unwrap_err();
+        assert!(err.to_string().contains("AE_NOT_FOUND"), "{err}");
+        assert_eq!(
+            *call.called.borrow(),
+            ["\\_SB.PCI0.GPP0.PG00._OFF", "\\_SB.PCI0.GPP0.PG00._ON"]
+        );
+
+        // Malformed methods are never written
+        let bad = AcpiDgpuOff {
+            method_off: "\\_SB.PCI0 ; reboot".to_string(),
+            ..methods()
+        };
+        assert!(acpi_dgpu_set(&bad, false, &sysfs, &call).is_err());
+        assert_eq!(call.called.borrow().len(), 2);
+
+        let unloaded = FakeAcpiCall {
+            loaded: false,
+            ..FakeAcpiCall::new(&[])
+        };
+        let err = acpi_dgpu_set(&methods(), false, &sysfs, &unloaded).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("the acpi_call module isn't loaded, /proc/acpi/call doesn't exist"),
+            "{err}"
+        );
+
+        // The proper interface is used where there is one
+        let path = sysfs.dgpu_disable.0.path().to_path_buf();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "0").unwrap();
+        sysfs.invalidate();
+        let err = acpi_dgpu_set(&methods(), false, &sysfs, &call).unwrap_err();
+        assert!(err.to_string().contains("use hotplug_type Asus"), "{err}");
+        assert_eq!(call.called.borrow().len(), 2);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    fn config(acpi: bool) -> GfxConfig {
+        let mut config = GfxConfig::new(Default::default());
+        config.acpi_dgpu_off = acpi.then(methods);
+        config
+    }
+
+    fn plan(config: &GfxConfig, acpi_call: bool, from: GfxMode, to: GfxMode) -> Vec<StagedAction> {
+        let env = PlanEnv {
+            acpi_call,
+            ..Default::default()
+        };
+        plan_switch(config, GfxVendor::Nvidia, from, to, &env)
+            .actions
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn sequencing() {
+        let config = config(true);
+        let enter = plan(&config, true, GfxMode::Hybrid, GfxMode::Integrated);
+        assert_eq!(
+            &enter[enter.len() - 4..],
+            [
+                StagedAction::CheckVulkanIcd,
+                StagedAction::DevTreeManaged,
+                StagedAction::AcpiDgpuOff,
+                StagedAction::StartDisplayManager,
+            ]
+        );
+        let unbind = enter
+            .iter()
+            .position(|a| *a == StagedAction::UnbindRemoveGpu)
+            .unwrap();
+        let off = enter
+            .iter()
+            .position(|a| *a == StagedAction::AcpiDgpuOff)
+            .unwrap();
+        assert!(unbind < off);
+
+        let leave = plan(&config, true, GfxMode::Integrated, GfxMode::Hybrid);
+        let on = leave
+            .iter()
+            .position(|a| *a == StagedAction::AcpiDgpuOn)
+            .unwrap();
+        assert_eq!(leave[on + 1], StagedAction::RescanPci);
+
+        // Not set, or acpi_call can't be used
+        assert!(!plan(
+            &GfxConfig::new(Default::default()),
+            true,
+            GfxMode::Hybrid,
+            GfxMode::Integrated
+        )
+        .contains(&StagedAction::AcpiDgpuOff));
+        assert!(!plan(&config, false, GfxMode::Hybrid, GfxMode::Integrated)
+            .contains(&StagedAction::AcpiDgpuOff));
+        assert!(!plan(&config, false, GfxMode::Integrated, GfxMode::Hybrid)
+            .contains(&StagedAction::AcpiDgpuOn));
+
+        let mut boot = StagedAction::action_list_for_boot(
+            &GfxConfig::new(Default::default()),
+            GfxVendor::Nvidia,
+            GfxMode::Integrated,
+        );
+        apply_acpi_dgpu_boot(Some(&methods()), true, GfxMode::Integrated, &mut boot);
+        assert_eq!(boot.last(), Some(&StagedAction::AcpiDgpuOff));
+    }
+
+    #[test]
+    fn plans_stay_in_order() {
+        let modes = [
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+            GfxMode::NvidiaNoModeset,
+            GfxMode::Vfio,
+        ];
+        for hotplug_type in [HotplugType::None, HotplugType::Std] {
+            for no_logind in [false, true] {
+                let mut config = config(true);
+                config.hotplug_type = hotplug_type;
+                config.no_logind = no_logind;
+                for from in modes {
+                    for to in modes {
+                        let plan = apply_acpi_dgpu(
+                            config.acpi_dgpu_off.as_ref(),
+                            true,
+                            from,
+                            to,
+                            StagedAction::action_list_for_switch_with(
+                                &config,
+                                GfxVendor::Nvidia,
+                                from,
+                                to,
+                                AsusToggleState::default(),
+                            ),
+                        );
+                        let plan = match plan {
+                            Action::StagedActions(plan) => plan,
+                            Action::UserAction(_) => continue,
+                        };
+                        let mut previous = StagedAction::None;
+                        for action in plan {
+                            action
+                                .verify_previous_action_for_current(previous)
+                                .and_then(|_| previous.verify_next_allowed_action(action))
+                                .unwrap_or_else(|err| {
+                                    panic!("{from} to {to} ({hotplug_type:?}): {err}")
+                                });
+                            previous = action;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
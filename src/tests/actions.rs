@@ -1,368 +1,585 @@
-use crate::{actions::StagedAction, error::GfxError};
-
-impl StagedAction {
-    /// Verification that the action lists are in the correct order. If incorrect then lockups and other errors can occur
-    pub fn verify_previous_action_for_current(
-        &self,
-        previous_action: StagedAction,
-    ) -> Result<(), GfxError> {
-        if match self {
-            StagedAction::StopDisplayManager => previous_action == StagedAction::WaitLogout,
-            StagedAction::StartDisplayManager => true,
-            StagedAction::NoLogind => [
-                StagedAction::None,
-                StagedAction::NoLogind,
-                StagedAction::HotplugUnplug,
-                StagedAction::AsusDgpuDisable,
-                StagedAction::AsusEgpuDisable,
-                StagedAction::DevTreeManaged,
-                StagedAction::EnableNvidiaPersistenced,
-                StagedAction::EnableNvidiaPowerd,
-                StagedAction::NotNvidia,
-            ]
-            .contains(&previous_action),
-
-            StagedAction::LoadGpuDrivers => previous_action == StagedAction::RescanPci,
-            StagedAction::UnloadGpuDrivers => [
-                StagedAction::StopDisplayManager,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::KillNvidia,
-                StagedAction::KillAmd,
-                StagedAction::NotNvidia,
-                StagedAction::AsusEgpuDisable,
-            ]
-            .contains(&previous_action),
-
-            StagedAction::KillNvidia => [
-                StagedAction::StopDisplayManager,
-                StagedAction::DisableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
-
-            StagedAction::KillAmd => [
-                StagedAction::NotNvidia,
-                StagedAction::DisableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::StopDisplayManager,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::Duration,
+    };
 
-            StagedAction::EnableNvidiaPowerd => [
-                StagedAction::DevTreeManaged,
-                StagedAction::LoadGpuDrivers,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
+    use futures_util::future::BoxFuture;
 
-            StagedAction::DisableNvidiaPowerd => [
-                StagedAction::StopDisplayManager,
-                StagedAction::NoLogind,
-                StagedAction::RescanPci,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
+    use crate::{
+        actions::{
+            start_display_managers, start_powerd_watched, stop_display_managers,
+            validate_disabled_actions, wait_display_managers, Action, ActionSettings,
+            DisplayManagerControl, NvidiaPowerdStart, PowerdControl, Readback, StagedAction,
+        },
+        config::{modprobe_conf, GfxConfig},
+        error::GfxError,
+        pci_device::{Device, DiscreetGpu, GfxMode, GfxVendor, HotplugType, NvidiaDriver},
+        special_asus::AsusToggleState,
+        switch_plan::verify_if_strict,
+        sysfs::ASUS_DGPU_DISABLE_PATH,
+        systemd::{SystemdUnitAction, SystemdUnitState},
+        MODPROBE_PATH,
+    };
 
-            StagedAction::EnableNvidiaPersistenced => [
-                StagedAction::DevTreeManaged,
-                StagedAction::LoadGpuDrivers,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
+    /// A system which reads back whatever the test says, true or not
+    #[derive(Default)]
+    struct FakeSystem {
+        files: HashMap<PathBuf, Vec<u8>>,
+        existing: HashSet<PathBuf>,
+        drivers: HashMap<PathBuf, String>,
+        /// Installed units and the state they are in
+        units: HashMap<&'static str, SystemdUnitState>,
+    }
 
-            StagedAction::DisableNvidiaPersistenced => [
-                StagedAction::StopDisplayManager,
-                StagedAction::NoLogind,
-                StagedAction::RescanPci,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
+    impl FakeSystem {
+        fn file(mut self, path: &str, content: &[u8]) -> Self {
+            self.files.insert(PathBuf::from(path), content.to_vec());
+            self
+        }
 
-            StagedAction::LoadVfioDrivers => true,
-            StagedAction::UnloadVfioDrivers => true,
-            StagedAction::RescanPci => [
-                StagedAction::None, // Allow None due to VFIO
-                StagedAction::AsusDgpuEnable,
-                StagedAction::AsusDgpuDisable,
-                StagedAction::AsusEgpuEnable,
-                StagedAction::AsusEgpuDisable,
-                StagedAction::HotplugPlug,
-                StagedAction::HotplugUnplug,
-                StagedAction::DevTreeManaged,
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&previous_action),
+        fn existing(mut self, path: &Path) -> Self {
+            self.existing.insert(path.to_path_buf());
+            self
+        }
+    }
 
-            StagedAction::UnbindRemoveGpu => [
-                StagedAction::UnloadGpuDrivers,
-                StagedAction::UnloadVfioDrivers,
-            ]
-            .contains(&previous_action),
+    impl Readback for FakeSystem {
+        fn read(&self, path: &Path) -> Option<Vec<u8>> {
+            self.files.get(path).cloned()
+        }
 
-            StagedAction::UnbindGpu => [
-                StagedAction::UnloadGpuDrivers,
-                StagedAction::UnloadVfioDrivers,
-            ]
-            .contains(&previous_action),
-
-            StagedAction::HotplugUnplug
-            | StagedAction::HotplugPlug
-            | StagedAction::AsusDgpuDisable
-            | StagedAction::AsusDgpuEnable
-            | StagedAction::AsusEgpuDisable
-            | StagedAction::AsusEgpuEnable
-            | StagedAction::DevTreeManaged => [
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&previous_action),
+        fn exists(&self, path: &Path) -> bool {
+            self.existing.contains(path)
+        }
 
-            StagedAction::AsusMuxIgpu => [
-                StagedAction::None,
-                StagedAction::DisableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::NotNvidia,
-            ]
-            .contains(&previous_action),
+        fn driver(&self, dev_path: &Path) -> Option<String> {
+            self.drivers.get(dev_path).cloned()
+        }
 
-            StagedAction::AsusMuxDgpu => [
-                StagedAction::EnableNvidiaPersistenced,
-                StagedAction::EnableNvidiaPowerd,
-                StagedAction::NotNvidia,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
-
-            StagedAction::WriteModprobeConf => [
-                StagedAction::StopDisplayManager,
-                StagedAction::NoLogind,
-                StagedAction::UnbindRemoveGpu,
-                StagedAction::UnloadGpuDrivers,
-                StagedAction::UnloadVfioDrivers,
-                StagedAction::None,
-            ]
-            .contains(&previous_action),
+        fn unit_installed(&self, unit: &str) -> bool {
+            self.units.contains_key(unit)
+        }
 
-            StagedAction::CheckVulkanIcd
-            | StagedAction::WaitLogout
-            | StagedAction::NotNvidia
-            | StagedAction::None => true,
-        } {
-            Ok(())
-        } else {
-            Err(GfxError::IncorrectActionOrder(*self, previous_action))
+        fn wait_unit<'a>(
+            &'a self,
+            state: SystemdUnitState,
+            unit: &'a str,
+        ) -> BoxFuture<'a, Result<(), GfxError>> {
+            Box::pin(async move {
+                if self.units.get(unit) == Some(&state) {
+                    Ok(())
+                } else {
+                    Err(GfxError::SystemdUnitWaitTimeout(<&str>::from(state).into()))
+                }
+            })
         }
     }
 
-    pub fn verify_next_allowed_action(
-        &self,
-        next_allowed_action: StagedAction,
-    ) -> Result<(), GfxError> {
-        if match self {
-            StagedAction::WaitLogout => StagedAction::StopDisplayManager == next_allowed_action,
-            StagedAction::StopDisplayManager => [
-                StagedAction::EnableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-                StagedAction::UnloadVfioDrivers,
-                StagedAction::KillAmd,
-                StagedAction::KillNvidia,
-                StagedAction::NotNvidia,
-            ]
-            .contains(&next_allowed_action),
+    fn nvidia_dgpu() -> DiscreetGpu {
+        DiscreetGpu::mock_devices(
+            GfxVendor::Nvidia,
+            0,
+            vec![
+                Device::mock("0000:01:00.0", GfxVendor::Nvidia, true),
+                Device::mock("0000:01:00.1", GfxVendor::Nvidia, false),
+            ],
+            0,
+        )
+    }
 
-            StagedAction::StartDisplayManager => {
-                [StagedAction::None].contains(&next_allowed_action)
+    /// The expected and observed state of a failed post-condition
+    fn post_failure(res: Result<(), GfxError>, action: StagedAction) -> (String, String) {
+        match res {
+            Err(GfxError::PostCondition(failed, expected, observed)) => {
+                assert_eq!(failed, action);
+                (expected, observed)
             }
-            StagedAction::NoLogind => [
-                StagedAction::NoLogind,
-                StagedAction::NotNvidia,
-                StagedAction::EnableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&next_allowed_action),
+            res => panic!("{action:?}: expected a post-condition failure, got {res:?}"),
+        }
+    }
 
-            StagedAction::LoadGpuDrivers => [
-                StagedAction::EnableNvidiaPersistenced,
-                StagedAction::EnableNvidiaPowerd,
-                StagedAction::NotNvidia,
-                StagedAction::None,
-            ]
-            .contains(&next_allowed_action),
+    #[tokio::test]
+    async fn modprobe_conf_read_back() {
+        let dgpu = nvidia_dgpu();
+        let conf = modprobe_conf(GfxMode::Integrated, &dgpu, &[])
+            .unwrap()
+            .unwrap();
+        let action = StagedAction::WriteModprobeConf;
 
-            StagedAction::UnloadGpuDrivers => [
-                StagedAction::UnbindGpu,
-                StagedAction::UnbindRemoveGpu,
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&next_allowed_action),
+        let honest = FakeSystem::default().file(MODPROBE_PATH, &conf);
+        action
+            .verify_post(
+                GfxMode::Integrated,
+                &dgpu,
+                &ActionSettings::default(),
+                &honest,
+            )
+            .await
+            .unwrap();
 
-            StagedAction::KillNvidia => [
-                StagedAction::UnloadGpuDrivers,
-                StagedAction::UnloadVfioDrivers,
-            ]
-            .contains(&next_allowed_action),
+        // The write succeeded but the file doesn't hold what was written
+        let mut torn = conf.clone();
+        torn.truncate(conf.len() / 2);
+        let lying = FakeSystem::default().file(MODPROBE_PATH, &torn);
+        let (expected, observed) = post_failure(
+            action
+                .verify_post(
+                    GfxMode::Integrated,
+                    &dgpu,
+                    &ActionSettings::default(),
+                    &lying,
+                )
+                .await,
+            action,
+        );
+        assert!(expected.starts_with(&format!("{MODPROBE_PATH} with crc32 ")));
+        assert!(observed.starts_with("crc32 "));
+        assert_ne!(expected.rsplit(' ').next(), observed.rsplit(' ').next());
 
-            StagedAction::KillAmd => [
-                StagedAction::UnloadGpuDrivers,
-                StagedAction::UnloadVfioDrivers,
-            ]
-            .contains(&next_allowed_action),
+        let (_, observed) = post_failure(
+            action
+                .verify_post(
+                    GfxMode::Integrated,
+                    &dgpu,
+                    &ActionSettings::default(),
+                    &FakeSystem::default(),
+                )
+                .await,
+            action,
+        );
+        assert_eq!(observed, "it can't be read");
 
-            StagedAction::EnableNvidiaPowerd => [
-                StagedAction::StartDisplayManager,
-                StagedAction::AsusMuxDgpu,
-                StagedAction::NoLogind,
-                StagedAction::None,
-            ]
-            .contains(&next_allowed_action),
+        // Without strict_verify nothing is checked, as before
+        verify_if_strict(
+            false,
+            action,
+            GfxMode::Integrated,
+            &dgpu,
+            &ActionSettings::default(),
+            &lying,
+        )
+        .await
+        .unwrap();
+        assert!(verify_if_strict(
+            true,
+            action,
+            GfxMode::Integrated,
+            &dgpu,
+            &ActionSettings::default(),
+            &lying
+        )
+        .await
+        .is_err());
+    }
 
-            StagedAction::DisableNvidiaPowerd => {
-                [StagedAction::KillNvidia, StagedAction::KillAmd].contains(&next_allowed_action)
-            }
+    #[tokio::test]
+    async fn driver_state_read_back() {
+        let dgpu = nvidia_dgpu();
+        let loaded = FakeSystem::default()
+            .existing(Path::new("/sys/module/nvidia"))
+            .existing(Path::new("/sys/module/nvidia_drm"));
 
-            StagedAction::EnableNvidiaPersistenced => [
-                StagedAction::StartDisplayManager,
-                StagedAction::AsusMuxDgpu,
-                StagedAction::NoLogind,
-                StagedAction::None,
-            ]
-            .contains(&next_allowed_action),
+        let action = StagedAction::UnloadGpuDrivers;
+        let (expected, observed) = post_failure(
+            action
+                .verify_post(
+                    GfxMode::Integrated,
+                    &dgpu,
+                    &ActionSettings::default(),
+                    &loaded,
+                )
+                .await,
+            action,
+        );
+        assert!(expected.ends_with(" unloaded"));
+        assert_eq!(observed, "nvidia_drm, nvidia loaded");
+        action
+            .verify_post(
+                GfxMode::Integrated,
+                &dgpu,
+                &ActionSettings::default(),
+                &FakeSystem::default(),
+            )
+            .await
+            .unwrap();
 
-            StagedAction::DisableNvidiaPersistenced => {
-                [StagedAction::KillNvidia, StagedAction::KillAmd].contains(&next_allowed_action)
-            }
-            StagedAction::LoadVfioDrivers => [StagedAction::None].contains(&next_allowed_action),
-            StagedAction::UnloadVfioDrivers => [
-                StagedAction::UnbindRemoveGpu,
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&next_allowed_action),
+        let action = StagedAction::LoadGpuDrivers;
+        let (_, observed) = post_failure(
+            action
+                .verify_post(GfxMode::Hybrid, &dgpu, &ActionSettings::default(), &loaded)
+                .await,
+            action,
+        );
+        assert!(observed.contains("nvidia_modeset"));
+        assert!(!observed.contains("nvidia_drm"));
 
-            StagedAction::DevTreeManaged => [
-                StagedAction::StartDisplayManager,
-                StagedAction::NoLogind,
-                StagedAction::RescanPci,
-            ]
-            .contains(&next_allowed_action),
+        // Only the nvidia modules are loaded and unloaded
+        let amd = DiscreetGpu::mock(GfxVendor::Amd);
+        StagedAction::UnloadGpuDrivers
+            .verify_post(
+                GfxMode::Integrated,
+                &amd,
+                &ActionSettings::default(),
+                &loaded,
+            )
+            .await
+            .unwrap();
 
-            StagedAction::RescanPci => [
-                StagedAction::LoadGpuDrivers,
-                StagedAction::DisableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::NotNvidia,
-            ]
-            .contains(&next_allowed_action),
+        // Under nouveau only nouveau is
+        let nouveau = nvidia_dgpu().with_nvidia_driver(NvidiaDriver::Nouveau);
+        StagedAction::UnloadGpuDrivers
+            .verify_post(
+                GfxMode::Integrated,
+                &nouveau,
+                &ActionSettings::default(),
+                &loaded,
+            )
+            .await
+            .unwrap();
+        let in_use = FakeSystem::default().existing(Path::new("/sys/module/nouveau"));
+        let action = StagedAction::UnloadGpuDrivers;
+        let (_, observed) = post_failure(
+            action
+                .verify_post(
+                    GfxMode::Integrated,
+                    &nouveau,
+                    &ActionSettings::default(),
+                    &in_use,
+                )
+                .await,
+            action,
+        );
+        assert_eq!(observed, "nouveau loaded");
+        StagedAction::LoadGpuDrivers
+            .verify_post(
+                GfxMode::Hybrid,
+                &nouveau,
+                &ActionSettings::default(),
+                &in_use,
+            )
+            .await
+            .unwrap();
+        // nvidia-powerd isn't started, nor expected to be running
+        let mut powerd_stopped = FakeSystem::default();
+        powerd_stopped
+            .units
+            .insert("nvidia-powerd.service", SystemdUnitState::Inactive);
+        StagedAction::EnableNvidiaPowerd
+            .verify_post(
+                GfxMode::Hybrid,
+                &nouveau,
+                &ActionSettings::default(),
+                &powerd_stopped,
+            )
+            .await
+            .unwrap();
+    }
 
-            StagedAction::UnbindRemoveGpu => [
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&next_allowed_action),
+    #[tokio::test]
+    async fn devices_read_back() {
+        let dgpu = nvidia_dgpu();
+        let on_bus = dgpu
+            .devices()
+            .iter()
+            .fold(FakeSystem::default(), |sys, dev| {
+                sys.existing(dev.dev_path())
+            });
 
-            StagedAction::UnbindGpu => {
-                [StagedAction::LoadVfioDrivers].contains(&next_allowed_action)
-            }
+        let action = StagedAction::UnbindRemoveGpu;
+        let (expected, observed) = post_failure(
+            action
+                .verify_post(
+                    GfxMode::Integrated,
+                    &dgpu,
+                    &ActionSettings::default(),
+                    &on_bus,
+                )
+                .await,
+            action,
+        );
+        assert_eq!(expected, "0000:01:00.0 removed");
+        assert_eq!(observed, "0000:01:00.0 still on the bus");
+        action
+            .verify_post(
+                GfxMode::Integrated,
+                &dgpu,
+                &ActionSettings::default(),
+                &FakeSystem::default(),
+            )
+            .await
+            .unwrap();
 
-            StagedAction::HotplugUnplug => {
-                [StagedAction::StartDisplayManager, StagedAction::NoLogind]
-                    .contains(&next_allowed_action)
-            }
+        StagedAction::RescanPci
+            .verify_post(GfxMode::Hybrid, &dgpu, &ActionSettings::default(), &on_bus)
+            .await
+            .unwrap();
 
-            StagedAction::HotplugPlug => [StagedAction::RescanPci].contains(&next_allowed_action),
-            StagedAction::AsusDgpuDisable => {
-                [StagedAction::StartDisplayManager, StagedAction::NoLogind]
-                    .contains(&next_allowed_action)
-            }
+        // An ignored function is left on the bus and bound
+        let mut dgpu = nvidia_dgpu();
+        dgpu.set_ignored_functions(vec![".1".to_string()]);
+        let mut ignored_bound = FakeSystem::default().existing(dgpu.devices()[1].dev_path());
+        ignored_bound.drivers.insert(
+            dgpu.devices()[1].dev_path().clone(),
+            "snd_hda_intel".to_string(),
+        );
+        StagedAction::UnbindRemoveGpu
+            .verify_post(
+                GfxMode::Integrated,
+                &dgpu,
+                &ActionSettings::default(),
+                &ignored_bound,
+            )
+            .await
+            .unwrap();
+        StagedAction::UnbindGpu
+            .verify_post(
+                GfxMode::Vfio,
+                &dgpu,
+                &ActionSettings::default(),
+                &ignored_bound,
+            )
+            .await
+            .unwrap();
+        let dgpu = nvidia_dgpu();
 
-            StagedAction::AsusDgpuEnable => {
-                [StagedAction::RescanPci].contains(&next_allowed_action)
-            }
+        let mut bound = FakeSystem::default();
+        bound.drivers.insert(
+            dgpu.devices()[1].dev_path().clone(),
+            "snd_hda_intel".to_string(),
+        );
+        let action = StagedAction::UnbindGpu;
+        let (_, observed) = post_failure(
+            action
+                .verify_post(GfxMode::Vfio, &dgpu, &ActionSettings::default(), &bound)
+                .await,
+            action,
+        );
+        assert_eq!(observed, "0000:01:00.1 bound to snd_hda_intel");
+    }
 
-            StagedAction::AsusEgpuDisable => [].contains(&next_allowed_action),
-            StagedAction::AsusEgpuEnable => {
-                [StagedAction::RescanPci].contains(&next_allowed_action)
-            }
+    #[tokio::test]
+    async fn toggles_and_units_read_back() {
+        let dgpu = nvidia_dgpu();
+        let action = StagedAction::AsusDgpuDisable;
+        let unchanged = FakeSystem::default().file(ASUS_DGPU_DISABLE_PATH, b"0\n");
+        let (expected, observed) = post_failure(
+            action
+                .verify_post(
+                    GfxMode::Integrated,
+                    &dgpu,
+                    &ActionSettings::default(),
+                    &unchanged,
+                )
+                .await,
+            action,
+        );
+        assert_eq!(expected, format!("{ASUS_DGPU_DISABLE_PATH} = 1"));
+        assert_eq!(observed, "0");
+        StagedAction::AsusDgpuEnable
+            .verify_post(
+                GfxMode::Hybrid,
+                &dgpu,
+                &ActionSettings::default(),
+                &unchanged,
+            )
+            .await
+            .unwrap();
 
-            StagedAction::AsusMuxIgpu => [].contains(&next_allowed_action),
-            StagedAction::AsusMuxDgpu => [].contains(&next_allowed_action),
-            StagedAction::WriteModprobeConf => [
-                StagedAction::AsusEgpuDisable,
-                StagedAction::AsusEgpuEnable,
-                StagedAction::HotplugUnplug,
-                StagedAction::AsusDgpuDisable,
-                StagedAction::DevTreeManaged,
-                StagedAction::HotplugPlug,
-                StagedAction::AsusDgpuEnable,
-                StagedAction::LoadVfioDrivers,
-                StagedAction::RescanPci,
-                StagedAction::CheckVulkanIcd,
-            ]
-            .contains(&next_allowed_action),
+        let mut units = FakeSystem::default();
+        units
+            .units
+            .insert("nvidia-powerd.service", SystemdUnitState::Inactive);
+        let action = StagedAction::EnableNvidiaPowerd;
+        let (expected, _) = post_failure(
+            action
+                .verify_post(GfxMode::Hybrid, &dgpu, &ActionSettings::default(), &units)
+                .await,
+            action,
+        );
+        assert_eq!(expected, "nvidia-powerd.service active");
+        StagedAction::DisableNvidiaPowerd
+            .verify_post(
+                GfxMode::Integrated,
+                &dgpu,
+                &ActionSettings::default(),
+                &units,
+            )
+            .await
+            .unwrap();
+        // Not installed, so not checked
+        StagedAction::EnableNvidiaPersistenced
+            .verify_post(GfxMode::Hybrid, &dgpu, &ActionSettings::default(), &units)
+            .await
+            .unwrap();
 
-            StagedAction::NotNvidia => [
-                StagedAction::KillAmd,
-                StagedAction::StartDisplayManager,
-                StagedAction::NoLogind,
-            ]
-            .contains(&next_allowed_action),
+        let settings = ActionSettings {
+            display_managers: vec!["display-manager.service".to_string()],
+            ..Default::default()
+        };
+        let err = post_failure(
+            StagedAction::StartDisplayManager
+                .verify_post(GfxMode::Hybrid, &dgpu, &settings, &units)
+                .await,
+            StagedAction::StartDisplayManager,
+        );
+        assert_eq!(err.0, "display-manager.service active");
 
-            StagedAction::None => [
-                StagedAction::RescanPci,
-                StagedAction::NoLogind,
-                StagedAction::WriteModprobeConf,
-                StagedAction::CheckVulkanIcd,
-                StagedAction::WaitLogout,
-                StagedAction::NotNvidia,
-                StagedAction::KillNvidia,
-                StagedAction::KillAmd,
-                StagedAction::EnableNvidiaPersistenced,
-                StagedAction::DisableNvidiaPersistenced,
-                StagedAction::EnableNvidiaPowerd,
-                StagedAction::DisableNvidiaPowerd,
-                StagedAction::UnloadVfioDrivers,
-            ]
-            .contains(&next_allowed_action),
+        // Each of the display managers is checked
+        let settings = ActionSettings {
+            display_managers: vec![
+                "display-manager.service".to_string(),
+                "greetd-seat1.service".to_string(),
+            ],
+            ..Default::default()
+        };
+        units
+            .units
+            .insert("display-manager.service", SystemdUnitState::Inactive);
+        units
+            .units
+            .insert("greetd-seat1.service", SystemdUnitState::Active);
+        let err = post_failure(
+            StagedAction::StopDisplayManager
+                .verify_post(GfxMode::Integrated, &dgpu, &settings, &units)
+                .await,
+            StagedAction::StopDisplayManager,
+        );
+        assert_eq!(err.0, "greetd-seat1.service inactive");
+    }
+
+    #[test]
+    fn action_names() {
+        assert_eq!(
+            StagedAction::StopDisplayManager.to_string(),
+            "StopDisplayManager"
+        );
+        assert_eq!(StagedAction::PreStopDelay(5).to_string(), "PreStopDelay(5)");
+        assert_eq!(
+            StagedAction::SpecialToggleOff("legion_gsync").to_string(),
+            "SpecialToggleOff(legion_gsync)"
+        );
+        // The name always starts with the id used in `disabled_actions`
+        for action in [
+            StagedAction::WaitLogout,
+            StagedAction::PreStopDelay(0),
+            StagedAction::SpecialToggleOn("x"),
+            StagedAction::AcpiDgpuOff,
+            StagedAction::None,
+        ] {
+            assert!(action.to_string().starts_with(action.id()), "{action:?}");
+        }
+    }
+
+    /// systemctl for the display managers, recording what it did
+    #[derive(Default)]
+    struct FakeSystemctl {
+        done: Mutex<Vec<String>>,
+        /// Units systemctl fails for
+        fail: Vec<&'static str>,
+        /// Units which never stop
+        stuck: Vec<&'static str>,
+    }
 
-            StagedAction::CheckVulkanIcd => true,
-        } {
+    impl DisplayManagerControl for FakeSystemctl {
+        fn unit_action(&self, action: SystemdUnitAction, unit: &str) -> Result<(), GfxError> {
+            if self.fail.contains(&unit) {
+                return Err(GfxError::SystemdUnitAction(format!(
+                    "systemctl {action:?} {unit} failed"
+                )));
+            }
+            let action = <&str>::from(action);
+            self.done.lock().unwrap().push(format!("{action} {unit}"));
             Ok(())
-        } else {
-            Err(GfxError::IncorrectActionOrder(next_allowed_action, *self))
+        }
+
+        fn wait_unit<'a>(
+            &'a self,
+            state: SystemdUnitState,
+            unit: &'a str,
+        ) -> BoxFuture<'a, Result<(), GfxError>> {
+            Box::pin(async move {
+                if self.stuck.contains(&unit) {
+                    Err(GfxError::SystemdUnitWaitTimeout(<&str>::from(state).into()))
+                } else {
+                    Ok(())
+                }
+            })
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        actions::{Action, StagedAction},
-        config::GfxConfig,
-        pci_device::{GfxMode, GfxVendor, HotplugType},
-    };
+    #[tokio::test]
+    async fn display_manager_units() {
+        let units = [
+            "display-manager.service".to_string(),
+            "greetd-seat1.service".to_string(),
+        ];
+        let ctl = FakeSystemctl::default();
+        stop_display_managers(&ctl, &units).await.unwrap();
+        start_display_managers(&ctl, &units).unwrap();
+        assert_eq!(
+            *ctl.done.lock().unwrap(),
+            [
+                "stop display-manager.service",
+                "stop greetd-seat1.service",
+                "start display-manager.service",
+                "start greetd-seat1.service",
+            ]
+        );
+
+        // The unit which didn't stop is named
+        let stuck = FakeSystemctl {
+            stuck: vec!["greetd-seat1.service"],
+            ..Default::default()
+        };
+        let err = stop_display_managers(&stuck, &units).await.unwrap_err();
+        assert!(
+            matches!(&err, GfxError::SystemdUnitAction(msg) if msg.starts_with("stop greetd-seat1.service")),
+            "{err}"
+        );
+
+        // A failed stop goes no further, but every unit is started
+        let failing = FakeSystemctl {
+            fail: vec!["display-manager.service"],
+            ..Default::default()
+        };
+        let err = stop_display_managers(&failing, &units).await.unwrap_err();
+        assert!(err.to_string().contains("display-manager.service"), "{err}");
+        assert!(failing.done.lock().unwrap().is_empty());
+        let err = start_display_managers(&failing, &units).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "systemd unit action start display-manager.service failed"
+        );
+        assert_eq!(
+            *failing.done.lock().unwrap(),
+            ["start greetd-seat1.service"]
+        );
+    }
+
+    #[test]
+    fn post_condition_message() {
+        let err = GfxError::PostCondition(
+            StagedAction::AsusDgpuDisable,
+            format!("{ASUS_DGPU_DISABLE_PATH} = 1"),
+            "0".to_string(),
+        );
+        assert_eq!(
+            err.to_string(),
+            format!("AsusDgpuDisable did not take effect: expected {ASUS_DGPU_DISABLE_PATH} = 1, found 0")
+        );
+    }
 
     #[test]
     fn verify_hybrid_to_integrated_action_order() {
         let mut config = GfxConfig {
-            config_path: Default::default(),
             mode: crate::pci_device::GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
-            vfio_enable: false,
-            vfio_save: false,
-            always_reboot: false,
-            no_logind: false,
             logout_timeout_s: 10,
             hotplug_type: crate::pci_device::HotplugType::None,
+            ..GfxConfig::new(Default::default())
         };
 
         let actions = StagedAction::action_list_for_switch(
@@ -416,17 +633,10 @@ mod tests {
     #[test]
     fn verify_integrated_to_hybrid_action_order() {
         let mut config = GfxConfig {
-            config_path: Default::default(),
             mode: crate::pci_device::GfxMode::Integrated,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
-            vfio_enable: false,
-            vfio_save: false,
-            always_reboot: false,
-            no_logind: false,
             logout_timeout_s: 10,
             hotplug_type: crate::pci_device::HotplugType::None,
+            ..GfxConfig::new(Default::default())
         };
 
         let actions = StagedAction::action_list_for_switch(
@@ -490,17 +700,10 @@ mod tests {
         ];
 
         let mut config = GfxConfig {
-            config_path: Default::default(),
             mode: crate::pci_device::GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
-            vfio_enable: false,
-            vfio_save: false,
-            always_reboot: false,
-            no_logind: false,
             logout_timeout_s: 10,
             hotplug_type: crate::pci_device::HotplugType::None,
+            ..GfxConfig::new(Default::default())
         };
 
         let run = |config: &GfxConfig| {
@@ -508,31 +711,36 @@ mod tests {
                 for to in modes {
                     for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
                         if vendor == GfxVendor::Amd && from == GfxMode::NvidiaNoModeset
-                            || from == GfxMode::AsusEgpu
                             || from == GfxMode::AsusMuxDgpu
                             || to == GfxMode::NvidiaNoModeset
-                            || to == GfxMode::AsusEgpu
                             || to == GfxMode::AsusMuxDgpu
                         {
                             continue;
                         }
 
-                        let actions =
-                            StagedAction::action_list_for_switch(config, vendor, from, to);
-                        match actions {
-                            Action::UserAction(_) => {} //panic!("Should be a list of actions"),
-                            Action::StagedActions(actions) => {
-                                let mut previous_action = StagedAction::None;
-                                for action in actions {
-                                    action
+                        for dgpu_disabled in [false, true] {
+                            let actions = StagedAction::action_list_for_switch_with(
+                                config,
+                                vendor,
+                                from,
+                                to,
+                                AsusToggleState { dgpu_disabled },
+                            );
+                            match actions {
+                                Action::UserAction(_) => {} //panic!("Should be a list of actions"),
+                                Action::StagedActions(actions) => {
+                                    let mut previous_action = StagedAction::None;
+                                    for action in actions {
+                                        action
                                         .verify_previous_action_for_current(previous_action)
                                         .map_err(|e| {
                                             println!(
-                                                "Action thread errored: from:{from}, to:{to}, {e}"
+                                                "Action thread errored: from:{from}, to:{to}, dgpu_disabled:{dgpu_disabled}, {e}"
                                             );
                                         })
                                         .unwrap();
-                                    previous_action = action;
+                                        previous_action = action;
+                                    }
                                 }
                             }
                         }
@@ -554,6 +762,17 @@ mod tests {
         run(&config);
         config.hotplug_type = HotplugType::Std;
         run(&config);
+
+        config.pre_stop_delay_s = 5;
+        run(&config);
+        config.no_logind = false;
+        run(&config);
+
+        config.vfio_keep_loaded = true;
+        run(&config);
+
+        config.nvidia_powerd_start = NvidiaPowerdStart::AfterDm { delay_ms: 0 };
+        run(&config);
     }
 
     #[test]
@@ -569,17 +788,10 @@ mod tests {
         ];
 
         let mut config = GfxConfig {
-            config_path: Default::default(),
             mode: crate::pci_device::GfxMode::Hybrid,
-            tmp_mode: None,
-            pending_mode: None,
-            pending_action: None,
-            vfio_enable: false,
-            vfio_save: false,
-            always_reboot: false,
-            no_logind: false,
             logout_timeout_s: 10,
             hotplug_type: crate::pci_device::HotplugType::None,
+            ..GfxConfig::new(Default::default())
         };
 
         let run = |config: &GfxConfig| {
@@ -587,31 +799,36 @@ mod tests {
                 for to in modes {
                     for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
                         if vendor == GfxVendor::Amd && from == GfxMode::NvidiaNoModeset
-                            || from == GfxMode::AsusEgpu
                             || from == GfxMode::AsusMuxDgpu
                             || to == GfxMode::NvidiaNoModeset
-                            || to == GfxMode::AsusEgpu
                             || to == GfxMode::AsusMuxDgpu
                         {
                             continue;
                         }
 
-                        let actions =
-                            StagedAction::action_list_for_switch(config, vendor, from, to);
-                        match actions {
-                            Action::UserAction(_) => {} //panic!("Should be a list of actions"),
-                            Action::StagedActions(actions) => {
-                                let mut previous_action = StagedAction::None;
-                                for action in actions {
-                                    previous_action
+                        for dgpu_disabled in [false, true] {
+                            let actions = StagedAction::action_list_for_switch_with(
+                                config,
+                                vendor,
+                                from,
+                                to,
+                                AsusToggleState { dgpu_disabled },
+                            );
+                            match actions {
+                                Action::UserAction(_) => {} //panic!("Should be a list of actions"),
+                                Action::StagedActions(actions) => {
+                                    let mut previous_action = StagedAction::None;
+                                    for action in actions {
+                                        previous_action
                                         .verify_next_allowed_action(action)
                                         .map_err(|e| {
                                             println!(
-                                                "Action thread errored: from:{from}, to:{to}, {e}"
+                                                "Action thread errored: from:{from}, to:{to}, dgpu_disabled:{dgpu_disabled}, {e}"
                                             );
                                         })
                                         .unwrap();
-                                    previous_action = action;
+                                        previous_action = action;
+                                    }
                                 }
                             }
                         }
@@ -633,5 +850,373 @@ mod tests {
         run(&config);
         config.hotplug_type = HotplugType::Std;
         run(&config);
+
+        config.pre_stop_delay_s = 5;
+        run(&config);
+        config.no_logind = false;
+        run(&config);
+
+        config.vfio_keep_loaded = true;
+        run(&config);
+
+        config.nvidia_powerd_start = NvidiaPowerdStart::AfterDm { delay_ms: 0 };
+        run(&config);
+    }
+
+    #[test]
+    fn pre_stop_delay_only_added_when_set() {
+        let mut config = GfxConfig {
+            mode: GfxMode::Hybrid,
+            ..GfxConfig::new(Default::default())
+        };
+
+        let has_delay = |config: &GfxConfig, from, to| match StagedAction::action_list_for_switch(
+            config,
+            GfxVendor::Nvidia,
+            from,
+            to,
+        ) {
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+            Action::StagedActions(actions) => actions
+                .iter()
+                .position(|a| matches!(a, StagedAction::PreStopDelay(_))),
+        };
+
+        assert_eq!(
+            has_delay(&config, GfxMode::Hybrid, GfxMode::Integrated),
+            None
+        );
+
+        config.pre_stop_delay_s = 5;
+        match StagedAction::action_list_for_switch(
+            &config,
+            GfxVendor::Nvidia,
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+        ) {
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+            Action::StagedActions(actions) => assert_eq!(
+                actions[..3],
+                [
+                    StagedAction::WaitLogout,
+                    StagedAction::PreStopDelay(5),
+                    StagedAction::StopDisplayManager
+                ]
+            ),
+        }
+        // Only switches which stop the display manager get a delay
+        assert_eq!(has_delay(&config, GfxMode::Integrated, GfxMode::Vfio), None);
+    }
+
+    #[test]
+    fn mode_lock_does_not_change_boot_actions() {
+        for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
+            for mode in [
+                GfxMode::Hybrid,
+                GfxMode::Integrated,
+                GfxMode::NvidiaNoModeset,
+                GfxMode::Vfio,
+                GfxMode::AsusEgpu,
+                GfxMode::AsusMuxDgpu,
+            ] {
+                let mut config = GfxConfig {
+                    mode,
+                    vfio_enable: true,
+                    ..GfxConfig::new(Default::default())
+                };
+                let unlocked = StagedAction::action_list_for_boot(&config, vendor, mode);
+                config.mode_locked = true;
+                assert_eq!(
+                    StagedAction::action_list_for_boot(&config, vendor, mode),
+                    unlocked
+                );
+            }
+        }
+    }
+
+    /// The plan for leaving AsusEgpu, with `dgpu_disable` read as `dgpu_disable`
+    fn egpu_exit(to: GfxMode, dgpu_disable: &'static str) -> Vec<StagedAction> {
+        let config = GfxConfig {
+            mode: GfxMode::AsusEgpu,
+            hotplug_type: HotplugType::None,
+            ..GfxConfig::new(Default::default())
+        };
+        let asus = AsusToggleState::read_with(|path| {
+            path.ends_with("/dgpu_disable")
+                .then(|| dgpu_disable.to_string())
+        });
+        match StagedAction::action_list_for_switch_with(
+            &config,
+            GfxVendor::Nvidia,
+            GfxMode::AsusEgpu,
+            to,
+            asus,
+        ) {
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+            Action::StagedActions(actions) => actions,
+        }
+    }
+
+    #[test]
+    fn egpu_exit_with_dgpu_disabled() {
+        let actions = egpu_exit(GfxMode::Hybrid, "1\n");
+        let start = actions
+            .iter()
+            .position(|a| *a == StagedAction::AsusEgpuDisable)
+            .unwrap();
+        // The dGPU is back on the bus before the rescan and driver load
+        assert_eq!(
+            actions[start..start + 4],
+            [
+                StagedAction::AsusEgpuDisable,
+                StagedAction::AsusDgpuEnable,
+                StagedAction::RescanPci,
+                StagedAction::LoadGpuDrivers,
+            ]
+        );
+
+        // The dGPU is left disabled as it was, even without ASUS hotplug
+        let actions = egpu_exit(GfxMode::Integrated, "1\n");
+        let egpu_off = actions
+            .iter()
+            .position(|a| *a == StagedAction::AsusEgpuDisable)
+            .unwrap();
+        let dgpu_off = actions
+            .iter()
+            .position(|a| *a == StagedAction::AsusDgpuDisable)
+            .unwrap();
+        assert!(egpu_off < dgpu_off);
+        assert!(!actions.contains(&StagedAction::DevTreeManaged));
+    }
+
+    #[test]
+    fn egpu_exit_with_dgpu_enabled() {
+        // The enable is kept as the egpu disable may also disable the dgpu
+        assert_eq!(
+            egpu_exit(GfxMode::Hybrid, "0\n"),
+            egpu_exit(GfxMode::Hybrid, "1\n")
+        );
+
+        let actions = egpu_exit(GfxMode::Integrated, "0\n");
+        assert!(!actions.contains(&StagedAction::AsusDgpuDisable));
+        assert_eq!(actions[actions.len() - 2], StagedAction::DevTreeManaged);
+    }
+
+    #[test]
+    fn egpu_exit_without_asus_toggles() {
+        // A missing dgpu_disable reads as enabled
+        assert_eq!(
+            AsusToggleState::read_with(|_| None),
+            AsusToggleState::default()
+        );
+    }
+
+    fn config_disabling(ids: &[&str]) -> GfxConfig {
+        GfxConfig {
+            disabled_actions: ids.iter().map(|id| id.to_string()).collect(),
+            ..GfxConfig::new(Default::default())
+        }
+    }
+
+    #[test]
+    fn disable_start_display_manager() {
+        // A greeter run by its own supervisor is started again without supergfxd
+        let config = config_disabling(&["StartDisplayManager"]);
+        validate_disabled_actions(&config).unwrap();
+
+        let plan = StagedAction::action_list_for_switch(
+            &config,
+            GfxVendor::Nvidia,
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+        );
+        let full = StagedAction::action_list_for_switch(
+            &GfxConfig::new(Default::default()),
+            GfxVendor::Nvidia,
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+        );
+        match (plan, full) {
+            (Action::StagedActions(plan), Action::StagedActions(mut full)) => {
+                assert_eq!(full.pop(), Some(StagedAction::StartDisplayManager));
+                assert_eq!(plan, full);
+            }
+            _ => panic!("Should be a list of actions"),
+        }
+    }
+
+    #[test]
+    fn disable_action_breaking_order_is_refused() {
+        // Without the stop the drivers would be unloaded under a running session
+        let err = validate_disabled_actions(&config_disabling(&["StopDisplayManager"]))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("removing StopDisplayManager puts WaitLogout before"),
+            "{err}"
+        );
+
+        // With no_logind the display manager is never stopped, so there is nothing to break
+        let config = GfxConfig {
+            no_logind: true,
+            ..config_disabling(&["StopDisplayManager"])
+        };
+        validate_disabled_actions(&config).unwrap();
+    }
+
+    #[test]
+    fn disable_action_deny_list() {
+        for id in ["WriteModprobeConf", "AsusDgpuDisable", "AsusMuxDgpu"] {
+            let err = validate_disabled_actions(&config_disabling(&["StartDisplayManager", id]))
+                .unwrap_err();
+            assert!(matches!(err, GfxError::DisabledActions(_)));
+            assert!(err.to_string().contains("can't be disabled"), "{err}");
+        }
+        let err = validate_disabled_actions(&config_disabling(&["StopTheWorld"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "disabled_actions: unknown action StopTheWorld"
+        );
+    }
+
+    fn nvidia_plan(config: &GfxConfig, from: GfxMode, to: GfxMode) -> Vec<StagedAction> {
+        match StagedAction::action_list_for_switch(config, GfxVendor::Nvidia, from, to) {
+            Action::StagedActions(actions) => actions,
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+    }
+
+    #[test]
+    fn nvidia_powerd_start_order() {
+        let mut config = GfxConfig::new(Default::default());
+        let before = nvidia_plan(&config, GfxMode::Integrated, GfxMode::Hybrid);
+        assert_eq!(
+            before[before.len() - 3..],
+            [
+                StagedAction::EnableNvidiaPersistenced,
+                StagedAction::EnableNvidiaPowerd,
+                StagedAction::StartDisplayManager,
+            ]
+        );
+
+        config.nvidia_powerd_start =
+            serde_json::from_str(r#"{"AfterDm": {"delay_ms": 500}}"#).unwrap();
+        let after = nvidia_plan(&config, GfxMode::Integrated, GfxMode::Hybrid);
+        assert_eq!(
+            after[after.len() - 4..],
+            [
+                StagedAction::EnableNvidiaPersistenced,
+                StagedAction::StartDisplayManager,
+                StagedAction::WaitDisplayManager(500),
+                StagedAction::EnableNvidiaPowerd,
+            ]
+        );
+        assert_eq!(after.len(), before.len() + 1);
+        // Without the display manager restarted there is nothing to start it after
+        assert_eq!(
+            nvidia_plan(&config, GfxMode::Integrated, GfxMode::NvidiaNoModeset),
+            nvidia_plan(
+                &GfxConfig::new(Default::default()),
+                GfxMode::Integrated,
+                GfxMode::NvidiaNoModeset
+            )
+        );
+        assert_eq!(
+            StagedAction::action_list_for_boot(&config, GfxVendor::Nvidia, GfxMode::Hybrid).last(),
+            Some(&StagedAction::EnableNvidiaPowerd)
+        );
+
+        // Both orders pass the order checks
+        for plan in [before, after] {
+            let mut previous = StagedAction::None;
+            for action in plan {
+                action
+                    .verify_previous_action_for_current(previous)
+                    .and_then(|_| previous.verify_next_allowed_action(action))
+                    .unwrap();
+                previous = action;
+            }
+        }
+        assert!(StagedAction::StartDisplayManager
+            .verify_next_allowed_action(StagedAction::EnableNvidiaPowerd)
+            .is_err());
+        assert!(StagedAction::EnableNvidiaPowerd
+            .verify_previous_action_for_current(StagedAction::StartDisplayManager)
+            .is_err());
+
+        let err = validate_disabled_actions(&config_disabling(&["WaitDisplayManager"]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("set nvidia_powerd_start to BeforeDm"), "{err}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_display_manager_before_powerd() {
+        let units = ["display-manager.service".to_string()];
+        let start = tokio::time::Instant::now();
+        wait_display_managers(
+            &FakeSystemctl::default(),
+            &units,
+            Duration::from_millis(500),
+        )
+        .await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+        // One slow to start doesn't hold back powerd for good
+        let stuck = FakeSystemctl {
+            stuck: vec!["display-manager.service"],
+            ..Default::default()
+        };
+        wait_display_managers(&stuck, &units, Duration::ZERO).await;
+    }
+
+    /// nvidia-powerd which fails after each of the first `crashes` starts
+    struct FakePowerd {
+        crashes: u32,
+        starts: Mutex<u32>,
+    }
+
+    impl FakePowerd {
+        fn new(crashes: u32) -> Self {
+            Self {
+                crashes,
+                starts: Mutex::new(0),
+            }
+        }
+
+        fn starts(&self) -> u32 {
+            *self.starts.lock().unwrap()
+        }
+    }
+
+    impl PowerdControl for FakePowerd {
+        fn start(&self) -> Result<(), GfxError> {
+            *self.starts.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn failed(&self) -> bool {
+            self.starts() <= self.crashes
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn powerd_crash_is_retried_once() {
+        let watch = Duration::from_secs(3);
+
+        let steady = FakePowerd::new(0);
+        let start = tokio::time::Instant::now();
+        start_powerd_watched(&steady, watch).await.unwrap();
+        assert_eq!(steady.starts(), 1);
+        assert_eq!(start.elapsed(), watch);
+
+        let crashed_once = FakePowerd::new(1);
+        start_powerd_watched(&crashed_once, watch).await.unwrap();
+        assert_eq!(crashed_once.starts(), 2);
+
+        // Only retried once, the switch still completes
+        let always = FakePowerd::new(u32::MAX);
+        start_powerd_watched(&always, watch).await.unwrap();
+        assert_eq!(always.starts(), 2);
     }
 }
@@ -0,0 +1,148 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        attention::{
+            AttentionBoard, BootDeviations, ATTENTION_DEGRADED_BOOT, ATTENTION_HOTPLUG_DOWNGRADE,
+            ATTENTION_MUX_ASSUMED, ATTENTION_REDUCED_BOOT,
+        },
+        config::GfxConfig,
+        controller::CtrlGraphics,
+        pci_device::{DiscreetGpu, GfxMode, GfxVendor},
+    };
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-attention-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("attention.json")
+    }
+
+    fn codes(board: &AttentionBoard) -> Vec<&str> {
+        board
+            .items()
+            .iter()
+            .map(|item| item.code.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn boot_deviations_are_registered() {
+        assert!(BootDeviations::default().attention().is_empty());
+        let deviations = BootDeviations {
+            failures: vec!["LoadGpuDrivers: nvidia missing".to_string()],
+            mux_assumed: true,
+            igpu_fallback: None,
+            hotplug_downgrade: Some("hotplug_type Asus needs dgpu_disable".to_string()),
+            reduced: Some("a graphical session was running".to_string()),
+        };
+        let attention = deviations.attention();
+        assert_eq!(
+            attention.iter().map(|(code, _)| *code).collect::<Vec<_>>(),
+            vec![
+                ATTENTION_DEGRADED_BOOT,
+                ATTENTION_MUX_ASSUMED,
+                ATTENTION_HOTPLUG_DOWNGRADE,
+                ATTENTION_REDUCED_BOOT,
+            ]
+        );
+        assert_eq!(
+            attention[0].1,
+            "Boot tasks failed: LoadGpuDrivers: nvidia missing"
+        );
+    }
+
+    #[test]
+    fn items_persist_and_expire() {
+        let path = test_path("expiry");
+        let mut board = AttentionBoard::new(path.clone(), "boot-1".to_string(), 2);
+        board.register(
+            ATTENTION_MUX_ASSUMED,
+            "assumed".to_string(),
+            GfxMode::AsusMuxDgpu,
+            100,
+        );
+        board.register(
+            ATTENTION_REDUCED_BOOT,
+            "reduced".to_string(),
+            GfxMode::Hybrid,
+            100,
+        );
+
+        // A restart of the daemon on the same boot doesn't age them
+        let board = AttentionBoard::new(path.clone(), "boot-1".to_string(), 2);
+        assert_eq!(
+            codes(&board),
+            vec![ATTENTION_MUX_ASSUMED, ATTENTION_REDUCED_BOOT]
+        );
+        assert_eq!(board.items()[0].boots, 0);
+        assert_eq!(board.items()[0].mode, GfxMode::AsusMuxDgpu);
+
+        // Happened again on the next boot, so it starts over
+        let mut board = AttentionBoard::new(path.clone(), "boot-2".to_string(), 2);
+        assert_eq!(board.items()[1].boots, 1);
+        board.register(
+            ATTENTION_REDUCED_BOOT,
+            "reduced again".to_string(),
+            GfxMode::Hybrid,
+            200,
+        );
+        let board = AttentionBoard::new(path.clone(), "boot-3".to_string(), 2);
+        assert_eq!(codes(&board), vec![ATTENTION_REDUCED_BOOT]);
+        assert_eq!(board.items()[0].message, "reduced again");
+        assert_eq!(board.items()[0].timestamp, 200);
+
+        // Kept until dismissed
+        let board = AttentionBoard::new(path.clone(), "boot-9".to_string(), 0);
+        assert_eq!(codes(&board), vec![ATTENTION_REDUCED_BOOT]);
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn dismissed_items_stay_dismissed() {
+        let path = test_path("dismiss");
+        let mut board = AttentionBoard::new(path.clone(), "boot-1".to_string(), 5);
+        board.register(
+            ATTENTION_DEGRADED_BOOT,
+            "failed".to_string(),
+            GfxMode::Hybrid,
+            100,
+        );
+        assert!(board.dismiss(ATTENTION_MUX_ASSUMED).is_none());
+        assert_eq!(
+            board.dismiss(ATTENTION_DEGRADED_BOOT).unwrap().message,
+            "failed"
+        );
+        let board = AttentionBoard::new(path.clone(), "boot-1".to_string(), 5);
+        assert!(board.items().is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[tokio::test]
+    async fn first_status_announces_items() {
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+        ctrl.register_attention(
+            GfxMode::Hybrid,
+            vec![(ATTENTION_REDUCED_BOOT, "reduced".to_string())],
+        )
+        .await;
+        assert_eq!(ctrl.get_status().await.attention, 1);
+
+        ctrl.announce_attention().await;
+        // Only the first client gets them sent
+        assert!(ctrl.attention.lock().await.take_announcement().is_none());
+        assert_eq!(ctrl.attention.lock().await.items().len(), 1);
+
+        ctrl.attention.lock().await.dismiss(ATTENTION_REDUCED_BOOT);
+        assert_eq!(ctrl.get_status().await.attention, 0);
+    }
+}
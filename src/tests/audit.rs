@@ -0,0 +1,151 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use futures_util::lock::Mutex;
+    use zbus::message::Message;
+
+    use crate::{
+        audit::{format_timestamp, Actor, AuditLog, AuditRecord},
+        config::{GfxConfig, GfxConfigDbus},
+        controller::CtrlGraphics,
+        pci_device::{DiscreetGpu, GfxVendor},
+        tests::test_dir,
+        zbus_iface::config_changes,
+    };
+
+    fn record(timestamp: u64, change: &str) -> AuditRecord {
+        AuditRecord {
+            timestamp,
+            actor: ":1.42".to_string(),
+            change: change.to_string(),
+        }
+    }
+
+    #[test]
+    fn record_lines() {
+        let rec = record(1700000000, "mode Hybrid -> Integrated");
+        assert_eq!(
+            rec.to_line(),
+            "1700000000\t:1.42\tmode Hybrid -> Integrated\n"
+        );
+        assert_eq!(AuditRecord::from_line(&rec.to_line()), Some(rec));
+
+        // A record is always one line
+        let rec = record(1, "vfio_enable false\n-> true\tinjected");
+        assert_eq!(rec.to_line().lines().count(), 1);
+        assert_eq!(
+            AuditRecord::from_line(&rec.to_line()).unwrap().change,
+            "vfio_enable false -> true injected"
+        );
+        assert_eq!(AuditRecord::from_line("garbage"), None);
+        assert_eq!(AuditRecord::from_line("x\tboot\tmode"), None);
+
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(1709251199), "2024-02-29 23:59:59");
+    }
+
+    #[test]
+    fn rotation_keeps_recent_records() {
+        let dir = test_dir("audit-rotation");
+        let path = dir.join("audit.log");
+        // Room for two records per file
+        let log = AuditLog::new(path.clone(), 40);
+        for i in 0..12 {
+            log.append(&record(i, &format!("change {i:02}"))).unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() <= 40);
+        assert!(dir.join("audit.log.3").exists());
+        assert!(!dir.join("audit.log.4").exists());
+
+        // The oldest records were dropped with the oldest rotated file
+        let all = log.tail(100);
+        assert_eq!(all.len(), 8);
+        assert_eq!(all[0], record(4, "change 04"));
+        assert_eq!(all[7], record(11, "change 11"));
+        assert_eq!(log.tail(3), all[5..].to_vec());
+
+        assert!(AuditLog::disabled().tail(10).is_empty());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn actor_from_header() {
+        let msg = Message::method_call("/org/supergfxctl/Gfx", "SetMode")
+            .unwrap()
+            .sender(":1.42")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        let actor = Actor::from_header(&msg.header());
+        assert_eq!(
+            actor,
+            Actor::Bus {
+                sender: ":1.42".to_string(),
+                uid: None
+            }
+        );
+        assert_eq!(actor.to_string(), ":1.42");
+        assert_eq!(actor.uid(), None);
+
+        let msg = Message::method_call("/org/supergfxctl/Gfx", "SetMode")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        assert_eq!(
+            Actor::from_header(&msg.header()),
+            Actor::Bus {
+                sender: "unknown".to_string(),
+                uid: None
+            }
+        );
+    }
+
+    #[test]
+    fn config_field_changes() {
+        let old = GfxConfigDbus::from(&GfxConfig::new(Default::default()));
+        assert!(config_changes(&old, &old.clone()).is_empty());
+        let new = GfxConfigDbus {
+            vfio_enable: true,
+            logout_timeout_s: 60,
+            ..old.clone()
+        };
+        assert_eq!(
+            config_changes(&old, &new),
+            ["vfio_enable false -> true", "logout_timeout_s 180 -> 60"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mode_lock_is_recorded_with_actor() {
+        let dir = test_dir("audit-lock");
+        let config = GfxConfig::new(dir.join("config.json").to_string_lossy().to_string());
+        let mut ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+        ctrl.set_audit_log(AuditLog::new(dir.join("audit.log"), 4096));
+        let actor = Actor::Bus {
+            sender: ":1.7".to_string(),
+            uid: Some(1000),
+        };
+        ctrl.set_mode_locked(true, &actor).await.unwrap();
+        // Not a change, so not recorded
+        ctrl.set_mode_locked(true, &actor).await.unwrap();
+        ctrl.set_mode_locked(false, &Actor::Daemon).await.unwrap();
+
+        let records = ctrl.audit.tail(10);
+        let records: Vec<(&str, &str)> = records
+            .iter()
+            .map(|r| (r.actor.as_str(), r.change.as_str()))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (":1.7", "mode locked to Hybrid"),
+                ("supergfxd", "mode unlocked from Hybrid"),
+            ]
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,137 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        audit::AuditLog,
+        automation_inhibit::{skip_if_inhibited, InhibitRegistry, MAX_INHIBIT},
+        error::GfxError,
+        tests::test_dir,
+    };
+
+    #[test]
+    fn inhibitions_expire() {
+        let start = Instant::now();
+        let mut registry = InhibitRegistry::default();
+        assert_eq!(registry.inhibited_by(start), None);
+        registry
+            .add(":1.10", "benchmark", Duration::from_secs(60), start)
+            .unwrap();
+        assert_eq!(
+            registry.inhibited_by(start + Duration::from_secs(59)),
+            Some("benchmark (:1.10)".to_string())
+        );
+        assert_eq!(registry.inhibited_by(start + Duration::from_secs(60)), None);
+        assert!(registry.active(start).is_empty());
+    }
+
+    #[test]
+    fn several_holders() {
+        let start = Instant::now();
+        let mut registry = InhibitRegistry::default();
+        let talk = registry
+            .add(":1.10", "presentation", Duration::from_secs(600), start)
+            .unwrap();
+        let bench = registry
+            .add(":1.11", "benchmark", Duration::from_secs(60), start)
+            .unwrap();
+        assert_ne!(talk, bench);
+        assert_eq!(
+            registry.inhibited_by(start),
+            Some("presentation (:1.10), benchmark (:1.11)".to_string())
+        );
+
+        // Only the owner can end it
+        assert!(matches!(
+            registry.remove(talk, ":1.11"),
+            Err(GfxError::InvalidInhibition(_))
+        ));
+        assert_eq!(registry.remove(bench, ":1.11").unwrap().reason, "benchmark");
+        assert!(registry.remove(bench, ":1.11").is_err());
+        // The other holder still inhibits
+        let active = registry.active(start + Duration::from_secs(120));
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].cookie, talk);
+        assert_eq!(active[0].owner, ":1.10");
+    }
+
+    #[test]
+    fn owner_leaving_the_bus() {
+        let start = Instant::now();
+        let mut registry = InhibitRegistry::default();
+        for reason in ["presentation", "screen recording"] {
+            registry
+                .add(":1.10", reason, Duration::from_secs(600), start)
+                .unwrap();
+        }
+        registry
+            .add(":1.11", "benchmark", Duration::from_secs(600), start)
+            .unwrap();
+
+        // The first client crashes
+        let gone = registry.owner_gone(":1.10");
+        assert_eq!(gone.len(), 2);
+        assert!(gone.iter().all(|i| i.owner == ":1.10"));
+        assert_eq!(
+            registry.inhibited_by(start),
+            Some("benchmark (:1.11)".to_string())
+        );
+        // A name which held nothing
+        assert!(registry.owner_gone(":1.12").is_empty());
+
+        assert_eq!(registry.owner_gone(":1.11").len(), 1);
+        assert_eq!(registry.inhibited_by(start), None);
+    }
+
+    #[test]
+    fn bounded_and_with_a_reason() {
+        let now = Instant::now();
+        let mut registry = InhibitRegistry::default();
+        for (reason, duration) in [
+            ("benchmark", Duration::ZERO),
+            ("benchmark", MAX_INHIBIT + Duration::from_secs(1)),
+            (" ", Duration::from_secs(60)),
+        ] {
+            assert!(matches!(
+                registry.add(":1.10", reason, duration, now),
+                Err(GfxError::InvalidInhibition(_))
+            ));
+        }
+        assert!(registry.add(":1.10", "benchmark", MAX_INHIBIT, now).is_ok());
+    }
+
+    #[tokio::test]
+    async fn skipped_actions_are_audited() {
+        let dir = test_dir("inhibit-skipped");
+        let audit = AuditLog::new(dir.join("audit.log"), 4096);
+        let registry = Mutex::new(InhibitRegistry::default());
+
+        assert!(!skip_if_inhibited(&registry, &audit, "periodic verify").await);
+        assert!(audit.tail(10).is_empty());
+
+        registry
+            .lock()
+            .await
+            .add(
+                ":1.10",
+                "benchmark",
+                Duration::from_secs(60),
+                Instant::now(),
+            )
+            .unwrap();
+        assert!(skip_if_inhibited(&registry, &audit, "periodic verify").await);
+        let records = audit.tail(10);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].actor, "supergfxd");
+        assert_eq!(
+            records[0].change,
+            "periodic verify: skipped due to inhibition by benchmark (:1.10)"
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,169 @@
+#[cfg(test)]
+mod tests {
+    use futures_util::future::BoxFuture;
+
+    use crate::{
+        actions::StagedAction,
+        boot_context::{
+            boot_subset, left_as_found, reduced_summary, BootContext, BootContextProbe,
+        },
+        config::GfxConfig,
+        error::GfxError,
+        logout_switch::SessionProbe,
+        pci_device::{GfxMode, GfxVendor},
+        verify::DriftFinding,
+    };
+
+    /// The sessions logind lists and the state of the display manager, `None` if they
+    /// can't be read
+    struct MockSystem {
+        sessions: Option<Vec<&'static str>>,
+        display_manager: Option<bool>,
+    }
+
+    impl SessionProbe for MockSystem {
+        fn graphical_sessions(&self) -> BoxFuture<'_, Result<Vec<String>, GfxError>> {
+            Box::pin(async move {
+                self.sessions
+                    .as_ref()
+                    .map(|ids| ids.iter().map(|id| id.to_string()).collect())
+                    .ok_or_else(|| GfxError::NotSupported("no logind".to_string()))
+            })
+        }
+    }
+
+    impl BootContextProbe for MockSystem {
+        fn display_manager_active(&self) -> Result<bool, GfxError> {
+            self.display_manager
+                .ok_or_else(|| GfxError::NotSupported("no systemctl".to_string()))
+        }
+    }
+
+    async fn classify(
+        sessions: Option<Vec<&'static str>>,
+        display_manager: Option<bool>,
+    ) -> BootContext {
+        BootContext::classify(&MockSystem {
+            sessions,
+            display_manager,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn at_boot() {
+        assert_eq!(
+            classify(Some(vec![]), Some(false)).await,
+            BootContext::AtBoot
+        );
+        // Nothing can be asked, as before the context was checked
+        assert_eq!(classify(None, None).await, BootContext::AtBoot);
+        assert_eq!(classify(Some(vec![]), None).await, BootContext::AtBoot);
+    }
+
+    #[tokio::test]
+    async fn live_session() {
+        assert_eq!(
+            classify(Some(vec!["2", "5"]), Some(true)).await,
+            BootContext::LiveSession("graphical sessions 2, 5 are open".to_string())
+        );
+        // The greeter runs before anyone logs in
+        assert_eq!(
+            classify(Some(vec![]), Some(true)).await,
+            BootContext::LiveSession("display-manager.service is active".to_string())
+        );
+        assert_eq!(
+            classify(None, Some(true)).await,
+            BootContext::LiveSession("display-manager.service is active".to_string())
+        );
+        // A session without a display manager, such as a compositor started from a tty
+        assert_eq!(
+            classify(Some(vec!["3"]), None).await,
+            BootContext::LiveSession("graphical sessions 3 are open".to_string())
+        );
+    }
+
+    #[test]
+    fn full_boot_tasks_at_boot() {
+        let config = GfxConfig::new(Default::default());
+        for mode in [GfxMode::Hybrid, GfxMode::Integrated, GfxMode::AsusMuxDgpu] {
+            let actions = StagedAction::action_list_for_boot(&config, GfxVendor::Nvidia, mode);
+            let (run, skipped) = boot_subset(&BootContext::AtBoot, actions.clone());
+            assert_eq!(run, actions);
+            assert!(skipped.is_empty());
+        }
+    }
+
+    #[test]
+    fn reduced_boot_tasks_in_a_live_session() {
+        let config = GfxConfig::new(Default::default());
+        let live = BootContext::LiveSession("display-manager.service is active".to_string());
+
+        let actions =
+            StagedAction::action_list_for_boot(&config, GfxVendor::Nvidia, GfxMode::Hybrid);
+        let (run, skipped) = boot_subset(&live, actions);
+        assert_eq!(
+            run,
+            [
+                StagedAction::WriteModprobeConf,
+                StagedAction::CheckVulkanIcd,
+                // The marker for hotplug_type None
+                StagedAction::DevTreeManaged,
+            ]
+        );
+        for action in [
+            StagedAction::RescanPci,
+            StagedAction::LoadGpuDrivers,
+            StagedAction::EnableNvidiaPowerd,
+        ] {
+            assert!(skipped.contains(&action), "{action:?} isn't skipped");
+        }
+
+        let actions =
+            StagedAction::action_list_for_boot(&config, GfxVendor::Nvidia, GfxMode::Integrated);
+        let (run, skipped) = boot_subset(&live, actions);
+        assert!(run.contains(&StagedAction::WriteModprobeConf));
+        for action in [
+            StagedAction::KillNvidia,
+            StagedAction::UnloadGpuDrivers,
+            StagedAction::UnbindRemoveGpu,
+        ] {
+            assert!(skipped.contains(&action), "{action:?} isn't skipped");
+        }
+    }
+
+    #[test]
+    fn drift_left_as_found() {
+        let runtime_pm = DriftFinding::RuntimePm {
+            function: "0000:01:00.0".to_string(),
+            control: "on".to_string(),
+        };
+        let left = left_as_found(vec![
+            DriftFinding::ModprobeConf,
+            runtime_pm.clone(),
+            DriftFinding::SwitcherooRule,
+            DriftFinding::UnexpectedModule("nvidia".to_string()),
+            DriftFinding::StaleInitramfs("initramfs regeneration recommended".to_string()),
+        ]);
+        assert_eq!(
+            left,
+            [
+                runtime_pm,
+                DriftFinding::UnexpectedModule("nvidia".to_string())
+            ]
+        );
+
+        assert_eq!(
+            reduced_summary(
+                "graphical sessions 2 are open",
+                &[StagedAction::RescanPci],
+                &left
+            ),
+            "graphical sessions 2 are open, only the boot tasks which don't disturb it were run, skipped RescanPci, left as found: runtime PM of 0000:01:00.0 is on, not auto; nvidia is loaded but is unloaded in this mode"
+        );
+        assert_eq!(
+            reduced_summary("display-manager.service is active", &[], &[]),
+            "display-manager.service is active, only the boot tasks which don't disturb it were run"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        buffers::{memory_report, rss_kib_from, BoundedQueue, BufferReport},
+        power_blockers::{BlockerWatch, MAX_POWER_BLOCKERS},
+        thermal::{ThermalAdvisory, ThermalWatch, MAX_THERMAL_SAMPLES},
+    };
+
+    fn listed(name: &str) -> Option<BufferReport> {
+        memory_report()
+            .buffers
+            .into_iter()
+            .find(|buffer| buffer.name == name)
+    }
+
+    #[test]
+    fn capacity_enforced() {
+        let mut queue = BoundedQueue::new("test_capacity_enforced", 100);
+        for i in 0..100_000u32 {
+            queue.push(i);
+            assert!(queue.len() <= 100);
+        }
+        assert_eq!(queue.len(), 100);
+        assert_eq!(queue.dropped(), 100_000 - 100);
+        // The newest are kept
+        assert!(queue.iter().copied().eq(99_900..100_000));
+
+        // Replacing drops what doesn't fit, and counts it
+        queue.replace(0..250);
+        assert!(queue.iter().copied().eq(150..250));
+        assert_eq!(queue.dropped(), 100_000 - 100 + 150);
+        assert_eq!(queue.into_vec(), (150..250).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_capacity_holds_nothing() {
+        let mut queue = BoundedQueue::new("test_zero_capacity", 0);
+        for i in 0..1000 {
+            queue.push(i);
+        }
+        assert!(queue.is_empty());
+        assert_eq!(queue.dropped(), 1000);
+    }
+
+    #[test]
+    fn report_lists_every_buffer() {
+        let mut small = BoundedQueue::new("test_report_small", 4);
+        let mut large = BoundedQueue::new("test_report_large", 10_000);
+        for i in 0..20 {
+            small.push(i);
+            large.push(i);
+        }
+        assert_eq!(
+            listed("test_report_small"),
+            Some(BufferReport {
+                name: "test_report_small".to_string(),
+                capacity: 4,
+                len: 4,
+                dropped: 16,
+            })
+        );
+        assert_eq!(
+            listed("test_report_large"),
+            Some(BufferReport {
+                name: "test_report_large".to_string(),
+                capacity: 10_000,
+                len: 20,
+                dropped: 0,
+            })
+        );
+        large.clear();
+        assert_eq!(listed("test_report_large").unwrap().len, 0);
+
+        // Only live buffers are listed
+        drop(small);
+        assert_eq!(listed("test_report_small"), None);
+        assert!(listed("test_report_large").is_some());
+    }
+
+    #[test]
+    fn daemon_buffers_registered() {
+        // Made with the queue, so a feature can't forget to list it
+        let _watch = BlockerWatch::new(Duration::from_secs(600));
+        let blockers = listed("power_blockers").unwrap();
+        assert_eq!(blockers.capacity, MAX_POWER_BLOCKERS as u64);
+        let _watch = ThermalWatch::new(ThermalAdvisory::default());
+        let samples = listed("thermal_samples").unwrap();
+        assert_eq!(samples.capacity, MAX_THERMAL_SAMPLES as u64);
+    }
+
+    #[test]
+    fn rss_read() {
+        let status = "Name:\tsupergfxd\nVmPeak:\t  12345 kB\nVmRSS:\t    6789 kB\nThreads:\t4\n";
+        assert_eq!(rss_kib_from(status), Some(6789));
+        assert_eq!(rss_kib_from("Name:\tsupergfxd\n"), None);
+        assert!(memory_report().rss_kib > 0);
+    }
+}
@@ -0,0 +1,73 @@
+#[cfg(test)]
+mod tests {
+    use crate::{
+        build_info::{render_versions, BuildInfo},
+        CONFIG_PATH, VERSION,
+    };
+
+    fn build(version: &str, commit: &str) -> BuildInfo {
+        BuildInfo {
+            version: version.to_string(),
+            git_commit: commit.to_string(),
+            features: vec!["daemon".to_string(), "cli".to_string()],
+            rustc: "rustc 1.80.0".to_string(),
+            build_date: "2024-07-21".to_string(),
+            paths: vec![("config".to_string(), CONFIG_PATH.to_string())],
+            overrides: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn current_build() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, VERSION);
+        assert!(!info.git_commit.is_empty());
+        assert!(info.rustc.starts_with("rustc") || info.rustc == "unknown");
+        assert_eq!(info.build_date.len(), 10);
+        assert!(info
+            .paths
+            .contains(&("config".to_string(), CONFIG_PATH.to_string())));
+        assert!(info.overrides.is_empty());
+    }
+
+    #[test]
+    fn daemon_unreachable() {
+        let out = render_versions(&build("5.2.7", "abc"), Err("connection refused"));
+        assert_eq!(
+            out,
+            "supergfxctl 5.2.7 (client)\nsupergfxd could not be reached: connection refused\n"
+        );
+    }
+
+    #[test]
+    fn same_build() {
+        let out = render_versions(&build("5.2.7", "abc"), Ok(&build("5.2.7", "abc")));
+        assert!(out.starts_with("5.2.7\n  commit: abc\n"), "{out}");
+        assert!(out.contains("  built: 2024-07-21 with rustc 1.80.0\n"));
+        assert!(out.contains("  features: daemon, cli\n"));
+        assert!(out.contains(&format!("  config: {CONFIG_PATH}\n")));
+        assert!(!out.contains("client"));
+    }
+
+    #[test]
+    fn different_builds_are_labelled() {
+        let mut daemon = build("5.2.7", "def");
+        daemon
+            .overrides
+            .push(("config".to_string(), "/etc/supergfxd.conf".to_string()));
+        let out = render_versions(&build("5.2.7", "abc"), Ok(&daemon));
+        assert!(out.starts_with("supergfxd 5.2.7 (daemon)\n"), "{out}");
+        assert!(out.contains("  config (in use): /etc/supergfxd.conf\n"));
+        assert!(out.ends_with("supergfxctl 5.2.7 (client, commit abc)\n"));
+
+        // A daemon from before BuildInfo
+        let out = render_versions(
+            &build("5.2.7", "abc"),
+            Ok(&BuildInfo::version_only("5.2.1".to_string())),
+        );
+        assert_eq!(
+            out,
+            "supergfxd 5.2.1 (daemon)\nsupergfxctl 5.2.7 (client, commit abc)\n"
+        );
+    }
+}
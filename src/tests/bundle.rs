@@ -0,0 +1,196 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        bundle::{crc32, redact_config, BundleMember, SupportBundle},
+        config::GfxConfig,
+        controller::CtrlGraphics,
+        pci_device::{DiscreetGpu, GfxVendor},
+    };
+
+    /// Members every bundle has, placeholders included
+    const EXPECTED_MEMBERS: &[&str] = &[
+        "manifest.json",
+        "config.json",
+        "build_info.json",
+        "status.json",
+        "devices.json",
+        "switcheroo.json",
+        "power_blockers.json",
+        "memory.json",
+        "link_info.json",
+        "supported.json",
+        "diagnostics.json",
+        "boot_report.json",
+        "verify.json",
+        "file_audit.json",
+        "recent_log.json",
+        "usage_stats.json",
+        "journal.txt",
+        "cmdline.txt",
+        "files/etc/modprobe.d/supergfxd.conf",
+        "files/etc/systemd/system/supergfxd.service.d/50-supergfxd-ordering.conf",
+    ];
+
+    fn mock_controller() -> CtrlGraphics {
+        CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        )
+    }
+
+    /// Unpack a `.tar.gz` made of stored deflate blocks, as written by the bundle
+    fn untar_gz(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&data[..3], &[0x1f, 0x8b, 8]);
+        let mut tar = Vec::new();
+        let mut pos = 10;
+        loop {
+            let last = data[pos] & 1 == 1;
+            assert_eq!(data[pos] & 0b110, 0, "only stored blocks are written");
+            let len = u16::from_le_bytes([data[pos + 1], data[pos + 2]]) as usize;
+            let nlen = u16::from_le_bytes([data[pos + 3], data[pos + 4]]) as usize;
+            assert_eq!(len, !nlen & 0xffff);
+            tar.extend_from_slice(&data[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break;
+            }
+        }
+        let crc = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+        assert_eq!(crc, crc32(&tar));
+        assert_eq!(size as usize, tar.len());
+
+        let mut members = Vec::new();
+        let mut pos = 0;
+        while tar[pos] != 0 {
+            let header = &tar[pos..pos + 512];
+            let name = String::from_utf8_lossy(&header[..100])
+                .trim_end_matches('\0')
+                .to_string();
+            let size = usize::from_str_radix(
+                String::from_utf8_lossy(&header[124..135]).trim_end_matches('\0'),
+                8,
+            )
+            .unwrap();
+            let checksum = usize::from_str_radix(
+                String::from_utf8_lossy(&header[148..154]).trim_end_matches('\0'),
+                8,
+            )
+            .unwrap();
+            let sum: usize = header[..148]
+                .iter()
+                .chain(&[b' '; 8])
+                .chain(&header[156..])
+                .map(|b| *b as usize)
+                .sum();
+            assert_eq!(checksum, sum, "{name}");
+            pos += 512;
+            members.push((name, tar[pos..pos + size].to_vec()));
+            pos += (size + 511) / 512 * 512;
+        }
+        members
+    }
+
+    fn check_members(members: &[(String, Vec<u8>)]) {
+        for expected in EXPECTED_MEMBERS {
+            assert!(
+                members.iter().any(|(name, _)| name == expected),
+                "{expected} missing"
+            );
+        }
+        for (name, data) in members {
+            if name.ends_with(".json") {
+                serde_json::from_slice::<serde_json::Value>(data)
+                    .unwrap_or_else(|e| panic!("{name}: {e}"));
+            }
+        }
+        let manifest: serde_json::Value = serde_json::from_slice(
+            &members
+                .iter()
+                .find(|(name, _)| name == "manifest.json")
+                .unwrap()
+                .1,
+        )
+        .unwrap();
+        assert_eq!(manifest["supergfxd_version"], crate::VERSION);
+        // Lists every other member
+        assert_eq!(
+            manifest["members"].as_array().unwrap().len(),
+            members.len() - 1
+        );
+    }
+
+    #[tokio::test]
+    async fn bundle_contains_all_sections() {
+        let dir =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-bundle", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("plain")).unwrap();
+        let ctrl = mock_controller();
+
+        // Plain directory
+        ctrl.write_support_bundle(&dir.join("plain")).await.unwrap();
+        let mut members = Vec::new();
+        for name in EXPECTED_MEMBERS {
+            members.push((
+                name.to_string(),
+                fs::read(dir.join("plain").join(name)).unwrap(),
+            ));
+        }
+        check_members(&members);
+
+        // Archive
+        let archive = dir.join("bundle.tar.gz");
+        ctrl.write_support_bundle(&archive).await.unwrap();
+        check_members(&untar_gz(&fs::read(&archive).unwrap()));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn empty_and_large_archives() {
+        // Larger than one stored block
+        let big = BundleMember {
+            name: "big.txt".to_string(),
+            data: (0..200_000u32).map(|i| (i % 251) as u8).collect(),
+            placeholder: None,
+        };
+        let bundle = SupportBundle {
+            members: vec![big.clone()],
+        };
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-bundle-big.tar.gz",
+            std::process::id()
+        ));
+        bundle.write(&dir).unwrap();
+        let members = untar_gz(&fs::read(&dir).unwrap());
+        assert_eq!(members, [(big.name, big.data)]);
+        fs::remove_file(&dir).ok();
+
+        assert!(untar_gz(&crate::bundle::gzip_stored(&crate::bundle::tar(&[]))).is_empty());
+    }
+
+    #[test]
+    fn failed_section_is_a_placeholder() {
+        let mut bundle = SupportBundle::default();
+        bundle.add_json("broken.json", Err("no such thing".to_string()));
+        bundle.add_text("broken.txt", Err("no such thing".to_string()));
+        assert_eq!(bundle.members.len(), 2);
+        let json: serde_json::Value = serde_json::from_slice(&bundle.members[0].data).unwrap();
+        assert_eq!(json["unavailable"], "no such thing");
+        assert!(bundle
+            .members
+            .iter()
+            .all(|m| m.placeholder.as_deref() == Some("no such thing")));
+    }
+
+    #[test]
+    fn config_redaction_keeps_known_keys() {
+        let config = serde_json::to_value(GfxConfig::new(Default::default())).unwrap();
+        assert_eq!(redact_config(config.clone()), config);
+    }
+}
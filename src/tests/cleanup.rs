@@ -0,0 +1,219 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        cleanup::{apply_cleanup, locate_artifacts, render_artifacts, Ownership},
+        error::GfxError,
+        special_asus::ASUS_MODULES_LOAD,
+        unit_dropins::{UnitReload, DROPIN_HEADER},
+    };
+
+    fn test_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-cleanup-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(root: &Path, path: &str, content: &[u8]) -> PathBuf {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[derive(Default)]
+    struct CountingReload(Cell<u32>);
+
+    impl UnitReload for CountingReload {
+        fn daemon_reload(&self) -> Result<(), GfxError> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn ownership(root: &Path, path: &str) -> Option<Ownership> {
+        locate_artifacts(root)
+            .into_iter()
+            .find(|artifact| artifact.path == root.join(path))
+            .map(|artifact| artifact.ownership)
+    }
+
+    #[test]
+    fn removes_what_supergfxd_wrote() {
+        let root = test_root("owned");
+        assert!(locate_artifacts(&root).is_empty());
+
+        write(
+            &root,
+            "etc/modprobe.d/supergfxd.conf",
+            b"# Automatically generated by supergfxd\nblacklist nouveau\n",
+        );
+        write(&root, "etc/modules-load.d/asus.conf", ASUS_MODULES_LOAD);
+        write(
+            &root,
+            "run/udev/rules.d/61-supergfxd-switcheroo.rules",
+            b"# Written by supergfxd, the dGPU is unusable in this mode\n",
+        );
+        write(
+            &root,
+            "etc/systemd/system/supergfxd.service.d/50-supergfxd-ordering.conf",
+            format!("{DROPIN_HEADER}\n[Unit]\n").as_bytes(),
+        );
+        write(&root, "etc/supergfxd/config.json", b"{}");
+        write(&root, "var/lib/supergfxd/audit.log", b"");
+        write(&root, "var/lib/supergfxd/vfio_functions", b"");
+        write(&root, "run/supergfxd/staged/supergfxd.conf", b"");
+
+        assert_eq!(
+            ownership(&root, "etc/modprobe.d/supergfxd.conf"),
+            Some(Ownership::Header)
+        );
+        assert_eq!(
+            ownership(&root, "etc/modules-load.d/asus.conf"),
+            Some(Ownership::Content)
+        );
+        assert_eq!(
+            ownership(&root, "run/supergfxd/staged/supergfxd.conf"),
+            Some(Ownership::OwnDirectory)
+        );
+        let artifacts = locate_artifacts(&root);
+        assert_eq!(artifacts.len(), 8);
+        // Listing changes nothing
+        assert!(render_artifacts(&artifacts).contains(&format!(
+            "remove   {}",
+            root.join("etc/supergfxd/config.json").display()
+        )));
+        assert_eq!(locate_artifacts(&root), artifacts);
+
+        let reload = CountingReload::default();
+        let report = apply_cleanup(&root, &artifacts, &reload);
+        assert_eq!(report.removed.len(), 8);
+        assert!(report.kept.is_empty() && report.failed.is_empty());
+        assert_eq!(reload.0.get(), 1);
+        assert!(locate_artifacts(&root).is_empty());
+        for dir in [
+            "etc/supergfxd",
+            "var/lib/supergfxd",
+            "run/supergfxd",
+            "etc/systemd/system/supergfxd.service.d",
+        ] {
+            assert!(!root.join(dir).exists(), "{dir}");
+        }
+        // Shared directories are left
+        assert!(root.join("etc/modprobe.d").exists());
+    }
+
+    #[test]
+    fn foreign_files_are_never_removed() {
+        let root = test_root("foreign");
+        let modprobe = write(
+            &root,
+            "etc/modprobe.d/supergfxd.conf",
+            b"options nvidia x=1\n",
+        );
+        let mut asus = ASUS_MODULES_LOAD.to_vec();
+        asus.extend_from_slice(b"asus-armoury\n");
+        let asus = write(&root, "etc/modules-load.d/asus.conf", &asus);
+        let dropin = write(
+            &root,
+            "etc/systemd/system/supergfxd.service.d/50-supergfxd-ordering.conf",
+            b"[Unit]\nAfter=local.service\n",
+        );
+
+        let artifacts = locate_artifacts(&root);
+        assert_eq!(artifacts.len(), 3);
+        assert!(artifacts
+            .iter()
+            .all(|artifact| artifact.ownership == Ownership::Foreign));
+        assert!(render_artifacts(&artifacts).contains("keep     "));
+
+        let reload = CountingReload::default();
+        let report = apply_cleanup(&root, &artifacts, &reload);
+        assert!(report.removed.is_empty());
+        assert_eq!(report.kept.len(), 3);
+        assert_eq!(reload.0.get(), 0);
+        for path in [modprobe, asus, dropin] {
+            assert!(path.exists(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn backups_are_put_back() {
+        let root = test_root("backups");
+        write(
+            &root,
+            "usr/share/vulkan/icd.d/nvidia_icd.json_inactive",
+            b"{\"icd\": 1}",
+        );
+        write(
+            &root,
+            "etc/supergfxd.conf.migrated",
+            b"{\"mode\": \"Hybrid\"}",
+        );
+        let backup = "var/lib/supergfxd/import-backup/1700000000";
+        write(
+            &root,
+            &format!("{backup}/1-xorg.conf"),
+            b"Section \"Device\"\n",
+        );
+        write(
+            &root,
+            &format!("{backup}/2-nvidia.conf"),
+            b"options nvidia-drm modeset=1\n",
+        );
+        write(
+            &root,
+            &format!("{backup}/manifest"),
+            b"config\t/etc/supergfxd/config.json\t-\nmoved\t/etc/X11/xorg.conf\t1-xorg.conf\nmoved\t/etc/modprobe.d/nvidia.conf\t2-nvidia.conf\n",
+        );
+        // Created again since the import, so the old one isn't put back over it
+        write(
+            &root,
+            "etc/modprobe.d/nvidia.conf",
+            b"options nvidia NVreg=1\n",
+        );
+
+        let artifacts = locate_artifacts(&root);
+        let backups: Vec<_> = artifacts
+            .iter()
+            .filter(|artifact| artifact.ownership == Ownership::Backup)
+            .collect();
+        assert_eq!(backups.len(), 4);
+        assert_eq!(
+            backups[2].restore_to.as_deref(),
+            Some(root.join("etc/X11/xorg.conf").as_path())
+        );
+
+        let report = apply_cleanup(&root, &artifacts, &CountingReload::default());
+        assert_eq!(report.restored.len(), 3);
+        assert_eq!(
+            fs::read(root.join("usr/share/vulkan/icd.d/nvidia_icd.json")).unwrap(),
+            b"{\"icd\": 1}"
+        );
+        assert_eq!(
+            fs::read(root.join("etc/supergfxd.conf")).unwrap(),
+            b"{\"mode\": \"Hybrid\"}"
+        );
+        assert_eq!(
+            fs::read(root.join("etc/X11/xorg.conf")).unwrap(),
+            b"Section \"Device\"\n"
+        );
+        assert_eq!(
+            fs::read(root.join("etc/modprobe.d/nvidia.conf")).unwrap(),
+            b"options nvidia NVreg=1\n"
+        );
+        // The backup which couldn't be put back is kept with the manifest
+        assert_eq!(report.kept.len(), 1);
+        assert!(root.join(backup).join("2-nvidia.conf").exists());
+        assert!(!root.join(backup).join("manifest").exists());
+    }
+}
@@ -0,0 +1,277 @@
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, sync::Arc};
+
+    use zbus::{message::Message, names::ErrorName};
+
+    use crate::{
+        cli_error::{hint, CliError, DaemonUnit, ErrorClass},
+        error::{parse_daemon_message, GfxError},
+        pci_device::GfxMode,
+    };
+
+    /// A method error reply named `org.freedesktop.DBus.Error.<name>`, as a proxy returns it
+    fn reply(name: &str, text: &str) -> GfxError {
+        let name = format!("org.freedesktop.DBus.Error.{name}");
+        let msg = Message::method_call("/org/supergfxctl/Gfx", "Mode")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        GfxError::Zbus(zbus::Error::MethodError(
+            ErrorName::try_from(name.as_str()).unwrap().into(),
+            Some(text.to_string()),
+            msg,
+        ))
+    }
+
+    fn unit(load: &str, active: &str, file: &str) -> Option<DaemonUnit> {
+        Some(DaemonUnit {
+            load_state: load.to_string(),
+            active_state: active.to_string(),
+            unit_file_state: file.to_string(),
+        })
+    }
+
+    #[test]
+    fn daemon_message_parsed() {
+        let text = GfxError::ModeLocked(GfxMode::Hybrid).daemon_message();
+        assert_eq!(
+            text,
+            "GFX fail: [mode-locked] The graphics mode is locked to Hybrid by the administrator"
+        );
+        assert_eq!(
+            parse_daemon_message(&text),
+            (
+                Some("mode-locked"),
+                "The graphics mode is locked to Hybrid by the administrator"
+            )
+        );
+        // From an older daemon, or not from supergfxd at all
+        assert_eq!(
+            parse_daemon_message("GFX fail: There is no mode switch in progress"),
+            (None, "There is no mode switch in progress")
+        );
+        assert_eq!(
+            parse_daemon_message("Only root can change the mode lock"),
+            (None, "Only root can change the mode lock")
+        );
+        assert_eq!(
+            parse_daemon_message("GFX fail: [0000:01:00.0] gone"),
+            (None, "[0000:01:00.0] gone")
+        );
+    }
+
+    #[test]
+    fn every_class_from_the_daemon() {
+        let cases = [
+            (
+                reply("Failed", &GfxError::PciNotSettled.daemon_message()),
+                ErrorClass::Failed,
+                Some("pci-not-settled"),
+            ),
+            (
+                reply(
+                    "InvalidArgs",
+                    &GfxError::DisplayManagerUnits("at least one unit is needed".to_string())
+                        .daemon_message(),
+                ),
+                ErrorClass::InvalidInput,
+                Some("display-manager-units"),
+            ),
+            // The code decides over the name of the error
+            (
+                reply(
+                    "Failed",
+                    &GfxError::ModeLocked(GfxMode::Integrated).daemon_message(),
+                ),
+                ErrorClass::PermissionDenied,
+                Some("mode-locked"),
+            ),
+            (
+                reply("AccessDenied", "Only root can change the mode lock"),
+                ErrorClass::PermissionDenied,
+                None,
+            ),
+            (
+                reply(
+                    "IOError",
+                    &GfxError::ConfigNotPersisted("read-only file system".to_string())
+                        .daemon_message(),
+                ),
+                ErrorClass::NotPersisted,
+                Some("config-not-persisted"),
+            ),
+            (
+                reply("UnknownMethod", "Unknown method 'PreviewConfigChange'"),
+                ErrorClass::VersionMismatch,
+                None,
+            ),
+            (
+                reply(
+                    "ServiceUnknown",
+                    "The name org.supergfxctl.Daemon was not provided by any .service files",
+                ),
+                ErrorClass::NotRunning,
+                None,
+            ),
+        ];
+        for (err, class, code) in cases {
+            let cli_error = CliError::from_error(&err);
+            assert_eq!(cli_error.class, class, "{err}");
+            assert_eq!(cli_error.code.as_deref(), code, "{err}");
+            assert!(cli_error.dbus_name.is_some());
+            assert_eq!(cli_error.chain[0], err.to_string());
+        }
+
+        let cli_error =
+            CliError::from_error(&reply("Failed", &GfxError::PciNotSettled.daemon_message()));
+        assert_eq!(
+            cli_error.message,
+            "The dGPU did not settle on the PCI bus after a rescan, another tool may be removing or rescanning PCI devices"
+        );
+        // The same through the fdo error
+        let fdo = GfxError::ZbusFdo(zbus::fdo::Error::AccessDenied(
+            GfxError::CancelNotAllowed("alice".to_string()).daemon_message(),
+        ));
+        let cli_error = CliError::from_error(&fdo);
+        assert_eq!(cli_error.class, ErrorClass::PermissionDenied);
+        assert_eq!(cli_error.code.as_deref(), Some("cancel-not-allowed"));
+        assert_eq!(
+            cli_error.dbus_name.as_deref(),
+            Some("org.freedesktop.DBus.Error.AccessDenied")
+        );
+    }
+
+    #[test]
+    fn local_errors() {
+        let denied = GfxError::Zbus(zbus::Error::InputOutput(Arc::new(std::io::Error::from(
+            std::io::ErrorKind::PermissionDenied,
+        ))));
+        assert_eq!(
+            CliError::from_error(&denied).class,
+            ErrorClass::PermissionDenied
+        );
+        let no_bus = GfxError::Zbus(zbus::Error::InputOutput(Arc::new(std::io::Error::from(
+            std::io::ErrorKind::NotFound,
+        ))));
+        assert_eq!(CliError::from_error(&no_bus).class, ErrorClass::Failed);
+        assert_eq!(
+            CliError::from_error(&GfxError::Zbus(zbus::Error::InvalidReply)).class,
+            ErrorClass::VersionMismatch
+        );
+
+        let import = CliError::from_error(&GfxError::Import("nothing to import".to_string()));
+        assert_eq!(import.class, ErrorClass::Failed);
+        assert_eq!(import.dbus_name, None);
+        assert_eq!(import.message, "Import: nothing to import");
+        // Nothing for the journal of supergfxd to say
+        assert!(!import.render(false).contains("journalctl"));
+        assert_eq!(
+            CliError::from_error(&GfxError::ParseMode).class,
+            ErrorClass::InvalidInput
+        );
+    }
+
+    #[test]
+    fn unit_state_says_why_unreachable() {
+        let unreachable = || {
+            CliError::from_error(&reply(
+                "ServiceUnknown",
+                "The name org.supergfxctl.Daemon was not provided by any .service files",
+            ))
+        };
+        let not_installed = unreachable().with_unit(|| unit("not-found", "inactive", ""));
+        assert_eq!(not_installed.class, ErrorClass::NotInstalled);
+        assert!(not_installed.message.contains("not installed"));
+
+        let cases = [
+            (unit("loaded", "inactive", "disabled"), "not enabled"),
+            (unit("loaded", "inactive", "enabled"), "not running"),
+            (unit("loaded", "failed", "enabled"), "supergfxd failed"),
+            (unit("loaded", "active", "enabled"), "isn't on the bus"),
+        ];
+        for (state, expected) in cases {
+            let cli_error = unreachable().with_unit(move || state);
+            assert_eq!(cli_error.class, ErrorClass::NotRunning);
+            assert!(
+                cli_error.message.contains(expected),
+                "{}",
+                cli_error.message
+            );
+            assert!(cli_error
+                .chain
+                .last()
+                .unwrap()
+                .starts_with("supergfxd.service: "));
+        }
+        // Systemd couldn't be asked
+        assert_eq!(unreachable().with_unit(|| None), unreachable());
+
+        // The unit isn't looked at for other errors
+        let asked = Cell::new(false);
+        CliError::from_error(&reply("Failed", "GFX fail: [hook] Switch hook: exit 1")).with_unit(
+            || {
+                asked.set(true);
+                None
+            },
+        );
+        assert!(!asked.get());
+    }
+
+    #[test]
+    fn unit_show_parsed() {
+        assert_eq!(
+            DaemonUnit::parse("LoadState=loaded\nActiveState=active\nUnitFileState=enabled\n"),
+            unit("loaded", "active", "enabled").unwrap()
+        );
+        assert_eq!(
+            DaemonUnit::parse("LoadState=not-found\nActiveState=inactive\nUnitFileState=\n"),
+            unit("not-found", "inactive", "").unwrap()
+        );
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let classes = [
+            ErrorClass::Failed,
+            ErrorClass::InvalidInput,
+            ErrorClass::PermissionDenied,
+            ErrorClass::NotInstalled,
+            ErrorClass::NotRunning,
+            ErrorClass::VersionMismatch,
+            ErrorClass::NotPersisted,
+        ];
+        let mut codes: Vec<i32> = classes.iter().map(|class| class.exit_code()).collect();
+        assert_eq!(codes[0], 1);
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), classes.len());
+        assert!(!codes.contains(&0));
+    }
+
+    #[test]
+    fn rendered() {
+        let cli_error = CliError::from_error(&reply(
+            "Failed",
+            &GfxError::ModeLocked(GfxMode::Hybrid).daemon_message(),
+        ));
+        let short = cli_error.render(false);
+        assert_eq!(
+            short,
+            "\x1b[0;31mError: The graphics mode is locked to Hybrid by the administrator\x1b[0m [mode-locked]\n"
+        );
+        let verbose = cli_error.render(true);
+        assert!(verbose.starts_with(&short));
+        assert!(verbose.contains("org.freedesktop.DBus.Error.Failed"));
+        assert!(verbose.contains("PermissionDenied, exit code 3"));
+        assert!(verbose.contains(hint("mode-locked").unwrap()));
+
+        // A failure in supergfxd points to its journal and the bundle
+        let failed =
+            CliError::from_error(&reply("Failed", &GfxError::PciNotSettled.daemon_message()))
+                .render(false);
+        assert_eq!(failed.lines().count(), 2);
+        assert!(failed.lines().nth(1).unwrap().contains("--bundle"));
+        assert!(hint("no-such-code").is_none());
+    }
+}
@@ -0,0 +1,98 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{
+        actions::UserActionRequired,
+        cli_error::CliError,
+        cli_json::{error_json, mode_value, JsonOutput},
+        controller::{GfxState, PendingInfo},
+        error::GfxError,
+        pci_device::{GfxMode, GfxPower, GfxVendor},
+    };
+
+    fn parsed(out: &JsonOutput) -> Value {
+        let text = out.render();
+        assert!(!text.contains('\n'));
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn modes_by_name_and_none_as_null() {
+        assert_eq!(mode_value(GfxMode::Hybrid), json!("Hybrid"));
+        assert_eq!(mode_value(GfxMode::AsusMuxDgpu), json!("AsusMuxDgpu"));
+        assert_eq!(mode_value(GfxMode::None), Value::Null);
+    }
+
+    #[test]
+    fn queries_in_one_object() {
+        let mut out = JsonOutput::default();
+        out.state(&GfxState {
+            mode: GfxMode::Hybrid,
+            power: GfxPower::Suspended,
+            pending_mode: GfxMode::None,
+            pending_action: UserActionRequired::Nothing,
+            vendor: GfxVendor::Nvidia,
+            supported: vec![GfxMode::Hybrid, GfxMode::Integrated],
+        });
+        out.insert_mode("persistent_mode", GfxMode::Integrated);
+        out.insert("mode_locked", false);
+        assert_eq!(
+            parsed(&out),
+            json!({
+                "mode": "Hybrid",
+                "power": "Suspended",
+                "pending_mode": null,
+                "pending_action": "Nothing",
+                "vendor": "Nvidia",
+                "supported": ["Hybrid", "Integrated"],
+                "persistent_mode": "Integrated",
+                "mode_locked": false,
+            })
+        );
+
+        // A later query of the same field replaces it
+        out.insert_mode("pending_mode", GfxMode::Integrated);
+        assert_eq!(parsed(&out)["pending_mode"], json!("Integrated"));
+    }
+
+    #[test]
+    fn pending_info_object() {
+        let mut out = JsonOutput::default();
+        out.pending_info(&PendingInfo::default());
+        let value = parsed(&out);
+        assert_eq!(value["pending"]["mode"], Value::Null);
+        assert_eq!(value["pending"]["uid"], json!(u32::MAX));
+
+        out.pending_info(&PendingInfo {
+            mode: GfxMode::Integrated,
+            action: UserActionRequired::Logout,
+            requester: ":1.42".to_string(),
+            uid: 1000,
+            ..Default::default()
+        });
+        let value = parsed(&out);
+        assert_eq!(value["pending"]["mode"], json!("Integrated"));
+        assert_eq!(value["pending"]["action"], json!("Logout"));
+        assert_eq!(value["pending"]["requester"], json!(":1.42"));
+    }
+
+    #[test]
+    fn errors_as_json() {
+        let err = CliError::from_error(&GfxError::ModeLocked(GfxMode::Hybrid));
+        let value: Value = serde_json::from_str(&error_json(&err)).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "error": "The graphics mode is locked to Hybrid by the administrator",
+                "code": "mode-locked",
+                "class": "PermissionDenied",
+                "exit_code": 3,
+            })
+        );
+        let err = CliError::from_error(&GfxError::Zbus(zbus::Error::InvalidReply));
+        let value: Value = serde_json::from_str(&error_json(&err)).unwrap();
+        assert_eq!(value["code"], Value::Null);
+        assert_ne!(value["exit_code"], json!(0));
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{
+        completions::{
+            check_mode_supported, completion_script, hide_options, list_modes, parse_usage,
+            CliOption, Shell,
+        },
+        pci_device::GfxMode,
+        timeout::with_timeout,
+    };
+
+    const USAGE: &str = "Optional arguments:
+  -m, --mode           Set graphics mode
+  --audit N            Show the last N changes
+  -g, --get            Get the current mode
+  --bundle PATH        Write a support bundle to PATH
+  --a-very-long-option-name-here
+                       Wrapped help
+  --completions SHELL";
+
+    fn options() -> Vec<CliOption> {
+        parse_usage(USAGE, &["completions", "a-very-long-option-name-here"])
+    }
+
+    #[test]
+    fn usage_parsed() {
+        let options = parse_usage(USAGE, &["completions"]);
+        assert_eq!(options.len(), 5);
+        assert_eq!(
+            options[0],
+            CliOption {
+                short: Some('m'),
+                long: "mode".to_string(),
+                meta: None,
+                help: "Set graphics mode".to_string(),
+            }
+        );
+        assert_eq!(
+            options[1],
+            CliOption {
+                short: None,
+                long: "audit".to_string(),
+                meta: Some("N".to_string()),
+                help: "Show the last N changes".to_string(),
+            }
+        );
+        assert_eq!(options[4].long, "a-very-long-option-name-here");
+        assert_eq!(options[4].help, "Wrapped help");
+    }
+
+    #[test]
+    fn hidden_from_help() {
+        let help = hide_options(USAGE, &["completions"]);
+        assert!(!help.contains("--completions"));
+        assert!(help.contains("  --audit N            Show the last N changes\n"));
+        // Only whole names are hidden
+        assert!(hide_options(USAGE, &["aud"]).contains("--audit"));
+    }
+
+    #[test]
+    fn bash_completions() {
+        assert_eq!(
+            completion_script(Shell::Bash, "supergfxctl", &options()),
+            r#"# bash completion for supergfxctl, generated by `supergfxctl --completions bash`
+_supergfxctl() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "$prev" in
+        -m|--mode)
+            COMPREPLY=($(compgen -W "$(supergfxctl --list-modes 2>/dev/null)" -- "$cur"))
+            return
+            ;;
+        --audit)
+            return
+            ;;
+        --bundle)
+            COMPREPLY=($(compgen -f -- "$cur"))
+            return
+            ;;
+    esac
+    COMPREPLY=($(compgen -W "-m --mode --audit -g --get --bundle" -- "$cur"))
+}
+complete -F _supergfxctl supergfxctl
+"#
+        );
+    }
+
+    #[test]
+    fn zsh_completions() {
+        assert_eq!(
+            completion_script(Shell::Zsh, "supergfxctl", &options()),
+            r#"#compdef supergfxctl
+# zsh completion for supergfxctl, generated by `supergfxctl --completions zsh`
+_supergfxctl_modes() {
+    compadd -- ${(f)"$(supergfxctl --list-modes 2>/dev/null)"}
+}
+_supergfxctl() {
+    _arguments \
+        '(-m --mode)'{-m,--mode}'[Set graphics mode]:mode:_supergfxctl_modes' \
+        '--audit[Show the last N changes]:n: ' \
+        '(-g --get)'{-g,--get}'[Get the current mode]' \
+        '--bundle[Write a support bundle to PATH]:path:_files'
+}
+_supergfxctl "$@"
+"#
+        );
+    }
+
+    #[test]
+    fn fish_completions() {
+        assert_eq!(
+            completion_script(Shell::Fish, "supergfxctl", &options()),
+            "# fish completion for supergfxctl, generated by `supergfxctl --completions fish`
+complete -c supergfxctl -f
+complete -c supergfxctl -s m -l mode -x -a '(supergfxctl --list-modes 2>/dev/null)' -d 'Set graphics mode'
+complete -c supergfxctl -l audit -x -d 'Show the last N changes'
+complete -c supergfxctl -s g -l get -d 'Get the current mode'
+complete -c supergfxctl -l bundle -r -F -d 'Write a support bundle to PATH'
+"
+        );
+    }
+
+    #[test]
+    fn shell_names() {
+        assert_eq!("bash".parse::<Shell>().unwrap(), Shell::Bash);
+        assert_eq!("Zsh".parse::<Shell>().unwrap(), Shell::Zsh);
+        assert_eq!("fish".parse::<Shell>().unwrap(), Shell::Fish);
+        assert!("tcsh".parse::<Shell>().is_err());
+    }
+
+    #[test]
+    fn listed_modes() {
+        assert_eq!(
+            list_modes(Some(&[GfxMode::Hybrid, GfxMode::Integrated, GfxMode::None])),
+            "Hybrid\nIntegrated\n"
+        );
+        assert_eq!(
+            list_modes(None),
+            "Hybrid\nIntegrated\nNvidiaNoModeset\nVfio\nAsusEgpu\nAsusMuxDgpu\n"
+        );
+    }
+
+    #[test]
+    fn daemon_query_timeout() {
+        let supported = with_timeout(Duration::from_secs(5), || {
+            Some(vec![GfxMode::Hybrid, GfxMode::Vfio])
+        });
+        assert_eq!(list_modes(supported.as_deref()), "Hybrid\nVfio\n");
+
+        // The daemon not answering in time, or not at all, lists every mode
+        let slow = with_timeout(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_millis(500));
+            Some(vec![GfxMode::Hybrid])
+        });
+        assert_eq!(slow, None);
+        let offline: Option<Vec<GfxMode>> = with_timeout(Duration::from_secs(5), || None);
+        assert_eq!(list_modes(offline.as_deref()), list_modes(None));
+    }
+
+    #[test]
+    fn mode_validated() {
+        let supported = [GfxMode::Hybrid, GfxMode::Integrated];
+        assert!(check_mode_supported(GfxMode::Integrated, &supported).is_ok());
+        assert_eq!(
+            check_mode_supported(GfxMode::AsusEgpu, &supported)
+                .unwrap_err()
+                .to_string(),
+            "AsusEgpu is not supported on this system, the supported modes are: Hybrid, Integrated"
+        );
+    }
+}
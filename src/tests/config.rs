@@ -0,0 +1,683 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use crate::{
+        config::{
+            create_vfio_conf, read_known_functions, render_modprobe_conf,
+            validate_display_manager_units, validate_modprobe_extra_options, GfxConfig,
+        },
+        error::GfxError,
+        logout_switch::{LogoutPolicy, LogoutTimeoutAction},
+        pci_device::{Device, DiscreetGpu, GfxMode, GfxVendor, HotplugType, NvidiaDriver},
+        tests::test_dir,
+    };
+
+    fn write_config(path: &Path, mode: GfxMode) {
+        let config = GfxConfig {
+            mode,
+            ..GfxConfig::new(path.to_string_lossy().to_string())
+        };
+        fs::write(path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+    }
+
+    fn read_mode(path: &Path) -> GfxMode {
+        let config: GfxConfig = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        config.mode
+    }
+
+    #[test]
+    fn config_fresh_install() {
+        let dir = test_dir("fresh");
+        let path = dir.join("supergfxd/config.json");
+        let legacy = dir.join("supergfxd.conf");
+
+        let config = GfxConfig::load_or_migrate(
+            path.to_string_lossy().to_string(),
+            &legacy.to_string_lossy(),
+        );
+        assert_eq!(config.config_path, path.to_string_lossy());
+        assert_eq!(config.mode, GfxMode::Hybrid);
+        assert_eq!(read_mode(&path), GfxMode::Hybrid);
+        assert!(!legacy.exists());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_legacy_only_is_migrated() {
+        let dir = test_dir("legacy");
+        let path = dir.join("supergfxd/config.json");
+        let legacy = dir.join("supergfxd.conf");
+        write_config(&legacy, GfxMode::Integrated);
+
+        let config = GfxConfig::load_or_migrate(
+            path.to_string_lossy().to_string(),
+            &legacy.to_string_lossy(),
+        );
+        assert_eq!(config.config_path, path.to_string_lossy());
+        assert_eq!(config.mode, GfxMode::Integrated);
+        // The user's config was carried over and the old file kept as a backup
+        assert_eq!(read_mode(&path), GfxMode::Integrated);
+        assert!(!legacy.exists());
+        assert_eq!(
+            read_mode(&dir.join("supergfxd.conf.migrated")),
+            GfxMode::Integrated
+        );
+
+        // Later writes go to the new location only
+        let mut config = GfxConfig {
+            mode: GfxMode::Vfio,
+            ..config
+        };
+        config.write().unwrap();
+        assert_eq!(read_mode(&path), GfxMode::Vfio);
+        assert!(!legacy.exists());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_both_present_new_wins() {
+        let dir = test_dir("both");
+        let path = dir.join("supergfxd/config.json");
+        let legacy = dir.join("supergfxd.conf");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_config(&path, GfxMode::Integrated);
+        write_config(&legacy, GfxMode::Vfio);
+
+        let config = GfxConfig::load_or_migrate(
+            path.to_string_lossy().to_string(),
+            &legacy.to_string_lossy(),
+        );
+        assert_eq!(config.config_path, path.to_string_lossy());
+        assert_eq!(config.mode, GfxMode::Integrated);
+        // The legacy file is left alone
+        assert_eq!(read_mode(&legacy), GfxMode::Vfio);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_failed_migration_keeps_legacy() {
+        let dir = test_dir("failed");
+        // The new config dir can't be created as a file is in the way
+        fs::write(dir.join("supergfxd"), "").unwrap();
+        let path = dir.join("supergfxd/config.json");
+        let legacy = dir.join("supergfxd.conf");
+        write_config(&legacy, GfxMode::Integrated);
+
+        let config = GfxConfig::load_or_migrate(
+            path.to_string_lossy().to_string(),
+            &legacy.to_string_lossy(),
+        );
+        assert_eq!(config.config_path, legacy.to_string_lossy());
+        assert_eq!(config.mode, GfxMode::Integrated);
+        assert!(legacy.exists());
+        assert!(!dir.join("supergfxd.conf.migrated").exists());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    /// Load a config file holding `body`
+    fn load_body(name: &str, body: &str) -> (GfxConfig, PathBuf) {
+        let dir = test_dir(name);
+        let path = dir.join("supergfxd.conf");
+        fs::write(&path, body).unwrap();
+        (GfxConfig::load(path.to_string_lossy().to_string()), dir)
+    }
+
+    #[test]
+    fn config_4x_egpu_is_kept() {
+        let (config, dir) = load_body(
+            "egpu405",
+            r#"{
+  "mode": "Egpu",
+  "vfio_enable": true,
+  "vfio_save": false,
+  "compute_save": false,
+  "always_reboot": false,
+  "no_logind": true,
+  "logout_timeout_s": 90
+}"#,
+        );
+        assert_eq!(config.mode, GfxMode::AsusEgpu);
+        assert!(config.vfio_enable);
+        assert!(config.no_logind);
+        assert_eq!(config.logout_timeout_s, 90);
+        // Upgrades keep dropping a switch when the logout wait times out
+        assert_eq!(config.logout_timeout_action, LogoutTimeoutAction::Fail);
+        // Written back with the current name
+        assert_eq!(read_mode(&dir.join("supergfxd.conf")), GfxMode::AsusEgpu);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_invalid_strings_are_dropped() {
+        let written = GfxConfig {
+            ignored_functions: ["0000:01:00.1", ".2", "01:00.3\nexit", "all"]
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect(),
+            hook_command_pre_switch: Some("notify-send switching\nreboot".to_string()),
+            hook_command_post_switch: Some("notify-send switched".to_string()),
+            ..GfxConfig::new(String::new())
+        };
+        let (config, dir) = load_body(
+            "invalid_strings",
+            &serde_json::to_string_pretty(&written).unwrap(),
+        );
+        assert_eq!(config.ignored_functions, vec!["0000:01:00.1", ".2"]);
+        assert_eq!(config.hook_command_pre_switch, None);
+        assert_eq!(
+            config.hook_command_post_switch.as_deref(),
+            Some("notify-send switched")
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_50_egpu_is_kept() {
+        let (config, dir) = load_body(
+            "egpu500",
+            r#"{"mode":"Egpu","vfio_enable":false,"vfio_save":false,"compute_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"Asus"}"#,
+        );
+        assert_eq!(config.mode, GfxMode::AsusEgpu);
+        assert_eq!(config.hotplug_type, HotplugType::Asus);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_logout_timeout_action() {
+        let (config, dir) = load_body(
+            "logout-timeout-action",
+            r#"{"mode":"Hybrid","vfio_enable":false,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":60,"hotplug_type":"None","logout_timeout_action":"ConvertToDeferred"}"#,
+        );
+        assert_eq!(
+            config.logout_policy(),
+            LogoutPolicy {
+                timeout_s: 60,
+                action: LogoutTimeoutAction::ConvertToDeferred,
+            }
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_legacy_mode_names() {
+        for (name, body, mode) in [
+            (
+                "nvidia300",
+                r#"{"gfx_mode":"Nvidia","gfx_managed":true,"gfx_vfio_enable":true}"#,
+                GfxMode::Hybrid,
+            ),
+            (
+                "dedicated405",
+                r#"{"mode":"Dedicated","vfio_enable":true,"vfio_save":false,"compute_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180}"#,
+                GfxMode::Hybrid,
+            ),
+            (
+                "compute402",
+                r#"{"mode":"Compute","vfio_enable":true,"vfio_save":false,"compute_save":true,"always_reboot":false,"no_logind":false,"logout_timeout_s":180}"#,
+                GfxMode::Hybrid,
+            ),
+        ] {
+            let (config, dir) = load_body(name, body);
+            assert_eq!(config.mode, mode, "{name}");
+            assert!(config.vfio_enable, "{name}: settings were lost");
+            fs::remove_dir_all(dir).ok();
+        }
+    }
+
+    #[test]
+    fn config_unknown_mode_is_recreated() {
+        let (config, dir) = load_body(
+            "unknown",
+            r#"{"mode":"Bogus","vfio_enable":true,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"None"}"#,
+        );
+        assert_eq!(config.mode, GfxMode::Hybrid);
+        assert!(!config.vfio_enable);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_invalid_disabled_actions_are_dropped() {
+        let (config, dir) = load_body(
+            "disabled_actions",
+            r#"{"mode":"Hybrid","vfio_enable":true,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"None","disabled_actions":["WriteModprobeConf"]}"#,
+        );
+        assert!(config.disabled_actions.is_empty());
+        assert!(config.vfio_enable);
+
+        let (config, _) = load_body(
+            "disabled_actions",
+            r#"{"mode":"Hybrid","vfio_enable":true,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"None","disabled_actions":["StartDisplayManager"]}"#,
+        );
+        assert_eq!(config.disabled_actions, ["StartDisplayManager"]);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_status_poll_bounds() {
+        let body = |ms: &str| {
+            format!(
+                r#"{{"mode":"Hybrid","vfio_enable":false,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"None"{ms}}}"#
+            )
+        };
+        let (config, dir) = load_body("status_poll", &body(""));
+        assert_eq!(config.status_poll_ms, 1000);
+        assert_eq!(config.status_poll(), Duration::from_secs(1));
+
+        for (ms, poll) in [(250, 250), (5, 100), (60_000, 10_000)] {
+            let (config, _) =
+                load_body("status_poll", &body(&format!(r#","status_poll_ms":{ms}"#)));
+            // Kept as set, only what is used is bounded
+            assert_eq!(config.status_poll_ms, ms);
+            assert_eq!(config.status_poll(), Duration::from_millis(poll), "{ms}");
+        }
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_display_manager_units() {
+        let body = |units: &str| {
+            format!(
+                r#"{{"mode":"Hybrid","vfio_enable":false,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"None"{units}}}"#
+            )
+        };
+        let (config, dir) = load_body("display_managers", &body(""));
+        assert_eq!(config.display_manager_units, ["display-manager.service"]);
+        let (config, _) = load_body(
+            "display_managers",
+            &body(r#","display_manager_units":["greetd.service","seat1-greeter@seat1.service"]"#),
+        );
+        assert_eq!(
+            config.display_manager_units,
+            ["greetd.service", "seat1-greeter@seat1.service"]
+        );
+        // Put back to the default if unusable
+        for bad in [
+            r#"[]"#,
+            r#"["--now"]"#,
+            r#"["greetd"]"#,
+            r#"["a b.service"]"#,
+        ] {
+            let (config, _) = load_body(
+                "display_managers",
+                &body(&format!(r#","display_manager_units":{bad}"#)),
+            );
+            assert_eq!(
+                config.display_manager_units,
+                ["display-manager.service"],
+                "{bad}"
+            );
+        }
+        assert!(matches!(
+            validate_display_manager_units(&["-.service".to_string()]),
+            Err(GfxError::DisplayManagerUnits(_))
+        ));
+        assert!(validate_display_manager_units(&["graphical.target".to_string()]).is_ok());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    const BLACKLIST: &str = "# Automatically generated by supergfxd
+blacklist nouveau
+blacklist nvidia_drm
+blacklist nvidia_uvm
+blacklist nvidia_modeset
+blacklist nvidia
+blacklist nvidia-wmi-ec-backlight
+";
+
+    fn modprobe(mode: GfxMode, driver: NvidiaDriver) -> String {
+        modprobe_with(mode, driver, &[])
+    }
+
+    fn modprobe_with(mode: GfxMode, driver: NvidiaDriver, extra: &[String]) -> String {
+        let dgpu = DiscreetGpu::mock_devices(
+            GfxVendor::Nvidia,
+            0,
+            vec![
+                function("0000:01:00.0", "10DE:1F99", true),
+                function("0000:01:00.1", "10DE:10FA", false),
+            ],
+            0,
+        )
+        .with_nvidia_driver(driver);
+        String::from_utf8(
+            render_modprobe_conf(mode, &dgpu, &[], extra)
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn modprobe_conf_per_driver() {
+        let proprietary = "# Automatically generated by supergfxd
+blacklist nouveau
+alias nouveau off
+
+options nvidia-drm modeset=1
+
+options nvidia-wmi-ec-backlight force=1
+";
+        for driver in [NvidiaDriver::Proprietary, NvidiaDriver::None] {
+            for mode in [GfxMode::Hybrid, GfxMode::AsusEgpu, GfxMode::NvidiaNoModeset] {
+                assert_eq!(modprobe(mode, driver), proprietary, "{mode} {driver:?}");
+            }
+            assert!(modprobe(GfxMode::Integrated, driver).starts_with(BLACKLIST));
+            assert!(modprobe(GfxMode::Integrated, driver).contains("options nvidia-drm"));
+        }
+
+        // Under nouveau the dGPU stays usable, with nothing for the nvidia modules
+        for mode in [GfxMode::Hybrid, GfxMode::AsusEgpu, GfxMode::NvidiaNoModeset] {
+            assert_eq!(
+                modprobe(mode, NvidiaDriver::Nouveau),
+                "# Automatically generated by supergfxd\n",
+                "{mode}"
+            );
+        }
+        assert_eq!(
+            modprobe(GfxMode::Integrated, NvidiaDriver::Nouveau),
+            BLACKLIST
+        );
+        // Vfio keeps every driver off the dGPU either way
+        assert_eq!(
+            modprobe(GfxMode::Vfio, NvidiaDriver::Nouveau),
+            modprobe(GfxMode::Vfio, NvidiaDriver::Proprietary)
+        );
+        assert!(modprobe(GfxMode::Vfio, NvidiaDriver::Nouveau).starts_with(BLACKLIST));
+    }
+
+    #[test]
+    fn modprobe_conf_extra_options() {
+        let extra = [
+            "nvidia NVreg_PreserveVideoMemoryAllocations=1".to_string(),
+            "nvidia_drm  fbdev=1".to_string(),
+        ];
+        assert_eq!(
+            modprobe_with(GfxMode::Hybrid, NvidiaDriver::Proprietary, &extra),
+            "# Automatically generated by supergfxd
+blacklist nouveau
+alias nouveau off
+
+options nvidia-drm modeset=1
+
+options nvidia-wmi-ec-backlight force=1
+
+options nvidia NVreg_PreserveVideoMemoryAllocations=1
+options nvidia_drm fbdev=1
+"
+        );
+        // Added after whatever the mode writes, which is otherwise unchanged
+        for mode in [GfxMode::Hybrid, GfxMode::Integrated, GfxMode::Vfio] {
+            let base = modprobe(mode, NvidiaDriver::Proprietary);
+            assert_eq!(
+                modprobe_with(mode, NvidiaDriver::Proprietary, &extra[..1]),
+                format!("{base}\noptions nvidia NVreg_PreserveVideoMemoryAllocations=1\n"),
+                "{mode}"
+            );
+            assert_eq!(
+                modprobe_with(mode, NvidiaDriver::Proprietary, &[]),
+                base,
+                "{mode}"
+            );
+        }
+    }
+
+    #[test]
+    fn config_modprobe_extra_options() {
+        let body = |options: &str| {
+            format!(
+                r#"{{"mode":"Hybrid","vfio_enable":false,"vfio_save":false,"always_reboot":false,"no_logind":false,"logout_timeout_s":180,"hotplug_type":"None"{options}}}"#
+            )
+        };
+        let (config, dir) = load_body("modprobe_extra", &body(""));
+        assert!(config.modprobe_extra_options.is_empty());
+        let (config, _) = load_body(
+            "modprobe_extra",
+            &body(r#","modprobe_extra_options":{"Integrated":["nvidia-drm modeset=0"]}"#),
+        );
+        assert_eq!(
+            config.modprobe_extra(GfxMode::Integrated),
+            ["nvidia-drm modeset=0"]
+        );
+        assert!(config.modprobe_extra(GfxMode::Hybrid).is_empty());
+        // Dropped if any of it is unusable
+        for bad in [
+            r#"{"Compute":["nvidia NVreg_X=1"]}"#,
+            r#"{"Unknown":["nvidia NVreg_X=1"]}"#,
+            r#"{"Hybrid":["nvidia"]}"#,
+            r#"{"Hybrid":["../nvidia NVreg_X=1"]}"#,
+            r#"{"Hybrid":["/etc/nvidia NVreg_X=1"]}"#,
+            r#"{"Hybrid":["nvidia NVreg_X=1
+install nvidia /bin/sh"]}"#,
+            r#"{"Hybrid":["nvidia NVreg_X=1"],"Vfio":[" "]}"#,
+        ] {
+            let (config, _) = load_body(
+                "modprobe_extra",
+                &body(&format!(r#","modprobe_extra_options":{bad}"#)),
+            );
+            assert!(config.modprobe_extra_options.is_empty(), "{bad}");
+        }
+        let mut options = std::collections::HashMap::new();
+        options.insert("Hybrid".to_string(), vec!["nvidia\tNVreg_X=1".to_string()]);
+        assert!(matches!(
+            validate_modprobe_extra_options(&options),
+            Err(GfxError::ModprobeExtraOptions(_))
+        ));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    fn function(name: &str, id: &str, vga: bool) -> Device {
+        Device::mock(name, GfxVendor::Nvidia, vga).with_pci_id(id)
+    }
+
+    fn vfio_conf(
+        devices: &[Device],
+        known: &[&str],
+        multifunction: Option<bool>,
+    ) -> Result<String, String> {
+        let known: Vec<String> = known.iter().map(|name| name.to_string()).collect();
+        create_vfio_conf(devices, &known, multifunction)
+            .map(|conf| String::from_utf8(conf).unwrap())
+            .map_err(|err| match err {
+                GfxError::VfioIncomplete(detail) => detail,
+                err => panic!("unexpected error {err}"),
+            })
+    }
+
+    #[test]
+    fn vfio_conf_complete() {
+        let devices = [
+            function("0000:01:00.1", "10DE:10FA", false),
+            function("0000:01:00.0", "10DE:1F99", true),
+        ];
+        let expected = format!("{BLACKLIST}options vfio-pci ids=10de:10fa,10de:1f99\n");
+        assert_eq!(vfio_conf(&devices, &[], Some(true)), Ok(expected.clone()));
+        // The same set as last time, in any order
+        assert_eq!(
+            vfio_conf(&devices, &["0000:01:00.0", "0000:01:00.1"], Some(true)),
+            Ok(expected)
+        );
+        // A single function GPU
+        assert_eq!(
+            vfio_conf(&devices[1..], &[], Some(false)),
+            Ok(format!("{BLACKLIST}options vfio-pci ids=10de:1f99\n"))
+        );
+    }
+
+    #[test]
+    fn vfio_conf_partial() {
+        let vga = function("0000:01:00.0", "10DE:1F99", true);
+        let audio = function("0000:01:00.1", "10DE:10FA", false);
+        assert_eq!(
+            vfio_conf(
+                std::slice::from_ref(&vga),
+                &["0000:01:00.0", "0000:01:00.1"],
+                None
+            ),
+            Err("0000:01:00.1 found before but missing now".to_string())
+        );
+        assert_eq!(
+            vfio_conf(std::slice::from_ref(&vga), &[], Some(true)),
+            Err(
+                "0000:01:00.0 is a multifunction device but none of its other functions were found"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            vfio_conf(std::slice::from_ref(&audio), &[], None),
+            Err("the VGA function is missing, only 0000:01:00.1 found".to_string())
+        );
+        // Functions seen for another GPU don't count
+        assert!(vfio_conf(&[vga, audio], &["0000:02:00.0", "0000:02:00.1"], None).is_ok());
+        assert_eq!(
+            vfio_conf(&[function("0000:01:00.0", "", true)], &[], None),
+            Err("0000:01:00.0 has no PCI id".to_string())
+        );
+    }
+
+    #[test]
+    fn vfio_conf_duplicate_ids() {
+        let devices = [
+            function("0000:01:00.0", "10DE:1F99", true),
+            function("0000:01:00.1", "10DE:10FA", false),
+            function("0000:01:00.2", "10de:10fa", false),
+            function("0000:01:00.3", "10DE:1ADA", false),
+        ];
+        assert_eq!(
+            vfio_conf(&devices, &[], Some(true)),
+            Ok(format!(
+                "{BLACKLIST}options vfio-pci ids=10de:1f99,10de:10fa,10de:1ada\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn vfio_conf_empty() {
+        assert_eq!(
+            vfio_conf(&[], &["0000:01:00.0"], None),
+            Err("no dGPU functions were found".to_string())
+        );
+    }
+
+    #[test]
+    fn known_functions_file() {
+        let dir = test_dir("vfio-functions");
+        let path = dir.join("vfio_functions");
+        assert!(read_known_functions(&path).is_empty());
+        fs::write(&path, "0000:01:00.0\n0000:01:00.1\n\n").unwrap();
+        assert_eq!(
+            read_known_functions(&path),
+            ["0000:01:00.0", "0000:01:00.1"]
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn write_failure_is_kept_until_a_write_succeeds() {
+        let dir = test_dir("unwritable");
+        // The directory is missing, so nothing can be written
+        let mut config = GfxConfig::new(
+            dir.join("missing/config.json")
+                .to_string_lossy()
+                .to_string(),
+        );
+        let err = config.write().unwrap_err();
+        assert!(matches!(err, GfxError::ConfigNotPersisted(_)), "{err:?}");
+        assert!(config.write_error.is_some());
+
+        // The mode is still switched to
+        assert!(matches!(
+            config.set_switched_mode(GfxMode::Integrated),
+            Err(GfxError::ConfigNotPersisted(_))
+        ));
+        assert_eq!(config.mode, GfxMode::Integrated);
+        // A temporary mode isn't written
+        config.set_switched_mode(GfxMode::Vfio).unwrap();
+        assert!(config.write_error.is_some());
+
+        config.config_path = dir.join("config.json").to_string_lossy().to_string();
+        config.write().unwrap();
+        assert_eq!(config.write_error, None);
+        assert_eq!(read_mode(&dir.join("config.json")), GfxMode::Integrated);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn config_without_path_is_not_written() {
+        let mut config = GfxConfig::new(Default::default());
+        assert!(matches!(
+            config.write(),
+            Err(GfxError::ConfigNotPersisted(_))
+        ));
+        assert!(!Path::new(".tmp").exists());
+    }
+
+    /// The config at `path` as supergfxd loads it when it starts, keeping the temporary mode
+    /// at `tmp_path`
+    fn start_daemon(path: &Path, tmp_path: &Path) -> GfxConfig {
+        let mut config = GfxConfig::load(path.to_string_lossy().to_string());
+        config.keep_tmp_mode_at(tmp_path);
+        config
+    }
+
+    #[test]
+    fn tmp_mode_outlasts_a_restart_not_a_reboot() {
+        let dir = test_dir("tmp-mode");
+        let path = dir.join("config.json");
+        // As /run/supergfxd, which doesn't exist before supergfxd first runs
+        let tmp_path = dir.join("run/tmp_mode");
+        write_config(&path, GfxMode::Integrated);
+
+        let mut config = start_daemon(&path, &tmp_path);
+        assert_eq!(config.tmp_mode, None);
+        config.set_temporary_mode(GfxMode::Hybrid).unwrap();
+        assert_eq!(fs::read_to_string(&tmp_path).unwrap(), "Hybrid");
+        assert_eq!(read_mode(&path), GfxMode::Integrated);
+
+        // A restart of supergfxd stays in the temporary mode
+        let restarted = start_daemon(&path, &tmp_path);
+        assert_eq!(restarted.effective_mode(), GfxMode::Hybrid);
+        assert_eq!(restarted.mode, GfxMode::Integrated);
+
+        // /run is empty after a reboot, which is back in the persisted mode
+        fs::remove_dir_all(dir.join("run")).unwrap();
+        let rebooted = start_daemon(&path, &tmp_path);
+        assert_eq!(rebooted.tmp_mode, None);
+        assert_eq!(rebooted.effective_mode(), GfxMode::Integrated);
+
+        // Vfio without vfio_save uses the same, a persisted mode clears it
+        let mut config = start_daemon(&path, &tmp_path);
+        config.set_switched_mode(GfxMode::Vfio).unwrap();
+        assert_eq!(fs::read_to_string(&tmp_path).unwrap(), "Vfio");
+        config.set_switched_mode(GfxMode::Hybrid).unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(
+            start_daemon(&path, &tmp_path).effective_mode(),
+            GfxMode::Hybrid
+        );
+
+        // Anything else there is ignored
+        fs::write(&tmp_path, "Quantum").unwrap();
+        assert_eq!(start_daemon(&path, &tmp_path).tmp_mode, None);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn boot_correction_keeps_tmp_mode_temporary() {
+        let mut config = GfxConfig {
+            mode: GfxMode::Integrated,
+            ..GfxConfig::new(String::new())
+        };
+        config.correct_effective_mode(GfxMode::Hybrid);
+        assert_eq!(config.mode, GfxMode::Hybrid);
+
+        config.tmp_mode = Some(GfxMode::AsusMuxDgpu);
+        config.correct_effective_mode(GfxMode::Integrated);
+        assert_eq!(config.tmp_mode, Some(GfxMode::Integrated));
+        assert_eq!(config.mode, GfxMode::Hybrid);
+    }
+}
@@ -0,0 +1,233 @@
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{render_modprobe_conf, ConfigChange, GfxConfig},
+        config_preview::{
+            preview_config_change, Artifact, ArtifactChange, ConfigPreview, PreviewEnv,
+        },
+        error::GfxError,
+        initramfs::InitramfsTool,
+        pci_device::{Device, DiscreetGpu, GfxMode, GfxVendor},
+        switch_plan::PlanEnv,
+        switcheroo::SwitcherooSystem,
+        validate::InputClass,
+        zbus_iface::fnv1a_hex,
+    };
+
+    /// switcheroo-control, installed or not, with no rule written
+    struct MockSwitcheroo {
+        installed: bool,
+    }
+
+    impl SwitcherooSystem for MockSwitcheroo {
+        fn installed(&self) -> bool {
+            self.installed
+        }
+
+        fn current_rule(&self) -> Option<String> {
+            None
+        }
+
+        fn write_rule(&self, _rule: Option<&str>) -> Result<(), GfxError> {
+            panic!("a preview writes nothing")
+        }
+
+        fn trigger(&self) -> Result<(), GfxError> {
+            panic!("a preview writes nothing")
+        }
+    }
+
+    const SUPPORTED: &[GfxMode] = &[GfxMode::Hybrid, GfxMode::Integrated, GfxMode::Vfio];
+
+    fn nvidia_dgpu() -> DiscreetGpu {
+        DiscreetGpu::mock_devices(
+            GfxVendor::Nvidia,
+            0,
+            vec![
+                Device::mock("0000:01:00.0", GfxVendor::Nvidia, true).with_pci_id("10DE:1F99"),
+                Device::mock("0000:01:00.1", GfxVendor::Nvidia, false).with_pci_id("10DE:10FA"),
+                Device::mock("0000:01:00.2", GfxVendor::Nvidia, false).with_pci_id("10DE:1ADA"),
+            ],
+            0,
+        )
+    }
+
+    fn change(field: &str, value: &str) -> ConfigChange {
+        ConfigChange {
+            field: field.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn find(artifacts: &[ArtifactChange], artifact: Artifact, mode: GfxMode) -> &ArtifactChange {
+        artifacts
+            .iter()
+            .find(|change| change.artifact == artifact && change.mode == mode)
+            .unwrap()
+    }
+
+    /// Preview `changes` to the default config on an Nvidia laptop in Hybrid
+    fn preview(
+        changes: &[ConfigChange],
+        initramfs: Option<InitramfsTool>,
+    ) -> Result<ConfigPreview, GfxError> {
+        let dgpu = nvidia_dgpu();
+        let switcheroo = MockSwitcheroo { installed: true };
+        preview_config_change(
+            &GfxConfig::new(String::new()),
+            changes,
+            &PreviewEnv {
+                dgpu: &dgpu,
+                supported: SUPPORTED,
+                known_functions: &[],
+                switcheroo: &switcheroo,
+                driver: Some("nvidia"),
+                initramfs,
+                plan: &PlanEnv::default(),
+            },
+        )
+    }
+
+    #[test]
+    fn no_change() {
+        let preview = preview(&[], Some(InitramfsTool::Dracut)).unwrap();
+        assert_eq!(preview.mode, GfxMode::Hybrid);
+        // The mode in use first, then the other supported modes
+        assert_eq!(
+            preview.artifacts.len(),
+            SUPPORTED.len() * Artifact::ALL.len()
+        );
+        assert_eq!(preview.artifacts[0].mode, GfxMode::Hybrid);
+        for change in &preview.artifacts {
+            assert!(!change.changed, "{change:?}");
+            assert!(change.diff.is_empty(), "{change:?}");
+        }
+        assert!(preview.advisories.is_empty(), "{:?}", preview.advisories);
+
+        // The hash is of the content as it would be written
+        let conf = render_modprobe_conf(GfxMode::Hybrid, &nvidia_dgpu(), &[], &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            find(&preview.artifacts, Artifact::Modprobe, GfxMode::Hybrid).hash,
+            fnv1a_hex(&conf)
+        );
+        // Nothing to hide from switcheroo-control while the bridge is off
+        assert!(
+            find(&preview.artifacts, Artifact::Udev, GfxMode::Integrated)
+                .hash
+                .is_empty()
+        );
+        assert!(
+            !find(&preview.artifacts, Artifact::Environment, GfxMode::Hybrid)
+                .hash
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn manage_switcheroo() {
+        let preview = preview(&[change("manage_switcheroo", "true")], None).unwrap();
+        for mode in [GfxMode::Integrated, GfxMode::Vfio] {
+            let udev = find(&preview.artifacts, Artifact::Udev, mode);
+            assert!(udev.changed, "{udev:?}");
+            assert!(!udev.hash.is_empty());
+            assert!(
+                udev.diff
+                    .iter()
+                    .all(|line| line.starts_with('+') && !line.starts_with("+-")),
+                "{udev:?}"
+            );
+            assert!(udev
+                .diff
+                .iter()
+                .any(|line| line.contains("KERNELS==\"0000:01:00.0\"")));
+        }
+        // The dGPU can be launched on in Hybrid, there is no rule
+        assert!(!find(&preview.artifacts, Artifact::Udev, GfxMode::Hybrid).changed);
+        for artifact in [Artifact::Modprobe, Artifact::Environment] {
+            for mode in SUPPORTED {
+                assert!(!find(&preview.artifacts, artifact, *mode).changed);
+            }
+        }
+        assert!(preview.advisories.is_empty(), "{:?}", preview.advisories);
+    }
+
+    #[test]
+    fn ignored_functions() {
+        let preview = preview(&[change("ignored_functions", r#"["0000:01:00.2"]"#)], None).unwrap();
+        let vfio = find(&preview.artifacts, Artifact::Modprobe, GfxMode::Vfio);
+        assert!(vfio.changed);
+        assert_eq!(
+            vfio.diff,
+            [
+                "-options vfio-pci ids=10de:1f99,10de:10fa,10de:1ada",
+                "+options vfio-pci ids=10de:1f99,10de:10fa",
+            ]
+        );
+        // The other modes don't bind the functions, nor does the mode in use change
+        assert!(!find(&preview.artifacts, Artifact::Modprobe, GfxMode::Hybrid).changed);
+        assert!(!find(&preview.artifacts, Artifact::Modprobe, GfxMode::Integrated).changed);
+        assert!(preview.advisories.is_empty(), "{:?}", preview.advisories);
+    }
+
+    #[test]
+    fn always_reboot() {
+        let preview = preview(&[change("always_reboot", "true")], None).unwrap();
+        assert!(preview.artifacts.iter().all(|change| !change.changed));
+        let integrated = preview
+            .advisories
+            .iter()
+            .find(|advisory| advisory.message.contains("Integrated"))
+            .unwrap();
+        assert_eq!(integrated.code, "reboot");
+        assert!(
+            integrated
+                .message
+                .starts_with("A switch to Integrated would need"),
+            "{}",
+            integrated.message
+        );
+        assert!(preview
+            .advisories
+            .iter()
+            .all(|advisory| advisory.code == "reboot"));
+    }
+
+    #[test]
+    fn initramfs() {
+        // Booting into Integrated blacklists the driver the initramfs loads now
+        let changes = [change("mode", r#""Integrated""#)];
+        let dracut = preview(&changes, Some(InitramfsTool::Dracut)).unwrap();
+        // The mode in use is unchanged until the switch
+        assert_eq!(dracut.mode, GfxMode::Hybrid);
+        assert!(dracut.artifacts.iter().all(|change| !change.changed));
+        assert_eq!(dracut.advisories.len(), 1, "{:?}", dracut.advisories);
+        assert_eq!(dracut.advisories[0].code, "initramfs");
+        assert_eq!(
+            dracut.advisories[0].message,
+            "initramfs regeneration recommended (dracut -f)"
+        );
+
+        // Without an initramfs which copies the conf there is nothing to regenerate
+        assert!(preview(&changes, None).unwrap().advisories.is_empty());
+    }
+
+    #[test]
+    fn refused() {
+        let err = preview(&[change("colour", "\"red\"")], None).unwrap_err();
+        assert!(
+            matches!(err, GfxError::InvalidInput(InputClass::Identifier, ref detail) if detail.contains("colour")),
+            "{err}"
+        );
+        let err = preview(&[change("always_reboot", "yes")], None).unwrap_err();
+        assert!(
+            matches!(err, GfxError::InvalidInput(InputClass::Text, ref detail) if detail.contains("always_reboot")),
+            "{err}"
+        );
+        let err = preview(&[change("ignored_functions", r#"["all"]"#)], None).unwrap_err();
+        assert!(matches!(err, GfxError::InvalidInput(..)), "{err}");
+        // State of the running daemon can't be set
+        assert!(preview(&[change("tmp_mode", r#""Vfio""#)], None).is_err());
+    }
+}
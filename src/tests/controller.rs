@@ -0,0 +1,1749 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use futures_util::{future::BoxFuture, lock::Mutex};
+
+    use crate::{
+        actions::{Action, StagedAction, UserActionRequired},
+        audit::{Actor, AuditLog},
+        config::GfxConfig,
+        controller::{
+            igpu_boot_safety_check, supported_modes_changed, AsusProbes, BootOutcome, CtrlGraphics,
+            DebugRun, DgpuHealth, ModeProbe, OperatingProfile, PendingInfo, ProbeCache, ProbeError,
+            SetModeOptions, SupportedModes, SwitchAdvisory, SwitchState, NO_IGPU,
+            NO_SWITCHABLE_GRAPHICS, SINGLE_GPU_DESKTOP,
+        },
+        error::GfxError,
+        logout_switch::SessionProbe,
+        pci_device::{DiscreetGpu, GfxMode, GfxPower, GfxVendor},
+        shutdown::{Interrupted, SHUTDOWN_GRACE},
+        system_class::{Chassis, SystemClass},
+    };
+
+    fn mock_controller(mode: GfxMode) -> CtrlGraphics {
+        let config = GfxConfig {
+            mode,
+            ..GfxConfig::new(Default::default())
+        };
+        CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        )
+    }
+
+    impl CtrlGraphics {
+        /// Start a switch which is recorded across reboots, as `SetMode` does
+        async fn start_switch(
+            &mut self,
+            mode: GfxMode,
+            user_action_required: UserActionRequired,
+            actions: Vec<StagedAction>,
+            actor: Actor,
+        ) -> tokio::task::JoinHandle<()> {
+            self.start_switch_with(mode, user_action_required, actions, actor, true)
+                .await
+                .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn switch_task_panic_recovers_to_switchable_state() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        {
+            let mut config = ctrl.config.lock().await;
+            config.pending_mode = Some(GfxMode::Integrated);
+            config.pending_action = Some(UserActionRequired::Logout);
+            config.switch_state = SwitchState::Switching;
+        }
+
+        // A mock action which panics part way through while holding the dgpu lock
+        let dgpu = ctrl.dgpu_arc_clone();
+        ctrl.spawn_switch_task(async move {
+            let _dgpu = dgpu.lock().await;
+            panic!("mock action panicked");
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert!(matches!(
+            ctrl.get_pending_user_action().await,
+            UserActionRequired::Nothing
+        ));
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Stalled);
+        // The lock held by the panicked task must have been released
+        assert!(ctrl.dgpu.try_lock().is_some());
+
+        // And a new switch request is accepted
+        let action = ctrl.set_gfx_mode(GfxMode::Hybrid).await.unwrap();
+        assert!(matches!(action, UserActionRequired::Nothing));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+    }
+
+    #[tokio::test]
+    async fn switch_task_without_panic_leaves_state() {
+        let ctrl = mock_controller(GfxMode::Hybrid);
+        ctrl.spawn_switch_task(async {}).await.unwrap();
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+    }
+
+    #[tokio::test]
+    async fn nothing_to_do_does_not_leave_pending() {
+        let mut ctrl = mock_controller(GfxMode::Integrated);
+        let action = ctrl.set_gfx_mode(GfxMode::Integrated).await.unwrap();
+        assert!(matches!(action, UserActionRequired::Nothing));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+    }
+
+    /// A switch plan which is harmless to run in tests: `KillAmd` is a no-op but is not
+    /// cancellable, so it stands in for `StopDisplayManager`
+    fn countdown_plan(seconds: u64) -> Vec<StagedAction> {
+        vec![
+            StagedAction::NoLogind,
+            StagedAction::PreStopDelay(seconds),
+            StagedAction::KillAmd,
+        ]
+    }
+
+    #[test]
+    fn set_mode_options_skip_pre_stop_delay() {
+        let plan = || Action::StagedActions(countdown_plan(5));
+
+        match SetModeOptions::default().apply(plan()) {
+            Action::StagedActions(actions) => assert_eq!(actions, countdown_plan(5)),
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+
+        let options = SetModeOptions {
+            skip_pre_stop_delay: true,
+            ..Default::default()
+        };
+        match options.apply(plan()) {
+            Action::StagedActions(actions) => {
+                assert_eq!(actions, [StagedAction::NoLogind, StagedAction::KillAmd])
+            }
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+    }
+
+    #[test]
+    fn set_mode_options_wait_inhibitors() {
+        let plan = || {
+            Action::StagedActions(vec![
+                StagedAction::WaitLogout,
+                StagedAction::StopDisplayManager,
+                StagedAction::KillNvidia,
+            ])
+        };
+
+        match SetModeOptions::default().apply(plan()) {
+            Action::StagedActions(actions) => assert_eq!(
+                actions,
+                [
+                    StagedAction::WaitLogout,
+                    StagedAction::WaitInhibitors,
+                    StagedAction::StopDisplayManager,
+                    StagedAction::KillNvidia,
+                ]
+            ),
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+
+        let options = SetModeOptions {
+            ignore_inhibitors: true,
+            ..Default::default()
+        };
+        match options.apply(plan()) {
+            Action::StagedActions(actions) => {
+                assert!(!actions.contains(&StagedAction::WaitInhibitors))
+            }
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+
+        // Nothing to wait for if the display manager isn't stopped
+        match SetModeOptions::default().apply(Action::StagedActions(countdown_plan(5))) {
+            Action::StagedActions(actions) => assert_eq!(actions, countdown_plan(5)),
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_pre_stop_delay_does_not_wait() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let start = tokio::time::Instant::now();
+        ctrl.start_switch(
+            GfxMode::Integrated,
+            UserActionRequired::Logout,
+            countdown_plan(0),
+            Actor::Daemon,
+        )
+        .await
+        .await
+        .unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pre_stop_delay_counts_down_then_switches() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let start = tokio::time::Instant::now();
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(3),
+                Actor::Daemon,
+            )
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Integrated);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Switching);
+
+        handle.await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        // Nothing left to cancel
+        assert!(matches!(
+            ctrl.cancel_pending_switch().await,
+            Err(GfxError::NoSwitchPending)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_during_pre_stop_delay() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let start = tokio::time::Instant::now();
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(10),
+                Actor::Daemon,
+            )
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        ctrl.cancel_pending_switch().await.unwrap();
+        // State is reset as soon as the cancel returns
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+
+        handle.await.unwrap();
+        // The countdown was cut short and nothing after it was done
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn committed_switch_refuses_another() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                vec![StagedAction::KillAmd, StagedAction::PreStopDelay(5)],
+                Actor::Daemon,
+            )
+            .await;
+        // Committed by `KillAmd`, counting down after it
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert!(matches!(
+            ctrl.start_switch_with(
+                GfxMode::Vfio,
+                UserActionRequired::Nothing,
+                vec![StagedAction::KillAmd],
+                Actor::Daemon,
+                true,
+            )
+            .await,
+            Err(GfxError::SwitchInProgress(GfxMode::Integrated))
+        ));
+        handle.await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+    }
+
+    #[tokio::test]
+    async fn switch_plan_is_a_dry_run() {
+        let ctrl = mock_controller(GfxMode::Hybrid);
+        let plan = ctrl.plan_switch_to(GfxMode::Integrated).await;
+        assert_eq!(plan.from, GfxMode::Hybrid);
+        assert_eq!(plan.to, GfxMode::Integrated);
+        assert_eq!(plan.user_action, UserActionRequired::Logout);
+        for action in ["WaitLogout", "StopDisplayManager", "UnloadGpuDrivers"] {
+            assert!(plan.actions.iter().any(|a| a == action), "{action}");
+        }
+        assert_eq!(plan.actions.last().unwrap(), "StartDisplayManager");
+
+        // Left to the user
+        let plan = ctrl.plan_switch_to(GfxMode::Vfio).await;
+        assert_eq!(plan.user_action, UserActionRequired::SwitchToIntegrated);
+        assert!(plan.actions.is_empty());
+
+        // Nothing is pending or changed
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_pending_info().await, PendingInfo::default());
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+    }
+
+    fn client(uid: u32) -> Actor {
+        Actor::Bus {
+            sender: ":1.42".to_string(),
+            uid: Some(uid),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_request_is_recorded() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        assert_eq!(ctrl.get_pending_info().await, PendingInfo::default());
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(3),
+                client(1000),
+            )
+            .await;
+        let info = ctrl.get_pending_info().await;
+        assert_eq!(info.mode, GfxMode::Integrated);
+        assert_eq!(info.action, UserActionRequired::Logout);
+        assert_eq!(info.requester, ":1.42");
+        assert_eq!(info.uid, 1000);
+        assert!(info.requested_at > 0);
+        {
+            let mut config = ctrl.config.lock().await;
+            let request = config.pending_request.as_mut().unwrap();
+            request.since = std::time::Instant::now()
+                .checked_sub(Duration::from_secs(150))
+                .unwrap();
+        }
+        assert_eq!(ctrl.get_pending_info().await.elapsed_s, 150);
+
+        // Cleared once the switch is done
+        handle.await.unwrap();
+        assert_eq!(ctrl.get_pending_info().await, PendingInfo::default());
+        assert_eq!(ctrl.config.lock().await.pending_request, None);
+
+        // and when it is cancelled
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Hybrid,
+                UserActionRequired::Logout,
+                countdown_plan(3),
+                Actor::Daemon,
+            )
+            .await;
+        let info = ctrl.get_pending_info().await;
+        assert_eq!(info.requester, "automation");
+        assert_eq!(info.uid, u32::MAX);
+        ctrl.cancel_pending_switch().await.unwrap();
+        assert_eq!(ctrl.config.lock().await.pending_request, None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_requester_only() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        ctrl.config.lock().await.cancel_requester_only = true;
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(10),
+                client(1000),
+            )
+            .await;
+        for uid in [Some(1001), None] {
+            assert!(matches!(
+                ctrl.cancel_pending_switch_by(uid).await,
+                Err(GfxError::CancelNotAllowed(requester)) if requester == ":1.42"
+            ));
+        }
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Integrated);
+        ctrl.cancel_pending_switch_by(Some(1000)).await.unwrap();
+        handle.await.unwrap();
+
+        // A switch supergfxd started itself only root can cancel
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(10),
+                Actor::Daemon,
+            )
+            .await;
+        assert!(ctrl.cancel_pending_switch_by(Some(1000)).await.is_err());
+        ctrl.cancel_pending_switch_by(Some(0)).await.unwrap();
+        handle.await.unwrap();
+        // Nothing pending is still reported as such
+        assert!(matches!(
+            ctrl.cancel_pending_switch_by(Some(1001)).await,
+            Err(GfxError::NoSwitchPending)
+        ));
+
+        // Anyone can while it is off
+        ctrl.config.lock().await.cancel_requester_only = false;
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(10),
+                client(1000),
+            )
+            .await;
+        ctrl.cancel_pending_switch_by(Some(1001)).await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_after_commit_is_refused() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                vec![StagedAction::KillAmd, StagedAction::PreStopDelay(5)],
+                Actor::Daemon,
+            )
+            .await;
+        // Block the switch on the dgpu lock after it has committed to the first action.
+        // The task can't have run yet as nothing has yielded to the runtime.
+        let dgpu = ctrl.dgpu_arc_clone();
+        let guard = dgpu.lock().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(matches!(
+            ctrl.cancel_pending_switch().await,
+            Err(GfxError::SwitchCommitted)
+        ));
+        drop(guard);
+        handle.await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_uncommitted_switch() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(10),
+                Actor::Daemon,
+            )
+            .await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        let wait = ctrl.begin_shutdown().await;
+        assert_eq!(
+            wait.interrupted,
+            Interrupted::Cancelled(GfxMode::Integrated)
+        );
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        assert!(wait.finish(SHUTDOWN_GRACE).await);
+        handle.await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_parks_committed_switch() {
+        let path = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-shutdown.json",
+            std::process::id()
+        ));
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        ctrl.config.lock().await.config_path = path.to_string_lossy().to_string();
+        let start = tokio::time::Instant::now();
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                vec![StagedAction::KillAmd, StagedAction::PreStopDelay(5)],
+                Actor::Daemon,
+            )
+            .await;
+        // Hold the switch in its first action, which commits it
+        let dgpu = ctrl.dgpu_arc_clone();
+        let guard = dgpu.lock().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let wait = ctrl.begin_shutdown().await;
+        assert_eq!(wait.interrupted, Interrupted::Parked(GfxMode::Integrated));
+        // Still switching until the action finishes, and nothing new is taken
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Switching);
+        assert!(matches!(
+            ctrl.check_mutation_allowed(),
+            Err(GfxError::ShuttingDown)
+        ));
+        assert!(matches!(
+            ctrl.cancel_pending_switch().await,
+            Err(GfxError::SwitchCommitted)
+        ));
+
+        drop(guard);
+        assert!(wait.finish(SHUTDOWN_GRACE).await);
+        handle.await.unwrap();
+        // The delay after the current action was never reached
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let config = ctrl.config.lock().await;
+        assert_eq!(config.mode, GfxMode::Hybrid);
+        assert_eq!(config.pending_mode, None);
+        assert_eq!(config.switch_state, SwitchState::Stalled);
+        // The mode the next start restores is what was written
+        let written: GfxConfig =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written.mode, GfxMode::Hybrid);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn supported_modes_from_probe() {
+        let probe = ModeProbe::default();
+        assert_eq!(probe.supported_modes(), [GfxMode::Integrated]);
+
+        // The dgpu can be hidden by dgpu_disable on boot
+        let probe = ModeProbe {
+            asus_dgpu_disable: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            probe.supported_modes(),
+            [GfxMode::Integrated, GfxMode::Hybrid]
+        );
+
+        let probe = ModeProbe {
+            dgpu_found: true,
+            vfio_enable: true,
+            asus_egpu_enable: true,
+            asus_gpu_mux: true,
+            nvidia_modeset_off: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            probe.supported_modes(),
+            [
+                GfxMode::Integrated,
+                GfxMode::Hybrid,
+                GfxMode::Vfio,
+                GfxMode::AsusEgpu,
+                GfxMode::AsusMuxDgpu,
+                GfxMode::NvidiaNoModeset
+            ]
+        );
+
+        let probe = ModeProbe {
+            asus_mux_discreet: true,
+            ..probe
+        };
+        assert_eq!(probe.supported_modes(), [GfxMode::AsusMuxDgpu]);
+    }
+
+    #[test]
+    fn unsupported_reason_per_mode() {
+        let probe = ModeProbe {
+            dgpu_found: true,
+            asus_gpu_mux: true,
+            ..Default::default()
+        };
+        assert_eq!(probe.unsupported_reason(GfxMode::Hybrid), None);
+        assert_eq!(probe.unsupported_reason(GfxMode::AsusMuxDgpu), None);
+        assert_eq!(
+            probe.unsupported_reason(GfxMode::Vfio),
+            Some("vfio_enable is off in the config")
+        );
+        assert_eq!(
+            probe.unsupported_reason(GfxMode::NvidiaNoModeset),
+            Some("nvidia-drm.modeset=0 isn't on the kernel cmdline")
+        );
+        assert!(probe.unsupported_reason(GfxMode::None).is_some());
+
+        // A reason for the whole list wins over the mode's own
+        let probe = ModeProbe {
+            asus_mux_discreet: true,
+            ..probe
+        };
+        assert_eq!(
+            probe.unsupported_reason(GfxMode::Vfio),
+            probe.supported_reason()
+        );
+        let probe = ModeProbe::default();
+        assert_eq!(
+            probe.unsupported_reason(GfxMode::Hybrid),
+            Some(NO_SWITCHABLE_GRAPHICS)
+        );
+    }
+
+    #[test]
+    fn supported_modes_change_detection() {
+        let dgpu_only = ModeProbe {
+            dgpu_found: true,
+            ..Default::default()
+        };
+        // asus-nb-wmi loads late and the paths appear, then the module is unloaded
+        let with_asus = ModeProbe {
+            asus_dgpu_disable: true,
+            asus_egpu_enable: true,
+            asus_gpu_mux: true,
+            ..dgpu_only
+        };
+        let sequence = [dgpu_only, dgpu_only, with_asus, with_asus, dgpu_only];
+
+        let mut last = None;
+        let changes: Vec<_> = sequence
+            .iter()
+            .map(|probe| supported_modes_changed(&mut last, probe.supported_modes()))
+            .collect();
+
+        assert_eq!(
+            changes,
+            [
+                // The first probe only sets the baseline
+                None,
+                None,
+                Some(vec![
+                    GfxMode::Integrated,
+                    GfxMode::Hybrid,
+                    GfxMode::AsusEgpu,
+                    GfxMode::AsusMuxDgpu
+                ]),
+                None,
+                Some(vec![GfxMode::Integrated, GfxMode::Hybrid]),
+            ]
+        );
+        assert_eq!(last, Some(vec![GfxMode::Integrated, GfxMode::Hybrid]));
+    }
+
+    #[test]
+    fn supported_modes_change_only_on_mode_list() {
+        // A path appearing that doesn't change the list isn't a change
+        let mut last = None;
+        let no_dgpu = ModeProbe::default();
+        let no_dgpu_egpu = ModeProbe {
+            asus_egpu_enable: true,
+            ..no_dgpu
+        };
+        assert_eq!(
+            supported_modes_changed(&mut last, no_dgpu.supported_modes()),
+            None
+        );
+        assert_eq!(
+            supported_modes_changed(&mut last, no_dgpu_egpu.supported_modes()),
+            None
+        );
+    }
+
+    fn probe_error(probe: &str, error: &str) -> ProbeError {
+        ProbeError {
+            probe: probe.to_string(),
+            error: error.to_string(),
+        }
+    }
+
+    #[test]
+    fn failed_probes_degrade_supported_modes() {
+        let dgpu = DiscreetGpu::mock(GfxVendor::Nvidia);
+        let all_ok = AsusProbes {
+            dgpu_disable: Ok(true),
+            egpu_enable: Ok(true),
+            gpu_mux: Ok(true),
+            mux_discreet: Ok(false),
+        };
+        let mut errors = Vec::new();
+        let probe = ModeProbe::from_probes(&dgpu, &Ok(true), &all_ok, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(
+            probe.supported_modes(),
+            [
+                GfxMode::Integrated,
+                GfxMode::Hybrid,
+                GfxMode::AsusEgpu,
+                GfxMode::AsusMuxDgpu,
+                GfxMode::NvidiaNoModeset
+            ]
+        );
+
+        // Only the modes whose probes failed are left out
+        let failing = AsusProbes {
+            egpu_enable: Err("permission denied".to_string()),
+            ..all_ok.clone()
+        };
+        let mut errors = Vec::new();
+        let probe =
+            ModeProbe::from_probes(&dgpu, &Err("no cmdline".to_string()), &failing, &mut errors);
+        assert_eq!(
+            probe.supported_modes(),
+            [GfxMode::Integrated, GfxMode::Hybrid, GfxMode::AsusMuxDgpu]
+        );
+        assert_eq!(
+            errors,
+            [
+                probe_error("asus_egpu_enable", "permission denied"),
+                probe_error("kernel_cmdline", "no cmdline"),
+            ]
+        );
+
+        // An unreadable MUX mode doesn't restrict the list to AsusMuxDgpu
+        let failing = AsusProbes {
+            mux_discreet: Err("Could not read".to_string()),
+            ..all_ok
+        };
+        let mut errors = Vec::new();
+        let probe = ModeProbe::from_probes(&dgpu, &Ok(false), &failing, &mut errors);
+        assert!(probe.supported_modes().contains(&GfxMode::Hybrid));
+        assert_eq!(errors, [probe_error("asus_gpu_mux_mode", "Could not read")]);
+    }
+
+    #[test]
+    fn probe_failures_logged_once() {
+        let mut cache = ProbeCache::default();
+        let errors = [
+            probe_error("asus_egpu_enable", "permission denied"),
+            probe_error("kernel_cmdline", "no cmdline"),
+        ];
+        assert_eq!(cache.log_new(&errors), 2);
+        assert_eq!(cache.log_new(&errors), 0);
+        // Invalidating the cache doesn't log the same failure again
+        cache.invalidate();
+        assert_eq!(cache.log_new(&errors[..1]), 0);
+        assert_eq!(
+            cache.log_new(&[probe_error("asus_egpu_enable", "no such device")]),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn supported_with_errors_uses_cached_probes() {
+        let ctrl = mock_controller(GfxMode::Hybrid);
+        {
+            let mut cache = ctrl.probe_cache.lock().await;
+            cache.nvidia_modeset_off = Some(Err("no cmdline".to_string()));
+            cache.asus = Some(AsusProbes {
+                egpu_enable: Err("permission denied".to_string()),
+                ..Default::default()
+            });
+        }
+        let expected = SupportedModes {
+            modes: vec![GfxMode::Integrated, GfxMode::Hybrid],
+            errors: vec![
+                probe_error("asus_egpu_enable", "permission denied"),
+                probe_error("kernel_cmdline", "no cmdline"),
+            ],
+        };
+        assert_eq!(ctrl.get_supported_with_errors().await, expected);
+        // The best-effort list is the same as the plain one
+        assert_eq!(ctrl.get_supported_modes().await, expected.modes);
+
+        ctrl.probe_cache.lock().await.invalidate_hardware();
+        assert!(ctrl.probe_cache.lock().await.asus.is_none());
+        assert!(ctrl.probe_cache.lock().await.nvidia_modeset_off.is_some());
+    }
+
+    #[test]
+    fn no_dgpu_profile_from_probe() {
+        let probe = ModeProbe::default();
+        assert_eq!(probe.profile(), OperatingProfile::NoDgpu);
+        assert_eq!(probe.supported_modes(), [GfxMode::Integrated]);
+        assert_eq!(probe.supported_reason(), Some(NO_SWITCHABLE_GRAPHICS));
+
+        for probe in [
+            ModeProbe {
+                dgpu_found: true,
+                ..Default::default()
+            },
+            ModeProbe {
+                asus_dgpu_disable: true,
+                ..Default::default()
+            },
+            ModeProbe {
+                asus_gpu_mux: true,
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(probe.profile(), OperatingProfile::Switchable);
+        }
+        assert_eq!(
+            ModeProbe {
+                dgpu_found: true,
+                ..Default::default()
+            }
+            .supported_reason(),
+            None
+        );
+    }
+
+    /// A controller for a system with only an iGPU. Returns `None` if the ASUS dgpu controls
+    /// exist on the machine running the tests, as they make the system switchable.
+    async fn mock_no_dgpu_controller() -> Option<CtrlGraphics> {
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::Unknown),
+        );
+        if ctrl.get_profile().await != OperatingProfile::NoDgpu {
+            return None;
+        }
+        Some(ctrl)
+    }
+
+    #[tokio::test]
+    async fn no_dgpu_startup_and_queries() {
+        let mut ctrl = match mock_no_dgpu_controller().await {
+            Some(ctrl) => ctrl,
+            None => return,
+        };
+        // Boot tasks are skipped so nothing touches the system
+        assert_eq!(
+            ctrl.reload().await.unwrap(),
+            BootOutcome::Skipped(GfxMode::Integrated, NO_SWITCHABLE_GRAPHICS.to_string())
+        );
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+
+        assert_eq!(ctrl.get_supported_modes().await, [GfxMode::Integrated]);
+        assert_eq!(ctrl.get_supported_reason().await, NO_SWITCHABLE_GRAPHICS);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        assert_eq!(ctrl.get_gfx_vendor().await, GfxVendor::Unknown);
+    }
+
+    #[tokio::test]
+    async fn no_dgpu_rejects_mutation() {
+        let mut ctrl = match mock_no_dgpu_controller().await {
+            Some(ctrl) => ctrl,
+            None => return,
+        };
+        for mode in [GfxMode::Integrated, GfxMode::Hybrid, GfxMode::Vfio] {
+            match ctrl.set_gfx_mode(mode).await {
+                Err(GfxError::NotSupported(msg)) => assert_eq!(msg, NO_SWITCHABLE_GRAPHICS),
+                res => panic!("Expected NotSupported, got {res:?}"),
+            }
+        }
+        assert!(matches!(
+            ctrl.cancel_pending_switch().await,
+            Err(GfxError::NoSwitchPending)
+        ));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+    }
+
+    const DESKTOP: SystemClass = SystemClass {
+        chassis: Chassis::Desktop,
+        battery: false,
+    };
+
+    #[test]
+    fn single_gpu_desktop_detected() {
+        let laptop = SystemClass {
+            chassis: Chassis::Portable,
+            battery: true,
+        };
+        let desktop_with_battery = SystemClass {
+            battery: true,
+            ..DESKTOP
+        };
+        // The class, whether there is an iGPU and force_enable_on_desktop
+        let cases = [
+            (DESKTOP, false, false, true),
+            (DESKTOP, true, false, false),
+            (DESKTOP, false, true, false),
+            (laptop, false, false, false),
+            (desktop_with_battery, false, false, false),
+            (SystemClass::default(), false, false, false),
+        ];
+        for (class, igpu, force, expected) in cases {
+            let mut dgpu = DiscreetGpu::mock(GfxVendor::Nvidia).with_system_class(class);
+            if !igpu {
+                dgpu = dgpu.without_igpu();
+            }
+            dgpu.set_force_enable_on_desktop(force);
+            assert_eq!(
+                dgpu.single_gpu_desktop(),
+                expected,
+                "{class:?}, iGPU {igpu}, forced {force}"
+            );
+        }
+    }
+
+    #[test]
+    fn desktop_profile_from_probe() {
+        let probe = ModeProbe {
+            dgpu_found: true,
+            igpu_missing: true,
+            single_gpu_desktop: true,
+            ..Default::default()
+        };
+        let profile = probe.profile();
+        assert_eq!(profile, OperatingProfile::Desktop);
+        assert!(profile.is_inert());
+        assert_eq!(profile.fixed_mode(), Some(GfxMode::Hybrid));
+        assert_eq!(probe.supported_modes(), [GfxMode::Hybrid]);
+        assert_eq!(probe.supported_reason(), Some(SINGLE_GPU_DESKTOP));
+        for mode in [GfxMode::Integrated, GfxMode::Vfio, GfxMode::AsusMuxDgpu] {
+            assert_eq!(probe.unsupported_reason(mode), Some(SINGLE_GPU_DESKTOP));
+        }
+
+        // A MUX or the ASUS dGPU controls make a desktop switchable
+        for switchable in [
+            ModeProbe {
+                asus_gpu_mux: true,
+                ..probe
+            },
+            ModeProbe {
+                vendor_mux: true,
+                ..probe
+            },
+            ModeProbe {
+                asus_dgpu_disable: true,
+                ..probe
+            },
+        ] {
+            assert_eq!(switchable.profile(), OperatingProfile::Switchable);
+        }
+        // Without a dGPU it is the iGPU which is the only GPU
+        let no_dgpu = ModeProbe {
+            single_gpu_desktop: true,
+            ..Default::default()
+        };
+        assert_eq!(no_dgpu.profile(), OperatingProfile::NoDgpu);
+        assert_eq!(OperatingProfile::Switchable.fixed_mode(), None);
+        assert!(!OperatingProfile::Switchable.is_inert());
+    }
+
+    /// A controller for a desktop with only a dGPU, with `force_enable_on_desktop` set to
+    /// `force`. Returns `None` if the ASUS or vendor MUX controls exist on the machine running
+    /// the tests, as they make the system switchable.
+    async fn mock_desktop_controller(force: bool) -> Option<CtrlGraphics> {
+        let config = GfxConfig {
+            force_enable_on_desktop: force,
+            ..GfxConfig::new(Default::default())
+        };
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia)
+                .without_igpu()
+                .with_system_class(DESKTOP),
+        );
+        ctrl.apply_force_enable_on_desktop().await;
+        if ctrl.get_profile().await.is_inert() == force {
+            return None;
+        }
+        Some(ctrl)
+    }
+
+    #[tokio::test]
+    async fn desktop_is_read_only() {
+        let mut ctrl = match mock_desktop_controller(false).await {
+            Some(ctrl) => ctrl,
+            None => return,
+        };
+        assert_eq!(ctrl.get_profile().await, OperatingProfile::Desktop);
+        // Boot tasks are skipped so nothing touches the system
+        assert_eq!(
+            ctrl.reload().await.unwrap(),
+            BootOutcome::Skipped(GfxMode::Hybrid, SINGLE_GPU_DESKTOP.to_string())
+        );
+        assert_eq!(ctrl.get_supported_modes().await, [GfxMode::Hybrid]);
+        assert_eq!(ctrl.get_supported_reason().await, SINGLE_GPU_DESKTOP);
+        let state = ctrl.get_state().await;
+        assert_eq!(state.mode, GfxMode::Hybrid);
+        assert_eq!(state.power, GfxPower::Unknown);
+
+        for mode in [GfxMode::Integrated, GfxMode::Hybrid, GfxMode::Vfio] {
+            match ctrl.set_gfx_mode(mode).await {
+                Err(GfxError::NotSupported(msg)) => assert_eq!(msg, SINGLE_GPU_DESKTOP),
+                res => panic!("Expected NotSupported, got {res:?}"),
+            }
+        }
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+    }
+
+    #[tokio::test]
+    async fn desktop_switchable_when_forced() {
+        let ctrl = match mock_desktop_controller(true).await {
+            Some(ctrl) => ctrl,
+            None => return,
+        };
+        assert_eq!(ctrl.get_profile().await, OperatingProfile::Switchable);
+        // The modes which turn off the only GPU are still not offered
+        let supported = ctrl.get_supported_modes().await;
+        assert!(supported.contains(&GfxMode::Hybrid));
+        assert!(!supported.contains(&GfxMode::Integrated));
+        assert_eq!(ctrl.get_supported_reason().await, NO_IGPU);
+
+        // Taken back on the next reload of the config
+        ctrl.config.lock().await.force_enable_on_desktop = false;
+        ctrl.apply_force_enable_on_desktop().await;
+        assert_eq!(ctrl.get_profile().await, OperatingProfile::Desktop);
+    }
+
+    #[test]
+    fn dgpu_health_classification() {
+        let dgpu_modes = [
+            GfxMode::Hybrid,
+            GfxMode::NvidiaNoModeset,
+            GfxMode::AsusEgpu,
+            GfxMode::AsusMuxDgpu,
+        ];
+        for mode in dgpu_modes {
+            assert_eq!(
+                DgpuHealth::classify(false, true, mode),
+                DgpuHealth::FellOffBus
+            );
+            assert_eq!(DgpuHealth::classify(true, true, mode), DgpuHealth::Ok);
+            assert_eq!(DgpuHealth::classify(false, false, mode), DgpuHealth::Ok);
+        }
+        // The dgpu is expected to be gone in these, with or without a lingering module
+        for mode in [GfxMode::Integrated, GfxMode::Vfio, GfxMode::None] {
+            for present in [true, false] {
+                for loaded in [true, false] {
+                    assert_eq!(DgpuHealth::classify(present, loaded, mode), DgpuHealth::Ok);
+                }
+            }
+        }
+        // Nothing tracked, nothing to check
+        assert_eq!(
+            DgpuHealth::check(&DiscreetGpu::mock(GfxVendor::Nvidia), GfxMode::Hybrid),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded_hardware_only_allows_integrated() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        ctrl.degraded_hardware.store(true, Ordering::Release);
+
+        for mode in [GfxMode::Hybrid, GfxMode::Vfio, GfxMode::AsusMuxDgpu] {
+            assert!(matches!(
+                ctrl.set_gfx_mode(mode).await,
+                Err(GfxError::DgpuFellOffBus)
+            ));
+        }
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert!(ctrl.get_degraded_hardware());
+
+        // Already in Integrated so there is nothing to do, but it isn't refused
+        let mut ctrl = mock_controller(GfxMode::Integrated);
+        ctrl.degraded_hardware.store(true, Ordering::Release);
+        assert!(!matches!(
+            ctrl.set_gfx_mode(GfxMode::Integrated).await,
+            Err(GfxError::DgpuFellOffBus)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_snapshot_consistent_mid_switch() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let idle = ctrl.get_status().await;
+        assert_eq!(idle.mode, GfxMode::Hybrid);
+        assert_eq!(idle.pending_mode, GfxMode::None);
+        assert_eq!(idle.pending_action, UserActionRequired::Nothing);
+        assert_eq!(idle.switch_state, SwitchState::Idle);
+        assert_eq!(idle.vendor, GfxVendor::Nvidia);
+        // Nothing changed so the generation is the same
+        assert_eq!(ctrl.get_status().await, idle);
+
+        let handle = ctrl
+            .start_switch(
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                countdown_plan(3),
+                Actor::Daemon,
+            )
+            .await;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // The switch hasn't finished, so the mode is still the old one alongside the pending
+        let switching = ctrl.get_status().await;
+        assert_eq!(switching.mode, GfxMode::Hybrid);
+        assert_eq!(switching.pending_mode, GfxMode::Integrated);
+        assert_eq!(switching.pending_action, UserActionRequired::Logout);
+        assert_eq!(switching.switch_state, SwitchState::Switching);
+        assert!(switching.generation > idle.generation);
+        assert_eq!(ctrl.get_status().await.generation, switching.generation);
+
+        handle.await.unwrap();
+        let done = ctrl.get_status().await;
+        assert_eq!(done.mode, GfxMode::Integrated);
+        assert_eq!(done.pending_mode, GfxMode::None);
+        assert_eq!(done.pending_action, UserActionRequired::Nothing);
+        assert_eq!(done.switch_state, SwitchState::Idle);
+        assert!(done.generation > switching.generation);
+    }
+
+    #[tokio::test]
+    async fn state_degrades_unreadable_power() {
+        let ctrl = mock_controller(GfxMode::Hybrid);
+        {
+            let mut config = ctrl.config.lock().await;
+            config.pending_mode = Some(GfxMode::Integrated);
+            config.pending_action = Some(UserActionRequired::Logout);
+        }
+        // The mock dGPU has no devices so its runtime status can't be read, which
+        // doesn't fail the rest
+        let state = ctrl.get_state().await;
+        assert_eq!(state.mode, GfxMode::Hybrid);
+        assert_eq!(state.power, GfxPower::Unknown);
+        assert_eq!(state.pending_mode, GfxMode::Integrated);
+        assert_eq!(state.pending_action, UserActionRequired::Logout);
+        assert_eq!(state.vendor, GfxVendor::Nvidia);
+        assert!(state.supported.contains(&GfxMode::Hybrid));
+
+        ctrl.config.lock().await.mode_locked = true;
+        assert_eq!(ctrl.get_state().await.supported, [GfxMode::Hybrid]);
+    }
+
+    #[test]
+    fn locked_mode_is_only_supported_mode() {
+        let probe = ModeProbe {
+            dgpu_found: true,
+            vfio_enable: true,
+            asus_gpu_mux: true,
+            locked_mode: Some(GfxMode::Hybrid),
+            ..Default::default()
+        };
+        assert_eq!(probe.supported_modes(), [GfxMode::Hybrid]);
+        assert!(probe.supported_reason().is_some());
+        assert_eq!(probe.profile(), OperatingProfile::Switchable);
+
+        // The MUX state is hardware, it still wins
+        let probe = ModeProbe {
+            asus_mux_discreet: true,
+            ..probe
+        };
+        assert_eq!(probe.supported_modes(), [GfxMode::AsusMuxDgpu]);
+    }
+
+    #[tokio::test]
+    async fn locked_mode_rejects_switching() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let path = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-controller-locked.json",
+            std::process::id()
+        ));
+        ctrl.config.lock().await.config_path = path.to_string_lossy().to_string();
+        ctrl.config.lock().await.mode_locked = true;
+
+        for mode in [GfxMode::Integrated, GfxMode::Vfio, GfxMode::Hybrid] {
+            assert!(matches!(
+                ctrl.set_gfx_mode(mode).await,
+                Err(GfxError::ModeLocked(GfxMode::Hybrid))
+            ));
+        }
+        let options = SetModeOptions {
+            skip_pre_stop_delay: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            ctrl.set_gfx_mode_with_options(GfxMode::Integrated, options, &Actor::Daemon)
+                .await,
+            Err(GfxError::ModeLocked(GfxMode::Hybrid))
+        ));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        assert!(ctrl.get_status().await.mode_locked);
+
+        ctrl.set_mode_locked(false, &Actor::Daemon).await.unwrap();
+        assert!(!ctrl.get_mode_locked().await);
+        assert!(!matches!(
+            ctrl.set_gfx_mode(GfxMode::Hybrid).await,
+            Err(GfxError::ModeLocked(_))
+        ));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn no_igpu_limits_supported_modes() {
+        let asus = AsusProbes {
+            dgpu_disable: Ok(false),
+            egpu_enable: Ok(false),
+            gpu_mux: Ok(true),
+            mux_discreet: Ok(false),
+        };
+        let with_igpu = DiscreetGpu::mock(GfxVendor::Nvidia);
+        let probe = ModeProbe {
+            vfio_enable: true,
+            ..ModeProbe::from_probes(&with_igpu, &Ok(true), &asus, &mut Vec::new())
+        };
+        assert!(!probe.igpu_missing);
+        assert_eq!(
+            probe.supported_modes(),
+            [
+                GfxMode::Integrated,
+                GfxMode::Hybrid,
+                GfxMode::Vfio,
+                GfxMode::AsusMuxDgpu,
+                GfxMode::NvidiaNoModeset
+            ]
+        );
+
+        let without_igpu = DiscreetGpu::mock(GfxVendor::Nvidia).without_igpu();
+        let probe = ModeProbe {
+            vfio_enable: true,
+            ..ModeProbe::from_probes(&without_igpu, &Ok(true), &asus, &mut Vec::new())
+        };
+        assert!(probe.igpu_missing);
+        assert_eq!(
+            probe.supported_modes(),
+            [
+                GfxMode::Hybrid,
+                GfxMode::AsusMuxDgpu,
+                GfxMode::NvidiaNoModeset
+            ]
+        );
+        assert_eq!(probe.unsupported_reason(GfxMode::Integrated), Some(NO_IGPU));
+        assert_eq!(probe.unsupported_reason(GfxMode::Vfio), Some(NO_IGPU));
+        assert_eq!(probe.unsupported_reason(GfxMode::Hybrid), None);
+        assert_eq!(probe.supported_reason(), Some(NO_IGPU));
+
+        // Behind a MUX set to the dGPU the iGPU is hidden, which isn't the same
+        let mux_discreet = AsusProbes {
+            mux_discreet: Ok(true),
+            ..asus
+        };
+        let probe =
+            ModeProbe::from_probes(&without_igpu, &Ok(false), &mux_discreet, &mut Vec::new());
+        assert!(!probe.igpu_missing);
+        assert_eq!(probe.supported_modes(), [GfxMode::AsusMuxDgpu]);
+        // Nothing to miss without a dGPU
+        let probe = ModeProbe::from_probes(
+            &DiscreetGpu::mock(GfxVendor::Unknown).without_igpu(),
+            &Ok(false),
+            &AsusProbes::default(),
+            &mut Vec::new(),
+        );
+        assert!(!probe.igpu_missing);
+    }
+
+    #[tokio::test]
+    async fn no_igpu_refuses_switching() {
+        let path = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-controller-no-igpu.json",
+            std::process::id()
+        ));
+        let config = GfxConfig {
+            mode: GfxMode::Hybrid,
+            vfio_enable: true,
+            ..GfxConfig::new(path.to_string_lossy().to_string())
+        };
+        let mut ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia).without_igpu(),
+        );
+        for mode in [GfxMode::Integrated, GfxMode::Vfio] {
+            assert!(matches!(
+                ctrl.set_gfx_mode(mode).await,
+                Err(GfxError::NoIgpu(refused)) if refused == mode
+            ));
+            let readiness = ctrl.get_switch_readiness(mode).await.unwrap();
+            assert!(!readiness.ready);
+            assert_eq!(readiness.findings[0].code, "no_igpu");
+        }
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        let info = ctrl.get_mode_info().await;
+        let integrated = info.iter().find(|i| i.mode == GfxMode::Integrated).unwrap();
+        assert!(!integrated.supported);
+        assert_eq!(integrated.unsupported_reason, NO_IGPU);
+        assert!(!ctrl
+            .get_supported_modes()
+            .await
+            .contains(&GfxMode::Integrated));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn no_igpu_boot_falls_back_to_hybrid() {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-controller-no-igpu-boot",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let audit = AuditLog::new(dir.join("audit.log"), 4096);
+        let mut config = GfxConfig {
+            mode: GfxMode::Integrated,
+            ..GfxConfig::new(dir.join("config.json").to_string_lossy().to_string())
+        };
+
+        // Left alone with an iGPU, or in a mode which keeps the dGPU on
+        assert_eq!(
+            igpu_boot_safety_check(GfxMode::Integrated, false, &mut config, &audit),
+            (GfxMode::Integrated, None)
+        );
+        assert_eq!(
+            igpu_boot_safety_check(GfxMode::AsusMuxDgpu, true, &mut config, &audit),
+            (GfxMode::AsusMuxDgpu, None)
+        );
+        assert!(audit.tail(10).is_empty());
+
+        let (mode, reason) = igpu_boot_safety_check(GfxMode::Integrated, true, &mut config, &audit);
+        assert_eq!(mode, GfxMode::Hybrid);
+        assert!(reason.unwrap().starts_with(NO_IGPU));
+        assert_eq!(config.mode, GfxMode::Hybrid);
+        let written: GfxConfig =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("config.json")).unwrap())
+                .unwrap();
+        assert_eq!(written.mode, GfxMode::Hybrid);
+        let records = audit.tail(10);
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].change,
+            "mode Integrated -> Hybrid by the iGPU boot safety check"
+        );
+
+        // Also from the kernel cmdline, which only sets the mode booted into
+        config.tmp_mode = Some(GfxMode::Vfio);
+        let (mode, _) = igpu_boot_safety_check(GfxMode::Vfio, true, &mut config, &audit);
+        assert_eq!(mode, GfxMode::Hybrid);
+        assert_eq!(config.effective_mode(), GfxMode::Hybrid);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Switch to `to` with `vfio_save` set as given, returning the mode written to disk
+    async fn switch_and_read_persisted(name: &str, to: GfxMode, vfio_save: bool) -> GfxMode {
+        let path =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-{name}", std::process::id()));
+        let mut config = GfxConfig {
+            mode: GfxMode::Integrated,
+            vfio_enable: true,
+            vfio_save,
+            ..GfxConfig::new(path.to_string_lossy().to_string())
+        };
+        config.write().unwrap();
+        let mut ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+        ctrl.start_switch(
+            to,
+            UserActionRequired::Nothing,
+            vec![StagedAction::KillAmd],
+            Actor::Daemon,
+        )
+        .await
+        .await
+        .unwrap();
+
+        let config = ctrl.config.lock().await;
+        assert_eq!(ctrl.get_gfx_mode(&config).unwrap(), to);
+        let on_disk: GfxConfig =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk.mode, config.mode);
+        std::fs::remove_file(&path).ok();
+        on_disk.mode
+    }
+
+    #[tokio::test]
+    async fn temporary_mode_is_not_persisted() {
+        for vfio_save in [false, true] {
+            for to in [GfxMode::Hybrid, GfxMode::Integrated, GfxMode::Vfio] {
+                let name = format!("tmp-mode-{to}-{vfio_save}.json");
+                let persisted = switch_and_read_persisted(&name, to, vfio_save).await;
+                if to == GfxMode::Vfio && !vfio_save {
+                    assert_eq!(persisted, GfxMode::Integrated, "{to} save={vfio_save}");
+                } else {
+                    assert_eq!(persisted, to, "{to} save={vfio_save}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn leaving_temporary_mode_clears_it() {
+        let mut ctrl = mock_controller(GfxMode::Integrated);
+        ctrl.config.lock().await.vfio_enable = true;
+        ctrl.start_switch(
+            GfxMode::Vfio,
+            UserActionRequired::Nothing,
+            vec![StagedAction::KillAmd],
+            Actor::Daemon,
+        )
+        .await
+        .await
+        .unwrap();
+        assert_eq!(ctrl.get_status().await.mode, GfxMode::Vfio);
+        assert_eq!(ctrl.get_persistent_mode().await, GfxMode::Integrated);
+
+        ctrl.start_switch(
+            GfxMode::Hybrid,
+            UserActionRequired::Nothing,
+            vec![StagedAction::KillAmd],
+            Actor::Daemon,
+        )
+        .await
+        .await
+        .unwrap();
+        let config = ctrl.config.lock().await;
+        assert_eq!(config.tmp_mode, None);
+        assert_eq!(config.effective_mode(), GfxMode::Hybrid);
+        assert_eq!(config.mode, GfxMode::Hybrid);
+    }
+
+    /// Switch `ctrl` to `mode`, persisted or only until reboot, and wait for it
+    async fn switch_persisting(ctrl: &mut CtrlGraphics, mode: GfxMode, persist: bool) {
+        ctrl.start_switch_with(
+            mode,
+            UserActionRequired::Nothing,
+            vec![StagedAction::KillAmd],
+            Actor::Daemon,
+            persist,
+        )
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn switch_until_reboot_keeps_the_persisted_mode() {
+        assert!(SetModeOptions::default().persist);
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-until-reboot",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tmp_path = dir.join("tmp_mode");
+        let mut ctrl = mock_controller(GfxMode::Integrated);
+        {
+            let mut config = ctrl.config.lock().await;
+            config.config_path = dir.join("config.json").to_string_lossy().to_string();
+            config.vfio_enable = true;
+            config.keep_tmp_mode_at(&tmp_path);
+        }
+
+        switch_persisting(&mut ctrl, GfxMode::Hybrid, false).await;
+        assert_eq!(ctrl.get_status().await.mode, GfxMode::Hybrid);
+        assert_eq!(ctrl.get_persistent_mode().await, GfxMode::Integrated);
+        assert_eq!(std::fs::read_to_string(&tmp_path).unwrap(), "Hybrid");
+
+        // Asking for the mode in use with persist saves it
+        ctrl.persist_current_mode(GfxMode::Hybrid, &Actor::Daemon)
+            .await
+            .unwrap();
+        assert_eq!(ctrl.config.lock().await.tmp_mode, None);
+        assert_eq!(ctrl.get_persistent_mode().await, GfxMode::Hybrid);
+        assert!(!tmp_path.exists());
+
+        // Vfio without vfio_save stays temporary however it is asked for
+        switch_persisting(&mut ctrl, GfxMode::Integrated, false).await;
+        switch_persisting(&mut ctrl, GfxMode::Vfio, true).await;
+        ctrl.persist_current_mode(GfxMode::Vfio, &Actor::Daemon)
+            .await
+            .unwrap();
+        {
+            let config = ctrl.config.lock().await;
+            assert_eq!(config.tmp_mode, Some(GfxMode::Vfio));
+            assert_eq!(config.mode, GfxMode::Hybrid);
+        }
+
+        // A later persisted switch clears the temporary mode
+        switch_persisting(&mut ctrl, GfxMode::Integrated, true).await;
+        let config = ctrl.config.lock().await;
+        assert_eq!(config.tmp_mode, None);
+        assert_eq!(config.mode, GfxMode::Integrated);
+        assert!(!tmp_path.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn switch_advisory_lists_dgpu_outputs_for_dgpu_off_modes() {
+        let outputs = || vec!["HDMI-A-1".to_string()];
+        for mode in [GfxMode::Integrated, GfxMode::Vfio, GfxMode::AsusEgpu] {
+            let advisory = SwitchAdvisory::for_switch(mode, outputs());
+            assert_eq!(advisory.outputs_that_will_turn_off, outputs());
+            assert!(SwitchAdvisory::for_switch(mode, Vec::new()).is_empty());
+        }
+        for mode in [
+            GfxMode::Hybrid,
+            GfxMode::NvidiaNoModeset,
+            GfxMode::AsusMuxDgpu,
+            GfxMode::None,
+        ] {
+            assert!(SwitchAdvisory::for_switch(mode, outputs()).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn switch_advisory_without_dgpu_device_is_empty() {
+        let ctrl = mock_controller(GfxMode::Hybrid);
+        assert!(ctrl
+            .get_switch_advisory(GfxMode::Integrated)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn debug_run_blocks_mutation() {
+        for allow_mutation in [false, true] {
+            let path = std::env::temp_dir().join(format!(
+                "supergfxctl-test-{}-debug-{allow_mutation}.json",
+                std::process::id()
+            ));
+            std::fs::remove_file(&path).ok();
+            let config = GfxConfig::new(path.to_string_lossy().to_string());
+            let mut ctrl = CtrlGraphics::from_dgpu(
+                Arc::new(Mutex::new(config)),
+                DiscreetGpu::mock(GfxVendor::Nvidia),
+            );
+            ctrl.set_debug_run(DebugRun { allow_mutation });
+
+            let locked = ctrl.set_mode_locked(true, &Actor::Daemon).await;
+            if allow_mutation {
+                locked.unwrap();
+                assert!(path.exists());
+                std::fs::remove_file(&path).ok();
+                continue;
+            }
+
+            assert!(matches!(locked, Err(GfxError::DebugMode)));
+            assert!(matches!(
+                ctrl.set_gfx_mode(GfxMode::Integrated).await,
+                Err(GfxError::DebugMode)
+            ));
+            assert!(matches!(
+                ctrl.try_recover_dgpu().await,
+                Err(GfxError::DebugMode)
+            ));
+            assert!(matches!(
+                ctrl.reload().await.unwrap(),
+                BootOutcome::Skipped(GfxMode::Hybrid, _)
+            ));
+
+            // Nothing was written or started
+            assert!(!path.exists());
+            assert!(!ctrl.get_mode_locked().await);
+            assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+            assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+            assert_eq!(ctrl.get_persistent_mode().await, GfxMode::Hybrid);
+        }
+    }
+    /// Graphical sessions which never end
+    struct OpenSessions(Vec<&'static str>);
+
+    impl SessionProbe for OpenSessions {
+        fn graphical_sessions(&self) -> BoxFuture<'_, Result<Vec<String>, GfxError>> {
+            Box::pin(async move { Ok(self.0.iter().map(|id| id.to_string()).collect()) })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn confirmed_logout_expires() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let (action, handle) = ctrl
+            .switch_after_logout_with(
+                GfxMode::Integrated,
+                "2".to_string(),
+                Arc::new(OpenSessions(vec!["2"])),
+                Duration::from_secs(5),
+                &Actor::Daemon,
+            )
+            .await
+            .unwrap();
+        assert_eq!(action, UserActionRequired::Logout);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Integrated);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Switching);
+
+        // The logout was cancelled, the session is still open after the window
+        handle.unwrap().await.unwrap();
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(
+            ctrl.get_pending_user_action().await,
+            UserActionRequired::Nothing
+        );
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+        // Too late to cancel, nothing is pending
+        assert!(matches!(
+            ctrl.cancel_pending_switch().await,
+            Err(GfxError::NoSwitchPending)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn confirmed_logout_cancelled() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let (_, handle) = ctrl
+            .switch_after_logout_with(
+                GfxMode::Integrated,
+                "2".to_string(),
+                Arc::new(OpenSessions(vec!["2", "5"])),
+                Duration::from_secs(60),
+                &Actor::Daemon,
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        ctrl.cancel_pending_switch().await.unwrap();
+        let start = tokio::time::Instant::now();
+        handle.unwrap().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_switch_drops_the_pending_one() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        let (_, first) = ctrl
+            .switch_after_logout_with(
+                GfxMode::Integrated,
+                "2".to_string(),
+                Arc::new(OpenSessions(vec!["2"])),
+                Duration::from_secs(60),
+                &Actor::Daemon,
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let start = tokio::time::Instant::now();
+        let second = ctrl
+            .start_switch(
+                GfxMode::Vfio,
+                UserActionRequired::Nothing,
+                countdown_plan(60),
+                Actor::Daemon,
+            )
+            .await;
+        // The waiting switch ended before the new one was pending, never getting as far as
+        // stopping the display manager
+        first.unwrap().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::Vfio);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Switching);
+
+        ctrl.cancel_pending_switch().await.unwrap();
+        second.await.unwrap();
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+    }
+
+    #[tokio::test]
+    async fn confirmed_logout_needs_graphical_session() {
+        let mut ctrl = mock_controller(GfxMode::Hybrid);
+        // Called from a tty or ssh, the session would never be seen to end
+        assert!(matches!(
+            ctrl.switch_after_logout_with(
+                GfxMode::Integrated,
+                "3".to_string(),
+                Arc::new(OpenSessions(vec!["2"])),
+                Duration::from_secs(60),
+                &Actor::Daemon,
+            )
+            .await,
+            Err(GfxError::NotSupported(_))
+        ));
+        assert_eq!(ctrl.get_pending_mode().await, GfxMode::None);
+
+        // Validated like any other switch
+        ctrl.config.lock().await.mode_locked = true;
+        assert!(matches!(
+            ctrl.switch_after_logout_with(
+                GfxMode::Integrated,
+                "2".to_string(),
+                Arc::new(OpenSessions(vec!["2"])),
+                Duration::from_secs(60),
+                &Actor::Daemon,
+            )
+            .await,
+            Err(GfxError::ModeLocked(GfxMode::Hybrid))
+        ));
+
+        // Nothing to do, nothing is armed
+        ctrl.config.lock().await.mode_locked = false;
+        let (action, handle) = ctrl
+            .switch_after_logout_with(
+                GfxMode::Hybrid,
+                "2".to_string(),
+                Arc::new(OpenSessions(vec!["2"])),
+                Duration::from_secs(60),
+                &Actor::Daemon,
+            )
+            .await
+            .unwrap();
+        assert_eq!(action, UserActionRequired::Nothing);
+        assert!(handle.is_none());
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+    }
+
+    /// A controller whose config is in a missing directory, so it can't be written, with
+    /// an audit log to see what was reported
+    fn unwritable_controller(name: &str) -> (std::path::PathBuf, CtrlGraphics) {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-controller-{name}",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = GfxConfig::new(
+            dir.join("missing/config.json")
+                .to_string_lossy()
+                .to_string(),
+        );
+        let mut ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+        ctrl.set_audit_log(AuditLog::new(dir.join("audit.log"), 4096));
+        (dir, ctrl)
+    }
+
+    #[tokio::test]
+    async fn mode_lock_not_saved_is_reported() {
+        let (dir, mut ctrl) = unwritable_controller("lock-unsaved");
+        let err = ctrl
+            .set_mode_locked(true, &Actor::Daemon)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GfxError::ConfigNotPersisted(_)), "{err:?}");
+        // Applied all the same
+        assert!(ctrl.get_mode_locked().await);
+        assert!(!ctrl.get_status().await.config_not_saved.is_empty());
+
+        ctrl.config.lock().await.config_path =
+            dir.join("config.json").to_string_lossy().to_string();
+        ctrl.set_mode_locked(false, &Actor::Daemon).await.unwrap();
+        assert!(ctrl.get_status().await.config_not_saved.is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn switch_not_saved_is_reported() {
+        let (dir, mut ctrl) = unwritable_controller("switch-unsaved");
+        ctrl.start_switch(
+            GfxMode::Integrated,
+            UserActionRequired::Nothing,
+            vec![StagedAction::KillAmd],
+            Actor::Daemon,
+        )
+        .await
+        .await
+        .unwrap();
+
+        // The switch completed, only saving it failed
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Integrated);
+        assert_eq!(ctrl.get_switch_state().await, SwitchState::Idle);
+        let status = ctrl.get_status().await;
+        assert!(status.config_not_saved.contains("missing/config.json"));
+        let records = ctrl.audit.tail(10);
+        assert!(
+            records.iter().any(|r| r
+                .change
+                .starts_with("Mode Integrated applied but not persisted")),
+            "{records:?}"
+        );
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,174 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        device_busy::{busy_context_in, d_state_suspects_in, kmsg_tail_in, KMSG_TAIL_LINES},
+        error::{BusyDevice, GfxError},
+        tests::test_dir,
+    };
+
+    const ADDRESS: &str = "0000:01:00.0";
+
+    fn process(proc_root: &Path, pid: u32, comm: &str, state: char, wchan: &str, stack: &str) {
+        let dir = proc_root.join(pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("stat"),
+            format!("{pid} ({comm}) {state} 1 {pid} {pid} 0 -1 4194560 107 0 0 0\n"),
+        )
+        .unwrap();
+        fs::write(dir.join("wchan"), wchan).unwrap();
+        if !stack.is_empty() {
+            fs::write(dir.join("stack"), stack).unwrap();
+        }
+    }
+
+    fn soon() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    #[test]
+    fn d_state_tasks_on_the_device() {
+        let proc_root = test_dir("busy-proc");
+        process(&proc_root, 2200, "nvidia-smi", 'D', "rpm_resume", "");
+        // wchan is 0 without the permission to read it, the stack says where it sleeps
+        process(
+            &proc_root,
+            310,
+            "irq/88-nvidia",
+            'D',
+            "0",
+            "[<0>] __schedule+0x2d1/0x870\n[<0>] nvidia_dev_put+0x4c/0x90 [nvidia]\n",
+        );
+        process(&proc_root, 1, "systemd", 'S', "ep_poll", "");
+        // Sleeping on something else
+        process(&proc_root, 4100, "cp", 'D', "folio_wait_bit_common", "");
+        // Running, not stuck
+        process(&proc_root, 5000, "bash", 'R', "pci_device_remove", "");
+        // A name with spaces and parentheses of its own
+        process(
+            &proc_root,
+            6000,
+            "a (b) c",
+            'D',
+            "device_release_driver",
+            "",
+        );
+        fs::create_dir_all(proc_root.join("self")).unwrap();
+
+        assert_eq!(
+            d_state_suspects_in(&proc_root, ADDRESS, Some("nvidia"), soon()),
+            vec![
+                "pid 310 (irq/88-nvidia) in nvidia_dev_put",
+                "pid 2200 (nvidia-smi) in rpm_resume",
+                "pid 6000 (a (b) c) in device_release_driver",
+            ]
+        );
+        // Without a driver bound only the PCI and runtime PM functions name the device
+        assert_eq!(
+            d_state_suspects_in(&proc_root, ADDRESS, None, soon()),
+            vec![
+                "pid 2200 (nvidia-smi) in rpm_resume",
+                "pid 6000 (a (b) c) in device_release_driver",
+            ]
+        );
+        // Past the deadline nothing more is looked at
+        assert!(
+            d_state_suspects_in(&proc_root, ADDRESS, Some("nvidia"), Instant::now()).is_empty()
+        );
+        assert!(d_state_suspects_in(&proc_root.join("missing"), ADDRESS, None, soon()).is_empty());
+        fs::remove_dir_all(proc_root).ok();
+    }
+
+    #[test]
+    fn kmsg_tail_of_the_device() {
+        let dir = test_dir("busy-kmsg");
+        let kmsg = dir.join("kmsg");
+        let mut log = String::new();
+        for seq in 0..KMSG_TAIL_LINES + 5 {
+            log.push_str(&format!(
+                "6,{},{}000,-;nvidia {ADDRESS}: event {seq}\n",
+                seq * 2,
+                seq
+            ));
+            log.push_str(" SUBSYSTEM=pci\n DEVICE=+pci:0000:01:00.0\n");
+            log.push_str(&format!("6,{},{}001,-;usb 1-2: reset\n", seq * 2 + 1, seq));
+        }
+        // The last record cut short of its newline
+        log.push_str(&format!(
+            "3,99,99000,-;pcieport 0000:00:01.0: {ADDRESS} gone"
+        ));
+        fs::write(&kmsg, log).unwrap();
+
+        let tail = kmsg_tail_in(&kmsg, ADDRESS, soon());
+        assert_eq!(tail.len(), KMSG_TAIL_LINES);
+        assert_eq!(tail[0], format!("nvidia {ADDRESS}: event 6"));
+        assert_eq!(
+            tail[KMSG_TAIL_LINES - 2],
+            format!("nvidia {ADDRESS}: event 24")
+        );
+        assert_eq!(
+            tail[KMSG_TAIL_LINES - 1],
+            format!("pcieport 0000:00:01.0: {ADDRESS} gone")
+        );
+
+        assert!(kmsg_tail_in(&kmsg, "0000:02:00.0", soon()).is_empty());
+        assert!(kmsg_tail_in(&kmsg, ADDRESS, Instant::now()).is_empty());
+        assert!(kmsg_tail_in(&dir.join("missing"), ADDRESS, soon()).is_empty());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn busy_error_rendered() {
+        let dir = test_dir("busy-context");
+        let proc_root = dir.join("proc");
+        process(&proc_root, 2200, "nvidia-smi", 'D', "rpm_resume", "");
+        fs::write(
+            dir.join("kmsg"),
+            format!("4,10,1000,-;NVRM: GPU at PCI:{ADDRESS}: GPU has fallen off the bus.\n"),
+        )
+        .unwrap();
+        let (suspects, kmsg_tail) = busy_context_in(
+            &proc_root,
+            &dir.join("kmsg"),
+            ADDRESS,
+            Some("nvidia"),
+            soon(),
+        );
+
+        let err = GfxError::DeviceBusy(Box::new(BusyDevice {
+            action: "unbind".to_string(),
+            device: ADDRESS.to_string(),
+            driver: Some("nvidia".to_string()),
+            runtime_status: Some("active".to_string()),
+            suspects,
+            kmsg_tail,
+            error: "Write /sys/bus/pci/drivers/nvidia/unbind: Device or resource busy".to_string(),
+        }));
+        assert_eq!(err.code(), "device-busy");
+        assert_eq!(
+            err.to_string(),
+            "Could not unbind 0000:01:00.0: Write /sys/bus/pci/drivers/nvidia/unbind: Device or resource busy. Bound to nvidia, runtime status active. Stuck on it: pid 2200 (nvidia-smi) in rpm_resume. Kernel log: NVRM: GPU at PCI:0000:01:00.0: GPU has fallen off the bus."
+        );
+
+        let err = GfxError::DeviceBusy(Box::new(BusyDevice {
+            action: "remove".to_string(),
+            device: ADDRESS.to_string(),
+            driver: None,
+            runtime_status: None,
+            suspects: Vec::new(),
+            kmsg_tail: Vec::new(),
+            error: "Write /sys/bus/pci/devices/0000:01:00.0/remove: Input/output error".to_string(),
+        }));
+        assert_eq!(
+            err.to_string(),
+            "Could not remove 0000:01:00.0: Write /sys/bus/pci/devices/0000:01:00.0/remove: Input/output error. No driver bound. No task is stuck on it"
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,243 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use futures_util::future::BoxFuture;
+
+    use crate::{
+        actions::StagedAction,
+        display_watchdog::{
+            console_message, enabled_connectors, failure_summary, watch_display, watched_step,
+            write_consoles, HealthEvidence, HealthProbe,
+        },
+        pci_device::GfxMode,
+        tests::test_dir,
+    };
+
+    fn healthy() -> HealthEvidence {
+        HealthEvidence {
+            display_manager_active: true,
+            graphical_sessions: vec!["c1".to_string()],
+            enabled_connectors: vec!["card1-eDP-1".to_string()],
+        }
+    }
+
+    /// Gives each evidence in turn, then the last one for good
+    struct MockProbe {
+        evidence: Mutex<Vec<HealthEvidence>>,
+        reads: AtomicUsize,
+    }
+
+    impl MockProbe {
+        fn new(mut evidence: Vec<HealthEvidence>) -> Self {
+            evidence.reverse();
+            Self {
+                evidence: Mutex::new(evidence),
+                reads: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl HealthProbe for MockProbe {
+        fn evidence(&self, with_sessions: bool) -> BoxFuture<'_, HealthEvidence> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let mut evidence = self.evidence.lock().unwrap();
+            let mut next = if evidence.len() > 1 {
+                evidence.pop().unwrap()
+            } else {
+                evidence[0].clone()
+            };
+            if !with_sessions {
+                next.graphical_sessions.clear();
+            }
+            Box::pin(async move { next })
+        }
+    }
+
+    #[test]
+    fn missing_evidence() {
+        let after = StagedAction::StartDisplayManager;
+        assert!(healthy().missing(&after).is_empty());
+        assert_eq!(
+            HealthEvidence::default().missing(&after),
+            [
+                "an active display-manager.service",
+                "a graphical login or greeter session",
+                "a display enabled on any DRM card"
+            ]
+        );
+        // A greeter is a session too, a display manager with no output isn't enough
+        let no_output = HealthEvidence {
+            enabled_connectors: Vec::new(),
+            ..healthy()
+        };
+        assert_eq!(
+            no_output.missing(&after),
+            ["a display enabled on any DRM card"]
+        );
+        // no_logind doesn't ask logind for sessions
+        let no_sessions = HealthEvidence {
+            graphical_sessions: Vec::new(),
+            ..healthy()
+        };
+        assert!(no_sessions.missing(&StagedAction::NoLogind).is_empty());
+        assert_eq!(
+            no_sessions.missing(&after),
+            ["a graphical login or greeter session"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_display() {
+        let poll = Duration::from_secs(2);
+        let probe = MockProbe::new(vec![
+            HealthEvidence::default(),
+            HealthEvidence {
+                display_manager_active: true,
+                ..Default::default()
+            },
+            healthy(),
+        ]);
+        let evidence = watch_display(
+            &probe,
+            &StagedAction::StartDisplayManager,
+            Duration::from_secs(60),
+            poll,
+        )
+        .await
+        .unwrap();
+        assert_eq!(evidence, healthy());
+        assert_eq!(probe.reads.load(Ordering::SeqCst), 3);
+
+        let probe = MockProbe::new(vec![HealthEvidence {
+            display_manager_active: true,
+            ..Default::default()
+        }]);
+        let last = watch_display(
+            &probe,
+            &StagedAction::StartDisplayManager,
+            Duration::from_secs(10),
+            poll,
+        )
+        .await
+        .unwrap_err();
+        assert!(last.display_manager_active);
+        // At 0, 2, 4, 6, 8 and 10s
+        assert_eq!(probe.reads.load(Ordering::SeqCst), 6);
+
+        // Without logind the sessions aren't needed
+        let probe = MockProbe::new(vec![healthy()]);
+        assert!(
+            watch_display(&probe, &StagedAction::NoLogind, Duration::ZERO, poll)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn watched_after() {
+        assert_eq!(
+            watched_step(&[
+                StagedAction::StopDisplayManager,
+                StagedAction::UnloadGpuDrivers,
+                StagedAction::StartDisplayManager,
+            ]),
+            Some(StagedAction::StartDisplayManager)
+        );
+        assert_eq!(
+            watched_step(&[StagedAction::WaitLogout, StagedAction::NoLogind]),
+            Some(StagedAction::NoLogind)
+        );
+        // Hotplug only switches leave the display alone
+        assert_eq!(
+            watched_step(&[StagedAction::HotplugUnplug, StagedAction::WriteModprobeConf]),
+            None
+        );
+    }
+
+    #[test]
+    fn message_formatting() {
+        let missing = HealthEvidence {
+            display_manager_active: true,
+            ..Default::default()
+        }
+        .missing(&StagedAction::StartDisplayManager);
+        let message = console_message(
+            GfxMode::Hybrid,
+            &StagedAction::StartDisplayManager,
+            None,
+            &missing,
+            Duration::from_secs(60),
+        );
+        assert!(message.contains("after switching to Hybrid."));
+        assert!(message.contains("All the steps of the switch succeeded."));
+        assert!(message.contains("started display-manager.service and waited 60s"));
+        assert!(message.contains("  - a graphical login or greeter session\n"));
+        assert!(message.contains("  - a display enabled on any DRM card\n"));
+        assert!(!message.contains("an active display-manager.service"));
+        assert!(message.contains("  supergfxctl --mode Integrated\n"));
+        assert!(message.contains("  journalctl -b -u supergfxd\n"));
+
+        let message = console_message(
+            GfxMode::Integrated,
+            &StagedAction::NoLogind,
+            Some(&StagedAction::UnloadGpuDrivers),
+            &["an active display-manager.service"],
+            Duration::from_secs(30),
+        );
+        assert!(message.contains("The switch failed at UnloadGpuDrivers"));
+        assert!(message.contains("finished the switch with no_logind set and waited 30s"));
+
+        assert_eq!(
+            failure_summary(
+                GfxMode::Hybrid,
+                &StagedAction::StartDisplayManager,
+                &missing
+            ),
+            "display watchdog: no working display after StartDisplayManager for Hybrid, missing a graphical login or greeter session, a display enabled on any DRM card"
+        );
+    }
+
+    #[test]
+    fn consoles_missing_or_unwritable() {
+        let dir = test_dir("display-watchdog-consoles");
+        fs::write(dir.join("tty1"), "").unwrap();
+        // tty2 is missing, tty3 can't be opened for writing
+        fs::create_dir(dir.join("tty3")).unwrap();
+        fs::write(dir.join("tty4"), "").unwrap();
+
+        let written = write_consoles(&dir, 4, "help\n");
+        assert_eq!(written, ["tty1", "tty4"]);
+        assert_eq!(fs::read_to_string(dir.join("tty1")).unwrap(), "help\n");
+        // Never created
+        assert!(!dir.join("tty2").exists());
+
+        assert!(write_consoles(&dir.join("missing"), 6, "help\n").is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn connectors_enabled() {
+        let dir = test_dir("display-watchdog-drm");
+        for (name, enabled) in [
+            ("card0-eDP-1", "enabled"),
+            ("card1-HDMI-A-1", "disabled"),
+            ("card1-DP-2", "enabled\n"),
+        ] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("enabled"), enabled).unwrap();
+        }
+        fs::create_dir_all(dir.join("card0")).unwrap();
+        fs::create_dir_all(dir.join("renderD128")).unwrap();
+        assert_eq!(enabled_connectors(&dir), ["card0-eDP-1", "card1-DP-2"]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(enabled_connectors(&dir).is_empty());
+    }
+}
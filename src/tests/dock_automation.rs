@@ -0,0 +1,288 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use futures_util::future::BoxFuture;
+    use tokio::time::Instant;
+
+    use crate::{
+        ac_automation::{AcContext, AcDecision, AcProbe, Debounce},
+        config::GfxConfig,
+        controller::SwitchState,
+        dock_automation::{
+            decide_dock, dock_state_in, parse_device_id, switch_busy, take_when_idle, DockProfile,
+            DockProfiles, DockState,
+        },
+        error::GfxError,
+        pci_device::GfxMode,
+    };
+
+    const HOLD: Duration = Duration::from_secs(5);
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-dock_automation-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        root
+    }
+
+    fn put(root: &std::path::Path, path: &str, value: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, value).unwrap();
+    }
+
+    fn profiles() -> DockProfiles {
+        DockProfiles {
+            devices: vec!["17ef:a396".to_string()],
+            docked: Some(DockProfile {
+                mode: GfxMode::Hybrid,
+                ac_automation: false,
+            }),
+            undocked: Some(DockProfile {
+                mode: GfxMode::Integrated,
+                ac_automation: true,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn device_ids() {
+        assert_eq!(parse_device_id("17ef:a396"), Ok((0x17ef, 0xa396)));
+        assert_eq!(parse_device_id("8086:15EF"), Ok((0x8086, 0x15ef)));
+        for bad in [
+            "",
+            "17ef",
+            "17ef:",
+            "17ef:a3966",
+            "0x17ef:a396",
+            "17ef-a396",
+            "zzzz:a396",
+        ] {
+            assert!(parse_device_id(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(profiles().validate().ok(), Some(()));
+        // Nothing set is fine
+        assert_eq!(DockProfiles::default().validate().ok(), Some(()));
+
+        let bad_id = DockProfiles {
+            devices: vec!["lenovo dock".to_string()],
+            ..profiles()
+        };
+        assert!(matches!(bad_id.validate(), Err(GfxError::DockProfiles(_))));
+        let nothing_to_detect = DockProfiles {
+            devices: Vec::new(),
+            ..profiles()
+        };
+        assert!(nothing_to_detect.validate().is_err());
+        let power_supply = DockProfiles {
+            power_supply: true,
+            ..nothing_to_detect
+        };
+        assert_eq!(power_supply.validate().ok(), Some(()));
+        let no_mode = DockProfiles {
+            undocked: Some(DockProfile {
+                mode: GfxMode::None,
+                ac_automation: true,
+            }),
+            ..profiles()
+        };
+        assert!(no_mode.validate().is_err());
+    }
+
+    #[test]
+    fn config_with_bad_profiles_is_not_used() {
+        let dir = root("config");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let mut json = serde_json::to_value(GfxConfig::new(String::new())).unwrap();
+        json["dock_profiles"] = serde_json::json!({
+            "devices": ["not an id"],
+            "docked": { "mode": "Hybrid" },
+        });
+        fs::write(&path, json.to_string()).unwrap();
+
+        let config = GfxConfig::peek(&path.to_string_lossy());
+        assert_eq!(config.dock_profiles, DockProfiles::default());
+
+        // A valid one loads, with ac_automation on by default
+        json["dock_profiles"]["devices"] = serde_json::json!(["17ef:a396"]);
+        fs::write(&path, json.to_string()).unwrap();
+        let config = GfxConfig::peek(&path.to_string_lossy());
+        assert_eq!(config.dock_profiles.devices, ["17ef:a396"]);
+        assert!(config.dock_profiles.docked.unwrap().ac_automation);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn detection() {
+        let root = root("detect");
+        let profiles = profiles();
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Undocked);
+
+        // Another USB device isn't the dock
+        put(&root, "sys/bus/usb/devices/1-1/idVendor", "046d\n");
+        put(&root, "sys/bus/usb/devices/1-1/idProduct", "c52b\n");
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Undocked);
+
+        put(&root, "sys/bus/usb/devices/3-2/idVendor", "17ef\n");
+        put(&root, "sys/bus/usb/devices/3-2/idProduct", "a396\n");
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Docked);
+        fs::remove_dir_all(root.join("sys/bus/usb/devices/3-2")).unwrap();
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Undocked);
+
+        // Thunderbolt ids are written with 0x
+        put(&root, "sys/bus/thunderbolt/devices/0-1/vendor", "0x17ef\n");
+        put(&root, "sys/bus/thunderbolt/devices/0-1/device", "0xa396\n");
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Docked);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn power_supply_detection() {
+        let root = root("power");
+        let profiles = DockProfiles {
+            devices: Vec::new(),
+            power_supply: true,
+            ..profiles()
+        };
+        put(&root, "sys/class/power_supply/AC/type", "Mains\n");
+        put(&root, "sys/class/power_supply/AC/online", "1\n");
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Undocked);
+
+        put(&root, "sys/class/power_supply/ucsi-dock-0/type", "USB\n");
+        put(&root, "sys/class/power_supply/ucsi-dock-0/online", "0\n");
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Undocked);
+        put(&root, "sys/class/power_supply/ucsi-dock-0/online", "1\n");
+        assert_eq!(dock_state_in(&root, &profiles), DockState::Docked);
+
+        // Not looked at unless set
+        let off = DockProfiles {
+            power_supply: false,
+            ..profiles.clone()
+        };
+        assert_eq!(dock_state_in(&root, &off), DockState::Undocked);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_ignores_a_flapping_dock() {
+        let mut debounce = Debounce::new(HOLD);
+        let start = Instant::now();
+        debounce.observe(DockState::Undocked, start, 0);
+        for i in 1..=6 {
+            let state = if i % 2 == 0 {
+                DockState::Undocked
+            } else {
+                DockState::Docked
+            };
+            debounce.observe(state, start + Duration::from_secs(i), 0);
+        }
+        assert_eq!(debounce.take_ready(start + HOLD * 3, 0), None);
+
+        let later = start + HOLD * 3;
+        debounce.observe(DockState::Docked, later, 0);
+        assert_eq!(
+            take_when_idle(&mut debounce, false, later + HOLD, 0),
+            Some(DockState::Docked)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_a_switch() {
+        let mut debounce = Debounce::new(HOLD);
+        let start = Instant::now();
+        debounce.observe(DockState::Undocked, start, 0);
+        debounce.observe(DockState::Docked, start, 0);
+        assert_eq!(take_when_idle(&mut debounce, true, start + HOLD, 0), None);
+        // Still there once the switch is done
+        assert_eq!(
+            take_when_idle(&mut debounce, false, start + HOLD * 2, 0),
+            Some(DockState::Docked)
+        );
+
+        let mut config = GfxConfig::new(String::new());
+        assert!(!switch_busy(&config));
+        config.pending_mode = Some(GfxMode::Integrated);
+        assert!(switch_busy(&config));
+        config.pending_mode = None;
+        config.switch_state = SwitchState::Switching;
+        assert!(switch_busy(&config));
+    }
+
+    struct Idle;
+
+    impl AcProbe for Idle {
+        fn sessions_active(&self) -> BoxFuture<'_, Result<bool, GfxError>> {
+            Box::pin(async { Ok(false) })
+        }
+
+        fn dgpu_users(&self) -> BoxFuture<'_, Vec<String>> {
+            Box::pin(async { Vec::new() })
+        }
+    }
+
+    fn context(mode: GfxMode) -> AcContext {
+        AcContext {
+            mode,
+            supported: vec![GfxMode::Hybrid, GfxMode::Integrated],
+            switching: false,
+            mode_locked: false,
+            always_reboot: false,
+            mutation_allowed: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn decisions() {
+        let profiles = profiles();
+        let ctx = context(GfxMode::Integrated);
+        assert_eq!(
+            decide_dock(&profiles, true, DockState::Docked, &ctx, &Idle).await,
+            AcDecision::Apply(GfxMode::Hybrid)
+        );
+        assert_eq!(
+            decide_dock(&profiles, false, DockState::Docked, &ctx, &Idle).await,
+            AcDecision::Suggest {
+                mode: GfxMode::Hybrid,
+                reason: "automatic switching is off".to_string()
+            }
+        );
+        assert_eq!(
+            decide_dock(&profiles, true, DockState::Undocked, &ctx, &Idle).await,
+            AcDecision::Nothing
+        );
+        let no_docked = DockProfiles {
+            docked: None,
+            ..profiles.clone()
+        };
+        assert_eq!(
+            decide_dock(&no_docked, true, DockState::Docked, &ctx, &Idle).await,
+            AcDecision::Nothing
+        );
+        let switching = AcContext {
+            switching: true,
+            ..ctx
+        };
+        assert!(matches!(
+            decide_dock(&profiles, true, DockState::Docked, &switching, &Idle).await,
+            AcDecision::Suggest { .. }
+        ));
+    }
+
+    #[test]
+    fn ac_automation_follows_the_profile() {
+        let profiles = profiles();
+        assert!(profiles.ac_automation_allowed(None));
+        assert!(!profiles.ac_automation_allowed(Some(DockState::Docked)));
+        assert!(profiles.ac_automation_allowed(Some(DockState::Undocked)));
+        assert!(DockProfiles::default().ac_automation_allowed(Some(DockState::Docked)));
+    }
+}
@@ -0,0 +1,134 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        audit::{Actor, AuditLog},
+        driver_override::{clear_stale_overrides, DriverOverrides},
+        pci_device::{Device, DiscreetGpu, GfxMode, GfxVendor},
+        tests::test_dir,
+    };
+
+    /// A function with a `driver_override` as the kernel shows it when unset
+    fn function(dir: &Path, name: &str, pci_id: &str) -> Device {
+        let dev_path = dir.join(name);
+        fs::create_dir_all(&dev_path).unwrap();
+        fs::write(dev_path.join("driver_override"), "(null)\n").unwrap();
+        Device::mock(name, GfxVendor::Nvidia, true)
+            .with_dev_path(&dev_path)
+            .with_pci_id(pci_id)
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let dir = test_dir("driver-override-round-trip");
+        let dev = function(&dir, "0000:01:00.0", "10de:2520");
+        assert_eq!(dev.driver_override(), None);
+        dev.set_driver_override(Some("vfio-pci")).unwrap();
+        assert_eq!(dev.driver_override().as_deref(), Some("vfio-pci"));
+        dev.set_driver_override(None).unwrap();
+        // The test file keeps the newline written, the kernel shows `(null)`
+        assert_eq!(dev.driver_override(), None);
+
+        let overrides = DriverOverrides::at(&dir.join("run/driver_overrides.json"));
+        let devices = vec![dev.clone()];
+        overrides.set(&dev, "vfio-pci", GfxMode::Vfio).unwrap();
+        assert_eq!(dev.driver_override().as_deref(), Some("vfio-pci"));
+        let registered = overrides.registered();
+        assert_eq!(registered.len(), 1);
+        assert_eq!(registered[0].pci_id, "10de:2520");
+        assert_eq!(registered[0].mode, GfxMode::Vfio);
+
+        // Still wanted in Vfio
+        assert!(overrides
+            .clear_for_mode(&devices, GfxMode::Vfio)
+            .unwrap()
+            .is_empty());
+        assert_eq!(dev.driver_override().as_deref(), Some("vfio-pci"));
+
+        let cleared = overrides.clear_for_mode(&devices, GfxMode::Hybrid).unwrap();
+        assert_eq!(cleared, registered);
+        assert_eq!(dev.driver_override(), None);
+        assert!(overrides.registered().is_empty());
+        assert!(!dir.join("run/driver_overrides.json").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_cleared_at_boot() {
+        let dir = test_dir("driver-override-boot");
+        let gpu = function(&dir, "0000:01:00.0", "10de:2520");
+        let audio = function(&dir, "0000:01:00.1", "10de:228e");
+        let registry = dir.join("driver_overrides.json");
+        // Left by the daemon before it was restarted
+        let before = DriverOverrides::at(&registry);
+        before.set(&gpu, "vfio-pci", GfxMode::Vfio).unwrap();
+        before.set(&audio, "vfio-pci", GfxMode::Vfio).unwrap();
+
+        let audit = AuditLog::new(dir.join("audit.log"), 4096);
+        let dgpu = DiscreetGpu::mock_devices(GfxVendor::Nvidia, 0, vec![gpu.clone(), audio], 0);
+        clear_stale_overrides(
+            &DriverOverrides::at(&registry),
+            &dgpu,
+            GfxMode::Hybrid,
+            &audit,
+            &Actor::Boot,
+        );
+        assert!(dgpu.devices().iter().all(|d| d.driver_override().is_none()));
+        assert!(!registry.exists());
+        let records = audit.tail(10);
+        assert_eq!(records.len(), 2);
+        assert!(records[0].change.contains("cleared, it was set for Vfio"));
+
+        // Nothing registered, nothing done
+        gpu.set_driver_override(Some("vfio-pci")).unwrap();
+        clear_stale_overrides(
+            &DriverOverrides::at(&registry),
+            &dgpu,
+            GfxMode::Hybrid,
+            &audit,
+            &Actor::Boot,
+        );
+        assert_eq!(gpu.driver_override().as_deref(), Some("vfio-pci"));
+        assert_eq!(audit.tail(10).len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn user_overrides_untouched() {
+        let dir = test_dir("driver-override-user");
+        let ours = function(&dir, "0000:01:00.0", "10de:2520");
+        let theirs = function(&dir, "0000:02:00.0", "8086:56a0");
+        let changed = function(&dir, "0000:03:00.0", "1002:73df");
+        let overrides = DriverOverrides::at(&dir.join("driver_overrides.json"));
+
+        theirs.set_driver_override(Some("vfio-pci")).unwrap();
+        overrides.set(&ours, "vfio-pci", GfxMode::Vfio).unwrap();
+        overrides.set(&changed, "vfio-pci", GfxMode::Vfio).unwrap();
+        // Changed by hand after supergfxd set it
+        changed.set_driver_override(Some("pci-stub")).unwrap();
+
+        let devices = vec![ours.clone(), theirs.clone(), changed.clone()];
+        let cleared = overrides.clear_for_mode(&devices, GfxMode::Hybrid).unwrap();
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].device, "0000:01:00.0");
+        assert_eq!(ours.driver_override(), None);
+        assert_eq!(theirs.driver_override().as_deref(), Some("vfio-pci"));
+        assert_eq!(changed.driver_override().as_deref(), Some("pci-stub"));
+        assert!(overrides.registered().is_empty());
+
+        // A different device at a registered address isn't ours either
+        overrides.set(&ours, "vfio-pci", GfxMode::Vfio).unwrap();
+        let replaced = function(&dir, "0000:01:00.0", "10de:28e0");
+        replaced.set_driver_override(Some("vfio-pci")).unwrap();
+        assert!(overrides
+            .clear_for_mode(std::slice::from_ref(&replaced), GfxMode::Hybrid)
+            .unwrap()
+            .is_empty());
+        assert_eq!(replaced.driver_override().as_deref(), Some("vfio-pci"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::process::ExitStatusExt,
+        process::{ExitStatus, Output},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    use crate::{
+        driver_retry::{driver_action_with_retry, proc_module_use, DriverCommand},
+        error::GfxError,
+        vfio::ModuleUse,
+        DriverAction,
+    };
+
+    const PROC_MODULES: &str = "\
+nvidia_uvm 1523712 0 - Live 0x0000000000000000 (POE)
+nvidia_drm 77824 4 - Live 0x0000000000000000 (POE)
+nvidia_modeset 1314816 3 nvidia_drm, Live 0x0000000000000000 (POE)
+nvidia 56799232 118 nvidia_uvm,nvidia_modeset, Live 0x0000000000000000 (POE)
+";
+
+    /// Fails with `stderr` until `failures` runs were made, counting them
+    struct MockCommand {
+        failures: u32,
+        stderr: &'static str,
+        runs: Mutex<u32>,
+    }
+
+    impl MockCommand {
+        fn new(failures: u32, stderr: &'static str) -> Self {
+            Self {
+                failures,
+                stderr,
+                runs: Mutex::new(0),
+            }
+        }
+
+        fn runs(&self) -> u32 {
+            *self.runs.lock().unwrap()
+        }
+    }
+
+    impl DriverCommand for MockCommand {
+        fn run(&self, _action: DriverAction, _driver: &str) -> std::io::Result<Output> {
+            let mut runs = self.runs.lock().unwrap();
+            *runs += 1;
+            let (code, stderr) = if *runs > self.failures {
+                (0, "")
+            } else {
+                (1, self.stderr)
+            };
+            Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: Vec::new(),
+                stderr: stderr.as_bytes().to_vec(),
+            })
+        }
+
+        fn proc_modules(&self) -> std::io::Result<String> {
+            Ok(PROC_MODULES.to_string())
+        }
+    }
+
+    const IN_USE: &str = "rmmod: ERROR: Module nvidia is in use by: nvidia_uvm nvidia_modeset\n";
+
+    #[test]
+    fn proc_modules_parsing() {
+        assert_eq!(
+            proc_module_use(PROC_MODULES, "nvidia"),
+            Some(ModuleUse {
+                refcnt: Some(118),
+                holders: vec!["nvidia_uvm".into(), "nvidia_modeset".into()],
+            })
+        );
+        assert_eq!(
+            proc_module_use(PROC_MODULES, "nvidia-drm"),
+            Some(ModuleUse {
+                refcnt: Some(4),
+                holders: Vec::new(),
+            })
+        );
+        assert_eq!(proc_module_use(PROC_MODULES, "nouveau"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff_until_it_passes() {
+        let cmd = MockCommand::new(3, IN_USE);
+        let start = tokio::time::Instant::now();
+        driver_action_with_retry(
+            &cmd,
+            "nvidia",
+            DriverAction::Remove,
+            5,
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cmd.runs(), 4);
+        assert_eq!(start.elapsed(), Duration::from_millis(200 + 400 + 800));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn last_failure_names_the_holders() {
+        let cmd = MockCommand::new(u32::MAX, IN_USE);
+        let start = tokio::time::Instant::now();
+        let err = driver_action_with_retry(
+            &cmd,
+            "nvidia",
+            DriverAction::Remove,
+            5,
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(cmd.runs(), 5);
+        assert_eq!(start.elapsed(), Duration::from_millis(3000));
+        match err {
+            GfxError::Modprobe(msg) => {
+                assert!(msg.contains("after 5 attempts"), "{msg}");
+                assert!(
+                    msg.ends_with("refcount 118, holders nvidia_uvm, nvidia_modeset"),
+                    "{msg}"
+                );
+            }
+            err => panic!("unexpected error {err}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn final_answers_are_not_retried() {
+        let cmd = MockCommand::new(1, "rmmod: ERROR: Module nvidia is not currently loaded\n");
+        driver_action_with_retry(
+            &cmd,
+            "nvidia",
+            DriverAction::Remove,
+            5,
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cmd.runs(), 1);
+
+        let cmd = MockCommand::new(1, "modprobe: FATAL: Module vfio-pci is builtin.\n");
+        let err = driver_action_with_retry(
+            &cmd,
+            "vfio-pci",
+            DriverAction::Load,
+            5,
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, GfxError::VfioBuiltin));
+        assert_eq!(cmd.runs(), 1);
+    }
+}
@@ -0,0 +1,149 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{controller::ModeProbe, egpu_watch::egpu_present_in, pci_device::GfxMode};
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-egpu_watch-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("sys/bus/pci/devices")).unwrap();
+        root
+    }
+
+    /// A PCI function at `path` under `sys/devices`, linked from `sys/bus/pci/devices`
+    /// if `linked`
+    fn function(root: &Path, path: &str, vendor: &str, class: &str, linked: bool) -> PathBuf {
+        let dir = root.join("sys/devices").join(path);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("vendor"), format!("{vendor}\n")).unwrap();
+        fs::write(dir.join("class"), format!("{class}\n")).unwrap();
+        if linked {
+            let name = dir.file_name().unwrap();
+            symlink(&dir, root.join("sys/bus/pci/devices").join(name)).unwrap();
+        }
+        dir
+    }
+
+    fn removable(dir: &Path) {
+        fs::write(dir.join("removable"), "removable\n").unwrap();
+    }
+
+    #[test]
+    fn internal_dgpu_is_not_an_egpu() {
+        let root = root("internal");
+        let dgpu = function(
+            &root,
+            "pci0000:00/0000:00:01.0/0000:01:00.0",
+            "0x10de",
+            "0x030000",
+            true,
+        );
+        fs::write(dgpu.join("removable"), "fixed\n").unwrap();
+        assert!(!egpu_present_in(&root));
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn enclosure_behind_removable_port() {
+        let root = root("enclosure");
+        // The Thunderbolt bridges of the enclosure are marked, not the GPU itself
+        let bridge = function(
+            &root,
+            "pci0000:00/0000:00:07.0/0000:05:00.0",
+            "0x8086",
+            "0x060400",
+            false,
+        );
+        function(
+            &root,
+            "pci0000:00/0000:00:07.0/0000:05:00.0/0000:06:00.0",
+            "0x1002",
+            "0x030000",
+            true,
+        );
+        assert!(!egpu_present_in(&root));
+        removable(&bridge);
+        assert!(egpu_present_in(&root));
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn only_gpus_count() {
+        let root = root("others");
+        // A USB controller of a dock, and the audio function of an eGPU whose GPU is gone
+        let usb = function(
+            &root,
+            "pci0000:00/0000:00:07.0/0000:07:00.0",
+            "0x8086",
+            "0x0c0330",
+            true,
+        );
+        removable(&usb);
+        let audio = function(
+            &root,
+            "pci0000:00/0000:00:07.0/0000:08:00.1",
+            "0x10de",
+            "0x040300",
+            true,
+        );
+        removable(&audio);
+        assert!(!egpu_present_in(&root));
+
+        let gpu = function(
+            &root,
+            "pci0000:00/0000:00:07.0/0000:08:00.0",
+            "0x10de",
+            "0x030200",
+            true,
+        );
+        removable(&gpu);
+        assert!(egpu_present_in(&root));
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn nothing_without_sysfs() {
+        assert!(!egpu_present_in(Path::new("/nonexistent")));
+    }
+
+    #[test]
+    fn asus_egpu_only_with_an_enclosure() {
+        let connected = ModeProbe {
+            dgpu_found: true,
+            asus_egpu_enable: true,
+            ..Default::default()
+        };
+        assert!(connected.supported_modes().contains(&GfxMode::AsusEgpu));
+        assert_eq!(connected.unsupported_reason(GfxMode::AsusEgpu), None);
+
+        let absent = ModeProbe {
+            egpu_absent: true,
+            ..connected
+        };
+        assert_eq!(
+            absent.supported_modes(),
+            [GfxMode::Integrated, GfxMode::Hybrid]
+        );
+        assert_eq!(
+            absent.unsupported_reason(GfxMode::AsusEgpu),
+            Some("No eGPU enclosure is connected")
+        );
+        // Without the ASUS eGPU port it is the port which is missing
+        let no_port = ModeProbe {
+            asus_egpu_enable: false,
+            ..absent
+        };
+        assert_eq!(
+            no_port.unsupported_reason(GfxMode::AsusEgpu),
+            Some("No ASUS eGPU port was found")
+        );
+    }
+}
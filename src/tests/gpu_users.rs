@@ -0,0 +1,244 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        error::GfxError,
+        gpu_users::{
+            blocking_processes_in, device_nodes_in, gpu_users_in, process_user_in,
+            render_blocking_processes, vfio_nodes_in, with_blocking_processes, BlockingProcess,
+            GpuUser,
+        },
+        tests::test_dir,
+    };
+
+    /// Add a process to a fake `/proc` with fds open on `targets`
+    fn fake_process(proc_root: &Path, pid: u32, comm: &str, targets: &[&str]) {
+        let dir = proc_root.join(pid.to_string());
+        fs::create_dir_all(dir.join("fd")).unwrap();
+        fs::write(dir.join("comm"), format!("{comm}\n")).unwrap();
+        for (fd, target) in targets.iter().enumerate() {
+            symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+        }
+    }
+
+    #[test]
+    fn finds_dgpu_nodes() {
+        let root = test_dir("gpu-nodes");
+        let dgpu = root.join("sys/0000:01:00.0");
+        let audio = root.join("sys/0000:01:00.1");
+        fs::create_dir_all(dgpu.join("drm/card1")).unwrap();
+        fs::create_dir_all(dgpu.join("drm/renderD129")).unwrap();
+        fs::create_dir_all(&audio).unwrap();
+        let dev = root.join("dev");
+        fs::create_dir_all(&dev).unwrap();
+        for node in ["nvidia0", "nvidiactl", "null"] {
+            fs::write(dev.join(node), "").unwrap();
+        }
+
+        let paths = vec![dgpu, audio];
+        assert_eq!(
+            device_nodes_in(&dev, &paths, false),
+            vec![dev.join("dri/card1"), dev.join("dri/renderD129")]
+        );
+        assert_eq!(
+            device_nodes_in(&dev, &paths, true),
+            vec![
+                dev.join("dri/card1"),
+                dev.join("dri/renderD129"),
+                dev.join("nvidia0"),
+                dev.join("nvidiactl"),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_vfio_nodes() {
+        let root = test_dir("vfio-nodes");
+        let sys = root.join("sys");
+        let groups = sys.join("kernel/iommu_groups");
+        let vfio_pci = sys.join("bus/pci/drivers/vfio-pci");
+        let nvidia = sys.join("bus/pci/drivers/nvidia");
+        for dir in [groups.join("14"), groups.join("15"), vfio_pci, nvidia] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let dgpu = sys.join("devices/0000:01:00.0");
+        let audio = sys.join("devices/0000:01:00.1");
+        let other = sys.join("devices/0000:02:00.0");
+        for (dev, driver, group) in [
+            (&dgpu, "vfio-pci", "14"),
+            (&audio, "vfio-pci", "14"),
+            (&other, "nvidia", "15"),
+        ] {
+            fs::create_dir_all(dev).unwrap();
+            symlink(sys.join("bus/pci/drivers").join(driver), dev.join("driver")).unwrap();
+            symlink(groups.join(group), dev.join("iommu_group")).unwrap();
+        }
+        // With iommufd the function also has a device node of its own
+        fs::create_dir_all(dgpu.join("vfio-dev/vfio0")).unwrap();
+        let dev = root.join("dev");
+
+        assert_eq!(
+            vfio_nodes_in(&dev, &[dgpu, audio, other.clone()]),
+            vec![dev.join("vfio/14"), dev.join("vfio/devices/vfio0")]
+        );
+        // Not bound to vfio-pci, nothing a VM can hold
+        assert!(vfio_nodes_in(&dev, &[other]).is_empty());
+
+        let proc_root = root.join("proc");
+        let group = dev.join("vfio/14");
+        fake_process(
+            &proc_root,
+            4242,
+            "qemu-system-x86",
+            &[group.to_str().unwrap()],
+        );
+        fake_process(&proc_root, 12, "firefox", &["/dev/dri/renderD128"]);
+        let users = gpu_users_in(&proc_root, &[group]);
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].to_string(), "qemu-system-x86 (4242)");
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn finds_processes_with_nodes_open() {
+        let proc_root = test_dir("gpu-users");
+        fake_process(
+            &proc_root,
+            40,
+            "blender",
+            &["/dev/null", "/dev/dri/renderD129"],
+        );
+        fake_process(&proc_root, 7, "nvidia-persistenced", &["/dev/nvidia0"]);
+        symlink("/opt/blender/blender (deleted)", proc_root.join("40/exe")).unwrap();
+        fake_process(&proc_root, 12, "firefox", &["/dev/dri/renderD128"]);
+        fake_process(&proc_root, 30, "nvidia-smi", &["/dev/nvidiactl"]);
+        fs::create_dir_all(proc_root.join("self")).unwrap();
+
+        let nodes = vec![
+            PathBuf::from("/dev/dri/renderD129"),
+            PathBuf::from("/dev/nvidia0"),
+            PathBuf::from("/dev/nvidiactl"),
+        ];
+        let users = gpu_users_in(&proc_root, &nodes);
+        assert_eq!(
+            users,
+            vec![
+                GpuUser {
+                    pid: 30,
+                    comm: "nvidia-smi".to_string(),
+                    exe: None,
+                },
+                GpuUser {
+                    pid: 40,
+                    comm: "blender".to_string(),
+                    exe: Some(PathBuf::from("/opt/blender/blender")),
+                },
+            ]
+        );
+        assert_eq!(users[1].to_string(), "blender (40)");
+        assert!(gpu_users_in(&proc_root, &[]).is_empty());
+    }
+
+    #[test]
+    fn finds_processes_with_nodes_mapped() {
+        let proc_root = test_dir("gpu-blockers");
+        fake_process(&proc_root, 7, "nvidia-powerd", &["/dev/nvidia0"]);
+        // The fd was closed but the node is still mapped
+        fake_process(&proc_root, 50, "steam", &["/dev/null"]);
+        fs::write(
+            proc_root.join("50/maps"),
+            "7f00a000-7f00b000 rw-s 00000000 00:05 1043                       /dev/nvidiactl\n\
+             7f00c000-7f00d000 r--p 00000000 103:02 2231                      /usr/lib/libc.so.6\n\
+             7f00e000-7f00f000 rw-p 00000000 00:00 0\n",
+        )
+        .unwrap();
+        fake_process(&proc_root, 60, "mpv", &["/dev/dri/renderD128"]);
+        fs::write(
+            proc_root.join("60/maps"),
+            "7f00a000-7f00b000 rw-s 00000000 00:05 1043 /dev/dri/renderD128\n",
+        )
+        .unwrap();
+
+        let nodes = vec![
+            PathBuf::from("/dev/nvidia0"),
+            PathBuf::from("/dev/nvidiactl"),
+        ];
+        let blockers = blocking_processes_in(&proc_root, &nodes);
+        // The services are included, they hold the module as much as anything else
+        assert_eq!(
+            blockers,
+            vec![
+                BlockingProcess {
+                    pid: 7,
+                    comm: "nvidia-powerd".to_string(),
+                    path: "/dev/nvidia0".to_string(),
+                },
+                BlockingProcess {
+                    pid: 50,
+                    comm: "steam".to_string(),
+                    path: "/dev/nvidiactl".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            gpu_users_in(&proc_root, &nodes)
+                .iter()
+                .map(|user| user.pid)
+                .collect::<Vec<_>>(),
+            vec![50]
+        );
+        assert!(render_blocking_processes(&blockers).contains("steam"));
+        assert_eq!(
+            render_blocking_processes(&[]),
+            "Nothing has the dGPU open\n"
+        );
+
+        let err = with_blocking_processes(
+            GfxError::Modprobe("rmmod nvidia failed after 5 attempts".to_string()),
+            &blockers,
+        );
+        assert_eq!(
+            err.to_string(),
+            "Modprobe error: rmmod nvidia failed after 5 attempts, held open by: \
+             nvidia-powerd (7) on /dev/nvidia0, steam (50) on /dev/nvidiactl"
+        );
+        assert!(matches!(
+            with_blocking_processes(GfxError::VfioBuiltin, &blockers),
+            GfxError::VfioBuiltin
+        ));
+        fs::remove_dir_all(proc_root).ok();
+    }
+
+    #[test]
+    fn process_user_names() {
+        let dir = test_dir("process-user");
+        let proc_root = dir.join("proc");
+        let passwd = dir.join("passwd");
+        fs::write(
+            &passwd,
+            "root:x:0:0:root:/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/zsh\n",
+        )
+        .unwrap();
+        for (pid, uid) in [(10, "1000"), (20, "1234")] {
+            let dir = proc_root.join(pid.to_string());
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join("status"),
+                format!("Name:\tfirefox\nUid:\t{uid}\t{uid}\t{uid}\t{uid}\n"),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(process_user_in(&proc_root, &passwd, 10), "alice");
+        // No name for the uid
+        assert_eq!(process_user_in(&proc_root, &passwd, 20), "1234");
+        // The process exited
+        assert_eq!(process_user_in(&proc_root, &passwd, 30), "");
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,143 @@
+#[cfg(test)]
+mod tests {
+    use crate::{
+        actions::StagedAction,
+        config::{GfxConfig, GfxConfigDbus},
+        error::GfxError,
+        hotplug_check::{
+            check_hotplug_type, check_requested_hotplug_type, loaded_hotplug_downgrade,
+            HotplugCheck, HotplugProbe,
+        },
+        pci_device::{GfxMode, GfxVendor, HotplugType},
+    };
+
+    /// What the probes find, `Err` for a failed probe
+    struct FakeProbe {
+        asus: Result<bool, String>,
+        slot: Result<bool, String>,
+    }
+
+    impl HotplugProbe for FakeProbe {
+        fn asus_dgpu_disable(&self) -> Result<bool, String> {
+            self.asus.clone()
+        }
+
+        fn slot_power(&self) -> Result<bool, String> {
+            self.slot.clone()
+        }
+    }
+
+    fn probe(asus: Result<bool, String>, slot: Result<bool, String>) -> FakeProbe {
+        FakeProbe { asus, slot }
+    }
+
+    #[test]
+    fn check_per_hotplug_type() {
+        let present = probe(Ok(true), Ok(true));
+        let absent = probe(Ok(false), Ok(false));
+        let failed = probe(Err("EIO".into()), Err("the dGPU wasn't found".into()));
+
+        for p in [&present, &absent, &failed] {
+            assert_eq!(
+                check_hotplug_type(HotplugType::None, p),
+                HotplugCheck::Usable
+            );
+        }
+
+        assert_eq!(
+            check_hotplug_type(HotplugType::Asus, &present),
+            HotplugCheck::Usable
+        );
+        assert!(matches!(
+            check_hotplug_type(HotplugType::Asus, &absent),
+            HotplugCheck::Unusable(reason) if reason.contains("dgpu_disable")
+        ));
+        assert_eq!(
+            check_hotplug_type(HotplugType::Asus, &failed),
+            HotplugCheck::Unknown("EIO".into())
+        );
+
+        assert_eq!(
+            check_hotplug_type(HotplugType::Std, &present),
+            HotplugCheck::Usable
+        );
+        assert!(matches!(
+            check_hotplug_type(HotplugType::Std, &absent),
+            HotplugCheck::Unusable(reason) if reason.contains("slot")
+        ));
+        assert!(matches!(
+            check_hotplug_type(HotplugType::Std, &failed),
+            HotplugCheck::Unknown(_)
+        ));
+
+        // Each type only looks at its own probe
+        let asus_only = probe(Ok(true), Ok(false));
+        assert_eq!(
+            check_hotplug_type(HotplugType::Asus, &asus_only),
+            HotplugCheck::Usable
+        );
+        assert!(matches!(
+            check_hotplug_type(HotplugType::Std, &asus_only),
+            HotplugCheck::Unusable(_)
+        ));
+    }
+
+    #[test]
+    fn dbus_change_refused_if_unusable() {
+        let absent = probe(Ok(false), Ok(false));
+        match check_requested_hotplug_type(HotplugType::Asus, &absent) {
+            Err(err @ GfxError::HotplugUnusable(HotplugType::Asus, _)) => {
+                assert!(err
+                    .to_string()
+                    .starts_with("hotplug_type Asus can't be used"))
+            }
+            res => panic!("expected HotplugUnusable, got {res:?}"),
+        }
+        assert!(check_requested_hotplug_type(HotplugType::Std, &absent).is_err());
+        assert!(check_requested_hotplug_type(HotplugType::None, &absent).is_ok());
+        // A failed probe doesn't stop it
+        let failed = probe(Err("EIO".into()), Err("EIO".into()));
+        assert!(check_requested_hotplug_type(HotplugType::Asus, &failed).is_ok());
+        assert!(
+            check_requested_hotplug_type(HotplugType::Std, &probe(Ok(false), Ok(true))).is_ok()
+        );
+    }
+
+    #[test]
+    fn load_downgrades_to_none() {
+        let mut config = GfxConfig::new(String::new());
+        config.hotplug_type = HotplugType::Asus;
+
+        // Kept on a failed probe
+        let failed = probe(Err("EIO".into()), Err("EIO".into()));
+        config.hotplug_downgrade = loaded_hotplug_downgrade(config.hotplug_type, &failed);
+        assert_eq!(config.effective_hotplug_type(), HotplugType::Asus);
+
+        config.hotplug_downgrade =
+            loaded_hotplug_downgrade(config.hotplug_type, &probe(Ok(false), Ok(true)));
+        let reason = config.hotplug_downgrade.clone().unwrap();
+        assert!(
+            reason.contains("hotplug_type Asus can't be used, using None"),
+            "{reason}"
+        );
+        assert_eq!(config.effective_hotplug_type(), HotplugType::None);
+        // The file keeps what was set
+        assert_eq!(config.hotplug_type, HotplugType::Asus);
+        let written: serde_json::Value = serde_json::to_value(&config).unwrap();
+        assert_eq!(written["hotplug_type"], "Asus");
+        assert!(written.get("hotplug_downgrade").is_none());
+        // config() reports the effective type
+        assert_eq!(GfxConfigDbus::from(&config).hotplug_type, HotplugType::None);
+
+        // The plans use it too
+        let actions =
+            StagedAction::action_list_for_boot(&config, GfxVendor::Nvidia, GfxMode::Integrated);
+        assert!(!actions.contains(&StagedAction::AsusDgpuDisable));
+        assert!(actions.contains(&StagedAction::DevTreeManaged));
+
+        config.hotplug_downgrade =
+            loaded_hotplug_downgrade(config.hotplug_type, &probe(Ok(true), Ok(false)));
+        assert_eq!(config.hotplug_downgrade, None);
+        assert_eq!(config.effective_hotplug_type(), HotplugType::Asus);
+    }
+}
@@ -0,0 +1,101 @@
+#[cfg(test)]
+mod tests {
+    use crate::inhibitors::{blocking_inhibitors, Inhibitor, InhibitorEffect};
+
+    fn inhibitor(what: &str, who: &str, why: &str, mode: &str) -> Inhibitor {
+        Inhibitor {
+            what: what.to_string(),
+            who: who.to_string(),
+            why: why.to_string(),
+            mode: mode.to_string(),
+            uid: 0,
+            pid: 1234,
+        }
+    }
+
+    #[test]
+    fn effect_decision_table() {
+        let cases = [
+            // A firmware flash must not be interrupted
+            (
+                inhibitor("shutdown:sleep", "fwupd", "Firmware update", "block"),
+                InhibitorEffect::Block,
+            ),
+            (
+                inhibitor("shutdown", "PackageKit", "Installing updates", "block"),
+                InhibitorEffect::Block,
+            ),
+            (
+                inhibitor("sleep", "NetworkManager", "Disconnect", "delay"),
+                InhibitorEffect::Delay,
+            ),
+            // Screen blanking locks held by players and browsers
+            (
+                inhibitor("idle", "Firefox", "Playing video", "block"),
+                InhibitorEffect::Ignore,
+            ),
+            (
+                inhibitor(
+                    "handle-power-key:handle-lid-switch",
+                    "systemd-logind",
+                    "",
+                    "block",
+                ),
+                InhibitorEffect::Ignore,
+            ),
+            // The session itself ends with the display manager
+            (
+                inhibitor("shutdown:sleep", "GNOME Shell", "", "block"),
+                InhibitorEffect::Ignore,
+            ),
+            (
+                inhibitor("sleep", "PowerDevil", "", "delay"),
+                InhibitorEffect::Ignore,
+            ),
+            (
+                inhibitor("shutdown", "supergfxd", "Switching", "block"),
+                InhibitorEffect::Ignore,
+            ),
+            (
+                inhibitor("shutdown", "example", "", "unknown"),
+                InhibitorEffect::Ignore,
+            ),
+        ];
+        for (inhibitor, effect) in cases {
+            assert_eq!(inhibitor.effect(), effect, "{inhibitor:?}");
+        }
+    }
+
+    #[test]
+    fn blocking_inhibitors_from_list() {
+        let list = [
+            inhibitor("shutdown:sleep", "fwupd", "Firmware update", "block"),
+            // fwupd takes one lock per device being flashed
+            inhibitor("shutdown:sleep", "fwupd", "Firmware update", "block"),
+            inhibitor("idle", "Firefox", "Playing video", "block"),
+            inhibitor("sleep", "gnome-settings-daemon", "", "delay"),
+        ];
+        let (blockers, delay) = blocking_inhibitors(&list);
+        assert_eq!(blockers, ["fwupd (Firmware update)"]);
+        assert!(!delay);
+
+        let list = [
+            inhibitor("sleep", "NetworkManager", "", "delay"),
+            inhibitor("idle", "Firefox", "Playing video", "block"),
+        ];
+        let (blockers, delay) = blocking_inhibitors(&list);
+        assert!(blockers.is_empty());
+        assert!(delay);
+
+        assert_eq!(blocking_inhibitors(&[]), (Vec::new(), false));
+    }
+
+    #[test]
+    fn describe_owner() {
+        assert_eq!(
+            inhibitor("shutdown", "fwupd", "Firmware update", "block").describe(),
+            "fwupd (Firmware update)"
+        );
+        assert_eq!(inhibitor("shutdown", "dnf", "", "block").describe(), "dnf");
+    }
+}
@@ -0,0 +1,224 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        initramfs::{
+            affects_early_boot, detect_in, dracut_embeds_modprobe, listing_contains,
+            mkinitcpio_embeds_modprobe, modprobe_directives, newest_image_in, InitramfsTool,
+            InitramfsWatch,
+        },
+        tests::test_dir,
+    };
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// As shipped by Fedora in `/usr/lib/dracut/dracut.conf.d/01-dist.conf`
+    const DRACUT_FEDORA: &str = r#"# dracut config file customized for RedHat/Fedora.
+
+# i18n
+i18n_vars="/etc/sysconfig/keyboard:KEYTABLE-KEYMAP /etc/sysconfig/i18n:SYSFONT-FONT"
+omit_dracutmodules+=" dash "
+stdloglvl=3
+hostonly="yes"
+hostonly_cmdline="no"
+"#;
+
+    /// The Arch Linux default `/etc/mkinitcpio.conf`, trimmed
+    const MKINITCPIO_ARCH: &str = r#"# vim:set ft=sh
+MODULES=()
+BINARIES=()
+FILES=()
+# HOOKS=(base udev autodetect modconf block filesystems fsck)
+HOOKS=(base udev autodetect microcode modconf kms keyboard keymap consolefont block filesystems fsck)
+#COMPRESSION="zstd"
+"#;
+
+    #[test]
+    fn dracut_confs() {
+        let confs =
+            |confs: &[&str]| -> Vec<String> { confs.iter().map(|conf| conf.to_string()).collect() };
+        assert!(dracut_embeds_modprobe(&confs(&[DRACUT_FEDORA])));
+        // hostonly is off unless set
+        assert!(!dracut_embeds_modprobe(&confs(&[])));
+        assert!(!dracut_embeds_modprobe(&confs(&[
+            DRACUT_FEDORA,
+            "hostonly=no\n"
+        ])));
+        // Without the kernel-modules module nothing from modprobe.d is installed
+        assert!(!dracut_embeds_modprobe(&confs(&[
+            DRACUT_FEDORA,
+            "omit_dracutmodules+=\" kernel-modules \"\n"
+        ])));
+        // Added by hand in a generic image
+        assert!(dracut_embeds_modprobe(&confs(&[
+            "hostonly=\"no\"\n",
+            "install_items+=\" /etc/modprobe.d/supergfxd.conf \"\n"
+        ])));
+        // Commented out settings are left alone
+        assert!(!dracut_embeds_modprobe(&confs(&["#hostonly=\"yes\"\n"])));
+    }
+
+    #[test]
+    fn mkinitcpio_confs() {
+        assert!(mkinitcpio_embeds_modprobe(&[MKINITCPIO_ARCH.to_string()]));
+        // An array split over lines, and the older string form
+        assert!(mkinitcpio_embeds_modprobe(&[
+            "HOOKS=(base\n  udev # devices\n  modconf\n  block)\n".to_string()
+        ]));
+        assert!(mkinitcpio_embeds_modprobe(&[
+            "HOOKS=\"base udev modconf block\"\n".to_string()
+        ]));
+        // A drop-in set later wins
+        assert!(!mkinitcpio_embeds_modprobe(&[
+            MKINITCPIO_ARCH.to_string(),
+            "HOOKS=(base systemd autodetect block filesystems)\n".to_string(),
+        ]));
+        assert!(!mkinitcpio_embeds_modprobe(&[]));
+    }
+
+    #[test]
+    fn detected() {
+        let root = test_dir("initramfs-detect");
+        assert_eq!(detect_in(&root), None);
+
+        write(&root, "usr/bin/dracut", "");
+        write(
+            &root,
+            "usr/lib/dracut/dracut.conf.d/01-dist.conf",
+            DRACUT_FEDORA,
+        );
+        assert_eq!(detect_in(&root), Some(InitramfsTool::Dracut));
+        write(
+            &root,
+            "etc/dracut.conf.d/90-generic.conf",
+            "hostonly=\"no\"\n",
+        );
+        assert_eq!(detect_in(&root), None);
+
+        // mkinitcpio with presets is the one in use
+        write(&root, "usr/bin/mkinitcpio", "");
+        write(&root, "etc/mkinitcpio.conf", MKINITCPIO_ARCH);
+        assert_eq!(detect_in(&root), None);
+        write(
+            &root,
+            "etc/mkinitcpio.d/linux.preset",
+            "PRESETS=('default')\n",
+        );
+        assert_eq!(detect_in(&root), Some(InitramfsTool::Mkinitcpio));
+        write(
+            &root,
+            "etc/mkinitcpio.conf.d/10-hooks.conf",
+            "HOOKS=(base udev block)\n",
+        );
+        assert_eq!(detect_in(&root), None);
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn listings() {
+        let lsinitrd = "Image: /boot/initramfs-6.8.5-301.fc40.x86_64.img: 38M
+========================================================================
+Version: dracut-102-2.fc40
+
+drwxr-xr-x  12 root     root            0 Apr 20 10:00 .
+-rw-r--r--   1 root     root          153 Apr 20 10:00 etc/modprobe.d/supergfxd.conf
+lrwxrwxrwx   1 root     root            7 Apr 20 10:00 bin -> usr/bin
+========================================================================
+";
+        assert!(listing_contains(lsinitrd, "etc/modprobe.d/supergfxd.conf"));
+        assert!(!listing_contains(lsinitrd, "etc/modprobe.d/nvidia.conf"));
+
+        let lsinitcpio = "./
+./etc/
+./etc/modprobe.d/
+./etc/modprobe.d/supergfxd.conf
+usr/lib/modules/6.8.7-arch1-1/kernel/nvidia.ko.zst
+";
+        assert!(listing_contains(
+            lsinitcpio,
+            "etc/modprobe.d/supergfxd.conf"
+        ));
+        assert!(listing_contains(
+            lsinitcpio,
+            "usr/lib/modules/6.8.7-arch1-1/kernel/nvidia.ko.zst"
+        ));
+        assert!(!listing_contains(lsinitcpio, "etc/modprobe.d"));
+    }
+
+    #[test]
+    fn early_boot_changes() {
+        let hybrid = b"# Automatically generated by supergfxd\noptions nvidia-drm modeset=1\n";
+        let integrated = b"blacklist nouveau\nblacklist nvidia\noptions nvidia-drm modeset=1\n";
+        assert_eq!(
+            modprobe_directives(integrated),
+            [
+                "blacklist nouveau",
+                "blacklist nvidia",
+                "options nvidia-drm modeset=1"
+            ]
+        );
+        assert!(affects_early_boot(Some(hybrid), Some(integrated)));
+        assert!(affects_early_boot(None, Some(integrated)));
+        // Comments and spacing don't matter to modprobe
+        assert!(!affects_early_boot(
+            Some(hybrid),
+            Some(b"options  nvidia-drm   modeset=1\n\n")
+        ));
+        assert!(!affects_early_boot(None, Some(b"# nothing\n")));
+    }
+
+    #[test]
+    fn mkinitcpio_image() {
+        let dir = test_dir("initramfs-images");
+        assert_eq!(newest_image_in(&dir), None);
+        fs::write(dir.join("initramfs-linux.img"), "").unwrap();
+        fs::write(dir.join("initramfs-linux-fallback.img"), "").unwrap();
+        fs::write(dir.join("vmlinuz-linux"), "").unwrap();
+        assert_eq!(newest_image_in(&dir), Some(dir.join("initramfs-linux.img")));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn advisory_kept() {
+        let dir = test_dir("initramfs-advisory");
+        let path = dir.join("state").join("initramfs_advisory.json");
+        let before: &[u8] = b"options nvidia-drm modeset=1\n";
+        let after: &[u8] = b"blacklist nvidia\noptions nvidia-drm modeset=1\n";
+
+        let mut watch = InitramfsWatch::new(path.clone(), Some(InitramfsTool::Dracut));
+        assert_eq!(
+            watch.regenerate_for(Some(before), Some(after)),
+            Some("dracut -f")
+        );
+        assert_eq!(watch.regenerate_for(Some(before), Some(before)), None);
+        assert!(!watch.conf_changed(Some(before), Some(before), 100));
+        assert!(watch.advisory().is_none());
+        assert!(watch.conf_changed(Some(before), Some(after), 100));
+        // Already raised, the first change is kept
+        assert!(!watch.conf_changed(Some(after), Some(before), 200));
+        assert_eq!(watch.advisory().unwrap().since, 100);
+        assert_eq!(
+            watch.advisory().unwrap().message(),
+            "initramfs regeneration recommended (dracut -f)"
+        );
+
+        // Outlasts a restart
+        let mut watch = InitramfsWatch::new(path.clone(), Some(InitramfsTool::Dracut));
+        assert_eq!(watch.advisory().unwrap().since, 100);
+        assert!(watch.clear());
+        assert!(!watch.clear());
+        assert!(!path.exists());
+        assert!(InitramfsWatch::new(path, None).advisory().is_none());
+
+        // No initramfs with a copy of the conf, nothing to remind
+        let mut watch = InitramfsWatch::disabled();
+        assert_eq!(watch.regenerate_for(Some(before), Some(after)), None);
+        assert!(!watch.conf_changed(Some(before), Some(after), 100));
+        fs::remove_dir_all(dir).ok();
+    }
+}
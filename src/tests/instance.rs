@@ -0,0 +1,131 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use futures_util::lock::Mutex;
+    use zbus::{connection::Builder, Connection, Guid};
+
+    use crate::{
+        config::GfxConfig,
+        controller::CtrlGraphics,
+        error::GfxError,
+        instance::{check_interface, serve_interface, InstanceInfo, InstanceLock},
+        pci_device::{DiscreetGpu, GfxVendor},
+        VERSION,
+    };
+
+    fn runtime_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn second_instance_refused() {
+        let dir = runtime_dir("instance");
+        let path = dir.join("supergfxd/instance.lock");
+        let first = InstanceLock::acquire_at(&path).unwrap();
+        let holder = InstanceInfo {
+            pid: std::process::id(),
+            switching: false,
+        };
+        match InstanceLock::acquire_at(&path) {
+            Err(GfxError::AlreadyRunning(info)) => assert_eq!(info, Some(holder)),
+            res => panic!("expected AlreadyRunning, got {res:?}"),
+        }
+
+        // The holder says it is mid switch, the second instance is told why it must wait
+        first.set_switching(true);
+        match InstanceLock::acquire_at(&path) {
+            Err(
+                err @ GfxError::AlreadyRunning(Some(InstanceInfo {
+                    switching: true, ..
+                })),
+            ) => assert!(err.to_string().contains("switching"), "{err}"),
+            res => panic!("expected AlreadyRunning while switching, got {res:?}"),
+        }
+        first.set_switching(false);
+
+        // Released when the first instance goes, and the file left behind doesn't matter
+        drop(first);
+        let second = InstanceLock::acquire_at(&path).unwrap();
+        assert_eq!(
+            InstanceInfo::parse(&fs::read_to_string(&path).unwrap()),
+            Some(holder)
+        );
+        drop(second);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn lock_info_parsed() {
+        assert_eq!(
+            InstanceInfo::parse("pid=42\nswitching=true\n"),
+            Some(InstanceInfo {
+                pid: 42,
+                switching: true
+            })
+        );
+        assert_eq!(
+            InstanceInfo::parse("pid=42\n").map(|i| i.switching),
+            Some(false)
+        );
+        // Written by an instance which died before writing
+        assert_eq!(InstanceInfo::parse(""), None);
+    }
+
+    fn ctrl() -> CtrlGraphics {
+        CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        )
+    }
+
+    /// The daemon and client ends of a peer to peer connection, in place of the bus
+    async fn connection_pair() -> (Connection, Connection) {
+        let (daemon, client) = tokio::net::UnixStream::pair().unwrap();
+        let guid = Guid::generate();
+        futures_util::try_join!(
+            Builder::unix_stream(daemon)
+                .server(guid)
+                .unwrap()
+                .p2p()
+                .build(),
+            Builder::unix_stream(client).p2p().build(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn served_interface_is_reachable() {
+        let (daemon, client) = connection_pair().await;
+        serve_interface(&daemon, ctrl()).await.unwrap();
+        assert_eq!(check_interface(&client, None).await.unwrap(), VERSION);
+    }
+
+    #[tokio::test]
+    async fn second_registration_refused() {
+        let (daemon, client) = connection_pair().await;
+        serve_interface(&daemon, ctrl()).await.unwrap();
+        match serve_interface(&daemon, ctrl()).await {
+            Err(GfxError::DbusInterface(detail)) => {
+                assert!(detail.contains("already served"), "{detail}")
+            }
+            res => panic!("expected DbusInterface, got {res:?}"),
+        }
+        // The first one is still served
+        assert!(check_interface(&client, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unserved_interface_fails_the_check() {
+        let (_daemon, client) = connection_pair().await;
+        match check_interface(&client, None).await {
+            Err(GfxError::DbusInterface(detail)) => {
+                assert!(detail.contains("can't be reached"), "{detail}")
+            }
+            res => panic!("expected DbusInterface, got {res:?}"),
+        }
+    }
+}
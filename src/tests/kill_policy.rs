@@ -0,0 +1,144 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        error::GfxError,
+        gpu_users::GpuUser,
+        kill_policy::{decide_kills, entry_matches, KillClass, KillPolicy},
+    };
+
+    fn user(pid: u32, comm: &str, exe: Option<&str>) -> GpuUser {
+        GpuUser {
+            pid,
+            comm: comm.to_string(),
+            exe: exe.map(PathBuf::from),
+        }
+    }
+
+    fn policy(never_kill: &[&str], kill_without_prompt: &[&str]) -> KillPolicy {
+        KillPolicy {
+            never_kill: never_kill.iter().map(|s| s.to_string()).collect(),
+            kill_without_prompt: kill_without_prompt.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn entries_match_exact_names() {
+        // comm is cut to 15 chars, the executable name isn't
+        let webhelper = user(
+            20,
+            "steamwebhelper",
+            Some("/home/a/.steam/ubuntu12_64/steamwebhelper"),
+        );
+        assert!(entry_matches("steamwebhelper", &webhelper));
+        assert!(!entry_matches("steam", &webhelper));
+        assert!(!entry_matches("webhelper", &webhelper));
+        assert!(!entry_matches("", &webhelper));
+
+        let sim = user(30, "simulation-run", Some("/opt/cfd/bin/simulation-runner"));
+        assert!(entry_matches("simulation-runner", &sim));
+        assert!(entry_matches("simulation-run", &sim));
+
+        // An absolute path only matches the whole executable path
+        assert!(entry_matches("/opt/cfd/bin/simulation-runner", &sim));
+        assert!(!entry_matches("/opt/cfd/bin", &sim));
+        assert!(!entry_matches("/usr/bin/simulation-runner", &sim));
+        assert!(!entry_matches(
+            "/opt/cfd/bin/simulation-runner",
+            &user(31, "simulation-run", None)
+        ));
+    }
+
+    #[test]
+    fn classify_processes() {
+        let policy = policy(&["code", "/opt/cfd/bin/sim"], &["steamwebhelper", "code"]);
+        assert_eq!(
+            policy.classify(&user(1, "code", Some("/usr/share/code/code"))),
+            KillClass::NeverKill
+        );
+        assert_eq!(
+            policy.classify(&user(2, "sim", Some("/opt/cfd/bin/sim"))),
+            KillClass::NeverKill
+        );
+        // Same name, another executable
+        assert_eq!(
+            policy.classify(&user(3, "sim", Some("/tmp/sim"))),
+            KillClass::Default
+        );
+        assert_eq!(
+            policy.classify(&user(4, "steamwebhelper", None)),
+            KillClass::KillWithoutPrompt
+        );
+        assert_eq!(
+            policy.classify(&user(5, "blender", None)),
+            KillClass::Default
+        );
+    }
+
+    #[test]
+    fn decide_per_class() {
+        let policy = policy(&[], &["xdg-desktop-portal-gnome"]);
+        let users = vec![
+            user(
+                10,
+                "xdg-desktop-por",
+                Some("/usr/libexec/xdg-desktop-portal-gnome"),
+            ),
+            user(11, "blender", None),
+        ];
+
+        let kills = |kill_default| -> Vec<(u32, KillClass, bool)> {
+            decide_kills(&policy, &users, kill_default)
+                .unwrap()
+                .into_iter()
+                .map(|d| (d.user.pid, d.class, d.kill))
+                .collect()
+        };
+        assert_eq!(
+            kills(true),
+            vec![
+                (10, KillClass::KillWithoutPrompt, true),
+                (11, KillClass::Default, true),
+            ]
+        );
+        // KillAmd only kills the listed ones
+        assert_eq!(
+            kills(false),
+            vec![
+                (10, KillClass::KillWithoutPrompt, true),
+                (11, KillClass::Default, false),
+            ]
+        );
+        assert!(decide_kills(&policy, &[], true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn protected_process_fails_switch() {
+        let policy = policy(&["code"], &["steamwebhelper"]);
+        let users = vec![
+            user(7, "steamwebhelper", None),
+            user(8, "code", None),
+            user(9, "blender", None),
+        ];
+        match decide_kills(&policy, &users, true) {
+            Err(err @ GfxError::ProtectedGpuUsers(..)) => {
+                if let GfxError::ProtectedGpuUsers(protected, others) = &err {
+                    assert_eq!(protected, &vec!["code (8)".to_string()]);
+                    assert_eq!(
+                        others,
+                        &vec![
+                            "steamwebhelper (7) (kill_without_prompt)".to_string(),
+                            "blender (9) (default)".to_string(),
+                        ]
+                    );
+                }
+                let msg = err.to_string();
+                assert!(msg.contains("never_kill"), "{msg}");
+                assert!(msg.contains("code (8)"), "{msg}");
+                assert!(msg.contains("nothing was killed"), "{msg}");
+            }
+            res => panic!("expected ProtectedGpuUsers, got {res:?}"),
+        }
+    }
+}
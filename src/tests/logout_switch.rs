@@ -0,0 +1,354 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicU8, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use futures_util::future::BoxFuture;
+    use logind_zbus::session::{SessionClass, SessionState, SessionType};
+
+    use crate::{
+        actions::StagedAction,
+        error::GfxError,
+        gpu_users::GpuUser,
+        logout_switch::{
+            after_session_end, logout_seconds_left, session_blocks_logout, wait_logout,
+            wait_session_end, DgpuHolder, HolderProbe, LogindSession, LogoutPolicy,
+            LogoutTimeoutAction, SessionEnd, SessionProbe, CONFIRM_LOGOUT_WINDOW,
+        },
+        switch_plan::{SWITCH_CANCELLABLE, SWITCH_CANCELLED},
+    };
+
+    /// Hands out the graphical sessions in turn, the last for every call after
+    struct MockLogind {
+        polls: Mutex<Vec<Vec<&'static str>>>,
+        calls: Mutex<u32>,
+    }
+
+    impl MockLogind {
+        fn new(polls: Vec<Vec<&'static str>>) -> Self {
+            Self {
+                polls: Mutex::new(polls),
+                calls: Mutex::new(0),
+            }
+        }
+
+        fn calls(&self) -> u32 {
+            *self.calls.lock().unwrap()
+        }
+    }
+
+    impl SessionProbe for MockLogind {
+        fn graphical_sessions(&self) -> BoxFuture<'_, Result<Vec<String>, GfxError>> {
+            Box::pin(async move {
+                *self.calls.lock().unwrap() += 1;
+                let mut polls = self.polls.lock().unwrap();
+                let sessions = if polls.len() > 1 {
+                    polls.remove(0)
+                } else {
+                    polls[0].clone()
+                };
+                Ok(sessions.iter().map(|id| id.to_string()).collect())
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn confirm_then_logout() {
+        let logind = MockLogind::new(vec![vec!["2"], vec!["2"], vec![]]);
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let end = wait_session_end(&logind, "2", CONFIRM_LOGOUT_WINDOW, &token)
+            .await
+            .unwrap();
+        assert_eq!(end, SessionEnd::Ended { others: false });
+        assert_eq!(logind.calls(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closing_session_has_ended() {
+        // logind lists a session being logged out of as closing, which isn't graphical
+        // and online
+        let logind = MockLogind::new(vec![vec!["2", "c1"], vec!["c1"]]);
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let end = wait_session_end(&logind, "2", CONFIRM_LOGOUT_WINDOW, &token)
+            .await
+            .unwrap();
+        // The greeter or another user is still on, the switch waits for them as usual
+        assert_eq!(end, SessionEnd::Ended { others: true });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn confirm_then_cancel() {
+        let logind = MockLogind::new(vec![vec!["2"]]);
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let start = tokio::time::Instant::now();
+        let end = wait_session_end(&logind, "2", Duration::from_secs(5), &token)
+            .await
+            .unwrap();
+        assert_eq!(end, SessionEnd::Expired);
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_while_armed() {
+        let logind = MockLogind::new(vec![vec!["2"]]);
+        let token = AtomicU8::new(SWITCH_CANCELLED);
+        let end = wait_session_end(&logind, "2", CONFIRM_LOGOUT_WINDOW, &token)
+            .await
+            .unwrap();
+        assert_eq!(end, SessionEnd::Cancelled);
+        assert_eq!(logind.calls(), 0);
+        assert_eq!(token.load(Ordering::Acquire), SWITCH_CANCELLED);
+    }
+
+    #[test]
+    fn logout_wait_skipped_for_last_session() {
+        let actions = vec![
+            StagedAction::WaitLogout,
+            StagedAction::StopDisplayManager,
+            StagedAction::UnloadGpuDrivers,
+            StagedAction::StartDisplayManager,
+        ];
+        assert_eq!(
+            after_session_end(actions.clone(), SessionEnd::Ended { others: false }),
+            actions[1..]
+        );
+        // Multi-session, falls back to waiting for the others
+        assert_eq!(
+            after_session_end(actions.clone(), SessionEnd::Ended { others: true }),
+            actions
+        );
+    }
+
+    /// The processes with the dGPU open, and their sessions
+    struct MockHolders(Vec<(GpuUser, Option<&'static str>)>);
+
+    impl HolderProbe for MockHolders {
+        fn dgpu_holders(&self) -> BoxFuture<'_, Result<Vec<DgpuHolder>, GfxError>> {
+            Box::pin(async move {
+                Ok(self
+                    .0
+                    .iter()
+                    .map(|(user, session)| (user.clone(), session.map(|s| s.to_string())))
+                    .collect())
+            })
+        }
+    }
+
+    fn holder(
+        pid: u32,
+        comm: &str,
+        session: Option<&'static str>,
+    ) -> (GpuUser, Option<&'static str>) {
+        let user = GpuUser {
+            pid,
+            comm: comm.to_string(),
+            exe: None,
+        };
+        (user, session)
+    }
+
+    /// Wait for logout with `action` taken after 10s, returning the result and what was
+    /// reported
+    async fn wait_with(
+        logind: &MockLogind,
+        holders: &MockHolders,
+        action: LogoutTimeoutAction,
+    ) -> (Result<(), GfxError>, String) {
+        let policy = LogoutPolicy {
+            timeout_s: 10,
+            action,
+        };
+        let report = futures_util::lock::Mutex::new(String::new());
+        let res = wait_logout(
+            logind,
+            holders,
+            policy,
+            &AtomicBool::new(false),
+            &report,
+            None,
+        )
+        .await;
+        (res, report.into_inner())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logout_before_timeout() {
+        let logind = MockLogind::new(vec![vec!["2"], vec!["2"], vec![]]);
+        let holders = MockHolders(vec![holder(40, "firefox", Some("2"))]);
+        for action in [
+            LogoutTimeoutAction::Fail,
+            LogoutTimeoutAction::ConvertToDeferred,
+            LogoutTimeoutAction::ForceIfIdle,
+        ] {
+            let (res, report) = wait_with(&logind, &holders, action).await;
+            assert!(res.is_ok(), "{action:?}");
+            assert_eq!(report, "", "{action:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logout_timeout_fails() {
+        let logind = MockLogind::new(vec![vec!["2", "5"]]);
+        let start = tokio::time::Instant::now();
+        let (res, report) =
+            wait_with(&logind, &MockHolders(vec![]), LogoutTimeoutAction::Fail).await;
+        assert!(start.elapsed() > Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(11));
+        match res {
+            Err(GfxError::LogoutTimeout(message)) => assert_eq!(message, report),
+            res => panic!("{res:?}"),
+        }
+        assert_eq!(
+            report,
+            "logout_timeout_s (10s) passed with sessions 2, 5 still open, logout_timeout_action Fail: the switch was dropped"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logout_timeout_deferred() {
+        // Still open a minute after the timeout
+        let mut polls = vec![vec!["2"]; 4 * 70];
+        polls.push(vec![]);
+        let logind = MockLogind::new(polls);
+        let (res, report) = wait_with(
+            &logind,
+            &MockHolders(vec![]),
+            LogoutTimeoutAction::ConvertToDeferred,
+        )
+        .await;
+        assert!(res.is_ok());
+        assert_eq!(logind.calls(), 4 * 70 + 1);
+        assert!(report.ends_with("ConvertToDeferred: the switch waits until they end"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logout_timeout_deferred_cancelled() {
+        let logind = MockLogind::new(vec![vec!["2"]]);
+        let holders = MockHolders(vec![]);
+        let loop_exit = std::sync::Arc::new(AtomicBool::new(false));
+        let report = futures_util::lock::Mutex::new(String::new());
+        let policy = LogoutPolicy {
+            timeout_s: 10,
+            action: LogoutTimeoutAction::ConvertToDeferred,
+        };
+        let cancel = {
+            let loop_exit = loop_exit.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(600)).await;
+                loop_exit.store(true, Ordering::Release);
+            }
+        };
+        let (res, _) = tokio::join!(
+            wait_logout(&logind, &holders, policy, &loop_exit, &report, None),
+            cancel
+        );
+        assert!(res.is_ok());
+        assert!(report.into_inner().contains("ConvertToDeferred"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logout_timeout_forced_when_idle() {
+        let logind = MockLogind::new(vec![vec!["2"]]);
+        // A system service, and a process in a session which has since ended
+        let holders = MockHolders(vec![
+            holder(12, "ollama", None),
+            holder(40, "firefox", Some("3")),
+        ]);
+        let (res, report) = wait_with(&logind, &holders, LogoutTimeoutAction::ForceIfIdle).await;
+        assert!(res.is_ok());
+        assert!(report
+            .ends_with("ForceIfIdle: none of them have the dGPU open, switching without waiting"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logout_timeout_refused_when_holding() {
+        let logind = MockLogind::new(vec![vec!["2", "5"]]);
+        let holders = MockHolders(vec![
+            holder(12, "ollama", None),
+            holder(40, "firefox", Some("5")),
+        ]);
+        let (res, report) = wait_with(&logind, &holders, LogoutTimeoutAction::ForceIfIdle).await;
+        assert!(matches!(res, Err(GfxError::LogoutTimeout(_))));
+        assert!(report.ends_with(
+            "ForceIfIdle: the switch was dropped as they have the dGPU open: firefox (40) in session 5"
+        ));
+    }
+
+    fn session(
+        kind: Option<SessionType>,
+        class: Option<SessionClass>,
+        state: SessionState,
+        desktop: &str,
+    ) -> LogindSession {
+        LogindSession {
+            id: "2".to_string(),
+            kind,
+            class,
+            state: Some(state),
+            desktop: desktop.to_string(),
+        }
+    }
+
+    #[test]
+    fn graphical_sessions_block_logout() {
+        let user = Some(SessionClass::User);
+        for kind in [SessionType::X11, SessionType::Wayland, SessionType::MIR] {
+            assert!(session_blocks_logout(&session(
+                Some(kind),
+                user,
+                SessionState::Active,
+                ""
+            )));
+        }
+        // On another VT
+        let online = session(Some(SessionType::Wayland), user, SessionState::Online, "");
+        assert!(session_blocks_logout(&online));
+        // A console login
+        let tty = session(Some(SessionType::TTY), user, SessionState::Active, "");
+        assert!(!session_blocks_logout(&tty));
+        let closing = session(Some(SessionType::X11), user, SessionState::Closing, "");
+        assert!(!session_blocks_logout(&closing));
+        let unknown_state = LogindSession {
+            state: None,
+            ..online
+        };
+        assert!(!session_blocks_logout(&unknown_state));
+    }
+
+    #[test]
+    fn greetd_sway_session_blocks_logout() {
+        // greetd registers the session as tty, and newer logind has classes logind-zbus
+        // can't read
+        let sway = session(Some(SessionType::TTY), None, SessionState::Active, "sway");
+        assert!(session_blocks_logout(&sway));
+        let unspecified = session(None, None, SessionState::Active, "sway");
+        assert!(session_blocks_logout(&unspecified));
+    }
+
+    #[test]
+    fn display_manager_sessions_dont_block_logout() {
+        for class in [SessionClass::Greeter, SessionClass::LockScreen] {
+            let greeter = session(
+                Some(SessionType::Wayland),
+                Some(class),
+                SessionState::Active,
+                "gnome-shell",
+            );
+            assert!(!session_blocks_logout(&greeter));
+        }
+    }
+
+    #[test]
+    fn logout_countdown() {
+        assert_eq!(logout_seconds_left(180, Duration::ZERO), 180);
+        assert_eq!(logout_seconds_left(180, Duration::from_millis(10_250)), 170);
+        assert_eq!(logout_seconds_left(180, Duration::from_secs(180)), 0);
+        assert_eq!(logout_seconds_left(180, Duration::from_secs(200)), 0);
+    }
+}
@@ -0,0 +1,352 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        config::GfxConfig,
+        error::GfxError,
+        migrate::{
+            apply_import, config_diff, latest_backup, map_mode, parse_ini, plan_import,
+            read_envycontrol, read_optimus_manager, render_plan, undo_import, ForeignTool,
+            ImportAction, UnitControl,
+        },
+        pci_device::GfxMode,
+        CONFIG_PATH,
+    };
+
+    /// A root directory laid out as the system was left by a tool
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/tests/fixtures/migrate")
+            .join(name)
+    }
+
+    fn paths(files: &[&str]) -> Vec<PathBuf> {
+        files.iter().map(PathBuf::from).collect()
+    }
+
+    /// The default config at `CONFIG_PATH`
+    fn default_config() -> GfxConfig {
+        GfxConfig::new(CONFIG_PATH.to_string())
+    }
+
+    /// Where `CONFIG_PATH` is under `root`
+    fn config_path_in(root: &Path) -> String {
+        root.join(CONFIG_PATH.trim_start_matches('/'))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Copy `from` to `to`, keeping symlinks as they are
+    fn copy_tree(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            let kind = entry.file_type().unwrap();
+            if kind.is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(entry.path()).unwrap(), target).unwrap();
+            } else if kind.is_dir() {
+                copy_tree(&entry.path(), &target);
+            } else {
+                fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    /// Records what the import asks of systemctl
+    #[derive(Default)]
+    struct RecordingUnits {
+        calls: RefCell<Vec<(String, bool)>>,
+    }
+
+    impl UnitControl for RecordingUnits {
+        fn set_enabled(&self, unit: &str, enabled: bool) -> Result<(), GfxError> {
+            self.calls.borrow_mut().push((unit.to_string(), enabled));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mode_mapping() {
+        assert_eq!(
+            map_mode("integrated", None),
+            Some((GfxMode::Integrated, None))
+        );
+        assert_eq!(
+            map_mode("Intel", Some(true)),
+            Some((GfxMode::Integrated, None))
+        );
+        assert_eq!(
+            map_mode("hybrid", Some(true)),
+            Some((GfxMode::Hybrid, None))
+        );
+        assert_eq!(
+            map_mode("hybrid", Some(false)),
+            Some((GfxMode::NvidiaNoModeset, None))
+        );
+        let (mode, note) = map_mode("nvidia", None).unwrap();
+        assert_eq!(mode, GfxMode::Hybrid);
+        assert!(note.unwrap().contains("without a MUX"));
+        assert_eq!(
+            map_mode("nvidia", Some(false)).unwrap().0,
+            GfxMode::NvidiaNoModeset
+        );
+        assert_eq!(map_mode("auto", None), None);
+    }
+
+    #[test]
+    fn ini_settings() {
+        let settings = parse_ini(
+            "# comment\n[Optimus]\nStartup_Mode = hybrid\n; other\n[nvidia]\nmodeset=no\noptions=\nmodeset=yes\n",
+        );
+        let get = |s: &str, k: &str| settings.get(&(s.to_string(), k.to_string())).cloned();
+        assert_eq!(get("optimus", "startup_mode").as_deref(), Some("hybrid"));
+        assert_eq!(get("nvidia", "modeset").as_deref(), Some("yes"));
+        assert_eq!(get("nvidia", "options").as_deref(), Some(""));
+        assert_eq!(settings.len(), 3);
+    }
+
+    #[test]
+    fn envycontrol_integrated() {
+        let found = read_envycontrol(&fixture("envycontrol-integrated"));
+        assert_eq!(found.mode.as_deref(), Some("integrated"));
+        assert_eq!(
+            found.files,
+            paths(&[
+                "/etc/modprobe.d/blacklist-nvidia.conf",
+                "/lib/udev/rules.d/50-remove-nvidia.rules"
+            ])
+        );
+        // Written by hand, so it is never touched
+        assert_eq!(found.unrecognised, paths(&["/etc/X11/xorg.conf"]));
+        assert!(found.units.is_empty());
+    }
+
+    #[test]
+    fn envycontrol_nvidia() {
+        let found = read_envycontrol(&fixture("envycontrol-nvidia"));
+        assert_eq!(found.mode.as_deref(), Some("nvidia"));
+        assert_eq!(found.modeset, Some(true));
+        assert_eq!(
+            found.files,
+            paths(&[
+                "/etc/X11/xorg.conf",
+                "/etc/modprobe.d/nvidia.conf",
+                "/etc/lightdm/lightdm.conf.d/20-nvidia.conf"
+            ])
+        );
+        assert_eq!(found.unmapped.len(), 2, "{:?}", found.unmapped);
+        assert!(found.unmapped[0].contains("Option \"ForceCompositionPipeline\" \"On\""));
+        assert!(found.unmapped[1].contains("ForceFullCompositionPipeline"));
+    }
+
+    #[test]
+    fn envycontrol_hybrid_rtd3() {
+        let found = read_envycontrol(&fixture("envycontrol-hybrid-rtd3"));
+        assert_eq!(found.mode.as_deref(), Some("hybrid"));
+        assert_eq!(found.unmapped.len(), 1);
+        assert!(found.unmapped[0].contains("NVreg_DynamicPowerManagement"));
+    }
+
+    #[test]
+    fn optimus_manager_user_config_over_defaults() {
+        let found = read_optimus_manager(&fixture("optimus-manager"));
+        assert_eq!(found.mode.as_deref(), Some("hybrid"));
+        assert_eq!(found.modeset, Some(false));
+        assert_eq!(found.auto_modes, None);
+        assert_eq!(
+            found.files,
+            paths(&[
+                "/etc/optimus-manager/optimus-manager.conf",
+                "/etc/X11/xorg.conf.d/10-optimus-manager.conf"
+            ])
+        );
+        assert_eq!(found.units, ["optimus-manager.service"]);
+        assert_eq!(found.unmapped.len(), 2, "{:?}", found.unmapped);
+        assert!(found.unmapped[0].starts_with("[optimus] switching=bbswitch"));
+        assert!(found.unmapped[1].starts_with("[nvidia] options=overclocking"));
+    }
+
+    #[test]
+    fn optimus_manager_auto() {
+        let root = fixture("optimus-manager-auto");
+        let found = read_optimus_manager(&root);
+        assert_eq!(found.mode.as_deref(), Some("auto"));
+        assert_eq!(
+            found.auto_modes,
+            Some(("integrated".to_string(), "hybrid".to_string()))
+        );
+        // The user cleared options, the unit isn't enabled
+        assert!(found.unmapped.is_empty(), "{:?}", found.unmapped);
+        assert!(found.units.is_empty());
+
+        let plan = plan_import(ForeignTool::OptimusManager, &root, default_config()).unwrap();
+        assert_eq!(plan.config.mode, GfxMode::Integrated);
+        assert_eq!(
+            plan.config.ac_automation.on_battery,
+            Some(GfxMode::Integrated)
+        );
+        assert_eq!(plan.config.ac_automation.on_ac, Some(GfxMode::Hybrid));
+        assert!(plan.notes[0].contains("ac_automation"));
+    }
+
+    #[test]
+    fn nothing_to_import() {
+        let root = fixture("optimus-manager");
+        assert!(matches!(
+            plan_import(ForeignTool::EnvyControl, &root, default_config()),
+            Err(GfxError::Import(_))
+        ));
+        assert!("bumblebee".parse::<ForeignTool>().is_err());
+        assert_eq!(
+            "Optimus-Manager".parse::<ForeignTool>().unwrap(),
+            ForeignTool::OptimusManager
+        );
+    }
+
+    #[test]
+    fn plan_shows_diff_and_actions() {
+        let root = fixture("envycontrol-nvidia");
+        let current = default_config();
+        let plan = plan_import(ForeignTool::EnvyControl, &root, current.clone()).unwrap();
+        assert_eq!(plan.config.mode, GfxMode::Hybrid);
+        assert_eq!(
+            config_diff(&current, &plan.config),
+            Vec::<String>::new(),
+            "Hybrid is already the default"
+        );
+        assert_eq!(
+            plan.actions,
+            vec![
+                // The config doesn't exist yet
+                ImportAction::WriteConfig(PathBuf::from(&current.config_path)),
+                ImportAction::MoveAside("/etc/X11/xorg.conf".into()),
+                ImportAction::MoveAside("/etc/modprobe.d/nvidia.conf".into()),
+                ImportAction::MoveAside("/etc/lightdm/lightdm.conf.d/20-nvidia.conf".into()),
+            ]
+        );
+        assert!(plan.notes.iter().any(|n| n.contains("without a MUX")));
+        assert!(plan.notes.iter().any(|n| n.contains("initramfs")));
+
+        let root = fixture("envycontrol-integrated");
+        let current = default_config();
+        let plan = plan_import(ForeignTool::EnvyControl, &root, current.clone()).unwrap();
+        assert_eq!(
+            config_diff(&current, &plan.config),
+            ["- \"mode\": \"Hybrid\"", "+ \"mode\": \"Integrated\""]
+        );
+        let rendered = render_plan(&plan);
+        assert!(rendered.starts_with("Found envycontrol, boot mode integrated\n"));
+        assert!(rendered.contains("+ \"mode\": \"Integrated\"\n"));
+        assert!(rendered.contains("/etc/X11/xorg.conf is left alone"));
+        assert!(rendered.contains("  move /etc/modprobe.d/blacklist-nvidia.conf to the backup\n"));
+    }
+
+    #[test]
+    fn apply_and_undo() {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-import-root",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        copy_tree(&fixture("optimus-manager"), &root);
+        let mut current = default_config();
+        current.vfio_enable = true;
+        let mut on_disk = current.clone();
+        on_disk.config_path = config_path_in(&root);
+        fs::create_dir_all(root.join("etc/supergfxd")).unwrap();
+        on_disk.write_atomic().unwrap();
+        let before = fs::read_to_string(&on_disk.config_path).unwrap();
+
+        let plan = plan_import(ForeignTool::OptimusManager, &root, current.clone()).unwrap();
+        assert_eq!(
+            plan.actions,
+            vec![
+                ImportAction::WriteConfig(PathBuf::from(&current.config_path)),
+                ImportAction::MoveAside("/etc/optimus-manager/optimus-manager.conf".into()),
+                ImportAction::MoveAside("/etc/X11/xorg.conf.d/10-optimus-manager.conf".into()),
+                ImportAction::DisableUnit("optimus-manager.service".to_string()),
+            ]
+        );
+
+        let backups = root.join("var/lib/supergfxd/import-backup");
+        let backup = backups.join("1700000000");
+        let units = RecordingUnits::default();
+        let done = apply_import(&plan, &root, &backup, &units).unwrap();
+        assert_eq!(done.len(), 4);
+        assert_eq!(
+            *units.calls.borrow(),
+            [("optimus-manager.service".to_string(), false)]
+        );
+        let written = GfxConfig::peek(&on_disk.config_path);
+        assert_eq!(written.mode, GfxMode::NvidiaNoModeset);
+        assert!(written.vfio_enable, "other settings are kept");
+        assert!(!root
+            .join("etc/optimus-manager/optimus-manager.conf")
+            .exists());
+        assert!(!root
+            .join("etc/X11/xorg.conf.d/10-optimus-manager.conf")
+            .exists());
+        // The defaults of the package are left alone
+        assert!(root.join("usr/share/optimus-manager.conf").exists());
+
+        assert_eq!(latest_backup(&backups), Some(backup.clone()));
+        let undone = undo_import(&root, &backup, &units).unwrap();
+        assert_eq!(undone.len(), 4);
+        assert_eq!(
+            units.calls.borrow().last(),
+            Some(&("optimus-manager.service".to_string(), true))
+        );
+        assert_eq!(fs::read_to_string(&on_disk.config_path).unwrap(), before);
+        for file in [
+            "etc/optimus-manager/optimus-manager.conf",
+            "etc/X11/xorg.conf.d/10-optimus-manager.conf",
+        ] {
+            assert_eq!(
+                fs::read(root.join(file)).unwrap(),
+                fs::read(fixture("optimus-manager").join(file)).unwrap()
+            );
+        }
+        // Undone only once
+        assert_eq!(latest_backup(&backups), None);
+        assert!(undo_import(&root, &backup, &units).is_err());
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn undo_keeps_recreated_files() {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-import-recreated",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        copy_tree(&fixture("envycontrol-integrated"), &root);
+        let current = default_config();
+        let plan = plan_import(ForeignTool::EnvyControl, &root, current.clone()).unwrap();
+        let backup = root.join("backup/1");
+        let units = RecordingUnits::default();
+        apply_import(&plan, &root, &backup, &units).unwrap();
+        let config_path = config_path_in(&root);
+        assert!(Path::new(&config_path).exists());
+
+        let blacklist = root.join("etc/modprobe.d/blacklist-nvidia.conf");
+        fs::write(&blacklist, "blacklist nouveau\n").unwrap();
+        let undone = undo_import(&root, &backup, &units).unwrap();
+        assert!(undone[0].starts_with("put back /lib/udev"), "{undone:?}");
+        assert!(undone[1].starts_with("leave /etc/modprobe.d/blacklist-nvidia.conf"));
+        assert_eq!(
+            fs::read_to_string(&blacklist).unwrap(),
+            "blacklist nouveau\n"
+        );
+        // There was no config before, so the written one is removed
+        assert_eq!(undone[2], format!("remove {CONFIG_PATH}"));
+        assert!(!Path::new(&config_path).exists());
+        assert!(units.calls.borrow().is_empty());
+        fs::remove_dir_all(&root).ok();
+    }
+}
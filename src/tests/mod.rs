@@ -1 +1,73 @@
+pub(crate) mod ac_automation;
+pub(crate) mod acpi_dgpu;
 pub(crate) mod actions;
+pub(crate) mod attention;
+pub(crate) mod audit;
+pub(crate) mod automation_inhibit;
+pub(crate) mod boot_context;
+pub(crate) mod buffers;
+pub(crate) mod build_info;
+pub(crate) mod bundle;
+pub(crate) mod cleanup;
+pub(crate) mod cli_error;
+pub(crate) mod cli_json;
+pub(crate) mod completions;
+pub(crate) mod config;
+pub(crate) mod config_preview;
+pub(crate) mod controller;
+pub(crate) mod device_busy;
+pub(crate) mod display_watchdog;
+pub(crate) mod dock_automation;
+pub(crate) mod driver_override;
+pub(crate) mod driver_retry;
+pub(crate) mod egpu_watch;
+pub(crate) mod gpu_users;
+pub(crate) mod hotplug_check;
+pub(crate) mod inhibitors;
+pub(crate) mod initramfs;
+pub(crate) mod instance;
+pub(crate) mod kill_policy;
+pub(crate) mod logout_switch;
+pub(crate) mod migrate;
+pub(crate) mod nvidia_nodes;
+pub(crate) mod pci_device;
+pub(crate) mod pci_link;
+pub(crate) mod pci_lock;
+pub(crate) mod power_blockers;
+pub(crate) mod power_semantics;
+pub(crate) mod power_watch;
+pub(crate) mod prime_env;
+pub(crate) mod runtime_pm_guard;
+pub(crate) mod sandbox;
+pub(crate) mod schedule;
+pub(crate) mod self_test;
+pub(crate) mod signal_counters;
+pub(crate) mod signal_filter;
+pub(crate) mod special_asus;
+pub(crate) mod special_vendor;
+pub(crate) mod staging;
+pub(crate) mod supervisor;
+pub(crate) mod switch_hooks;
+pub(crate) mod switch_plan;
+pub(crate) mod switch_readiness;
+pub(crate) mod switcheroo;
+pub(crate) mod sysfs;
+pub(crate) mod system_class;
+pub(crate) mod systemd_notify;
+pub(crate) mod thermal;
+pub(crate) mod timeout;
+pub(crate) mod unit_dropins;
+pub(crate) mod validate;
+pub(crate) mod verified_write;
+pub(crate) mod verify;
+pub(crate) mod vfio;
+pub(crate) mod zbus_iface;
+
+/// An empty directory for the test `name` in the temp dir, named after this process so test
+/// runs side by side don't share it
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("supergfxctl-test-{}-{name}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
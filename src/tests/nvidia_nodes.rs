@@ -0,0 +1,276 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        error::GfxError,
+        nvidia_nodes::{
+            char_major_in, check_nvidia_nodes, fix_nvidia_nodes, usable_mode, NodeFinding,
+            NodeFixer, NodeProblem,
+        },
+    };
+
+    fn test_dev(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxd-test-nvidia-nodes-{name}-{}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A node under the fake `dev`, a plain file as only root can make device nodes
+    fn node(dev: &Path, name: &str, mode: u32) {
+        let path = dev.join(name);
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn healthy(name: &str) -> PathBuf {
+        let dev = test_dev(name);
+        for name in ["nvidia0", "nvidiactl", "nvidia-modeset", "nvidia-uvm"] {
+            node(&dev, name, 0o666);
+        }
+        dev
+    }
+
+    #[test]
+    fn present() {
+        let dev = healthy("present");
+        assert!(check_nvidia_nodes(&dev, true).is_empty());
+        // Only the group may open them, such as video with NVreg_DeviceFileGID
+        for name in ["nvidia0", "nvidiactl", "nvidia-modeset"] {
+            node(&dev, name, 0o660);
+        }
+        assert!(check_nvidia_nodes(&dev, false).is_empty());
+        fs::remove_dir_all(&dev).ok();
+    }
+
+    #[test]
+    fn missing() {
+        let dev = healthy("missing");
+        fs::remove_file(dev.join("nvidiactl")).unwrap();
+        fs::remove_file(dev.join("nvidia-uvm")).unwrap();
+        assert_eq!(
+            check_nvidia_nodes(&dev, false),
+            [NodeFinding {
+                node: dev.join("nvidiactl"),
+                problem: NodeProblem::Missing,
+            }]
+        );
+        // The uvm node is only needed while nvidia_uvm is loaded
+        assert_eq!(
+            check_nvidia_nodes(&dev, true),
+            [
+                NodeFinding {
+                    node: dev.join("nvidiactl"),
+                    problem: NodeProblem::Missing,
+                },
+                NodeFinding {
+                    node: dev.join("nvidia-uvm"),
+                    problem: NodeProblem::Missing,
+                },
+            ]
+        );
+        // Nothing at all, the udev rules never ran
+        let empty = test_dev("empty");
+        assert_eq!(check_nvidia_nodes(&empty, false).len(), 3);
+        fs::remove_dir_all(&dev).ok();
+        fs::remove_dir_all(&empty).ok();
+    }
+
+    #[test]
+    fn wrong_permissions() {
+        let dev = healthy("permissions");
+        node(&dev, "nvidia0", 0o600);
+        node(&dev, "nvidia-modeset", 0o644);
+        assert_eq!(
+            check_nvidia_nodes(&dev, true),
+            [
+                NodeFinding {
+                    node: dev.join("nvidia0"),
+                    problem: NodeProblem::Permissions(0o600),
+                },
+                NodeFinding {
+                    node: dev.join("nvidia-modeset"),
+                    problem: NodeProblem::Permissions(0o644),
+                },
+            ]
+        );
+        assert!(usable_mode(0o666));
+        assert!(usable_mode(0o660));
+        assert!(usable_mode(0o606));
+        assert!(!usable_mode(0o640));
+        assert!(!usable_mode(0o604));
+        fs::remove_dir_all(&dev).ok();
+    }
+
+    #[test]
+    fn finding_text() {
+        let missing = NodeFinding {
+            node: "/dev/nvidiactl".into(),
+            problem: NodeProblem::Missing,
+        }
+        .to_string();
+        assert!(
+            missing.starts_with("/dev/nvidiactl is missing"),
+            "{missing}"
+        );
+        assert!(missing.contains("nvidia-utils"), "{missing}");
+        assert!(missing.contains("nvidia-modprobe"), "{missing}");
+
+        let permissions = NodeFinding {
+            node: "/dev/nvidia0".into(),
+            problem: NodeProblem::Permissions(0o600),
+        }
+        .to_string();
+        assert!(
+            permissions.starts_with("/dev/nvidia0 has mode 0600"),
+            "{permissions}"
+        );
+        assert!(
+            permissions.contains("NVreg_DeviceFileMode"),
+            "{permissions}"
+        );
+    }
+
+    #[test]
+    fn uvm_major() {
+        let devices = "Character devices:\n  1 mem\n195 nvidia\n195 nvidiactl\n\
+                       234 nvidia-uvm\n\nBlock devices:\n259 blkext\n235 nvidia-uvm\n";
+        assert_eq!(char_major_in(devices, "nvidia-uvm"), Some(234));
+        assert_eq!(char_major_in(devices, "nvidia"), Some(195));
+        assert_eq!(char_major_in(devices, "blkext"), None);
+        assert_eq!(char_major_in("", "nvidia-uvm"), None);
+    }
+
+    /// Makes the nodes as plain files under the fake `dev`, recording what it was asked
+    struct MockFixer {
+        dev: PathBuf,
+        /// nvidia-modprobe is installed
+        modprobe: bool,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl NodeFixer for MockFixer {
+        fn nvidia_modprobe(&self, uvm: bool) -> Result<bool, GfxError> {
+            if !self.modprobe {
+                return Ok(false);
+            }
+            self.calls
+                .borrow_mut()
+                .push(format!("nvidia-modprobe uvm={uvm}"));
+            for name in ["nvidia0", "nvidiactl", "nvidia-modeset"] {
+                node(&self.dev, name, 0o666);
+            }
+            Ok(true)
+        }
+
+        fn char_major(&self, name: &str) -> Option<u32> {
+            (name == "nvidia-uvm").then_some(234)
+        }
+
+        fn mknod(&self, node: &Path, major: u32, minor: u32) -> Result<(), GfxError> {
+            self.calls.borrow_mut().push(format!(
+                "mknod {} {major} {minor}",
+                node.file_name().unwrap().to_string_lossy()
+            ));
+            fs::write(node, "").map_err(|err| GfxError::Write(node.display().to_string(), err))
+        }
+
+        fn chmod(&self, node: &Path, mode: u32) -> Result<(), GfxError> {
+            fs::set_permissions(node, fs::Permissions::from_mode(mode))
+                .map_err(|err| GfxError::Write(node.display().to_string(), err))
+        }
+    }
+
+    #[test]
+    fn fixed_with_mknod() {
+        let dev = healthy("mknod");
+        for name in ["nvidia0", "nvidiactl", "nvidia-modeset", "nvidia-uvm"] {
+            fs::remove_file(dev.join(name)).unwrap();
+        }
+        node(&dev, "nvidiactl", 0o600);
+        let fixer = MockFixer {
+            dev: dev.clone(),
+            modprobe: false,
+            calls: RefCell::new(Vec::new()),
+        };
+        let findings = check_nvidia_nodes(&dev, true);
+        assert_eq!(findings.len(), 4);
+        let left = fix_nvidia_nodes(&fixer, &dev, true, findings);
+        assert!(left.is_empty(), "{left:?}");
+        // The numbers of the driver README, uvm from /proc/devices
+        assert_eq!(
+            *fixer.calls.borrow(),
+            [
+                "mknod nvidia0 195 0",
+                "mknod nvidia-modeset 195 254",
+                "mknod nvidia-uvm 234 0",
+            ]
+        );
+        let mode = fs::metadata(dev.join("nvidiactl"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o666);
+        fs::remove_dir_all(&dev).ok();
+    }
+
+    #[test]
+    fn fixed_with_nvidia_modprobe() {
+        let dev = healthy("modprobe");
+        fs::remove_file(dev.join("nvidia0")).unwrap();
+        fs::remove_file(dev.join("nvidia-uvm")).unwrap();
+        let fixer = MockFixer {
+            dev: dev.clone(),
+            modprobe: true,
+            calls: RefCell::new(Vec::new()),
+        };
+        let left = fix_nvidia_nodes(&fixer, &dev, true, check_nvidia_nodes(&dev, true));
+        assert!(left.is_empty(), "{left:?}");
+        // mknod only makes what nvidia-modprobe didn't
+        assert_eq!(
+            *fixer.calls.borrow(),
+            ["nvidia-modprobe uvm=true", "mknod nvidia-uvm 234 0"]
+        );
+
+        // Nothing is done when nothing is wrong
+        fixer.calls.borrow_mut().clear();
+        assert!(fix_nvidia_nodes(&fixer, &dev, true, Vec::new()).is_empty());
+        assert!(fixer.calls.borrow().is_empty());
+        fs::remove_dir_all(&dev).ok();
+    }
+
+    #[test]
+    fn what_cant_be_fixed_is_left() {
+        let dev = test_dev("unfixable");
+        struct Refusing;
+        impl NodeFixer for Refusing {
+            fn nvidia_modprobe(&self, _uvm: bool) -> Result<bool, GfxError> {
+                Err(GfxError::NvidiaNodes("exited with Some(1)".to_string()))
+            }
+            fn char_major(&self, _name: &str) -> Option<u32> {
+                None
+            }
+            fn mknod(&self, _node: &Path, _major: u32, _minor: u32) -> Result<(), GfxError> {
+                Err(GfxError::NvidiaNodes(
+                    "mknod exited with Some(1)".to_string(),
+                ))
+            }
+            fn chmod(&self, _node: &Path, _mode: u32) -> Result<(), GfxError> {
+                Ok(())
+            }
+        }
+        let findings = check_nvidia_nodes(&dev, false);
+        let left = fix_nvidia_nodes(&Refusing, &dev, false, findings.clone());
+        assert_eq!(left, findings);
+        fs::remove_dir_all(&dev).ok();
+    }
+}
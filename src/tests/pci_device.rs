@@ -0,0 +1,545 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        config::GfxConfig,
+        controller::CtrlGraphics,
+        find_connected_displays,
+        pci_device::{
+            dgpu_functions, ignored_entry_matches, igpu_present_in, nvidia_driver_in,
+            render_devices, Device, DeviceInfo, DiscreetGpu, GfxMode, GfxPower, GfxVendor,
+            ModeInfo, NvidiaDriver, PciAddress, RuntimePowerManagement, MODE_DOCS, RISK_CODES,
+            RISK_REQUIRES_REBOOT,
+        },
+    };
+
+    fn names(devices: &[Device]) -> Vec<&str> {
+        devices.iter().map(|dev| dev.name()).collect()
+    }
+
+    #[test]
+    fn parse_pci_address() {
+        assert_eq!(
+            PciAddress::parse("0000:01:00.1"),
+            Some(PciAddress {
+                domain: 0,
+                bus: 1,
+                slot: 0,
+                function: 1,
+            })
+        );
+        assert_eq!(
+            PciAddress::parse("10000:e1:1f.7"),
+            Some(PciAddress {
+                domain: 0x10000,
+                bus: 0xe1,
+                slot: 0x1f,
+                function: 7,
+            })
+        );
+        for bad in [
+            "",
+            "0000:01:00",
+            "0000:01.0",
+            "0000:01:00:00.0",
+            "0000:01:20.0",
+            "0000:01:00.8",
+            "0000:0g:00.0",
+            "pci0000:00",
+        ] {
+            assert_eq!(PciAddress::parse(bad), None, "{bad}");
+        }
+
+        let a = PciAddress::parse("0000:01:00.0").unwrap();
+        assert!(a.same_device(&PciAddress::parse("0000:01:00.1").unwrap()));
+        assert!(!a.same_device(&PciAddress::parse("0000:01:01.0").unwrap()));
+        assert!(!a.same_device(&PciAddress::parse("0000:02:00.0").unwrap()));
+        assert!(!a.same_device(&PciAddress::parse("0001:01:00.0").unwrap()));
+    }
+
+    #[test]
+    fn dgpu_functions_any_order() {
+        let fixture = || {
+            vec![
+                Device::mock("0000:00:02.0", GfxVendor::Amd, false),
+                Device::mock("0000:01:00.0", GfxVendor::Nvidia, true),
+                Device::mock("0000:01:00.1", GfxVendor::Nvidia, false),
+                Device::mock("0000:01:00.2", GfxVendor::Nvidia, false),
+                Device::mock("0000:05:00.0", GfxVendor::Amd, false),
+            ]
+        };
+        let expected = ["0000:01:00.0", "0000:01:00.1", "0000:01:00.2"];
+
+        // Every rotation and the reverse, including the audio function before the dGPU
+        for rotation in 0..5 {
+            let mut devices = fixture();
+            devices.rotate_left(rotation);
+            assert_eq!(names(&dgpu_functions(devices.clone())), expected);
+            devices.reverse();
+            assert_eq!(names(&dgpu_functions(devices)), expected);
+        }
+    }
+
+    #[test]
+    fn dgpu_functions_same_bus_other_domain() {
+        let devices = vec![
+            Device::mock("0001:01:00.1", GfxVendor::Amd, false),
+            Device::mock("0000:01:00.1", GfxVendor::Nvidia, false),
+            Device::mock("0001:01:00.0", GfxVendor::Amd, false),
+            Device::mock("0000:01:00.0", GfxVendor::Nvidia, true),
+        ];
+        assert_eq!(
+            names(&dgpu_functions(devices)),
+            ["0000:01:00.0", "0000:01:00.1"]
+        );
+
+        let devices = vec![
+            Device::mock("0000:01:00.1", GfxVendor::Nvidia, false),
+            Device::mock("0001:01:00.1", GfxVendor::Amd, false),
+            Device::mock("0001:01:00.0", GfxVendor::Amd, true),
+            Device::mock("0000:01:00.0", GfxVendor::Nvidia, false),
+        ];
+        assert_eq!(
+            names(&dgpu_functions(devices)),
+            ["0001:01:00.0", "0001:01:00.1"]
+        );
+    }
+
+    #[test]
+    fn dgpu_functions_without_dgpu() {
+        let devices = vec![
+            Device::mock("0000:00:02.0", GfxVendor::Amd, false),
+            Device::mock("0000:00:02.1", GfxVendor::Amd, false),
+            Device::mock("not-an-address", GfxVendor::Nvidia, true),
+        ];
+        assert!(dgpu_functions(devices).is_empty());
+    }
+
+    /// Create a fake PCI device directory with drm connectors and their status
+    fn fake_gpu(name: &str, connectors: &[(&str, &str)]) -> PathBuf {
+        let gpu =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&gpu).ok();
+        fs::create_dir_all(gpu.join("drm/renderD128")).unwrap();
+        let card = gpu.join("drm/card1");
+        fs::create_dir_all(card.join("power")).unwrap();
+        for (connector, status) in connectors {
+            let dir = card.join(format!("card1-{connector}"));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("status"), format!("{status}\n")).unwrap();
+        }
+        gpu
+    }
+
+    /// Add a PCI function under `devices` as sysfs has it
+    fn fake_pci_function(devices: &Path, name: &str, vendor: &str, class: &str) {
+        let dir = devices.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("vendor"), format!("{vendor}\n")).unwrap();
+        fs::write(dir.join("class"), format!("{class}\n")).unwrap();
+    }
+
+    #[test]
+    fn nvidia_driver_detection() {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-pci_device-nvidia_driver",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("proc")).unwrap();
+        assert_eq!(nvidia_driver_in(&root), NvidiaDriver::None);
+
+        fs::write(
+            root.join("proc/modules"),
+            "nouveau 2957312 12 - Live 0x0000000000000000\nvideo 77824 1 nouveau, Live 0x0000000000000000\n",
+        )
+        .unwrap();
+        assert_eq!(nvidia_driver_in(&root), NvidiaDriver::Nouveau);
+
+        // A module named after it isn't it
+        fs::write(
+            root.join("proc/modules"),
+            "nvidia_wmi_ec_backlight 12288 0 - Live 0x0000000000000000\n",
+        )
+        .unwrap();
+        assert_eq!(nvidia_driver_in(&root), NvidiaDriver::None);
+
+        // Only /sys/module says so, as with no procfs
+        fs::create_dir_all(root.join("sys/module/nvidia")).unwrap();
+        fs::write(root.join("sys/module/nvidia/refcnt"), "3\n").unwrap();
+        assert_eq!(nvidia_driver_in(&root), NvidiaDriver::Proprietary);
+        fs::remove_dir_all(&root).ok();
+
+        assert_eq!(NvidiaDriver::Nouveau.modules(), ["nouveau"]);
+        // Nothing loaded is handled as the proprietary driver, as before
+        assert_eq!(
+            NvidiaDriver::None.modules(),
+            NvidiaDriver::Proprietary.modules()
+        );
+        assert_eq!(
+            DiscreetGpu::mock(GfxVendor::Nvidia).nvidia_driver(),
+            NvidiaDriver::None
+        );
+    }
+
+    #[test]
+    fn igpu_detection() {
+        let devices = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-pci_device-igpu",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&devices).ok();
+        // An Nvidia dGPU with its audio function, and an Intel USB controller
+        fake_pci_function(&devices, "0000:01:00.0", "0x10de", "0x030000");
+        fake_pci_function(&devices, "0000:01:00.1", "0x10de", "0x040300");
+        fake_pci_function(&devices, "0000:00:14.0", "0x8086", "0x0c0330");
+        assert!(!igpu_present_in(&devices, "0000:01:00.0"));
+
+        fake_pci_function(&devices, "0000:00:02.0", "0x8086", "0x030000");
+        assert!(igpu_present_in(&devices, "0000:01:00.0"));
+        fs::remove_dir_all(&devices).ok();
+
+        // An AMD iGPU alongside an AMD dGPU, which is never counted as the iGPU
+        fake_pci_function(&devices, "0000:03:00.0", "0x1002", "0x038000");
+        assert!(!igpu_present_in(&devices, "0000:03:00.0"));
+        fake_pci_function(&devices, "0000:06:00.0", "0x1002", "0x030000");
+        assert!(igpu_present_in(&devices, "0000:03:00.0"));
+        fs::remove_dir_all(&devices).ok();
+
+        // Assumed present if it can't be told
+        assert!(igpu_present_in(&devices, "0000:01:00.0"));
+    }
+
+    #[test]
+    fn connected_displays_from_drm() {
+        let gpu = fake_gpu(
+            "drm-connected",
+            &[
+                ("HDMI-A-1", "connected"),
+                ("DP-1", "disconnected"),
+                ("DP-2", "connected"),
+            ],
+        );
+        let mut displays = find_connected_displays(&gpu).unwrap();
+        displays.sort();
+        assert_eq!(displays, ["DP-2", "HDMI-A-1"]);
+        fs::remove_dir_all(&gpu).ok();
+
+        // Nothing plugged in or no connectors at all is never a list of made up names
+        let gpu = fake_gpu("drm-disconnected", &[("HDMI-A-1", "disconnected")]);
+        assert!(find_connected_displays(&gpu).is_err());
+        fs::remove_dir_all(&gpu).ok();
+        let gpu = fake_gpu("drm-none", &[]);
+        assert!(find_connected_displays(&gpu).is_err());
+        fs::remove_dir_all(&gpu).ok();
+        assert!(find_connected_displays(&gpu).is_err());
+    }
+
+    #[test]
+    fn mode_legacy_names() {
+        assert_eq!("Egpu".parse::<GfxMode>().unwrap(), GfxMode::AsusEgpu);
+        assert_eq!("Dedicated".parse::<GfxMode>().unwrap(), GfxMode::Hybrid);
+        assert_eq!("Compute".parse::<GfxMode>().unwrap(), GfxMode::Hybrid);
+        assert_eq!("AsusEgpu".parse::<GfxMode>().unwrap(), GfxMode::AsusEgpu);
+        assert!("Bogus".parse::<GfxMode>().is_err());
+    }
+
+    #[test]
+    fn mode_wire_values() {
+        for mode in [
+            GfxMode::Hybrid,
+            GfxMode::Integrated,
+            GfxMode::NvidiaNoModeset,
+            GfxMode::Vfio,
+            GfxMode::AsusEgpu,
+            GfxMode::AsusMuxDgpu,
+            GfxMode::None,
+        ] {
+            assert_eq!(GfxMode::from_wire(mode as u32).unwrap(), mode);
+        }
+        // Encoded the same as by zvariant
+        let ctxt = zbus::zvariant::serialized::Context::new_dbus(zbus::zvariant::LE, 0);
+        let data = zbus::zvariant::to_bytes(ctxt, &GfxMode::AsusEgpu).unwrap();
+        let value: u32 = data.deserialize().unwrap().0;
+        assert_eq!(GfxMode::from_wire(value).unwrap(), GfxMode::AsusEgpu);
+        assert!(GfxMode::from_wire(7).is_err());
+        assert!(GfxMode::from_wire(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn every_mode_documented() {
+        assert_eq!(GfxMode::wire_order().len(), MODE_DOCS.len());
+        for mode in GfxMode::wire_order() {
+            let doc = mode.doc();
+            assert_eq!(doc.mode, *mode);
+            assert!(!doc.summary.is_empty() && !doc.description.is_empty());
+            for risk in doc.risks {
+                assert!(
+                    RISK_CODES.contains(risk),
+                    "{mode:?} has unknown risk {risk}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mode_info_from_doc() {
+        let info = ModeInfo::new(GfxMode::AsusMuxDgpu, Some("No GPU MUX".to_string()));
+        assert_eq!(info.name, "AsusMuxDgpu");
+        assert_eq!(info.risks[0], RISK_REQUIRES_REBOOT);
+        assert!(!info.supported);
+        assert_eq!(info.unsupported_reason, "No GPU MUX");
+
+        let info = ModeInfo::new(GfxMode::Hybrid, None);
+        assert!(info.supported);
+        assert!(info.unsupported_reason.is_empty());
+        assert_eq!(info.name.parse::<GfxMode>().unwrap(), GfxMode::Hybrid);
+    }
+
+    /// `count` functions of one device, named for `bus`
+    fn functions(bus: u64, count: u64) -> Vec<Device> {
+        (0..count)
+            .map(|f| Device::mock(&format!("0000:{bus:02x}:00.{f}"), GfxVendor::Nvidia, f == 0))
+            .collect()
+    }
+
+    #[test]
+    fn dgpu_index_out_of_range() {
+        // A refresh which found nothing, with an index left from before
+        let dgpu = DiscreetGpu::mock_devices(GfxVendor::Nvidia, 3, Vec::new(), 0);
+        assert_eq!(dgpu.dgpu_present(), None);
+        assert!(dgpu.connected_outputs().is_empty());
+        assert!(dgpu.link_info().is_none());
+        assert!(dgpu.snapshot().dgpu().is_none());
+        dgpu.get_runtime_status().ok();
+        dgpu.set_runtime_pm(RuntimePowerManagement::Auto).unwrap();
+
+        let dgpu = DiscreetGpu::mock_devices(GfxVendor::Nvidia, 5, functions(1, 2), 0);
+        assert_eq!(dgpu.dgpu_present(), None);
+        assert!(dgpu.get_runtime_status().is_err());
+        assert!(!dgpu.hotplug_capable());
+    }
+
+    #[test]
+    fn snapshot_survives_refresh() {
+        // The dGPU last, so its index is out of range for the refreshed list
+        let mut devices = functions(1, 3);
+        devices.rotate_left(1);
+        let mut dgpu = DiscreetGpu::mock_devices(GfxVendor::Nvidia, 2, devices, 0);
+        let held = dgpu.clone();
+        dgpu.set_mock_devices(0, functions(2, 1));
+
+        assert_eq!(dgpu.generation(), 1);
+        assert_eq!(names(dgpu.devices()), ["0000:02:00.0"]);
+        // The old view is unchanged, and its index still valid for it
+        assert_eq!(held.generation(), 0);
+        assert_eq!(held.devices().len(), 3);
+        assert_eq!(
+            held.snapshot().dgpu().map(|dev| dev.name()),
+            Some("0000:01:00.0")
+        );
+        assert_eq!(
+            dgpu.snapshot().dgpu().map(|dev| dev.name()),
+            Some("0000:02:00.0")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn status_reads_during_refreshes() {
+        let dgpu = Arc::new(Mutex::new(DiscreetGpu::mock(GfxVendor::Nvidia)));
+
+        let writer = {
+            let dgpu = dgpu.clone();
+            tokio::spawn(async move {
+                for generation in 1..=500u64 {
+                    let count = generation % 4;
+                    // The dGPU index is left out of range when there are no devices
+                    dgpu.lock()
+                        .await
+                        .set_mock_devices(0, functions(count + 1, count));
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let dgpu = dgpu.clone();
+                tokio::spawn(async move {
+                    for _ in 0..500 {
+                        let view = dgpu.lock().await.clone();
+                        let generation = view.generation();
+                        tokio::task::yield_now().await;
+                        // Still the same devices, all from the same discovery
+                        assert_eq!(view.generation(), generation);
+                        let count = generation % 4;
+                        assert_eq!(view.devices().len() as u64, count);
+                        let bus = format!("0000:{:02x}:", count + 1);
+                        assert!(view
+                            .devices()
+                            .iter()
+                            .all(|dev| dev.name().starts_with(&bus)));
+                        assert_eq!(view.dgpu_present().is_some(), count > 0);
+                        view.connected_outputs();
+                        view.get_runtime_status().ok();
+                    }
+                })
+            })
+            .collect();
+        writer.await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        assert_eq!(dgpu.lock().await.generation(), 500);
+    }
+
+    #[test]
+    fn ignored_entries() {
+        assert!(ignored_entry_matches("0000:01:00.2", "0000:01:00.2"));
+        assert!(ignored_entry_matches(" 01:00.2 ", "0000:01:00.2"));
+        assert!(ignored_entry_matches(".2", "0000:01:00.2"));
+        assert!(!ignored_entry_matches(".2", "0000:01:00.3"));
+        assert!(!ignored_entry_matches("01:00.2", "0001:01:00.2"));
+        assert!(!ignored_entry_matches("0000:01:00", "0000:01:00.2"));
+        assert!(!ignored_entry_matches("", "0000:01:00.2"));
+        assert!(!ignored_entry_matches(".x", "0000:01:00.2"));
+    }
+
+    #[test]
+    fn ignored_functions_filtered() {
+        let mut dgpu = DiscreetGpu::mock_devices(GfxVendor::Nvidia, 0, functions(1, 4), 0);
+        assert_eq!(dgpu.managed_devices().count(), 4);
+        dgpu.set_ignored_functions(vec![
+            ".2".to_string(),
+            "0000:01:00.3".to_string(),
+            "0000:01:00.0".to_string(),
+            "02:00.1".to_string(),
+        ]);
+        let managed: Vec<&str> = dgpu.managed_devices().map(|dev| dev.name()).collect();
+        assert_eq!(managed, ["0000:01:00.0", "0000:01:00.1"]);
+        assert!(dgpu.is_ignored(&dgpu.devices()[2]));
+        assert!(!dgpu.is_ignored(&dgpu.devices()[0]));
+        let rev: Vec<&str> = dgpu.managed_devices().rev().map(|dev| dev.name()).collect();
+        assert_eq!(rev, ["0000:01:00.1", "0000:01:00.0"]);
+
+        let warnings = dgpu.check_ignored_functions();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("\"0000:01:00.0\" is the dGPU"));
+        assert!(warnings[1].contains("\"02:00.1\" matches none"));
+
+        // Kept when the devices are rediscovered
+        dgpu.set_mock_devices(0, functions(1, 3));
+        assert_eq!(dgpu.managed_devices().count(), 2);
+    }
+
+    /// A PCI function in `root` with its own driver, which can be unbound and removed
+    fn fake_function(root: &Path, name: &str, driver: &str) -> Device {
+        let dev_path = root.join("devices").join(name);
+        let driver_path = root.join("drivers").join(driver);
+        fs::create_dir_all(dev_path.join("power")).unwrap();
+        fs::create_dir_all(&driver_path).unwrap();
+        // Files are written without truncating, as sysfs attributes are
+        fs::write(dev_path.join("power/control"), "").unwrap();
+        fs::write(dev_path.join("remove"), "").unwrap();
+        fs::write(driver_path.join("unbind"), "").unwrap();
+        std::os::unix::fs::symlink(&driver_path, dev_path.join("driver")).unwrap();
+        Device::mock(name, GfxVendor::Nvidia, name.ends_with(".0")).with_dev_path(&dev_path)
+    }
+
+    #[tokio::test]
+    async fn ignored_function_untouched_by_switch() {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-ignored-functions",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        let devices = vec![
+            fake_function(&root, "0000:01:00.0", "nvidia"),
+            fake_function(&root, "0000:01:00.1", "snd_hda_intel"),
+            fake_function(&root, "0000:01:00.2", "xhci_hcd"),
+        ];
+        let mut dgpu = DiscreetGpu::mock_devices(GfxVendor::Nvidia, 0, devices, 0);
+        dgpu.set_ignored_functions(vec![".2".to_string()]);
+
+        // What a switch to Integrated does to the functions
+        dgpu.set_runtime_pm(RuntimePowerManagement::On).unwrap();
+        dgpu.unbind_remove().await.unwrap();
+
+        let read = |path: &str| fs::read_to_string(root.join(path)).unwrap();
+        for (name, driver) in [
+            ("0000:01:00.0", "nvidia"),
+            ("0000:01:00.1", "snd_hda_intel"),
+        ] {
+            assert_eq!(read(&format!("devices/{name}/power/control")), "on");
+            assert_eq!(read(&format!("devices/{name}/remove")), "1");
+            assert_eq!(read(&format!("drivers/{driver}/unbind")), name);
+        }
+        assert_eq!(read("devices/0000:01:00.2/power/control"), "");
+        assert_eq!(read("devices/0000:01:00.2/remove"), "");
+        assert_eq!(read("drivers/xhci_hcd/unbind"), "");
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn device_listing() {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-device-listing",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        let gpu = fake_function(&root, "0000:01:00.0", "nvidia").with_pci_id("10de:1f9d");
+        fs::write(
+            root.join("devices/0000:01:00.0/power/runtime_status"),
+            "active\n",
+        )
+        .unwrap();
+        let audio = fake_function(&root, "0000:01:00.1", "snd_hda_intel");
+        fs::remove_file(root.join("devices/0000:01:00.1/driver")).unwrap();
+        let gone = Device::mock("0000:01:00.2", GfxVendor::Nvidia, false)
+            .with_dev_path(&root.join("devices/0000:01:00.2"));
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock_devices(GfxVendor::Nvidia, 0, vec![gpu, audio, gone], 0),
+        );
+
+        // Functions no longer on the bus are left out
+        let devices = ctrl.get_devices().await;
+        assert_eq!(
+            devices[0],
+            DeviceInfo {
+                pci_id: "10de:1f9d".to_string(),
+                sysname: "0000:01:00.0".to_string(),
+                vendor: GfxVendor::Nvidia,
+                is_dgpu: true,
+                power: GfxPower::Active,
+                driver: "nvidia".to_string(),
+            }
+        );
+        assert_eq!(devices.len(), 2);
+        assert!(!devices[1].is_dgpu);
+        assert_eq!(devices[1].driver, "");
+
+        let table = render_devices(&devices);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Device"), "{table}");
+        assert!(lines[1].starts_with("0000:01:00.0  10de:1f9d"), "{table}");
+        assert!(lines[1].ends_with("active  nvidia"), "{table}");
+        assert!(!lines[2].ends_with(' '), "{table}");
+        fs::remove_dir_all(&root).ok();
+
+        // Nothing on the bus, such as with dgpu_disable, is not an error
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::AsusDgpuDisabled),
+        );
+        assert!(ctrl.get_devices().await.is_empty());
+        assert_eq!(render_devices(&[]), "No dGPU functions are present\n");
+    }
+}
@@ -0,0 +1,175 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::pci_link::{active_aspm_policy, readable_while_suspended, upstream_port, LinkInfo};
+
+    /// Create a fake PCI device under `parent` with the given attributes
+    fn fake_device(parent: &Path, name: &str, attrs: &[(&str, &str)]) -> PathBuf {
+        let dev = parent.join(name);
+        fs::create_dir_all(dev.join("power")).unwrap();
+        fs::create_dir_all(dev.join("link")).unwrap();
+        for (attr, value) in attrs {
+            fs::write(dev.join(attr), format!("{value}\n")).unwrap();
+        }
+        dev
+    }
+
+    fn fake_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!("supergfxctl-test-{}-{name}", std::process::id()))
+            .join("pci0000:00");
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn link_attrs<'a>(vendor: &'a str, status: &'a str) -> Vec<(&'a str, &'a str)> {
+        vec![
+            ("vendor", vendor),
+            ("power/runtime_status", status),
+            ("current_link_speed", "8.0 GT/s PCIe"),
+            ("current_link_width", "8"),
+            ("max_link_speed", "16.0 GT/s PCIe"),
+            ("max_link_width", "16"),
+        ]
+    }
+
+    /// Nvidia: the dGPU is directly below a root port of the CPU
+    fn fake_nvidia(name: &str, status: &str) -> PathBuf {
+        let root = fake_root(name);
+        let port = fake_device(
+            &root,
+            "0000:00:01.0",
+            &[link_attrs("0x8086", "active"), vec![("link/l1_aspm", "1")]].concat(),
+        );
+        fake_device(
+            &port,
+            "0000:01:00.0",
+            &[
+                link_attrs("0x10de", status),
+                vec![
+                    ("link/l1_aspm", "1"),
+                    ("link/l1_2_aspm", "1"),
+                    ("link/l0s_aspm", "0"),
+                ],
+            ]
+            .concat(),
+        )
+    }
+
+    /// AMD: the dGPU sits behind the upstream and downstream ports of a switch on the card
+    fn fake_amd(name: &str, status: &str) -> PathBuf {
+        let root = fake_root(name);
+        let port = fake_device(&root, "0000:00:01.1", &link_attrs("0x1022", "active"));
+        let upstream = fake_device(&port, "0000:01:00.0", &link_attrs("0x1002", status));
+        let downstream = fake_device(&upstream, "0000:02:00.0", &link_attrs("0x1002", status));
+        fake_device(&downstream, "0000:03:00.0", &link_attrs("0x1002", status))
+    }
+
+    fn remove(dgpu: &Path) {
+        let mut top = dgpu.to_path_buf();
+        while !top.ends_with("pci0000:00") {
+            top.pop();
+        }
+        top.pop();
+        fs::remove_dir_all(top).ok();
+    }
+
+    #[test]
+    fn suspended_read_classification() {
+        for attr in [
+            "power/runtime_status",
+            "power/control",
+            "link/l1_aspm",
+            "link/l1_2_aspm",
+            "link/clkpm",
+            "vendor",
+        ] {
+            assert!(readable_while_suspended(attr), "{attr}");
+        }
+        for attr in [
+            "current_link_speed",
+            "current_link_width",
+            "max_link_speed",
+            "max_link_width",
+            "config",
+            "link/unknown",
+            "drm/card1/status",
+        ] {
+            assert!(!readable_while_suspended(attr), "{attr}");
+        }
+    }
+
+    #[test]
+    fn aspm_policy() {
+        assert_eq!(
+            active_aspm_policy("default [performance] powersave powersupersave\n").as_deref(),
+            Some("performance")
+        );
+        assert_eq!(active_aspm_policy("default performance"), None);
+    }
+
+    #[test]
+    fn upstream_port_layouts() {
+        let nvidia = fake_nvidia("link-port-nvidia", "active");
+        assert!(upstream_port(&nvidia).unwrap().ends_with("0000:00:01.0"));
+        let amd = fake_amd("link-port-amd", "active");
+        assert!(upstream_port(&amd).unwrap().ends_with("0000:00:01.1"));
+
+        // Directly on the root bus there is no port
+        let root = fake_root("link-port-none");
+        let dgpu = fake_device(&root, "0000:01:00.0", &link_attrs("0x10de", "active"));
+        assert_eq!(upstream_port(&dgpu), None);
+
+        for dgpu in [nvidia, amd, dgpu] {
+            remove(&dgpu);
+        }
+    }
+
+    #[test]
+    fn link_info_active() {
+        let dgpu = fake_nvidia("link-active", "active");
+        let policy = dgpu.parent().unwrap().join("policy");
+        fs::write(&policy, "[default] performance powersave powersupersave\n").unwrap();
+
+        let info = LinkInfo::read(&dgpu, &policy);
+        assert_eq!(info.dgpu.name, "0000:01:00.0");
+        assert_eq!(info.dgpu.current_speed, "8.0 GT/s PCIe");
+        assert_eq!(info.dgpu.max_width, "16");
+        assert_eq!(info.dgpu.aspm_enabled, ["l1_aspm", "l1_2_aspm"]);
+        assert_eq!(info.port.name, "0000:00:01.0");
+        assert_eq!(info.port.aspm_enabled, ["l1_aspm"]);
+        assert_eq!(info.aspm_policy, "default");
+        assert!(info.notes.is_empty(), "{:?}", info.notes);
+        remove(&dgpu);
+    }
+
+    #[test]
+    fn link_info_suspended_is_partial() {
+        for (dgpu, port) in [
+            (
+                fake_nvidia("link-suspended-nvidia", "suspended"),
+                "0000:00:01.0",
+            ),
+            (fake_amd("link-suspended-amd", "suspended"), "0000:00:01.1"),
+        ] {
+            let info = LinkInfo::read(&dgpu, Path::new("/nonexistent"));
+            assert_eq!(info.dgpu.runtime_status, "suspended");
+            assert!(info.dgpu.current_speed.is_empty());
+            assert!(info.dgpu.current_width.is_empty());
+            assert!(info.dgpu.max_speed.is_empty());
+            assert!(info.dgpu.max_width.is_empty());
+            // The port is awake so it is read in full
+            assert_eq!(info.port.name, port);
+            assert_eq!(info.port.current_width, "8");
+            assert_eq!(info.notes.len(), 1);
+            assert!(info.notes[0].contains("suspended"), "{:?}", info.notes);
+            assert!(info.aspm_policy.is_empty());
+            remove(&dgpu);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        error::GfxError,
+        pci_lock::{wait_for_settle, PciLock, SettleTracker, PCI_SETTLE_MAX_CHECKS},
+    };
+
+    fn snapshot(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn expected() -> Vec<String> {
+        snapshot(&["0000:01:00.0", "0000:01:00.1"])
+    }
+
+    #[test]
+    fn settle_stable() {
+        let mut tracker = SettleTracker::new(&expected());
+        assert!(!tracker.push(snapshot(&["0000:01:00.0", "0000:01:00.1"])));
+        // Order of enumeration doesn't matter
+        assert!(tracker.push(snapshot(&["0000:01:00.1", "0000:01:00.0"])));
+        assert_eq!(tracker.checks, 2);
+        assert_eq!(tracker.changes, 0);
+    }
+
+    #[test]
+    fn settle_flapping() {
+        let mut tracker = SettleTracker::new(&expected());
+        for names in [
+            &["0000:01:00.0"][..],
+            &["0000:01:00.0", "0000:01:00.1"],
+            &["0000:01:00.1"],
+            &[],
+            &["0000:01:00.0", "0000:01:00.1"],
+        ] {
+            assert!(!tracker.push(snapshot(names)), "{names:?}");
+        }
+        assert!(tracker.push(snapshot(&["0000:01:00.0", "0000:01:00.1"])));
+        assert_eq!(tracker.changes, 4);
+    }
+
+    #[test]
+    fn settle_stable_but_incomplete() {
+        let mut tracker = SettleTracker::new(&expected());
+        for _ in 0..5 {
+            // Half the functions are back and staying that way is not settled
+            assert!(!tracker.push(snapshot(&["0000:01:00.0"])));
+        }
+        assert_eq!(tracker.changes, 0);
+        // An extra function appearing is fine once it is stable
+        assert!(!tracker.push(snapshot(&["0000:01:00.0", "0000:01:00.1", "0000:01:00.2"])));
+        assert!(tracker.push(snapshot(&["0000:01:00.0", "0000:01:00.1", "0000:01:00.2"])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn settle_wait_is_bounded() {
+        let full = snapshot(&["0000:01:00.0", "0000:01:00.1"]);
+        let partial = snapshot(&["0000:01:00.0"]);
+
+        // Flaps twice then settles
+        let mut sequence = vec![partial.clone(), full.clone(), partial.clone(), full.clone()];
+        sequence.reverse();
+        let mut calls = 0;
+        wait_for_settle(&expected(), || {
+            calls += 1;
+            sequence.pop().unwrap_or_else(|| full.clone())
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls, 5);
+
+        // Never settles
+        let mut calls = 0;
+        let mut flip = false;
+        let res = wait_for_settle(&expected(), || {
+            calls += 1;
+            flip = !flip;
+            if flip {
+                full.clone()
+            } else {
+                partial.clone()
+            }
+        })
+        .await;
+        assert!(matches!(res, Err(GfxError::PciNotSettled)));
+        assert_eq!(calls, PCI_SETTLE_MAX_CHECKS);
+    }
+
+    #[tokio::test]
+    async fn pci_lock_is_exclusive() {
+        let path = std::env::temp_dir()
+            .join(format!("supergfxctl-test-{}-pci", std::process::id()))
+            .join("pci.lock");
+        let held = PciLock::acquire_at(&path, Duration::from_millis(10)).await;
+        assert!(held.is_held());
+        // A second holder gives up after the timeout and goes ahead unlocked
+        let second = PciLock::acquire_at(&path, Duration::from_millis(200)).await;
+        assert!(!second.is_held());
+        drop(held);
+        assert!(PciLock::acquire_at(&path, Duration::from_millis(10))
+            .await
+            .is_held());
+        std::fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+}
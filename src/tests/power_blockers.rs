@@ -0,0 +1,194 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        pci_device::GfxPower,
+        power_blockers::{BlockerScanner, BlockerWatch, PowerBlocker, BLOCKER_REFRESH},
+    };
+
+    const THRESHOLD: Duration = Duration::from_secs(600);
+
+    /// Returns `found` and counts the scans
+    struct MockScanner {
+        found: Vec<PowerBlocker>,
+        scans: usize,
+    }
+
+    impl BlockerScanner for MockScanner {
+        fn scan(&mut self) -> Vec<PowerBlocker> {
+            self.scans += 1;
+            self.found.clone()
+        }
+    }
+
+    fn blocker(pid: u32, comm: &str) -> PowerBlocker {
+        PowerBlocker {
+            pid,
+            comm: comm.to_string(),
+            user: "alice".to_string(),
+            first_seen: 0,
+        }
+    }
+
+    fn scanner() -> MockScanner {
+        MockScanner {
+            found: vec![blocker(10, "firefox")],
+            scans: 0,
+        }
+    }
+
+    #[test]
+    fn scanned_after_threshold() {
+        let start = Instant::now();
+        let mut watch = BlockerWatch::new(THRESHOLD);
+        let mut scanner = scanner();
+
+        assert!(!watch.observe(start, 1000, GfxPower::Active, true, &mut scanner));
+        assert!(!watch.observe(
+            start + THRESHOLD - Duration::from_secs(1),
+            1599,
+            GfxPower::Active,
+            true,
+            &mut scanner
+        ));
+        assert_eq!(scanner.scans, 0);
+
+        assert!(watch.observe(
+            start + THRESHOLD,
+            1600,
+            GfxPower::Active,
+            true,
+            &mut scanner
+        ));
+        assert_eq!(scanner.scans, 1);
+        assert_eq!(
+            watch.blockers(),
+            [PowerBlocker {
+                first_seen: 1600,
+                ..blocker(10, "firefox")
+            }]
+        );
+    }
+
+    #[test]
+    fn scans_capped() {
+        let start = Instant::now();
+        let mut watch = BlockerWatch::new(THRESHOLD);
+        let mut scanner = scanner();
+        watch.observe(start, 1000, GfxPower::Active, true, &mut scanner);
+        let first = start + THRESHOLD;
+        watch.observe(first, 1600, GfxPower::Active, true, &mut scanner);
+
+        // Polled every few seconds, scanned again only after the refresh
+        for secs in (10..BLOCKER_REFRESH.as_secs()).step_by(10) {
+            let now = first + Duration::from_secs(secs);
+            watch.observe(now, 1600 + secs, GfxPower::Active, true, &mut scanner);
+        }
+        assert_eq!(scanner.scans, 1);
+
+        // A new process is added, the one already seen keeps its first_seen
+        scanner.found.push(blocker(20, "steam"));
+        let wall = 1600 + BLOCKER_REFRESH.as_secs();
+        assert!(watch.observe(
+            first + BLOCKER_REFRESH,
+            wall,
+            GfxPower::Active,
+            true,
+            &mut scanner
+        ));
+        assert_eq!(scanner.scans, 2);
+        assert_eq!(watch.blockers()[0].first_seen, 1600);
+        assert_eq!(watch.blockers()[1].first_seen, wall);
+    }
+
+    #[test]
+    fn never_scanned_asleep() {
+        let start = Instant::now();
+        let mut watch = BlockerWatch::new(THRESHOLD);
+        let mut scanner = scanner();
+        for power in [GfxPower::Suspended, GfxPower::Off, GfxPower::AsusDisabled] {
+            for secs in [0, 600, 6000] {
+                let now = start + Duration::from_secs(secs);
+                assert!(!watch.observe(now, secs, power, true, &mut scanner));
+            }
+        }
+        assert_eq!(scanner.scans, 0);
+    }
+
+    #[test]
+    fn cleared_on_suspend() {
+        let start = Instant::now();
+        let mut watch = BlockerWatch::new(THRESHOLD);
+        let mut scanner = scanner();
+        watch.observe(start, 1000, GfxPower::Active, true, &mut scanner);
+        watch.observe(
+            start + THRESHOLD,
+            1600,
+            GfxPower::Active,
+            true,
+            &mut scanner,
+        );
+        assert_eq!(watch.blockers().len(), 1);
+
+        // A failed read isn't a suspend
+        let later = start + THRESHOLD + Duration::from_secs(5);
+        assert!(!watch.observe(later, 1605, GfxPower::Unknown, true, &mut scanner));
+        assert_eq!(watch.blockers().len(), 1);
+
+        assert!(watch.observe(later, 1605, GfxPower::Suspended, true, &mut scanner));
+        assert!(watch.blockers().is_empty());
+
+        // Awake again, the threshold starts over
+        let woke = later + Duration::from_secs(5);
+        watch.observe(woke, 1610, GfxPower::Active, true, &mut scanner);
+        watch.observe(
+            woke + THRESHOLD - Duration::from_secs(1),
+            2209,
+            GfxPower::Active,
+            true,
+            &mut scanner,
+        );
+        assert_eq!(scanner.scans, 1);
+        watch.observe(woke + THRESHOLD, 2210, GfxPower::Active, true, &mut scanner);
+        assert_eq!(scanner.scans, 2);
+        assert_eq!(watch.blockers()[0].first_seen, 2210);
+    }
+
+    #[test]
+    fn not_watched() {
+        let start = Instant::now();
+        let mut scanner = scanner();
+        // On AC or not in Hybrid
+        let mut watch = BlockerWatch::new(THRESHOLD);
+        watch.observe(start, 1000, GfxPower::Active, false, &mut scanner);
+        watch.observe(
+            start + THRESHOLD * 2,
+            2200,
+            GfxPower::Active,
+            false,
+            &mut scanner,
+        );
+        assert_eq!(scanner.scans, 0);
+        // Unplugged after being awake a while, scanned at once
+        assert!(watch.observe(
+            start + THRESHOLD * 2,
+            2200,
+            GfxPower::Active,
+            true,
+            &mut scanner
+        ));
+
+        // Turned off
+        let mut watch = BlockerWatch::new(Duration::ZERO);
+        watch.observe(start, 1000, GfxPower::Active, true, &mut scanner);
+        watch.observe(
+            start + THRESHOLD,
+            1600,
+            GfxPower::Active,
+            true,
+            &mut scanner,
+        );
+        assert_eq!(scanner.scans, 1);
+    }
+}
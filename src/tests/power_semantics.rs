@@ -0,0 +1,144 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        pci_device::{Device, GfxPower, GfxVendor},
+        power_semantics::{derive_power, PowerSemantics, POWER_SEMANTICS_VERSION},
+    };
+
+    const RUNTIME_STATUS: &[Option<&str>] = &[
+        Some("active\n"),
+        Some("suspended\n"),
+        Some("suspending\n"),
+        Some("resuming\n"),
+        Some("error\n"),
+        Some("unsupported\n"),
+        None,
+    ];
+    const POWER_STATE: &[Option<&str>] = &[
+        Some("D0\n"),
+        Some("D3hot\n"),
+        Some("D3cold\n"),
+        Some("unknown\n"),
+        Some("error\n"),
+        None,
+    ];
+    const VENDORS: &[GfxVendor] = &[
+        GfxVendor::Nvidia,
+        GfxVendor::Amd,
+        GfxVendor::Intel,
+        GfxVendor::Unknown,
+        GfxVendor::AsusDgpuDisabled,
+    ];
+
+    /// What a function of `vendor` should read as, written out separately from
+    /// `derive_power`
+    fn expected(
+        vendor: GfxVendor,
+        runtime_status: Option<&str>,
+        power_state: Option<&str>,
+    ) -> GfxPower {
+        let nvidia = matches!(vendor, GfxVendor::Nvidia | GfxVendor::AsusDgpuDisabled);
+        match (
+            nvidia,
+            runtime_status.map(str::trim),
+            power_state.map(str::trim),
+        ) {
+            (true, None, _) => GfxPower::Off,
+            (true, Some("active"), _) => GfxPower::Active,
+            (true, Some("suspended"), _) => GfxPower::Suspended,
+            (true, Some(_), _) => GfxPower::Unknown,
+            (false, _, Some("D3cold")) => GfxPower::Off,
+            (false, Some("active"), _) => GfxPower::Active,
+            (false, Some("suspended"), _) => GfxPower::Suspended,
+            (false, _, _) => GfxPower::Unknown,
+        }
+    }
+
+    #[test]
+    fn every_combination() {
+        for &vendor in VENDORS {
+            for &runtime_status in RUNTIME_STATUS {
+                for &power_state in POWER_STATE {
+                    assert_eq!(
+                        derive_power(vendor, runtime_status, power_state),
+                        expected(vendor, runtime_status, power_state),
+                        "{vendor:?} runtime_status {runtime_status:?} power_state {power_state:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn amd_off_only_in_d3cold() {
+        // A failed read used to be reported as Off
+        assert_eq!(derive_power(GfxVendor::Amd, None, None), GfxPower::Unknown);
+        assert_eq!(
+            derive_power(GfxVendor::Amd, Some("suspended"), Some("D3hot")),
+            GfxPower::Suspended
+        );
+        assert_eq!(
+            derive_power(GfxVendor::Amd, Some("suspended"), Some("D3cold")),
+            GfxPower::Off
+        );
+        assert_eq!(
+            derive_power(GfxVendor::Amd, None, Some("D3cold")),
+            GfxPower::Off
+        );
+        // Nvidia keeps Off for a function gone from the bus
+        assert_eq!(derive_power(GfxVendor::Nvidia, None, None), GfxPower::Off);
+        assert_eq!(
+            derive_power(GfxVendor::Nvidia, Some("suspended"), Some("D3cold")),
+            GfxPower::Suspended
+        );
+    }
+
+    #[test]
+    fn read_from_sysfs() {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-power-semantics",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("power")).unwrap();
+        let amd = Device::mock("0000:03:00.0", GfxVendor::Amd, true).with_dev_path(&dir);
+        let nvidia = Device::mock("0000:01:00.0", GfxVendor::Nvidia, true).with_dev_path(&dir);
+
+        fs::write(dir.join("power/runtime_status"), "suspended\n").unwrap();
+        fs::write(dir.join("power_state"), "D3hot\n").unwrap();
+        assert_eq!(amd.get_runtime_status().unwrap(), GfxPower::Suspended);
+        fs::write(dir.join("power_state"), "D3cold\n").unwrap();
+        assert_eq!(amd.get_runtime_status().unwrap(), GfxPower::Off);
+        assert_eq!(nvidia.get_runtime_status().unwrap(), GfxPower::Suspended);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(amd.get_runtime_status().unwrap(), GfxPower::Unknown);
+        assert_eq!(nvidia.get_runtime_status().unwrap(), GfxPower::Off);
+    }
+
+    #[test]
+    fn mapping_covers_every_status() {
+        for &vendor in VENDORS {
+            let semantics = PowerSemantics::for_vendor(vendor);
+            assert_eq!(semantics.vendor, vendor);
+            assert_eq!(semantics.version, POWER_SEMANTICS_VERSION);
+            assert_eq!(semantics.mapping.len(), 6);
+            assert!(semantics
+                .mapping
+                .iter()
+                .all(|(_, meaning)| !meaning.is_empty()));
+        }
+        let off = |vendor| {
+            PowerSemantics::for_vendor(vendor)
+                .mapping
+                .into_iter()
+                .find(|(power, _)| *power == GfxPower::Off)
+                .unwrap()
+                .1
+        };
+        assert!(off(GfxVendor::Amd).contains("D3cold"));
+        assert!(off(GfxVendor::Nvidia).contains("removed"));
+    }
+}
@@ -0,0 +1,119 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::mpsc::channel, time::Instant};
+
+    use crate::power_watch::{PowerTrigger, PowerWatch, POWER_POLL_FAST, POWER_POLL_KEEPALIVE};
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_without_event_source() {
+        let mut watch = PowerWatch::new(None);
+        assert_eq!(watch.interval(), POWER_POLL_FAST);
+        let start = Instant::now();
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        assert_eq!(start.elapsed(), POWER_POLL_FAST);
+
+        watch.record(PowerTrigger::Poll, true);
+        assert_eq!(watch.poll_transitions, 1);
+        assert_eq!(watch.interval(), POWER_POLL_FAST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn configured_fast_poll() {
+        let mut watch = PowerWatch::new(None);
+        watch.set_fast_poll(Duration::from_millis(250));
+        let start = Instant::now();
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+
+        // The keep-alive poll with events is unchanged
+        let (_tx, rx) = channel(1);
+        let mut watch = PowerWatch::new(Some(rx));
+        watch.set_fast_poll(Duration::from_millis(250));
+        assert_eq!(watch.interval(), POWER_POLL_KEEPALIVE);
+        watch.record(PowerTrigger::Poll, true);
+        assert_eq!(watch.interval(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_with_keepalive_poll() {
+        let (tx, rx) = channel(1);
+        let mut watch = PowerWatch::new(Some(rx));
+        assert_eq!(watch.interval(), POWER_POLL_KEEPALIVE);
+
+        tx.send(()).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(watch.wait().await, PowerTrigger::Event);
+        assert_eq!(start.elapsed(), std::time::Duration::ZERO);
+        watch.record(PowerTrigger::Event, true);
+        assert_eq!(watch.event_transitions, 1);
+
+        // No events, the keep-alive poll fires
+        let start = Instant::now();
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        assert_eq!(start.elapsed(), POWER_POLL_KEEPALIVE);
+        // Nothing changed so events are still trusted
+        watch.record(PowerTrigger::Poll, false);
+        assert_eq!(watch.interval(), POWER_POLL_KEEPALIVE);
+        // The first read isn't counted either way
+        watch.record(PowerTrigger::Start, true);
+        assert_eq!(watch.interval(), POWER_POLL_KEEPALIVE);
+        assert_eq!(watch.poll_transitions, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_event_falls_back_to_fast_poll() {
+        let (tx, rx) = channel(1);
+        let mut watch = PowerWatch::new(Some(rx));
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        watch.record(PowerTrigger::Poll, true);
+        assert_eq!(watch.poll_transitions, 1);
+        assert_eq!(watch.interval(), POWER_POLL_FAST);
+
+        // Events are still used when they do come
+        tx.send(()).await.unwrap();
+        assert_eq!(watch.wait().await, PowerTrigger::Event);
+        let start = Instant::now();
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        assert_eq!(start.elapsed(), POWER_POLL_FAST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_keeps_the_interval() {
+        let (_tx, rx) = channel(1);
+        let mut watch = PowerWatch::new(Some(rx));
+        watch.set_held(true);
+        // Reported as skipped the first time only
+        assert!(watch.record(PowerTrigger::Poll, true));
+        assert!(!watch.record(PowerTrigger::Poll, true));
+        assert_eq!(watch.poll_transitions, 2);
+        assert_eq!(watch.interval(), POWER_POLL_KEEPALIVE);
+
+        watch.set_held(false);
+        assert!(!watch.record(PowerTrigger::Poll, true));
+        assert_eq!(watch.interval(), POWER_POLL_FAST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_event_source_falls_back_to_fast_poll() {
+        let (tx, rx) = channel(1);
+        let mut watch = PowerWatch::new(Some(rx));
+        drop(tx);
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        assert_eq!(watch.interval(), POWER_POLL_FAST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_events_are_one_wakeup() {
+        let (tx, rx) = channel(4);
+        let mut watch = PowerWatch::new(Some(rx));
+        for _ in 0..3 {
+            tx.send(()).await.unwrap();
+        }
+        assert_eq!(watch.wait().await, PowerTrigger::Event);
+        let start = Instant::now();
+        assert_eq!(watch.wait().await, PowerTrigger::Poll);
+        assert_eq!(start.elapsed(), POWER_POLL_KEEPALIVE);
+    }
+}
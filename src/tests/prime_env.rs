@@ -0,0 +1,107 @@
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::GfxError,
+        pci_device::{GfxMode, GfxVendor},
+        prime_env::{
+            offload_possible, prime_env, run_offloaded, OffloadDriver, PrimeEnvSource,
+            MESA_OFFLOAD_ENV, NVIDIA_OFFLOAD_ENV,
+        },
+    };
+
+    /// Answers as the daemon would in `mode` with an nvidia dGPU
+    struct MockDaemon {
+        mode: GfxMode,
+    }
+
+    impl PrimeEnvSource for MockDaemon {
+        fn mode(&self) -> Result<GfxMode, GfxError> {
+            Ok(self.mode)
+        }
+
+        fn prime_env(&self) -> Result<Vec<(String, String)>, GfxError> {
+            Ok(prime_env(self.mode, GfxVendor::Nvidia, Some("nvidia")))
+        }
+    }
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn driver_tables() {
+        assert_eq!(
+            OffloadDriver::detect(GfxVendor::Nvidia, Some("nvidia")).map(OffloadDriver::env),
+            Some(NVIDIA_OFFLOAD_ENV)
+        );
+        // nouveau is offloaded to through mesa like amdgpu
+        assert_eq!(
+            OffloadDriver::detect(GfxVendor::Nvidia, Some("nouveau")).map(OffloadDriver::env),
+            Some(MESA_OFFLOAD_ENV)
+        );
+        assert_eq!(
+            OffloadDriver::detect(GfxVendor::Amd, Some("amdgpu")),
+            Some(OffloadDriver::Amdgpu)
+        );
+        // No driver bound yet goes by the vendor
+        assert_eq!(
+            OffloadDriver::detect(GfxVendor::Amd, None),
+            Some(OffloadDriver::Amdgpu)
+        );
+        assert_eq!(
+            OffloadDriver::detect(GfxVendor::Nvidia, Some("vfio-pci")),
+            None
+        );
+        assert_eq!(OffloadDriver::detect(GfxVendor::Unknown, None), None);
+    }
+
+    #[test]
+    fn env_for_mode() {
+        for mode in GfxMode::wire_order() {
+            let env = prime_env(*mode, GfxVendor::Amd, Some("amdgpu"));
+            if offload_possible(*mode) {
+                assert_eq!(env, [("DRI_PRIME".to_string(), "1".to_string())]);
+            } else {
+                assert!(env.is_empty(), "{mode:?}");
+            }
+        }
+        assert!(!offload_possible(GfxMode::Integrated));
+        assert!(!offload_possible(GfxMode::Vfio));
+        assert!(offload_possible(GfxMode::Hybrid));
+        let env = prime_env(GfxMode::Hybrid, GfxVendor::Nvidia, Some("nvidia"));
+        assert!(env.contains(&("__NV_PRIME_RENDER_OFFLOAD".to_string(), "1".to_string())));
+    }
+
+    #[test]
+    fn run_with_env() {
+        let daemon = MockDaemon {
+            mode: GfxMode::Hybrid,
+        };
+        let check = r#"[ "$__NV_PRIME_RENDER_OFFLOAD" = 1 ] && [ "$__GLX_VENDOR_LIBRARY_NAME" = nvidia ] && exit "$0""#;
+        assert_eq!(
+            run_offloaded(&daemon, &command(&["sh", "-c", check, "7"])).unwrap(),
+            7
+        );
+        // Killed by a signal, as a shell reports it
+        assert_eq!(
+            run_offloaded(&daemon, &command(&["sh", "-c", "kill -TERM $$"])).unwrap(),
+            128 + libc::SIGTERM
+        );
+        assert!(run_offloaded(&daemon, &[]).is_err());
+        assert!(matches!(
+            run_offloaded(&daemon, &command(&["/nonexistent/supergfxctl-test"])),
+            Err(GfxError::Command(..))
+        ));
+    }
+
+    #[test]
+    fn run_refused_without_offload() {
+        let daemon = MockDaemon {
+            mode: GfxMode::Integrated,
+        };
+        match run_offloaded(&daemon, &command(&["sh", "-c", "exit 0"])) {
+            Err(GfxError::NotSupported(msg)) => assert!(msg.contains("Integrated"), "{msg}"),
+            res => panic!("expected NotSupported, got {res:?}"),
+        }
+    }
+}
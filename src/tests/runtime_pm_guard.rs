@@ -0,0 +1,243 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex as StdMutex, time::Duration};
+
+    use futures_util::{future::BoxFuture, lock::Mutex};
+    use tokio::{sync::mpsc::channel, time::Instant};
+
+    use crate::{
+        controller::SwitchState,
+        error::GfxError,
+        pci_device::{GfxMode, GfxVendor},
+        power_watch::DeviceEvent,
+        runtime_pm_guard::{
+            is_reprobe, reassert_runtime_pm, reassert_skipped, watch_reprobes, Debounce,
+            ReprobeState, ReprobeStats, ReprobeSystem,
+        },
+    };
+
+    const DGPU: &str = "0000:01:00.0";
+    const AUDIO: &str = "0000:01:00.1";
+
+    fn event(action: &str, function: &str) -> DeviceEvent {
+        DeviceEvent {
+            action: action.to_string(),
+            function: function.to_string(),
+        }
+    }
+
+    fn hybrid() -> ReprobeState {
+        ReprobeState {
+            mode: GfxMode::Hybrid,
+            vendor: GfxVendor::Nvidia,
+            switch_state: SwitchState::Idle,
+        }
+    }
+
+    /// Devices whose `power/control` a re-probe can reset, recording each write
+    struct RecordingDevices {
+        state: StdMutex<ReprobeState>,
+        control: StdMutex<Vec<(String, String)>>,
+        written: StdMutex<Vec<String>>,
+    }
+
+    impl RecordingDevices {
+        fn new(state: ReprobeState, control: &[(&str, &str)]) -> Self {
+            Self {
+                state: StdMutex::new(state),
+                control: StdMutex::new(
+                    control
+                        .iter()
+                        .map(|(f, c)| (f.to_string(), c.to_string()))
+                        .collect(),
+                ),
+                written: StdMutex::new(Vec::new()),
+            }
+        }
+
+        /// A re-probe by the driver, which sets `power/control` to `on`
+        fn reprobed(&self) {
+            for (_, control) in self.control.lock().unwrap().iter_mut() {
+                *control = "on".to_string();
+            }
+        }
+
+        fn written(&self) -> Vec<String> {
+            self.written.lock().unwrap().clone()
+        }
+    }
+
+    impl ReprobeSystem for RecordingDevices {
+        fn state(&self) -> BoxFuture<'_, ReprobeState> {
+            let state = *self.state.lock().unwrap();
+            Box::pin(async move { state })
+        }
+
+        fn runtime_pm(&self) -> BoxFuture<'_, Vec<(String, String)>> {
+            let control = self.control.lock().unwrap().clone();
+            Box::pin(async move { control })
+        }
+
+        fn set_auto(&self, function: String) -> BoxFuture<'_, Result<(), GfxError>> {
+            for (f, control) in self.control.lock().unwrap().iter_mut() {
+                if *f == function {
+                    *control = "auto".to_string();
+                }
+            }
+            self.written.lock().unwrap().push(function);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn reprobe_events() {
+        let functions = [DGPU.to_string(), AUDIO.to_string()];
+        assert!(is_reprobe(&event("bind", DGPU), &functions));
+        assert!(is_reprobe(&event("add", AUDIO), &functions));
+        assert!(is_reprobe(&event("change", DGPU), &functions));
+        // Nothing to put back once the driver is gone
+        assert!(!is_reprobe(&event("unbind", DGPU), &functions));
+        assert!(!is_reprobe(&event("remove", DGPU), &functions));
+        // Not a function supergfxd manages
+        assert!(!is_reprobe(&event("bind", "0000:00:02.0"), &functions));
+    }
+
+    #[test]
+    fn debounce_waits_for_quiet() {
+        let window = Duration::from_secs(3);
+        let mut debounce = Debounce::new(window, Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(debounce.deadline(), None);
+        assert!(!debounce.take(start));
+
+        debounce.event(start);
+        debounce.event(start + Duration::from_secs(2));
+        assert_eq!(debounce.deadline(), Some(start + Duration::from_secs(5)));
+        assert!(!debounce.take(start + Duration::from_secs(4)));
+        assert!(debounce.take(start + Duration::from_secs(5)));
+        // Started over
+        assert_eq!(debounce.deadline(), None);
+
+        // Events every 2s are cut off at the limit
+        for secs in [0, 2, 4, 6, 8, 10] {
+            debounce.event(start + Duration::from_secs(secs));
+        }
+        assert_eq!(debounce.deadline(), Some(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn skipped_by_mode_and_switch() {
+        assert_eq!(reassert_skipped(&hybrid()), None);
+        for mode in [GfxMode::Integrated, GfxMode::AsusMuxDgpu] {
+            assert_eq!(reassert_skipped(&ReprobeState { mode, ..hybrid() }), None);
+        }
+        assert_eq!(
+            reassert_skipped(&ReprobeState {
+                switch_state: SwitchState::Switching,
+                ..hybrid()
+            }),
+            Some("a switch is running")
+        );
+        // A switch which stalled is no longer changing the devices
+        assert_eq!(
+            reassert_skipped(&ReprobeState {
+                switch_state: SwitchState::Stalled,
+                ..hybrid()
+            }),
+            None
+        );
+        assert!(reassert_skipped(&ReprobeState {
+            mode: GfxMode::Vfio,
+            ..hybrid()
+        })
+        .is_some());
+        assert!(reassert_skipped(&ReprobeState {
+            vendor: GfxVendor::AsusDgpuDisabled,
+            ..hybrid()
+        })
+        .is_some());
+    }
+
+    #[tokio::test]
+    async fn puts_back_only_what_changed() {
+        let devices = RecordingDevices::new(hybrid(), &[(DGPU, "on"), (AUDIO, "auto")]);
+        let stats = Mutex::new(ReprobeStats::default());
+        assert_eq!(
+            reassert_runtime_pm(&devices, &stats).await,
+            ["0000:01:00.0 on -> auto"]
+        );
+        assert_eq!(devices.written(), [DGPU]);
+        // Already auto
+        assert!(reassert_runtime_pm(&devices, &stats).await.is_empty());
+        let stats = stats.lock().await.clone();
+        assert_eq!(stats.corrections, 1);
+        assert_eq!(stats.last_changed, ["0000:01:00.0 on -> auto"]);
+        assert!(stats.last_correction.is_some());
+
+        let devices = RecordingDevices::new(
+            ReprobeState {
+                mode: GfxMode::Vfio,
+                ..hybrid()
+            },
+            &[(DGPU, "on")],
+        );
+        let stats = Mutex::new(ReprobeStats::default());
+        assert!(reassert_runtime_pm(&devices, &stats).await.is_empty());
+        assert!(devices.written().is_empty());
+        assert_eq!(stats.lock().await.skipped, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn corrects_once_per_reprobe() {
+        let functions = vec![DGPU.to_string(), AUDIO.to_string()];
+        let devices = RecordingDevices::new(hybrid(), &[(DGPU, "auto"), (AUDIO, "auto")]);
+        let stats = Mutex::new(ReprobeStats::default());
+        let (tx, rx) = channel(16);
+        let window = Duration::from_secs(3);
+
+        let events = async {
+            // A re-probe: several events on both functions, and one on another device
+            devices.reprobed();
+            for e in [
+                event("unbind", DGPU),
+                event("bind", DGPU),
+                event("bind", "0000:00:02.0"),
+                event("bind", AUDIO),
+            ] {
+                tx.send(e).await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            tx.send(event("change", DGPU)).await.unwrap();
+            // Still within the window of the last event
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            assert!(devices.written().is_empty());
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            let mut written = devices.written();
+            written.sort();
+            assert_eq!(written, [DGPU, AUDIO]);
+
+            // During a switch nothing is written
+            devices.state.lock().unwrap().switch_state = SwitchState::Switching;
+            devices.reprobed();
+            tx.send(event("bind", DGPU)).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            assert_eq!(devices.written().len(), 2);
+            drop(tx);
+        };
+        tokio::join!(
+            watch_reprobes(
+                rx,
+                &functions,
+                Debounce::new(window, Duration::from_secs(30)),
+                &devices,
+                &stats,
+            ),
+            events
+        );
+
+        let stats = stats.lock().await;
+        assert_eq!(stats.events, 4);
+        assert_eq!(stats.corrections, 1);
+        assert_eq!(stats.skipped, 1);
+    }
+}
@@ -0,0 +1,251 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::atomic::AtomicU8,
+    };
+
+    use futures_util::future::BoxFuture;
+
+    use crate::{
+        actions::StagedAction,
+        config::{write_modprobe_conf_to, GfxConfig},
+        error::GfxError,
+        pci_device::{Device, GfxMode, GfxVendor, HotplugType},
+        pci_lock::PciLock,
+        sandbox::{
+            action_access, base_access, covers, disable_drift_check, enable_drift_check,
+            generate_sandbox_profile, in_action, note_write, planned_actions, DriftMiss,
+            PROFILE_HEADER,
+        },
+        switch_plan::{execute_plan, plan_switch, PlanEnv, SwitchOps, SwitchOutcome},
+        sysfs::Sysfs,
+    };
+
+    const DGPU: &str = "sys/devices/pci0000:00/0000:00:01.1/0000:01:00.0";
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-sandbox-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        root
+    }
+
+    fn put(root: &Path, path: &str, value: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, value).unwrap();
+    }
+
+    fn switch_actions(config: &GfxConfig, vendor: GfxVendor) -> Vec<StagedAction> {
+        planned_actions(
+            config,
+            vendor,
+            &[GfxMode::Hybrid, GfxMode::Integrated],
+            &|_| PlanEnv::default(),
+        )
+    }
+
+    #[test]
+    fn coverage() {
+        assert!(covers(
+            "/sys/bus/pci",
+            Path::new("/sys/bus/pci/drivers/nvidia/unbind")
+        ));
+        assert!(covers(
+            "/sys/devices/pci*",
+            Path::new("/sys/devices/pci0000:00/0000:01:00.0/remove")
+        ));
+        assert!(covers(
+            "/sys/bus/platform/drivers/legion/*/gsync",
+            Path::new("/sys/bus/platform/drivers/legion/PNP0C09:00/gsync")
+        ));
+        assert!(!covers("/sys/bus/pci", Path::new("/sys/bus/platform")));
+        assert!(!covers("/sys/bus/pci/slots", Path::new("/sys/bus/pci")));
+        assert!(!covers(
+            "/sys/devices/pci*",
+            Path::new("/sys/devices/platform")
+        ));
+    }
+
+    #[test]
+    fn profile_covers_a_switch() {
+        let root = root("profile");
+        fs::create_dir_all(root.join(DGPU)).unwrap();
+        fs::create_dir_all(root.join("etc/supergfxd")).unwrap();
+
+        for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
+            for hotplug_type in [HotplugType::None, HotplugType::Std, HotplugType::Asus] {
+                let mut config = GfxConfig::new(String::new());
+                config.hotplug_type = hotplug_type;
+                let actions = switch_actions(&config, vendor);
+                assert!(actions.contains(&StagedAction::WriteModprobeConf));
+                let profile = generate_sandbox_profile(&actions, &base_access(&config), &root);
+                assert!(profile.starts_with(PROFILE_HEADER), "{profile}");
+                let lines: Vec<&str> = profile.lines().collect();
+                let caps = lines
+                    .iter()
+                    .find_map(|line| line.strip_prefix("CapabilityBoundingSet="))
+                    .unwrap();
+
+                for action in actions {
+                    let access = action_access(action);
+                    for path in access.writes {
+                        let expected = if path.contains('*') {
+                            // Resolved to the PCI domain of the dGPU
+                            "ReadWritePaths=/sys/devices/pci0000:00".to_string()
+                        } else if root.join(&path[1..]).exists() {
+                            format!("ReadWritePaths={path}")
+                        } else {
+                            format!("ReadWritePaths=-{path}")
+                        };
+                        assert!(
+                            lines.contains(&expected.as_str()),
+                            "{vendor:?} {hotplug_type:?} {action:?}: {expected}\n{profile}"
+                        );
+                    }
+                    for cap in access.caps {
+                        assert!(caps.split(' ').any(|c| c == *cap), "{action:?} {cap}");
+                    }
+                }
+                // The drivers are loaded, so modules can't be protected
+                assert!(caps.contains("CAP_SYS_MODULE"));
+                assert!(!profile.contains("ProtectKernelModules=yes"));
+                assert!(lines.contains(&"ReadWritePaths=/etc/supergfxd"));
+                assert!(lines.contains(&"DeviceAllow=/dev/tty1 w"));
+            }
+        }
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn base_operations_follow_the_config() {
+        let mut config = GfxConfig::new(String::new());
+        let writes = |config: &GfxConfig| -> Vec<&str> {
+            base_access(config)
+                .iter()
+                .flat_map(|base| base.access.writes.iter().copied())
+                .collect()
+        };
+        assert!(!writes(&config).contains(&"/etc/modules-load.d/asus.conf"));
+        config.hotplug_type = HotplugType::Asus;
+        assert!(writes(&config).contains(&"/etc/modules-load.d/asus.conf"));
+
+        // Nothing is written when nothing is done
+        let profile = generate_sandbox_profile(&[], &[], Path::new("/nonexistent"));
+        assert!(!profile.contains("ReadWritePaths="));
+        assert!(profile.contains("ProtectKernelModules=yes"));
+    }
+
+    /// Does the writes of the actions of a switch under `root`, through the same functions
+    /// as the running daemon
+    struct MockSystemOps {
+        root: PathBuf,
+        dgpu: Device,
+        sysfs: Sysfs,
+    }
+
+    impl MockSystemOps {
+        async fn pci_lock(&self) -> PciLock {
+            let path = self.root.join("run/supergfxd/pci.lock");
+            PciLock::acquire_at(&path, std::time::Duration::from_secs(1)).await
+        }
+    }
+
+    impl SwitchOps for MockSystemOps {
+        fn perform(
+            &self,
+            action: StagedAction,
+            _mode: GfxMode,
+        ) -> BoxFuture<'_, Result<(), GfxError>> {
+            Box::pin(in_action(action, async move {
+                match action {
+                    StagedAction::UnbindRemoveGpu => {
+                        let _lock = self.pci_lock().await;
+                        self.dgpu.unbind().await?;
+                        self.dgpu.remove().await
+                    }
+                    StagedAction::UnbindGpu => self.dgpu.unbind().await,
+                    StagedAction::RescanPci => {
+                        let _lock = self.pci_lock().await;
+                        self.sysfs.pci_rescan.rescan()
+                    }
+                    StagedAction::WriteModprobeConf => write_modprobe_conf_to(
+                        &self.root.join("etc/modprobe.d/supergfxd.conf"),
+                        b"blacklist nouveau\n",
+                    ),
+                    _ => Ok(()),
+                }
+            }))
+        }
+
+        fn rollback(&self, _mode: GfxMode) -> BoxFuture<'_, Vec<StagedAction>> {
+            Box::pin(async { Vec::new() })
+        }
+    }
+
+    #[tokio::test]
+    async fn drift_check_of_a_switch() {
+        let root = root("drift");
+        put(&root, &format!("{DGPU}/remove"), "");
+        put(&root, "sys/bus/pci/drivers/nvidia/unbind", "");
+        put(&root, "sys/bus/pci/rescan", "");
+        fs::create_dir_all(root.join("etc/modprobe.d")).unwrap();
+        std::os::unix::fs::symlink(
+            root.join("sys/bus/pci/drivers/nvidia"),
+            root.join(DGPU).join("driver"),
+        )
+        .unwrap();
+        let ops = MockSystemOps {
+            root: root.clone(),
+            dgpu: Device::mock("0000:01:00.0", GfxVendor::Nvidia, true)
+                .with_dev_path(&root.join(DGPU)),
+            sysfs: Sysfs::at(&root),
+        };
+
+        let config = GfxConfig::new(String::new());
+        let env = PlanEnv::default();
+        enable_drift_check(&root);
+        for (from, to) in [
+            (GfxMode::Hybrid, GfxMode::Integrated),
+            (GfxMode::Integrated, GfxMode::Hybrid),
+        ] {
+            let actions = plan_switch(&config, GfxVendor::Nvidia, from, to, &env)
+                .actions
+                .unwrap();
+            let outcome = execute_plan(to, &actions, &AtomicU8::new(0), &ops).await;
+            assert_eq!(outcome, SwitchOutcome::Completed, "{from} to {to}");
+        }
+        assert_eq!(
+            fs::read_to_string(root.join("sys/bus/pci/rescan")).unwrap(),
+            "1"
+        );
+
+        // Written by the wrong action, and outside of any
+        let conf = root.join("etc/modprobe.d/supergfxd.conf");
+        in_action(StagedAction::RescanPci, async { note_write(&conf) }).await;
+        note_write(&root.join("opt/supergfxd"));
+        // Not under the root checked
+        note_write(Path::new("/etc/modprobe.d/supergfxd.conf"));
+        assert_eq!(
+            disable_drift_check(),
+            [
+                DriftMiss {
+                    action: Some(StagedAction::RescanPci),
+                    path: PathBuf::from("/etc/modprobe.d/supergfxd.conf"),
+                },
+                DriftMiss {
+                    action: None,
+                    path: PathBuf::from("/opt/supergfxd"),
+                },
+            ]
+        );
+        // Off again
+        note_write(&root.join("opt/supergfxd"));
+        assert!(disable_drift_check().is_empty());
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,396 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures_util::future::BoxFuture;
+
+    use crate::{
+        ac_automation::{AcContext, AcDecision, AcProbe},
+        config::GfxConfig,
+        error::GfxError,
+        pci_device::GfxMode,
+        schedule::{
+            decide_schedule, local_to_utc, next_trigger, parse_time_of_day, take_when_idle,
+            validate_schedule, HeldEntry, LocalZone, ScheduleClock, ScheduleEntry,
+        },
+    };
+
+    const DAY: i64 = 24 * 3600;
+    /// A day in 2024, in days since the epoch
+    const D: i64 = 19_800;
+
+    /// A fixed offset from UTC, in seconds
+    struct Fixed(i64);
+
+    impl LocalZone for Fixed {
+        fn offset_at(&self, _utc: i64) -> i64 {
+            self.0
+        }
+    }
+
+    /// A zone which changes from `before` to `after` at `change`
+    struct Dst {
+        change: i64,
+        before: i64,
+        after: i64,
+    }
+
+    impl LocalZone for Dst {
+        fn offset_at(&self, utc: i64) -> i64 {
+            if utc < self.change {
+                self.before
+            } else {
+                self.after
+            }
+        }
+    }
+
+    /// UTC+1 going forward to UTC+2 at 02:00 local time on day `D`
+    fn spring_forward() -> Dst {
+        Dst {
+            change: D * DAY + 3600,
+            before: 3600,
+            after: 7200,
+        }
+    }
+
+    /// UTC+2 going back to UTC+1 at 03:00 local time on day `D`
+    fn fall_back() -> Dst {
+        Dst {
+            change: D * DAY + 3600,
+            before: 7200,
+            after: 3600,
+        }
+    }
+
+    /// The local time of `utc` in `zone`, as seconds past midnight of day `D`
+    fn local(zone: &dyn LocalZone, utc: i64) -> i64 {
+        utc + zone.offset_at(utc) - D * DAY
+    }
+
+    fn hm(hour: i64, minute: i64) -> i64 {
+        hour * 3600 + minute * 60
+    }
+
+    fn entry(at: &str, mode: GfxMode) -> ScheduleEntry {
+        ScheduleEntry {
+            at: at.to_string(),
+            mode,
+        }
+    }
+
+    /// Integrated from 22:30 and Hybrid from 08:00
+    fn night() -> Vec<(u32, GfxMode)> {
+        vec![
+            (22 * 60 + 30, GfxMode::Integrated),
+            (8 * 60, GfxMode::Hybrid),
+        ]
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!(parse_time_of_day("08:00"), Ok(480));
+        assert_eq!(parse_time_of_day("22:30"), Ok(1350));
+        assert_eq!(parse_time_of_day("8:05"), Ok(485));
+        assert_eq!(parse_time_of_day("0:00"), Ok(0));
+        assert_eq!(parse_time_of_day("23:59"), Ok(1439));
+        for bad in [
+            "24:00", "12:60", "12", "12:5", "123:00", "ab:cd", "+1:00", " 8:00", "8:00 ", "",
+            "08:00:00",
+        ] {
+            assert!(parse_time_of_day(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let ok = [
+            entry("22:30", GfxMode::Integrated),
+            entry("08:00", GfxMode::Hybrid),
+        ];
+        assert!(validate_schedule(&ok).is_ok());
+        assert!(validate_schedule(&[]).is_ok());
+        assert!(validate_schedule(&ok[..1]).is_ok());
+
+        let refused = |schedule: &[ScheduleEntry], detail: &str| match validate_schedule(schedule) {
+            Err(GfxError::Schedule(err)) => assert!(err.contains(detail), "{err}"),
+            res => panic!("expected Schedule, got {res:?}"),
+        };
+        refused(
+            &[
+                entry("8:00", GfxMode::Hybrid),
+                entry("08:00", GfxMode::Integrated),
+            ],
+            "same time",
+        );
+        // Integrated at 22:30 then again at 23:00, the second does nothing
+        refused(
+            &[
+                entry("22:30", GfxMode::Integrated),
+                entry("08:00", GfxMode::Hybrid),
+                entry("23:00", GfxMode::Integrated),
+            ],
+            "repeats",
+        );
+        // Around midnight too
+        refused(
+            &[
+                entry("00:30", GfxMode::Integrated),
+                entry("08:00", GfxMode::Hybrid),
+                entry("23:00", GfxMode::Integrated),
+            ],
+            "repeats",
+        );
+        refused(
+            &[
+                entry("22:30", GfxMode::None),
+                entry("08:00", GfxMode::Hybrid),
+            ],
+            "no mode",
+        );
+        refused(&[entry("22:30", GfxMode::AsusMuxDgpu)], "reboot");
+        refused(&[entry("25:00", GfxMode::Integrated)], "not a time of day");
+    }
+
+    #[test]
+    fn config_with_bad_schedule_is_not_used() {
+        let dir =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-schedule", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let mut json = serde_json::to_value(GfxConfig::new(String::new())).unwrap();
+        json["schedule"] = serde_json::json!([
+            { "at": "22:30", "mode": "Integrated" },
+            { "at": "22:30", "mode": "Hybrid" },
+        ]);
+        fs::write(&path, json.to_string()).unwrap();
+        assert!(GfxConfig::peek(&path.to_string_lossy()).schedule.is_empty());
+
+        json["schedule"][1]["at"] = serde_json::json!("08:00");
+        fs::write(&path, json.to_string()).unwrap();
+        let config = GfxConfig::peek(&path.to_string_lossy());
+        assert_eq!(
+            config.schedule,
+            [
+                entry("22:30", GfxMode::Integrated),
+                entry("08:00", GfxMode::Hybrid)
+            ]
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn next_trigger_in_fixed_zone() {
+        let zone = Fixed(7200);
+        let evening = D * DAY + hm(21, 0) - 7200;
+        let (at, mode) = next_trigger(&night(), evening, &zone).unwrap();
+        assert_eq!((local(&zone, at), mode), (hm(22, 30), GfxMode::Integrated));
+        // The next is the morning after
+        let (at, mode) = next_trigger(&night(), at, &zone).unwrap();
+        assert_eq!((local(&zone, at), mode), (DAY + hm(8, 0), GfxMode::Hybrid));
+        assert_eq!(next_trigger(&[], evening, &zone), None);
+
+        // West of UTC the local day starts later
+        let zone = Fixed(-5 * 3600);
+        let late = D * DAY + hm(23, 0) + 5 * 3600;
+        let (at, mode) = next_trigger(&night(), late, &zone).unwrap();
+        assert_eq!((local(&zone, at), mode), (DAY + hm(8, 0), GfxMode::Hybrid));
+    }
+
+    #[test]
+    fn clocks_going_forward() {
+        let zone = spring_forward();
+        // 02:30 doesn't happen, it is as late after the change as it is after 02:00
+        let at = local_to_utc(&zone, D, 2 * 60 + 30);
+        assert_eq!(local(&zone, at), hm(3, 30));
+        // 03:00 is the change itself
+        assert_eq!(local_to_utc(&zone, D, 3 * 60), zone.change);
+        // Others are in the offset of their side of it
+        assert_eq!(local(&zone, local_to_utc(&zone, D, 60)), hm(1, 0));
+        assert_eq!(local(&zone, local_to_utc(&zone, D, 8 * 60)), hm(8, 0));
+        assert_eq!(
+            local(&zone, local_to_utc(&zone, D - 1, 22 * 60 + 30)),
+            hm(22, 30) - DAY
+        );
+    }
+
+    #[test]
+    fn clocks_going_back_trigger_once() {
+        let zone = fall_back();
+        // 02:30 happens twice, the first is used
+        let at = local_to_utc(&zone, D, 2 * 60 + 30);
+        assert_eq!(at, D * DAY + hm(0, 30));
+        assert_eq!(local(&zone, at), hm(2, 30));
+
+        let schedule = [
+            (2 * 60 + 30, GfxMode::Integrated),
+            (8 * 60, GfxMode::Hybrid),
+        ];
+        let mut clock = ScheduleClock::default();
+        let mut due = Vec::new();
+        let mut now = D * DAY - hm(2, 0);
+        while now < D * DAY + hm(6, 0) {
+            due.extend(clock.tick(&schedule, now, &zone));
+            now += 60;
+        }
+        assert_eq!(due, [GfxMode::Integrated]);
+    }
+
+    #[test]
+    fn clock_ticks() {
+        let zone = Fixed(0);
+        let mut clock = ScheduleClock::default();
+        let before = D * DAY + hm(22, 29);
+        // Nothing is due on the first look, even at the time of an entry
+        assert_eq!(clock.tick(&night(), before + 60, &zone), None);
+        let mut clock = ScheduleClock::default();
+        assert_eq!(clock.tick(&night(), before, &zone), None);
+        assert_eq!(
+            clock.tick(&night(), before + 60, &zone),
+            Some(GfxMode::Integrated)
+        );
+        assert_eq!(clock.tick(&night(), before + 120, &zone), None);
+
+        // Set back over the entry, it comes due again
+        assert_eq!(clock.tick(&night(), before, &zone), None);
+        assert_eq!(
+            clock.tick(&night(), before + 90, &zone),
+            Some(GfxMode::Integrated)
+        );
+
+        // Two entries within a step, the last one is in effect
+        let close = [(60, GfxMode::Integrated), (61, GfxMode::Hybrid)];
+        let mut clock = ScheduleClock::default();
+        assert_eq!(clock.tick(&close, D * DAY + hm(0, 59), &zone), None);
+        assert_eq!(
+            clock.tick(&close, D * DAY + hm(1, 1), &zone),
+            Some(GfxMode::Hybrid)
+        );
+    }
+
+    #[test]
+    fn suspend_skips_entries() {
+        let zone = Fixed(3600);
+        let evening = D * DAY + hm(21, 0) - 3600;
+        let mut clock = ScheduleClock::default();
+        assert_eq!(clock.tick(&night(), evening, &zone), None);
+        // Suspended over 22:30, woken at 23:00
+        let wake = evening + hm(2, 0);
+        assert_eq!(clock.tick(&night(), wake, &zone), None);
+        // The next trigger is found from the wake
+        let (at, mode) = next_trigger(&night(), wake, &zone).unwrap();
+        assert_eq!((local(&zone, at), mode), (DAY + hm(8, 0), GfxMode::Hybrid));
+        assert_eq!(clock.tick(&night(), wake + 60, &zone), None);
+        assert_eq!(clock.tick(&night(), at, &zone), None);
+        // Ticking on from there the morning entry comes due
+        let mut clock = ScheduleClock::default();
+        assert_eq!(clock.tick(&night(), at - 30, &zone), None);
+        assert_eq!(clock.tick(&night(), at, &zone), Some(GfxMode::Hybrid));
+    }
+
+    #[test]
+    fn held_while_busy() {
+        let mut held = Some(HeldEntry {
+            mode: GfxMode::Integrated,
+            user_switches: 3,
+        });
+        assert_eq!(take_when_idle(&mut held, true, 3), None);
+        assert!(held.is_some());
+        assert_eq!(
+            take_when_idle(&mut held, false, 3),
+            Some(GfxMode::Integrated)
+        );
+        assert_eq!(held, None);
+        assert_eq!(take_when_idle(&mut held, false, 3), None);
+
+        // The user switched while it was held
+        let mut held = Some(HeldEntry {
+            mode: GfxMode::Integrated,
+            user_switches: 3,
+        });
+        assert_eq!(take_when_idle(&mut held, true, 4), None);
+        assert_eq!(take_when_idle(&mut held, false, 4), None);
+        assert_eq!(held, None);
+    }
+
+    struct MockProbe {
+        sessions_active: bool,
+        dgpu_users: Vec<String>,
+    }
+
+    impl AcProbe for MockProbe {
+        fn sessions_active(&self) -> BoxFuture<'_, Result<bool, GfxError>> {
+            Box::pin(async move { Ok(self.sessions_active) })
+        }
+
+        fn dgpu_users(&self) -> BoxFuture<'_, Vec<String>> {
+            Box::pin(async move { self.dgpu_users.clone() })
+        }
+    }
+
+    fn context(mode: GfxMode) -> AcContext {
+        AcContext {
+            mode,
+            supported: vec![GfxMode::Hybrid, GfxMode::Integrated],
+            switching: false,
+            mode_locked: false,
+            always_reboot: false,
+            mutation_allowed: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        let suggest = |reason: &str| AcDecision::Suggest {
+            mode: GfxMode::Integrated,
+            reason: reason.to_string(),
+        };
+        let idle = MockProbe {
+            sessions_active: false,
+            dgpu_users: Vec::new(),
+        };
+        let sessions = MockProbe {
+            sessions_active: true,
+            dgpu_users: Vec::new(),
+        };
+        let used = MockProbe {
+            sessions_active: false,
+            dgpu_users: vec!["ollama (4321)".to_string()],
+        };
+        let hybrid = context(GfxMode::Hybrid);
+
+        assert_eq!(
+            decide_schedule(GfxMode::Integrated, &hybrid, &idle).await,
+            AcDecision::Apply(GfxMode::Integrated)
+        );
+        // Never while a session is active, the switch would end it
+        assert_eq!(
+            decide_schedule(GfxMode::Integrated, &hybrid, &sessions).await,
+            suggest("graphical sessions are active")
+        );
+        assert_eq!(
+            decide_schedule(GfxMode::Integrated, &hybrid, &used).await,
+            suggest("the dGPU is in use by ollama (4321)")
+        );
+        assert_eq!(
+            decide_schedule(GfxMode::Integrated, &context(GfxMode::Integrated), &idle).await,
+            AcDecision::Nothing
+        );
+        let unsupported = AcContext {
+            supported: vec![GfxMode::Hybrid],
+            ..context(GfxMode::Hybrid)
+        };
+        assert_eq!(
+            decide_schedule(GfxMode::Integrated, &unsupported, &idle).await,
+            suggest("Integrated is not supported right now")
+        );
+        let switching = AcContext {
+            switching: true,
+            ..context(GfxMode::Hybrid)
+        };
+        assert_eq!(
+            decide_schedule(GfxMode::Integrated, &switching, &idle).await,
+            suggest("a switch is already in progress")
+        );
+    }
+}
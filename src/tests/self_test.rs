@@ -0,0 +1,194 @@
+#[cfg(test)]
+mod tests {
+    use futures_util::future::BoxFuture;
+
+    use crate::{
+        actions::StagedAction,
+        config::GfxConfig,
+        error::GfxError,
+        pci_device::{GfxMode, GfxVendor},
+        self_test::{choose_target, run_self_test, SelfTestEnv, SystemSnapshot, TestTarget},
+    };
+
+    /// A fake machine: switches change the mode and the config mode, and a switch can be
+    /// made to fail part way through leaving the state changed
+    #[derive(Default)]
+    struct MockEnv {
+        state: SystemSnapshot,
+        fail_switch_to: Option<GfxMode>,
+        fail_check: bool,
+        switches: Vec<GfxMode>,
+        restores: u32,
+    }
+
+    impl SelfTestEnv for MockEnv {
+        fn snapshot(&mut self) -> BoxFuture<'_, Result<SystemSnapshot, GfxError>> {
+            Box::pin(async move { Ok(self.state.clone()) })
+        }
+
+        fn checks(
+            &mut self,
+            _from: GfxMode,
+            to: GfxMode,
+        ) -> BoxFuture<'_, Vec<(String, Result<String, String>)>> {
+            Box::pin(async move {
+                let result = if self.fail_check {
+                    Err("missing".to_string())
+                } else {
+                    Ok(String::new())
+                };
+                vec![(format!("modules for {to}"), result)]
+            })
+        }
+
+        fn switch(&mut self, mode: GfxMode) -> BoxFuture<'_, Result<(), GfxError>> {
+            Box::pin(async move {
+                self.switches.push(mode);
+                // The config is written before the switch fails
+                self.state.config_mode = mode;
+                if self.fail_switch_to == Some(mode) {
+                    return Err(GfxError::NotSupported("switch failed".to_string()));
+                }
+                self.state.mode = mode;
+                self.state.modprobe_conf = Some(format!("{mode}"));
+                Ok(())
+            })
+        }
+
+        fn restore<'a>(
+            &'a mut self,
+            before: &'a SystemSnapshot,
+        ) -> BoxFuture<'a, Result<(), GfxError>> {
+            Box::pin(async move {
+                self.restores += 1;
+                self.state = before.clone();
+                Ok(())
+            })
+        }
+    }
+
+    fn hybrid_env() -> MockEnv {
+        MockEnv {
+            state: SystemSnapshot {
+                mode: GfxMode::Hybrid,
+                config_mode: GfxMode::Hybrid,
+                modprobe_conf: Some("Hybrid".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    const ROUND_TRIP: Option<TestTarget> = Some(TestTarget {
+        mode: GfxMode::Vfio,
+        round_trip: true,
+    });
+
+    #[tokio::test]
+    async fn round_trip_passes() {
+        let mut env = hybrid_env();
+        let report = run_self_test(&mut env, GfxMode::Hybrid, ROUND_TRIP).await;
+        assert!(report.passed, "{report:?}");
+        assert!(!report.restored);
+        assert_eq!(report.test_mode, GfxMode::Vfio);
+        assert_eq!(env.switches, [GfxMode::Vfio, GfxMode::Hybrid]);
+        assert_eq!(env.restores, 0);
+        let names: Vec<&str> = report.phases.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "snapshot before",
+                "modules for Vfio",
+                "switch to Vfio",
+                "switch back to Hybrid",
+                "compare with snapshot",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_switch_back_is_restored() {
+        let mut env = MockEnv {
+            fail_switch_to: Some(GfxMode::Hybrid),
+            ..hybrid_env()
+        };
+        let before = env.state.clone();
+        let report = run_self_test(&mut env, GfxMode::Hybrid, ROUND_TRIP).await;
+        assert!(!report.passed);
+        assert!(report.restored);
+        assert_eq!(env.restores, 1);
+        assert_eq!(env.state, before);
+        let compare = report
+            .phases
+            .iter()
+            .find(|p| p.name == "compare with snapshot")
+            .unwrap();
+        assert!(!compare.passed);
+        assert!(compare.detail.contains("mode is Vfio was Hybrid"));
+        assert!(report.phases.last().unwrap().passed);
+    }
+
+    #[tokio::test]
+    async fn checks_only_without_round_trip() {
+        let mut env = hybrid_env();
+        let target = Some(TestTarget {
+            mode: GfxMode::Integrated,
+            round_trip: false,
+        });
+        let report = run_self_test(&mut env, GfxMode::Hybrid, target).await;
+        assert!(report.passed);
+        assert_eq!(report.test_mode, GfxMode::None);
+        assert!(env.switches.is_empty());
+        assert_eq!(report.phases[1].name, "modules for Integrated");
+    }
+
+    #[tokio::test]
+    async fn failed_check_prevents_switch() {
+        let mut env = MockEnv {
+            fail_check: true,
+            ..hybrid_env()
+        };
+        let report = run_self_test(&mut env, GfxMode::Hybrid, ROUND_TRIP).await;
+        assert!(!report.passed);
+        assert!(!report.restored);
+        assert!(env.switches.is_empty());
+        assert_eq!(env.restores, 0);
+    }
+
+    #[test]
+    fn target_is_cheapest_without_logout() {
+        let config = GfxConfig {
+            vfio_enable: true,
+            ..GfxConfig::new(Default::default())
+        };
+        let plan =
+            |from, to| StagedAction::action_list_for_switch(&config, GfxVendor::Nvidia, from, to);
+
+        assert_eq!(
+            choose_target(
+                GfxMode::Integrated,
+                &[GfxMode::Integrated, GfxMode::Hybrid, GfxMode::Vfio],
+                plan
+            ),
+            Some(TestTarget {
+                mode: GfxMode::Vfio,
+                round_trip: true,
+            })
+        );
+        assert_eq!(
+            choose_target(
+                GfxMode::Hybrid,
+                &[GfxMode::Integrated, GfxMode::Hybrid],
+                plan
+            ),
+            Some(TestTarget {
+                mode: GfxMode::Integrated,
+                round_trip: false,
+            })
+        );
+        assert_eq!(
+            choose_target(GfxMode::Hybrid, &[GfxMode::Hybrid], plan),
+            None
+        );
+    }
+}
@@ -0,0 +1,70 @@
+#[cfg(test)]
+mod tests {
+    use crate::signal_counters::{Signal, SignalLedger};
+
+    #[test]
+    fn counted_once_per_emission() {
+        let mut ledger = SignalLedger::new();
+        let counters = ledger.counters();
+        assert_eq!(counters.sequence, 0);
+        assert_eq!(counters.signals.len(), Signal::ALL.len());
+        assert!(counters.signals.iter().all(|(_, count)| *count == 0));
+
+        assert_eq!(ledger.stamp(Signal::Action), 1);
+        assert_eq!(ledger.stamp(Signal::Gfx), 2);
+        assert_eq!(ledger.stamp(Signal::Gfx), 3);
+        let counters = ledger.counters();
+        assert_eq!(counters.sequence, 3);
+        assert_eq!(counters.count("NotifyAction"), 1);
+        assert_eq!(counters.count("NotifyGfx"), 2);
+        assert_eq!(counters.count("NotifyError"), 0);
+        assert_eq!(counters.count("NotifyNothing"), 0);
+        // The sequence is the sum of the counts
+        let total: u64 = counters.signals.iter().map(|(_, count)| count).sum();
+        assert_eq!(total, counters.sequence);
+    }
+
+    #[test]
+    fn missed_signals_across_a_reconnect() {
+        let mut ledger = SignalLedger::new();
+        // A client sees each NotifyEvent while connected, keeping the last sequence
+        let mut last_seen = 0;
+        for signal in [Signal::ModeChange, Signal::Gfx, Signal::Action] {
+            last_seen = ledger.stamp(signal);
+        }
+        assert!(!ledger.counters().missed_since(last_seen));
+
+        // Suspended, nothing emitted meanwhile
+        assert!(!ledger.counters().missed_since(last_seen));
+
+        // Off the bus while a switch finished
+        ledger.stamp(Signal::GfxStatus);
+        let counters = ledger.counters();
+        assert!(counters.missed_since(last_seen));
+        assert_eq!(counters.count("NotifyGfxStatus"), 1);
+        // Caught up after reading the status again
+        last_seen = counters.sequence;
+        assert!(!ledger.counters().missed_since(last_seen));
+
+        // supergfxd restarted and counts from zero again
+        let mut restarted = SignalLedger::new();
+        assert!(restarted.counters().missed_since(last_seen));
+        restarted.stamp(Signal::SupportedChanged);
+        assert!(restarted.counters().missed_since(last_seen));
+    }
+
+    #[test]
+    fn names_are_signals_of_the_interface() {
+        let xml = include_str!("../../data/org.supergfxctl.Daemon.xml");
+        for signal in Signal::ALL {
+            assert!(
+                xml.contains(&format!("<signal name=\"{}\">", signal.name())),
+                "{signal:?}"
+            );
+        }
+        assert!(xml.contains("<signal name=\"NotifyEvent\">"));
+        // Every other signal is counted
+        let signals = xml.matches("<signal name=").count();
+        assert_eq!(signals, Signal::ALL.len() + 1);
+    }
+}
@@ -0,0 +1,140 @@
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::GfxError,
+        signal_counters::Signal,
+        signal_filter::{SignalFilter, SignalFilters, SignalRoute, SIGNAL_CATEGORIES},
+        validate::InputClass,
+    };
+
+    const WIDGET: &str = ":1.42";
+    const SHELL: &str = ":1.57";
+
+    fn categories(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn broadcast_without_filters() {
+        let filters = SignalFilters::new();
+        for category in SIGNAL_CATEGORIES {
+            assert_eq!(filters.route(category), SignalRoute::Broadcast);
+        }
+        assert!(filters.filters().is_empty());
+    }
+
+    #[test]
+    fn two_clients_with_different_filters() {
+        let mut filters = SignalFilters::new();
+        // A battery widget only wants the power status
+        filters.set(WIDGET, &categories(&["power"])).unwrap();
+        // A shell extension wants the mode and the progress of a switch
+        filters
+            .set(SHELL, &categories(&["mode", "progress", "power", "mode"]))
+            .unwrap();
+
+        assert_eq!(
+            filters.route("power"),
+            SignalRoute::Unicast(vec![WIDGET.to_string(), SHELL.to_string()])
+        );
+        assert_eq!(
+            filters.route("mode"),
+            SignalRoute::Unicast(vec![SHELL.to_string()])
+        );
+        assert_eq!(
+            filters.route("progress"),
+            SignalRoute::Unicast(vec![SHELL.to_string()])
+        );
+        // Nobody filtered for these, so old clients still get them
+        assert_eq!(filters.route("errors"), SignalRoute::Broadcast);
+        assert_eq!(filters.route("events"), SignalRoute::Broadcast);
+
+        // Duplicates are dropped, in the order asked for
+        assert_eq!(
+            filters.filters(),
+            vec![
+                SignalFilter {
+                    owner: WIDGET.to_string(),
+                    categories: categories(&["power"]),
+                },
+                SignalFilter {
+                    owner: SHELL.to_string(),
+                    categories: categories(&["mode", "progress", "power"]),
+                },
+            ]
+        );
+
+        // Replacing a filter drops what it had
+        filters.set(SHELL, &categories(&["errors"])).unwrap();
+        assert_eq!(filters.route("mode"), SignalRoute::Broadcast);
+        assert_eq!(
+            filters.route("errors"),
+            SignalRoute::Unicast(vec![SHELL.to_string()])
+        );
+        assert_eq!(
+            filters.route("power"),
+            SignalRoute::Unicast(vec![WIDGET.to_string()])
+        );
+
+        // An empty filter removes it
+        filters.set(WIDGET, &[]).unwrap();
+        assert_eq!(filters.route("power"), SignalRoute::Broadcast);
+        assert_eq!(filters.filters().len(), 1);
+    }
+
+    #[test]
+    fn removed_when_the_client_leaves() {
+        let mut filters = SignalFilters::new();
+        filters.set(WIDGET, &categories(&["power"])).unwrap();
+        filters.set(SHELL, &categories(&["power", "mode"])).unwrap();
+
+        assert!(filters.owner_gone(SHELL));
+        assert_eq!(
+            filters.route("power"),
+            SignalRoute::Unicast(vec![WIDGET.to_string()])
+        );
+        assert_eq!(filters.route("mode"), SignalRoute::Broadcast);
+        // A client without a filter leaving changes nothing
+        assert!(!filters.owner_gone(SHELL));
+        assert!(!filters.owner_gone(":1.99"));
+
+        assert!(filters.owner_gone(WIDGET));
+        assert!(filters.filters().is_empty());
+        assert_eq!(filters.route("power"), SignalRoute::Broadcast);
+    }
+
+    #[test]
+    fn unknown_categories_are_refused() {
+        let mut filters = SignalFilters::new();
+        filters.set(WIDGET, &categories(&["power"])).unwrap();
+        let err = filters
+            .set(WIDGET, &categories(&["mode", "battery"]))
+            .unwrap_err();
+        assert!(
+            matches!(err, GfxError::InvalidInput(InputClass::Identifier, ref detail) if detail.contains("battery")),
+            "{err}"
+        );
+        // The filter it had is kept
+        assert_eq!(
+            filters.route("power"),
+            SignalRoute::Unicast(vec![WIDGET.to_string()])
+        );
+        assert_eq!(filters.route("mode"), SignalRoute::Broadcast);
+    }
+
+    #[test]
+    fn every_signal_has_a_category() {
+        for signal in Signal::ALL {
+            assert!(SIGNAL_CATEGORIES.contains(&signal.category()), "{signal:?}");
+        }
+        // Each category but `events`, which is `NotifyEvent`, has a signal
+        for category in SIGNAL_CATEGORIES.iter().filter(|c| **c != "events") {
+            assert!(
+                Signal::ALL
+                    .iter()
+                    .any(|signal| signal.category() == *category),
+                "{category}"
+            );
+        }
+    }
+}
@@ -0,0 +1,408 @@
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc, time::Duration};
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        actions::UserActionRequired,
+        audit::Actor,
+        config::GfxConfig,
+        controller::CtrlGraphics,
+        error::GfxError,
+        pci_device::{DiscreetGpu, GfxMode, GfxVendor, HotplugType},
+        special_asus::{
+            asus_boot_safety_check_with, asus_settle_and_toggle, evaluate_asus_mode, reverify_mux,
+            AsusBootCheck, AsusEvaluation, AsusGpuMuxMode, AsusReadings, MuxRead, MuxReader,
+            MuxReverify, MUX_READ_WINDOW, MUX_REVERIFY_WINDOW,
+        },
+        sysfs::{Sysfs, ASUS_DGPU_DISABLE_PATH, ASUS_GPU_MUX_PATH},
+    };
+
+    /// Fails the read with EIO `not_ready` times, as asus-wmi does until the EC is ready,
+    /// then reads `mux`
+    struct EcNotReady {
+        not_ready: u32,
+        mux: AsusGpuMuxMode,
+        reads: std::sync::Mutex<u32>,
+    }
+
+    impl EcNotReady {
+        fn new(not_ready: u32, mux: AsusGpuMuxMode) -> Self {
+            Self {
+                not_ready,
+                mux,
+                reads: std::sync::Mutex::new(0),
+            }
+        }
+
+        fn reads(&self) -> u32 {
+            *self.reads.lock().unwrap()
+        }
+    }
+
+    impl MuxReader for EcNotReady {
+        fn read_mux(&self) -> MuxRead {
+            let mut reads = self.reads.lock().unwrap();
+            *reads += 1;
+            let read = if *reads > self.not_ready {
+                Ok(self.mux)
+            } else {
+                Err(GfxError::Read(
+                    ASUS_GPU_MUX_PATH.to_string(),
+                    io::Error::from_raw_os_error(libc::EIO),
+                ))
+            };
+            MuxRead::from_read(true, read)
+        }
+    }
+
+    /// A laptop without a MUX
+    struct NoMux;
+
+    impl MuxReader for NoMux {
+        fn read_mux(&self) -> MuxRead {
+            MuxRead::from_read(
+                false,
+                Err(GfxError::Path(
+                    ASUS_GPU_MUX_PATH.to_string(),
+                    io::Error::from(io::ErrorKind::NotFound),
+                )),
+            )
+        }
+    }
+
+    fn checked(mode: GfxMode, mux_assumed: bool) -> AsusBootCheck {
+        AsusBootCheck { mode, mux_assumed }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mux_read_once_ec_ready() {
+        let mux = EcNotReady::new(4, AsusGpuMuxMode::Optimus);
+        let check = asus_boot_safety_check_with(GfxMode::Hybrid, false, &mux, MUX_READ_WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(check, checked(GfxMode::Hybrid, false));
+        assert_eq!(mux.reads(), 5);
+
+        let mux = EcNotReady::new(4, AsusGpuMuxMode::Optimus);
+        let check = asus_boot_safety_check_with(GfxMode::AsusMuxDgpu, false, &mux, MUX_READ_WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(check, checked(GfxMode::Hybrid, false));
+
+        let mux = EcNotReady::new(4, AsusGpuMuxMode::Discreet);
+        let check = asus_boot_safety_check_with(GfxMode::Hybrid, false, &mux, MUX_READ_WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(check, checked(GfxMode::AsusMuxDgpu, false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mux_retries_back_off() {
+        let mux = EcNotReady::new(u32::MAX, AsusGpuMuxMode::Optimus);
+        let start = tokio::time::Instant::now();
+        asus_boot_safety_check_with(GfxMode::Hybrid, false, &mux, MUX_READ_WINDOW)
+            .await
+            .unwrap();
+        // Gives up at the end of the window, without reading every few milliseconds
+        assert_eq!(start.elapsed(), MUX_READ_WINDOW);
+        assert!(mux.reads() > 3 && mux.reads() < 15, "{}", mux.reads());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unreadable_mux_assumed_discreet() {
+        for requested in [GfxMode::Hybrid, GfxMode::Integrated, GfxMode::AsusMuxDgpu] {
+            let mux = EcNotReady::new(u32::MAX, AsusGpuMuxMode::Optimus);
+            let check = asus_boot_safety_check_with(requested, false, &mux, MUX_READ_WINDOW)
+                .await
+                .unwrap();
+            assert_eq!(check, checked(GfxMode::AsusMuxDgpu, true), "{requested}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_mux_not_retried() {
+        let check = asus_boot_safety_check_with(GfxMode::Hybrid, false, &NoMux, MUX_READ_WINDOW);
+        let start = tokio::time::Instant::now();
+        assert_eq!(check.await.unwrap(), checked(GfxMode::Hybrid, false));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn assumed_mux_corrected() {
+        // Still not ready at boot, readable once the EC is
+        let mux = EcNotReady::new(20, AsusGpuMuxMode::Optimus);
+        let check = asus_boot_safety_check_with(GfxMode::Hybrid, false, &mux, MUX_READ_WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(check, checked(GfxMode::AsusMuxDgpu, true));
+        assert_eq!(
+            reverify_mux(&mux, GfxMode::Hybrid, false, MUX_REVERIFY_WINDOW)
+                .await
+                .unwrap(),
+            MuxReverify::Corrected(GfxMode::Hybrid)
+        );
+
+        // The mode the user had asked for is booted in, unless it needs the MUX
+        let mux = EcNotReady::new(20, AsusGpuMuxMode::Optimus);
+        assert_eq!(
+            reverify_mux(&mux, GfxMode::AsusMuxDgpu, false, MUX_REVERIFY_WINDOW)
+                .await
+                .unwrap(),
+            MuxReverify::Corrected(GfxMode::Hybrid)
+        );
+        let mux = EcNotReady::new(20, AsusGpuMuxMode::Optimus);
+        assert_eq!(
+            reverify_mux(&mux, GfxMode::Integrated, false, MUX_REVERIFY_WINDOW)
+                .await
+                .unwrap(),
+            MuxReverify::Corrected(GfxMode::Integrated)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn assumed_mux_confirmed() {
+        let mux = EcNotReady::new(20, AsusGpuMuxMode::Discreet);
+        let check = asus_boot_safety_check_with(GfxMode::Hybrid, false, &mux, MUX_READ_WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(check, checked(GfxMode::AsusMuxDgpu, true));
+        assert_eq!(
+            reverify_mux(&mux, GfxMode::Hybrid, false, MUX_REVERIFY_WINDOW)
+                .await
+                .unwrap(),
+            MuxReverify::Confirmed
+        );
+
+        // Never readable, the assumption stands
+        let mux = EcNotReady::new(u32::MAX, AsusGpuMuxMode::Optimus);
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            reverify_mux(&mux, GfxMode::Hybrid, false, MUX_REVERIFY_WINDOW)
+                .await
+                .unwrap(),
+            MuxReverify::Unreadable
+        );
+        assert_eq!(start.elapsed(), MUX_REVERIFY_WINDOW);
+    }
+
+    /// Modules with code that runs on the executor, which must not block it
+    const ASYNC_MODULES: &[(&str, &str)] = &[
+        ("ac_automation.rs", include_str!("../ac_automation.rs")),
+        ("actions.rs", include_str!("../actions.rs")),
+        ("config.rs", include_str!("../config.rs")),
+        ("controller.rs", include_str!("../controller.rs")),
+        ("inhibitors.rs", include_str!("../inhibitors.rs")),
+        ("lib.rs", include_str!("../lib.rs")),
+        ("pci_device.rs", include_str!("../pci_device.rs")),
+        ("pci_lock.rs", include_str!("../pci_lock.rs")),
+        ("power_watch.rs", include_str!("../power_watch.rs")),
+        ("self_test.rs", include_str!("../self_test.rs")),
+        ("special_asus.rs", include_str!("../special_asus.rs")),
+        ("special_vendor.rs", include_str!("../special_vendor.rs")),
+        ("supervisor.rs", include_str!("../supervisor.rs")),
+        ("systemd.rs", include_str!("../systemd.rs")),
+        ("vfio.rs", include_str!("../vfio.rs")),
+        ("zbus_iface.rs", include_str!("../zbus_iface.rs")),
+    ];
+
+    #[test]
+    fn no_blocking_sleep_in_async_modules() {
+        for (name, source) in ASYNC_MODULES {
+            for (line, text) in source.lines().enumerate() {
+                assert!(
+                    !text.contains("thread::sleep"),
+                    "{name}:{}: blocking sleep, use tokio::time::sleep",
+                    line + 1
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn status_answered_during_slow_toggle() {
+        let root =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-toggle", std::process::id()));
+        let sysfs = Arc::new(Sysfs::at(&root));
+        let path = sysfs.dgpu_disable.0.path().to_path_buf();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "0").unwrap();
+        assert!(path.ends_with(ASUS_DGPU_DISABLE_PATH.trim_start_matches('/')));
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+
+        // This test runtime has one thread, so a blocking sleep would hold up the query
+        let start = std::time::Instant::now();
+        let toggle = tokio::spawn(async move {
+            asus_settle_and_toggle(true, &sysfs.dgpu_disable.0, false, Duration::from_secs(2)).await
+        });
+        tokio::task::yield_now().await;
+        ctrl.get_status().await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!toggle.is_finished());
+
+        toggle.abort();
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    fn readings(
+        mux: MuxRead,
+        dgpu_disabled: Option<bool>,
+        egpu_enabled: Option<bool>,
+    ) -> AsusReadings {
+        AsusReadings {
+            mux,
+            dgpu_disabled,
+            egpu_enabled,
+        }
+    }
+
+    #[test]
+    fn evaluation_of_the_mux() {
+        let discreet = MuxRead::Mode(AsusGpuMuxMode::Discreet);
+        let optimus = MuxRead::Mode(AsusGpuMuxMode::Optimus);
+
+        let evaluation = evaluate_asus_mode(
+            GfxMode::Hybrid,
+            false,
+            &readings(discreet, Some(false), None),
+        );
+        assert_eq!(evaluation.mode, GfxMode::AsusMuxDgpu);
+        assert!(!evaluation.enable_dgpu);
+        assert_eq!(evaluation.reasons.len(), 1);
+        assert!(evaluation.reasons[0].contains("is 0, the MUX is discreet"));
+
+        // Already right, dgpu_disable must still be turned off
+        let evaluation = evaluate_asus_mode(
+            GfxMode::AsusMuxDgpu,
+            true,
+            &readings(discreet, Some(true), None),
+        );
+        assert_eq!(evaluation.mode, GfxMode::AsusMuxDgpu);
+        assert!(evaluation.enable_dgpu);
+        assert_eq!(evaluation.fallback, None);
+        assert_eq!(evaluation.reasons.len(), 1);
+
+        let evaluation = evaluate_asus_mode(
+            GfxMode::Integrated,
+            true,
+            &readings(MuxRead::NotReady, None, None),
+        );
+        assert_eq!(evaluation.mode, GfxMode::AsusMuxDgpu);
+        assert!(evaluation.reasons[0].contains("assumed discreet"));
+
+        let evaluation =
+            evaluate_asus_mode(GfxMode::AsusMuxDgpu, false, &readings(optimus, None, None));
+        assert_eq!(evaluation.mode, GfxMode::Hybrid);
+        assert_eq!(evaluation.reasons.len(), 1);
+
+        for mux in [optimus, MuxRead::Absent] {
+            let evaluation = evaluate_asus_mode(GfxMode::Hybrid, false, &readings(mux, None, None));
+            assert_eq!(
+                evaluation,
+                AsusEvaluation {
+                    mode: GfxMode::Hybrid,
+                    reasons: Vec::new(),
+                    enable_dgpu: false,
+                    fallback: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn evaluation_of_the_toggles() {
+        let optimus = MuxRead::Mode(AsusGpuMuxMode::Optimus);
+
+        // dgpu_disable left on without Asus hotplug, the dGPU is enabled again
+        let evaluation = evaluate_asus_mode(
+            GfxMode::Integrated,
+            false,
+            &readings(optimus, Some(true), None),
+        );
+        assert_eq!(evaluation.mode, GfxMode::Hybrid);
+        assert!(evaluation.enable_dgpu);
+        assert_eq!(evaluation.fallback, Some(GfxMode::Integrated));
+
+        let evaluation =
+            evaluate_asus_mode(GfxMode::Hybrid, true, &readings(optimus, Some(true), None));
+        assert_eq!(evaluation.mode, GfxMode::Integrated);
+        assert!(!evaluation.enable_dgpu);
+        assert!(evaluation.reasons[0].contains("dgpu_disable is 1 but the mode is Hybrid"));
+
+        let evaluation = evaluate_asus_mode(
+            GfxMode::Integrated,
+            true,
+            &readings(optimus, Some(true), Some(true)),
+        );
+        assert_eq!(evaluation.mode, GfxMode::AsusEgpu);
+
+        let evaluation = evaluate_asus_mode(
+            GfxMode::Hybrid,
+            true,
+            &readings(MuxRead::Absent, Some(false), Some(true)),
+        );
+        assert_eq!(evaluation.mode, GfxMode::AsusEgpu);
+        assert!(evaluation.reasons[0].contains("egpu_enable is 1"));
+
+        for (mode, dgpu, egpu) in [
+            (GfxMode::Integrated, Some(true), Some(false)),
+            (GfxMode::Hybrid, Some(false), Some(false)),
+            (GfxMode::AsusEgpu, Some(false), Some(true)),
+        ] {
+            let evaluation = evaluate_asus_mode(mode, true, &readings(optimus, dgpu, egpu));
+            assert_eq!(evaluation.mode, mode);
+            assert!(
+                evaluation.reasons.is_empty(),
+                "{mode}: {:?}",
+                evaluation.reasons
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn safety_check_on_demand() {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-special_asus-on-demand",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&root).ok();
+        let sysfs = Sysfs::at(&root);
+        let path = sysfs.dgpu_disable.0.path().to_path_buf();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "1").unwrap();
+        let mut config = GfxConfig::new(Default::default());
+        config.hotplug_type = HotplugType::Asus;
+        let ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(config)),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+
+        let (_, check) = ctrl.asus_safety_check_in(&sysfs, &NoMux).await.unwrap();
+        assert_eq!(check.current, GfxMode::Hybrid);
+        assert_eq!(check.mode, GfxMode::Integrated);
+        assert!(check.switch_required);
+        assert!(!check.enable_dgpu);
+        assert_eq!(check.reasons.len(), 1);
+        // Nothing was changed
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+        assert_eq!(ctrl.config.lock().await.mode, GfxMode::Hybrid);
+
+        // The switch is left to the caller
+        let to = ctrl
+            .apply_safety_toggles(&sysfs, &NoMux, &Actor::Daemon)
+            .await
+            .unwrap();
+        assert_eq!(to, Some(GfxMode::Integrated));
+
+        std::fs::write(&path, "0").unwrap();
+        let (_, check) = ctrl.asus_safety_check_in(&sysfs, &NoMux).await.unwrap();
+        assert!(!check.switch_required);
+        assert_eq!(check.action, UserActionRequired::Nothing);
+        assert!(check.reasons.is_empty());
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
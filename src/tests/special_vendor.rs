@@ -0,0 +1,198 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use crate::{
+        actions::{Action, StagedAction},
+        config::GfxConfig,
+        error::GfxError,
+        pci_device::{GfxMode, GfxVendor},
+        special_vendor::{
+            apply_toggles, discover_in, glob_match, vendor_boot_safety_check, SpecialToggle,
+            BUILTIN_TOGGLES,
+        },
+    };
+
+    fn fake_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// The layout of the LenovoLegionLinux platform driver
+    fn fake_legion(name: &str, gsync: &str) -> PathBuf {
+        let root = fake_root(name);
+        let dev = root.join("sys/bus/platform/drivers/legion/PNP0C09:00");
+        fs::create_dir_all(&dev).unwrap();
+        fs::write(dev.join("gsync"), format!("{gsync}\n")).unwrap();
+        root
+    }
+
+    fn legion_toggle(root: &Path) -> SpecialToggle {
+        let mut toggles = discover_in(root, BUILTIN_TOGGLES);
+        assert_eq!(toggles.len(), 1);
+        toggles.remove(0)
+    }
+
+    fn plan(toggles: &[SpecialToggle], from: GfxMode, to: GfxMode) -> Vec<StagedAction> {
+        let config = GfxConfig::new(Default::default());
+        let actions = StagedAction::action_list_for_switch(&config, GfxVendor::Nvidia, from, to);
+        match apply_toggles(toggles, from, to, actions) {
+            Action::StagedActions(actions) => actions,
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+        }
+    }
+
+    #[test]
+    fn glob_components() {
+        assert!(glob_match("*", "PNP0C09:00"));
+        assert!(glob_match("PNP*", "PNP0C09:00"));
+        assert!(glob_match("*:00", "PNP0C09:00"));
+        assert!(glob_match("PNP*:*", "PNP0C09:00"));
+        assert!(glob_match("gsync", "gsync"));
+        assert!(!glob_match("gsync", "gsync2"));
+        assert!(!glob_match("VPC*", "PNP0C09:00"));
+        assert!(!glob_match("*:01", "PNP0C09:00"));
+    }
+
+    #[test]
+    fn discover_legion() {
+        let root = fake_legion("vendor-legion", "0");
+        let toggle = legion_toggle(&root);
+        assert_eq!(toggle.def.id, "legion_gsync");
+        assert_eq!(
+            toggle.path,
+            root.join("sys/bus/platform/drivers/legion/PNP0C09:00/gsync")
+        );
+        assert!(toggle.is_mux());
+        assert!(toggle.def.requires_reboot);
+        assert_eq!(toggle.is_on(), Some(false));
+
+        fs::write(&toggle.path, "1\n").unwrap();
+        assert_eq!(toggle.is_on(), Some(true));
+        // Neither value, such as an error from the driver
+        fs::write(&toggle.path, "-5\n").unwrap();
+        assert_eq!(toggle.is_on(), None);
+    }
+
+    #[test]
+    fn discover_unknown_machine() {
+        let root = fake_root("vendor-unknown");
+        fs::create_dir_all(root.join("sys/bus/platform/drivers/asus-nb-wmi")).unwrap();
+        let toggles = discover_in(&root, BUILTIN_TOGGLES);
+        assert!(toggles.is_empty());
+
+        // Plans are left alone
+        for (from, to) in [
+            (GfxMode::Hybrid, GfxMode::Integrated),
+            (GfxMode::Integrated, GfxMode::AsusMuxDgpu),
+            (GfxMode::AsusMuxDgpu, GfxMode::Hybrid),
+        ] {
+            let config = GfxConfig::new(Default::default());
+            let expected =
+                match StagedAction::action_list_for_switch(&config, GfxVendor::Nvidia, from, to) {
+                    Action::StagedActions(actions) => actions,
+                    Action::UserAction(_) => panic!("Should be a list of actions"),
+                };
+            assert_eq!(plan(&toggles, from, to), expected);
+        }
+        assert_eq!(
+            vendor_boot_safety_check(GfxMode::AsusMuxDgpu, &toggles),
+            GfxMode::AsusMuxDgpu
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_verifies_value() {
+        let root = fake_legion("vendor-set", "0");
+        let toggle = legion_toggle(&root);
+
+        toggle.set(true, Duration::ZERO).await.unwrap();
+        assert_eq!(fs::read_to_string(&toggle.path).unwrap(), "1");
+        // Already set, nothing is written
+        fs::write(&toggle.path, "1\n").unwrap();
+        toggle.set(true, Duration::ZERO).await.unwrap();
+        assert_eq!(fs::read_to_string(&toggle.path).unwrap(), "1\n");
+        toggle.set(false, Duration::ZERO).await.unwrap();
+        assert_eq!(toggle.is_on(), Some(false));
+
+        // Writes are accepted but never read back
+        let sink = SpecialToggle {
+            path: PathBuf::from("/dev/null"),
+            ..toggle
+        };
+        assert!(matches!(
+            sink.set(true, Duration::ZERO).await,
+            Err(GfxError::SpecialToggle(_))
+        ));
+    }
+
+    #[test]
+    fn interlocks() {
+        let root = fake_legion("vendor-interlock", "0");
+        let toggle = legion_toggle(&root);
+        assert!(toggle.check_interlocks(true, true).is_ok());
+        assert!(matches!(
+            toggle.check_interlocks(false, false),
+            Err(GfxError::SpecialToggle(_))
+        ));
+    }
+
+    #[test]
+    fn plans_use_legion_toggle() {
+        let root = fake_legion("vendor-plan", "0");
+        let toggles = vec![legion_toggle(&root)];
+
+        for from in [GfxMode::Hybrid, GfxMode::Integrated, GfxMode::Vfio] {
+            let actions = plan(&toggles, from, GfxMode::AsusMuxDgpu);
+            assert_eq!(
+                actions.last(),
+                Some(&StagedAction::SpecialToggleOn("legion_gsync"))
+            );
+            assert!(!actions.contains(&StagedAction::AsusMuxDgpu));
+            // The toggle takes the place of the ASUS MUX action so follows the same order
+            let toggle = actions[actions.len() - 1];
+            toggle
+                .verify_previous_action_for_current(actions[actions.len() - 2])
+                .unwrap();
+        }
+
+        assert_eq!(
+            plan(&toggles, GfxMode::AsusMuxDgpu, GfxMode::Hybrid),
+            [StagedAction::SpecialToggleOff("legion_gsync")]
+        );
+
+        // Switches which don't involve the MUX are unchanged
+        let actions = plan(&toggles, GfxMode::Hybrid, GfxMode::Integrated);
+        assert!(!actions.iter().any(|a| matches!(
+            a,
+            StagedAction::SpecialToggleOn(_) | StagedAction::SpecialToggleOff(_)
+        )));
+    }
+
+    #[test]
+    fn boot_mode_follows_toggle() {
+        let root = fake_legion("vendor-boot", "1");
+        let toggles = vec![legion_toggle(&root)];
+        assert_eq!(
+            vendor_boot_safety_check(GfxMode::Hybrid, &toggles),
+            GfxMode::AsusMuxDgpu
+        );
+
+        fs::write(&toggles[0].path, "0\n").unwrap();
+        assert_eq!(
+            vendor_boot_safety_check(GfxMode::AsusMuxDgpu, &toggles),
+            GfxMode::Hybrid
+        );
+        assert_eq!(
+            vendor_boot_safety_check(GfxMode::Integrated, &toggles),
+            GfxMode::Integrated
+        );
+    }
+}
@@ -0,0 +1,146 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        config::{modprobe_conf, GfxConfig},
+        controller::ProbeCache,
+        pci_device::{DiscreetGpu, GfxMode, GfxVendor},
+        staging::{likely_next_mode, stage_likely_next, staged_modprobe_path, WarmStaging},
+        tests::test_dir,
+    };
+
+    fn staging_in(dir: &Path) -> WarmStaging {
+        WarmStaging::new(dir.join("staged"), dir.join("supergfxd.conf"))
+    }
+
+    #[test]
+    fn likely_next() {
+        use GfxMode::{AsusEgpu, Hybrid, Integrated, Vfio};
+        let supported = [Hybrid, Integrated, Vfio];
+        assert_eq!(likely_next_mode(Hybrid, None, &supported), Some(Integrated));
+        assert_eq!(likely_next_mode(Integrated, None, &supported), Some(Hybrid));
+        assert_eq!(likely_next_mode(Hybrid, Some(Vfio), &supported), Some(Vfio));
+        // The last mode is no longer supported
+        assert_eq!(
+            likely_next_mode(Integrated, Some(AsusEgpu), &supported),
+            Some(Hybrid)
+        );
+        assert_eq!(likely_next_mode(Vfio, Some(Vfio), &supported), Some(Hybrid));
+        assert_eq!(likely_next_mode(Hybrid, None, &[Hybrid]), None);
+    }
+
+    #[test]
+    fn staged_conf_is_renamed_into_place() {
+        let dir = test_dir("staging-rename");
+        let device = DiscreetGpu::mock(GfxVendor::Nvidia);
+        let mut staging = staging_in(&dir);
+        let content = modprobe_conf(GfxMode::Integrated, &device, &[])
+            .unwrap()
+            .unwrap();
+
+        staging.stage(GfxMode::Integrated, &content).unwrap();
+        // Staging never touches the live path
+        assert!(!dir.join("supergfxd.conf").exists());
+        assert!(staging.is_current(GfxMode::Integrated, &content));
+        assert!(!staging.is_current(GfxMode::Hybrid, &content));
+
+        staging
+            .write_modprobe_conf(GfxMode::Integrated, &device, &[])
+            .unwrap();
+        assert_eq!(fs::read(dir.join("supergfxd.conf")).unwrap(), content);
+        // The staged conf was moved, not copied
+        assert!(!staged_modprobe_path(&dir.join("staged"), GfxMode::Integrated).exists());
+        assert_eq!(staging.staged_mode(), None);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn stale_staged_conf_is_regenerated() {
+        let dir = test_dir("staging-stale");
+        let device = DiscreetGpu::mock(GfxVendor::Nvidia);
+        let mut staging = staging_in(&dir);
+
+        // Rendered from an older config
+        staging.stage(GfxMode::Hybrid, b"options old\n").unwrap();
+        let content = modprobe_conf(GfxMode::Hybrid, &device, &[])
+            .unwrap()
+            .unwrap();
+        assert!(!staging.is_current(GfxMode::Hybrid, &content));
+        assert_eq!(staging.install(GfxMode::Hybrid, &content).unwrap(), None);
+        assert!(!dir.join("staged").exists());
+
+        staging.stage(GfxMode::Hybrid, b"options old\n").unwrap();
+        staging
+            .write_modprobe_conf(GfxMode::Hybrid, &device, &[])
+            .unwrap();
+        assert_eq!(fs::read(dir.join("supergfxd.conf")).unwrap(), content);
+
+        // Staged for another mode
+        staging.stage(GfxMode::Hybrid, &content).unwrap();
+        staging
+            .write_modprobe_conf(GfxMode::Integrated, &device, &[])
+            .unwrap();
+        assert_eq!(
+            fs::read(dir.join("supergfxd.conf")).unwrap(),
+            modprobe_conf(GfxMode::Integrated, &device, &[])
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(staging.staged_mode(), None);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn config_change_restages() {
+        let dir = test_dir("staging-config");
+        let dgpu = Mutex::new(DiscreetGpu::mock(GfxVendor::Nvidia));
+        let config = Mutex::new(GfxConfig::new(Default::default()));
+        let cache = Mutex::new(ProbeCache::default());
+        let staging = Mutex::new(staging_in(&dir));
+
+        stage_likely_next(&dgpu, &config, &cache, &staging)
+            .await
+            .unwrap();
+        assert_eq!(
+            staging.lock().await.staged_mode(),
+            Some(GfxMode::Integrated)
+        );
+
+        // The config now has Integrated, so Hybrid is the likely next mode
+        config.lock().await.mode = GfxMode::Integrated;
+        stage_likely_next(&dgpu, &config, &cache, &staging)
+            .await
+            .unwrap();
+        assert_eq!(staging.lock().await.staged_mode(), Some(GfxMode::Hybrid));
+        assert!(!staged_modprobe_path(&dir.join("staged"), GfxMode::Integrated).exists());
+
+        let device = DiscreetGpu::mock(GfxVendor::Nvidia);
+        staging
+            .lock()
+            .await
+            .write_modprobe_conf(GfxMode::Hybrid, &device, &[])
+            .unwrap();
+        assert_eq!(
+            fs::read(dir.join("supergfxd.conf")).unwrap(),
+            modprobe_conf(GfxMode::Hybrid, &device, &[])
+                .unwrap()
+                .unwrap()
+        );
+
+        // Disabling it drops anything staged
+        stage_likely_next(&dgpu, &config, &cache, &staging)
+            .await
+            .unwrap();
+        assert!(dir.join("staged").exists());
+        config.lock().await.no_warm_staging = true;
+        stage_likely_next(&dgpu, &config, &cache, &staging)
+            .await
+            .unwrap();
+        assert_eq!(staging.lock().await.staged_mode(), None);
+        assert!(!dir.join("staged").exists());
+        fs::remove_dir_all(dir).ok();
+    }
+}
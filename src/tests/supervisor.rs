@@ -0,0 +1,137 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::supervisor::{RestartPolicy, TaskState, TaskSupervisor, ESCALATION_EXIT_CODE};
+
+    /// A task which panics on its first run and then runs until cancelled
+    fn panics_once(
+        runs: &Arc<AtomicUsize>,
+    ) -> impl FnMut() -> futures_util::future::BoxFuture<'static, ()> {
+        let runs = runs.clone();
+        move || {
+            let runs = runs.clone();
+            Box::pin(async move {
+                if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("mock task panicked");
+                }
+                std::future::pending::<()>().await;
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarted_after_panic_with_backoff() {
+        let tasks = TaskSupervisor::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        tasks.spawn("mock task", RestartPolicy::WithBackoff, panics_once(&runs));
+
+        // First run panics, the second is started after the backoff
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let roster = tasks.roster();
+        assert_eq!(roster[0].name, "mock task");
+        assert_eq!(roster[0].state, TaskState::Restarting);
+        assert_eq!(roster[0].last_error, "panicked: mock task panicked");
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        // The healthy run is left alone
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        let roster = tasks.roster();
+        assert_eq!(roster[0].state, TaskState::Running);
+        assert_eq!(roster[0].restarts, 1);
+        assert_eq!(roster[0].last_error, "panicked: mock task panicked");
+
+        tasks.shutdown().await;
+        assert_eq!(tasks.roster()[0].state, TaskState::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_restarted() {
+        let tasks = TaskSupervisor::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        tasks.spawn("mock task", RestartPolicy::Never, panics_once(&runs));
+        tasks.spawn("one shot", RestartPolicy::Never, || async {});
+
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let roster = tasks.roster();
+        assert_eq!(roster[0].state, TaskState::Failed);
+        assert_eq!(roster[0].restarts, 0);
+        // Finishing is what a task run once is meant to do
+        assert_eq!(roster[1].state, TaskState::Stopped);
+        assert_eq!(roster[1].last_error, "");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn escalation_stops_the_daemon() {
+        let tasks = TaskSupervisor::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        tasks.spawn("watcher", RestartPolicy::WithBackoff, panics_once(&runs));
+        tasks.spawn("essential", RestartPolicy::EscalateToExit, || async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let code = tasks.wait_for_stop(std::future::pending()).await;
+        assert_eq!(code, ESCALATION_EXIT_CODE);
+        assert_eq!(
+            tasks.escalated().await,
+            "essential: exited unexpectedly".to_string()
+        );
+
+        tasks.shutdown().await;
+        let roster = tasks.roster();
+        assert_eq!(roster[0].state, TaskState::Stopped);
+        assert_eq!(roster[1].state, TaskState::Failed);
+        // Nothing is started while stopping
+        tasks.spawn("late", RestartPolicy::WithBackoff, || async {});
+        assert_eq!(tasks.roster().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stopped_by_a_signal() {
+        let tasks = TaskSupervisor::default();
+        tasks.spawn("idle", RestartPolicy::EscalateToExit, || {
+            std::future::pending::<()>()
+        });
+        assert_eq!(tasks.wait_for_stop(async {}).await, 0);
+        tasks.shutdown().await;
+        // Cancelling on shutdown isn't an escalation
+        assert_eq!(tasks.roster()[0].state, TaskState::Stopped);
+        assert_eq!(tasks.wait_for_stop(async {}).await, 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_last_started_first() {
+        let tasks = TaskSupervisor::default();
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for name in ["first", "second", "third"] {
+            let order = order.clone();
+            tasks.spawn(name, RestartPolicy::WithBackoff, move || {
+                let order = order.clone();
+                async move {
+                    /// Records the task when it is cancelled and dropped
+                    struct OnDrop(&'static str, Arc<std::sync::Mutex<Vec<&'static str>>>);
+                    impl Drop for OnDrop {
+                        fn drop(&mut self) {
+                            self.1.lock().unwrap().push(self.0);
+                        }
+                    }
+                    let _guard = OnDrop(name, order);
+                    std::future::pending::<()>().await;
+                }
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        tasks.shutdown().await;
+        assert_eq!(*order.lock().unwrap(), ["third", "second", "first"]);
+    }
+}
@@ -0,0 +1,88 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        actions::StagedAction,
+        error::GfxError,
+        pci_device::GfxMode,
+        switch_hooks::{hook_env, hook_result, run_hook, HOOK_RESULT_PENDING},
+        switch_plan::SwitchOutcome,
+        tests::test_dir,
+    };
+
+    /// Write an executable shell script with `body` to `dir`, returning its path
+    fn script(dir: &Path, body: &str) -> String {
+        let path = dir.join("hook.sh");
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn hook_environment() {
+        assert_eq!(
+            hook_env(GfxMode::Hybrid, GfxMode::Integrated, HOOK_RESULT_PENDING),
+            vec![
+                ("SUPERGFXD_FROM", "Hybrid".to_string()),
+                ("SUPERGFXD_TO", "Integrated".to_string()),
+                ("SUPERGFXD_RESULT", "pending".to_string()),
+            ]
+        );
+        assert_eq!(hook_result(&SwitchOutcome::Completed), "completed");
+        assert_eq!(
+            hook_result(&SwitchOutcome::RolledBack {
+                failed: StagedAction::LoadGpuDrivers
+            }),
+            "rolled_back"
+        );
+    }
+
+    #[tokio::test]
+    async fn hook_gets_the_switch() {
+        let dir = test_dir("switch-hooks-env");
+        let out = dir.join("out");
+        let command = script(
+            &dir,
+            &format!(
+                "echo \"$SUPERGFXD_FROM $SUPERGFXD_TO $SUPERGFXD_RESULT\" > {}",
+                out.display()
+            ),
+        );
+        let env = hook_env(GfxMode::Integrated, GfxMode::Hybrid, "completed");
+        run_hook(&command, &env, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "Integrated Hybrid completed\n"
+        );
+
+        let command = script(&dir, "echo no dGPU here >&2\nexit 3");
+        let err = run_hook(&command, &env, Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, GfxError::Hook(_)), "{err}");
+        assert!(err.to_string().contains("no dGPU here"), "{err}");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn hook_is_killed_after_timeout() {
+        let dir = test_dir("switch-hooks-timeout");
+        let command = script(&dir, "sleep 30");
+        let env = hook_env(GfxMode::Hybrid, GfxMode::Integrated, HOOK_RESULT_PENDING);
+        let started = Instant::now();
+        let err = run_hook(&command, &env, Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(err.to_string().contains("timed out"), "{err}");
+        fs::remove_dir_all(dir).ok();
+    }
+}
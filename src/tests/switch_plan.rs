@@ -0,0 +1,674 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU8, Ordering},
+            Mutex,
+        },
+    };
+
+    use futures_util::future::BoxFuture;
+
+    use crate::{
+        actions::{StagedAction, UserActionRequired},
+        config::GfxConfig,
+        controller::SetModeOptions,
+        error::GfxError,
+        pci_device::{GfxMode, GfxVendor},
+        special_asus::AsusToggleState,
+        special_vendor::{SpecialToggle, BUILTIN_TOGGLES},
+        switch_plan::{
+            execute_plan, park_switch, plan_switch, PlanEnv, SwitchOps, SwitchOutcome,
+            SWITCH_CANCELLABLE, SWITCH_CANCELLED, SWITCH_COMMITTED, SWITCH_PARKED,
+        },
+    };
+
+    const MODES: [GfxMode; 7] = [
+        GfxMode::Hybrid,
+        GfxMode::Integrated,
+        GfxMode::NvidiaNoModeset,
+        GfxMode::Vfio,
+        GfxMode::AsusEgpu,
+        GfxMode::AsusMuxDgpu,
+        GfxMode::None,
+    ];
+
+    fn config() -> GfxConfig {
+        GfxConfig::new(String::new())
+    }
+
+    fn no_logind() -> GfxConfig {
+        GfxConfig {
+            no_logind: true,
+            ..config()
+        }
+    }
+
+    fn always_reboot() -> GfxConfig {
+        GfxConfig {
+            always_reboot: true,
+            ..config()
+        }
+    }
+
+    fn legion() -> PlanEnv {
+        PlanEnv {
+            toggles: vec![SpecialToggle {
+                def: &BUILTIN_TOGGLES[0],
+                path: PathBuf::from("/sys/bus/platform/drivers/legion/PNP0C09:00/gsync"),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn hybrid_to_integrated() -> Vec<StagedAction> {
+        vec![
+            StagedAction::WaitLogout,
+            StagedAction::StopDisplayManager,
+            StagedAction::DisableNvidiaPersistenced,
+            StagedAction::DisableNvidiaPowerd,
+            StagedAction::KillNvidia,
+            StagedAction::UnloadGpuDrivers,
+            StagedAction::UnbindRemoveGpu,
+            StagedAction::WriteModprobeConf,
+            StagedAction::CheckVulkanIcd,
+            StagedAction::DevTreeManaged,
+            StagedAction::StartDisplayManager,
+        ]
+    }
+
+    /// A name, what is planned with, and the plan expected
+    type PlanCase = (
+        &'static str,
+        GfxConfig,
+        GfxVendor,
+        PlanEnv,
+        GfxMode,
+        GfxMode,
+        UserActionRequired,
+        Option<Vec<StagedAction>>,
+    );
+
+    #[test]
+    fn planned() {
+        let table: Vec<PlanCase> = vec![
+            (
+                "hybrid to integrated",
+                config(),
+                GfxVendor::Nvidia,
+                PlanEnv::default(),
+                GfxMode::Hybrid,
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                Some(hybrid_to_integrated()),
+            ),
+            (
+                "without logind the display manager is left alone",
+                no_logind(),
+                GfxVendor::Nvidia,
+                PlanEnv::default(),
+                GfxMode::Hybrid,
+                GfxMode::Integrated,
+                UserActionRequired::Logout,
+                Some(vec![
+                    StagedAction::NoLogind,
+                    StagedAction::NoLogind,
+                    StagedAction::DisableNvidiaPersistenced,
+                    StagedAction::DisableNvidiaPowerd,
+                    StagedAction::KillNvidia,
+                    StagedAction::UnloadGpuDrivers,
+                    StagedAction::UnbindRemoveGpu,
+                    StagedAction::WriteModprobeConf,
+                    StagedAction::CheckVulkanIcd,
+                    StagedAction::DevTreeManaged,
+                    StagedAction::NoLogind,
+                ]),
+            ),
+            (
+                "always_reboot asks for a reboot",
+                always_reboot(),
+                GfxVendor::Nvidia,
+                PlanEnv::default(),
+                GfxMode::Integrated,
+                GfxMode::Hybrid,
+                UserActionRequired::Reboot,
+                Some(vec![
+                    StagedAction::NoLogind,
+                    StagedAction::NoLogind,
+                    StagedAction::WriteModprobeConf,
+                    StagedAction::CheckVulkanIcd,
+                    StagedAction::DevTreeManaged,
+                    StagedAction::RescanPci,
+                    StagedAction::LoadGpuDrivers,
+                    StagedAction::EnableNvidiaPersistenced,
+                    StagedAction::EnableNvidiaPowerd,
+                    StagedAction::NoLogind,
+                ]),
+            ),
+            (
+                "amd to vfio",
+                config(),
+                GfxVendor::Amd,
+                PlanEnv::default(),
+                GfxMode::Integrated,
+                GfxMode::Vfio,
+                UserActionRequired::Nothing,
+                Some(vec![
+                    StagedAction::WriteModprobeConf,
+                    StagedAction::CheckVulkanIcd,
+                    StagedAction::DevTreeManaged,
+                    StagedAction::RescanPci,
+                    StagedAction::DisableNvidiaPersistenced,
+                    StagedAction::DisableNvidiaPowerd,
+                    StagedAction::KillAmd,
+                    StagedAction::UnloadGpuDrivers,
+                    StagedAction::UnbindGpu,
+                    StagedAction::LoadVfioDrivers,
+                ]),
+            ),
+            (
+                "hybrid to vfio is left to the user",
+                config(),
+                GfxVendor::Nvidia,
+                PlanEnv::default(),
+                GfxMode::Hybrid,
+                GfxMode::Vfio,
+                UserActionRequired::SwitchToIntegrated,
+                None,
+            ),
+            (
+                "no change",
+                config(),
+                GfxVendor::Nvidia,
+                PlanEnv::default(),
+                GfxMode::Integrated,
+                GfxMode::Integrated,
+                UserActionRequired::Nothing,
+                None,
+            ),
+            (
+                "a vendor MUX replaces the ASUS one",
+                config(),
+                GfxVendor::Nvidia,
+                legion(),
+                GfxMode::Hybrid,
+                GfxMode::AsusMuxDgpu,
+                UserActionRequired::Reboot,
+                Some(vec![
+                    StagedAction::CheckVulkanIcd,
+                    StagedAction::EnableNvidiaPersistenced,
+                    StagedAction::EnableNvidiaPowerd,
+                    StagedAction::SpecialToggleOn("legion_gsync"),
+                ]),
+            ),
+            (
+                "leaving the mux",
+                config(),
+                GfxVendor::Nvidia,
+                PlanEnv::default(),
+                GfxMode::AsusMuxDgpu,
+                GfxMode::Hybrid,
+                UserActionRequired::Reboot,
+                Some(vec![StagedAction::AsusMuxIgpu]),
+            ),
+        ];
+        for (name, config, vendor, env, from, to, user_action, actions) in table {
+            let plan = plan_switch(&config, vendor, from, to, &env);
+            assert_eq!(plan.from, from, "{name}");
+            assert_eq!(plan.to, to, "{name}");
+            assert_eq!(plan.user_action, user_action, "{name}");
+            assert_eq!(plan.actions, actions, "{name}");
+        }
+    }
+
+    #[test]
+    fn egpu_exit_keeps_dgpu_disabled() {
+        let leave = |dgpu_disabled| {
+            let env = PlanEnv {
+                asus: AsusToggleState { dgpu_disabled },
+                ..Default::default()
+            };
+            plan_switch(
+                &config(),
+                GfxVendor::Nvidia,
+                GfxMode::AsusEgpu,
+                GfxMode::Integrated,
+                &env,
+            )
+            .actions
+            .unwrap()
+        };
+        assert!(leave(true).contains(&StagedAction::AsusDgpuDisable));
+        assert!(!leave(false).contains(&StagedAction::AsusDgpuDisable));
+    }
+
+    #[test]
+    fn plan_invariants() {
+        let configs = [config(), no_logind(), always_reboot()];
+        for config in &configs {
+            let logind = !config.no_logind && !config.always_reboot;
+            for vendor in [GfxVendor::Nvidia, GfxVendor::Amd] {
+                for env in [PlanEnv::default(), legion()] {
+                    for from in MODES {
+                        for to in MODES {
+                            let plan = plan_switch(config, vendor, from, to, &env);
+                            let name = format!("{from} -> {to} {vendor:?} logind {logind}");
+                            let actions = match plan.actions {
+                                Some(actions) => actions,
+                                None => continue,
+                            };
+                            if config.always_reboot {
+                                assert_eq!(plan.user_action, UserActionRequired::Reboot, "{name}");
+                            }
+                            let at = |action| actions.iter().position(|a| *a == action);
+                            let stop = at(StagedAction::StopDisplayManager);
+                            let start = at(StagedAction::StartDisplayManager);
+                            if !logind {
+                                assert_eq!(stop, None, "{name}");
+                                assert_eq!(at(StagedAction::WaitLogout), None, "{name}");
+                            }
+                            // The display manager is only stopped after the logout, and
+                            // is always started again
+                            if let Some(stop) = stop {
+                                assert_eq!(at(StagedAction::WaitLogout), Some(0), "{name}");
+                                assert_eq!(stop, 1, "{name}");
+                                assert_eq!(start, Some(actions.len() - 1), "{name}");
+                            } else {
+                                assert_eq!(start, None, "{name}");
+                            }
+                            // The drivers are unloaded before the devices are removed
+                            if let (Some(unload), Some(remove)) = (
+                                at(StagedAction::UnloadGpuDrivers),
+                                at(StagedAction::UnbindRemoveGpu),
+                            ) {
+                                assert!(unload < remove, "{name}");
+                            }
+                            // The devices are back on the bus before the drivers load
+                            if let (Some(rescan), Some(load)) = (
+                                at(StagedAction::RescanPci),
+                                at(StagedAction::LoadGpuDrivers),
+                            ) {
+                                assert!(rescan < load, "{name}");
+                            }
+                            if vendor != GfxVendor::Nvidia {
+                                assert_eq!(at(StagedAction::KillNvidia), None, "{name}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn options_applied() {
+        let config = GfxConfig {
+            pre_stop_delay_s: 10,
+            ..config()
+        };
+        let plan = |options| {
+            plan_switch(
+                &config,
+                GfxVendor::Nvidia,
+                GfxMode::Hybrid,
+                GfxMode::Integrated,
+                &PlanEnv::default(),
+            )
+            .with_options(options)
+            .actions
+            .unwrap()
+        };
+        assert_eq!(
+            plan(SetModeOptions::default())[..4],
+            [
+                StagedAction::WaitLogout,
+                StagedAction::PreStopDelay(10),
+                StagedAction::WaitInhibitors,
+                StagedAction::StopDisplayManager,
+            ]
+        );
+        assert_eq!(
+            plan(SetModeOptions {
+                skip_pre_stop_delay: true,
+                ignore_inhibitors: true,
+                persist: true,
+            }),
+            hybrid_to_integrated()
+        );
+        // A switch left to the user stays that way
+        let plan = plan_switch(
+            &config,
+            GfxVendor::Nvidia,
+            GfxMode::Hybrid,
+            GfxMode::Vfio,
+            &PlanEnv::default(),
+        )
+        .with_options(SetModeOptions::default());
+        assert_eq!(plan.actions, None);
+    }
+
+    /// Records what the executor asks for, failing the actions it is told to
+    #[derive(Default)]
+    struct RecordingOps<'a> {
+        performed: Mutex<Vec<StagedAction>>,
+        /// Fail the first time these are performed
+        fail: Vec<StagedAction>,
+        /// Fail as if the display manager didn't stop in time
+        timeout_on: Option<StagedAction>,
+        /// Fail as if `logout_timeout_action` dropped the switch
+        logout_timeout_on: Option<StagedAction>,
+        /// Fail as if `strict_verify` found this didn't take effect
+        unverified: Option<StagedAction>,
+        rollback: Vec<StagedAction>,
+        /// Cancel the switch through `token` while this action is performed, as a user
+        /// would while waiting for logout
+        cancel_on: Option<(StagedAction, &'a AtomicU8)>,
+        /// Stop the switch through `token` while this action is performed, as the daemon
+        /// does when it is stopped
+        shutdown_on: Option<(StagedAction, &'a AtomicU8)>,
+        /// The progress reported, as `NotifySwitchProgress` would be emitted
+        progress: Mutex<Vec<(StagedAction, u32, u32)>>,
+    }
+
+    impl RecordingOps<'_> {
+        fn performed(&self) -> Vec<StagedAction> {
+            self.performed.lock().unwrap().clone()
+        }
+    }
+
+    impl SwitchOps for RecordingOps<'_> {
+        fn perform(
+            &self,
+            action: StagedAction,
+            _mode: GfxMode,
+        ) -> BoxFuture<'_, Result<(), GfxError>> {
+            Box::pin(async move {
+                let mut performed = self.performed.lock().unwrap();
+                let first = !performed.contains(&action);
+                performed.push(action);
+                if let Some((on, token)) = self.cancel_on {
+                    if on == action {
+                        token
+                            .compare_exchange(
+                                SWITCH_CANCELLABLE,
+                                SWITCH_CANCELLED,
+                                Ordering::AcqRel,
+                                Ordering::Acquire,
+                            )
+                            .ok();
+                    }
+                }
+                if let Some((on, token)) = self.shutdown_on {
+                    if on == action {
+                        park_switch(token);
+                    }
+                }
+                if !first {
+                    Ok(())
+                } else if self.timeout_on == Some(action) {
+                    Err(GfxError::SystemdUnitWaitTimeout("active".to_string()))
+                } else if self.logout_timeout_on == Some(action) {
+                    Err(GfxError::LogoutTimeout("sessions 2 still open".to_string()))
+                } else if self.unverified == Some(action) {
+                    Err(GfxError::PostCondition(
+                        action,
+                        "nvidia unloaded".to_string(),
+                        "nvidia loaded".to_string(),
+                    ))
+                } else if self.fail.contains(&action) {
+                    Err(GfxError::NotSupported(format!("{action:?} failed")))
+                } else {
+                    Ok(())
+                }
+            })
+        }
+
+        fn rollback(&self, _mode: GfxMode) -> BoxFuture<'_, Vec<StagedAction>> {
+            Box::pin(async move { self.rollback.clone() })
+        }
+
+        fn progress(&self, action: StagedAction, index: u32, total: u32) -> BoxFuture<'_, ()> {
+            self.progress.lock().unwrap().push((action, index, total));
+            Box::pin(async {})
+        }
+    }
+
+    fn rollback() -> Vec<StagedAction> {
+        vec![
+            StagedAction::WriteModprobeConf,
+            StagedAction::RescanPci,
+            StagedAction::LoadGpuDrivers,
+        ]
+    }
+
+    #[tokio::test]
+    async fn executed() {
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps::default();
+        let actions = hybrid_to_integrated();
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(outcome, SwitchOutcome::Completed);
+        assert_eq!(ops.performed(), actions);
+        assert_eq!(token.load(Ordering::Acquire), SWITCH_COMMITTED);
+    }
+
+    #[tokio::test]
+    async fn progress_before_each_action() {
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            fail: vec![StagedAction::UnloadGpuDrivers],
+            rollback: rollback(),
+            ..Default::default()
+        };
+        let actions = hybrid_to_integrated();
+        let total = actions.len() as u32;
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert!(!outcome.succeeded());
+        // Counted from 1 over the switch, the actions which undo it aren't reported
+        let expected: Vec<_> = actions
+            .iter()
+            .enumerate()
+            .map(|(i, action)| (*action, i as u32 + 1, total))
+            .collect();
+        assert_eq!(*ops.progress.lock().unwrap(), expected);
+        assert_eq!(
+            outcome.describe(),
+            "failed at UnloadGpuDrivers and was undone"
+        );
+        assert_eq!(SwitchOutcome::Completed.describe(), "completed");
+        assert!(SwitchOutcome::Completed.succeeded());
+    }
+
+    #[tokio::test]
+    async fn failure_at_each_step() {
+        let actions = hybrid_to_integrated();
+        for (i, &failed) in actions.iter().enumerate() {
+            let token = AtomicU8::new(SWITCH_CANCELLABLE);
+            let ops = RecordingOps {
+                fail: vec![failed],
+                rollback: rollback(),
+                ..Default::default()
+            };
+            let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+            assert_eq!(outcome, SwitchOutcome::RolledBack { failed }, "step {i}");
+            // The rest of the switch is still performed, then undone
+            let mut expected = actions.clone();
+            expected.extend(rollback());
+            assert_eq!(ops.performed(), expected, "step {i}");
+        }
+    }
+
+    #[tokio::test]
+    async fn logout_timeout_drops_the_switch() {
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            logout_timeout_on: Some(StagedAction::WaitLogout),
+            rollback: rollback(),
+            ..Default::default()
+        };
+        let actions = hybrid_to_integrated();
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(
+            outcome,
+            SwitchOutcome::RolledBack {
+                failed: StagedAction::WaitLogout
+            }
+        );
+        // Nothing was changed, so nothing is undone
+        assert_eq!(ops.performed(), [StagedAction::WaitLogout]);
+        assert_eq!(token.load(Ordering::Acquire), SWITCH_CANCELLABLE);
+    }
+
+    #[tokio::test]
+    async fn display_manager_timeout_stops_the_switch() {
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            timeout_on: Some(StagedAction::StopDisplayManager),
+            rollback: rollback(),
+            ..Default::default()
+        };
+        let actions = hybrid_to_integrated();
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(
+            outcome,
+            SwitchOutcome::RolledBack {
+                failed: StagedAction::StopDisplayManager
+            }
+        );
+        let mut expected = actions[..2].to_vec();
+        expected.extend(rollback());
+        assert_eq!(ops.performed(), expected);
+    }
+
+    #[tokio::test]
+    async fn strict_verify_stops_the_switch() {
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            unverified: Some(StagedAction::UnloadGpuDrivers),
+            rollback: rollback(),
+            ..Default::default()
+        };
+        let actions = hybrid_to_integrated();
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(
+            outcome,
+            SwitchOutcome::RolledBack {
+                failed: StagedAction::UnloadGpuDrivers
+            }
+        );
+        // The device isn't removed with the driver still loaded
+        let unload = actions
+            .iter()
+            .position(|a| *a == StagedAction::UnloadGpuDrivers)
+            .unwrap();
+        let mut expected = actions[..=unload].to_vec();
+        expected.extend(rollback());
+        assert_eq!(ops.performed(), expected);
+    }
+
+    #[tokio::test]
+    async fn rollback_failure_stalls() {
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            fail: vec![StagedAction::UnloadGpuDrivers, StagedAction::RescanPci],
+            rollback: rollback(),
+            ..Default::default()
+        };
+        let actions = hybrid_to_integrated();
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(
+            outcome,
+            SwitchOutcome::Stalled {
+                failed: StagedAction::UnloadGpuDrivers,
+                rollback_failed: StagedAction::RescanPci,
+            }
+        );
+        // Nothing more is done once undoing the switch fails
+        assert_eq!(ops.performed().last(), Some(&StagedAction::RescanPci));
+        assert!(!ops.performed().contains(&StagedAction::LoadGpuDrivers));
+    }
+
+    #[tokio::test]
+    async fn cancelled() {
+        let actions = hybrid_to_integrated();
+
+        // Cancelled before it started
+        let token = AtomicU8::new(SWITCH_CANCELLED);
+        let ops = RecordingOps::default();
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(outcome, SwitchOutcome::Cancelled);
+        assert!(ops.performed().is_empty());
+
+        // Cancelled while waiting for logout, nothing which changes the system is done
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            cancel_on: Some((StagedAction::WaitLogout, &token)),
+            ..Default::default()
+        };
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(outcome, SwitchOutcome::Cancelled);
+        assert_eq!(ops.performed(), [StagedAction::WaitLogout]);
+
+        // Too late once the display manager has been stopped
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            cancel_on: Some((StagedAction::UnloadGpuDrivers, &token)),
+            ..Default::default()
+        };
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(outcome, SwitchOutcome::Completed);
+        assert_eq!(ops.performed(), actions);
+    }
+
+    #[tokio::test]
+    async fn shutdown_mid_plan() {
+        let actions = hybrid_to_integrated();
+
+        // Before anything changed the switch is cancelled as by a user
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            shutdown_on: Some((StagedAction::WaitLogout, &token)),
+            ..Default::default()
+        };
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(outcome, SwitchOutcome::Cancelled);
+        assert_eq!(ops.performed(), [StagedAction::WaitLogout]);
+
+        // After, the current action finishes and the display manager is started again
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let ops = RecordingOps {
+            shutdown_on: Some((StagedAction::UnloadGpuDrivers, &token)),
+            ..Default::default()
+        };
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(
+            outcome,
+            SwitchOutcome::Parked {
+                before: StagedAction::UnbindRemoveGpu
+            }
+        );
+        assert_eq!(
+            ops.performed(),
+            [&actions[..6], &[StagedAction::StartDisplayManager]].concat()
+        );
+        assert_eq!(token.load(Ordering::Acquire), SWITCH_PARKED);
+
+        // Nothing to restore once the display manager was started again
+        let token = AtomicU8::new(SWITCH_CANCELLABLE);
+        let mut actions = actions;
+        actions.push(StagedAction::DevTreeManaged);
+        let ops = RecordingOps {
+            shutdown_on: Some((StagedAction::StartDisplayManager, &token)),
+            ..Default::default()
+        };
+        let outcome = execute_plan(GfxMode::Integrated, &actions, &token, &ops).await;
+        assert_eq!(
+            outcome,
+            SwitchOutcome::Parked {
+                before: StagedAction::DevTreeManaged
+            }
+        );
+        assert_eq!(ops.performed(), actions[..11]);
+    }
+}
@@ -0,0 +1,295 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::lock::Mutex;
+
+    use crate::{
+        actions::UserActionRequired,
+        audit::Actor,
+        config::GfxConfig,
+        controller::{CtrlGraphics, NO_SWITCHABLE_GRAPHICS},
+        error::GfxError,
+        pci_device::{DiscreetGpu, GfxMode, GfxVendor},
+        switch_readiness::{
+            preflight, Blocker, PreflightInput, ReadinessGeneration, SwitchReadiness,
+        },
+    };
+
+    #[test]
+    fn each_blocker() {
+        let ready = PreflightInput::default();
+        assert!(preflight(&ready, GfxMode::Hybrid).is_empty());
+
+        let cases = [
+            (
+                PreflightInput {
+                    debug_mode: true,
+                    ..Default::default()
+                },
+                Blocker::DebugMode,
+            ),
+            (
+                PreflightInput {
+                    shutting_down: true,
+                    ..Default::default()
+                },
+                Blocker::ShuttingDown,
+            ),
+            (
+                PreflightInput {
+                    sleeping: true,
+                    ..Default::default()
+                },
+                Blocker::SleepImminent,
+            ),
+            (
+                PreflightInput {
+                    switching_to: Some(GfxMode::Integrated),
+                    ..Default::default()
+                },
+                Blocker::SwitchPending(GfxMode::Integrated),
+            ),
+            (
+                PreflightInput {
+                    switching_to: Some(GfxMode::Integrated),
+                    switch_committed: true,
+                    ..Default::default()
+                },
+                Blocker::SwitchInProgress(GfxMode::Integrated),
+            ),
+            (
+                PreflightInput {
+                    no_dgpu: Some(NO_SWITCHABLE_GRAPHICS),
+                    ..Default::default()
+                },
+                Blocker::NoDgpu(NO_SWITCHABLE_GRAPHICS),
+            ),
+            (
+                PreflightInput {
+                    no_igpu: true,
+                    ..Default::default()
+                },
+                Blocker::NoIgpu(GfxMode::Vfio),
+            ),
+            (
+                PreflightInput {
+                    locked_to: Some(GfxMode::Integrated),
+                    ..Default::default()
+                },
+                Blocker::ModeLocked(GfxMode::Integrated),
+            ),
+            (
+                PreflightInput {
+                    unsupported: Some("no eGPU".to_string()),
+                    ..Default::default()
+                },
+                Blocker::NotSupported("no eGPU".to_string()),
+            ),
+            (
+                PreflightInput {
+                    degraded: true,
+                    ..Default::default()
+                },
+                Blocker::DgpuFellOffBus,
+            ),
+            (
+                PreflightInput {
+                    vfio_users: vec!["qemu-system-x86 (4242)".to_string()],
+                    ..Default::default()
+                },
+                Blocker::VfioInUse(vec!["qemu-system-x86 (4242)".to_string()]),
+            ),
+            (
+                PreflightInput {
+                    plan_refused: Some(UserActionRequired::SwitchToIntegrated),
+                    ..Default::default()
+                },
+                Blocker::PlanRefused(UserActionRequired::SwitchToIntegrated),
+            ),
+        ];
+        for (input, blocker) in cases {
+            assert_eq!(preflight(&input, GfxMode::Vfio), [blocker]);
+        }
+        // Without an iGPU the modes which keep the dGPU on can still be set
+        let no_igpu = PreflightInput {
+            no_igpu: true,
+            ..Default::default()
+        };
+        assert!(preflight(&no_igpu, GfxMode::Hybrid).is_empty());
+    }
+
+    #[test]
+    fn blockers_in_switch_order() {
+        let input = PreflightInput {
+            debug_mode: true,
+            shutting_down: true,
+            locked_to: Some(GfxMode::Hybrid),
+            degraded: true,
+            plan_refused: Some(UserActionRequired::AsusEgpuDisable),
+            ..Default::default()
+        };
+        // Debug mode is reported over shutting down, as check_mutation_allowed does
+        assert_eq!(
+            preflight(&input, GfxMode::AsusEgpu),
+            [
+                Blocker::DebugMode,
+                Blocker::ModeLocked(GfxMode::Hybrid),
+                Blocker::DgpuFellOffBus,
+                Blocker::PlanRefused(UserActionRequired::AsusEgpuDisable),
+            ]
+        );
+        // Integrated can still be set with the dGPU off the bus
+        assert_eq!(
+            preflight(&input, GfxMode::Integrated),
+            [
+                Blocker::DebugMode,
+                Blocker::ModeLocked(GfxMode::Hybrid),
+                Blocker::PlanRefused(UserActionRequired::AsusEgpuDisable),
+            ]
+        );
+    }
+
+    #[test]
+    fn codes_and_messages() {
+        let blockers = [
+            Blocker::ModeLocked(GfxMode::Hybrid),
+            Blocker::PlanRefused(UserActionRequired::AsusEgpuDisable),
+            Blocker::PlanRefused(UserActionRequired::SwitchToIntegrated),
+        ];
+        let readiness = SwitchReadiness::new(GfxMode::Vfio, 3, &blockers);
+        assert!(!readiness.ready);
+        assert_eq!(readiness.generation, 3);
+        let codes: Vec<&str> = readiness.findings.iter().map(|f| f.code.as_str()).collect();
+        assert_eq!(
+            codes,
+            [
+                "mode_locked",
+                "asus_egpu_disable_first",
+                "switch_to_integrated_first"
+            ]
+        );
+        assert_eq!(
+            readiness.findings[0].message,
+            GfxError::ModeLocked(GfxMode::Hybrid).to_string()
+        );
+        assert_eq!(
+            readiness.findings[1].message,
+            <&str>::from(UserActionRequired::AsusEgpuDisable)
+        );
+        assert!(readiness.findings.iter().all(|f| !f.overridable));
+
+        let readiness = SwitchReadiness::new(GfxMode::Hybrid, 0, &[]);
+        assert!(readiness.ready);
+        assert!(readiness.findings.is_empty());
+
+        // A pending switch is replaced by a new one, one changing the system isn't
+        let readiness = SwitchReadiness::new(
+            GfxMode::Hybrid,
+            4,
+            &[Blocker::SwitchPending(GfxMode::Integrated)],
+        );
+        assert!(readiness.ready);
+        assert_eq!(readiness.findings[0].code, "switch_pending");
+        assert!(readiness.findings[0].overridable);
+        let blockers = [
+            Blocker::SleepImminent,
+            Blocker::SwitchInProgress(GfxMode::Integrated),
+            Blocker::VfioInUse(vec!["qemu-system-x86 (4242)".to_string()]),
+        ];
+        let readiness = SwitchReadiness::new(GfxMode::Hybrid, 4, &blockers);
+        assert!(!readiness.ready);
+        let codes: Vec<&str> = readiness.findings.iter().map(|f| f.code.as_str()).collect();
+        assert_eq!(
+            codes,
+            ["sleep_imminent", "switch_in_progress", "vfio_in_use"]
+        );
+        assert_eq!(
+            readiness.findings[2].message,
+            "The dGPU is passed through to a VM, stop it first. Held by: qemu-system-x86 (4242)"
+        );
+        assert_eq!(
+            GfxError::from(Blocker::SwitchInProgress(GfxMode::Integrated)).code(),
+            "switch-in-progress"
+        );
+    }
+
+    #[test]
+    fn generation_counts_changes() {
+        let generation = ReadinessGeneration::default();
+        let shared = generation.clone();
+        assert_eq!(generation.get(), 0);
+        assert_eq!(shared.bump(), 1);
+        assert_eq!(shared.bump(), 2);
+        assert_eq!(generation.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn readiness_agrees_with_switch() {
+        let path = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-switch_readiness-agrees.json",
+            std::process::id()
+        ));
+        let mut ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(
+                path.to_string_lossy().to_string(),
+            ))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+        ctrl.set_mode_locked(true, &Actor::Daemon).await.unwrap();
+
+        let readiness = ctrl
+            .get_switch_readiness(GfxMode::Integrated)
+            .await
+            .unwrap();
+        assert!(!readiness.ready);
+        assert_eq!(readiness.findings[0].code, "mode_locked");
+        let err = ctrl.set_gfx_mode(GfxMode::Integrated).await.unwrap_err();
+        assert_eq!(readiness.findings[0].message, err.to_string());
+        // Asking changes nothing
+        assert_eq!(
+            ctrl.get_switch_readiness(GfxMode::Integrated)
+                .await
+                .unwrap(),
+            readiness
+        );
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn sleep_blocks_switching() {
+        let path = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-switch_readiness-sleep.json",
+            std::process::id()
+        ));
+        let mut ctrl = CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(
+                path.to_string_lossy().to_string(),
+            ))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        );
+        let generation = ctrl.readiness.get();
+        ctrl.set_sleeping(true).await;
+        assert_eq!(ctrl.readiness.get(), generation + 1);
+        let readiness = ctrl
+            .get_switch_readiness(GfxMode::Integrated)
+            .await
+            .unwrap();
+        assert_eq!(readiness.findings[0].code, "sleep_imminent");
+        let err = ctrl.set_gfx_mode(GfxMode::Integrated).await.unwrap_err();
+        assert_eq!(err.code(), "sleep-imminent");
+
+        // Waking counts as a change, saying it again doesn't
+        ctrl.set_sleeping(false).await;
+        ctrl.set_sleeping(false).await;
+        assert_eq!(ctrl.readiness.get(), generation + 2);
+        assert!(ctrl
+            .get_switch_readiness(GfxMode::Integrated)
+            .await
+            .unwrap()
+            .findings
+            .iter()
+            .all(|finding| finding.code != "sleep_imminent"));
+        std::fs::remove_file(path).ok();
+    }
+}
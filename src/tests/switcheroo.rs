@@ -0,0 +1,175 @@
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use crate::{
+        error::GfxError,
+        pci_device::{Device, DiscreetGpu, GfxMode, GfxVendor},
+        switcheroo::{
+            dgpu_launch_allowed, exclude_rule, update_switcheroo, SwitcherooStatus,
+            SwitcherooSystem,
+        },
+    };
+
+    /// switcheroo-control and udev, as seen by the bridge
+    #[derive(Default)]
+    struct MockSwitcheroo {
+        installed: bool,
+        rule: RefCell<Option<String>>,
+        triggers: Cell<u32>,
+        fail_trigger: bool,
+    }
+
+    impl SwitcherooSystem for MockSwitcheroo {
+        fn installed(&self) -> bool {
+            self.installed
+        }
+
+        fn current_rule(&self) -> Option<String> {
+            self.rule.borrow().clone()
+        }
+
+        fn write_rule(&self, rule: Option<&str>) -> Result<(), GfxError> {
+            *self.rule.borrow_mut() = rule.map(str::to_string);
+            Ok(())
+        }
+
+        fn trigger(&self) -> Result<(), GfxError> {
+            if self.fail_trigger {
+                return Err(GfxError::NotSupported("udevadm missing".to_string()));
+            }
+            self.triggers.set(self.triggers.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn nvidia_dgpu() -> DiscreetGpu {
+        DiscreetGpu::mock_devices(
+            GfxVendor::Nvidia,
+            0,
+            vec![
+                Device::mock("0000:01:00.0", GfxVendor::Nvidia, true),
+                Device::mock("0000:01:00.1", GfxVendor::Nvidia, false),
+            ],
+            0,
+        )
+    }
+
+    #[test]
+    fn launch_allowed_per_mode() {
+        let nvidia = GfxVendor::Nvidia;
+        for mode in [
+            GfxMode::Hybrid,
+            GfxMode::NvidiaNoModeset,
+            GfxMode::AsusEgpu,
+            GfxMode::AsusMuxDgpu,
+        ] {
+            assert!(dgpu_launch_allowed(mode, nvidia), "{mode}");
+        }
+        for mode in [GfxMode::Integrated, GfxMode::Vfio, GfxMode::None] {
+            assert!(!dgpu_launch_allowed(mode, nvidia), "{mode}");
+        }
+        assert!(!dgpu_launch_allowed(
+            GfxMode::Hybrid,
+            GfxVendor::AsusDgpuDisabled
+        ));
+    }
+
+    #[test]
+    fn rule_names_every_function() {
+        let rule = exclude_rule(&["0000:01:00.0".to_string(), "0000:01:00.1".to_string()]);
+        assert!(rule.contains(
+            "SUBSYSTEM==\"drm\", KERNELS==\"0000:01:00.0\", ENV{SWITCHEROO_CONTROL_EXCLUDE}=\"1\"\n"
+        ));
+        assert!(rule.contains("KERNELS==\"0000:01:00.1\""));
+    }
+
+    #[test]
+    fn hides_and_shows_dgpu() {
+        let system = MockSwitcheroo {
+            installed: true,
+            ..Default::default()
+        };
+        let dgpu = nvidia_dgpu();
+
+        assert_eq!(
+            update_switcheroo(&system, true, GfxMode::Hybrid, &dgpu),
+            SwitcherooStatus::DgpuShown
+        );
+        assert_eq!(system.triggers.get(), 0);
+
+        assert_eq!(
+            update_switcheroo(&system, true, GfxMode::Integrated, &dgpu),
+            SwitcherooStatus::DgpuHidden
+        );
+        assert!(system
+            .current_rule()
+            .unwrap()
+            .contains("KERNELS==\"0000:01:00.0\""));
+        assert_eq!(system.triggers.get(), 1);
+
+        // Already hidden
+        assert_eq!(
+            update_switcheroo(&system, true, GfxMode::Vfio, &dgpu),
+            SwitcherooStatus::DgpuHidden
+        );
+        assert_eq!(system.triggers.get(), 1);
+
+        assert_eq!(
+            update_switcheroo(&system, true, GfxMode::Hybrid, &dgpu),
+            SwitcherooStatus::DgpuShown
+        );
+        assert_eq!(system.current_rule(), None);
+        assert_eq!(system.triggers.get(), 2);
+    }
+
+    #[test]
+    fn asus_disabled_dgpu_is_hidden() {
+        let system = MockSwitcheroo {
+            installed: true,
+            ..Default::default()
+        };
+        let dgpu = DiscreetGpu::mock(GfxVendor::AsusDgpuDisabled);
+        assert_eq!(
+            update_switcheroo(&system, true, GfxMode::Hybrid, &dgpu),
+            SwitcherooStatus::DgpuHidden
+        );
+    }
+
+    #[test]
+    fn disabled_or_missing_removes_rule() {
+        let dgpu = nvidia_dgpu();
+        let system = MockSwitcheroo::default();
+        assert_eq!(
+            update_switcheroo(&system, true, GfxMode::Integrated, &dgpu),
+            SwitcherooStatus::NotInstalled
+        );
+        assert_eq!(system.triggers.get(), 0);
+
+        // A rule left from when it was on
+        let system = MockSwitcheroo {
+            installed: true,
+            rule: RefCell::new(Some(exclude_rule(&["0000:01:00.0".to_string()]))),
+            ..Default::default()
+        };
+        assert_eq!(
+            update_switcheroo(&system, false, GfxMode::Integrated, &dgpu),
+            SwitcherooStatus::Disabled
+        );
+        assert_eq!(system.current_rule(), None);
+        assert_eq!(system.triggers.get(), 1);
+    }
+
+    #[test]
+    fn trigger_failure_is_reported() {
+        let system = MockSwitcheroo {
+            installed: true,
+            fail_trigger: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            update_switcheroo(&system, true, GfxMode::Integrated, &nvidia_dgpu()),
+            SwitcherooStatus::Failed(_)
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        controller::AsusProbes,
+        error::GfxError,
+        pci_device::{DiscreetGpu, GfxPower, GfxVendor},
+        special_asus::AsusGpuMuxMode,
+        sysfs::{Sysfs, ASUS_DGPU_DISABLE_PATH, ASUS_EGPU_ALT_ENABLE_PATH},
+    };
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-sysfs-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&root).ok();
+        root
+    }
+
+    fn put(path: &std::path::Path, value: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, value).unwrap();
+    }
+
+    #[test]
+    fn paths_are_under_the_root() {
+        let root = root("under");
+        let sysfs = Sysfs::at(&root);
+        assert_eq!(sysfs.dgpu_disable.0.name(), ASUS_DGPU_DISABLE_PATH);
+        assert_eq!(
+            sysfs.dgpu_disable.0.path(),
+            root.join("sys/devices/platform/asus-nb-wmi/dgpu_disable")
+        );
+    }
+
+    #[test]
+    fn existence_is_cached_until_invalidated() {
+        let root = root("cached");
+        let sysfs = Sysfs::at(&root);
+        assert!(!sysfs.dgpu_disable.exists());
+        assert_eq!(sysfs.exists_checks(), 1);
+
+        // Appearing isn't seen while cached
+        put(sysfs.dgpu_disable.0.path(), "0");
+        assert!(!sysfs.dgpu_disable.exists());
+        assert_eq!(sysfs.exists_checks(), 1);
+
+        sysfs.invalidate();
+        assert!(sysfs.dgpu_disable.exists());
+        assert!(sysfs.dgpu_disable.exists());
+        assert_eq!(sysfs.exists_checks(), 2);
+
+        // refresh always checks
+        assert_eq!(sysfs.dgpu_disable.0.refresh(), Ok(true));
+        assert_eq!(sysfs.exists_checks(), 3);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn read_of_missing_attribute() {
+        let root = root("missing");
+        let sysfs = Sysfs::at(&root);
+        put(sysfs.dgpu_disable.0.path(), "1");
+        assert!(sysfs.dgpu_disable.exists());
+        assert!(sysfs.dgpu_disable.disabled().unwrap());
+
+        // Removed, such as asus-wmi unloaded, the failed read marks it absent
+        fs::remove_file(sysfs.dgpu_disable.0.path()).unwrap();
+        let err = sysfs.dgpu_disable.disabled().unwrap_err();
+        assert!(
+            matches!(&err, GfxError::Path(path, _) if path == ASUS_DGPU_DISABLE_PATH),
+            "{err:?}"
+        );
+        let checks = sysfs.exists_checks();
+        assert!(!sysfs.dgpu_disable.exists());
+        assert_eq!(sysfs.exists_checks(), checks);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn write_errors() {
+        let root = root("write");
+        let sysfs = Sysfs::at(&root);
+        let err = sysfs.gpu_mux.set_igpu(true).unwrap_err();
+        assert!(matches!(err, GfxError::Path(..)), "{err:?}");
+
+        put(sysfs.gpu_mux.0.path(), "0");
+        sysfs.gpu_mux.set_igpu(true).unwrap();
+        assert_eq!(
+            fs::read_to_string(sysfs.gpu_mux.0.path()).unwrap(),
+            "1".to_string()
+        );
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn mux_mode() {
+        let root = root("mux");
+        let sysfs = Sysfs::at(&root);
+        put(sysfs.gpu_mux.0.path(), "0\n");
+        assert_eq!(sysfs.gpu_mux.read().unwrap(), AsusGpuMuxMode::Discreet);
+        put(sysfs.gpu_mux.0.path(), "1\n");
+        assert_eq!(sysfs.gpu_mux.read().unwrap(), AsusGpuMuxMode::Optimus);
+
+        put(sysfs.gpu_mux.0.path(), "");
+        let err = sysfs.gpu_mux.read().unwrap_err();
+        assert!(matches!(err, GfxError::Read(..)), "{err:?}");
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn egpu_prefers_the_newer_place() {
+        let root = root("egpu");
+        let sysfs = Sysfs::at(&root);
+        assert!(!sysfs.egpu_enable.exists());
+
+        put(sysfs.egpu_enable.main.path(), "0");
+        put(sysfs.egpu_enable.alt.path(), "1");
+        sysfs.invalidate();
+        assert!(sysfs.egpu_enable.exists());
+        assert_eq!(sysfs.egpu_enable.attr().name(), ASUS_EGPU_ALT_ENABLE_PATH);
+        assert!(sysfs.egpu_enable.enabled().unwrap());
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn probes() {
+        let root = root("probes");
+        let sysfs = Sysfs::at(&root);
+        let probes = AsusProbes::read_in(&sysfs);
+        assert_eq!(probes, AsusProbes::default());
+
+        put(sysfs.dgpu_disable.0.path(), "0");
+        put(sysfs.gpu_mux.0.path(), "0");
+        sysfs.invalidate();
+        let probes = AsusProbes::read_in(&sysfs);
+        assert_eq!(probes.dgpu_disable, Ok(true));
+        assert_eq!(probes.gpu_mux, Ok(true));
+        assert_eq!(probes.mux_discreet, Ok(true));
+        assert_eq!(probes.egpu_enable, Ok(false));
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn status_polls_check_existence_once() {
+        let root = root("poll");
+        let sysfs = Sysfs::at(&root);
+        put(sysfs.dgpu_disable.0.path(), "1");
+        let dgpu = DiscreetGpu::mock(GfxVendor::Nvidia);
+
+        assert_eq!(
+            dgpu.runtime_status_in(&sysfs).unwrap(),
+            GfxPower::AsusDisabled
+        );
+        let checks = sysfs.exists_checks();
+        for _ in 0..10 {
+            assert_eq!(
+                dgpu.runtime_status_in(&sysfs).unwrap(),
+                GfxPower::AsusDisabled
+            );
+        }
+        assert_eq!(sysfs.exists_checks(), checks);
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,96 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        system_class::{battery_present_in, Chassis, SystemClass},
+        tests::test_dir,
+    };
+
+    /// A supply under `dir` with the `type` and, if not empty, `scope`
+    fn fake_supply(dir: &Path, name: &str, kind: &str, scope: &str) {
+        let supply = dir.join(name);
+        fs::create_dir_all(&supply).unwrap();
+        fs::write(supply.join("type"), format!("{kind}\n")).unwrap();
+        if !scope.is_empty() {
+            fs::write(supply.join("scope"), format!("{scope}\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn chassis_from_dmi() {
+        let cases = [
+            ("3\n", Chassis::Desktop),
+            ("7\n", Chassis::Desktop),
+            ("13", Chassis::Desktop),
+            ("17", Chassis::Desktop),
+            ("35", Chassis::Desktop),
+            ("9\n", Chassis::Portable),
+            ("10", Chassis::Portable),
+            ("31", Chassis::Portable),
+            ("1", Chassis::Unknown),
+            ("2", Chassis::Unknown),
+            ("12", Chassis::Unknown),
+            ("", Chassis::Unknown),
+            ("Desktop", Chassis::Unknown),
+        ];
+        for (chassis_type, chassis) in cases {
+            assert_eq!(
+                Chassis::from_chassis_type(chassis_type),
+                chassis,
+                "{chassis_type:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_system_batteries() {
+        let dir = test_dir("class-supplies");
+        fake_supply(&dir, "AC", "Mains", "");
+        fake_supply(&dir, "hidpp_battery_0", "Battery", "Device");
+        assert!(!battery_present_in(&dir));
+
+        fake_supply(&dir, "BAT0", "Battery", "");
+        assert!(battery_present_in(&dir));
+        fs::remove_dir_all(dir.join("BAT0")).unwrap();
+        fake_supply(&dir, "BAT1", "Battery", "System");
+        assert!(battery_present_in(&dir));
+
+        assert!(!battery_present_in(&dir.join("missing")));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn desktop_from_chassis_and_battery() {
+        let dir = test_dir("class-read");
+        let supplies = dir.join("power_supply");
+        fs::create_dir_all(&supplies).unwrap();
+        let chassis_type = dir.join("chassis_type");
+
+        fs::write(&chassis_type, "3\n").unwrap();
+        let class = SystemClass::read_in(&chassis_type, &supplies);
+        assert_eq!(
+            class,
+            SystemClass {
+                chassis: Chassis::Desktop,
+                battery: false
+            }
+        );
+        assert!(class.is_desktop());
+
+        // A laptop which says it is a desktop still has its battery
+        fake_supply(&supplies, "BAT0", "Battery", "");
+        assert!(!SystemClass::read_in(&chassis_type, &supplies).is_desktop());
+        fs::remove_dir_all(supplies.join("BAT0")).unwrap();
+
+        fs::write(&chassis_type, "10\n").unwrap();
+        assert!(!SystemClass::read_in(&chassis_type, &supplies).is_desktop());
+
+        // Without DMI nothing is taken away
+        fs::remove_file(&chassis_type).unwrap();
+        let class = SystemClass::read_in(&chassis_type, &supplies);
+        assert_eq!(class.chassis, Chassis::Unknown);
+        assert!(!class.is_desktop());
+        fs::remove_dir_all(dir).ok();
+    }
+}
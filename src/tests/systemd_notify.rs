@@ -0,0 +1,127 @@
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, os::unix::net::UnixDatagram};
+
+    use crate::{
+        actions::StagedAction,
+        controller::BootOutcome,
+        pci_device::GfxMode,
+        switch_plan::SwitchOutcome,
+        systemd_notify::{boot_status, send_to, state_message, switch_status},
+    };
+
+    #[test]
+    fn message_format() {
+        assert_eq!(
+            state_message(&[("READY", "1"), ("STATUS", "mode=Hybrid ok")]),
+            "READY=1\nSTATUS=mode=Hybrid ok\n"
+        );
+        // A newline would start another assignment
+        assert_eq!(
+            state_message(&[("STATUS", "DEGRADED: a\nWATCHDOG=1")]),
+            "STATUS=DEGRADED: a WATCHDOG=1\n"
+        );
+    }
+
+    #[test]
+    fn boot_status_per_outcome() {
+        assert_eq!(
+            boot_status(&BootOutcome::Ok(GfxMode::Hybrid)),
+            "mode=Hybrid ok"
+        );
+        assert_eq!(
+            boot_status(&BootOutcome::Degraded(
+                GfxMode::Integrated,
+                vec![
+                    "UnloadGpuDrivers: nvidia failed to unload".to_string(),
+                    "UnbindRemoveGpu: busy".to_string()
+                ]
+            )),
+            "mode=Integrated DEGRADED: UnloadGpuDrivers: nvidia failed to unload; UnbindRemoveGpu: busy"
+        );
+        assert_eq!(
+            boot_status(&BootOutcome::Fallback(
+                GfxMode::AsusMuxDgpu,
+                "the MUX is assumed discreet".to_string()
+            )),
+            "mode=AsusMuxDgpu safe-mode fallback active: the MUX is assumed discreet"
+        );
+        assert_eq!(
+            boot_status(&BootOutcome::Skipped(
+                GfxMode::Hybrid,
+                "debug run".to_string()
+            )),
+            "mode=Hybrid boot tasks skipped: debug run"
+        );
+        assert_eq!(
+            boot_status(&BootOutcome::Failed("no dGPU".to_string())),
+            "DEGRADED: boot tasks failed: no dGPU"
+        );
+        assert_eq!(
+            boot_status(&BootOutcome::Reduced(
+                GfxMode::Integrated,
+                "display-manager.service is active".to_string()
+            )),
+            "mode=Integrated reduced boot path: display-manager.service is active"
+        );
+
+        assert!(BootOutcome::Degraded(GfxMode::Hybrid, Vec::new()).broken());
+        assert!(BootOutcome::Failed(String::new()).broken());
+        assert!(!BootOutcome::Fallback(GfxMode::Hybrid, String::new()).broken());
+        assert!(!BootOutcome::Ok(GfxMode::Hybrid).broken());
+        assert!(!BootOutcome::Reduced(GfxMode::Hybrid, String::new()).broken());
+    }
+
+    #[test]
+    fn switch_status_per_outcome() {
+        let from = GfxMode::Hybrid;
+        let to = GfxMode::Integrated;
+        assert_eq!(
+            switch_status(from, to, &SwitchOutcome::Completed).as_deref(),
+            Some("mode=Integrated ok")
+        );
+        assert_eq!(switch_status(from, to, &SwitchOutcome::Cancelled), None);
+        assert_eq!(
+            switch_status(
+                from,
+                to,
+                &SwitchOutcome::RolledBack {
+                    failed: StagedAction::UnloadGpuDrivers
+                }
+            )
+            .as_deref(),
+            Some("mode=Hybrid ok, switch to Integrated failed at UnloadGpuDrivers and was undone")
+        );
+        let stalled = switch_status(
+            from,
+            to,
+            &SwitchOutcome::Stalled {
+                failed: StagedAction::UnloadGpuDrivers,
+                rollback_failed: StagedAction::LoadGpuDrivers,
+            },
+        )
+        .unwrap();
+        assert!(stalled.starts_with("mode=Hybrid DEGRADED:"), "{stalled}");
+    }
+
+    #[test]
+    fn sends_to_socket() {
+        let dir =
+            std::env::temp_dir().join(format!("supergfxctl-test-{}-sd-notify", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        let msg = state_message(&[("STATUS", "mode=Hybrid ok")]);
+        assert!(send_to(path.as_os_str(), &msg).unwrap());
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], msg.as_bytes());
+
+        // Not sent to an abstract socket, and an error if nothing is listening
+        assert!(!send_to(OsStr::new("@/org/freedesktop/systemd1/notify"), &msg).unwrap());
+        assert!(send_to(dir.join("gone").as_os_str(), &msg).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
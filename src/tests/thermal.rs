@@ -0,0 +1,187 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        pci_device::GfxMode,
+        thermal::{
+            hwmon_temp_in, thermal_watched, TempSource, ThermalAdvisory, ThermalState,
+            ThermalWatch, THERMAL_SAMPLE,
+        },
+    };
+
+    /// Reads whatever the test last set, in °C
+    struct FakeTemp(Option<i32>);
+
+    impl TempSource for FakeTemp {
+        fn read_millic(&mut self) -> Option<i32> {
+            self.0.map(|c| c * 1000)
+        }
+    }
+
+    fn config() -> ThermalAdvisory {
+        ThermalAdvisory {
+            threshold_c: 80,
+            sustained_s: 600,
+            average_window_s: 180,
+        }
+    }
+
+    /// Observe once a minute for `minutes` from `*now`, returning the advisories given
+    fn run(
+        watch: &mut ThermalWatch,
+        now: &mut Instant,
+        minutes: u32,
+        on_battery: bool,
+        temp: &mut FakeTemp,
+    ) -> Vec<u32> {
+        let mut advised = Vec::new();
+        for _ in 0..minutes {
+            *now += THERMAL_SAMPLE;
+            advised.extend(watch.observe(*now, true, on_battery, temp));
+        }
+        advised
+    }
+
+    #[test]
+    fn advises_once_when_sustained_on_battery() {
+        let mut watch = ThermalWatch::new(config());
+        let mut now = Instant::now();
+        let mut temp = FakeTemp(Some(90));
+
+        // Hot on AC is fine
+        assert!(run(&mut watch, &mut now, 30, false, &mut temp).is_empty());
+        assert_eq!(
+            watch.state(),
+            ThermalState {
+                sampling: true,
+                average_c: 90,
+                advising: false
+            }
+        );
+
+        // Not yet sustained for 10 minutes
+        assert!(run(&mut watch, &mut now, 10, true, &mut temp).is_empty());
+        assert_eq!(run(&mut watch, &mut now, 1, true, &mut temp), vec![90]);
+        assert!(watch.state().advising);
+        // Only once while it stays hot
+        assert!(run(&mut watch, &mut now, 60, true, &mut temp).is_empty());
+    }
+
+    #[test]
+    fn samples_at_the_slow_interval() {
+        let mut watch = ThermalWatch::new(config());
+        let start = Instant::now();
+        let mut temp = FakeTemp(Some(70));
+        watch.observe(start, true, true, &mut temp);
+        // Woken often by the status loop, read once a minute
+        temp.0 = Some(100);
+        for secs in (10..60).step_by(10) {
+            watch.observe(start + Duration::from_secs(secs), true, true, &mut temp);
+        }
+        assert_eq!(watch.state().average_c, 70);
+        watch.observe(start + THERMAL_SAMPLE, true, true, &mut temp);
+        assert_eq!(watch.state().average_c, 85);
+    }
+
+    #[test]
+    fn rolling_average_and_reset() {
+        let mut watch = ThermalWatch::new(config());
+        let mut now = Instant::now();
+        let mut temp = FakeTemp(Some(95));
+        assert_eq!(run(&mut watch, &mut now, 11, true, &mut temp), vec![95]);
+
+        // A short dip doesn't reset it, the average over 3 minutes stays high
+        temp.0 = Some(60);
+        assert!(run(&mut watch, &mut now, 1, true, &mut temp).is_empty());
+        assert!(watch.state().advising);
+        temp.0 = Some(95);
+        run(&mut watch, &mut now, 4, true, &mut temp);
+
+        // Cooled to under the threshold, but not by the hysteresis
+        temp.0 = Some(78);
+        run(&mut watch, &mut now, 4, true, &mut temp);
+        assert_eq!(watch.state().average_c, 78);
+        assert!(watch.state().advising);
+        temp.0 = Some(95);
+        assert!(run(&mut watch, &mut now, 20, true, &mut temp).is_empty());
+
+        // Cooled well under it, so it can advise again
+        temp.0 = Some(60);
+        run(&mut watch, &mut now, 4, true, &mut temp);
+        assert!(!watch.state().advising);
+        temp.0 = Some(95);
+        assert_eq!(run(&mut watch, &mut now, 14, true, &mut temp), vec![95]);
+
+        // Plugging in AC resets it
+        run(&mut watch, &mut now, 1, false, &mut temp);
+        assert!(!watch.state().advising);
+        assert_eq!(run(&mut watch, &mut now, 11, true, &mut temp), vec![95]);
+
+        // Leaving the mode stops sampling and resets it
+        assert_eq!(
+            watch.observe(now + THERMAL_SAMPLE, false, true, &mut temp),
+            None
+        );
+        assert_eq!(watch.state(), ThermalState::default());
+    }
+
+    #[test]
+    fn unreadable_or_disabled() {
+        let mut watch = ThermalWatch::new(config());
+        let mut now = Instant::now();
+        let mut temp = FakeTemp(None);
+        assert!(run(&mut watch, &mut now, 30, true, &mut temp).is_empty());
+        assert_eq!(watch.state().average_c, 0);
+
+        watch.set_config(ThermalAdvisory {
+            threshold_c: 0,
+            ..config()
+        });
+        temp.0 = Some(100);
+        assert!(run(&mut watch, &mut now, 30, true, &mut temp).is_empty());
+        assert!(!watch.state().sampling);
+    }
+
+    #[test]
+    fn window_kept_within_bounds() {
+        let mut watch = ThermalWatch::new(ThermalAdvisory {
+            average_window_s: u64::MAX,
+            ..config()
+        });
+        let mut now = Instant::now();
+        let mut temp = FakeTemp(Some(100));
+        run(&mut watch, &mut now, 500, false, &mut temp);
+        // Only the last hour is averaged, the samples before it are gone
+        temp.0 = Some(50);
+        run(&mut watch, &mut now, 61, false, &mut temp);
+        assert_eq!(watch.state().average_c, 50);
+
+        let short = ThermalAdvisory {
+            average_window_s: 0,
+            ..config()
+        };
+        assert_eq!(short.average_window(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn reads_hwmon_temp() {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-hwmon-temp",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(hwmon_temp_in(&dir), None);
+        fs::create_dir_all(dir.join("hwmon/hwmon3")).unwrap();
+        fs::create_dir_all(dir.join("hwmon/hwmon4")).unwrap();
+        fs::write(dir.join("hwmon/hwmon4/temp1_input"), "67000\n").unwrap();
+        assert_eq!(hwmon_temp_in(&dir), Some(67000));
+
+        assert!(thermal_watched(GfxMode::AsusMuxDgpu));
+        assert!(!thermal_watched(GfxMode::Hybrid));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
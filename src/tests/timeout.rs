@@ -0,0 +1,29 @@
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::timeout::blocking_with_timeout;
+
+    #[tokio::test]
+    async fn blocking_query_given_up_on() {
+        assert_eq!(
+            blocking_with_timeout(Duration::from_secs(5), || Some(1)).await,
+            Some(1)
+        );
+        let slow = blocking_with_timeout(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_millis(500));
+            Some(1)
+        })
+        .await;
+        assert_eq!(slow, None);
+
+        // Another task on the runtime keeps running while the query blocks
+        let ticker = tokio::spawn(tokio::time::sleep(Duration::from_millis(1)));
+        blocking_with_timeout(Duration::from_secs(5), || {
+            thread::sleep(Duration::from_millis(50));
+            Some(())
+        })
+        .await;
+        assert!(ticker.is_finished());
+    }
+}
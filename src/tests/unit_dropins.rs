@@ -0,0 +1,152 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        error::GfxError,
+        unit_dropins::{
+            apply_dropin, dropin_path, generate_unit_dropins, installed_units, remove_dropin,
+            UnitReload, DROPIN_HEADER, ORDERING_TABLE,
+        },
+    };
+
+    fn test_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "supergfxctl-test-{}-unit-dropins-{name}",
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn install(root: &Path, dir: &str, unit: &str) {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(unit), "[Unit]\n").unwrap();
+    }
+
+    #[derive(Default)]
+    struct CountingReload(Cell<u32>);
+
+    impl UnitReload for CountingReload {
+        fn daemon_reload(&self) -> Result<(), GfxError> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn detects_installed_units() {
+        let root = test_root("detect");
+        assert!(installed_units(&root).is_empty());
+
+        install(&root, "usr/lib/systemd/system", "libvirtd.service");
+        install(&root, "lib/systemd/system", "nvidia-persistenced.service");
+        // The display manager is an alias in /etc
+        install(&root, "etc/systemd/system", "display-manager.service");
+        install(&root, "usr/lib/systemd/system", "unrelated.service");
+        // In the order of the table
+        assert_eq!(
+            installed_units(&root),
+            [
+                "display-manager.service",
+                "nvidia-persistenced.service",
+                "libvirtd.service"
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dropin_for_each_combination() {
+        assert_eq!(generate_unit_dropins(&[]), None);
+        assert_eq!(generate_unit_dropins(&["unrelated.service"]), None);
+
+        let units: Vec<&str> = ORDERING_TABLE.iter().map(|o| o.unit).collect();
+        // Every subset of the table gives a line for each unit in it and no others
+        for bits in 1..(1u32 << units.len()) {
+            let installed: Vec<&str> = units
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| bits & (1 << i) != 0)
+                .map(|(_, unit)| *unit)
+                .collect();
+            let dropin = generate_unit_dropins(&installed).unwrap();
+            assert!(dropin.starts_with(DROPIN_HEADER));
+            assert!(dropin.contains("\n[Unit]\n"));
+            for ordering in ORDERING_TABLE {
+                let line = format!("\n{}={}\n", ordering.relation, ordering.unit);
+                assert_eq!(
+                    dropin.contains(&line),
+                    installed.contains(&ordering.unit),
+                    "{line} in {dropin}"
+                );
+            }
+        }
+
+        let dropin = generate_unit_dropins(&["asusd.service", "libvirtd.service"]).unwrap();
+        let lines: Vec<&str> = dropin
+            .lines()
+            .filter(|line| !line.starts_with('#') && !line.is_empty())
+            .collect();
+        assert_eq!(
+            lines,
+            ["[Unit]", "Before=libvirtd.service", "After=asusd.service"]
+        );
+    }
+
+    #[test]
+    fn apply_and_remove() {
+        let root = test_root("apply");
+        let reload = CountingReload::default();
+        let dropin = generate_unit_dropins(&["libvirtd.service"]).unwrap();
+
+        assert!(!remove_dropin(&root, &reload).unwrap());
+        assert_eq!(reload.0.get(), 0);
+
+        let path = apply_dropin(&root, &dropin, &reload).unwrap();
+        assert_eq!(path, dropin_path(&root));
+        assert_eq!(fs::read_to_string(&path).unwrap(), dropin);
+        assert_eq!(reload.0.get(), 1);
+        // Its own drop-in is replaced
+        let dropin = generate_unit_dropins(&["asusd.service"]).unwrap();
+        apply_dropin(&root, &dropin, &reload).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), dropin);
+
+        assert!(remove_dropin(&root, &reload).unwrap());
+        assert!(!path.exists());
+        assert!(!path.parent().unwrap().exists());
+        assert_eq!(reload.0.get(), 3);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn foreign_dropin_left_alone() {
+        let root = test_root("foreign");
+        let reload = CountingReload::default();
+        let path = dropin_path(&root);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "[Unit]\nAfter=mine.service\n").unwrap();
+
+        let dropin = generate_unit_dropins(&["libvirtd.service"]).unwrap();
+        assert!(matches!(
+            apply_dropin(&root, &dropin, &reload),
+            Err(GfxError::UnitDropin(_))
+        ));
+        assert!(matches!(
+            remove_dropin(&root, &reload),
+            Err(GfxError::UnitDropin(_))
+        ));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[Unit]\nAfter=mine.service\n"
+        );
+        assert_eq!(reload.0.get(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::{
+        config::create_vfio_conf,
+        error::GfxError,
+        pci_device::{Device, GfxVendor},
+        switcheroo::exclude_rule,
+        validate::{
+            absolute_path, identifier, pci_address, pci_function, pci_id, text, unit_name,
+            InputClass,
+        },
+    };
+
+    /// Strings made of the characters which break out of a line, a quoted value or a path,
+    /// mixed with those each class accepts
+    fn adversarial() -> impl Strategy<Value = String> {
+        let pool = vec![
+            '\n', '\r', '\0', '\t', '"', '\'', '\\', '/', '.', '-', '_', ':', ';', '$', '`', ' ',
+            '#', '=', '@', ',', '0', '1', '9', 'a', 'f', 'g', 'Z', 'é', '\u{202e}',
+        ];
+        prop_oneof![
+            prop::collection::vec(prop::sample::select(pool), 0..40)
+                .prop_map(|chars| chars.into_iter().collect()),
+            any::<String>(),
+        ]
+    }
+
+    /// How every line of the switcheroo rule ends
+    const EXCLUDE: &str = "ENV{SWITCHEROO_CONTROL_EXCLUDE}=\"1\"";
+
+    fn class_of(err: GfxError) -> InputClass {
+        match err {
+            GfxError::InvalidInput(class, _) => class,
+            err => panic!("unexpected error {err}"),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn accepted_values_are_one_line(value in adversarial()) {
+            let accepted = [
+                unit_name(&value).is_ok(),
+                pci_address(&value).is_ok(),
+                pci_function(&value).is_ok(),
+                pci_id(&value).is_ok(),
+                identifier(&value).is_ok(),
+                text(&value, 256).is_ok(),
+                absolute_path(&value).is_ok(),
+            ];
+            if accepted.contains(&true) {
+                prop_assert!(!value.is_empty());
+                prop_assert!(!value.chars().any(char::is_control));
+            }
+        }
+
+        #[test]
+        fn accepted_names_are_not_options(value in adversarial()) {
+            if unit_name(&value).is_ok() {
+                prop_assert!(!value.starts_with('-'));
+                prop_assert!(value.ends_with(".service") || value.ends_with(".target"));
+            }
+            if identifier(&value).is_ok() {
+                prop_assert!(!value.starts_with('-'));
+                prop_assert!(value.len() <= 64);
+            }
+        }
+
+        #[test]
+        fn accepted_paths_stay_put(value in adversarial()) {
+            if absolute_path(&value).is_ok() {
+                prop_assert!(value.starts_with('/'));
+                prop_assert!(!value.split('/').any(|part| part == ".."));
+            }
+        }
+
+        #[test]
+        fn rejections_name_the_class(value in adversarial()) {
+            if let Err(err) = pci_id(&value) {
+                prop_assert_eq!(class_of(err), InputClass::PciId);
+            }
+            if let Err(err) = unit_name(&value) {
+                prop_assert_eq!(class_of(err), InputClass::UnitName);
+            }
+        }
+
+        #[test]
+        fn well_formed_values_are_accepted(
+            domain in 0u16..=0xffff,
+            bus in 0u8..=0xff,
+            slot in 0u8..0x20,
+            function in 0u8..8,
+            vendor in 0u16..=0xffff,
+            device in 0u16..=0xffff,
+        ) {
+            let address = format!("{domain:04x}:{bus:02x}:{slot:02x}.{function}");
+            prop_assert!(pci_address(&address).is_ok());
+            prop_assert!(pci_function(&address).is_ok());
+            let short = format!("{bus:02x}:{slot:02x}.{function}");
+            prop_assert!(pci_function(&short).is_ok());
+            let only_function = format!(".{function}");
+            prop_assert!(pci_function(&only_function).is_ok());
+            let id = format!("{vendor:04x}:{device:04x}");
+            prop_assert!(pci_id(&id).is_ok());
+        }
+
+        #[test]
+        fn switcheroo_rule_has_a_line_per_address(
+            names in prop::collection::vec(adversarial(), 0..6),
+        ) {
+            let rule = exclude_rule(&names);
+            let valid = names.iter().filter(|name| pci_address(name).is_ok()).count();
+            let mut lines = rule.lines();
+            prop_assert!(lines.next().is_some());
+            let mut count = 0;
+            for line in lines {
+                prop_assert!(line.starts_with("SUBSYSTEM==\"drm\", KERNELS==\""));
+                prop_assert!(line.ends_with(EXCLUDE));
+                count += 1;
+            }
+            prop_assert_eq!(count, valid);
+        }
+
+        #[test]
+        fn vfio_conf_only_lists_ids(id in adversarial()) {
+            let devices = [Device::mock("0000:01:00.0", GfxVendor::Nvidia, true).with_pci_id(&id)];
+            match create_vfio_conf(&devices, &[], Some(false)) {
+                Ok(conf) => {
+                    let conf = String::from_utf8(conf).unwrap();
+                    let last = conf.lines().last().unwrap();
+                    prop_assert_eq!(last, format!("options vfio-pci ids={}", id.to_lowercase()));
+                    prop_assert!(pci_id(&id.to_lowercase()).is_ok());
+                }
+                Err(GfxError::InvalidInput(class, _)) => prop_assert_eq!(class, InputClass::PciId),
+                Err(GfxError::VfioIncomplete(_)) => prop_assert!(id.is_empty()),
+                Err(err) => panic!("unexpected error {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unit_names() {
+        assert!(unit_name("gdm.service").is_ok());
+        assert!(unit_name("getty@tty1.service").is_ok());
+        assert!(unit_name("graphical.target").is_ok());
+        assert!(unit_name("gdm").is_err());
+        assert!(unit_name("-gdm.service").is_err());
+        assert!(unit_name("gdm.service\nExecStart=/bin/sh").is_err());
+        assert!(unit_name("gdm.socket").is_err());
+    }
+
+    #[test]
+    fn pci_functions() {
+        assert!(pci_function("0000:01:00.1").is_ok());
+        assert!(pci_function("01:00.1").is_ok());
+        assert!(pci_function(".2").is_ok());
+        assert!(pci_function(".8").is_err());
+        assert!(pci_function("0000:01:00.1 ").is_err());
+        assert!(pci_function("").is_err());
+        assert!(pci_address("01:00.1").is_err());
+        assert!(pci_address("0000:01:00.1\"").is_err());
+    }
+
+    #[test]
+    fn identifiers_text_and_paths() {
+        assert!(identifier("mux_assumed").is_ok());
+        assert!(identifier("--all").is_err());
+        assert!(identifier(&"a".repeat(65)).is_err());
+        assert!(text("switching for a game", 256).is_ok());
+        assert!(text("   ", 256).is_err());
+        assert!(text("line\nanother", 256).is_err());
+        assert!(text(&"a".repeat(257), 256).is_err());
+        assert!(absolute_path("/tmp/bundle.tar").is_ok());
+        assert!(absolute_path("bundle.tar").is_err());
+        assert!(absolute_path("/tmp/../etc/shadow").is_err());
+        assert_eq!(
+            absolute_path("bundle.tar").unwrap_err().to_string(),
+            "Invalid path: \"bundle.tar\" is not absolute"
+        );
+    }
+}
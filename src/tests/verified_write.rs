@@ -0,0 +1,75 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        error::GfxError,
+        tests::test_dir,
+        verified_write::{temp_path_for, write_verified, write_verified_with},
+    };
+
+    #[test]
+    fn written_and_replaced() {
+        let dir = test_dir("verified-replace");
+        let path = dir.join("supergfxd.conf");
+        assert_eq!(
+            temp_path_for(&path),
+            dir.join("supergfxd.conf.tmp"),
+            "not read by modprobe"
+        );
+
+        write_verified(&path, b"blacklist nouveau\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"blacklist nouveau\n");
+        write_verified(&path, b"options nvidia-drm modeset=1\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"options nvidia-drm modeset=1\n");
+        write_verified(&path, b"").unwrap();
+        assert!(fs::read(&path).unwrap().is_empty());
+        assert!(!temp_path_for(&path).exists());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn short_write_leaves_the_file() {
+        let dir = test_dir("verified-short");
+        let path = dir.join("supergfxd.conf");
+        fs::write(&path, "blacklist nouveau\n").unwrap();
+
+        // As a full filesystem can, the write is cut short without an error
+        let err = write_verified_with(&path, b"options nvidia-drm modeset=1\n", |tmp, content| {
+            fs::write(tmp, &content[..8]).unwrap();
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(
+            matches!(&err, GfxError::VerifyFailed(failed) if *failed == path.display().to_string()),
+            "{err:?}"
+        );
+        assert_eq!(err.code(), "verify-failed");
+        assert_eq!(fs::read(&path).unwrap(), b"blacklist nouveau\n");
+        assert!(!temp_path_for(&path).exists());
+
+        // A failed write is given back as it is
+        let err = write_verified_with(&path, b"", |_, _| {
+            Err(GfxError::Write(
+                "supergfxd.conf.tmp".to_string(),
+                std::io::Error::from(std::io::ErrorKind::Other),
+            ))
+        })
+        .unwrap_err();
+        assert!(matches!(err, GfxError::Write(..)), "{err:?}");
+        assert_eq!(fs::read(&path).unwrap(), b"blacklist nouveau\n");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn missing_directory_fails() {
+        let dir = test_dir("verified-missing");
+        let path = dir.join("modprobe.d/supergfxd.conf");
+        assert!(matches!(
+            write_verified(&path, b"blacklist nouveau\n"),
+            Err(GfxError::Path(..))
+        ));
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).ok();
+    }
+}
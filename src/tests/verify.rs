@@ -0,0 +1,345 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        config::GfxConfig,
+        error::GfxError,
+        nvidia_nodes::{NodeFinding, NodeProblem},
+        pci_device::{Device, DiscreetGpu, GfxMode, GfxVendor},
+        switcheroo::{exclude_rule, SwitcherooSystem},
+        tests::test_dir,
+        verify::{
+            find_drift, xorg_nvidia_confs_in, DriftFinding, DriftRemedy, ExpectedState,
+            ObservedState, VerifySchedule,
+        },
+    };
+
+    struct MockSwitcheroo {
+        installed: bool,
+    }
+
+    impl SwitcherooSystem for MockSwitcheroo {
+        fn installed(&self) -> bool {
+            self.installed
+        }
+
+        fn current_rule(&self) -> Option<String> {
+            None
+        }
+
+        fn write_rule(&self, _rule: Option<&str>) -> Result<(), GfxError> {
+            Ok(())
+        }
+
+        fn trigger(&self) -> Result<(), GfxError> {
+            Ok(())
+        }
+    }
+
+    fn nvidia_dgpu() -> DiscreetGpu {
+        DiscreetGpu::mock_devices(
+            GfxVendor::Nvidia,
+            0,
+            vec![
+                Device::mock("0000:01:00.0", GfxVendor::Nvidia, true),
+                Device::mock("0000:01:00.1", GfxVendor::Nvidia, false),
+            ],
+            0,
+        )
+    }
+
+    #[test]
+    fn remedy_table() {
+        let table = [
+            (DriftFinding::ModprobeConf, DriftRemedy::SelfHeal),
+            (
+                DriftFinding::RuntimePm {
+                    function: "0000:01:00.0".to_string(),
+                    control: "on".to_string(),
+                },
+                DriftRemedy::SelfHeal,
+            ),
+            (
+                DriftFinding::NvidiaPowerd {
+                    expected_active: false,
+                },
+                DriftRemedy::SelfHeal,
+            ),
+            (DriftFinding::SwitcherooRule, DriftRemedy::SelfHeal),
+            (
+                DriftFinding::ForeignXorgConf("/etc/X11/xorg.conf".into()),
+                DriftRemedy::ReportOnly,
+            ),
+            (
+                DriftFinding::UnexpectedModule("nvidia".to_string()),
+                DriftRemedy::ReportOnly,
+            ),
+            (
+                DriftFinding::StaleInitramfs(String::new()),
+                DriftRemedy::ReportOnly,
+            ),
+        ];
+        for (finding, remedy) in table {
+            assert_eq!(finding.remedy(), remedy, "{finding}");
+        }
+
+        // nvidia device nodes are only made when the config allows it
+        let node = DriftFinding::NvidiaNode(NodeFinding {
+            node: "/dev/nvidiactl".into(),
+            problem: NodeProblem::Missing,
+        });
+        let mut config = GfxConfig::new(String::new());
+        assert_eq!(node.remedy(), DriftRemedy::ReportOnly);
+        assert_eq!(node.remedy_with(&config), DriftRemedy::ReportOnly);
+        config.fix_nvidia_nodes = true;
+        assert_eq!(node.remedy_with(&config), DriftRemedy::SelfHeal);
+        assert_eq!(
+            DriftFinding::ModprobeConf.remedy_with(&config),
+            DriftRemedy::SelfHeal
+        );
+    }
+
+    #[test]
+    fn expected_for_mode() {
+        let config = GfxConfig::new(String::new());
+        let dgpu = nvidia_dgpu();
+        let switcheroo = MockSwitcheroo { installed: true };
+
+        let hybrid = ExpectedState::for_mode(&config, GfxMode::Hybrid, &dgpu, &switcheroo);
+        assert_eq!(hybrid.powerd_active, Some(true));
+        assert!(!hybrid.nvidia_unloaded);
+        assert!(hybrid.runtime_pm_auto);
+        assert!(hybrid.modprobe_conf.is_some());
+        assert!(hybrid.nvidia_usable);
+        // manage_switcheroo is off, no rule is wanted
+        assert_eq!(hybrid.switcheroo_rule, Some(None));
+
+        let mut config = config;
+        config.manage_switcheroo = true;
+        let integrated = ExpectedState::for_mode(&config, GfxMode::Integrated, &dgpu, &switcheroo);
+        assert_eq!(integrated.powerd_active, Some(false));
+        assert!(integrated.nvidia_unloaded);
+        assert!(!integrated.nvidia_usable);
+        assert_eq!(
+            integrated.switcheroo_rule,
+            Some(Some(exclude_rule(&[
+                "0000:01:00.0".to_string(),
+                "0000:01:00.1".to_string()
+            ])))
+        );
+
+        // Nothing is checked for modes with no boot actions
+        let none = ExpectedState::for_mode(&config, GfxMode::None, &dgpu, &switcheroo);
+        assert_eq!(none.powerd_active, None);
+        assert_eq!(none.modprobe_conf, None);
+        assert!(!none.nvidia_unloaded);
+
+        // An AMD dGPU doesn't get a modprobe conf or nvidia-powerd
+        let amd = DiscreetGpu::mock(GfxVendor::Amd);
+        let amd = ExpectedState::for_mode(&config, GfxMode::Integrated, &amd, &switcheroo);
+        assert_eq!(amd.powerd_active, None);
+        assert_eq!(amd.modprobe_conf, None);
+        assert!(!amd.nvidia_unloaded);
+    }
+
+    fn applied() -> (ExpectedState, ObservedState) {
+        let expected = ExpectedState {
+            modprobe_conf: Some(b"blacklist nvidia\n".to_vec()),
+            runtime_pm_auto: true,
+            powerd_active: Some(false),
+            switcheroo_rule: Some(Some("rule\n".to_string())),
+            nvidia_unloaded: true,
+            nvidia_usable: false,
+        };
+        let observed = ObservedState {
+            modprobe_conf: Some(b"blacklist nvidia\n".to_vec()),
+            runtime_pm: vec![("0000:01:00.0".to_string(), "auto".to_string())],
+            powerd_active: Some(false),
+            switcheroo_rule: Some("rule\n".to_string()),
+            xorg_nvidia_confs: Vec::new(),
+            nvidia_loaded: false,
+            nvidia_nodes: Vec::new(),
+            initramfs_advisory: None,
+        };
+        (expected, observed)
+    }
+
+    #[test]
+    fn no_drift() {
+        let (expected, observed) = applied();
+        assert!(find_drift(&expected, &observed).is_empty());
+        // nvidia-powerd not installed isn't drift
+        let observed = ObservedState {
+            powerd_active: None,
+            ..observed
+        };
+        assert!(find_drift(&expected, &observed).is_empty());
+        // Nothing is checked if nothing is expected
+        assert!(find_drift(&ExpectedState::default(), &ObservedState::default()).is_empty());
+    }
+
+    #[test]
+    fn drift_found() {
+        let (expected, _) = applied();
+        let observed = ObservedState {
+            modprobe_conf: None,
+            runtime_pm: vec![
+                ("0000:01:00.0".to_string(), "on".to_string()),
+                ("0000:01:00.1".to_string(), "auto".to_string()),
+            ],
+            powerd_active: Some(true),
+            switcheroo_rule: None,
+            xorg_nvidia_confs: vec!["/etc/X11/xorg.conf.d/10-nvidia.conf".into()],
+            nvidia_loaded: true,
+            nvidia_nodes: Vec::new(),
+            initramfs_advisory: Some("initramfs regeneration recommended (dracut -f)".to_string()),
+        };
+        assert_eq!(
+            find_drift(&expected, &observed),
+            [
+                DriftFinding::ModprobeConf,
+                DriftFinding::RuntimePm {
+                    function: "0000:01:00.0".to_string(),
+                    control: "on".to_string(),
+                },
+                DriftFinding::NvidiaPowerd {
+                    expected_active: false,
+                },
+                DriftFinding::SwitcherooRule,
+                DriftFinding::UnexpectedModule("nvidia".to_string()),
+                DriftFinding::ForeignXorgConf("/etc/X11/xorg.conf.d/10-nvidia.conf".into()),
+                DriftFinding::StaleInitramfs(
+                    "initramfs regeneration recommended (dracut -f)".to_string()
+                ),
+            ]
+        );
+
+        // The nvidia driver in use is only drift in modes which unload it
+        let expected = ExpectedState {
+            nvidia_unloaded: false,
+            ..expected
+        };
+        let observed = ObservedState {
+            modprobe_conf: expected.modprobe_conf.clone(),
+            runtime_pm: Vec::new(),
+            powerd_active: Some(false),
+            switcheroo_rule: Some("rule\n".to_string()),
+            initramfs_advisory: None,
+            ..observed
+        };
+        assert!(find_drift(&expected, &observed).is_empty());
+    }
+
+    #[test]
+    fn nvidia_nodes_drift() {
+        let expected = ExpectedState {
+            nvidia_usable: true,
+            ..Default::default()
+        };
+        let missing = NodeFinding {
+            node: "/dev/nvidia-modeset".into(),
+            problem: NodeProblem::Missing,
+        };
+        let observed = ObservedState {
+            nvidia_loaded: true,
+            nvidia_nodes: vec![missing.clone()],
+            ..Default::default()
+        };
+        assert_eq!(
+            find_drift(&expected, &observed),
+            [DriftFinding::NvidiaNode(missing.clone())]
+        );
+        // Not in modes which don't use the dGPU
+        assert!(find_drift(&ExpectedState::default(), &observed).is_empty());
+        // Nor while the driver isn't loaded
+        let observed = ObservedState {
+            nvidia_loaded: false,
+            ..observed
+        };
+        assert!(find_drift(&expected, &observed).is_empty());
+    }
+
+    #[test]
+    fn empty_modprobe_conf_may_be_missing() {
+        let expected = ExpectedState {
+            modprobe_conf: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert!(find_drift(&expected, &ObservedState::default()).is_empty());
+    }
+
+    #[test]
+    fn xorg_confs() {
+        let dir = test_dir("verify-xorg");
+        let confd = dir.join("xorg.conf.d");
+        fs::create_dir_all(&confd).unwrap();
+        fs::write(
+            confd.join("10-nvidia.conf"),
+            "Section \"OutputClass\"\n    Driver \"nvidia\"\nEndSection\n",
+        )
+        .unwrap();
+        fs::write(
+            confd.join("20-intel.conf"),
+            "Section \"Device\"\n    Driver \"modesetting\"\nEndSection\n",
+        )
+        .unwrap();
+        fs::write(
+            confd.join("30-commented.conf"),
+            "Section \"Device\"\n#    Driver \"nvidia\"\nEndSection\n",
+        )
+        .unwrap();
+        // Not a .conf, xorg ignores it
+        fs::write(confd.join("10-nvidia.conf.bak"), "Driver \"nvidia\"\n").unwrap();
+        let xorg_conf = dir.join("xorg.conf");
+        fs::write(
+            &xorg_conf,
+            "Section \"Device\"\n\tdriver \"nvidia\"\nEndSection\n",
+        )
+        .unwrap();
+
+        let paths: Vec<&Path> = vec![&xorg_conf, &confd, Path::new("/nonexistent")];
+        assert_eq!(
+            xorg_nvidia_confs_in(&paths),
+            [xorg_conf.clone(), confd.join("10-nvidia.conf")]
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn schedule_by_wall_clock() {
+        let hour = Duration::from_secs(3600);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut schedule = VerifySchedule::new(start);
+
+        // Waits are short so the clock is looked at again soon
+        let wait = schedule.wait(start, hour).unwrap();
+        assert!(wait <= Duration::from_secs(60));
+        assert_eq!(
+            schedule.wait(start + hour - Duration::from_secs(10), hour),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(schedule.wait(start + hour, hour), None);
+
+        // Suspended past the deadline, it is due on wake
+        assert_eq!(schedule.wait(start + hour * 30, hour), None);
+        schedule.ran(start + hour * 30);
+        assert!(schedule.wait(start + hour * 30, hour).is_some());
+        assert_eq!(schedule.wait(start + hour * 31, hour), None);
+    }
+
+    #[test]
+    fn schedule_clock_set_back() {
+        let hour = Duration::from_secs(3600);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut schedule = VerifySchedule::new(start);
+        let earlier = start - hour * 24;
+        assert!(schedule.wait(earlier, hour).is_some());
+        // Counted from when the clock went back, not from the old time
+        assert_eq!(schedule.wait(earlier + hour, hour), None);
+    }
+}
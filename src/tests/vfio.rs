@@ -0,0 +1,138 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        actions::{Action, StagedAction},
+        config::GfxConfig,
+        pci_device::{GfxMode, GfxVendor},
+        tests::test_dir,
+        vfio::{module_use, plan_vfio_unload, ModuleUse},
+    };
+
+    /// A fake `/sys/module`
+    fn add_module(root: &Path, name: &str, refcnt: u32, holders: &[&str]) {
+        let dir = root.join(name);
+        fs::create_dir_all(dir.join("holders")).unwrap();
+        fs::write(dir.join("refcnt"), format!("{refcnt}\n")).unwrap();
+        for holder in holders {
+            fs::create_dir_all(dir.join("holders").join(holder)).unwrap();
+        }
+    }
+
+    /// The vfio modules as loaded by supergfxd for Vfio
+    fn add_ours(root: &Path) {
+        add_module(root, "vfio_pci", 0, &[]);
+        add_module(root, "vfio_pci_core", 1, &["vfio_pci"]);
+        add_module(root, "vfio_iommu_type1", 0, &[]);
+        add_module(
+            root,
+            "vfio",
+            3,
+            &["vfio_iommu_type1", "vfio_pci", "vfio_pci_core"],
+        );
+    }
+
+    #[test]
+    fn reads_module_use() {
+        let root = test_dir("vfio-read");
+        add_module(&root, "vfio", 2, &["vfio_pci_core", "kvm"]);
+        assert_eq!(
+            module_use(&root, "vfio"),
+            Some(ModuleUse {
+                refcnt: Some(2),
+                holders: vec!["kvm".to_string(), "vfio_pci_core".to_string()],
+            })
+        );
+        assert_eq!(module_use(&root, "vfio_pci"), None);
+        // Builtin modules have a directory but no refcnt
+        fs::create_dir_all(root.join("vfio_virqfd")).unwrap();
+        assert_eq!(module_use(&root, "vfio_virqfd"), Some(ModuleUse::default()));
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn unloads_only_ours() {
+        let root = test_dir("vfio-ours");
+        add_ours(&root);
+        let (unload, keep) = plan_vfio_unload(&root);
+        assert_eq!(
+            unload,
+            ["vfio_pci", "vfio_pci_core", "vfio_iommu_type1", "vfio"]
+        );
+        assert!(keep.is_empty());
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn keeps_modules_used_by_mdev() {
+        let root = test_dir("vfio-mdev");
+        add_ours(&root);
+        // An iGPU SR-IOV or GVT-g device holds the vfio core
+        add_module(
+            &root,
+            "vfio",
+            4,
+            &["kvmgt", "vfio_iommu_type1", "vfio_pci_core"],
+        );
+        add_module(&root, "vfio_mdev", 1, &["kvmgt"]);
+        let (unload, keep) = plan_vfio_unload(&root);
+        assert_eq!(unload, ["vfio_pci", "vfio_pci_core", "vfio_iommu_type1"]);
+        assert_eq!(
+            keep,
+            [
+                ("vfio_mdev", "in use by kvmgt".to_string()),
+                ("vfio", "in use by kvmgt".to_string()),
+            ]
+        );
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn keeps_modules_with_open_devices() {
+        let root = test_dir("vfio-open");
+        add_ours(&root);
+        // A VM has a vfio-pci device open
+        add_module(&root, "vfio_pci", 1, &[]);
+        let (unload, keep) = plan_vfio_unload(&root);
+        assert_eq!(unload, ["vfio_iommu_type1"]);
+        assert_eq!(
+            keep,
+            [
+                ("vfio_pci", "1 references not held by a module".to_string()),
+                ("vfio_pci_core", "in use by vfio_pci".to_string()),
+                ("vfio", "in use by vfio_pci, vfio_pci_core".to_string()),
+            ]
+        );
+        fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn keep_loaded_only_releases_devices() {
+        let mut config = GfxConfig::new(Default::default());
+        let leaves_vfio = |config: &GfxConfig, to| match StagedAction::action_list_for_switch(
+            config,
+            GfxVendor::Nvidia,
+            GfxMode::Vfio,
+            to,
+        ) {
+            Action::UserAction(_) => panic!("Should be a list of actions"),
+            Action::StagedActions(actions) => actions
+                .into_iter()
+                .filter(|a| {
+                    matches!(
+                        a,
+                        StagedAction::UnloadVfioDrivers | StagedAction::ReleaseVfioDevices
+                    )
+                })
+                .collect::<Vec<_>>(),
+        };
+        for to in [GfxMode::Hybrid, GfxMode::Integrated] {
+            assert_eq!(leaves_vfio(&config, to), [StagedAction::UnloadVfioDrivers]);
+        }
+        config.vfio_keep_loaded = true;
+        for to in [GfxMode::Hybrid, GfxMode::Integrated] {
+            assert_eq!(leaves_vfio(&config, to), [StagedAction::ReleaseVfioDevices]);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use futures_util::lock::Mutex;
+    use zbus::zvariant::{OwnedValue, Value};
+
+    use crate::{
+        config::GfxConfig,
+        controller::{CtrlGraphics, DebugRun},
+        error::GfxError,
+        pci_device::{DiscreetGpu, GfxMode, GfxPower, GfxVendor},
+        zbus_iface::{fdo_error, INTROSPECTION_XML_FILE},
+        VERSION,
+    };
+
+    fn mock_controller() -> CtrlGraphics {
+        CtrlGraphics::from_dgpu(
+            Arc::new(Mutex::new(GfxConfig::new(Default::default()))),
+            DiscreetGpu::mock(GfxVendor::Nvidia),
+        )
+    }
+
+    /// Fails if the interface has changed without the shipped XML being updated. Run with
+    /// `UPDATE_INTROSPECTION=1` to regenerate it.
+    #[test]
+    fn introspection_xml_up_to_date() {
+        let xml = mock_controller().introspection_xml();
+        assert!(xml.contains(r#"<interface name="org.supergfxctl.Daemon">"#));
+
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(INTROSPECTION_XML_FILE);
+        if std::env::var_os("UPDATE_INTROSPECTION").is_some() {
+            fs::write(&path, &xml).unwrap();
+        }
+        let shipped = fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            shipped == xml,
+            "{INTROSPECTION_XML_FILE} is out of date, run the tests with UPDATE_INTROSPECTION=1 and commit the result"
+        );
+    }
+
+    #[tokio::test]
+    async fn capabilities_hash_is_stable() {
+        let a = mock_controller().get_capabilities().await;
+        let b = mock_controller().get_capabilities().await;
+        assert_eq!(a, b);
+        assert_eq!(a.interface_hash.len(), 16);
+    }
+
+    #[tokio::test]
+    async fn capabilities_mark_debug_run() {
+        let normal = mock_controller().get_capabilities().await;
+        assert!(!normal.debug);
+        assert_eq!(normal.version, VERSION);
+
+        let mut ctrl = mock_controller();
+        ctrl.set_debug_run(DebugRun::default());
+        let debug = ctrl.get_capabilities().await;
+        assert!(debug.debug);
+        assert_eq!(debug.version, format!("{VERSION}-debug"));
+        assert_eq!(debug.interface_hash, normal.interface_hash);
+    }
+
+    #[test]
+    fn not_saved_is_a_distinct_error() {
+        let err = fdo_error(GfxError::ConfigNotPersisted("Write x: denied".to_string()));
+        assert!(matches!(err, zbus::fdo::Error::IOError(_)), "{err:?}");
+        assert!(matches!(
+            fdo_error(GfxError::ModeLocked(GfxMode::Hybrid)),
+            zbus::fdo::Error::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn power_status_property() {
+        for power in [
+            GfxPower::Active,
+            GfxPower::AsusMuxDiscreet,
+            GfxPower::Unknown,
+        ] {
+            let value = OwnedValue::try_from(Value::from(power)).unwrap();
+            assert_eq!(GfxPower::try_from(value).unwrap(), power);
+        }
+        assert!(GfxPower::try_from(OwnedValue::from(6u32)).is_err());
+
+        let ctrl = mock_controller();
+        assert_eq!(
+            ctrl.status_cache.lock().await.hardware.reported_power(),
+            GfxPower::Unknown
+        );
+        ctrl.status_cache.lock().await.hardware.power = GfxPower::Suspended;
+        assert_eq!(
+            ctrl.status_cache.lock().await.hardware.reported_power(),
+            GfxPower::Suspended
+        );
+        // As `Power` reports it
+        ctrl.status_cache.lock().await.hardware.mux_discreet = true;
+        assert_eq!(
+            ctrl.status_cache.lock().await.hardware.reported_power(),
+            GfxPower::AsusMuxDiscreet
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    fs,
+    ops::RangeInclusive,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use zbus::{object_server::SignalEmitter, zvariant::Type};
+
+use crate::{
+    ac_automation::{ModeSuggestion, PowerSource},
+    buffers::BoundedQueue,
+    pci_device::{DiscreetGpu, GfxMode},
+    signal_counters::{emit_counted, Signal},
+};
+
+/// How often the dGPU temperature is read while it is watched
+pub(crate) const THERMAL_SAMPLE: Duration = Duration::from_secs(60);
+/// How far the average must fall below the threshold before an advisory can be given again
+pub(crate) const THERMAL_RESET_HYSTERESIS_C: u32 = 5;
+/// The bounds of `average_window_s`
+pub(crate) const AVERAGE_WINDOW_S: RangeInclusive<u64> = 60..=3600;
+/// The most samples kept, enough to fill the longest window
+pub(crate) const MAX_THERMAL_SAMPLES: usize =
+    (*AVERAGE_WINDOW_S.end() / THERMAL_SAMPLE.as_secs()) as usize + 1;
+
+/// Suggest leaving AsusMuxDgpu when the dGPU runs hot on battery for a long time
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ThermalAdvisory {
+    /// Average temperature in °C above which the dGPU is hot. `0` to not watch it.
+    pub threshold_c: u32,
+    /// Seconds the average must stay above `threshold_c` on battery before the advisory
+    pub sustained_s: u64,
+    /// Seconds of samples averaged
+    pub average_window_s: u64,
+}
+
+impl ThermalAdvisory {
+    /// How far back samples are averaged, `average_window_s` kept within its bounds
+    pub(crate) fn average_window(&self) -> Duration {
+        Duration::from_secs(
+            self.average_window_s
+                .clamp(*AVERAGE_WINDOW_S.start(), *AVERAGE_WINDOW_S.end()),
+        )
+    }
+}
+
+impl Default for ThermalAdvisory {
+    fn default() -> Self {
+        Self {
+            threshold_c: 85,
+            sustained_s: 900,
+            average_window_s: 300,
+        }
+    }
+}
+
+/// The dGPU temperature as watched for the advisory, in `GfxStatus`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct ThermalState {
+    /// The temperature is being sampled, only in AsusMuxDgpu
+    pub sampling: bool,
+    /// The rolling average in °C, `0` if there are no samples
+    pub average_c: u32,
+    /// The advisory was given and its conditions haven't reset since
+    pub advising: bool,
+}
+
+/// Reads the dGPU temperature, so the watch can be tested
+pub(crate) trait TempSource {
+    /// The temperature in millidegrees Celsius, `None` if it can't be read
+    fn read_millic(&mut self) -> Option<i32>;
+}
+
+/// The hwmon `temp1_input` of the dGPU on the running system
+pub(crate) struct SystemTempSource<'a> {
+    pub dgpu: &'a DiscreetGpu,
+}
+
+impl TempSource for SystemTempSource<'_> {
+    fn read_millic(&mut self) -> Option<i32> {
+        self.dgpu
+            .devices()
+            .iter()
+            .filter(|dev| dev.is_dgpu())
+            .find_map(|dev| hwmon_temp_in(dev.dev_path()))
+    }
+}
+
+/// The first readable `hwmon/hwmon*/temp1_input` under the PCI device at `dev_path`
+pub(crate) fn hwmon_temp_in(dev_path: &Path) -> Option<i32> {
+    let mut dirs: Vec<_> = fs::read_dir(dev_path.join("hwmon"))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    dirs.sort();
+    dirs.iter().find_map(|dir| {
+        fs::read_to_string(dir.join("temp1_input"))
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+    })
+}
+
+/// The temperature in `mode` is watched. In AsusMuxDgpu the dGPU drives the panel and is
+/// always awake, so reading it wakes nothing.
+pub(crate) fn thermal_watched(mode: GfxMode) -> bool {
+    mode == GfxMode::AsusMuxDgpu
+}
+
+/// Samples the dGPU temperature every `THERMAL_SAMPLE` while in a watched mode, and gives
+/// the advisory once the rolling average has been over the threshold for `sustained_s` on
+/// battery. It is given once, then again only after leaving the mode, plugging in AC or the
+/// average falling `THERMAL_RESET_HYSTERESIS_C` under the threshold.
+#[derive(Debug)]
+pub(crate) struct ThermalWatch {
+    config: ThermalAdvisory,
+    /// Millidegrees, oldest first
+    samples: BoundedQueue<(Instant, i32)>,
+    last_sample: Option<Instant>,
+    hot_since: Option<Instant>,
+    advised: bool,
+}
+
+impl ThermalWatch {
+    pub(crate) fn new(config: ThermalAdvisory) -> Self {
+        Self {
+            config,
+            samples: BoundedQueue::new("thermal_samples", MAX_THERMAL_SAMPLES),
+            last_sample: None,
+            hot_since: None,
+            advised: false,
+        }
+    }
+
+    pub(crate) fn set_config(&mut self, config: ThermalAdvisory) {
+        self.config = config;
+    }
+
+    pub(crate) fn state(&self) -> ThermalState {
+        ThermalState {
+            sampling: self.last_sample.is_some(),
+            average_c: self.average_c().unwrap_or(0),
+            advising: self.advised,
+        }
+    }
+
+    fn average_c(&self) -> Option<u32> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let sum: i64 = self.samples.iter().map(|(_, t)| *t as i64).sum();
+        let average = sum / self.samples.len() as i64;
+        Some((average.max(0) / 1000) as u32)
+    }
+
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.last_sample = None;
+        self.hot_since = None;
+        self.advised = false;
+    }
+
+    /// Record the state at `now`. `watched` is whether the mode is one where the temperature
+    /// is watched and the dGPU is awake. Returns the average in °C when the advisory should
+    /// be given.
+    pub(crate) fn observe(
+        &mut self,
+        now: Instant,
+        watched: bool,
+        on_battery: bool,
+        source: &mut dyn TempSource,
+    ) -> Option<u32> {
+        if !watched || self.config.threshold_c == 0 {
+            if self.last_sample.is_some() {
+                self.reset();
+            }
+            return None;
+        }
+        if self
+            .last_sample
+            .map_or(false, |last| now.duration_since(last) < THERMAL_SAMPLE)
+        {
+            return None;
+        }
+        self.last_sample = Some(now);
+        if let Some(temp) = source.read_millic() {
+            self.samples.push((now, temp));
+        }
+        let window = self.config.average_window();
+        while self
+            .samples
+            .front()
+            .map_or(false, |(at, _)| now.duration_since(*at) > window)
+        {
+            self.samples.pop_front();
+        }
+
+        let average = self.average_c()?;
+        if !on_battery {
+            self.hot_since = None;
+            self.advised = false;
+            return None;
+        }
+        if average >= self.config.threshold_c {
+            let since = *self.hot_since.get_or_insert(now);
+            if !self.advised
+                && now.duration_since(since) >= Duration::from_secs(self.config.sustained_s)
+            {
+                self.advised = true;
+                info!(
+                    "Thermal: the dGPU has averaged {average}°C for {}s on battery",
+                    now.duration_since(since).as_secs()
+                );
+                return Some(average);
+            }
+        } else {
+            self.hot_since = None;
+            if average + THERMAL_RESET_HYSTERESIS_C <= self.config.threshold_c {
+                self.advised = false;
+            }
+        }
+        None
+    }
+}
+
+/// Suggest switching back to Hybrid with `NotifySuggestion`, the dGPU having averaged
+/// `average_c` on battery
+pub(crate) async fn notify_thermal_advice(ctxt: &SignalEmitter<'_>, average_c: u32) {
+    let suggestion = ModeSuggestion {
+        mode: GfxMode::Hybrid,
+        power: PowerSource::Battery,
+        applying: false,
+        reason: format!(
+            "The dGPU has been running at {average_c}°C on battery in {}, switching to {} lets it power down",
+            GfxMode::AsusMuxDgpu,
+            GfxMode::Hybrid
+        ),
+        blockers: Vec::new(),
+    };
+    emit_counted!(ctxt, Signal::Suggestion, notify_suggestion(&suggestion))
+        .await
+        .unwrap_or_else(|err| warn!("Thermal: {err}"));
+}
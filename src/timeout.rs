@@ -0,0 +1,29 @@
+use std::{sync::mpsc, thread, time::Duration};
+
+/// Run `query` on its own thread, giving up after `timeout`. A query which times out is
+/// left to finish on its own.
+pub fn with_timeout<T, F>(timeout: Duration, query: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Option<T> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        tx.send(query()).ok();
+    });
+    rx.recv_timeout(timeout).ok().flatten()
+}
+
+/// As `with_timeout` for async code: `query` runs on the blocking pool of the runtime, so
+/// the task awaiting it doesn't hold up the others while it waits
+pub async fn blocking_with_timeout<T, F>(timeout: Duration, query: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Option<T> + Send + 'static,
+{
+    tokio::time::timeout(timeout, tokio::task::spawn_blocking(query))
+        .await
+        .ok()?
+        .ok()
+        .flatten()
+}
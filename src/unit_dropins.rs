@@ -0,0 +1,162 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    error::GfxError,
+    systemd::{systemd_daemon_reload, SYSTEMD_UNIT_DIRS},
+};
+
+/// Where the ordering drop-in for `supergfxd.service` is written, named to come before most
+/// local drop-ins
+pub const DROPIN_PATH: &str = "/etc/systemd/system/supergfxd.service.d/50-supergfxd-ordering.conf";
+/// The first line of the drop-in. A file without it wasn't written by supergfxctl and is
+/// never replaced or removed.
+pub const DROPIN_HEADER: &str = "# Automatically generated by supergfxctl --generate-dropins";
+
+/// One line of the compatibility table, the ordering of `supergfxd.service` against `unit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnitOrdering {
+    pub unit: &'static str,
+    /// `After` or `Before`
+    pub relation: &'static str,
+    /// Why, written above the line in the drop-in
+    pub why: &'static str,
+}
+
+/// The units supergfxd is known to race with at boot, and how it is ordered against each.
+/// Only those installed go in the drop-in.
+pub const ORDERING_TABLE: &[UnitOrdering] = &[
+    UnitOrdering {
+        unit: "display-manager.service",
+        relation: "Before",
+        why: "the mode is set before a graphical session starts on the GPUs",
+    },
+    UnitOrdering {
+        unit: "nvidia-persistenced.service",
+        relation: "Before",
+        why: "the modprobe conf is written and the dGPU set up before the driver is held open",
+    },
+    UnitOrdering {
+        unit: "libvirtd.service",
+        relation: "Before",
+        why: "in Vfio the dGPU is bound to vfio-pci before VMs are autostarted",
+    },
+    UnitOrdering {
+        unit: "asusd.service",
+        relation: "After",
+        why: "the ASUS firmware attributes asusd restores are set before the MUX is read",
+    },
+    UnitOrdering {
+        unit: "nbfc_service.service",
+        relation: "Before",
+        why: "fan control reads the dGPU after it is in its boot mode",
+    },
+];
+
+/// `path` as found under `root`, which is `/` other than in tests
+fn under(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// The units of `ORDERING_TABLE` installed under `root`, in the order of the table
+pub fn installed_units(root: &Path) -> Vec<&'static str> {
+    ORDERING_TABLE
+        .iter()
+        .map(|ordering| ordering.unit)
+        .filter(|unit| {
+            SYSTEMD_UNIT_DIRS
+                .iter()
+                .any(|dir| under(root, Path::new(dir)).join(unit).exists())
+        })
+        .collect()
+}
+
+/// The drop-in ordering `supergfxd.service` against the `installed` units, `None` if none
+/// of them need it
+pub fn generate_unit_dropins(installed: &[&str]) -> Option<String> {
+    let orderings: Vec<&UnitOrdering> = ORDERING_TABLE
+        .iter()
+        .filter(|ordering| installed.contains(&ordering.unit))
+        .collect();
+    if orderings.is_empty() {
+        return None;
+    }
+    let mut dropin =
+        format!("{DROPIN_HEADER}\n# Remove with `supergfxctl --remove-dropins`\n\n[Unit]\n");
+    for ordering in orderings {
+        dropin += &format!(
+            "# {}: {}\n{}={}\n",
+            ordering.unit, ordering.why, ordering.relation, ordering.unit
+        );
+    }
+    Some(dropin)
+}
+
+/// Where the drop-in is under `root`
+pub fn dropin_path(root: &Path) -> PathBuf {
+    under(root, Path::new(DROPIN_PATH))
+}
+
+/// Has systemd re-read the units, so it can be tested without systemctl
+pub trait UnitReload {
+    fn daemon_reload(&self) -> Result<(), GfxError>;
+}
+
+/// Fails if there is a file at `path` which wasn't written by supergfxctl
+fn check_owned(path: &Path) -> Result<bool, GfxError> {
+    match fs::read_to_string(path) {
+        Ok(content) if content.starts_with(DROPIN_HEADER) => Ok(true),
+        Ok(_) => Err(GfxError::UnitDropin(format!(
+            "{} wasn't written by supergfxctl, it is left as it is",
+            path.display()
+        ))),
+        Err(_) if !path.exists() => Ok(false),
+        Err(err) => Err(GfxError::from_io(err, path.into())),
+    }
+}
+
+/// Write `dropin` under `root` and reload systemd. Returns where it was written.
+pub fn apply_dropin(
+    root: &Path,
+    dropin: &str,
+    reload: &dyn UnitReload,
+) -> Result<PathBuf, GfxError> {
+    let path = dropin_path(root);
+    check_owned(&path)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| GfxError::from_io(err, dir.into()))?;
+    }
+    fs::write(&path, dropin).map_err(|err| GfxError::from_io(err, path.clone()))?;
+    info!("apply_dropin: wrote {}", path.display());
+    reload.daemon_reload()?;
+    Ok(path)
+}
+
+/// Remove the drop-in under `root` and reload systemd. `false` if there was none.
+pub fn remove_dropin(root: &Path, reload: &dyn UnitReload) -> Result<bool, GfxError> {
+    let path = dropin_path(root);
+    if !check_owned(&path)? {
+        return Ok(false);
+    }
+    fs::remove_file(&path).map_err(|err| GfxError::from_io(err, path.clone()))?;
+    // Left if anything else is in it
+    if let Some(dir) = path.parent() {
+        fs::remove_dir(dir).ok();
+    }
+    info!("remove_dropin: removed {}", path.display());
+    reload.daemon_reload()?;
+    Ok(true)
+}
+
+/// `UnitReload` with `systemctl`
+pub struct SystemctlReload;
+
+impl UnitReload for SystemctlReload {
+    fn daemon_reload(&self) -> Result<(), GfxError> {
+        systemd_daemon_reload()
+    }
+}
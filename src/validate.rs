@@ -0,0 +1,208 @@
+use std::{fmt, path::Path};
+
+use crate::error::GfxError;
+
+/// The longest unit name systemd accepts
+const UNIT_NAME_MAX: usize = 255;
+const IDENTIFIER_MAX: usize = 64;
+const PATH_MAX: usize = 4096;
+
+/// The kinds of string which come from clients or the config, each with its own rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputClass {
+    /// A systemd service or target passed to `systemctl`
+    UnitName,
+    /// A full PCI address as in sysfs, such as `0000:01:00.0`
+    PciAddress,
+    /// An entry of `ignored_functions`: a full or short PCI address, or a function such
+    /// as `.2`
+    PciFunction,
+    /// A PCI or USB vendor and device id, such as `10de:2520`
+    PciId,
+    /// A name such as an attention code or profile, letters, digits, `_` and `-`
+    Identifier,
+    /// Free text such as the reason of an inhibition, on one line
+    Text,
+    /// An absolute path
+    Path,
+}
+
+impl fmt::Display for InputClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            InputClass::UnitName => "unit name",
+            InputClass::PciAddress => "PCI address",
+            InputClass::PciFunction => "PCI function",
+            InputClass::PciId => "PCI id",
+            InputClass::Identifier => "identifier",
+            InputClass::Text => "text",
+            InputClass::Path => "path",
+        };
+        write!(f, "{name}")
+    }
+}
+
+fn invalid(class: InputClass, value: &str, why: &str) -> GfxError {
+    GfxError::InvalidInput(class, format!("{value:?} {why}"))
+}
+
+/// What every class has in common: something is given, it isn't longer than `max` bytes,
+/// and there are no control characters, such as a newline which would start another line
+/// of a generated file
+fn check_common(class: InputClass, value: &str, max: usize) -> Result<(), GfxError> {
+    if value.is_empty() {
+        return Err(invalid(class, value, "is empty"));
+    }
+    if value.len() > max {
+        return Err(invalid(
+            class,
+            value,
+            &format!("is longer than {max} bytes"),
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(invalid(class, value, "has a control character"));
+    }
+    Ok(())
+}
+
+/// Every character of `value` is one `allowed` accepts
+fn check_chars(
+    class: InputClass,
+    value: &str,
+    allowed: impl Fn(char) -> bool,
+) -> Result<(), GfxError> {
+    match value.chars().find(|c| !allowed(*c)) {
+        Some(c) => Err(invalid(class, value, &format!("has {c:?}"))),
+        None => Ok(()),
+    }
+}
+
+/// `value` is exactly `len` hex digits, without the sign `from_str_radix` accepts
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A plain `.service` or `.target` unit name, which can't be read as an option of
+/// `systemctl`
+pub(crate) fn unit_name(unit: &str) -> Result<(), GfxError> {
+    let class = InputClass::UnitName;
+    check_common(class, unit, UNIT_NAME_MAX)?;
+    let (name, suffix) = unit.rsplit_once('.').unwrap_or((unit, ""));
+    if !matches!(suffix, "service" | "target") {
+        return Err(invalid(class, unit, "is not a service or target unit name"));
+    }
+    if name.is_empty() || name.starts_with('-') {
+        return Err(invalid(class, unit, "is not a service or target unit name"));
+    }
+    check_chars(class, name, |c| {
+        c.is_ascii_alphanumeric() || ":-_.@\\".contains(c)
+    })
+}
+
+/// A full PCI address, `domain:bus:slot.function` with 4, 2, 2 and 1 hex digits
+pub(crate) fn pci_address(name: &str) -> Result<(), GfxError> {
+    let class = InputClass::PciAddress;
+    check_common(class, name, 12)?;
+    if !full_pci_address(name) {
+        return Err(invalid(
+            class,
+            name,
+            "is not a PCI address such as 0000:01:00.0",
+        ));
+    }
+    Ok(())
+}
+
+fn full_pci_address(name: &str) -> bool {
+    match name.split_once(':') {
+        Some((domain, rest)) => is_hex(domain, 4) && short_pci_address(rest),
+        None => false,
+    }
+}
+
+/// `bus:slot.function`, with a slot up to 1f and a function up to 7
+fn short_pci_address(name: &str) -> bool {
+    let (bus, rest) = match name.split_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let (slot, function) = match rest.split_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    is_hex(bus, 2)
+        && is_hex(slot, 2)
+        && u8::from_str_radix(slot, 16).map_or(false, |slot| slot <= 0x1f)
+        && pci_function_number(function)
+}
+
+fn pci_function_number(function: &str) -> bool {
+    function.len() == 1 && matches!(function.as_bytes()[0], b'0'..=b'7')
+}
+
+/// An entry of `ignored_functions`: `0000:01:00.2`, `01:00.2` or `.2`
+pub(crate) fn pci_function(entry: &str) -> Result<(), GfxError> {
+    let class = InputClass::PciFunction;
+    check_common(class, entry, 12)?;
+    let valid = match entry.strip_prefix('.') {
+        Some(function) => pci_function_number(function),
+        None => full_pci_address(entry) || short_pci_address(entry),
+    };
+    if !valid {
+        return Err(invalid(
+            class,
+            entry,
+            "is not a PCI address such as 0000:01:00.2 or a function such as .2",
+        ));
+    }
+    Ok(())
+}
+
+/// `vendor:device` with 4 hex digits each
+pub(crate) fn pci_id(id: &str) -> Result<(), GfxError> {
+    let class = InputClass::PciId;
+    check_common(class, id, 9)?;
+    match id.split_once(':') {
+        Some((vendor, device)) if is_hex(vendor, 4) && is_hex(device, 4) => Ok(()),
+        _ => Err(invalid(class, id, "is not an id such as 10de:2520")),
+    }
+}
+
+/// A name such as an attention code, letters, digits, `_` and `-` not starting with `-`
+pub(crate) fn identifier(id: &str) -> Result<(), GfxError> {
+    let class = InputClass::Identifier;
+    check_common(class, id, IDENTIFIER_MAX)?;
+    if id.starts_with('-') {
+        return Err(invalid(class, id, "starts with '-'"));
+    }
+    check_chars(class, id, |c| {
+        c.is_ascii_alphanumeric() || c == '_' || c == '-'
+    })
+}
+
+/// Free text of at most `max` bytes on one line, which isn't only whitespace
+pub(crate) fn text(value: &str, max: usize) -> Result<(), GfxError> {
+    let class = InputClass::Text;
+    check_common(class, value, max)?;
+    if value.trim().is_empty() {
+        return Err(invalid(class, value, "is empty"));
+    }
+    Ok(())
+}
+
+/// An absolute path without `..`, so it can't climb out of the directory it names
+pub(crate) fn absolute_path(path: &str) -> Result<(), GfxError> {
+    let class = InputClass::Path;
+    check_common(class, path, PATH_MAX)?;
+    if !path.starts_with('/') {
+        return Err(invalid(class, path, "is not absolute"));
+    }
+    if Path::new(path)
+        .components()
+        .any(|part| part == std::path::Component::ParentDir)
+    {
+        return Err(invalid(class, path, "has '..'"));
+    }
+    Ok(())
+}
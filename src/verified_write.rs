@@ -0,0 +1,67 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::GfxError, sandbox::note_write};
+
+/// The file `write_verified` writes first. It is in the same directory as `path` so the
+/// rename over `path` stays on one filesystem, and doesn't end in `.conf` so modprobe never
+/// reads it.
+pub(crate) fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Write `content` to `path` so that `path` is either all of it or left as it was. It is
+/// written to `temp_path_for(path)` and synced, read back and compared, then renamed over
+/// `path`. A write cut short without an error, as on a full filesystem, is
+/// `GfxError::VerifyFailed` and `path` is not touched.
+pub(crate) fn write_verified(path: &Path, content: &[u8]) -> Result<(), GfxError> {
+    write_verified_with(path, content, write_synced)
+}
+
+/// As `write_verified`, writing the temporary file with `write`
+pub(crate) fn write_verified_with(
+    path: &Path,
+    content: &[u8],
+    write: impl FnOnce(&Path, &[u8]) -> Result<(), GfxError>,
+) -> Result<(), GfxError> {
+    note_write(path);
+    let tmp = temp_path_for(path);
+    let res = write(&tmp, content)
+        .and_then(|_| {
+            let written =
+                fs::read(&tmp).map_err(|err| GfxError::Read(tmp.display().to_string(), err))?;
+            if written == content {
+                Ok(())
+            } else {
+                Err(GfxError::VerifyFailed(path.display().to_string()))
+            }
+        })
+        .and_then(|_| {
+            fs::rename(&tmp, path).map_err(|err| GfxError::Write(path.display().to_string(), err))
+        });
+    if res.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    res?;
+    // So the rename itself outlasts a power cut
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|err| GfxError::from_io(err, dir.to_path_buf()))?;
+    }
+    Ok(())
+}
+
+/// Create or truncate `path`, write `content` and sync it
+fn write_synced(path: &Path, content: &[u8]) -> Result<(), GfxError> {
+    let mut file =
+        File::create(path).map_err(|err| GfxError::Path(path.display().to_string(), err))?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .map_err(|err| GfxError::Write(path.display().to_string(), err))
+}
@@ -0,0 +1,574 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use futures_util::lock::Mutex;
+use log::{debug, info, warn};
+use tokio::time::sleep;
+use zbus::object_server::SignalEmitter;
+
+use crate::{
+    actions::StagedAction,
+    audit::{Actor, AuditLog},
+    automation_inhibit::{skip_if_inhibited, InhibitRegistry},
+    config::{modprobe_conf, write_modprobe_conf_to, GfxConfig},
+    controller::{CtrlGraphics, SwitchState},
+    initramfs::{modprobe_conf_written, refresh_advisory, InitramfsWatch},
+    nvidia_module_loaded,
+    nvidia_nodes::{
+        check_nvidia_nodes, fix_nvidia_nodes, nvidia_uvm_loaded, NodeFinding, SystemNodeFixer,
+        DEV_DIR,
+    },
+    pci_device::{DiscreetGpu, GfxMode, GfxVendor, RuntimePowerManagement},
+    signal_counters::{emit_counted, Signal},
+    supervisor::RestartPolicy,
+    switcheroo::{
+        update_switcheroo, wanted_switcheroo, SwitcherooStatus, SwitcherooSystem, SystemSwitcheroo,
+    },
+    systemd::{is_systemd_unit_state, SystemdUnitState},
+    toggle_nvidia_powerd, MODPROBE_PATH,
+};
+
+/// xorg configs, files or directories of `*.conf` files, checked for the nvidia driver
+const XORG_CONF_PATHS: &[&str] = &[
+    "/etc/X11/xorg.conf",
+    "/etc/X11/xorg.conf.d",
+    "/usr/share/X11/xorg.conf.d",
+];
+const NVIDIA_POWERD_UNIT: &str = "nvidia-powerd.service";
+/// Where the nvidia-powerd unit is installed by distros
+const NVIDIA_POWERD_UNIT_PATHS: &[&str] = &[
+    "/usr/lib/systemd/system/nvidia-powerd.service",
+    "/lib/systemd/system/nvidia-powerd.service",
+    "/etc/systemd/system/nvidia-powerd.service",
+];
+/// The longest sleep between looks at the wall clock. The clock tokio sleeps on stops
+/// during suspend, so a verification which fell due while suspended runs this long after
+/// resume at most.
+const VERIFY_CLOCK_CHECK: Duration = Duration::from_secs(60);
+
+/// A way the system no longer matches the mode supergfxd applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DriftFinding {
+    /// `MODPROBE_PATH` is missing or not what supergfxd writes for the mode
+    ModprobeConf,
+    /// Runtime PM of a dGPU function isn't `auto`, such as after a tuning tool set it
+    RuntimePm { function: String, control: String },
+    /// nvidia-powerd is running when the mode stops it, or the reverse
+    NvidiaPowerd { expected_active: bool },
+    /// The switcheroo-control udev rule is missing, stale or left behind
+    SwitcherooRule,
+    /// An xorg config not written by supergfxd uses the nvidia driver while it is unloaded
+    ForeignXorgConf(PathBuf),
+    /// A driver module is loaded in a mode which unloads it
+    UnexpectedModule(String),
+    /// The initramfs has an old copy of the modprobe conf, with the advisory message
+    StaleInitramfs(String),
+    /// A device node of the loaded nvidia driver is missing or users can't open it
+    NvidiaNode(NodeFinding),
+}
+
+/// What the verifier does about a finding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DriftRemedy {
+    /// Put back without asking, it is state supergfxd sets itself and setting it again is
+    /// what a reload would do
+    SelfHeal,
+    /// Only report it, the fix could break something supergfxd doesn't own
+    ReportOnly,
+}
+
+impl DriftFinding {
+    /// The remedy for each kind of finding
+    pub(crate) fn remedy(&self) -> DriftRemedy {
+        match self {
+            Self::ModprobeConf => DriftRemedy::SelfHeal,
+            Self::RuntimePm { .. } => DriftRemedy::SelfHeal,
+            Self::NvidiaPowerd { .. } => DriftRemedy::SelfHeal,
+            Self::SwitcherooRule => DriftRemedy::SelfHeal,
+            Self::ForeignXorgConf(_) => DriftRemedy::ReportOnly,
+            Self::UnexpectedModule(_) => DriftRemedy::ReportOnly,
+            // supergfxd never regenerates the initramfs itself
+            Self::StaleInitramfs(_) => DriftRemedy::ReportOnly,
+            // Unless `fix_nvidia_nodes` is set, see `remedy_with`
+            Self::NvidiaNode(_) => DriftRemedy::ReportOnly,
+        }
+    }
+
+    /// The remedy with `config`, nvidia device nodes are made with `fix_nvidia_nodes`
+    pub(crate) fn remedy_with(&self, config: &GfxConfig) -> DriftRemedy {
+        match self {
+            Self::NvidiaNode(_) if config.fix_nvidia_nodes => DriftRemedy::SelfHeal,
+            _ => self.remedy(),
+        }
+    }
+}
+
+impl fmt::Display for DriftFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ModprobeConf => write!(f, "{MODPROBE_PATH} was changed or removed"),
+            Self::RuntimePm { function, control } => {
+                write!(f, "runtime PM of {function} is {control}, not auto")
+            }
+            Self::NvidiaPowerd {
+                expected_active: true,
+            } => write!(f, "{NVIDIA_POWERD_UNIT} is stopped but should be running"),
+            Self::NvidiaPowerd {
+                expected_active: false,
+            } => write!(f, "{NVIDIA_POWERD_UNIT} is running but should be stopped"),
+            Self::SwitcherooRule => write!(f, "the switcheroo-control udev rule is out of date"),
+            Self::ForeignXorgConf(path) => write!(
+                f,
+                "{} uses the nvidia driver but it is unloaded in this mode",
+                path.display()
+            ),
+            Self::UnexpectedModule(module) => {
+                write!(f, "{module} is loaded but is unloaded in this mode")
+            }
+            Self::StaleInitramfs(advisory) => write!(f, "{advisory}"),
+            Self::NvidiaNode(finding) => write!(f, "{finding}"),
+        }
+    }
+}
+
+/// The system as the mode left it. `None` fields aren't checked.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ExpectedState {
+    pub modprobe_conf: Option<Vec<u8>>,
+    /// The dGPU functions are left with runtime PM `auto`
+    pub runtime_pm_auto: bool,
+    pub powerd_active: Option<bool>,
+    /// The switcheroo-control rule, `Some(None)` for no rule
+    pub switcheroo_rule: Option<Option<String>>,
+    /// The nvidia driver is unloaded in this mode
+    pub nvidia_unloaded: bool,
+    /// The nvidia driver is loaded in this mode for apps to use the dGPU
+    pub nvidia_usable: bool,
+}
+
+impl ExpectedState {
+    /// What the boot actions for `mode` leave, as reload does those. Actions in
+    /// `disabled_actions` are left to the distro and not checked.
+    pub(crate) fn for_mode(
+        config: &GfxConfig,
+        mode: GfxMode,
+        dgpu: &DiscreetGpu,
+        switcheroo: &dyn SwitcherooSystem,
+    ) -> Self {
+        let vendor = dgpu.vendor();
+        let actions = StagedAction::action_list_for_boot(config, vendor, mode);
+        let modprobe_conf = if actions.contains(&StagedAction::WriteModprobeConf) {
+            // An incomplete Vfio conf isn't written so there is nothing to compare with
+            modprobe_conf(mode, dgpu, config.modprobe_extra(mode))
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
+        let powerd_active = if actions.contains(&StagedAction::EnableNvidiaPowerd) {
+            Some(true)
+        } else if actions.contains(&StagedAction::DisableNvidiaPowerd) {
+            Some(false)
+        } else {
+            None
+        };
+        Self {
+            modprobe_conf,
+            runtime_pm_auto: vendor != GfxVendor::AsusDgpuDisabled,
+            powerd_active,
+            switcheroo_rule: Some(
+                wanted_switcheroo(switcheroo, config.manage_switcheroo, mode, dgpu).1,
+            ),
+            nvidia_unloaded: vendor == GfxVendor::Nvidia
+                && actions.contains(&StagedAction::UnloadGpuDrivers)
+                && !actions.contains(&StagedAction::LoadGpuDrivers),
+            nvidia_usable: vendor == GfxVendor::Nvidia
+                && actions.contains(&StagedAction::LoadGpuDrivers),
+        }
+    }
+}
+
+/// The system as it is now
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ObservedState {
+    /// `None` if there is no file
+    pub modprobe_conf: Option<Vec<u8>>,
+    /// `(function, power/control)` of each dGPU function which has one
+    pub runtime_pm: Vec<(String, String)>,
+    /// `None` if nvidia-powerd isn't installed
+    pub powerd_active: Option<bool>,
+    pub switcheroo_rule: Option<String>,
+    /// xorg configs using the nvidia driver
+    pub xorg_nvidia_confs: Vec<PathBuf>,
+    pub nvidia_loaded: bool,
+    /// The nvidia device nodes which are missing or can't be opened, while it is loaded
+    pub nvidia_nodes: Vec<NodeFinding>,
+    /// The initramfs advisory if it hasn't been resolved or dismissed
+    pub initramfs_advisory: Option<String>,
+}
+
+impl ObservedState {
+    /// Read the state of the running system
+    pub(crate) fn read(dgpu: &DiscreetGpu, switcheroo: &dyn SwitcherooSystem) -> Self {
+        let runtime_pm = dgpu
+            .managed_devices()
+            .filter_map(|dev| {
+                fs::read_to_string(dev.dev_path().join("power").join("control"))
+                    .ok()
+                    .map(|control| (dev.name().to_string(), control.trim().to_string()))
+            })
+            .collect();
+        let powerd_installed = NVIDIA_POWERD_UNIT_PATHS
+            .iter()
+            .any(|path| Path::new(path).exists());
+        let powerd_active = if powerd_installed {
+            is_systemd_unit_active(NVIDIA_POWERD_UNIT)
+        } else {
+            None
+        };
+        let xorg_paths: Vec<&Path> = XORG_CONF_PATHS.iter().map(Path::new).collect();
+        let nvidia_loaded = nvidia_module_loaded();
+        let nvidia_nodes = if nvidia_loaded {
+            check_nvidia_nodes(Path::new(DEV_DIR), nvidia_uvm_loaded())
+        } else {
+            Vec::new()
+        };
+        Self {
+            modprobe_conf: fs::read(MODPROBE_PATH).ok(),
+            runtime_pm,
+            powerd_active,
+            switcheroo_rule: switcheroo.current_rule(),
+            xorg_nvidia_confs: xorg_nvidia_confs_in(&xorg_paths),
+            nvidia_loaded,
+            nvidia_nodes,
+            // Held by the controller, see `PeriodicVerify::verify`
+            initramfs_advisory: None,
+        }
+    }
+}
+
+/// `None` if the state couldn't be read
+fn is_systemd_unit_active(unit: &str) -> Option<bool> {
+    is_systemd_unit_state(SystemdUnitState::Active, unit)
+        .map_err(|err| debug!("verify: {err}"))
+        .ok()
+}
+
+/// The xorg configs in `paths` with a `Driver "nvidia"` line. A path may be a file or a
+/// directory of `*.conf` files.
+pub(crate) fn xorg_nvidia_confs_in(paths: &[&Path]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            if let Ok(entries) = fs::read_dir(path) {
+                files.extend(
+                    entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| e.path())
+                        .filter(|p| p.extension().map_or(false, |ext| ext == "conf")),
+                );
+            }
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        }
+    }
+    let mut confs: Vec<PathBuf> = files
+        .into_iter()
+        .filter(|file| {
+            fs::read_to_string(file).map_or(false, |buf| {
+                buf.lines().any(|line| {
+                    let mut words = line.split_whitespace();
+                    words
+                        .next()
+                        .map_or(false, |word| word.eq_ignore_ascii_case("driver"))
+                        && words.next() == Some("\"nvidia\"")
+                })
+            })
+        })
+        .collect();
+    confs.sort();
+    confs
+}
+
+/// The differences between `expected` and `observed`
+pub(crate) fn find_drift(expected: &ExpectedState, observed: &ObservedState) -> Vec<DriftFinding> {
+    let mut findings = Vec::new();
+    if let Some(conf) = &expected.modprobe_conf {
+        // An empty conf and no conf are the same to modprobe
+        if observed.modprobe_conf.as_deref().unwrap_or_default() != conf.as_slice() {
+            findings.push(DriftFinding::ModprobeConf);
+        }
+    }
+    if expected.runtime_pm_auto {
+        for (function, control) in &observed.runtime_pm {
+            if control != <&str>::from(RuntimePowerManagement::Auto) {
+                findings.push(DriftFinding::RuntimePm {
+                    function: function.clone(),
+                    control: control.clone(),
+                });
+            }
+        }
+    }
+    if let (Some(expected_active), Some(active)) = (expected.powerd_active, observed.powerd_active)
+    {
+        if expected_active != active {
+            findings.push(DriftFinding::NvidiaPowerd { expected_active });
+        }
+    }
+    if let Some(rule) = &expected.switcheroo_rule {
+        if *rule != observed.switcheroo_rule {
+            findings.push(DriftFinding::SwitcherooRule);
+        }
+    }
+    if expected.nvidia_unloaded {
+        if observed.nvidia_loaded {
+            findings.push(DriftFinding::UnexpectedModule("nvidia".to_string()));
+        }
+        for path in &observed.xorg_nvidia_confs {
+            findings.push(DriftFinding::ForeignXorgConf(path.clone()));
+        }
+    }
+    if expected.nvidia_usable && observed.nvidia_loaded {
+        for node in &observed.nvidia_nodes {
+            findings.push(DriftFinding::NvidiaNode(node.clone()));
+        }
+    }
+    if let Some(advisory) = &observed.initramfs_advisory {
+        findings.push(DriftFinding::StaleInitramfs(advisory.clone()));
+    }
+    findings
+}
+
+/// When the next verification is due, by the wall clock. Waits are kept short and the
+/// deadline recomputed after each, as the monotonic clock doesn't count time suspended.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VerifySchedule {
+    last: SystemTime,
+}
+
+impl VerifySchedule {
+    /// The first verification is one interval after `now`
+    pub(crate) fn new(now: SystemTime) -> Self {
+        Self { last: now }
+    }
+
+    /// How long to sleep before looking again, `None` if a verification is due
+    pub(crate) fn wait(&mut self, now: SystemTime, interval: Duration) -> Option<Duration> {
+        // The clock was set back, count from now rather than waiting out the difference
+        if now < self.last {
+            self.last = now;
+        }
+        let elapsed = now.duration_since(self.last).unwrap_or_default();
+        if elapsed >= interval {
+            return None;
+        }
+        Some((interval - elapsed).min(VERIFY_CLOCK_CHECK))
+    }
+
+    /// A verification ran at `now`
+    pub(crate) fn ran(&mut self, now: SystemTime) {
+        self.last = now;
+    }
+}
+
+/// The state the verifier needs from the controller
+struct PeriodicVerify {
+    dgpu: Arc<Mutex<DiscreetGpu>>,
+    config: Arc<Mutex<GfxConfig>>,
+    audit: Arc<AuditLog>,
+    switcheroo: Arc<Mutex<SwitcherooStatus>>,
+    degraded_hardware: Arc<AtomicBool>,
+    initramfs: Arc<Mutex<InitramfsWatch>>,
+    inhibits: Arc<Mutex<InhibitRegistry>>,
+    ctxt: SignalEmitter<'static>,
+}
+
+impl PeriodicVerify {
+    /// The interval set in `periodic_verify_hours`, `None` if off
+    async fn interval(&self) -> Option<Duration> {
+        self.config
+            .lock()
+            .await
+            .periodic_verify_hours
+            .filter(|hours| *hours > 0)
+            .map(|hours| Duration::from_secs(hours * 3600))
+    }
+
+    /// Verify the applied mode and heal what can be, `false` if it wasn't run as a switch
+    /// is running or waiting for a logout. Skipped until the next interval while automation
+    /// is inhibited.
+    async fn verify(&self) -> bool {
+        let (config, mode) = {
+            let config = self.config.lock().await;
+            if config.switch_state != SwitchState::Idle || config.pending_mode.is_some() {
+                return false;
+            }
+            (config.clone(), config.effective_mode())
+        };
+        if skip_if_inhibited(&self.inhibits, &self.audit, "periodic verify").await {
+            return true;
+        }
+        if self.degraded_hardware.load(Ordering::Acquire) {
+            debug!("verify: the dGPU is gone, skipping");
+            return true;
+        }
+        let dgpu = self.dgpu.lock().await.clone();
+        let expected = ExpectedState::for_mode(&config, mode, &dgpu, &SystemSwitcheroo);
+        let mut observed = ObservedState::read(&dgpu, &SystemSwitcheroo);
+        refresh_advisory(&self.initramfs).await;
+        observed.initramfs_advisory = self
+            .initramfs
+            .lock()
+            .await
+            .advisory()
+            .map(|advisory| advisory.message());
+        let findings = find_drift(&expected, &observed);
+        if findings.is_empty() {
+            info!("verify: {mode} is applied as expected");
+            return true;
+        }
+
+        let mut reported = Vec::new();
+        for finding in findings {
+            if finding.remedy_with(&config) == DriftRemedy::ReportOnly {
+                warn!("verify: {finding}");
+                reported.push(finding.to_string());
+                continue;
+            }
+            match self.heal(&finding, &expected, &config, mode, &dgpu).await {
+                Ok(()) => {
+                    self.audit
+                        .record(&Actor::Daemon, &format!("verify: fixed drift, {finding}"));
+                }
+                Err(err) => {
+                    warn!("verify: could not fix {finding}: {err}");
+                    emit_counted!(
+                        &self.ctxt,
+                        Signal::Error,
+                        notify_error(&format!("Could not fix drift, {finding}: {err}"))
+                    )
+                    .await
+                    .unwrap_or_else(|err| warn!("verify: {err}"));
+                }
+            }
+        }
+        if !reported.is_empty() {
+            emit_counted!(&self.ctxt, Signal::Drift, notify_drift(&reported))
+                .await
+                .unwrap_or_else(|err| warn!("verify: {err}"));
+        }
+        true
+    }
+
+    /// Put back what `finding` found changed
+    async fn heal(
+        &self,
+        finding: &DriftFinding,
+        expected: &ExpectedState,
+        config: &GfxConfig,
+        mode: GfxMode,
+        dgpu: &DiscreetGpu,
+    ) -> Result<(), String> {
+        match finding {
+            DriftFinding::ModprobeConf => {
+                let before = fs::read(MODPROBE_PATH).ok();
+                write_modprobe_conf_to(
+                    Path::new(MODPROBE_PATH),
+                    expected.modprobe_conf.as_deref().unwrap_or_default(),
+                )
+                .map_err(|err| err.to_string())?;
+                modprobe_conf_written(&self.initramfs, before, Some(&self.ctxt)).await;
+                Ok(())
+            }
+            DriftFinding::RuntimePm { .. } => dgpu
+                .set_runtime_pm(RuntimePowerManagement::Auto)
+                .map_err(|err| err.to_string()),
+            DriftFinding::NvidiaPowerd { expected_active } => {
+                toggle_nvidia_powerd(*expected_active, dgpu.vendor()).map_err(|err| err.to_string())
+            }
+            DriftFinding::SwitcherooRule => {
+                let status =
+                    update_switcheroo(&SystemSwitcheroo, config.manage_switcheroo, mode, dgpu);
+                *self.switcheroo.lock().await = status.clone();
+                match status {
+                    SwitcherooStatus::Failed(err) => Err(err),
+                    _ => Ok(()),
+                }
+            }
+            DriftFinding::NvidiaNode(node) => {
+                let left = fix_nvidia_nodes(
+                    &SystemNodeFixer,
+                    Path::new(DEV_DIR),
+                    nvidia_uvm_loaded(),
+                    vec![node.clone()],
+                );
+                match left.into_iter().find(|left| left.node == node.node) {
+                    Some(left) => Err(left.to_string()),
+                    None => Ok(()),
+                }
+            }
+            DriftFinding::ForeignXorgConf(_)
+            | DriftFinding::UnexpectedModule(_)
+            | DriftFinding::StaleInitramfs(_) => Err("only reported".to_string()),
+        }
+    }
+}
+
+async fn run_periodic_verify(verify: Arc<PeriodicVerify>) {
+    let mut schedule = VerifySchedule::new(SystemTime::now());
+    loop {
+        let interval = match verify.interval().await {
+            Some(interval) => interval,
+            None => {
+                // Count from when it is turned on
+                schedule.ran(SystemTime::now());
+                sleep(VERIFY_CLOCK_CHECK).await;
+                continue;
+            }
+        };
+        match schedule.wait(SystemTime::now(), interval) {
+            Some(wait) => sleep(wait).await,
+            None => {
+                if verify.verify().await {
+                    schedule.ran(SystemTime::now());
+                } else {
+                    debug!("verify: a switch is in progress, trying again later");
+                    sleep(VERIFY_CLOCK_CHECK).await;
+                }
+            }
+        }
+    }
+}
+
+impl CtrlGraphics {
+    /// Check every `periodic_verify_hours` that the mode is still applied, fixing what
+    /// supergfxd owns and reporting the rest with `notify_drift`. Not started if there is
+    /// no signal context to notify with.
+    pub fn start_periodic_verify(&self) {
+        let ctxt = match self.signal_ctxt.clone() {
+            Some(ctxt) => ctxt,
+            None => return,
+        };
+        let verify = Arc::new(PeriodicVerify {
+            dgpu: self.dgpu.clone(),
+            config: self.config.clone(),
+            audit: self.audit.clone(),
+            switcheroo: self.switcheroo.clone(),
+            degraded_hardware: self.degraded_hardware.clone(),
+            initramfs: self.initramfs.clone(),
+            inhibits: self.automation_inhibits.clone(),
+            ctxt,
+        });
+        self.tasks
+            .spawn("periodic verify", RestartPolicy::WithBackoff, move || {
+                run_periodic_verify(verify.clone())
+            });
+    }
+}
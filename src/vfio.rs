@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+
+use crate::{
+    do_driver_action,
+    driver_override::DriverOverrides,
+    error::GfxError,
+    pci_device::{DiscreetGpu, GfxMode},
+    sandbox::note_write,
+    DriverAction, VFIO_DRIVERS,
+};
+
+/// A directory for each loaded (or builtin) module
+const SYS_MODULE_PATH: &str = "/sys/module";
+const VFIO_PCI_DRIVER_PATH: &str = "/sys/bus/pci/drivers/vfio-pci";
+const PCI_DRIVERS_PROBE_PATH: &str = "/sys/bus/pci/drivers_probe";
+
+/// The users of a loaded module, from `/sys/module/<name>`
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub(crate) struct ModuleUse {
+    /// `refcnt`, which counts the holders as well as other users such as a VM with a device
+    /// open. `None` for a builtin module.
+    pub refcnt: Option<u32>,
+    /// Modules using this one, from `holders/`
+    pub holders: Vec<String>,
+}
+
+/// How the module `name` is used, `None` if it isn't loaded
+pub(crate) fn module_use(root: &Path, name: &str) -> Option<ModuleUse> {
+    let dir = root.join(name);
+    if !dir.exists() {
+        return None;
+    }
+    let refcnt = fs::read_to_string(dir.join("refcnt"))
+        .ok()
+        .and_then(|s| s.trim().parse().ok());
+    let mut holders: Vec<String> = fs::read_dir(dir.join("holders"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    holders.sort();
+    Some(ModuleUse { refcnt, holders })
+}
+
+/// Decide which of the loaded vfio modules can be unloaded, in unload order, with `root` as
+/// `/sys/module`. A module is left loaded, with the reason, if it is held by a module that
+/// stays loaded (such as `mdev` for an iGPU SR-IOV device) or has users other than its
+/// holders (such as a VM with a device open).
+pub(crate) fn plan_vfio_unload(root: &Path) -> (Vec<&'static str>, Vec<(&'static str, String)>) {
+    let mut unload: Vec<&'static str> = Vec::new();
+    let mut keep = Vec::new();
+    for name in VFIO_DRIVERS {
+        let usage = match module_use(root, name) {
+            Some(usage) => usage,
+            None => continue,
+        };
+        let holders: Vec<&str> = usage
+            .holders
+            .iter()
+            .map(|h| h.as_str())
+            .filter(|h| !unload.contains(h))
+            .collect();
+        let others = usage
+            .refcnt
+            .unwrap_or(0)
+            .saturating_sub(usage.holders.len() as u32);
+        if !holders.is_empty() {
+            keep.push((name, format!("in use by {}", holders.join(", "))));
+        } else if others > 0 {
+            keep.push((name, format!("{others} references not held by a module")));
+        } else {
+            unload.push(name);
+        }
+    }
+    (unload, keep)
+}
+
+/// Unload the vfio modules which nothing else is using, each tried up to `attempts` times
+pub(crate) async fn unload_vfio_modules(attempts: u32) -> Result<(), GfxError> {
+    let (unload, keep) = plan_vfio_unload(Path::new(SYS_MODULE_PATH));
+    for (name, reason) in keep {
+        info!("unload_vfio_modules: leaving {name} loaded, {reason}");
+    }
+    for name in unload {
+        do_driver_action(name, DriverAction::Remove, attempts).await?;
+    }
+    Ok(())
+}
+
+pub(crate) fn vfio_pci_loaded() -> bool {
+    Path::new(SYS_MODULE_PATH).join("vfio_pci").exists()
+}
+
+fn write_attr(path: PathBuf, data: &str) -> Result<(), GfxError> {
+    note_write(&path);
+    fs::write(&path, data).map_err(|err| GfxError::from_io(err, path))
+}
+
+/// The name of the driver bound to the PCI function at `dev_path`
+pub(crate) fn driver_name(dev_path: &Path) -> Option<String> {
+    fs::canonicalize(dev_path.join("driver"))
+        .ok()
+        .and_then(|path| path.file_name().map(|n| n.to_string_lossy().to_string()))
+}
+
+/// Bind the dGPU functions to an already loaded vfio-pci with `driver_override`. The `ids`
+/// option in the modprobe conf only applies when the module is loaded.
+pub(crate) async fn bind_vfio(
+    device: &DiscreetGpu,
+    overrides: &DriverOverrides,
+) -> Result<(), GfxError> {
+    for dev in device.managed_devices() {
+        if driver_name(dev.dev_path()).as_deref() == Some("vfio-pci") {
+            continue;
+        }
+        dev.unbind().await?;
+        overrides.set(dev, "vfio-pci", GfxMode::Vfio)?;
+        write_attr(PathBuf::from(PCI_DRIVERS_PROBE_PATH), dev.name())?;
+        info!("bind_vfio: bound {} to vfio-pci", dev.name());
+    }
+    Ok(())
+}
+
+/// Unbind the dGPU functions from vfio-pci and clear the `driver_override`s set by
+/// `bind_vfio` and the ids added by the modprobe conf, so the GPU driver can claim them. The
+/// vfio modules are left loaded.
+pub(crate) async fn release_vfio(
+    device: &DiscreetGpu,
+    overrides: &DriverOverrides,
+) -> Result<(), GfxError> {
+    overrides.clear(device.devices(), |record| record.driver == "vfio-pci")?;
+    for dev in device.managed_devices() {
+        if driver_name(dev.dev_path()).as_deref() == Some("vfio-pci") {
+            dev.unbind().await?;
+            info!("release_vfio: unbound {} from vfio-pci", dev.name());
+        }
+        // Fails if the id was never added, which is fine
+        let id = dev.pci_id().replace(':', " ");
+        let remove_id = Path::new(VFIO_PCI_DRIVER_PATH).join("remove_id");
+        note_write(&remove_id);
+        if let Err(err) = fs::write(remove_id, &id) {
+            debug!("release_vfio: remove_id {id}: {err}");
+        }
+    }
+    Ok(())
+}
@@ -1,22 +1,319 @@
 use ::zbus::interface;
 use log::{error, info, warn};
-use zbus::{object_server::SignalEmitter, zvariant::ObjectPath};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use zbus::{
+    message::Header,
+    names::BusName,
+    object_server::{Interface, SignalEmitter},
+    zvariant::{ObjectPath, Type},
+    Connection,
+};
 
 use crate::{
-    actions::UserActionRequired,
-    config::GfxConfigDbus,
-    pci_device::{GfxMode, GfxPower},
-    special_asus::{asus_gpu_mux_mode, AsusGpuMuxMode},
-    DBUS_IFACE_PATH, VERSION,
+    ac_automation::ModeSuggestion,
+    actions::{graphical_sessions_active, validate_disabled_actions, UserActionRequired},
+    attention::AttentionItem,
+    audit::{sender_uid, Actor, AuditRecord},
+    buffers::{memory_report, MemoryReport},
+    build_info::BuildInfo,
+    config::validate_display_manager_units,
+    config::{ConfigChange, GfxConfig, GfxConfigDbus},
+    config_preview::ConfigPreview,
+    controller::{
+        GfxState, GfxStatus, OperatingProfile, PendingInfo, PlannedSwitch, SetModeOptions,
+        SupportedModes, SwitchAdvisory, SwitchInitiator, SwitchState,
+    },
+    dock_automation::DockSuggestion,
+    error::GfxError,
+    gpu_users::BlockingProcess,
+    hotplug_check::{check_requested_hotplug_type, SystemHotplugProbe},
+    initramfs::refresh_advisory,
+    logout_switch::session_of_sender,
+    pci_device::{DeviceInfo, GfxMode, GfxPower, ModeInfo},
+    pci_link::LinkInfo,
+    pci_lock::PCI_LOCK_PATH,
+    power_blockers::PowerBlocker,
+    power_semantics::PowerSemantics,
+    schedule::ScheduleSuggestion,
+    self_test::SelfTestReport,
+    signal_counters::{emit_counted, signal_counters, Signal, SignalCounters},
+    signal_filter::{set_signal_filter, signal_filters, SignalFilter},
+    special_asus::{asus_gpu_mux_mode, AsusGpuMuxMode, SafetyCheck, SystemMuxReader},
+    special_vendor::{vendor_mux_on, SpecialToggle},
+    supervisor::TaskInfo,
+    switch_readiness::SwitchReadiness,
+    sysfs::Sysfs,
+    validate, CONFIG_PATH, DBUS_IFACE_PATH, VERSION,
 };
 
 use super::controller::CtrlGraphics;
 
+/// Where the introspection XML is shipped in the source tree, installed to
+/// `/usr/share/dbus-1/interfaces`
+/// The longest reason `InhibitAutomation` takes, it is shown in `Status` and the audit log
+const INHIBIT_REASON_MAX: usize = 256;
+
+pub const INTROSPECTION_XML_FILE: &str = "data/org.supergfxctl.Daemon.xml";
+
+const INTROSPECTION_DOCTYPE: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+"#;
+
+/// What this daemon provides, for clients to check before using newer methods
+#[derive(Debug, Clone, PartialEq, Eq, Type, Deserialize, Serialize)]
+pub struct Capabilities {
+    pub version: String,
+    /// Hash of the introspection XML, changes whenever the interface does
+    pub interface_hash: String,
+    /// The daemon was started with `--debug-run` and is not the system service
+    pub debug: bool,
+    /// Lock file taken with `flock(LOCK_EX)` around PCI remove and rescan, other tools doing
+    /// the same should take it too
+    pub pci_lock_path: String,
+    /// Ids of the vendor toggles found on this machine, e.g `legion_gsync`
+    pub special_toggles: Vec<String>,
+    /// A client has sent a mode value which isn't a mode in this version, likely as it was
+    /// built against the numbering of an older release and needs updating
+    pub legacy_mode_value_seen: bool,
+    /// Experimental features set in the config, e.g `acpi_dgpu_off`
+    pub experimental: Vec<String>,
+    /// The signal categories each client asked for with `SetSignalFilter`
+    pub signal_filters: Vec<SignalFilter>,
+}
+
+/// FNV-1a, used instead of `DefaultHasher` as the hash must be stable between builds
+pub(crate) fn fnv1a_hex(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")
+}
+
+impl CtrlGraphics {
+    /// The introspection XML of the `org.supergfxctl.Daemon` interface. This doesn't need a
+    /// dbus connection, it is the same data served by the object server.
+    pub fn introspection_xml(&self) -> String {
+        let mut xml = String::from(INTROSPECTION_DOCTYPE);
+        xml.push_str("<node>\n");
+        self.introspect_to_writer(&mut xml, 2);
+        xml.push_str("</node>\n");
+        xml
+    }
+
+    pub(crate) async fn get_capabilities(&self) -> Capabilities {
+        let mut experimental = Vec::new();
+        if self.config.lock().await.acpi_dgpu_off.is_some() {
+            experimental.push("acpi_dgpu_off".to_string());
+        }
+        Capabilities {
+            version: self.get_version(),
+            interface_hash: fnv1a_hex(self.introspection_xml().as_bytes()),
+            debug: self.is_debug_run(),
+            pci_lock_path: PCI_LOCK_PATH.to_string(),
+            special_toggles: SpecialToggle::discover()
+                .iter()
+                .map(|t| t.def.id.to_string())
+                .collect(),
+            legacy_mode_value_seen: self.legacy_mode_value_seen.load(Ordering::Acquire),
+            experimental,
+            signal_filters: signal_filters(),
+        }
+    }
+
+    /// Request a switch to `mode` for a dbus caller, telling frontends of it
+    async fn switch_by_user(
+        &mut self,
+        ctxt: &SignalEmitter<'_>,
+        actor: &Actor,
+        mode: GfxMode,
+        options: SetModeOptions,
+    ) -> zbus::fdo::Result<UserActionRequired> {
+        info!("Switching gfx mode to {mode} with {options:?}");
+        // Must be checked before the dGPU is powered down
+        let advisory = self.get_switch_advisory(mode).await;
+        let msg = self
+            .set_gfx_mode_with_options(mode, options, actor)
+            .await
+            .map_err(|err| {
+                error!("{}", err);
+                zbus::fdo::Error::Failed(err.daemon_message())
+            })?;
+        self.user_switches.fetch_add(1, Ordering::AcqRel);
+
+        emit_counted!(ctxt, Signal::Action, notify_action(&msg))
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+        if !advisory.is_empty() {
+            warn!(
+                "Outputs {:?} will stop working in {mode}",
+                advisory.outputs_that_will_turn_off
+            );
+            emit_counted!(
+                ctxt,
+                Signal::SwitchAdvisory,
+                notify_switch_advisory(&advisory)
+            )
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+        }
+
+        emit_counted!(
+            ctxt,
+            Signal::ModeChange,
+            notify_mode_change(&mode, &SwitchInitiator::User)
+        )
+        .await
+        .unwrap_or_else(|err| warn!("{}", err));
+        emit_counted!(ctxt, Signal::Gfx, notify_gfx(&mode))
+            .await
+            .unwrap_or_else(|err| warn!("{}", err));
+
+        Ok(msg)
+    }
+
+    /// Decode a mode received over dbus, flagging a value from an older numbering
+    fn mode_from_wire(&self, value: u32) -> zbus::fdo::Result<GfxMode> {
+        GfxMode::from_wire(value).map_err(|_| {
+            warn!(
+                "Mode value {value} is not a mode in {VERSION}, the client is likely built against an older release and should be updated"
+            );
+            self.legacy_mode_value_seen.store(true, Ordering::Release);
+            zbus::fdo::Error::InvalidArgs(format!(
+                "{value} is not a mode, the modes are numbered as of supergfxctl 5.1"
+            ))
+        })
+    }
+
+    /// The build of the daemon, with the paths in use which differ from the compiled in ones
+    pub(crate) async fn get_build_info(&self) -> BuildInfo {
+        let mut info = BuildInfo::current();
+        info.version = self.get_version();
+        let config_path = self.config.lock().await.config_path.clone();
+        if config_path != CONFIG_PATH {
+            info.overrides.push(("config".to_string(), config_path));
+        }
+        if self.is_debug_run() {
+            info.overrides
+                .push(("bus".to_string(), "session".to_string()));
+        }
+        info
+    }
+
+    /// The daemon version, with a `-debug` suffix for a debug run
+    pub(crate) fn get_version(&self) -> String {
+        if self.is_debug_run() {
+            format!("{VERSION}-debug")
+        } else {
+            VERSION.to_string()
+        }
+    }
+}
+
+/// The fields which differ between two configs, as `name old -> new`
+pub(crate) fn config_changes(old: &GfxConfigDbus, new: &GfxConfigDbus) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut diff = |name: &str, old: String, new: String| {
+        if old != new {
+            changes.push(format!("{name} {old} -> {new}"));
+        }
+    };
+    diff(
+        "vfio_enable",
+        old.vfio_enable.to_string(),
+        new.vfio_enable.to_string(),
+    );
+    diff(
+        "vfio_save",
+        old.vfio_save.to_string(),
+        new.vfio_save.to_string(),
+    );
+    diff(
+        "always_reboot",
+        old.always_reboot.to_string(),
+        new.always_reboot.to_string(),
+    );
+    diff(
+        "no_logind",
+        old.no_logind.to_string(),
+        new.no_logind.to_string(),
+    );
+    diff(
+        "logout_timeout_s",
+        old.logout_timeout_s.to_string(),
+        new.logout_timeout_s.to_string(),
+    );
+    diff(
+        "hotplug_type",
+        format!("{:?}", old.hotplug_type),
+        format!("{:?}", new.hotplug_type),
+    );
+    diff(
+        "display_manager_units",
+        old.display_manager_units.join(","),
+        new.display_manager_units.join(","),
+    );
+    changes
+}
+
+/// `GfxError::ConfigNotPersisted` as an `IOError`, so a client can tell the change was made
+/// but wasn't saved, `GfxError::CancelNotAllowed` as `AccessDenied`, anything else as `Failed`.
+/// The text has the code of the error, see `GfxError::daemon_message`.
+pub(crate) fn fdo_error(err: GfxError) -> zbus::fdo::Error {
+    warn!("{}", err);
+    match err {
+        GfxError::ConfigNotPersisted(_) => zbus::fdo::Error::IOError(err.daemon_message()),
+        GfxError::CancelNotAllowed(_) => zbus::fdo::Error::AccessDenied(err.daemon_message()),
+        _ => zbus::fdo::Error::Failed(err.daemon_message()),
+    }
+}
+
+/// Check that the sender of a message is root, for methods which only an administrator
+/// may call. `what` completes the denial message, e.g "change the mode lock".
+async fn require_root(
+    connection: &Connection,
+    header: &Header<'_>,
+    what: &str,
+) -> zbus::fdo::Result<()> {
+    if header.sender().is_none() {
+        return Err(zbus::fdo::Error::AccessDenied("Unknown sender".to_string()));
+    }
+    if sender_uid(connection, header).await? != 0 {
+        return Err(zbus::fdo::Error::AccessDenied(format!(
+            "Only root can {what}"
+        )));
+    }
+    Ok(())
+}
+
 #[interface(name = "org.supergfxctl.Daemon")]
 impl CtrlGraphics {
     /// Get supergfxd version
     fn version(&self) -> zbus::fdo::Result<String> {
-        Ok(VERSION.to_string())
+        Ok(self.get_version())
+    }
+
+    /// Get the version, git commit, cargo features, rustc version, build date and the
+    /// compiled in paths of the daemon, with any paths in use which differ from them
+    async fn build_info(&self) -> zbus::fdo::Result<BuildInfo> {
+        Ok(self.get_build_info().await)
+    }
+
+    /// Get the version and a hash of the interface description
+    async fn capabilities(&self) -> zbus::fdo::Result<Capabilities> {
+        Ok(self.get_capabilities().await)
+    }
+
+    /// Get the introspection XML of this interface, the same as is shipped in
+    /// `/usr/share/dbus-1/interfaces`, for clients which can't introspect the bus
+    fn introspect_xml(&self) -> zbus::fdo::Result<String> {
+        Ok(self.introspection_xml())
     }
 
     /// Get the current graphics mode:
@@ -41,33 +338,129 @@ impl CtrlGraphics {
     /// # assert_eq!(pci_device::GfxMode::None as u8, GfxMode::None as u8);
     /// ```
     async fn mode(&self) -> zbus::fdo::Result<GfxMode> {
+        if let Some(mode) = self.get_profile().await.fixed_mode() {
+            return Ok(mode);
+        }
         if let Ok(state) = asus_gpu_mux_mode() {
             if state == AsusGpuMuxMode::Discreet {
                 return Ok(GfxMode::AsusMuxDgpu);
             }
         }
+        if vendor_mux_on() {
+            return Ok(GfxMode::AsusMuxDgpu);
+        }
         let config = self.config.lock().await;
         self.get_gfx_mode(&config).map_err(|err| {
             error!("{}", err);
-            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
+            zbus::fdo::Error::Failed(err.daemon_message())
         })
     }
 
+    /// Get the mode which will be used on the next boot. This differs from `Mode` while a
+    /// temporary mode, such as Vfio without `vfio_save` or one set with `persist` off, is in
+    /// use.
+    async fn persistent_mode(&self) -> zbus::fdo::Result<GfxMode> {
+        if let Some(mode) = self.get_profile().await.fixed_mode() {
+            return Ok(mode);
+        }
+        Ok(self.get_persistent_mode().await)
+    }
+
     /// Get list of supported modes
     async fn supported(&self) -> zbus::fdo::Result<Vec<GfxMode>> {
-        if let Ok(state) = asus_gpu_mux_mode() {
-            if state == AsusGpuMuxMode::Discreet {
-                return Ok(vec![GfxMode::AsusMuxDgpu]);
-            }
-        }
         Ok(self.get_supported_modes().await)
     }
 
+    /// Get the list of supported modes as for `Supported`, with the probes which failed
+    /// while making it. A failed probe leaves out the mode it checks for. The struct fields
+    /// in order are: pub modes: Vec<GfxMode>, pub errors: Vec<ProbeError>, where ProbeError
+    /// is:
+    /// ```rust
+    /// struct ProbeError {
+    ///     probe: String,
+    ///     error: String,
+    /// }
+    /// ```
+    async fn supported_with_errors(&self) -> zbus::fdo::Result<SupportedModes> {
+        Ok(self.get_supported_with_errors().await)
+    }
+
+    /// Get why the list of supported modes is limited, for example if this system has no
+    /// dGPU. Empty if it isn't limited.
+    async fn supported_reason(&self) -> zbus::fdo::Result<String> {
+        Ok(self.get_supported_reason().await)
+    }
+
+    /// Get the documentation of every mode in wire order, for frontends to show as help,
+    /// and whether this system supports each. Risks are stable codes such as
+    /// `EXTERNAL_PORTS_OFF` or `REQUIRES_REBOOT`. The struct is:
+    /// ```rust
+    /// struct ModeInfo {
+    ///     mode: u32,
+    ///     name: String,
+    ///     summary: String,
+    ///     description: String,
+    ///     typical_action: u32,
+    ///     risks: Vec<String>,
+    ///     supported: bool,
+    ///     unsupported_reason: String,
+    /// }
+    /// ```
+    async fn mode_info(&self) -> zbus::fdo::Result<Vec<ModeInfo>> {
+        Ok(self.get_mode_info().await)
+    }
+
+    /// Get how the daemon is operating on this system:
+    /// ```rust
+    /// enum OperatingProfile {
+    ///     Switchable,
+    ///     NoDgpu,
+    ///     Desktop,
+    /// }
+    /// ```
+    async fn profile(&self) -> zbus::fdo::Result<OperatingProfile> {
+        Ok(self.get_profile().await)
+    }
+
     /// Get the vendor name of the dGPU
     async fn vendor(&self) -> zbus::fdo::Result<String> {
         Ok(<&str>::from(self.get_gfx_vendor().await).to_string())
     }
 
+    /// Get the functions of the dGPU which are present, such as its audio or USB-C
+    /// controller, each with its runtime power status and the driver bound. Each is a
+    /// struct of pci_id: String, sysname: String, vendor: GfxVendor, is_dgpu: bool,
+    /// power: GfxPower and driver: String (empty if unbound). Empty if the dGPU is off the
+    /// bus, such as with `dgpu_disable`.
+    async fn devices(&self) -> zbus::fdo::Result<Vec<DeviceInfo>> {
+        Ok(self.get_devices().await)
+    }
+
+    /// Get a snapshot of the mode, pending change, vendor, power status and supported modes
+    /// in one call. `generation` increases whenever anything else in it changes. The state
+    /// is cached so this is cheap enough to poll.
+    async fn status(&self) -> zbus::fdo::Result<GfxStatus> {
+        self.announce_attention().await;
+        Ok(self.get_status().await)
+    }
+
+    /// Get the mode, power status, pending change, vendor and supported modes in one call,
+    /// read together so they agree. Unlike `Status` this reads the hardware. The power
+    /// status is `Unknown` if it can't be read. The struct is:
+    /// ```rust
+    /// struct GfxState {
+    ///     mode: u32,
+    ///     power: u32,
+    ///     pending_mode: u32,
+    ///     pending_action: u32,
+    ///     vendor: u32,
+    ///     supported: Vec<u32>,
+    /// }
+    /// ```
+    async fn state(&self) -> zbus::fdo::Result<GfxState> {
+        Ok(self.get_state().await)
+    }
+
     /// Get the current power status:
     /// enum GfxPower {
     ///     Active,
@@ -77,18 +470,47 @@ impl CtrlGraphics {
     ///     Unknown,
     /// }
     async fn power(&self) -> zbus::fdo::Result<GfxPower> {
+        if self.get_profile().await.is_inert() {
+            return Ok(GfxPower::Unknown);
+        }
         if let Ok(state) = asus_gpu_mux_mode() {
             if state == AsusGpuMuxMode::Discreet {
                 return Ok(GfxPower::AsusMuxDiscreet);
             }
         }
-        let dgpu = self.dgpu.lock().await;
+        if vendor_mux_on() {
+            return Ok(GfxPower::AsusMuxDiscreet);
+        }
+        let dgpu = self.dgpu_snapshot().await;
         dgpu.get_runtime_status().map_err(|err| {
             error!("{}", err);
-            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
+            zbus::fdo::Error::Failed(err.daemon_message())
         })
     }
 
+    /// The dGPU power status as `Power` returns it, as last read by the status notifier.
+    /// `PropertiesChanged` is emitted when it changes, so a client can follow it without
+    /// polling. `notify_gfx_status` is still emitted too, with the status as read.
+    #[zbus(property)]
+    async fn power_status(&self) -> GfxPower {
+        self.status_cache.lock().await.hardware.reported_power()
+    }
+
+    /// Get what each power status from `Power` means for the dGPU of this system, which
+    /// differs by vendor: for Nvidia `Off` means the dGPU was removed from the bus, for
+    /// the others it is only reported in D3cold. `version` increases when a meaning
+    /// changes. The struct is:
+    /// ```rust
+    /// struct PowerSemantics {
+    ///     vendor: u32, // GfxVendor
+    ///     version: u32,
+    ///     mapping: Vec<(u32, String)>, // GfxPower and what it means
+    /// }
+    /// ```
+    async fn power_semantics(&self) -> zbus::fdo::Result<PowerSemantics> {
+        Ok(PowerSemantics::for_vendor(self.get_gfx_vendor().await))
+    }
+
     /// Set the graphics mode:
     /// ```rust
     /// enum GfxMode {
@@ -111,6 +533,9 @@ impl CtrlGraphics {
     /// # assert_eq!(pci_device::GfxMode::None as u8, GfxMode::None as u8);
     /// ```
     ///
+    /// A value past `None` is rejected, and flagged in `Capabilities` as the client is likely
+    /// built against the numbering of a release before 5.1.
+    ///
     /// Returns action required:
     /// ```rust
     /// enum UserActionRequired {
@@ -130,24 +555,499 @@ impl CtrlGraphics {
     /// ```
     async fn set_mode(
         &mut self,
+        #[zbus(connection)] connection: &Connection,
         #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
-        mode: GfxMode,
+        #[zbus(header)] header: Header<'_>,
+        mode: u32,
     ) -> zbus::fdo::Result<UserActionRequired> {
-        info!("Switching gfx mode to {mode}");
-        let msg = self.set_gfx_mode(mode).await.map_err(|err| {
-            error!("{}", err);
-            zbus::fdo::Error::Failed(format!("GFX fail: {}", err))
-        })?;
+        self.set_mode_with_options(connection, ctxt, header, mode, SetModeOptions::default())
+            .await
+    }
+
+    /// Set the graphics mode as with `SetMode`, with options:
+    /// ```rust
+    /// struct SetModeOptions {
+    ///     skip_pre_stop_delay: bool,
+    ///     ignore_inhibitors: bool,
+    ///     persist: bool, // false to only use the mode until reboot
+    /// }
+    /// ```
+    async fn set_mode_with_options(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(header)] header: Header<'_>,
+        mode: u32,
+        options: SetModeOptions,
+    ) -> zbus::fdo::Result<UserActionRequired> {
+        let mode = self.mode_from_wire(mode)?;
+        let actor = Actor::from_call(connection, &header).await;
+        self.switch_by_user(&ctxt, &actor, mode, options).await
+    }
 
-        Self::notify_action(&ctxt, &msg)
+    /// Run the ASUS boot safety check against sysfs as it is now, changing nothing. Useful
+    /// after the MUX, `dgpu_disable` or `egpu_enable` were changed by the BIOS or another
+    /// tool while supergfxd runs, which the boot check would only notice at the next boot.
+    /// Returns:
+    /// ```rust
+    /// struct SafetyCheck {
+    ///     current: u32, // GfxMode
+    ///     mode: u32, // the mode which should be active
+    ///     reasons: Vec<String>, // the attribute values which decided it
+    ///     enable_dgpu: bool, // dgpu_disable would be turned off
+    ///     switch_required: bool,
+    ///     action: u32, // UserActionRequired for the switch, Nothing if none is needed
+    /// }
+    /// ```
+    async fn safety_check(&self) -> zbus::fdo::Result<SafetyCheck> {
+        self.asus_safety_check_in(&Sysfs::system(), &SystemMuxReader)
+            .await
+            .map(|(_, check)| check)
+            .map_err(fdo_error)
+    }
+
+    /// Apply what `SafetyCheck` reports: `dgpu_disable` is turned off if it must be, then
+    /// the switch is requested as with `SetMode` so a logout or reboot is asked for where
+    /// needed. Returns action required, `Nothing` if the mode was already right.
+    async fn safety_check_apply(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<UserActionRequired> {
+        let actor = Actor::from_call(connection, &header).await;
+        match self
+            .apply_safety_toggles(&Sysfs::system(), &SystemMuxReader, &actor)
+            .await
+            .map_err(fdo_error)?
+        {
+            Some(mode) => {
+                self.switch_by_user(&ctxt, &actor, mode, SetModeOptions::default())
+                    .await
+            }
+            None => Ok(UserActionRequired::Nothing),
+        }
+    }
+
+    /// Switch to a mode as soon as the logind session of the caller ends, for a desktop
+    /// whose user has confirmed a logout in order to switch. The wait for all graphical
+    /// sessions to end is skipped if it was the last one. If the session doesn't end within
+    /// a minute, such as when the logout was cancelled, the switch is dropped and the
+    /// pending mode cleared. Returns action required.
+    async fn confirm_logout_and_switch(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(header)] header: Header<'_>,
+        mode: u32,
+    ) -> zbus::fdo::Result<UserActionRequired> {
+        let mode = self.mode_from_wire(mode)?;
+        let sender = header
+            .sender()
+            .ok_or_else(|| zbus::fdo::Error::Failed("Unknown sender".to_string()))?;
+        let session = session_of_sender(connection, BusName::Unique(sender.clone()))
+            .await
+            .map_err(|err| {
+                warn!("{}", err);
+                zbus::fdo::Error::Failed(format!("GFX fail: no logind session found: {}", err))
+            })?;
+        info!("Switching gfx mode to {mode} once session {session} ends");
+        let msg = self
+            .switch_after_logout(mode, session, &Actor::from_call(connection, &header).await)
+            .await
+            .map_err(|err| {
+                error!("{}", err);
+                zbus::fdo::Error::Failed(err.daemon_message())
+            })?;
+        self.user_switches.fetch_add(1, Ordering::AcqRel);
+
+        emit_counted!(&ctxt, Signal::Action, notify_action(&msg))
             .await
             .unwrap_or_else(|err| warn!("{}", err));
+        Ok(msg)
+    }
+
+    /// Get advice on switching to a mode without switching, such as the connected outputs
+    /// wired to the dGPU which will stop working, or the command to regenerate the initramfs
+    /// with if it has a copy of the modprobe conf the switch changes:
+    /// ```rust
+    /// struct SwitchAdvisory {
+    ///     outputs_that_will_turn_off: Vec<String>,
+    ///     initramfs_regeneration: String,
+    /// }
+    /// ```
+    async fn switch_advisory(&self, mode: GfxMode) -> zbus::fdo::Result<SwitchAdvisory> {
+        Ok(self.get_switch_advisory(mode).await)
+    }
+
+    /// Get the actions a switch to `mode` would perform, as `SetMode` would plan it now.
+    /// Nothing is done and the pending mode isn't changed. Whether the switch would be made
+    /// is `SwitchReadiness`:
+    /// ```rust
+    /// struct PlannedSwitch {
+    ///     from: u32, // GfxMode
+    ///     to: u32, // GfxMode
+    ///     user_action: u32, // UserActionRequired
+    ///     actions: Vec<String>, // empty if the switch is left to the user
+    /// }
+    /// ```
+    async fn get_switch_plan(&self, mode: GfxMode) -> zbus::fdo::Result<PlannedSwitch> {
+        Ok(self.plan_switch_to(mode).await)
+    }
+
+    /// Check whether a switch to a mode would be made if asked for now, for frontends to
+    /// grey out the modes which can't be set with why. Read only, nothing is switched:
+    /// ```rust
+    /// struct SwitchReadiness {
+    ///     mode: u32, // GfxMode
+    ///     ready: bool,
+    ///     generation: u64,
+    ///     findings: Vec<ReadinessFinding>,
+    /// }
+    /// struct ReadinessFinding {
+    ///     code: String, // such as mode_locked, stable between releases
+    ///     message: String,
+    ///     overridable: bool,
+    /// }
+    /// ```
+    /// Ask again for each mode when `NotifyReadinessChanged` is received.
+    async fn switch_readiness(&self, mode: GfxMode) -> zbus::fdo::Result<SwitchReadiness> {
+        self.get_switch_readiness(mode)
+            .await
+            .map_err(|err| zbus::fdo::Error::Failed(err.daemon_message()))
+    }
+
+    /// Get the environment variables to run an app on the dGPU, such as
+    /// `__NV_PRIME_RENDER_OFFLOAD=1` or `DRI_PRIME=1`, for the current mode and the driver
+    /// of the dGPU. Empty if the mode doesn't allow offload, such as Integrated or Vfio.
+    async fn prime_env(&self) -> zbus::fdo::Result<Vec<(String, String)>> {
+        self.get_prime_env().await.map_err(|err| {
+            error!("{}", err);
+            zbus::fdo::Error::Failed(err.daemon_message())
+        })
+    }
+
+    /// Get the PCIe link speed, width and enabled ASPM states of the dGPU and the port it
+    /// is on. Attributes which would wake a suspended dGPU are left empty and a note says why.
+    async fn link_info(&self) -> zbus::fdo::Result<LinkInfo> {
+        Ok(self.get_link_info().await)
+    }
+
+    /// Get the reminder to regenerate the initramfs, raised when a switch changed the
+    /// modprobe conf it has a copy of. Empty if there is none. The initramfs is checked
+    /// first where it can be listed, and the reminder dropped if it has the current conf.
+    async fn initramfs_advisory(&self) -> zbus::fdo::Result<String> {
+        refresh_advisory(&self.initramfs).await;
+        Ok(self
+            .initramfs
+            .lock()
+            .await
+            .advisory()
+            .map(|advisory| advisory.message())
+            .unwrap_or_default())
+    }
+
+    /// Dismiss the reminder to regenerate the initramfs. supergfxd never regenerates it.
+    async fn dismiss_initramfs_advisory(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        if self.initramfs.lock().await.clear() {
+            self.audit
+                .record(&Actor::from_header(&header), "initramfs advisory dismissed");
+        }
+        Ok(())
+    }
+
+    /// Get what supergfxd did differently at boot and wasn't dismissed yet, such as the
+    /// MUX being assumed discreet or the reduced boot path. Each is a struct of code:
+    /// String, message: String, timestamp: u64 (seconds since the epoch), mode: GfxMode and
+    /// boots: u32 (since it was registered). They expire after `attention_expiry_boots`.
+    async fn attention_items(&self) -> zbus::fdo::Result<Vec<AttentionItem>> {
+        Ok(self.attention.lock().await.items().to_vec())
+    }
+
+    /// Dismiss the attention item with `code`, which is recorded in the audit log
+    async fn dismiss_attention(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        code: &str,
+    ) -> zbus::fdo::Result<()> {
+        validate::identifier(code).map_err(|err| zbus::fdo::Error::InvalidArgs(err.to_string()))?;
+        if self.attention.lock().await.dismiss(code).is_none() {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "No attention item {code}"
+            )));
+        }
+        self.audit.record(
+            &Actor::from_header(&header),
+            &format!("attention item {code} dismissed"),
+        );
+        Ok(())
+    }
+
+    /// Get the processes which had the dGPU open when it was last found kept awake in
+    /// Hybrid on battery for `power_blocker_threshold_s`. Empty if it isn't, and cleared
+    /// once the dGPU suspends. Each is a struct of pid: u32, comm: String, user: String and
+    /// first_seen: u64 (seconds since the epoch).
+    async fn power_blockers(&self) -> zbus::fdo::Result<Vec<PowerBlocker>> {
+        Ok(self.power_blockers.lock().await.clone())
+    }
+
+    /// Get the processes holding a dGPU device node open or mapped, which keep its driver
+    /// from being unloaded, so they can be closed before a switch. Each is a struct of
+    /// pid: u32, comm: String and path: String (the device node). The nvidia services are
+    /// included, a switch stops those itself.
+    async fn blocking_processes(&self) -> zbus::fdo::Result<Vec<BlockingProcess>> {
+        Ok(self.get_blocking_processes().await)
+    }
+
+    /// Stop supergfxd doing anything by itself for `seconds`, such as during a presentation
+    /// or a benchmark: no `ac_automation` suggestions or switches, no thermal advisory, no
+    /// periodic verification and no change to the power poll. What is skipped is recorded
+    /// in the audit log. Mode changes asked for by clients still happen. Ends after
+    /// `seconds`, at most 12 hours, with `Uninhibit`, or when the client leaves the bus.
+    /// Several clients can inhibit at once. Returns the cookie to give to `Uninhibit`.
+    async fn inhibit_automation(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        reason: &str,
+        seconds: u64,
+    ) -> zbus::fdo::Result<u32> {
+        validate::text(reason, INHIBIT_REASON_MAX)
+            .map_err(|err| zbus::fdo::Error::InvalidArgs(err.to_string()))?;
+        let actor = Actor::from_header(&header);
+        let cookie = self
+            .automation_inhibits
+            .lock()
+            .await
+            .add(
+                &actor.to_string(),
+                reason,
+                Duration::from_secs(seconds),
+                Instant::now(),
+            )
+            .map_err(|err| zbus::fdo::Error::InvalidArgs(err.to_string()))?;
+        self.audit.record(
+            &actor,
+            &format!("automation inhibited for {seconds}s: {}", reason.trim()),
+        );
+        Ok(cookie)
+    }
+
+    /// Only get the signals of `categories` from now on, such as `["power"]` for a battery
+    /// widget, see `SIGNAL_CATEGORIES`. They are then sent to this client by its unique name,
+    /// so it should match signals sent to it rather than broadcast. A category no client
+    /// asked for is still broadcast. An empty list removes the filter, as does leaving the
+    /// bus.
+    async fn set_signal_filter(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        categories: Vec<String>,
+    ) -> zbus::fdo::Result<()> {
+        let owner = Actor::from_header(&header).to_string();
+        set_signal_filter(&owner, &categories)
+            .map_err(|err| zbus::fdo::Error::InvalidArgs(err.to_string()))?;
+        info!("Signal filter of {owner}: {categories:?}");
+        Ok(())
+    }
 
-        Self::notify_gfx(&ctxt, &mode)
+    /// End an inhibition from `InhibitAutomation` early. Only the client which asked for it
+    /// can end it.
+    async fn uninhibit(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        cookie: u32,
+    ) -> zbus::fdo::Result<()> {
+        let actor = Actor::from_header(&header);
+        let ended = self
+            .automation_inhibits
+            .lock()
+            .await
+            .remove(cookie, &actor.to_string())
+            .map_err(|err| zbus::fdo::Error::InvalidArgs(err.to_string()))?;
+        self.audit.record(
+            &actor,
+            &format!("automation inhibition ended: {}", ended.reason),
+        );
+        Ok(())
+    }
+
+    /// Get the resident set size of the daemon and the bounded buffers it holds, for
+    /// checking it doesn't grow over a long uptime:
+    /// ```rust
+    /// struct MemoryReport {
+    ///     rss_kib: u64,
+    ///     buffers: Vec<BufferReport>,
+    /// }
+    /// struct BufferReport {
+    ///     name: String,
+    ///     capacity: u64,
+    ///     len: u64,
+    ///     dropped: u64,
+    /// }
+    /// ```
+    async fn memory_report(&self) -> zbus::fdo::Result<MemoryReport> {
+        Ok(memory_report())
+    }
+
+    /// Get how many of each signal were emitted since supergfxd started. A client which
+    /// keeps the `sequence` of the last `NotifyEvent` it saw, such as across a suspend or an
+    /// extension reload, missed something if it differs and should read `Status` again:
+    /// ```rust
+    /// struct SignalCounters {
+    ///     sequence: u64,
+    ///     signals: Vec<(String, u64)>,
+    /// }
+    /// ```
+    async fn signal_counters(&self) -> zbus::fdo::Result<SignalCounters> {
+        Ok(signal_counters())
+    }
+
+    /// Get the background tasks of the daemon and whether each is still running:
+    /// ```rust
+    /// struct TaskInfo {
+    ///     name: String,
+    ///     policy: u32, // Never, WithBackoff, EscalateToExit
+    ///     state: u32, // Running, Restarting, Stopped, Failed
+    ///     restarts: u32,
+    ///     last_error: String,
+    /// }
+    /// ```
+    fn tasks(&self) -> zbus::fdo::Result<Vec<TaskInfo>> {
+        Ok(self.tasks.roster())
+    }
+
+    /// Cancel the pending mode change. Fails if there is none, or if it has already
+    /// started changing the system. With `cancel_requester_only` set only the user who
+    /// asked for the switch, or root, may cancel it.
+    async fn cancel_switch(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(header)] header: Header<'_>,
+    ) -> zbus::fdo::Result<()> {
+        let uid = sender_uid(connection, &header).await.ok();
+        self.cancel_pending_switch_by(uid)
+            .await
+            .map_err(fdo_error)?;
+
+        let mode = self
+            .get_gfx_mode(&*self.config.lock().await)
+            .map_err(|err| {
+                error!("{}", err);
+                zbus::fdo::Error::Failed(err.daemon_message())
+            })?;
+        emit_counted!(&ctxt, Signal::Gfx, notify_gfx(&mode))
             .await
             .unwrap_or_else(|err| warn!("{}", err));
+        emit_counted!(
+            &ctxt,
+            Signal::Action,
+            notify_action(&UserActionRequired::Nothing)
+        )
+        .await
+        .unwrap_or_else(|err| warn!("{}", err));
+        Ok(())
+    }
 
-        Ok(msg)
+    /// Lock the mode to the one currently configured, or unlock it. While locked mode
+    /// changes are refused and only the locked mode is supported. Only root may call this.
+    async fn set_mode_lock(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        locked: bool,
+    ) -> zbus::fdo::Result<()> {
+        // A debug run is on the session bus of the developer, who is not root
+        if !self.is_debug_run() {
+            require_root(connection, &header, "change the mode lock").await?;
+        }
+        self.set_mode_locked(locked, &Actor::from_header(&header))
+            .await
+            .map_err(fdo_error)
+    }
+
+    /// Get the last `count` records of the audit log, oldest first. Each is a struct of
+    /// timestamp: u64 (seconds since the epoch), actor: String (a bus name, or boot, cmdline
+    /// or supergfxd) and change: String. Only root may call this, as the records name the
+    /// bus clients which made changes.
+    async fn audit_log(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        count: u32,
+    ) -> zbus::fdo::Result<Vec<AuditRecord>> {
+        if !self.is_debug_run() {
+            require_root(connection, &header, "read the audit log").await?;
+        }
+        Ok(self.audit.tail(count as usize))
+    }
+
+    /// Write a support bundle for bug reports to `path`, as a `.tar.gz` or as plain files if
+    /// `path` is a directory. Sections which can't be collected are replaced by a note.
+    /// Returns the path written. Only root may call this.
+    async fn export_support_bundle(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        path: String,
+    ) -> zbus::fdo::Result<String> {
+        if !self.is_debug_run() {
+            require_root(connection, &header, "export a support bundle").await?;
+        }
+        validate::absolute_path(&path)
+            .map_err(|err| zbus::fdo::Error::InvalidArgs(err.to_string()))?;
+        self.write_support_bundle(Path::new(&path))
+            .await
+            .map(|path| path.to_string_lossy().to_string())
+            .map_err(|err| {
+                warn!("{}", err);
+                zbus::fdo::Error::Failed(err.daemon_message())
+            })
+    }
+
+    /// Switch to the cheapest other mode and back, then check nothing was left changed,
+    /// restoring the recorded state if it was. If every other mode needs a logout or reboot
+    /// only the checks which change nothing are run. Refused while a graphical session is
+    /// active unless `force` is set. Only root may call this.
+    async fn self_test(
+        &mut self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        force: bool,
+    ) -> zbus::fdo::Result<SelfTestReport> {
+        if !self.is_debug_run() {
+            require_root(connection, &header, "run a self-test").await?;
+        }
+        if !force && graphical_sessions_active().await.unwrap_or(true) {
+            return Err(zbus::fdo::Error::Failed(
+                "Graphical sessions are active, log out first or force the self-test".to_string(),
+            ));
+        }
+        self.run_self_test().await.map_err(|err| {
+            warn!("{}", err);
+            zbus::fdo::Error::Failed(err.daemon_message())
+        })
+    }
+
+    /// Get if the mode is locked by the administrator
+    async fn mode_locked(&self) -> zbus::fdo::Result<bool> {
+        Ok(self.get_mode_locked().await)
+    }
+
+    /// Rescan the PCI bus to try to recover a dGPU which dropped off it. Returns `true`
+    /// if the dGPU is back.
+    async fn rescan_dgpu(&mut self) -> zbus::fdo::Result<bool> {
+        self.try_recover_dgpu().await.map_err(|err| {
+            warn!("{}", err);
+            zbus::fdo::Error::Failed(err.daemon_message())
+        })
+    }
+
+    /// Get if the dGPU has dropped off the bus. While set only Integrated can be set.
+    async fn degraded_hardware(&self) -> zbus::fdo::Result<bool> {
+        Ok(self.get_degraded_hardware())
     }
 
     /// Get the `String` name of the pending mode change if any
@@ -160,6 +1060,33 @@ impl CtrlGraphics {
         Ok(self.get_pending_user_action().await)
     }
 
+    /// Get the pending mode change with who asked for it and how long ago:
+    /// ```rust
+    /// struct PendingInfo {
+    ///     mode: u32, // GfxMode, None if no switch is pending
+    ///     action: u32, // UserActionRequired
+    ///     requested_at: u64, // seconds since the epoch
+    ///     elapsed_s: u64,
+    ///     requester: String, // a bus name such as :1.42, or automation, boot or cmdline
+    ///     uid: u32, // u32::MAX if not known
+    /// }
+    /// ```
+    async fn pending_info(&self) -> zbus::fdo::Result<PendingInfo> {
+        Ok(self.get_pending_info().await)
+    }
+
+    /// Get the state of the mode switch task:
+    /// ```rust
+    /// enum SwitchState {
+    ///     Idle,
+    ///     Switching,
+    ///     Stalled,
+    /// }
+    /// ```
+    async fn switch_state(&self) -> zbus::fdo::Result<SwitchState> {
+        Ok(self.get_switch_state().await)
+    }
+
     /// Get the base config, args in order are:
     /// pub mode: GfxMode,
     /// vfio_enable: bool,
@@ -168,12 +1095,53 @@ impl CtrlGraphics {
     /// always_reboot: bool,
     /// no_logind: bool,
     /// logout_timeout_s: u64,
+    /// hotplug_type: HotplugType,
+    /// display_manager_units: Vec<String>,
     async fn config(&self) -> zbus::fdo::Result<GfxConfigDbus> {
         let cfg = self.config.lock().await;
         let cfg = GfxConfigDbus::from(&*cfg);
         Ok(cfg)
     }
 
+    /// Get the path of the config file in use
+    async fn config_path(&self) -> zbus::fdo::Result<String> {
+        Ok(self.config.lock().await.config_path.clone())
+    }
+
+    /// What setting config options would change in the generated files, for the mode in use
+    /// and each other supported mode, without setting them. Each change is the name of an
+    /// option in the config file and its new value as JSON, such as
+    /// `("manage_switcheroo", "true")`. Advisories are `initramfs`, `reboot` or `logout`.
+    /// ```rust
+    /// struct ConfigPreview {
+    ///     mode: u32,
+    ///     /// Artifact is Modprobe, Udev or Environment
+    ///     artifacts: Vec<(u32, u32, bool, String, Vec<String>)>,
+    ///     /// The code, then the message
+    ///     advisories: Vec<(String, String)>,
+    /// }
+    /// ```
+    async fn preview_config_change(
+        &self,
+        changes: Vec<ConfigChange>,
+    ) -> zbus::fdo::Result<ConfigPreview> {
+        self.get_config_preview(&changes)
+            .await
+            .map_err(|err| match err {
+                GfxError::InvalidInput(..) => zbus::fdo::Error::InvalidArgs(err.to_string()),
+                _ => {
+                    error!("{}", err);
+                    zbus::fdo::Error::Failed(err.daemon_message())
+                }
+            })
+    }
+
+    /// Why the `hotplug_type` in the config file isn't used and `None` is used in its place,
+    /// empty if it is used
+    async fn hotplug_downgrade(&self) -> zbus::fdo::Result<String> {
+        Ok(self.get_hotplug_downgrade().await.unwrap_or_default())
+    }
+
     /// Set the base config, args in order are:
     /// pub mode: GfxMode,
     /// vfio_enable: bool,
@@ -182,32 +1150,103 @@ impl CtrlGraphics {
     /// always_reboot: bool,
     /// no_logind: bool,
     /// logout_timeout_s: u64,
+    /// hotplug_type: HotplugType,
+    /// display_manager_units: Vec<String>,
+    /// Only root may change `display_manager_units`, each must be a `.service` or `.target`.
+    /// Fails with `IOError` if the config was changed but couldn't be saved.
     async fn set_config(
         &mut self,
+        #[zbus(connection)] connection: &Connection,
         #[zbus(signal_context)] ctxt: SignalEmitter<'_>,
+        #[zbus(header)] header: Header<'_>,
         config: GfxConfigDbus,
     ) -> zbus::fdo::Result<()> {
+        self.check_mutation_allowed().map_err(|err| {
+            warn!("{}", err);
+            zbus::fdo::Error::Failed(err.daemon_message())
+        })?;
+        if let Some(reason) = self.get_profile().await.reason() {
+            return Err(zbus::fdo::Error::NotSupported(reason.to_string()));
+        }
         let do_mode_change;
         let mode;
+        let written;
 
         {
             let mut cfg = self.config.lock().await;
+            // The plans depend on these options, the disabled actions must still fit them
+            let candidate = GfxConfig {
+                always_reboot: config.always_reboot,
+                no_logind: config.no_logind,
+                ..cfg.clone()
+            };
+            validate_disabled_actions(&candidate).map_err(|err| {
+                warn!("{}", err);
+                zbus::fdo::Error::InvalidArgs(err.daemon_message())
+            })?;
+            let display_managers_changed =
+                config.display_manager_units != cfg.display_manager_units;
+            if display_managers_changed {
+                validate_display_manager_units(&config.display_manager_units).map_err(|err| {
+                    warn!("{}", err);
+                    zbus::fdo::Error::InvalidArgs(err.daemon_message())
+                })?;
+                // These are stopped and started as root
+                if !self.is_debug_run() {
+                    require_root(connection, &header, "change display_manager_units").await?;
+                }
+            }
+            // Unchanged unless it differs from what config() reported
+            let hotplug_changed = config.hotplug_type != cfg.effective_hotplug_type();
+            if hotplug_changed {
+                let dgpu = self.dgpu_snapshot().await;
+                check_requested_hotplug_type(
+                    config.hotplug_type,
+                    &SystemHotplugProbe { dgpu: &dgpu },
+                )
+                .map_err(|err| {
+                    warn!("{}", err);
+                    zbus::fdo::Error::InvalidArgs(err.daemon_message())
+                })?;
+            }
 
             do_mode_change = cfg.mode == config.mode;
             mode = cfg.mode;
 
+            let changes = config_changes(&GfxConfigDbus::from(&*cfg), &config);
+            if !changes.is_empty() {
+                self.audit
+                    .record(&Actor::from_header(&header), &changes.join(", "));
+            }
+
             cfg.vfio_enable = config.vfio_enable;
             cfg.vfio_save = config.vfio_save;
             cfg.always_reboot = config.always_reboot;
             cfg.no_logind = config.no_logind;
             cfg.logout_timeout_s = config.logout_timeout_s;
+            cfg.display_manager_units = config.display_manager_units;
+            if hotplug_changed {
+                warn!(
+                    "hotplug_type changed to {:?}, reboot to be sure the dGPU is in the right state",
+                    config.hotplug_type
+                );
+                cfg.hotplug_type = config.hotplug_type;
+                cfg.hotplug_downgrade = None;
+            }
+            written = cfg.write();
         }
+        // Anything staged was rendered for the old config
+        self.staging.lock().await.invalidate();
+        // vfio_enable may have changed
+        self.recheck_supported_modes().await;
 
         if do_mode_change {
-            self.set_mode(ctxt, mode).await.ok();
+            self.set_mode(connection, ctxt, header, mode as u32)
+                .await
+                .ok();
         }
 
-        Ok(())
+        written.map_err(fdo_error)
     }
 
     /// Be notified when the dgpu status changes:
@@ -228,15 +1267,234 @@ impl CtrlGraphics {
 
     /// Recieve a notification if the graphics mode changes and to which mode
     #[zbus(signal)]
-    async fn notify_gfx(signal_ctxt: &SignalEmitter<'_>, vendor: &GfxMode) -> zbus::Result<()> {}
+    pub async fn notify_gfx(signal_ctxt: &SignalEmitter<'_>, vendor: &GfxMode) -> zbus::Result<()> {
+    }
+
+    /// Recieve the mode a switch was started for and what started it, emitted before
+    /// `NotifyGfx`:
+    /// ```rust
+    /// enum SwitchInitiator {
+    ///     User,
+    ///     Automation,
+    ///     Schedule,
+    /// }
+    /// ```
+    #[zbus(signal)]
+    pub async fn notify_mode_change(
+        signal_ctxt: &SignalEmitter<'_>,
+        mode: &GfxMode,
+        initiator: &SwitchInitiator,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the mode suggested for the power source when AC is plugged in or unplugged,
+    /// see `ac_automation` in the config. If `applying` is set supergfxd is switching to it,
+    /// otherwise `reason` says why not. The struct fields in order are:
+    /// pub mode: GfxMode,
+    /// pub power: PowerSource, (Ac, Battery)
+    /// pub applying: bool,
+    /// pub reason: String,
+    #[zbus(signal)]
+    pub async fn notify_suggestion(
+        signal_ctxt: &SignalEmitter<'_>,
+        suggestion: &ModeSuggestion,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the mode suggested when the machine is docked or undocked, see
+    /// `dock_profiles` in the config. If `applying` is set supergfxd is switching to it,
+    /// otherwise `reason` says why not. The struct fields in order are:
+    /// pub mode: GfxMode,
+    /// pub dock: DockState, (Docked, Undocked)
+    /// pub applying: bool,
+    /// pub reason: String,
+    #[zbus(signal)]
+    pub async fn notify_dock_suggestion(
+        signal_ctxt: &SignalEmitter<'_>,
+        suggestion: &DockSuggestion,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve whether an eGPU enclosure is connected, when one is connected or removed
+    /// while `egpu_detect` is on in the config. AsusEgpu is only supported while one is.
+    #[zbus(signal)]
+    pub async fn notify_egpu_presence(
+        signal_ctxt: &SignalEmitter<'_>,
+        present: bool,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the mode of an entry of `schedule` in the config as it comes due. If
+    /// `applying` is set supergfxd is switching to it, otherwise `reason` says why not. The
+    /// struct fields in order are:
+    /// pub mode: GfxMode,
+    /// pub applying: bool,
+    /// pub reason: String,
+    #[zbus(signal)]
+    pub async fn notify_schedule_suggestion(
+        signal_ctxt: &SignalEmitter<'_>,
+        suggestion: &ScheduleSuggestion,
+    ) -> zbus::Result<()> {
+    }
 
     /// Recieve a notification on required action if mode changes
     #[zbus(signal)]
-    async fn notify_action(
+    pub async fn notify_action(
         signal_ctxt: &SignalEmitter<'_>,
         action: &UserActionRequired,
     ) -> zbus::Result<()> {
     }
+
+    /// Recieve advice about a mode switch that was just started, emitted after
+    /// `NotifyAction` if there is any
+    #[zbus(signal)]
+    pub async fn notify_switch_advisory(
+        signal_ctxt: &SignalEmitter<'_>,
+        advisory: &SwitchAdvisory,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the programs holding inhibitor locks that a pending switch is waiting for,
+    /// e.g `fwupd (Firmware update)`. Empty once it is no longer waiting.
+    #[zbus(signal)]
+    pub async fn notify_switch_waiting(
+        signal_ctxt: &SignalEmitter<'_>,
+        waiting_for: &[String],
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve what was done when graphical sessions were still open after
+    /// `logout_timeout_s`, as set by `logout_timeout_action`, such as `logout_timeout_s
+    /// (180s) passed with sessions 2 still open, logout_timeout_action ConvertToDeferred: the
+    /// switch waits until they end`
+    #[zbus(signal)]
+    pub async fn notify_logout_timeout(
+        signal_ctxt: &SignalEmitter<'_>,
+        message: &str,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the seconds left of `logout_timeout_s` while a switch waits for the graphical
+    /// sessions to end, emitted every 10 seconds so a countdown can be shown
+    #[zbus(signal)]
+    pub async fn notify_pending_logout(
+        signal_ctxt: &SignalEmitter<'_>,
+        seconds_remaining: u64,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the new list of supported modes if it changes after startup, for example
+    /// if the ASUS platform driver loads late
+    #[zbus(signal)]
+    pub async fn notify_supported_changed(
+        signal_ctxt: &SignalEmitter<'_>,
+        modes: &[GfxMode],
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the seconds remaining before the display manager is stopped for a mode
+    /// switch, emitted each second while `pre_stop_delay_s` counts down
+    #[zbus(signal)]
+    pub async fn notify_switch_countdown(
+        signal_ctxt: &SignalEmitter<'_>,
+        seconds_remaining: u64,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the action a switch is about to perform, such as `StopDisplayManager`, with
+    /// its place in the switch counting from 1 and how many actions it has
+    #[zbus(signal)]
+    pub async fn notify_switch_progress(
+        signal_ctxt: &SignalEmitter<'_>,
+        current_action: &str,
+        index: u32,
+        total: u32,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve how a switch to `mode` ended, the last signal of a switch. `success` is set
+    /// if it completed, otherwise `outcome` says why not, such as `failed at
+    /// UnloadGpuDrivers and was undone` or `cancelled`.
+    #[zbus(signal)]
+    pub async fn notify_switch_complete(
+        signal_ctxt: &SignalEmitter<'_>,
+        mode: &GfxMode,
+        success: bool,
+        outcome: &str,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve what the periodic verification found changed since the mode was applied
+    /// which supergfxd won't put back itself, such as an xorg config using the nvidia
+    /// driver in Integrated. See `periodic_verify_hours` in the config.
+    #[zbus(signal)]
+    pub async fn notify_drift(
+        signal_ctxt: &SignalEmitter<'_>,
+        findings: &[String],
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a reminder to regenerate the initramfs, such as `initramfs regeneration
+    /// recommended (dracut -f)`, after a switch changed the modprobe conf it has a copy of
+    #[zbus(signal)]
+    pub async fn notify_initramfs_advisory(
+        signal_ctxt: &SignalEmitter<'_>,
+        advisory: &str,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a warning about the boot safety checks, such as the ASUS MUX being assumed
+    /// discreet because it couldn't be read yet, then what was found when it was read again
+    #[zbus(signal)]
+    pub async fn notify_boot_advisory(
+        signal_ctxt: &SignalEmitter<'_>,
+        advisory: &str,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the attention items registered at boot and not dismissed yet. Sent once to
+    /// the first client to call `Status` after supergfxd started, as none was connected to
+    /// see the boot advisories.
+    #[zbus(signal)]
+    pub async fn notify_attention(
+        signal_ctxt: &SignalEmitter<'_>,
+        items: &[AttentionItem],
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve the new generation when the answer of `SwitchReadiness` may have changed for
+    /// any mode, such as when the mode is locked or a switch ends
+    #[zbus(signal)]
+    pub async fn notify_readiness_changed(
+        signal_ctxt: &SignalEmitter<'_>,
+        generation: u64,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification if a background task such as a mode switch failed
+    #[zbus(signal)]
+    pub async fn notify_error(signal_ctxt: &SignalEmitter<'_>, error: &str) -> zbus::Result<()> {}
+
+    /// Recieve the name and sequence of each other signal just after it is emitted, such as
+    /// `NotifyGfx` and 42. The sequence increases by one for every signal, compare the last
+    /// one seen with `SignalCounters` to tell if any were missed.
+    #[zbus(signal)]
+    pub async fn notify_event(
+        signal_ctxt: &SignalEmitter<'_>,
+        signal: &str,
+        sequence: u64,
+    ) -> zbus::Result<()> {
+    }
+
+    /// Recieve a notification as the daemon stops, the last signal it emits apart from its
+    /// `NotifyEvent`. `interrupted` says what happened to a switch which was running, empty
+    /// if there was none.
+    #[zbus(signal)]
+    pub async fn notify_shutdown(
+        signal_ctxt: &SignalEmitter<'_>,
+        interrupted: &str,
+    ) -> zbus::Result<()> {
+    }
 }
 
 impl CtrlGraphics {
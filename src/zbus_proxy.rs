@@ -24,8 +24,31 @@
 use zbus::proxy;
 
 use crate::{
+    ac_automation::ModeSuggestion,
     actions::UserActionRequired,
-    pci_device::{GfxMode, GfxPower},
+    attention::AttentionItem,
+    audit::AuditRecord,
+    buffers::MemoryReport,
+    build_info::BuildInfo,
+    config::ConfigChange,
+    config_preview::ConfigPreview,
+    controller::{
+        GfxState, GfxStatus, OperatingProfile, PendingInfo, PlannedSwitch, SetModeOptions,
+        SupportedModes, SwitchAdvisory, SwitchInitiator, SwitchState,
+    },
+    dock_automation::DockSuggestion,
+    gpu_users::BlockingProcess,
+    pci_device::{DeviceInfo, GfxMode, GfxPower, ModeInfo},
+    pci_link::LinkInfo,
+    power_blockers::PowerBlocker,
+    power_semantics::PowerSemantics,
+    schedule::ScheduleSuggestion,
+    self_test::SelfTestReport,
+    signal_counters::SignalCounters,
+    special_asus::SafetyCheck,
+    supervisor::TaskInfo,
+    switch_readiness::SwitchReadiness,
+    zbus_iface::Capabilities,
 };
 
 #[proxy(
@@ -37,6 +60,15 @@ pub trait Daemon {
     /// Version method
     fn version(&self) -> zbus::Result<String>;
 
+    /// Get the version, commit, features and compiled in paths of the daemon build
+    fn build_info(&self) -> zbus::Result<BuildInfo>;
+
+    /// Get the version and a hash of the interface description
+    fn capabilities(&self) -> zbus::Result<Capabilities>;
+
+    /// Get the introspection XML of the interface
+    fn introspect_xml(&self) -> zbus::Result<String>;
+
     /// Get the base config, args in order are:
     /// pub mode: GfxMode,
     /// vfio_enable: bool,
@@ -47,6 +79,15 @@ pub trait Daemon {
     /// logout_timeout_s: u64,
     fn config(&self) -> zbus::Result<(u32, bool, bool, bool, bool, bool, u64, bool)>;
 
+    /// Get the path of the config file in use
+    fn config_path(&self) -> zbus::Result<String>;
+
+    /// What setting config options would change in the generated files, without setting them
+    fn preview_config_change(&self, changes: &[ConfigChange]) -> zbus::Result<ConfigPreview>;
+
+    /// Why the `hotplug_type` in the config file isn't used, empty if it is
+    fn hotplug_downgrade(&self) -> zbus::Result<String>;
+
     /// Set the base config, args in order are:
     /// pub mode: GfxMode,
     /// vfio_enable: bool,
@@ -63,21 +104,150 @@ pub trait Daemon {
     /// Get the current power status
     fn power(&self) -> zbus::Result<GfxPower>;
 
+    /// Get what each power status means for the dGPU vendor of this system
+    fn power_semantics(&self) -> zbus::Result<PowerSemantics>;
+
     /// Set the graphics mode. Returns action required.
     fn set_mode(&self, mode: &GfxMode) -> zbus::Result<UserActionRequired>;
 
+    /// Set the graphics mode with options. Returns action required.
+    fn set_mode_with_options(
+        &self,
+        mode: &GfxMode,
+        options: &SetModeOptions,
+    ) -> zbus::Result<UserActionRequired>;
+
+    /// Run the ASUS boot safety check against sysfs as it is now, changing nothing
+    fn safety_check(&self) -> zbus::Result<SafetyCheck>;
+
+    /// Apply what `safety_check` reports through the usual switch. Returns action required.
+    fn safety_check_apply(&self) -> zbus::Result<UserActionRequired>;
+
+    /// Switch to a mode once the session of the caller ends, after the user confirmed a
+    /// logout to switch. Returns action required.
+    fn confirm_logout_and_switch(&self, mode: &GfxMode) -> zbus::Result<UserActionRequired>;
+
+    /// Get advice on switching to a mode, such as outputs that will stop working
+    fn switch_advisory(&self, mode: &GfxMode) -> zbus::Result<SwitchAdvisory>;
+
+    /// Get whether a switch to a mode would be made now, and what keeps it from being made
+    fn switch_readiness(&self, mode: &GfxMode) -> zbus::Result<SwitchReadiness>;
+
+    /// Get the actions a switch to a mode would perform, without switching
+    fn get_switch_plan(&self, mode: &GfxMode) -> zbus::Result<PlannedSwitch>;
+
+    /// Write a support bundle to `path`, returns the path written. Root only.
+    fn export_support_bundle(&self, path: &str) -> zbus::Result<String>;
+
+    /// Get the PCIe link state of the dGPU and its port
+    fn link_info(&self) -> zbus::Result<LinkInfo>;
+
+    /// The dGPU power status as `power` returns it, with `PropertiesChanged` on change
+    #[zbus(property)]
+    fn power_status(&self) -> zbus::Result<GfxPower>;
+
+    /// Get the functions of the dGPU which are present, empty if it is off the bus
+    fn devices(&self) -> zbus::Result<Vec<DeviceInfo>>;
+
+    /// Get the reminder to regenerate the initramfs, empty if there is none
+    fn initramfs_advisory(&self) -> zbus::Result<String>;
+
+    /// Dismiss the reminder to regenerate the initramfs
+    fn dismiss_initramfs_advisory(&self) -> zbus::Result<()>;
+
+    /// Get what supergfxd did differently at boot and wasn't dismissed yet
+    fn attention_items(&self) -> zbus::Result<Vec<AttentionItem>>;
+
+    /// Dismiss the attention item with `code`
+    fn dismiss_attention(&self, code: &str) -> zbus::Result<()>;
+
+    /// Get the processes keeping the dGPU awake in Hybrid on battery
+    fn power_blockers(&self) -> zbus::Result<Vec<PowerBlocker>>;
+
+    /// Get the processes holding the dGPU open, which would keep a switch from unloading it
+    fn blocking_processes(&self) -> zbus::Result<Vec<BlockingProcess>>;
+
+    /// Get the resident set size of the daemon and the state of its bounded buffers
+    fn memory_report(&self) -> zbus::Result<MemoryReport>;
+
+    /// Get how many of each signal were emitted, to tell if any were missed
+    fn signal_counters(&self) -> zbus::Result<SignalCounters>;
+
+    /// Get the background tasks of the daemon, their restart policy and state
+    fn tasks(&self) -> zbus::Result<Vec<TaskInfo>>;
+
+    /// Switch to another mode and back, checking nothing is left changed. Root only.
+    fn self_test(&self, force: bool) -> zbus::Result<SelfTestReport>;
+
+    /// Cancel the pending mode change if it has not yet changed the system
+    fn cancel_switch(&self) -> zbus::Result<()>;
+
+    /// Stop supergfxd doing anything by itself for `seconds`, returning a cookie
+    fn inhibit_automation(&self, reason: &str, seconds: u64) -> zbus::Result<u32>;
+
+    /// End an inhibition from `inhibit_automation` early
+    fn uninhibit(&self, cookie: u32) -> zbus::Result<()>;
+
+    /// Only get the signals of `categories`, such as `power`. Empty removes the filter.
+    fn set_signal_filter(&self, categories: &[String]) -> zbus::Result<()>;
+
+    /// Lock the mode to the one currently configured, or unlock it. Root only.
+    fn set_mode_lock(&self, locked: bool) -> zbus::Result<()>;
+
+    /// Get the last `count` records of the audit log, oldest first. Root only.
+    fn audit_log(&self, count: u32) -> zbus::Result<Vec<AuditRecord>>;
+
+    /// Get if the mode is locked by the administrator
+    fn mode_locked(&self) -> zbus::Result<bool>;
+
+    /// Rescan the PCI bus to try to recover a dGPU which dropped off it
+    fn rescan_dgpu(&self) -> zbus::Result<bool>;
+
+    /// Get if the dGPU has dropped off the bus
+    fn degraded_hardware(&self) -> zbus::Result<bool>;
+
     /// Get the `String` name of the pending mode change if any
     fn pending_mode(&self) -> zbus::Result<GfxMode>;
 
     /// Get the `String` name of the pending required user action if any
     fn pending_user_action(&self) -> zbus::Result<UserActionRequired>;
 
+    /// Get the pending mode change with who asked for it and how long ago
+    fn pending_info(&self) -> zbus::Result<PendingInfo>;
+
+    /// Get a snapshot of the daemon state
+    fn status(&self) -> zbus::Result<GfxStatus>;
+
+    /// Get the mode, power, pending change, vendor and supported modes read together
+    fn state(&self) -> zbus::Result<GfxState>;
+
+    /// Get the state of the mode switch task
+    fn switch_state(&self) -> zbus::Result<SwitchState>;
+
     /// Get the current graphics mode
     fn mode(&self) -> zbus::Result<GfxMode>;
 
+    /// Get the mode which will be used on the next boot
+    fn persistent_mode(&self) -> zbus::Result<GfxMode>;
+
     /// Get list of supported modes
     fn supported(&self) -> zbus::Result<Vec<GfxMode>>;
 
+    /// Get list of supported modes with the probes which failed while making it
+    fn supported_with_errors(&self) -> zbus::Result<SupportedModes>;
+
+    /// Get why the list of supported modes is limited, empty if it isn't
+    fn supported_reason(&self) -> zbus::Result<String>;
+
+    /// Get the documentation of every mode and whether each is supported
+    fn mode_info(&self) -> zbus::Result<Vec<ModeInfo>>;
+
+    /// Get the environment variables to run an app on the dGPU, empty if it can't be
+    fn prime_env(&self) -> zbus::Result<Vec<(String, String)>>;
+
+    /// Get how the daemon is operating on this system
+    fn profile(&self) -> zbus::Result<OperatingProfile>;
+
     /// Get the vendor name of the dGPU
     fn vendor(&self) -> zbus::Result<String>;
 
@@ -89,7 +259,101 @@ pub trait Daemon {
     #[zbus(signal)]
     fn notify_action(&self, action: UserActionRequired) -> zbus::Result<()>;
 
+    /// NotifySwitchAdvisory signal
+    #[zbus(signal)]
+    fn notify_switch_advisory(&self, advisory: SwitchAdvisory) -> zbus::Result<()>;
+
+    /// NotifySwitchWaiting signal
+    #[zbus(signal)]
+    fn notify_switch_waiting(&self, waiting_for: Vec<String>) -> zbus::Result<()>;
+
+    /// NotifyLogoutTimeout signal
+    #[zbus(signal)]
+    fn notify_logout_timeout(&self, message: &str) -> zbus::Result<()>;
+
+    /// NotifyPendingLogout signal
+    #[zbus(signal)]
+    fn notify_pending_logout(&self, seconds_remaining: u64) -> zbus::Result<()>;
+
     /// NotifyGfx signal
     #[zbus(signal)]
     fn notify_gfx(&self, mode: GfxMode) -> zbus::Result<()>;
+
+    /// NotifyModeChange signal
+    #[zbus(signal)]
+    fn notify_mode_change(&self, mode: GfxMode, initiator: SwitchInitiator) -> zbus::Result<()>;
+
+    /// NotifySuggestion signal
+    #[zbus(signal)]
+    fn notify_suggestion(&self, suggestion: ModeSuggestion) -> zbus::Result<()>;
+
+    /// NotifyDockSuggestion signal
+    #[zbus(signal)]
+    fn notify_dock_suggestion(&self, suggestion: DockSuggestion) -> zbus::Result<()>;
+
+    /// NotifyEgpuPresence signal
+    #[zbus(signal)]
+    fn notify_egpu_presence(&self, present: bool) -> zbus::Result<()>;
+
+    /// NotifyScheduleSuggestion signal
+    #[zbus(signal)]
+    fn notify_schedule_suggestion(&self, suggestion: ScheduleSuggestion) -> zbus::Result<()>;
+
+    /// NotifySupportedChanged signal
+    #[zbus(signal)]
+    fn notify_supported_changed(&self, modes: Vec<GfxMode>) -> zbus::Result<()>;
+
+    /// NotifySwitchCountdown signal
+    #[zbus(signal)]
+    fn notify_switch_countdown(&self, seconds_remaining: u64) -> zbus::Result<()>;
+
+    /// NotifySwitchProgress signal
+    #[zbus(signal)]
+    fn notify_switch_progress(
+        &self,
+        current_action: &str,
+        index: u32,
+        total: u32,
+    ) -> zbus::Result<()>;
+
+    /// NotifySwitchComplete signal
+    #[zbus(signal)]
+    fn notify_switch_complete(
+        &self,
+        mode: GfxMode,
+        success: bool,
+        outcome: &str,
+    ) -> zbus::Result<()>;
+
+    /// NotifyDrift signal
+    #[zbus(signal)]
+    fn notify_drift(&self, findings: Vec<String>) -> zbus::Result<()>;
+
+    /// NotifyInitramfsAdvisory signal
+    #[zbus(signal)]
+    fn notify_initramfs_advisory(&self, advisory: &str) -> zbus::Result<()>;
+
+    /// NotifyBootAdvisory signal
+    #[zbus(signal)]
+    fn notify_boot_advisory(&self, advisory: &str) -> zbus::Result<()>;
+
+    /// NotifyAttention signal
+    #[zbus(signal)]
+    fn notify_attention(&self, items: Vec<AttentionItem>) -> zbus::Result<()>;
+
+    /// NotifyReadinessChanged signal
+    #[zbus(signal)]
+    fn notify_readiness_changed(&self, generation: u64) -> zbus::Result<()>;
+
+    /// NotifyError signal
+    #[zbus(signal)]
+    fn notify_error(&self, error: &str) -> zbus::Result<()>;
+
+    /// NotifyShutdown signal
+    #[zbus(signal)]
+    fn notify_shutdown(&self, interrupted: &str) -> zbus::Result<()>;
+
+    /// NotifyEvent signal
+    #[zbus(signal)]
+    fn notify_event(&self, signal: &str, sequence: u64) -> zbus::Result<()>;
 }